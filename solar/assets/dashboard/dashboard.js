@@ -0,0 +1,166 @@
+// Solar dashboard.
+//
+// Polls the JSON-RPC API of the node (forwarded by the dashboard server on
+// `/rpc`) and renders connections, replication progress, storage usage and
+// recent log lines.
+
+"use strict";
+
+const REFRESH_INTERVAL_MS = 5000;
+const LOG_LINES = 200;
+
+let requestId = 0;
+
+// Token of the dashboard, embedded in the page by the dashboard server and
+// required by `/rpc`.
+const TOKEN = document.querySelector('meta[name="dashboard-token"]').content;
+
+async function call(method, params) {
+  const response = await fetch("/rpc", {
+    method: "POST",
+    headers: { "Content-Type": "application/json", "X-Dashboard-Token": TOKEN },
+    body: JSON.stringify({ jsonrpc: "2.0", id: ++requestId, method, params }),
+  });
+  const body = await response.json();
+  if (body.error) {
+    throw new Error(`${method}: ${body.error.message}`);
+  }
+  return body.result;
+}
+
+function formatBytes(bytes) {
+  const units = ["B", "KB", "MB", "GB", "TB"];
+  let value = bytes;
+  let unit = 0;
+  while (value >= 1000 && unit < units.length - 1) {
+    value /= 1000;
+    unit += 1;
+  }
+  return `${value.toFixed(unit === 0 ? 0 : 1)} ${units[unit]}`;
+}
+
+function formatTime(millis) {
+  return new Date(millis).toLocaleString();
+}
+
+function cell(text, className) {
+  const td = document.createElement("td");
+  td.textContent = text;
+  if (className) {
+    td.className = className;
+  }
+  return td;
+}
+
+function renderList(id, entries) {
+  const list = document.getElementById(id);
+  list.replaceChildren();
+  for (const [term, description] of entries) {
+    const dt = document.createElement("dt");
+    dt.textContent = term;
+    const dd = document.createElement("dd");
+    dd.textContent = description;
+    list.append(dt, dd);
+  }
+}
+
+function renderRows(id, rows) {
+  const body = document.getElementById(id);
+  body.replaceChildren();
+  for (const cells of rows) {
+    const tr = document.createElement("tr");
+    tr.append(...cells);
+    body.append(tr);
+  }
+}
+
+function renderStorage(usage) {
+  renderList("storage", [
+    ["Feeds", usage.feeds],
+    ["Database", formatBytes(usage.database_bytes)],
+    ["Blobs", usage.blobs],
+    ["Blob storage", formatBytes(usage.blob_bytes)],
+  ]);
+}
+
+function renderReplication(stats) {
+  const today = stats[stats.length - 1] || {};
+  renderList("replication", [
+    ["Messages replicated", today.messages_replicated || 0],
+    ["New feeds", today.new_feeds || 0],
+    ["Unique peers", today.unique_peers || 0],
+    ["Received", formatBytes(today.bytes_received || 0)],
+    ["Sent", formatBytes(today.bytes_sent || 0)],
+  ]);
+}
+
+function renderConnections(connections) {
+  renderRows(
+    "connections",
+    connections.map((connection) => [
+      cell(connection.connection_id),
+      cell(connection.peer || "unknown", "id"),
+      cell(connection.addr || ""),
+      cell(connection.inbound ? "inbound" : "outbound"),
+      cell(
+        connection.strategy
+          ? `${connection.state} (${connection.strategy})`
+          : connection.state,
+      ),
+      cell(formatTime(connection.since)),
+    ]),
+  );
+}
+
+function renderFeeds(peers) {
+  const feeds = [...peers].sort((a, b) => b[1] - a[1]);
+  renderRows(
+    "feeds",
+    feeds.map(([feed, seq]) => [cell(feed, "id"), cell(seq)]),
+  );
+}
+
+function renderLogs(lines) {
+  const logs = document.getElementById("logs");
+  const atBottom = logs.scrollTop + logs.clientHeight >= logs.scrollHeight - 1;
+  logs.textContent = lines.join("\n");
+  if (atBottom) {
+    logs.scrollTop = logs.scrollHeight;
+  }
+}
+
+async function refresh() {
+  const status = document.getElementById("status");
+  try {
+    const [usage, stats, connections, peers, logs] = await Promise.all([
+      call("storage_usage"),
+      call("network_stats", { days: 1 }),
+      call("connections"),
+      call("peers"),
+      call("recent_logs", { limit: LOG_LINES }),
+    ]);
+    renderStorage(usage);
+    renderReplication(stats);
+    renderConnections(connections);
+    renderFeeds(peers);
+    renderLogs(logs);
+
+    status.textContent = `Updated ${new Date().toLocaleTimeString()}`;
+    status.classList.remove("error");
+  } catch (err) {
+    status.textContent = `Failed to refresh: ${err.message}`;
+    status.classList.add("error");
+  }
+}
+
+async function init() {
+  try {
+    document.getElementById("whoami").textContent = await call("whoami");
+  } catch (err) {
+    // Reported by the next refresh.
+  }
+  await refresh();
+  setInterval(refresh, REFRESH_INTERVAL_MS);
+}
+
+init();
@@ -0,0 +1,160 @@
+//! Storage and replication benchmarks.
+//!
+//! Run with `cargo bench -p solar`. Criterion stores the results of each run
+//! in `target/criterion`, allowing changes to be compared against a
+//! baseline (`cargo bench -p solar -- --save-baseline <name>`).
+
+use async_std::task;
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use kuska_ssb::feed::Message;
+use sled::Config;
+use solar::{
+    ebt_clock,
+    storage::{kv::KvStorage, synthetic},
+    VectorClock,
+};
+
+/// Number of feeds used to populate the database.
+const FEEDS: usize = 50;
+/// Number of messages in each feed.
+const FEED_LENGTH: u64 = 100;
+
+/// Open a temporary key-value store.
+fn open_temporary_kv() -> KvStorage {
+    let mut kv = KvStorage::default();
+    let (sender, _) = futures::channel::mpsc::unbounded();
+    kv.open(Config::new().temporary(true), sender).unwrap();
+
+    kv
+}
+
+/// Open a temporary key-value store and append the given feeds.
+fn populate_kv(feeds: &[Vec<Message>]) -> KvStorage {
+    let kv = open_temporary_kv();
+
+    task::block_on(async {
+        for feed in feeds {
+            for msg in feed {
+                kv.append_feed(msg.clone()).await.unwrap();
+            }
+        }
+    });
+
+    kv
+}
+
+fn bench_append_feed(c: &mut Criterion) {
+    let author = synthetic::generate_keypairs(1).remove(0);
+    let feed = synthetic::generate_feed(&author, &[], FEED_LENGTH).unwrap();
+
+    c.bench_function("append_feed", |b| {
+        b.iter_batched(
+            open_temporary_kv,
+            |kv| {
+                task::block_on(async {
+                    for msg in &feed {
+                        kv.append_feed(msg.clone()).await.unwrap();
+                    }
+                })
+            },
+            BatchSize::PerIteration,
+        )
+    });
+}
+
+fn bench_clock_construction(c: &mut Criterion) {
+    let authors = synthetic::generate_keypairs(FEEDS);
+    let feeds = synthetic::generate_feeds(&authors, FEED_LENGTH).unwrap();
+    let kv = populate_kv(&feeds);
+
+    // Construct the local vector clock in the same manner as the EBT
+    // manager: look up the latest sequence number of each replicated feed
+    // and encode it.
+    c.bench_function("clock_construction", |b| {
+        b.iter(|| {
+            let mut clock = VectorClock::new();
+            for author in &authors {
+                let seq = kv.get_latest_seq(&author.id).unwrap().unwrap_or(0);
+                let value = ebt_clock::encode(true, Some(true), Some(seq)).unwrap();
+                clock.insert(author.id.to_owned(), value);
+            }
+
+            black_box(clock)
+        })
+    });
+
+    // Decode every entry of a received vector clock.
+    let mut clock = VectorClock::new();
+    for author in &authors {
+        clock.insert(
+            author.id.to_owned(),
+            ebt_clock::encode(true, Some(true), Some(FEED_LENGTH)).unwrap(),
+        );
+    }
+
+    c.bench_function("clock_decode", |b| {
+        b.iter(|| {
+            for value in clock.values() {
+                black_box(ebt_clock::decode(*value).unwrap());
+            }
+        })
+    });
+}
+
+fn bench_feed_queries(c: &mut Criterion) {
+    let authors = synthetic::generate_keypairs(FEEDS);
+    let feeds = synthetic::generate_feeds(&authors, FEED_LENGTH).unwrap();
+    let kv = populate_kv(&feeds);
+    let author = &authors[0].id;
+
+    c.bench_function("get_feed", |b| {
+        b.iter(|| black_box(kv.get_feed(author).unwrap()))
+    });
+
+    // Retrieve the latest half of a feed, as when serving a peer which
+    // requests messages from a given sequence number.
+    c.bench_function("get_msg_kvt_range", |b| {
+        b.iter(|| {
+            for seq in (FEED_LENGTH / 2)..=FEED_LENGTH {
+                black_box(kv.get_msg_kvt(author, seq).unwrap());
+            }
+        })
+    });
+}
+
+fn bench_index_queries(c: &mut Criterion) {
+    let authors = synthetic::generate_keypairs(FEEDS);
+    let feeds = synthetic::generate_feeds(&authors, FEED_LENGTH).unwrap();
+    let kv = populate_kv(&feeds);
+    let indexes = kv.indexes.as_ref().unwrap();
+    let (peer_a, peer_b) = (&authors[0].id, &authors[1].id);
+
+    c.bench_function("get_follows", |b| {
+        b.iter(|| black_box(indexes.get_follows(peer_a).unwrap()))
+    });
+
+    c.bench_function("get_followers", |b| {
+        b.iter(|| black_box(indexes.get_followers(peer_a).unwrap()))
+    });
+
+    c.bench_function("is_following", |b| {
+        b.iter(|| black_box(indexes.is_following(peer_a, peer_b).unwrap()))
+    });
+
+    c.bench_function("get_friends", |b| {
+        b.iter(|| black_box(indexes.get_friends(peer_a).unwrap()))
+    });
+
+    c.bench_function("get_latest_name", |b| {
+        b.iter(|| black_box(indexes.get_latest_name(peer_a).unwrap()))
+    });
+}
+
+criterion_group!(
+    benches,
+    bench_append_feed,
+    bench_clock_construction,
+    bench_feed_queries,
+    bench_index_queries
+);
+criterion_main!(benches);
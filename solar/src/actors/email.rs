@@ -0,0 +1,670 @@
+//! Email notifications
+//!
+//! Sends an email to the operator of the node through an SMTP server when
+//! selected events concerning the local identity occur, so that pub
+//! activity shows up in an inbox. The notifications are configured in the
+//! `email.toml` file in the root data directory, which is read when the
+//! node starts:
+//!
+//! ```toml
+//! from = "Solar <solar@example.com>"
+//! to = ["operator@example.com"]
+//! events = ["new_follower", "mention", "private_message"]
+//!
+//! [smtp]
+//! host = "smtp.example.com"
+//! # `starttls` (the default), `tls` or `none`.
+//! security = "starttls"
+//! username = "solar@example.com"
+//! password = "..."
+//! ```
+//!
+//! As with webhooks, events are only reported for messages claiming to have
+//! been published within the last day. The text of private messages is left
+//! out of the emails unless `include_private_text` is set.
+//!
+//! At most `max_emails` emails are sent in each window of `window_secs`
+//! seconds (10 per hour by default). The events beyond the cap are held back
+//! and reported together in a single digest email at the end of the window,
+//! so that a burst of activity does not flood the inbox (or the SMTP
+//! server).
+use std::{fs, path::Path, time::Duration};
+
+use async_std::{stream, task};
+use futures::{select_biased, stream::StreamExt, FutureExt};
+use kuska_ssb::{crypto::ed25519, feed::Message, keystore::OwnedIdentity};
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncStd1Executor, AsyncTransport, Message as Email,
+};
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{
+    actors::webhooks::{self, WebhookEvent},
+    broker::{ActorEndpoint, BrokerMessage, Void, BROKER},
+    node::KV_STORE,
+    private_box,
+    storage::kv::StoreKvEvent,
+    util::now_millis,
+    Error, Result,
+};
+
+/// Name of the email configuration file in the root data directory.
+pub const EMAIL_CONFIG_FILE: &str = "email.toml";
+
+/// Maximum duration of an exchange with the SMTP server.
+const SMTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Maximum number of held back events listed in a digest email; the others
+/// are only counted.
+const MAX_DIGEST_ENTRIES: usize = 100;
+
+/// Security of the connection to the SMTP server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SmtpSecurity {
+    /// Upgrade the connection with `STARTTLS` (port 587 by default).
+    #[default]
+    Starttls,
+    /// Connect over TLS (port 465 by default).
+    Tls,
+    /// Send emails in the clear (port 25 by default), for example to a
+    /// relay on the local host.
+    None,
+}
+
+/// The SMTP server through which emails are sent.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct SmtpConfig {
+    pub host: String,
+    /// Port of the server; defaults to the usual port of the security.
+    #[serde(default)]
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub security: SmtpSecurity,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+impl SmtpConfig {
+    /// Return a transport sending emails through the server.
+    fn transport(&self) -> Result<AsyncSmtpTransport<AsyncStd1Executor>> {
+        let (builder, port) = match self.security {
+            SmtpSecurity::Starttls => (
+                AsyncSmtpTransport::<AsyncStd1Executor>::starttls_relay(&self.host),
+                587,
+            ),
+            SmtpSecurity::Tls => (
+                AsyncSmtpTransport::<AsyncStd1Executor>::relay(&self.host),
+                465,
+            ),
+            SmtpSecurity::None => (
+                Ok(AsyncSmtpTransport::<AsyncStd1Executor>::builder_dangerous(
+                    &self.host,
+                )),
+                25,
+            ),
+        };
+        let mut builder = builder
+            .map_err(|err| Error::Config(format!("Invalid SMTP server {}: {}", self.host, err)))?
+            .port(self.port.unwrap_or(port))
+            .timeout(Some(SMTP_TIMEOUT));
+        if let (Some(username), Some(password)) = (&self.username, &self.password) {
+            builder =
+                builder.credentials(Credentials::new(username.to_owned(), password.to_owned()));
+        }
+
+        Ok(builder.build())
+    }
+}
+
+/// Kind of an email event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EmailEventKind {
+    NewFollower,
+    Mention,
+    PrivateMessage,
+}
+
+/// Contents of the email configuration file.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct EmailConfig {
+    pub smtp: SmtpConfig,
+    /// Sender of the emails (`name <address>` or `address`).
+    pub from: String,
+    /// Recipients of the emails.
+    pub to: Vec<String>,
+    pub events: Vec<EmailEventKind>,
+    /// Include the text of private messages in the emails.
+    #[serde(default)]
+    pub include_private_text: bool,
+    /// Maximum number of emails sent in each window; the events beyond it
+    /// are reported in a digest at the end of the window.
+    #[serde(default = "default_max_emails")]
+    pub max_emails: usize,
+    /// Duration of a window, in seconds.
+    #[serde(default = "default_window_secs")]
+    pub window_secs: u64,
+}
+
+fn default_max_emails() -> usize {
+    10
+}
+
+fn default_window_secs() -> u64 {
+    3600
+}
+
+impl EmailConfig {
+    /// Read the email configuration file at the given path. Returns `None`
+    /// if the file does not exist.
+    pub fn read(path: &Path) -> Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let config: Self = toml::from_str(&fs::read_to_string(path)?)?;
+        config.mailboxes()?;
+        if config.window_secs == 0 {
+            return Err(Error::Config(
+                "The email window must last at least a second".to_string(),
+            ));
+        }
+
+        Ok(Some(config))
+    }
+
+    /// Return the sender and the recipients of the emails.
+    fn mailboxes(&self) -> Result<(Mailbox, Vec<Mailbox>)> {
+        let parse = |address: &str| {
+            address
+                .parse::<Mailbox>()
+                .map_err(|err| Error::Config(format!("Invalid email address {}: {}", address, err)))
+        };
+        if self.to.is_empty() {
+            return Err(Error::Config("No email recipients".to_string()));
+        }
+
+        Ok((
+            parse(&self.from)?,
+            self.to
+                .iter()
+                .map(|address| parse(address))
+                .collect::<Result<_>>()?,
+        ))
+    }
+}
+
+/// An event reported by email.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EmailEvent {
+    /// A peer published a follow of the local identity.
+    NewFollower { follower: String, msg_ref: String },
+    /// A peer published a message mentioning the local identity.
+    Mention {
+        author: String,
+        msg_ref: String,
+        text: Option<String>,
+    },
+    /// A peer sent a private message to the local identity.
+    PrivateMessage {
+        author: String,
+        msg_ref: String,
+        text: Option<String>,
+    },
+}
+
+impl EmailEvent {
+    fn kind(&self) -> EmailEventKind {
+        match self {
+            EmailEvent::NewFollower { .. } => EmailEventKind::NewFollower,
+            EmailEvent::Mention { .. } => EmailEventKind::Mention,
+            EmailEvent::PrivateMessage { .. } => EmailEventKind::PrivateMessage,
+        }
+    }
+
+    /// Return the author of the message of the event.
+    fn author(&self) -> &str {
+        match self {
+            EmailEvent::NewFollower { follower, .. } => follower,
+            EmailEvent::Mention { author, .. } | EmailEvent::PrivateMessage { author, .. } => {
+                author
+            }
+        }
+    }
+
+    /// Return the reference of the message of the event.
+    fn msg_ref(&self) -> &str {
+        match self {
+            EmailEvent::NewFollower { msg_ref, .. }
+            | EmailEvent::Mention { msg_ref, .. }
+            | EmailEvent::PrivateMessage { msg_ref, .. } => msg_ref,
+        }
+    }
+
+    /// Return a one-line summary of the event, given the name of the author
+    /// of its message.
+    fn summary(&self, name: &str) -> String {
+        match self {
+            EmailEvent::NewFollower { .. } => format!("{} followed you", name),
+            EmailEvent::Mention { .. } => format!("{} mentioned you", name),
+            EmailEvent::PrivateMessage { .. } => format!("{} sent you a private message", name),
+        }
+    }
+
+    /// Return the subject and the body of the email reporting the event,
+    /// given the name of the author of its message.
+    fn render(&self, name: &str, include_private_text: bool) -> (String, String) {
+        let subject = self.summary(name);
+        let text = match self {
+            EmailEvent::NewFollower { .. } => None,
+            EmailEvent::Mention { text, .. } => text.as_deref(),
+            EmailEvent::PrivateMessage { text, .. } => {
+                text.as_deref().filter(|_| include_private_text)
+            }
+        };
+
+        let mut body = format!(
+            "{}.\n\nAuthor: {}\nMessage: {}\n",
+            subject,
+            self.author(),
+            self.msg_ref()
+        );
+        if let Some(text) = text {
+            body.push('\n');
+            body.push_str(text);
+            body.push('\n');
+        }
+
+        (format!("[solar] {}", subject), body)
+    }
+}
+
+/// The emails sent in the current window, and the events held back once the
+/// cap was reached.
+#[derive(Debug, Default)]
+struct Window {
+    /// Number of emails sent in the window.
+    sent: usize,
+    /// Summaries and message references of the held back events, up to
+    /// `MAX_DIGEST_ENTRIES`.
+    held_back: Vec<String>,
+    /// Total number of held back events.
+    held_back_count: usize,
+}
+
+impl Window {
+    /// Count an email to be sent, returning `false` if the given cap of
+    /// emails per window has been reached.
+    fn try_send(&mut self, max_emails: usize) -> bool {
+        if self.sent >= max_emails {
+            return false;
+        }
+        self.sent += 1;
+
+        true
+    }
+
+    /// Hold back an event with the given summary and message reference until
+    /// the digest of the window.
+    fn hold_back(&mut self, summary: String, msg_ref: &str) {
+        if self.held_back.len() < MAX_DIGEST_ENTRIES {
+            self.held_back.push(format!("{}: {}", summary, msg_ref));
+        }
+        self.held_back_count += 1;
+    }
+
+    /// Close the window, returning the subject and body of the digest of
+    /// the held back events (if any).
+    fn close(&mut self, max_emails: usize) -> Option<(String, String)> {
+        let window = std::mem::take(self);
+        if window.held_back_count == 0 {
+            return None;
+        }
+
+        let mut body = format!(
+            "{} notifications were held back to send at most {} emails at a time.\n\n",
+            window.held_back_count, max_emails
+        );
+        for line in &window.held_back {
+            body.push_str(&format!("- {}\n", line));
+        }
+        let unlisted = window.held_back_count - window.held_back.len();
+        if unlisted > 0 {
+            body.push_str(&format!("- and {} more\n", unlisted));
+        }
+
+        Some((
+            format!("[solar] {} more notifications", window.held_back_count),
+            body,
+        ))
+    }
+}
+
+/// Return the events to be reported for the given stored message, at the
+/// given time (in milliseconds since the UNIX epoch).
+fn message_events(
+    msg: &Message,
+    local_id: &str,
+    sk: &ed25519::SecretKey,
+    now: u64,
+) -> Vec<EmailEvent> {
+    let text = |content: &Value| content["text"].as_str().map(ToOwned::to_owned);
+
+    if let Value::String(boxed) = msg.content() {
+        if msg.author() == local_id || !webhooks::is_recent(msg, now) {
+            return Vec::new();
+        }
+        return private_box::open(boxed, sk)
+            .map(|content| EmailEvent::PrivateMessage {
+                author: msg.author().to_owned(),
+                msg_ref: msg.id().to_string(),
+                text: text(&content),
+            })
+            .into_iter()
+            .collect();
+    }
+
+    webhooks::message_events(msg, local_id, now)
+        .into_iter()
+        .filter_map(|event| match event {
+            WebhookEvent::NewFollower { follower, msg_ref } => {
+                Some(EmailEvent::NewFollower { follower, msg_ref })
+            }
+            WebhookEvent::Mention { author, msg_ref } => Some(EmailEvent::Mention {
+                author,
+                msg_ref,
+                text: text(msg.content()),
+            }),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Return the events to be reported for the message with the given author
+/// and sequence number.
+async fn stored_message_events(
+    author: &str,
+    seq: u64,
+    identity: &OwnedIdentity,
+) -> Result<Vec<EmailEvent>> {
+    let msg = match KV_STORE.read().await.get_msg_kvt(author, seq)? {
+        Some(msg_kvt) => msg_kvt.into_message()?,
+        None => return Ok(Vec::new()),
+    };
+
+    Ok(message_events(
+        &msg,
+        &identity.id,
+        &identity.sk,
+        now_millis(),
+    ))
+}
+
+/// Return the name of the author of the message of the given event, or its
+/// public key if it has not named itself.
+async fn author_name(event: &EmailEvent) -> String {
+    match KV_STORE.read().await.indexes.as_ref() {
+        Some(indexes) => indexes.get_display_name(event.author()).ok().flatten(),
+        None => None,
+    }
+    .unwrap_or_else(|| event.author().to_owned())
+}
+
+/// Send an email with the given subject and body to the configured
+/// recipients. The email is sent from its own task, so that a slow server
+/// does not hold back the dispatcher.
+fn send(
+    transport: &AsyncSmtpTransport<AsyncStd1Executor>,
+    (from, to): &(Mailbox, Vec<Mailbox>),
+    subject: String,
+    body: String,
+    description: String,
+) {
+    let mut builder = Email::builder().from(from.to_owned()).subject(subject);
+    for recipient in to {
+        builder = builder.to(recipient.to_owned());
+    }
+    let email = match builder.header(ContentType::TEXT_PLAIN).body(body) {
+        Ok(email) => email,
+        Err(err) => {
+            warn!("Failed to build {} email: {}", description, err);
+            return;
+        }
+    };
+
+    let transport = transport.to_owned();
+    task::spawn(async move {
+        match transport.send(email).await {
+            Ok(_) => info!("Sent {} email", description),
+            Err(err) => warn!("Failed to send {} email: {}", description, err),
+        }
+    });
+}
+
+/// Send an email reporting the given event to the configured recipients, or
+/// hold it back until the digest if the cap of the window has been reached.
+async fn dispatch(
+    transport: &AsyncSmtpTransport<AsyncStd1Executor>,
+    config: &EmailConfig,
+    mailboxes: &(Mailbox, Vec<Mailbox>),
+    window: &mut Window,
+    event: EmailEvent,
+) {
+    let name = author_name(&event).await;
+    if !window.try_send(config.max_emails) {
+        window.hold_back(event.summary(&name), event.msg_ref());
+        return;
+    }
+
+    let (subject, body) = event.render(&name, config.include_private_text);
+    let description = format!("{:?} notification", event.kind());
+    send(transport, mailboxes, subject, body, description);
+}
+
+/// Start the email notifier.
+///
+/// Register the notifier with the broker (as an actor) and send an email
+/// for each stored message matching the configured events, decrypting the
+/// private messages addressed to the given identity. The events beyond the
+/// cap of a window are reported in a digest at the end of the window.
+pub async fn actor(config: EmailConfig, identity: OwnedIdentity) -> Result<()> {
+    let ActorEndpoint {
+        ch_terminate,
+        ch_terminated,
+        ch_msg,
+        ..
+    } = BROKER.lock().await.register("email", true).await?;
+
+    let mut ch_msg = ch_msg.ok_or(Error::OptionIsNone)?;
+    let mut ch_terminate_fuse = ch_terminate.fuse();
+
+    let mailboxes = config.mailboxes()?;
+    let transport = config.smtp.transport()?;
+
+    let mut window = Window::default();
+    let mut ticker = stream::interval(Duration::from_secs(config.window_secs)).fuse();
+
+    loop {
+        select_biased! {
+            // Received termination signal. Break out of the loop.
+            _value = ch_terminate_fuse => {
+                break;
+            },
+            _tick = ticker.next() => {
+                if let Some((subject, body)) = window.close(config.max_emails) {
+                    send(&transport, &mailboxes, subject, body, "notification digest".to_string());
+                }
+            },
+            msg = ch_msg.next().fuse() => {
+                match msg {
+                    Some(BrokerMessage::StoreKv(StoreKvEvent((author, seq)))) => {
+                        match stored_message_events(&author, seq, &identity).await {
+                            Ok(events) => {
+                                for event in events {
+                                    if config.events.contains(&event.kind()) {
+                                        dispatch(
+                                            &transport,
+                                            &config,
+                                            &mailboxes,
+                                            &mut window,
+                                            event,
+                                        )
+                                        .await;
+                                    }
+                                }
+                            }
+                            Err(err) => warn!("Failed to read message {}:{}: {}", author, seq, err),
+                        }
+                    }
+                    Some(_) => (),
+                    None => break,
+                }
+            },
+        }
+    }
+
+    let _ = ch_terminated.send(Void {});
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    use crate::secret_config::SecretConfig;
+
+    #[test]
+    fn test_email_config() -> crate::Result<()> {
+        let config: EmailConfig = toml::from_str(
+            r#"
+            from = "Solar <solar@example.com>"
+            to = ["operator@example.com"]
+            events = ["mention", "private_message"]
+
+            [smtp]
+            host = "smtp.example.com"
+            username = "solar@example.com"
+            password = "secret"
+            "#,
+        )?;
+        assert_eq!(config.smtp.security, SmtpSecurity::Starttls);
+        assert_eq!(config.smtp.port, None);
+        assert!(!config.include_private_text);
+        assert_eq!(config.max_emails, 10);
+        assert_eq!(config.window_secs, 3600);
+
+        let (from, to) = config.mailboxes()?;
+        assert_eq!(from.email.to_string(), "solar@example.com");
+        assert_eq!(to.len(), 1);
+
+        let invalid = EmailConfig {
+            to: vec!["not an address".to_string()],
+            ..config.to_owned()
+        };
+        assert!(invalid.mailboxes().is_err());
+        let no_recipients = EmailConfig {
+            to: Vec::new(),
+            ..config
+        };
+        assert!(no_recipients.mailboxes().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_message_events() -> crate::Result<()> {
+        let local = SecretConfig::create().to_owned_identity()?;
+        let peer = SecretConfig::create().to_owned_identity()?;
+
+        let content = json!({ "type": "post", "text": "Hello", "recps": [local.id, peer.id] });
+        let boxed = private_box::seal(&content, &[&local.sk, &peer.sk]);
+        let msg = Message::sign(None, &peer, json!(boxed))?;
+        let now = msg.value["timestamp"].as_f64().unwrap_or_default() as u64;
+
+        assert_eq!(
+            message_events(&msg, &local.id, &local.sk, now),
+            vec![EmailEvent::PrivateMessage {
+                author: peer.id.to_owned(),
+                msg_ref: msg.id().to_string(),
+                text: Some("Hello".to_string()),
+            }]
+        );
+
+        // Private messages addressed to others are ignored.
+        let other = SecretConfig::create().to_owned_identity()?;
+        assert!(message_events(&msg, &other.id, &other.sk, now).is_empty());
+
+        let post = json!({
+            "type": "post",
+            "text": format!("Hello [@solar]({})", local.id),
+            "mentions": [{ "link": local.id, "name": "solar" }]
+        });
+        let msg = Message::sign(Some(&msg), &peer, post)?;
+        assert_eq!(
+            message_events(&msg, &local.id, &local.sk, now),
+            vec![EmailEvent::Mention {
+                author: peer.id.to_owned(),
+                msg_ref: msg.id().to_string(),
+                text: Some(format!("Hello [@solar]({})", local.id)),
+            }]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_render() {
+        let event = EmailEvent::PrivateMessage {
+            author: "@peer=.ed25519".to_string(),
+            msg_ref: "%msg=.sha256".to_string(),
+            text: Some("Secret".to_string()),
+        };
+
+        let (subject, body) = event.render("peer", false);
+        assert_eq!(subject, "[solar] peer sent you a private message");
+        assert!(body.contains("Author: @peer=.ed25519\nMessage: %msg=.sha256\n"));
+        assert!(!body.contains("Secret"));
+
+        let (_, body) = event.render("peer", true);
+        assert!(body.ends_with("\nSecret\n"));
+    }
+
+    #[test]
+    fn test_window() {
+        let mut window = Window::default();
+        assert!(window.try_send(2));
+        assert!(window.try_send(2));
+        assert!(!window.try_send(2));
+
+        // Nothing is held back: no digest is sent.
+        assert_eq!(window.close(2), None);
+
+        assert!(window.try_send(2));
+        assert!(window.try_send(2));
+        for i in 0..MAX_DIGEST_ENTRIES + 3 {
+            assert!(!window.try_send(2));
+            window.hold_back(format!("peer {} followed you", i), "%msg=.sha256");
+        }
+
+        let (subject, body) = window.close(2).unwrap();
+        assert_eq!(subject, "[solar] 103 more notifications");
+        assert!(body.starts_with("103 notifications were held back"));
+        assert!(body.contains("- peer 0 followed you: %msg=.sha256\n"));
+        assert!(!body.contains(&format!("peer {} followed you", MAX_DIGEST_ENTRIES)));
+        assert!(body.ends_with("- and 3 more\n"));
+
+        // A new window starts once closed.
+        assert!(window.try_send(2));
+        assert_eq!(window.close(2), None);
+    }
+}
@@ -0,0 +1,251 @@
+//! Follow Back
+//!
+//! Follows back new followers of the local identity, as is expected of pubs.
+//! The follow-back job listens for `new_follower` social events and applies
+//! the configured policy to each new follower:
+//!
+//! - peers within a given number of hops from the local identity in the
+//!   follow graph are followed back right away
+//! - peers who redeemed an invite (whose follow message is flagged with
+//!   `autofollow`, as published when an invite is used) are followed back
+//!   right away
+//! - other peers are queued, to be followed back once approved through the
+//!   JSON-RPC server (or otherwise ignored)
+//!
+//! Peers which the local identity already follows or blocks are left alone.
+//! A queued request is withdrawn once the peer unfollows or blocks the local
+//! identity, or once the local identity follows or blocks the peer by other
+//! means.
+use futures::{select_biased, stream::StreamExt, FutureExt};
+use kuska_ssb::{feed::Message, keystore::OwnedIdentity};
+use log::{info, warn};
+use serde_json::{json, Value};
+
+use crate::{
+    actors::social::SocialEvent,
+    broker::{ActorEndpoint, BrokerMessage, Void, BROKER},
+    error::Error,
+    node::KV_STORE,
+    storage::publish,
+    Result,
+};
+
+/// Policy by which new followers are followed back.
+#[derive(Debug, Clone, Default)]
+pub struct FollowBackPolicy {
+    /// Follow back new followers within the given number of hops from the
+    /// local identity in the follow graph (default: none).
+    pub hops: Option<usize>,
+
+    /// Follow back new followers who redeemed an invite (default: false).
+    pub invites: bool,
+
+    /// Queue the other new followers, to be followed back on approval
+    /// (default: false).
+    pub queue: bool,
+}
+
+/// What to do about a new follower.
+#[derive(Debug, PartialEq, Eq)]
+enum Decision {
+    Follow,
+    Queue,
+    Ignore,
+}
+
+impl FollowBackPolicy {
+    /// Query whether any new followers are to be followed back or queued.
+    pub fn is_enabled(&self) -> bool {
+        self.hops.is_some() || self.invites || self.queue
+    }
+
+    /// Decide what to do about a new follower, given whether the follower is
+    /// within the configured number of hops and whether it redeemed an
+    /// invite.
+    fn decide(&self, within_hops: bool, invited: bool) -> Decision {
+        if within_hops || (self.invites && invited) {
+            Decision::Follow
+        } else if self.queue {
+            Decision::Queue
+        } else {
+            Decision::Ignore
+        }
+    }
+}
+
+/// Query whether the given content is that of a follow message published by
+/// a peer redeeming an invite.
+fn is_invite_follow(content: &Value) -> bool {
+    content.get("type").and_then(Value::as_str) == Some("contact")
+        && content.get("following").and_then(Value::as_bool) == Some(true)
+        && content.get("autofollow").and_then(Value::as_bool) == Some(true)
+}
+
+/// Follow the given peer with the given identity. Returns the published
+/// message and its sequence number.
+pub async fn follow(identity: &OwnedIdentity, peer: &str) -> Result<(Message, u64)> {
+    let content = json!({
+        "type": "contact",
+        "contact": peer,
+        "following": true,
+    });
+
+    publish::publish(identity, content, None).await
+}
+
+/// Follow back the given peer if it is queued, removing it from the queue.
+/// Returns the published message and its sequence number, or `None` if the
+/// peer was not queued.
+pub async fn approve(identity: &OwnedIdentity, peer: &str) -> Result<Option<(Message, u64)>> {
+    if KV_STORE.read().await.remove_follow_request(peer)?.is_none() {
+        return Ok(None);
+    }
+
+    follow(identity, peer).await.map(Some)
+}
+
+/// Apply the given policy to the given new follower, whose follow message
+/// has the given reference.
+async fn handle_new_follower(
+    identity: &OwnedIdentity,
+    policy: &FollowBackPolicy,
+    peer: &str,
+    msg_ref: &str,
+) -> Result<()> {
+    let (within_hops, invited) = {
+        let db = KV_STORE.read().await;
+        let indexes = db.indexes.as_ref().ok_or(Error::Indexes)?;
+
+        let contact = indexes.get_contact(&identity.id, peer)?;
+        if contact.map_or(false, |state| state.following || state.blocking) {
+            return Ok(());
+        }
+
+        let within_hops = match policy.hops {
+            Some(max_hops) => indexes.get_hops(&identity.id, max_hops)?.contains_key(peer),
+            None => false,
+        };
+        let invited = db
+            .get_msg_val(msg_ref)?
+            .map_or(false, |msg| is_invite_follow(msg.content()));
+
+        (within_hops, invited)
+    };
+
+    match policy.decide(within_hops, invited) {
+        Decision::Follow => {
+            let (msg, _seq) = follow(identity, peer).await?;
+            info!("Followed back {} with {}", peer, msg.id().to_string());
+        }
+        Decision::Queue => {
+            KV_STORE.read().await.add_follow_request(peer, msg_ref)?;
+            info!("Queued {} to be followed back", peer);
+        }
+        Decision::Ignore => (),
+    }
+
+    Ok(())
+}
+
+/// Apply the given policy to the given social event.
+async fn handle_event(
+    identity: &OwnedIdentity,
+    policy: &FollowBackPolicy,
+    event: SocialEvent,
+) -> Result<()> {
+    match event {
+        SocialEvent::NewFollower { peer, msg_ref } => {
+            handle_new_follower(identity, policy, &peer, &msg_ref).await
+        }
+        SocialEvent::LostFollower { peer, .. }
+        | SocialEvent::BlockedBy { peer, .. }
+        | SocialEvent::Followed { peer, .. }
+        | SocialEvent::Blocked { peer, .. } => {
+            let removed = KV_STORE.read().await.remove_follow_request(&peer)?;
+            if removed.is_some() {
+                info!("Withdrew the follow request of {}", peer);
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Start the follow-back job.
+///
+/// Register the job with the broker (as an actor) and apply the given policy
+/// to the new followers of the local identity.
+pub async fn actor(identity: OwnedIdentity, policy: FollowBackPolicy) -> Result<()> {
+    let ActorEndpoint {
+        ch_terminate,
+        ch_terminated,
+        ch_msg,
+        ..
+    } = BROKER.lock().await.register("follow-back", true).await?;
+
+    let mut ch_msg = ch_msg.ok_or(Error::OptionIsNone)?;
+    let mut ch_terminate_fuse = ch_terminate.fuse();
+
+    loop {
+        select_biased! {
+            // Received termination signal. Break out of the loop.
+            _value = ch_terminate_fuse => {
+                break;
+            },
+            msg = ch_msg.next().fuse() => {
+                let event = match msg {
+                    Some(BrokerMessage::Social(event)) => event,
+                    Some(_) => continue,
+                    None => break,
+                };
+                if let Err(err) = handle_event(&identity, &policy, event).await {
+                    warn!("Failed to follow back: {}", err);
+                }
+            }
+        }
+    }
+
+    let _ = ch_terminated.send(Void {});
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decide() {
+        let policy = FollowBackPolicy {
+            hops: Some(2),
+            invites: true,
+            queue: true,
+        };
+        assert_eq!(policy.decide(true, false), Decision::Follow);
+        assert_eq!(policy.decide(false, true), Decision::Follow);
+        assert_eq!(policy.decide(false, false), Decision::Queue);
+
+        let policy = FollowBackPolicy {
+            hops: Some(2),
+            ..FollowBackPolicy::default()
+        };
+        assert_eq!(policy.decide(false, true), Decision::Ignore);
+        assert_eq!(policy.decide(false, false), Decision::Ignore);
+        assert!(!FollowBackPolicy::default().is_enabled());
+    }
+
+    #[test]
+    fn test_is_invite_follow() {
+        assert!(is_invite_follow(&json!({
+            "type": "contact",
+            "contact": "@pub=.ed25519",
+            "following": true,
+            "autofollow": true,
+        })));
+        assert!(!is_invite_follow(&json!({
+            "type": "contact",
+            "contact": "@pub=.ed25519",
+            "following": true,
+        })));
+    }
+}
@@ -0,0 +1,236 @@
+//! JSON-RPC audit log.
+//!
+//! Operators of nodes shared by several clients can keep an audit trail of
+//! the JSON-RPC calls which change the state of the node (publishing,
+//! outbox edits, pins, local records, room membership etc.). Each call is
+//! appended to the audit log as a line of JSON, recording the time of the
+//! call, the method, the caller, a hash of the parameters and the outcome.
+//!
+//! Callers identify themselves by passing their API token as the `token`
+//! (named) parameter of each call. While the audit log is enabled, only the
+//! tokens listed in the API tokens file of the node are accepted: audited
+//! calls without a token or with an unknown one are rejected (and recorded
+//! without a caller), so that each recorded caller is a configured client.
+//! Neither tokens nor parameters are written to the log; their SHA-256
+//! hashes are recorded instead, so that the calls of a client (or the calls
+//! made with given parameters) can be found without the log disclosing them.
+//!
+//! The audit log is rotated in the same way as the log file.
+use std::{collections::HashSet, fs, path::Path, sync::Mutex};
+
+use log::warn;
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::{error::Error, logger::RotatingFile, util::now_millis, Result};
+
+/// The audit log, if enabled.
+static AUDIT_LOG: OnceCell<AuditLog> = OnceCell::new();
+
+/// The audit log file, along with the API tokens accepted from callers.
+struct AuditLog {
+    file: Mutex<RotatingFile>,
+    tokens: HashSet<String>,
+}
+
+/// The outcome of an audited call.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "outcome", content = "error", rename_all = "snake_case")]
+pub enum Outcome {
+    Ok,
+    Error(String),
+}
+
+/// An entry of the audit log.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AuditRecord {
+    /// Milliseconds since the UNIX epoch.
+    pub timestamp: u64,
+    pub method: String,
+    /// Hex-encoded SHA-256 hash of the API token of the caller, unless the
+    /// call was rejected.
+    pub caller: Option<String>,
+    /// Hex-encoded SHA-256 hash of the parameters of the call, if any.
+    pub params_hash: Option<String>,
+    #[serde(flatten)]
+    pub outcome: Outcome,
+}
+
+impl AuditRecord {
+    /// Create a record of a call of the given method, made with the given
+    /// (accepted) API token and raw (JSON-encoded) parameters.
+    pub fn new(
+        timestamp: u64,
+        method: &str,
+        caller: Option<&str>,
+        params: Option<&str>,
+        outcome: Outcome,
+    ) -> Self {
+        AuditRecord {
+            timestamp,
+            method: method.to_owned(),
+            caller: caller.map(hash),
+            params_hash: params.map(hash),
+            outcome,
+        }
+    }
+}
+
+/// Return the hex-encoded SHA-256 hash of the given string.
+fn hash(value: &str) -> String {
+    hex::encode(Sha256::digest(value.as_bytes()))
+}
+
+/// Return the API token passed as the `token` parameter of a call with the
+/// given raw parameters, if any.
+fn token_of(params: Option<&str>) -> Option<String> {
+    let params: Value = serde_json::from_str(params?).ok()?;
+    params.get("token")?.as_str().map(str::to_owned)
+}
+
+/// Read the API tokens listed in the file at the given path, one per line.
+/// Blank lines and lines starting with `#` are ignored.
+fn read_tokens(path: &Path) -> Result<HashSet<String>> {
+    let tokens: HashSet<String> = fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_owned)
+        .collect();
+    if tokens.is_empty() {
+        return Err(Error::Config(format!(
+            "No API tokens listed in {}",
+            path.display()
+        )));
+    }
+
+    Ok(tokens)
+}
+
+/// Enable the audit log, writing it to the given file, rotated once it
+/// exceeds the given size in bytes and keeping the given number of rotated
+/// files. Only the API tokens listed in the file at the given tokens path
+/// are accepted from the callers of audited methods.
+///
+/// May only be called once.
+pub fn init(path: &Path, max_size: Option<u64>, max_files: usize, tokens: &Path) -> Result<()> {
+    let tokens = read_tokens(tokens)?;
+    let file = RotatingFile::open(path, max_size, None, max_files)?;
+
+    AUDIT_LOG
+        .set(AuditLog {
+            file: Mutex::new(file),
+            tokens,
+        })
+        .map_err(|_| Error::Config("Audit log already initialised".to_string()))
+}
+
+/// Return the API token of the caller of an audited method with the given
+/// raw parameters, or an error if the audit log is enabled and the token is
+/// missing or unknown. Returns `None` if the audit log is disabled.
+pub fn authenticate(params: Option<&str>) -> Result<Option<String>> {
+    let audit_log = match AUDIT_LOG.get() {
+        Some(audit_log) => audit_log,
+        None => return Ok(None),
+    };
+
+    match token_of(params) {
+        Some(token) if audit_log.tokens.contains(&token) => Ok(Some(token)),
+        Some(_) => Err(Error::Other("Unknown API token".to_string())),
+        None => Err(Error::Other(
+            "An API token must be passed as the `token` parameter".to_string(),
+        )),
+    }
+}
+
+/// Append a record of a call of the given method, made with the given
+/// (accepted) API token and raw parameters, to the audit log, if enabled.
+pub fn record(method: &str, caller: Option<&str>, params: Option<&str>, outcome: Outcome) {
+    let audit_log = match AUDIT_LOG.get() {
+        Some(audit_log) => audit_log,
+        None => return,
+    };
+
+    let timestamp = now_millis();
+    let record = AuditRecord::new(timestamp, method, caller, params, outcome);
+
+    let line = match serde_json::to_string(&record) {
+        Ok(line) => line,
+        Err(err) => {
+            warn!("Failed to encode audit record: {}", err);
+            return;
+        }
+    };
+    let mut file = audit_log.file.lock().unwrap_or_else(|err| err.into_inner());
+    if let Err(err) = file.write_line(&line) {
+        warn!("Failed to write audit record: {}", err)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    fn test_audit_record() {
+        let params = r#"{"msg":{"type":"post","text":"hi"},"token":"client-a"}"#;
+        let caller = token_of(Some(params));
+        assert_eq!(caller.as_deref(), Some("client-a"));
+
+        let record = AuditRecord::new(1, "publish", caller.as_deref(), Some(params), Outcome::Ok);
+        assert_eq!(record.caller, Some(hash("client-a")));
+        assert_eq!(record.params_hash, Some(hash(params)));
+        assert_eq!(
+            serde_json::to_value(&record).unwrap(),
+            json!({
+                "timestamp": 1,
+                "method": "publish",
+                "caller": hash("client-a"),
+                "params_hash": hash(params),
+                "outcome": "ok",
+            })
+        );
+
+        // Positional parameters cannot carry a token.
+        let params = r#"["@abc=.ed25519"]"#;
+        assert_eq!(token_of(Some(params)), None);
+
+        // Rejected calls are recorded without a caller.
+        let record = AuditRecord::new(
+            2,
+            "pin_feed",
+            None,
+            Some(params),
+            Outcome::Error("Unknown API token".to_string()),
+        );
+        assert_eq!(record.caller, None);
+        assert_eq!(
+            serde_json::to_value(&record).unwrap()["error"],
+            json!("Unknown API token")
+        );
+
+        let record = AuditRecord::new(3, "clear_faults", Some("client-a"), None, Outcome::Ok);
+        assert_eq!(record.params_hash, None);
+    }
+
+    #[test]
+    fn test_read_tokens() -> Result<()> {
+        let dir = tempdir::TempDir::new("solaraudit")?;
+        let path = dir.path().join("tokens");
+
+        fs::write(&path, "# Clients\nclient-a\n\n  client-b  \n")?;
+        let tokens = read_tokens(&path)?;
+        assert_eq!(tokens.len(), 2);
+        assert!(tokens.contains("client-a") && tokens.contains("client-b"));
+
+        // A tokens file listing no tokens would reject every audited call.
+        fs::write(&path, "# No clients yet\n")?;
+        assert!(read_tokens(&path).is_err());
+
+        Ok(())
+    }
+}
@@ -1,4 +1,7 @@
-use std::net::{IpAddr, Ipv4Addr};
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    path::PathBuf,
+};
 
 #[derive(Debug, Clone)]
 pub struct JsonRpcConfig {
@@ -10,6 +13,44 @@ pub struct JsonRpcConfig {
 
     /// Port to bind for JSON-RPC server (default: 3030).
     pub port: u16,
+
+    /// Serve the web dashboard, which requires the JSON-RPC server
+    /// (default: false).
+    pub dashboard: bool,
+
+    /// Port to bind for the web dashboard, on the JSON-RPC IP
+    /// (default: 3031).
+    pub dashboard_port: u16,
+
+    /// Serve the public web viewer of feeds and threads (default: false).
+    pub viewer: bool,
+
+    /// IP to bind for the public web viewer (default: 0.0.0.0).
+    pub viewer_ip: IpAddr,
+
+    /// Port to bind for the public web viewer (default: 3032).
+    pub viewer_port: u16,
+
+    /// Record the state-changing JSON-RPC calls in an audit log at the given
+    /// path (default: none).
+    pub audit_log: Option<PathBuf>,
+
+    /// Rotate the audit log once it exceeds the given size in bytes
+    /// (default: 10 MiB).
+    pub audit_log_max_size: Option<u64>,
+
+    /// Number of rotated audit log files to keep (default: 5).
+    pub audit_log_max_files: usize,
+
+    /// Path of the file listing the API tokens (one per line) accepted from
+    /// the callers of the state-changing JSON-RPC calls, required by the
+    /// audit log (default: none).
+    pub api_tokens: Option<PathBuf>,
+
+    /// Filter the results of the JSON-RPC queries returning messages by the
+    /// filter profiles defined in the TOML file at the given path
+    /// (default: none).
+    pub filters: Option<PathBuf>,
 }
 
 impl Default for JsonRpcConfig {
@@ -18,6 +59,16 @@ impl Default for JsonRpcConfig {
             server: true,
             ip: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
             port: 3030,
+            dashboard: false,
+            dashboard_port: 3031,
+            viewer: false,
+            viewer_ip: IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
+            viewer_port: 3032,
+            audit_log: None,
+            audit_log_max_size: Some(10 << 20),
+            audit_log_max_files: 5,
+            api_tokens: None,
+            filters: None,
         }
     }
 }
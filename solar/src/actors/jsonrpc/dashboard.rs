@@ -0,0 +1,417 @@
+//! Web dashboard.
+//!
+//! An optional HTTP endpoint serving a single-page dashboard, so that
+//! operators can keep an eye on a pub from a browser: connections,
+//! replication progress, storage usage and recent log lines.
+//!
+//! The dashboard serves its static assets (embedded in the binary) and
+//! forwards `POST /rpc` requests to the JSON-RPC server, which the page
+//! polls for the data it shows. Since the dashboard thereby exposes the
+//! whole JSON-RPC API, it should only be reachable by the operator (it binds
+//! to the JSON-RPC IP, which defaults to the loopback interface).
+//!
+//! Since a web page open in the same browser may send requests to the
+//! dashboard as well, requests are only served if their `Host` (and
+//! `Origin`, if any) is the address of the dashboard, which defeats DNS
+//! rebinding. `POST /rpc` requests must moreover be JSON (so that they
+//! cannot be sent by a plain form) and carry the token generated when the
+//! dashboard is started, which is embedded in the page it serves.
+
+use std::{net::SocketAddr, time::Duration};
+
+use async_std::{
+    io::{self, ReadExt, WriteExt},
+    net::{TcpListener, TcpStream},
+};
+use futures::{select_biased, stream::StreamExt, FutureExt};
+use log::{debug, info, warn};
+
+use crate::{broker::*, Result};
+
+/// The dashboard page.
+const INDEX_HTML: &str = include_str!("../../../assets/dashboard/index.html");
+
+/// The dashboard script.
+const DASHBOARD_JS: &str = include_str!("../../../assets/dashboard/dashboard.js");
+
+/// The dashboard stylesheet.
+const DASHBOARD_CSS: &str = include_str!("../../../assets/dashboard/dashboard.css");
+
+/// Maximum size of the head (request line and headers) of a request.
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+
+/// Maximum size of the body of a request.
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+/// Header carrying the token of the dashboard.
+const TOKEN_HEADER: &str = "x-dashboard-token";
+
+/// Placeholder for the token of the dashboard in the dashboard page.
+const TOKEN_PLACEHOLDER: &str = "{{token}}";
+
+/// Maximum duration of the reception of a request.
+pub(super) const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The request line and headers of an HTTP request.
+#[derive(Debug, PartialEq, Eq)]
+pub(super) struct RequestHead {
+    pub(super) method: String,
+    /// Path of the request, without the query string.
+    pub(super) path: String,
+    /// Value of the `Host` header, if any.
+    pub(super) host: Option<String>,
+    /// Value of the `X-Forwarded-Proto` header set by reverse proxies, if
+    /// any.
+    pub(super) forwarded_proto: Option<String>,
+    /// Value of the `Origin` header, if any.
+    origin: Option<String>,
+    /// Value of the `Content-Type` header, if any.
+    content_type: Option<String>,
+    /// Value of the header carrying the token of the dashboard, if any.
+    token: Option<String>,
+    content_length: usize,
+    /// Length of the head, including the blank line which ends it.
+    len: usize,
+}
+
+/// An HTTP response.
+pub(super) struct Response {
+    status: u16,
+    reason: &'static str,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    pub(super) fn ok(content_type: &'static str, body: impl Into<Vec<u8>>) -> Self {
+        Response {
+            status: 200,
+            reason: "OK",
+            content_type,
+            body: body.into(),
+        }
+    }
+
+    pub(super) fn error(status: u16, reason: &'static str) -> Self {
+        Response {
+            status,
+            reason,
+            content_type: "text/plain; charset=utf-8",
+            body: reason.as_bytes().to_vec(),
+        }
+    }
+
+    pub(super) fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+             Cache-Control: no-store\r\nConnection: close\r\n\r\n",
+            self.status,
+            self.reason,
+            self.content_type,
+            self.body.len()
+        )
+        .into_bytes();
+        bytes.extend_from_slice(&self.body);
+
+        bytes
+    }
+}
+
+/// Parse the head of an HTTP request from the start of the given buffer.
+///
+/// Returns `None` if the buffer does not yet hold the whole head, or if the
+/// head is malformed.
+fn parse_head(buf: &[u8]) -> Option<RequestHead> {
+    let end = buf.windows(4).position(|window| window == b"\r\n\r\n")?;
+    let head = std::str::from_utf8(&buf[..end]).ok()?;
+    let mut lines = head.split("\r\n");
+
+    let mut request_line = lines.next()?.split(' ');
+    let method = request_line.next()?.to_owned();
+    let target = request_line.next()?;
+    let path = target.split('?').next()?.to_owned();
+
+    let mut host = None;
+    let mut forwarded_proto = None;
+    let mut origin = None;
+    let mut content_type = None;
+    let mut token = None;
+    let mut content_length = 0;
+    for line in lines {
+        if let Some((name, value)) = line.split_once(':') {
+            let (name, value) = (name.trim(), value.trim());
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.parse().ok()?;
+            } else if name.eq_ignore_ascii_case("host") {
+                host = Some(value.to_owned());
+            } else if name.eq_ignore_ascii_case("x-forwarded-proto") {
+                forwarded_proto = Some(value.to_owned());
+            } else if name.eq_ignore_ascii_case("origin") {
+                origin = Some(value.to_owned());
+            } else if name.eq_ignore_ascii_case("content-type") {
+                content_type = Some(value.to_owned());
+            } else if name.eq_ignore_ascii_case(TOKEN_HEADER) {
+                token = Some(value.to_owned());
+            }
+        }
+    }
+
+    Some(RequestHead {
+        method,
+        path,
+        host,
+        forwarded_proto,
+        origin,
+        content_type,
+        token,
+        content_length,
+        len: end + 4,
+    })
+}
+
+/// Check whether the `Host` and `Origin` (if any) of the given request name
+/// the given local address of the dashboard (or `localhost`, if it is a
+/// loopback address).
+fn is_same_origin(head: &RequestHead, local_addr: SocketAddr) -> bool {
+    let is_local = |host: &str| {
+        host == local_addr.to_string()
+            || (local_addr.ip().is_loopback() && host == format!("localhost:{}", local_addr.port()))
+    };
+
+    match (&head.host, &head.origin) {
+        (Some(host), None) => is_local(host),
+        (Some(host), Some(origin)) => {
+            is_local(host) && origin.strip_prefix("http://") == Some(host.as_str())
+        }
+        (None, _) => false,
+    }
+}
+
+/// Check whether the body of the given request is declared to be JSON.
+fn is_json(head: &RequestHead) -> bool {
+    head.content_type
+        .as_deref()
+        .and_then(|content_type| content_type.split(';').next())
+        .map(|media_type| media_type.trim().eq_ignore_ascii_case("application/json"))
+        .unwrap_or(false)
+}
+
+/// Read a request from the given stream, returning its head and body.
+/// Returns `None` if the connection is closed or the request is malformed
+/// or too large.
+pub(super) async fn read_request(
+    stream: &mut TcpStream,
+) -> io::Result<Option<(RequestHead, Vec<u8>)>> {
+    let mut buf = Vec::new();
+    let mut chunk = [0; 4096];
+
+    let head = loop {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        buf.extend_from_slice(&chunk[..n]);
+
+        if let Some(head) = parse_head(&buf) {
+            break head;
+        }
+        if buf.len() > MAX_HEAD_SIZE {
+            return Ok(None);
+        }
+    };
+    if head.content_length > MAX_BODY_SIZE {
+        return Ok(None);
+    }
+
+    let mut body = buf.split_off(head.len);
+    while body.len() < head.content_length {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        body.extend_from_slice(&chunk[..n]);
+    }
+    body.truncate(head.content_length);
+
+    Ok(Some((head, body)))
+}
+
+/// Forward the given JSON-RPC request body to the JSON-RPC server at the
+/// given URL.
+async fn forward(client: &reqwest::Client, rpc_url: &str, body: Vec<u8>) -> Response {
+    let result = client
+        .post(rpc_url)
+        .header("Content-Type", "application/json")
+        .body(body)
+        .send()
+        .await;
+
+    match result {
+        Ok(res) => match res.bytes().await {
+            Ok(bytes) => Response::ok("application/json", bytes.to_vec()),
+            Err(err) => {
+                warn!("Failed to read JSON-RPC response: {}", err);
+                Response::error(502, "Bad Gateway")
+            }
+        },
+        Err(err) => {
+            warn!("Failed to forward request to the JSON-RPC server: {}", err);
+            Response::error(502, "Bad Gateway")
+        }
+    }
+}
+
+/// Serve a single request on the given stream, only forwarding JSON-RPC
+/// requests carrying the given token.
+async fn serve(
+    mut stream: TcpStream,
+    client: reqwest::Client,
+    rpc_url: String,
+    token: String,
+) -> Result<()> {
+    let (head, body) = match io::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await? {
+        Some(request) => request,
+        None => return Ok(()),
+    };
+    debug!("Dashboard request: {} {}", head.method, head.path);
+
+    let response = if !is_same_origin(&head, stream.local_addr()?) {
+        Response::error(403, "Forbidden")
+    } else {
+        match (head.method.as_str(), head.path.as_str()) {
+            ("GET", "/") | ("GET", "/index.html") => Response::ok(
+                "text/html; charset=utf-8",
+                INDEX_HTML.replace(TOKEN_PLACEHOLDER, &token),
+            ),
+            ("GET", "/dashboard.js") => {
+                Response::ok("text/javascript; charset=utf-8", DASHBOARD_JS)
+            }
+            ("GET", "/dashboard.css") => Response::ok("text/css; charset=utf-8", DASHBOARD_CSS),
+            ("POST", "/rpc") if head.token.as_deref() != Some(token.as_str()) => {
+                Response::error(403, "Forbidden")
+            }
+            ("POST", "/rpc") if !is_json(&head) => Response::error(415, "Unsupported Media Type"),
+            ("POST", "/rpc") => forward(&client, &rpc_url, body).await,
+            _ => Response::error(404, "Not Found"),
+        }
+    };
+
+    stream.write_all(&response.to_bytes()).await?;
+    stream.flush().await?;
+
+    Ok(())
+}
+
+/// Serve the dashboard on the given address, forwarding JSON-RPC requests to
+/// the JSON-RPC server on the given address.
+pub async fn actor(addr: SocketAddr, jsonrpc_addr: SocketAddr) -> Result<()> {
+    let broker = BROKER.lock().await.register("dashboard", false).await?;
+
+    let mut ch_terminate = broker.ch_terminate.fuse();
+
+    let listener = TcpListener::bind(addr).await?;
+    let mut incoming = listener.incoming();
+    info!("Dashboard started on: http://{}", listener.local_addr()?);
+
+    let client = reqwest::Client::new();
+    let rpc_url = format!("http://{}", jsonrpc_addr);
+    // The token is generated anew each time the dashboard is started, so the
+    // page must be reloaded after a restart.
+    let token = hex::encode(rand::random::<[u8; 32]>());
+
+    loop {
+        select_biased! {
+            _ = ch_terminate => break,
+            stream = incoming.next().fuse() => {
+                match stream {
+                    Some(Ok(stream)) => {
+                        Broker::spawn(
+                            "dashboard-request",
+                            serve(stream, client.clone(), rpc_url.to_owned(), token.clone()),
+                        );
+                    }
+                    Some(Err(err)) => warn!("Failed to accept dashboard connection: {}", err),
+                    None => break,
+                }
+            },
+        }
+    }
+
+    let _ = broker.ch_terminated.send(Void {});
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_head() {
+        let request = b"POST /rpc?poll=1 HTTP/1.1\r\nHost: localhost\r\n\
+            content-length: 42\r\n\r\n{\"jsonrpc\"";
+        assert_eq!(
+            parse_head(request),
+            Some(RequestHead {
+                method: "POST".to_string(),
+                path: "/rpc".to_string(),
+                host: Some("localhost".to_string()),
+                forwarded_proto: None,
+                origin: None,
+                content_type: None,
+                token: None,
+                content_length: 42,
+                len: 66,
+            })
+        );
+
+        let request = b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let head = parse_head(request).unwrap();
+        assert_eq!((head.method.as_str(), head.path.as_str()), ("GET", "/"));
+        assert_eq!(head.content_length, 0);
+
+        // The head is incomplete.
+        assert_eq!(parse_head(b"GET / HTTP/1.1\r\nHost: localhost\r\n"), None);
+        // The content length is invalid.
+        assert_eq!(
+            parse_head(b"POST /rpc HTTP/1.1\r\nContent-Length: many\r\n\r\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_request_checks() {
+        let local_addr: SocketAddr = "127.0.0.1:3031".parse().unwrap();
+        let head = |headers: &str| {
+            parse_head(format!("POST /rpc HTTP/1.1\r\n{headers}\r\n").as_bytes()).unwrap()
+        };
+
+        assert!(is_same_origin(
+            &head("Host: 127.0.0.1:3031\r\n"),
+            local_addr
+        ));
+        assert!(is_same_origin(
+            &head("Host: localhost:3031\r\nOrigin: http://localhost:3031\r\n"),
+            local_addr
+        ));
+        // DNS rebinding.
+        assert!(!is_same_origin(
+            &head("Host: evil.example:3031\r\n"),
+            local_addr
+        ));
+        // Cross-origin request.
+        assert!(!is_same_origin(
+            &head("Host: 127.0.0.1:3031\r\nOrigin: http://evil.example\r\n"),
+            local_addr
+        ));
+        assert!(!is_same_origin(&head(""), local_addr));
+
+        let request = head("Content-Type: application/json; charset=utf-8\r\n");
+        assert!(is_json(&request));
+        assert!(!is_json(&head("Content-Type: text/plain\r\n")));
+        assert!(!is_json(&head("")));
+
+        let request = head("X-Dashboard-Token: abc\r\n");
+        assert_eq!(request.token.as_deref(), Some("abc"));
+    }
+}
@@ -0,0 +1,294 @@
+//! JSON-RPC filter profiles.
+//!
+//! Nodes serving several clients (for example, a family-friendly deployment)
+//! can enforce a content policy centrally: messages matching the filter
+//! profile of a client are left out of the results of the JSON-RPC queries
+//! returning messages (`feed`, `message`, `latest_message`, `timeline`,
+//! `notifications`, `preview_feed` and `fetch_message`).
+//!
+//! Profiles are defined in a TOML file, which also assigns a profile to each
+//! API token and, optionally, a default profile applied to calls without an
+//! assigned token:
+//!
+//! ```toml
+//! default = "family"
+//!
+//! [profiles.family]
+//! hide_content_warnings = true
+//! hide_tags = ["nsfw"]
+//! hide_blocked = true
+//!
+//! [tokens]
+//! "kitchen-tablet" = "family"
+//! ```
+//!
+//! As with the audit log, callers identify themselves by passing their API
+//! token as the `token` parameter of any call.
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::Path,
+};
+
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{error::Error, storage::kv::KvStorage, Result};
+
+/// The filter profiles, if enabled.
+static FILTERS: OnceCell<FilterConfig> = OnceCell::new();
+
+/// Rules by which messages are hidden from a client.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct FilterProfile {
+    /// Hide messages with a content warning (default: false).
+    pub hide_content_warnings: bool,
+
+    /// Hide messages posted in or tagged with any of the given channels or
+    /// hashtags, with or without the leading `#` (default: none).
+    pub hide_tags: Vec<String>,
+
+    /// Hide messages authored by feeds blocked by the local identity,
+    /// including their mentions of it (default: false).
+    pub hide_blocked: bool,
+}
+
+/// The filter profiles and the API tokens to which they are assigned.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct FilterConfig {
+    /// Name of the profile applied to calls without an assigned token.
+    #[serde(default)]
+    pub default: Option<String>,
+
+    /// Filter profiles, keyed by name.
+    #[serde(default)]
+    pub profiles: HashMap<String, FilterProfile>,
+
+    /// Name of the profile assigned to each API token.
+    #[serde(default)]
+    pub tokens: HashMap<String, String>,
+}
+
+impl FilterConfig {
+    /// Deserialize and validate a TOML string slice into filter profiles.
+    fn from_toml(serialized_config: &str) -> Result<Self> {
+        let config = toml::from_str::<FilterConfig>(serialized_config)?;
+
+        for name in config.default.iter().chain(config.tokens.values()) {
+            if !config.profiles.contains_key(name) {
+                return Err(Error::Config(format!("Unknown filter profile: {}", name)));
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Return the profile applied to a call with the given API token.
+    fn profile(&self, token: Option<&str>) -> Option<&FilterProfile> {
+        let name = token
+            .and_then(|token| self.tokens.get(token))
+            .or(self.default.as_ref())?;
+
+        self.profiles.get(name)
+    }
+}
+
+impl FilterProfile {
+    /// Query whether the given message value is hidden by the profile, given
+    /// the feeds blocked by the local identity.
+    fn hides(&self, msg_value: &Value, blocked: &HashSet<String>) -> bool {
+        if self.hide_blocked {
+            let author = msg_value.get("author").and_then(Value::as_str);
+            if author.map_or(false, |author| blocked.contains(author)) {
+                return true;
+            }
+        }
+
+        // The content of private messages is an encrypted string, which is
+        // left alone.
+        let content = match msg_value.get("content") {
+            Some(content) if content.is_object() => content,
+            _ => return false,
+        };
+
+        if self.hide_content_warnings {
+            let warning = content.get("contentWarning").and_then(Value::as_str);
+            if warning.map_or(false, |warning| !warning.trim().is_empty()) {
+                return true;
+            }
+        }
+
+        tags(content).any(|tag| {
+            self.hide_tags
+                .iter()
+                .any(|hidden| hidden.trim_start_matches('#').eq_ignore_ascii_case(tag))
+        })
+    }
+}
+
+/// Return the channel of the given message content, along with the
+/// hashtags it mentions, without the leading `#`.
+fn tags(content: &Value) -> impl Iterator<Item = &str> {
+    let channel = content.get("channel").and_then(Value::as_str);
+    let hashtags = content
+        .get("mentions")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(|mention| mention.get("link").and_then(Value::as_str))
+        .filter(|link| link.starts_with('#'));
+
+    channel
+        .into_iter()
+        .chain(hashtags)
+        .map(|tag| tag.trim_start_matches('#'))
+}
+
+/// The filter profile applied to the results of a call, if any, along with
+/// the feeds blocked by the local identity.
+#[derive(Default)]
+pub struct Filter {
+    profile: Option<&'static FilterProfile>,
+    blocked: HashSet<String>,
+}
+
+impl Filter {
+    /// Return the filter applied to the results of a call with the given raw
+    /// (JSON-encoded) parameters.
+    pub fn for_call(params: Option<&str>, db: &KvStorage) -> Result<Self> {
+        let token = params
+            .and_then(|params| serde_json::from_str::<Value>(params).ok())
+            .and_then(|params| {
+                params
+                    .get("token")
+                    .and_then(Value::as_str)
+                    .map(str::to_owned)
+            });
+        let profile = match FILTERS
+            .get()
+            .and_then(|filters| filters.profile(token.as_deref()))
+        {
+            Some(profile) => profile,
+            None => return Ok(Filter::default()),
+        };
+
+        let blocked = if profile.hide_blocked {
+            let indexes = db.indexes.as_ref().ok_or(Error::Indexes)?;
+            match indexes.local_id() {
+                Some(local_id) => indexes.get_blocks(local_id)?,
+                None => HashSet::new(),
+            }
+        } else {
+            HashSet::new()
+        };
+
+        Ok(Filter {
+            profile: Some(profile),
+            blocked,
+        })
+    }
+
+    /// Query whether the given message value is hidden by the filter.
+    pub fn hides(&self, msg_value: &Value) -> bool {
+        self.profile
+            .map_or(false, |profile| profile.hides(msg_value, &self.blocked))
+    }
+
+    /// Query whether the messages of the given author are hidden by the
+    /// filter, since the author is blocked by the local identity.
+    pub fn hides_author(&self, author: &str) -> bool {
+        // Blocked feeds are only looked up for profiles hiding them.
+        self.blocked.contains(author)
+    }
+}
+
+/// Enable the filter profiles, reading them from the given TOML file.
+///
+/// May only be called once.
+pub fn init(path: &Path) -> Result<()> {
+    let config = FilterConfig::from_toml(&fs::read_to_string(path)?)?;
+
+    FILTERS
+        .set(config)
+        .map_err(|_| Error::Config("Filter profiles already initialised".to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    fn test_filter_config() -> Result<()> {
+        let config = FilterConfig::from_toml(
+            r#"
+            default = "family"
+
+            [profiles.family]
+            hide_content_warnings = true
+            hide_tags = ["nsfw"]
+
+            [profiles.open]
+
+            [tokens]
+            "grown-up" = "open"
+            "#,
+        )?;
+
+        assert_eq!(
+            config.profile(Some("grown-up")),
+            Some(&FilterProfile::default())
+        );
+        assert_eq!(config.profile(Some("unknown")), config.profile(None));
+        assert!(config.profile(None).unwrap().hide_content_warnings);
+
+        // Profiles must be defined before being assigned.
+        assert!(FilterConfig::from_toml("default = \"missing\"").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_hides() {
+        let profile = FilterProfile {
+            hide_content_warnings: true,
+            hide_tags: vec!["nsfw".to_string()],
+            hide_blocked: true,
+        };
+        let blocked: HashSet<String> = ["@troll".to_string()].into();
+
+        let post = |author: &str, content: Value| json!({ "author": author, "content": content });
+
+        assert!(!profile.hides(
+            &post("@a", json!({ "type": "post", "text": "hi" })),
+            &blocked
+        ));
+        assert!(profile.hides(&post("@troll", json!({ "type": "post" })), &blocked));
+        assert!(profile.hides(
+            &post(
+                "@a",
+                json!({ "type": "post", "contentWarning": "spoilers" })
+            ),
+            &blocked
+        ));
+        assert!(!profile.hides(
+            &post("@a", json!({ "type": "post", "contentWarning": " " })),
+            &blocked
+        ));
+        assert!(profile.hides(
+            &post("@a", json!({ "type": "post", "channel": "NSFW" })),
+            &blocked
+        ));
+        assert!(profile.hides(
+            &post(
+                "@a",
+                json!({ "type": "post", "mentions": [{ "link": "#nsfw" }] })
+            ),
+            &blocked
+        ));
+        assert!(!profile.hides(&post("@a", json!("encrypted.box")), &blocked));
+    }
+}
@@ -1,2 +1,7 @@
+pub mod audit;
 pub mod config;
+pub mod dashboard;
+pub mod filter;
 pub mod server;
+pub mod syndication;
+pub mod viewer;
@@ -3,15 +3,71 @@
 use std::net::SocketAddr;
 
 use async_std::task;
-use futures::FutureExt;
-use jsonrpsee::server::{logger::Params, RpcModule, ServerBuilder};
+use futures::{pin_mut, select_biased, FutureExt, SinkExt, StreamExt};
+use jsonrpsee::core::SubscriptionResult;
+use jsonrpsee::server::{
+    logger::Params, PendingSubscriptionSink, RpcModule, ServerBuilder, SubscriptionMessage,
+};
 use jsonrpsee::types::error::ErrorObject as JsonRpcError;
-use kuska_ssb::{api::dto::content::TypedMessage, feed::Message, keystore::OwnedIdentity};
+use kuska_ssb::{
+    api::dto::content::TypedMessage,
+    crypto::ToSsbId,
+    feed::{Feed as MessageKvt, Message},
+    keystore::OwnedIdentity,
+};
 use log::{info, warn};
 use serde::Deserialize;
 use serde_json::{json, Value};
 
-use crate::{broker::*, error::Error, node::KV_STORE, Result};
+use crate::{
+    actors::{
+        follow_back,
+        jsonrpc::{
+            audit::{self, Outcome},
+            filter::Filter,
+        },
+        muxrpc,
+        network::{
+            connection_manager::{ConnectionManager, ReplicationStrategy, CONNECTION_MANAGER},
+            connection_scheduler::ScheduleRequest,
+            multiserver, reputation, room_invite, stats,
+        },
+        outbox,
+        replication::{blob_events::BlobEvent, ebt::query, trace},
+        retention::pressure,
+    },
+    broker::*,
+    config::MIRROR_MODE,
+    error::Error,
+    logger,
+    node::{BLOB_STORE, KV_STORE},
+    ssb_uri,
+    storage::{
+        blob::StoreBlobEvent,
+        indexes::TimelineOrder,
+        kv::OutboxEntry,
+        mutes::MutePattern,
+        publish::{self, Expected},
+        validation,
+    },
+    util::prefixed,
+    Result,
+};
+
+#[cfg(feature = "fault-injection")]
+use crate::actors::network::fault::{Fault, FAULT_INJECTOR};
+
+/// Default number of days returned by the `network_stats` method.
+const NETWORK_STATS_DAYS: u64 = 30;
+
+/// Default number of log lines returned by the `recent_logs` method.
+const RECENT_LOGS_LIMIT: usize = 100;
+
+/// Default number of messages returned by the `preview_feed` method.
+const PREVIEW_LIMIT: u64 = 20;
+
+/// Default number of messages returned by the `timeline` method.
+const TIMELINE_PAGE_LIMIT: usize = 20;
 
 /// The name of a channel.
 #[derive(Debug, Deserialize)]
@@ -19,17 +75,100 @@ struct Channel {
     channel: String,
 }
 
+/// The API token of a client, namespacing the state kept for the client.
+#[derive(Debug, Deserialize)]
+struct ClientToken {
+    token: String,
+}
+
 /// The public keys (ID) of two peers.
 #[derive(Debug, Deserialize)]
 struct IsFollowing {
+    #[serde(deserialize_with = "ssb_uri::deserialize_link")]
     peer_a: String,
+    #[serde(deserialize_with = "ssb_uri::deserialize_link")]
     peer_b: String,
 }
 
-/// The contents of a raw message (of any supported type).
+/// A fault to be injected into outbound MUXRPC packets, along with the
+/// number of packets to which it should be applied.
+#[cfg(feature = "fault-injection")]
+#[derive(Debug, Deserialize)]
+struct InjectFault {
+    #[serde(flatten)]
+    fault: Fault,
+    count: Option<usize>,
+    /// Public key of the peer to whose packets the fault should be applied
+    /// (any peer if `None`).
+    peer: Option<String>,
+}
+
+/// The public address (host and port) at which the local node is reachable
+/// over TCP.
+#[derive(Debug, Deserialize)]
+struct PubAddress {
+    host: String,
+    port: u16,
+}
+
+/// An HTTP room invite (`https://<room>/join?token=<token>`).
+#[derive(Debug, Deserialize)]
+struct Invite {
+    invite: String,
+}
+
+/// A log level, along with the target to which it applies. The default
+/// level is set if no target is given.
+#[derive(Debug, Deserialize)]
+struct LogLevel {
+    target: Option<String>,
+    level: String,
+}
+
+/// Message references of the notifications to be marked as read.
+#[derive(Debug, Deserialize)]
+struct MarkNotificationsRead {
+    msg_refs: Option<Vec<String>>,
+}
+
+/// The API token of a client, along with the reference of a message to be
+/// marked as read by the client.
+#[derive(Debug, Deserialize)]
+struct MarkRead {
+    token: String,
+    #[serde(deserialize_with = "ssb_uri::deserialize_link")]
+    msg_ref: String,
+}
+
+/// The public key (ID) of a traced feed, optionally with the sequence number
+/// of the message whose trace is returned.
+#[derive(Debug, Deserialize)]
+struct MessageTrace {
+    #[serde(deserialize_with = "ssb_uri::deserialize_link")]
+    pub_key: String,
+    sequence: Option<u64>,
+}
+
+/// A feed, message or blob reference, given as a sigil link or an SSB URI.
+#[derive(Debug, Deserialize)]
+struct Link {
+    link: String,
+}
+
+/// The contents of a raw message (of any supported type), optionally with
+/// the expected ID of the latest message of the local feed or the expected
+/// sequence number of the published message.
 #[derive(Debug, Deserialize)]
 struct Msg {
     msg: TypedMessage,
+    previous: Option<String>,
+    sequence: Option<u64>,
+}
+
+/// A message signed by a client on behalf of a remote signer.
+#[derive(Debug, Deserialize)]
+struct SignedMsg {
+    msg: Value,
 }
 
 /// Message reference containing the key (sha256 hash) of a message.
@@ -37,36 +176,228 @@ struct Msg {
 /// endpoint.
 #[derive(Debug, Deserialize)]
 struct MsgRef {
+    #[serde(deserialize_with = "ssb_uri::deserialize_link")]
     msg_ref: String,
 }
 
+/// Network statistics query options.
+#[derive(Debug, Deserialize)]
+struct NetworkStatsQuery {
+    days: Option<u64>,
+}
+
+/// Notification query options.
+#[derive(Debug, Deserialize)]
+struct Notifications {
+    unread_only: Option<bool>,
+}
+
+/// The ID of an outbox entry.
+#[derive(Debug, Deserialize)]
+struct OutboxEntryId {
+    id: u64,
+}
+
+/// The contents of a raw message (of any supported type) to be added to the
+/// outbox, optionally with the time at which it is scheduled to be published
+/// (in milliseconds since the UNIX epoch). Without a time, the message is
+/// added as a draft.
+#[derive(Debug, Deserialize)]
+struct OutboxMsg {
+    msg: TypedMessage,
+    publish_at: Option<u64>,
+}
+
+/// The ID of an outbox entry, along with the message and the scheduled time
+/// replacing those of the entry.
+#[derive(Debug, Deserialize)]
+struct OutboxEdit {
+    id: u64,
+    msg: TypedMessage,
+    publish_at: Option<u64>,
+}
+
+/// Timeline pagination options.
+#[derive(Debug, Deserialize)]
+struct Timeline {
+    cursor: Option<String>,
+    limit: Option<usize>,
+    order: Option<TimelineOrder>,
+}
+
+/// The public key (ID) of a feed to be previewed, optionally with the
+/// connected peer from which it is fetched and the number of messages.
+#[derive(Debug, Deserialize)]
+struct PreviewFeed {
+    #[serde(deserialize_with = "ssb_uri::deserialize_link")]
+    pub_key: String,
+    peer: Option<String>,
+    limit: Option<u64>,
+}
+
 /// The public key (ID) of a peer.
 #[derive(Debug, Deserialize)]
 struct PubKey {
+    #[serde(deserialize_with = "ssb_uri::deserialize_link")]
     pub_key: String,
 }
 
-/// Register the JSON-RPC server endpoint, define the JSON-RPC methods
-/// and spawn the server.
+/// The app name and key of a local record.
+#[derive(Debug, Deserialize)]
+struct RecordKey {
+    app: String,
+    key: String,
+}
+
+/// The app name, key and value (any JSON value) of a local record.
+#[derive(Debug, Deserialize)]
+struct RecordValue {
+    app: String,
+    key: String,
+    value: Value,
+}
+
+/// The app name of local records, optionally with a prefix to filter the
+/// records by key.
+#[derive(Debug, Deserialize)]
+struct RecordsQuery {
+    app: String,
+    prefix: Option<String>,
+}
+
+/// Recent log lines query options.
+#[derive(Debug, Deserialize)]
+struct RecentLogs {
+    limit: Option<usize>,
+}
+
+/// The public key (ID) of a peer, along with the replication strategy to be
+/// used in a new session with the peer.
+#[derive(Debug, Deserialize)]
+struct ReplicateNow {
+    #[serde(deserialize_with = "ssb_uri::deserialize_link")]
+    pub_key: String,
+    strategy: ReplicationStrategy,
+}
+
+/// Ensure the given client API token is not empty, since an empty token
+/// would share its state with any client which omits it.
+fn check_token(token: &str) -> Result<()> {
+    if token.is_empty() {
+        return Err(Error::Other("API token must not be empty".to_string()));
+    }
+
+    Ok(())
+}
+
+/// Return the error raised when the local records store has not been opened.
+fn records_unavailable() -> Error {
+    Error::LocalRecord("local records store is not open".to_string())
+}
+
+/// Serialize the given message KVT. A message whose content does not match
+/// the schema of its type is flagged with a `malformed` field giving the
+/// reason.
+fn annotated(msg_kvt: &MessageKvt) -> Value {
+    let mut value = json!(msg_kvt);
+    if let Some(failure) = validation::check_schema(&msg_kvt.value["content"]) {
+        value["malformed"] = json!(failure.reason);
+    }
+
+    value
+}
+
+/// Subscribe or unsubscribe the given identity to or from the given channel
+/// by publishing a `channel` message, unless the identity is already in the
+/// requested state.
 ///
-/// Listens for a termination signal from the broker. When received, the
-/// JSON-RPC server is closed and a terminated signal is sent to the broker.
-pub async fn actor(server_id: OwnedIdentity, server_addr: SocketAddr) -> Result<()> {
-    let broker = BROKER
-        .lock()
-        .await
-        .register("jsonrpc-listener", false)
-        .await?;
+/// Returns a tuple of the reference and sequence number of the published
+/// message, or `null` if no message was published.
+async fn set_channel_subscription(
+    identity: &OwnedIdentity,
+    channel: &str,
+    subscribed: bool,
+) -> Result<Value> {
+    // Channels are named without the leading `#`.
+    let channel = channel.trim_start_matches('#');
+    if channel.is_empty() {
+        return Err(Error::Other("Channel name must not be empty".to_string()));
+    }
 
-    let ch_terminate = broker.ch_terminate.fuse();
+    let is_subscribed = {
+        let db = KV_STORE.read().await;
+        let indexes = db.indexes.as_ref().ok_or(Error::Indexes)?;
+        indexes
+            .get_channel_subscriptions(&identity.id)?
+            .contains(channel)
+    };
+    if is_subscribed == subscribed {
+        return Ok(Value::Null);
+    }
 
-    let server = ServerBuilder::default()
-        .http_only()
-        .build(&server_addr)
-        .await?;
+    let content = TypedMessage::Channel {
+        channel: channel.to_owned(),
+        subscribed,
+    };
+    let (msg, seq) = publish::publish(identity, json!(content), None).await?;
+
+    Ok(json!((msg.id().to_string(), seq)))
+}
+
+/// Register the given method, recording its calls in the audit log (if
+/// enabled). While the audit log is enabled, calls which do not pass an
+/// accepted API token are rejected.
+fn register_audited<F>(
+    rpc_module: &mut RpcModule<()>,
+    method: &'static str,
+    callback: F,
+) -> Result<()>
+where
+    F: Fn(Params, &()) -> std::result::Result<Value, JsonRpcError> + Send + Sync + 'static,
+{
+    rpc_module.register_method(method, move |params: Params, ctx| {
+        let raw_params = params.as_str().map(str::to_owned);
+        let (caller, result) = match audit::authenticate(raw_params.as_deref()) {
+            Ok(caller) => (caller, callback(params, ctx)),
+            Err(err) => (None, Err(err.into())),
+        };
+
+        let outcome = match &result {
+            Ok(_) => Outcome::Ok,
+            Err(err) => Outcome::Error(err.message().to_owned()),
+        };
+        audit::record(method, caller.as_deref(), raw_params.as_deref(), outcome);
+
+        result
+    })?;
+
+    Ok(())
+}
 
+/// Define the JSON-RPC methods, returning a module which can be served over
+/// HTTP or called in-process.
+pub fn rpc_module(server_id: OwnedIdentity) -> Result<RpcModule<()>> {
     let mut rpc_module = RpcModule::new(());
 
+    // Retrieve the latest name, image and description for the given public
+    // key. Each field includes the most recent value assigned by any author
+    // (`latest`) and the most recent self-assigned value (`latest_self`).
+    //
+    // Returns an object.
+    rpc_module.register_method("about", move |params: Params, _| {
+        task::block_on(async {
+            let pub_key: PubKey = params.parse()?;
+
+            let db = KV_STORE.read().await;
+
+            let indexes = &db.indexes.as_ref().ok_or(Error::Indexes)?;
+            let about = indexes.get_about(&pub_key.pub_key)?;
+            let response = json!(about);
+
+            Ok::<Value, JsonRpcError>(response)
+        })
+    })?;
+
     // Retrieve the public keys of all feeds blocked by the given public key.
     //
     // Returns an array of public keys.
@@ -103,6 +434,24 @@ pub async fn actor(server_id: OwnedIdentity, server_addr: SocketAddr) -> Result<
         })
     })?;
 
+    // Retrieve the latest contact state (follow and block) of every peer
+    // about whom the given public key has published a contact message.
+    //
+    // Returns an object keyed by public key.
+    rpc_module.register_method("contacts", move |params: Params, _| {
+        task::block_on(async {
+            let pub_key: PubKey = params.parse()?;
+
+            let db = KV_STORE.read().await;
+
+            let indexes = &db.indexes.as_ref().ok_or(Error::Indexes)?;
+            let contacts = indexes.get_contacts(&pub_key.pub_key)?;
+            let response = json!(contacts);
+
+            Ok::<Value, JsonRpcError>(response)
+        })
+    })?;
+
     // Retrieve the descriptions for the given public key.
     //
     // Returns an array of descriptions.
@@ -380,7 +729,8 @@ pub async fn actor(server_id: OwnedIdentity, server_addr: SocketAddr) -> Result<
 
     // Retrieve the public keys of all feeds subscribed to the given channel.
     //
-    // Returns an array of public keys.
+    // Returns an array of public keys, which is empty if the channel is
+    // muted.
     rpc_module.register_method("subscribers", move |params: Params, _| {
         task::block_on(async {
             let channel: Channel = params.parse()?;
@@ -388,14 +738,18 @@ pub async fn actor(server_id: OwnedIdentity, server_addr: SocketAddr) -> Result<
             let db = KV_STORE.read().await;
 
             let indexes = &db.indexes.as_ref().ok_or(Error::Indexes)?;
-            let subscribers = indexes.get_channel_subscribers(&channel.channel)?;
+            let mut subscribers = indexes.get_channel_subscribers(&channel.channel)?;
+            if indexes.is_channel_muted(&channel.channel) {
+                subscribers.clear();
+            }
             let response = json!(subscribers);
 
             Ok::<Value, JsonRpcError>(response)
         })
     })?;
 
-    // Retrieve all channels to which the given public key is subscribed.
+    // Retrieve all channels to which the given public key is subscribed,
+    // except muted channels.
     //
     // Returns an array of channel names.
     rpc_module.register_method("subscriptions", move |params: Params, _| {
@@ -405,15 +759,310 @@ pub async fn actor(server_id: OwnedIdentity, server_addr: SocketAddr) -> Result<
             let db = KV_STORE.read().await;
 
             let indexes = &db.indexes.as_ref().ok_or(Error::Indexes)?;
-            let subscriptions = indexes.get_channel_subscriptions(&pub_key.pub_key)?;
+            let mut subscriptions = indexes.get_channel_subscriptions(&pub_key.pub_key)?;
+            subscriptions.retain(|channel| !indexes.is_channel_muted(channel));
             let response = json!(subscriptions);
 
             Ok::<Value, JsonRpcError>(response)
         })
     })?;
 
+    // Subscribe the local identity to the given channel by publishing a
+    // `channel` message.
+    //
+    // Returns a tuple of the reference and sequence number of the message,
+    // or `null` if the local identity is already subscribed.
+    let subscribe_identity = server_id.clone();
+    register_audited(
+        &mut rpc_module,
+        "subscribe_channel",
+        move |params: Params, _| {
+            task::block_on(async {
+                let channel: Channel = params.parse()?;
+                let response =
+                    set_channel_subscription(&subscribe_identity, &channel.channel, true).await?;
+
+                Ok::<Value, JsonRpcError>(response)
+            })
+        },
+    )?;
+
+    // Unsubscribe the local identity from the given channel by publishing a
+    // `channel` message.
+    //
+    // Returns a tuple of the reference and sequence number of the message,
+    // or `null` if the local identity is not subscribed.
+    let unsubscribe_identity = server_id.clone();
+    register_audited(
+        &mut rpc_module,
+        "unsubscribe_channel",
+        move |params: Params, _| {
+            task::block_on(async {
+                let channel: Channel = params.parse()?;
+                let response =
+                    set_channel_subscription(&unsubscribe_identity, &channel.channel, false)
+                        .await?;
+
+                Ok::<Value, JsonRpcError>(response)
+            })
+        },
+    )?;
+
+    // Convert the given feed, message or blob reference (sigil link or SSB
+    // URI) to both forms.
+    //
+    // Returns an object containing the sigil link and the SSB URI.
+    rpc_module.register_method("link", move |params: Params, _| {
+        let link: Link = params.parse()?;
+
+        let sigil = ssb_uri::to_sigil(&link.link)?;
+        let uri = ssb_uri::to_uri(&sigil)?;
+
+        Ok::<Value, JsonRpcError>(json!({ "sigil": sigil, "uri": uri }))
+    })?;
+
+    // Retrieve the public keys of all peers who like the given message.
+    //
+    // Returns an array of public keys.
+    rpc_module.register_method("likes", move |params: Params, _| {
+        task::block_on(async {
+            let msg_ref: MsgRef = params.parse()?;
+
+            let db = KV_STORE.read().await;
+
+            let indexes = &db.indexes.as_ref().ok_or(Error::Indexes)?;
+            let likes = indexes.get_likes(&msg_ref.msg_ref)?;
+            let response = json!(likes);
+
+            Ok::<Value, JsonRpcError>(response)
+        })
+    })?;
+
+    // Retrieve the latest vote cast by each voter on the given message.
+    //
+    // Returns an array of votes.
+    rpc_module.register_method("votes", move |params: Params, _| {
+        task::block_on(async {
+            let msg_ref: MsgRef = params.parse()?;
+
+            let db = KV_STORE.read().await;
+
+            let indexes = &db.indexes.as_ref().ok_or(Error::Indexes)?;
+            let votes = indexes.get_votes(&msg_ref.msg_ref)?;
+            let response = json!(votes);
+
+            Ok::<Value, JsonRpcError>(response)
+        })
+    })?;
+
+    // Retrieve the daily network statistics of the local node for the given
+    // number of most recent days (30 by default).
+    //
+    // Returns an array of daily statistics, ordered from oldest to newest.
+    rpc_module.register_method("network_stats", move |params: Params, _| {
+        task::block_on(async {
+            let query: Option<NetworkStatsQuery> = params.parse()?;
+            let days = query
+                .and_then(|query| query.days)
+                .unwrap_or(NETWORK_STATS_DAYS);
+
+            let daily_stats = stats::daily(days).await?;
+            let response = json!(daily_stats);
+
+            Ok::<Value, JsonRpcError>(response)
+        })
+    })?;
+
+    // Retrieve the reputation and score of each peer with recorded activity.
+    //
+    // Returns an array of peer scores, ordered from the highest to the
+    // lowest score.
+    rpc_module.register_method("peer_scores", move |_: Params, _| {
+        task::block_on(async {
+            let scores = reputation::scores().await?;
+            let response = json!(scores);
+
+            Ok::<Value, JsonRpcError>(response)
+        })
+    })?;
+
+    // Retrieve the notifications for the local identity (mentions and replies),
+    // omitting read notifications if `unread_only` is true.
+    //
+    // Returns an array of notifications, ordered from newest to oldest.
+    rpc_module.register_method("notifications", move |params: Params, _| {
+        task::block_on(async {
+            let notifications: Option<Notifications> = params.parse()?;
+            let unread_only = notifications
+                .and_then(|notifications| notifications.unread_only)
+                .unwrap_or(false);
+
+            let db = KV_STORE.read().await;
+            let filter = Filter::for_call(params.as_str(), &db)?;
+
+            let indexes = &db.indexes.as_ref().ok_or(Error::Indexes)?;
+            let mut notifications = Vec::new();
+            for notification in indexes.get_notifications(unread_only)? {
+                let hidden = filter.hides_author(&notification.author)
+                    || db
+                        .get_msg_val(&notification.msg_ref)?
+                        .map_or(false, |msg| filter.hides(&msg.value));
+                if !hidden {
+                    notifications.push(notification)
+                }
+            }
+            let response = json!(notifications);
+
+            Ok::<Value, JsonRpcError>(response)
+        })
+    })?;
+
+    // Mark the notifications for the given message references as read, or
+    // all notifications if no message references are given.
+    //
+    // Returns the number of notifications marked as read.
+    register_audited(
+        &mut rpc_module,
+        "mark_notifications_read",
+        move |params: Params, _| {
+            task::block_on(async {
+                let mark_read: Option<MarkNotificationsRead> = params.parse()?;
+                let msg_refs = mark_read
+                    .and_then(|mark_read| mark_read.msg_refs)
+                    .map(|msg_refs| {
+                        msg_refs
+                            .iter()
+                            .map(|msg_ref| ssb_uri::to_sigil(msg_ref))
+                            .collect::<Result<Vec<String>>>()
+                    })
+                    .transpose()?;
+
+                let db = KV_STORE.read().await;
+
+                let indexes = &db.indexes.as_ref().ok_or(Error::Indexes)?;
+                let marked = indexes.mark_notifications_read(msg_refs.as_deref())?;
+                let response = json!(marked);
+
+                Ok::<Value, JsonRpcError>(response)
+            })
+        },
+    )?;
+
+    // Mark the given message, and the messages before it in its feed, as
+    // read by the client with the given API token. Read state is kept
+    // separately for each token.
+    //
+    // Returns `false` if the message was already marked as read.
+    register_audited(&mut rpc_module, "mark_read", move |params: Params, _| {
+        task::block_on(async {
+            let mark_read: MarkRead = params.parse()?;
+            check_token(&mark_read.token)?;
+
+            let db = KV_STORE.read().await;
+            let marked = db.mark_read(&mark_read.token, &mark_read.msg_ref)?;
+            let response = json!(marked);
+
+            Ok::<Value, JsonRpcError>(response)
+        })
+    })?;
+
+    // Count the stored messages of each feed above the read mark of the
+    // client with the given API token.
+    //
+    // Returns an object mapping public keys to counts, omitting feeds
+    // without unread messages.
+    rpc_module.register_method("unread_counts", move |params: Params, _| {
+        task::block_on(async {
+            let client: ClientToken = params.parse()?;
+            check_token(&client.token)?;
+
+            let db = KV_STORE.read().await;
+            let unread_counts = db.get_unread_counts(&client.token).await?;
+            let response = json!(unread_counts);
+
+            Ok::<Value, JsonRpcError>(response)
+        })
+    })?;
+
+    // Retrieve a page of messages from all stored feeds, ordered from newest
+    // to oldest by claimed (default) or received timestamp. Pass the returned
+    // cursor to retrieve the next page. Muted messages are left out.
+    //
+    // Returns an object containing an array of message KVTs and a cursor.
+    rpc_module.register_method("timeline", move |params: Params, _| {
+        task::block_on(async {
+            let timeline: Option<Timeline> = params.parse()?;
+            let (cursor, limit, order) = match timeline {
+                Some(timeline) => (timeline.cursor, timeline.limit, timeline.order),
+                None => (None, None, None),
+            };
+
+            let db = KV_STORE.read().await;
+            let filter = Filter::for_call(params.as_str(), &db)?;
+
+            let indexes = &db.indexes.as_ref().ok_or(Error::Indexes)?;
+            let page = indexes.get_timeline(
+                order.unwrap_or(TimelineOrder::Claimed),
+                cursor.as_deref(),
+                limit.unwrap_or(TIMELINE_PAGE_LIMIT),
+            )?;
+
+            let mut messages = Vec::new();
+            for entry in page.entries {
+                if let Some(msg_kvt) = db.get_msg_kvt(&entry.author, entry.sequence)? {
+                    if !filter.hides(&msg_kvt.value) && !indexes.is_muted(&msg_kvt.value["content"])
+                    {
+                        messages.push(annotated(&msg_kvt))
+                    }
+                }
+            }
+            let response = json!({ "messages": messages, "cursor": page.cursor });
+
+            Ok::<Value, JsonRpcError>(response)
+        })
+    })?;
+
+    // Retrieve the outcome of recent dial attempts to the given peer.
+    // Returns an array of dial attempts, ordered from oldest to newest.
+    rpc_module.register_method("dial_history", move |params: Params, _| {
+        task::block_on(async {
+            // Parse the parameter containing the public key.
+            let pub_key: PubKey = params.parse()?;
+
+            let history = CONNECTION_MANAGER
+                .read()
+                .await
+                .dial_history(&pub_key.pub_key);
+            let response = json!(history);
+
+            Ok::<Value, JsonRpcError>(response)
+        })
+    })?;
+
+    // Retrieve the metrics of recent secret handshakes, inbound and outbound.
+    // Returns an array of handshakes, ordered from oldest to newest.
+    rpc_module.register_method("handshakes", move |_, _| {
+        task::block_on(async {
+            let handshakes = CONNECTION_MANAGER.read().await.handshakes();
+            let response = json!(handshakes);
+
+            Ok::<Value, JsonRpcError>(response)
+        })
+    })?;
+
+    // Retrieve the connections which are not yet closed and their states.
+    // Returns an array of connections, ordered by connection ID.
+    rpc_module.register_method("connections", move |_, _| {
+        task::block_on(async {
+            let connections = CONNECTION_MANAGER.read().await.connections();
+            let response = json!(connections);
+
+            Ok::<Value, JsonRpcError>(response)
+        })
+    })?;
+
     // Retrieve a feed by public key.
-    // Returns an array of messages as a KVTs.
+    // Returns an array of messages as a KVTs, flagging malformed messages.
     rpc_module.register_method("feed", move |params: Params, _| {
         task::block_on(async {
             // Parse the parameter containing the public key.
@@ -422,16 +1071,24 @@ pub async fn actor(server_id: OwnedIdentity, server_addr: SocketAddr) -> Result<
             // Open the primary KV database for reading.
             let db = KV_STORE.read().await;
 
-            // Retrieve the message value for the requested message.
+            let filter = Filter::for_call(params.as_str(), &db)?;
+
+            // Retrieve the messages of the requested feed, leaving out those
+            // hidden from the client.
             let feed = db.get_feed(&pub_key.pub_key)?;
-            let response = json!(feed);
+            let response = json!(feed
+                .iter()
+                .filter(|msg_kvt| !filter.hides(&msg_kvt.value))
+                .map(annotated)
+                .collect::<Vec<Value>>());
 
             Ok::<Value, JsonRpcError>(response)
         })
     })?;
 
-    // Retrieve a message by key.
-    // Returns the message as a KVT.
+    // Retrieve a message by key, falling back to the messages fetched out of
+    // order.
+    // Returns the message as a KVT, flagged if malformed.
     rpc_module.register_method("message", move |params: Params, _| {
         task::block_on(async {
             // Parse the parameter containing the message reference (key).
@@ -448,72 +1105,985 @@ pub async fn actor(server_id: OwnedIdentity, server_addr: SocketAddr) -> Result<
             let msg_kvt = if let Some(val) = msg_val {
                 db.get_msg_kvt(val.author(), val.sequence())?
             } else {
-                None
+                db.get_ooo_msg_kvt(&msg_ref.msg_ref)?
             };
 
-            let response = json!(msg_kvt);
+            let filter = Filter::for_call(params.as_str(), &db)?;
+            let response = json!(msg_kvt
+                .filter(|msg_kvt| !filter.hides(&msg_kvt.value))
+                .as_ref()
+                .map(annotated));
 
             Ok::<Value, JsonRpcError>(response)
         })
     })?;
 
-    // Return the public key and latest sequence number for all feeds in the
-    // local database.
-    rpc_module.register_method("peers", |_, _| {
+    // Retrieve the latest message of a feed by public key.
+    // Returns the message as a KVT, flagged if malformed, or `null` if the
+    // feed is empty.
+    rpc_module.register_method("latest_message", move |params: Params, _| {
         task::block_on(async {
+            let pub_key: PubKey = params.parse()?;
+
             let db = KV_STORE.read().await;
-            let peers = db.get_peers().await?;
-            let response = json!(peers);
+
+            let msg_kvt = match db.get_latest_seq(&pub_key.pub_key)? {
+                Some(seq) => db.get_msg_kvt(&pub_key.pub_key, seq)?,
+                None => None,
+            };
+
+            let filter = Filter::for_call(params.as_str(), &db)?;
+            let response = json!(msg_kvt
+                .filter(|msg_kvt| !filter.hides(&msg_kvt.value))
+                .as_ref()
+                .map(annotated));
 
             Ok::<Value, JsonRpcError>(response)
         })
     })?;
 
-    // Simple `ping` endpoint.
-    rpc_module.register_method("ping", |_, _| "pong!")?;
-
-    // Clone the local public key (ID) so it can later be captured by the
-    // `whoami` closure.
+    // Fetch the latest messages of a feed from a connected peer, without
+    // replicating or storing the feed. The messages are fetched from the
+    // given peer, else from the author of the feed if connected, else from
+    // any connected peer.
+    //
+    // Returns the messages (at most `limit`, 20 by default) ordered by
+    // sequence number.
+    rpc_module.register_method("preview_feed", move |params: Params, _| {
+        task::block_on(async {
+            let preview: PreviewFeed = params.parse()?;
+
+            let msgs = muxrpc::fetch_preview(
+                &preview.pub_key,
+                preview.peer.as_deref(),
+                preview.limit.unwrap_or(PREVIEW_LIMIT),
+            )
+            .await?;
+
+            let filter = Filter::for_call(params.as_str(), &*KV_STORE.read().await)?;
+            let response: Vec<Value> = msgs
+                .iter()
+                .filter(|msg| !filter.hides(&msg.value))
+                .map(|msg| json!({ "key": msg.id().to_string(), "value": msg.value }))
+                .collect();
+
+            Ok::<Value, JsonRpcError>(json!(response))
+        })
+    })?;
+
+    // Fetch a message by key from the connected peers, unless it is stored
+    // already, without replicating the feed of its author. The fetched
+    // message is cached and served to peers in turn.
+    //
+    // Returns the message as a KVT, flagged if malformed, or `null` if no
+    // connected peer returned it in time.
+    rpc_module.register_method("fetch_message", move |params: Params, _| {
+        task::block_on(async {
+            let msg_ref: MsgRef = params.parse()?;
+
+            let msg_kvt = muxrpc::fetch_message(&msg_ref.msg_ref).await?;
+
+            let filter = Filter::for_call(params.as_str(), &*KV_STORE.read().await)?;
+            let response = json!(msg_kvt
+                .filter(|msg_kvt| !filter.hides(&msg_kvt.value))
+                .as_ref()
+                .map(annotated));
+
+            Ok::<Value, JsonRpcError>(response)
+        })
+    })?;
+
+    // Queue a fault to be applied to outbound MUXRPC packets, on the
+    // connections to the given peer or (by default) to any peer. The fault is
+    // applied to `count` packets (defaults to 1).
+    //
+    // Returns the number of pending faults.
+    #[cfg(feature = "fault-injection")]
+    rpc_module.register_method("inject_fault", |params: Params, _| {
+        let inject_fault: InjectFault = params.parse()?;
+
+        let mut injector = FAULT_INJECTOR.lock().unwrap();
+        injector.inject(
+            inject_fault.fault,
+            inject_fault.count.unwrap_or(1),
+            inject_fault.peer.as_deref(),
+        );
+
+        Ok::<Value, JsonRpcError>(json!(injector.pending()))
+    })?;
+
+    // Remove all pending faults.
+    //
+    // Returns the number of faults removed.
+    #[cfg(feature = "fault-injection")]
+    rpc_module.register_method("clear_faults", |_, _| {
+        let cleared = FAULT_INJECTOR.lock().unwrap().clear();
+
+        json!(cleared)
+    })?;
+
+    // Consume the given HTTP room invite and add the room to the connection
+    // scheduler.
+    //
+    // Returns the public key and address of the room.
+    let invitee_id = server_id.id.clone();
+    register_audited(&mut rpc_module, "join_room", move |params: Params, _| {
+        task::block_on(async {
+            let invite: Invite = params.parse()?;
+
+            let (public_key, addr) =
+                room_invite::consume_invite(&invite.invite, &invitee_id).await?;
+
+            let mut ch_broker = BROKER.lock().await.create_sender();
+            ch_broker
+                .send(BrokerEvent::new(
+                    Destination::Broadcast,
+                    BrokerMessage::Schedule(ScheduleRequest((public_key, addr.to_owned()))),
+                ))
+                .await
+                .map_err(Error::from)?;
+
+            let ssb_id = prefixed(&public_key.to_ssb_id());
+            let response = json!({ "id": ssb_id, "addr": addr });
+
+            Ok::<Value, JsonRpcError>(response)
+        })
+    })?;
+
+    // Retrieve the attendants of the given room.
+    // Returns an array of public keys, which is empty unless the local node
+    // is connected to the room.
+    rpc_module.register_method("room_attendants", move |params: Params, _| {
+        task::block_on(async {
+            // Parse the parameter containing the public key of the room.
+            let pub_key: PubKey = params.parse()?;
+
+            let attendants = CONNECTION_MANAGER.read().await.attendants(&pub_key.pub_key);
+            let response = json!(attendants);
+
+            Ok::<Value, JsonRpcError>(response)
+        })
+    })?;
+
+    // Return the recorded identity conflict: evidence of the keypair of the
+    // local identity being in use on another device. Publishing is disabled
+    // while a conflict is recorded.
+    //
+    // Returns `null` if no conflict has been detected.
+    rpc_module.register_method("identity_conflict", |_, _| {
+        task::block_on(async {
+            let conflict = KV_STORE.read().await.get_identity_conflict()?;
+
+            Ok::<Value, JsonRpcError>(json!(conflict))
+        })
+    })?;
+
+    // Clear the recorded identity conflict, enabling publishing again.
+    //
+    // Returns `false` if no conflict was recorded.
+    register_audited(&mut rpc_module, "clear_identity_conflict", |_, _| {
+        task::block_on(async {
+            let cleared = KV_STORE.read().await.clear_identity_conflict()?;
+
+            Ok::<Value, JsonRpcError>(json!(cleared))
+        })
+    })?;
+
+    // Add a message to the outbox, either as a draft or scheduled to be
+    // published at the given time.
+    //
+    // Returns the ID of the outbox entry.
+    register_audited(&mut rpc_module, "outbox_add", |params: Params, _| {
+        task::block_on(async {
+            let outbox_msg: OutboxMsg = params.parse()?;
+
+            let entry = OutboxEntry {
+                content: json!(outbox_msg.msg),
+                publish_at: outbox_msg.publish_at,
+                error: None,
+            };
+            let entry_id = KV_STORE.read().await.add_outbox_entry(&entry)?;
+
+            Ok::<Value, JsonRpcError>(json!(entry_id))
+        })
+    })?;
+
+    // Return the entries of the outbox, ordered from oldest to newest.
+    rpc_module.register_method("outbox", |_, _| {
+        task::block_on(async {
+            let entries = KV_STORE.read().await.get_outbox_entries()?;
+            let response: Vec<Value> = entries
+                .into_iter()
+                .map(|(entry_id, entry)| {
+                    json!({
+                        "id": entry_id,
+                        "msg": entry.content,
+                        "publish_at": entry.publish_at,
+                        "error": entry.error,
+                    })
+                })
+                .collect();
+
+            Ok::<Value, JsonRpcError>(json!(response))
+        })
+    })?;
+
+    // Replace the message and the scheduled time of the given outbox entry.
+    //
+    // Returns `false` if there is no such entry.
+    register_audited(&mut rpc_module, "outbox_edit", |params: Params, _| {
+        task::block_on(async {
+            let outbox_edit: OutboxEdit = params.parse()?;
+
+            let entry = OutboxEntry {
+                content: json!(outbox_edit.msg),
+                publish_at: outbox_edit.publish_at,
+                error: None,
+            };
+            let replaced = KV_STORE
+                .read()
+                .await
+                .replace_outbox_entry(outbox_edit.id, &entry)?;
+
+            Ok::<Value, JsonRpcError>(json!(replaced))
+        })
+    })?;
+
+    // Remove the given entry from the outbox.
+    //
+    // Returns `false` if there is no such entry.
+    register_audited(&mut rpc_module, "outbox_cancel", |params: Params, _| {
+        task::block_on(async {
+            let entry_id: OutboxEntryId = params.parse()?;
+
+            let removed = KV_STORE.read().await.remove_outbox_entry(entry_id.id)?;
+
+            Ok::<Value, JsonRpcError>(json!(removed.is_some()))
+        })
+    })?;
+
+    // Publish the message of the given outbox entry (draft or scheduled)
+    // right away. If the publish fails, the entry is kept as a draft.
+    //
+    // Returns the key (hash) and sequence number of the published message,
+    // or `null` if there is no such entry.
+    let outbox_identity = server_id.clone();
+    register_audited(
+        &mut rpc_module,
+        "outbox_publish",
+        move |params: Params, _| {
+            task::block_on(async {
+                let entry_id: OutboxEntryId = params.parse()?;
+
+                let response = outbox::publish_entry(&outbox_identity, entry_id.id)
+                    .await?
+                    .map(|(msg, seq)| (msg.id().to_string(), seq));
+
+                Ok::<Value, JsonRpcError>(json!(response))
+            })
+        },
+    )?;
+
+    // Return the peers queued to be followed back (see the follow-back
+    // policy), along with the references of their follow messages.
+    rpc_module.register_method("follow_requests", |_, _| {
+        task::block_on(async {
+            let requests = KV_STORE.read().await.get_follow_requests()?;
+            let response: Vec<Value> = requests
+                .into_iter()
+                .map(|(peer, msg_ref)| json!({ "peer": peer, "msg_ref": msg_ref }))
+                .collect();
+
+            Ok::<Value, JsonRpcError>(json!(response))
+        })
+    })?;
+
+    // Follow back the given peer queued by the follow-back policy.
+    //
+    // Returns the key (hash) and sequence number of the published message,
+    // or `null` if the peer was not queued.
+    let follow_back_identity = server_id.clone();
+    register_audited(
+        &mut rpc_module,
+        "approve_follow_request",
+        move |params: Params, _| {
+            task::block_on(async {
+                let pub_key: PubKey = params.parse()?;
+
+                let response = follow_back::approve(&follow_back_identity, &pub_key.pub_key)
+                    .await?
+                    .map(|(msg, seq)| (msg.id().to_string(), seq));
+
+                Ok::<Value, JsonRpcError>(json!(response))
+            })
+        },
+    )?;
+
+    // Remove the given peer from the follow-back queue without following it.
+    //
+    // Returns `false` if the peer was not queued.
+    register_audited(
+        &mut rpc_module,
+        "reject_follow_request",
+        |params: Params, _| {
+            task::block_on(async {
+                let pub_key: PubKey = params.parse()?;
+
+                let removed = KV_STORE
+                    .read()
+                    .await
+                    .remove_follow_request(&pub_key.pub_key)?;
+
+                Ok::<Value, JsonRpcError>(json!(removed.is_some()))
+            })
+        },
+    )?;
+
+    // Return the public key and latest sequence number for all feeds in the
+    // local database.
+    rpc_module.register_method("peers", |_, _| {
+        task::block_on(async {
+            let db = KV_STORE.read().await;
+            let peers = db.get_peers().await?;
+            let response = json!(peers);
+
+            Ok::<Value, JsonRpcError>(response)
+        })
+    })?;
+
+    // Pin the feed authored by the given public key, exempting it from
+    // pruning.
+    //
+    // Returns `false` if the feed was already pinned.
+    register_audited(&mut rpc_module, "pin_feed", move |params: Params, _| {
+        task::block_on(async {
+            let pub_key: PubKey = params.parse()?;
+
+            let db = KV_STORE.write().await;
+            let pinned = db.pin_feed(&pub_key.pub_key)?;
+
+            Ok::<Value, JsonRpcError>(json!(pinned))
+        })
+    })?;
+
+    // Unpin the feed authored by the given public key.
+    //
+    // Returns `false` if the feed was not pinned.
+    register_audited(&mut rpc_module, "unpin_feed", move |params: Params, _| {
+        task::block_on(async {
+            let pub_key: PubKey = params.parse()?;
+
+            let db = KV_STORE.write().await;
+            let unpinned = db.unpin_feed(&pub_key.pub_key)?;
+
+            Ok::<Value, JsonRpcError>(json!(unpinned))
+        })
+    })?;
+
+    // Return the public keys of all pinned feeds.
+    rpc_module.register_method("pins", |_, _| {
+        task::block_on(async {
+            let db = KV_STORE.read().await;
+            let feeds = db.get_pinned_feeds()?;
+            let response = json!({ "feeds": feeds });
+
+            Ok::<Value, JsonRpcError>(response)
+        })
+    })?;
+
+    // Mute the given word (`{"word": ...}`) or regular expression
+    // (`{"regex": ...}`), leaving the messages whose text or channel matches
+    // it out of the timeline and channel indexes and queries.
+    //
+    // Returns `false` if the pattern was already muted.
+    register_audited(&mut rpc_module, "mute", |params: Params, _| {
+        task::block_on(async {
+            let pattern: MutePattern = params.parse()?;
+
+            let mut db = KV_STORE.write().await;
+            let muted = db.mute(&pattern)?;
+
+            Ok::<Value, JsonRpcError>(json!(muted))
+        })
+    })?;
+
+    // Unmute the given word or regular expression.
+    //
+    // Returns `false` if the pattern was not muted.
+    register_audited(&mut rpc_module, "unmute", |params: Params, _| {
+        task::block_on(async {
+            let pattern: MutePattern = params.parse()?;
+
+            let mut db = KV_STORE.write().await;
+            let unmuted = db.unmute(&pattern)?;
+
+            Ok::<Value, JsonRpcError>(json!(unmuted))
+        })
+    })?;
+
+    // Return the muted words and regular expressions.
+    rpc_module.register_method("mutes", |_, _| {
+        task::block_on(async {
+            let db = KV_STORE.read().await;
+            let patterns = db.get_mute_patterns()?;
+
+            Ok::<Value, JsonRpcError>(json!(patterns))
+        })
+    })?;
+
+    // Simple `ping` endpoint.
+    rpc_module.register_method("ping", |_, _| "pong!")?;
+
+    // Clone the local public key (ID) so it can later be captured by the
+    // `whoami` closure.
     let local_pk = server_id.id.clone();
 
+    // Publish a pub message announcing the local node at the given public
+    // address (host and port). The message lists the addresses of the local
+    // node through the rooms it attends along with its TCP address.
+    //
+    // Returns a tuple of the reference and sequence number of the message.
+    let pub_identity = server_id.clone();
+    register_audited(&mut rpc_module, "announce_pub", move |params: Params, _| {
+        task::block_on(async {
+            let pub_address: PubAddress = params.parse()?;
+
+            let addr = format!("{}:{}", pub_address.host, pub_address.port);
+            let addresses = multiserver::local_addresses(&addr, &pub_identity.pk).await;
+            let content = json!({
+                "type": "pub",
+                "address": {
+                    "host": pub_address.host,
+                    "port": pub_address.port,
+                    "key": pub_identity.id,
+                },
+                "addresses": addresses,
+            });
+
+            let (msg, seq) = publish::publish(&pub_identity, content, None).await?;
+            let response = json!((msg.id().to_string(), seq));
+
+            Ok::<Value, JsonRpcError>(response)
+        })
+    })?;
+
     // Publish a typed message (raw).
     // Returns the key (hash) and sequence number of the published message.
-    rpc_module.register_method("publish", move |params: Params, _| {
+    //
+    // Concurrent publishes are serialized. If the expected previous message
+    // or sequence number is given, the publish fails with a conflict error
+    // unless the local feed is in the expected state.
+    register_audited(&mut rpc_module, "publish", move |params: Params, _| {
         task::block_on(async {
             // Parse the parameter containing the message content.
             let msg_object: Msg = params.parse()?;
             let msg_content: TypedMessage = msg_object.msg;
 
-            // Open the primary KV database for writing.
-            let db = KV_STORE.write().await;
+            let expected = match (msg_object.previous, msg_object.sequence) {
+                (Some(previous), _) => Some(Expected::Previous(Some(previous))),
+                (None, Some(sequence)) => Some(Expected::Sequence(sequence)),
+                (None, None) => None,
+            };
+
+            // Sign the message and append it to the local feed.
+            let (msg, seq) = publish::publish(&server_id, json!(msg_content), expected).await?;
+
+            // Return a tuple of message reference and sequence number.
+            let response = json!((msg.id().to_string(), seq));
 
-            // Lookup the last message published on the local feed.
-            // Return `None` if no messages have yet been published on the feed.
-            let last_msg = db.get_latest_msg_val(&server_id.id)?;
+            Ok::<Value, JsonRpcError>(response)
+        })
+    })?;
 
-            // Instantiate and cryptographically-sign a new message.
-            let msg = Message::sign(last_msg.as_ref(), &server_id, json!(msg_content))
-                .map_err(Error::Validation)?;
+    // Append a message signed by a client on behalf of a remote signer (see
+    // the publish policy) to the feed of its author.
+    //
+    // Returns the key (hash) and sequence number of the appended message.
+    register_audited(&mut rpc_module, "append_signed", |params: Params, _| {
+        task::block_on(async {
+            let signed_msg: SignedMsg = params.parse()?;
 
-            // Append the signed message to the feed.
-            let seq = db.append_feed(msg.clone()).await?;
+            // Parsing the message verifies its signature.
+            let msg_bytes = serde_json::to_vec(&signed_msg.msg).map_err(Error::from)?;
+            let msg = Message::from_slice(&msg_bytes).map_err(Error::from)?;
+            let seq = publish::append_signed(&msg).await?;
 
-            info!(
-                "published message {} with sequence number {}",
-                msg.id().to_string(),
-                seq
-            );
+            Ok::<Value, JsonRpcError>(json!((msg.id().to_string(), seq)))
+        })
+    })?;
 
-            // Return a tuple of message reference and sequence number.
-            let response = json!((msg.id().to_string(), seq));
+    // Start a new replication session with the given peer using the given
+    // strategy (`ebt` or `classic`). Any active connection with the peer is
+    // closed and the peer is dialed again.
+    //
+    // Returns `false` if no address is known for the peer, in which case the
+    // strategy is used when the peer next connects.
+    register_audited(
+        &mut rpc_module,
+        "replicate_now",
+        move |params: Params, _| {
+            task::block_on(async {
+                let replicate_now: ReplicateNow = params.parse()?;
+
+                let dialed = ConnectionManager::replicate_now(
+                    &replicate_now.pub_key,
+                    replicate_now.strategy,
+                )
+                .await?;
+
+                Ok::<Value, JsonRpcError>(json!(dialed))
+            })
+        },
+    )?;
+
+    // Retrieve the cached capabilities of the given peer.
+    // Returns the capabilities, or `null` if none have been observed.
+    rpc_module.register_method("peer_capabilities", move |params: Params, _| {
+        task::block_on(async {
+            // Parse the parameter containing the public key.
+            let pub_key: PubKey = params.parse()?;
+
+            // Open the primary KV database for reading.
+            let db = KV_STORE.read().await;
+
+            let capabilities = db.get_peer_capabilities(&pub_key.pub_key)?;
+            let response = json!(capabilities);
+
+            Ok::<Value, JsonRpcError>(response)
+        })
+    })?;
+
+    // Set the value of the given local record of the given app. Local
+    // records are kept on this node only: they are never published nor
+    // replicated.
+    //
+    // Returns `true` if the record did not exist yet.
+    register_audited(&mut rpc_module, "records_set", |params: Params, _| {
+        task::block_on(async {
+            let record: RecordValue = params.parse()?;
+
+            let db = KV_STORE.read().await;
+            let records = db.records.as_ref().ok_or_else(records_unavailable)?;
+            let created = records.set(&record.app, &record.key, &record.value)?;
+
+            Ok::<Value, JsonRpcError>(json!(created))
+        })
+    })?;
+
+    // Retrieve the value of the given local record of the given app.
+    //
+    // Returns `null` if there is no such record.
+    rpc_module.register_method("records_get", |params: Params, _| {
+        task::block_on(async {
+            let record: RecordKey = params.parse()?;
+
+            let db = KV_STORE.read().await;
+            let records = db.records.as_ref().ok_or_else(records_unavailable)?;
+            let value = records.get(&record.app, &record.key)?;
+
+            Ok::<Value, JsonRpcError>(json!(value))
+        })
+    })?;
+
+    // Remove the given local record of the given app.
+    //
+    // Returns `false` if there is no such record.
+    register_audited(&mut rpc_module, "records_delete", |params: Params, _| {
+        task::block_on(async {
+            let record: RecordKey = params.parse()?;
+
+            let db = KV_STORE.read().await;
+            let records = db.records.as_ref().ok_or_else(records_unavailable)?;
+            let removed = records.remove(&record.app, &record.key)?;
+
+            Ok::<Value, JsonRpcError>(json!(removed))
+        })
+    })?;
+
+    // Retrieve the local records of the given app, optionally only those
+    // whose key starts with the given prefix.
+    //
+    // Returns an object mapping keys to values.
+    rpc_module.register_method("records_list", |params: Params, _| {
+        task::block_on(async {
+            let query: RecordsQuery = params.parse()?;
+
+            let db = KV_STORE.read().await;
+            let records = db.records.as_ref().ok_or_else(records_unavailable)?;
+            let list = records.list(&query.app, query.prefix.as_deref().unwrap_or_default())?;
+
+            Ok::<Value, JsonRpcError>(json!(list))
+        })
+    })?;
+
+    // Remove all the local records of the given app.
+    //
+    // Returns the number of records removed.
+    register_audited(&mut rpc_module, "records_clear", |params: Params, _| {
+        task::block_on(async {
+            let query: RecordsQuery = params.parse()?;
+
+            let db = KV_STORE.read().await;
+            let records = db.records.as_ref().ok_or_else(records_unavailable)?;
+            let removed = records.clear(&query.app)?;
+
+            Ok::<Value, JsonRpcError>(json!(removed))
+        })
+    })?;
+
+    // Retrieve the replication log for the given peer.
+    // Returns an array of replication events, ordered from oldest to newest.
+    rpc_module.register_method("replication_log", move |params: Params, _| {
+        task::block_on(async {
+            // Parse the parameter containing the public key.
+            let pub_key: PubKey = params.parse()?;
+
+            // Open the primary KV database for reading.
+            let db = KV_STORE.read().await;
+
+            let log = db.get_replication_log(&pub_key.pub_key)?;
+            let response = json!(log);
+
+            Ok::<Value, JsonRpcError>(response)
+        })
+    })?;
+
+    // Retrieve the trace of the messages of the given feed: the peer from
+    // which each message was received and the peers to which it was sent,
+    // optionally for the message with the given sequence number only.
+    // Returns whether the feed is traced, along with an array of trace entries
+    // ordered from oldest to newest.
+    rpc_module.register_method("message_trace", move |params: Params, _| {
+        task::block_on(async {
+            let query: MessageTrace = params.parse()?;
+
+            let entries: Vec<_> = KV_STORE
+                .read()
+                .await
+                .get_message_trace(&query.pub_key)?
+                .into_iter()
+                .filter(|entry| query.sequence.map_or(true, |seq| entry.sequence == seq))
+                .collect();
+
+            let response = json!({
+                "traced": trace::is_traced(&query.pub_key),
+                "entries": entries,
+            });
 
             Ok::<Value, JsonRpcError>(response)
         })
     })?;
 
+    // Retrieve the latest EBT vector clock received from the given peer (as
+    // held by the node, and persisted across sessions).
+    // Returns an object mapping each feed ID to its encoded value, replicate
+    // flag, receive flag and sequence number, or `null` if no clock has been
+    // received from the peer.
+    rpc_module.register_method("clock", move |params: Params, _| {
+        task::block_on(async {
+            let pub_key: PubKey = params.parse()?;
+
+            let clock = query::query_clock(Some(&pub_key.pub_key)).await?;
+            let response = match clock {
+                Some(clock) => query::describe(&clock)?,
+                None => Value::Null,
+            };
+
+            Ok::<Value, JsonRpcError>(response)
+        })
+    })?;
+
+    // Retrieve the local EBT vector clock, as sent to peers at the start of
+    // every session.
+    // Returns an object mapping each replicated feed ID to its encoded value,
+    // replicate flag, receive flag and sequence number.
+    rpc_module.register_method("local_clock", move |_, _| {
+        task::block_on(async {
+            let clock = query::query_clock(None).await?.unwrap_or_default();
+            let response = query::describe(&clock)?;
+
+            Ok::<Value, JsonRpcError>(response)
+        })
+    })?;
+
+    // Retrieve the most recent log lines (100 by default, at most 500),
+    // regardless of whether logs are written to stderr or to a file.
+    // Returns an array of formatted log lines, ordered from oldest to newest.
+    rpc_module.register_method("recent_logs", |params: Params, _| {
+        let query: Option<RecentLogs> = params.parse()?;
+        let limit = query
+            .and_then(|query| query.limit)
+            .unwrap_or(RECENT_LOGS_LIMIT);
+        let lines = logger::recent_lines(limit)?;
+
+        Ok::<Value, JsonRpcError>(json!(lines))
+    })?;
+
+    // Set the log level for the given target (or the default log level if no
+    // target is given) without restarting the node.
+    //
+    // Returns the resulting log filter directives.
+    register_audited(&mut rpc_module, "set_log_level", |params: Params, _| {
+        let log_level: LogLevel = params.parse()?;
+        let filter = logger::set_level(log_level.target.as_deref(), &log_level.level)?;
+
+        Ok::<Value, JsonRpcError>(json!(filter))
+    })?;
+
+    // Retrieve the storage usage of the node: the number of stored feeds, the
+    // size of the key-value database on disk and the number and total size
+    // of stored blobs (all sizes in bytes), along with the storage pressure
+    // as of the last check.
+    rpc_module.register_method("storage_usage", |_, _| {
+        task::block_on(async {
+            let (feeds, database_bytes) = {
+                let db = KV_STORE.read().await;
+                (db.get_peers().await?.len(), db.size_on_disk()?)
+            };
+            let (blobs, blob_bytes) = BLOB_STORE.read().await.usage().map_err(Error::from)?;
+
+            let response = json!({
+                "feeds": feeds,
+                "database_bytes": database_bytes,
+                "blobs": blobs,
+                "blob_bytes": blob_bytes,
+                "pressure": pressure::status(),
+            });
+
+            Ok::<Value, JsonRpcError>(response)
+        })
+    })?;
+
+    // Retrieve the storage used by the feeds replicated in mirror mode: the
+    // number of messages and bytes stored for each mirrored feed since it was
+    // first mirrored, along with the totals.
+    rpc_module.register_method("mirror_status", |_, _| {
+        task::block_on(async {
+            let mirrored = KV_STORE.read().await.get_mirrored_feeds()?;
+
+            let messages: u64 = mirrored.iter().map(|(_, feed)| feed.messages).sum();
+            let bytes: u64 = mirrored.iter().map(|(_, feed)| feed.bytes).sum();
+            let feeds: Vec<Value> = mirrored
+                .into_iter()
+                .map(|(feed_id, feed)| {
+                    json!({
+                        "feed": feed_id,
+                        "added": feed.added,
+                        "messages": feed.messages,
+                        "bytes": feed.bytes,
+                    })
+                })
+                .collect();
+
+            let response = json!({
+                "enabled": MIRROR_MODE.get().copied().unwrap_or(false),
+                "feeds": feeds,
+                "messages": messages,
+                "bytes": bytes,
+            });
+
+            Ok::<Value, JsonRpcError>(response)
+        })
+    })?;
+
+    // Subscribe to the lifecycle events of wanted blobs (want registered,
+    // fetch started, progress, stored and failed). Requires a WebSocket
+    // connection.
+    //
+    // Sends a `blob_event` notification for each event until unsubscribed.
+    rpc_module.register_subscription(
+        "subscribe_blob_events",
+        "blob_event",
+        "unsubscribe_blob_events",
+        |_, pending, _| forward_blob_events(pending),
+    )?;
+
+    // Subscribe to the changes of the storage pressure level (`normal`, `low`
+    // or `critical`). Requires a WebSocket connection.
+    //
+    // Sends a `storage_pressure` notification each time the level changes
+    // until unsubscribed.
+    rpc_module.register_subscription(
+        "subscribe_storage_pressure",
+        "storage_pressure",
+        "unsubscribe_storage_pressure",
+        |_, pending, _| forward_storage_pressure(pending),
+    )?;
+
+    // Subscribe to the changes of the follow graph involving the local
+    // identity. Requires a WebSocket connection.
+    //
+    // Sends a `social_event` notification for each change until
+    // unsubscribed.
+    rpc_module.register_subscription(
+        "subscribe_social_events",
+        "social_event",
+        "unsubscribe_social_events",
+        |_, pending, _| forward_social_events(pending),
+    )?;
+
     // Return the public key of the local SSB server.
     rpc_module.register_method("whoami", move |_, _| local_pk.clone())?;
 
+    Ok(rpc_module)
+}
+
+/// Forward the blob events broadcast on the broker to the given subscriber
+/// until the subscription is closed or the node is stopped.
+async fn forward_blob_events(pending: PendingSubscriptionSink) -> SubscriptionResult {
+    // Register with the broker before accepting the subscription, so that
+    // no event is missed.
+    let ActorEndpoint {
+        actor_id,
+        mut ch_broker,
+        ch_terminate,
+        ch_terminated,
+        ch_msg,
+    } = BROKER
+        .lock()
+        .await
+        .register("jsonrpc-blob-events", true)
+        .await?;
+    let mut ch_msg = ch_msg.ok_or(Error::OptionIsNone)?;
+    let mut ch_terminate = ch_terminate.fuse();
+
+    let sink = pending.accept().await?;
+    let closed = sink.closed().fuse();
+    pin_mut!(closed);
+
+    loop {
+        select_biased! {
+            _ = ch_terminate => break,
+            _ = closed => break,
+            msg = ch_msg.next().fuse() => {
+                let event = match msg {
+                    Some(BrokerMessage::Blob(event)) => event,
+                    Some(BrokerMessage::StoreBlob(StoreBlobEvent(blob_id))) => {
+                        BlobEvent::Stored { blob_id }
+                    }
+                    Some(_) => continue,
+                    None => break,
+                };
+                if sink.send(SubscriptionMessage::from_json(&event)?).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    let _ = ch_broker.send(BrokerEvent::Disconnect { actor_id }).await;
+    let _ = ch_terminated.send(Void {});
+
+    Ok(())
+}
+
+/// Forward the storage pressure changes to the given subscriber.
+async fn forward_storage_pressure(pending: PendingSubscriptionSink) -> SubscriptionResult {
+    let ActorEndpoint {
+        actor_id,
+        mut ch_broker,
+        ch_terminate,
+        ch_terminated,
+        ch_msg,
+    } = BROKER
+        .lock()
+        .await
+        .register("jsonrpc-storage-pressure", true)
+        .await?;
+    let mut ch_msg = ch_msg.ok_or(Error::OptionIsNone)?;
+    let mut ch_terminate = ch_terminate.fuse();
+
+    let sink = pending.accept().await?;
+    let closed = sink.closed().fuse();
+    pin_mut!(closed);
+
+    loop {
+        select_biased! {
+            _ = ch_terminate => break,
+            _ = closed => break,
+            msg = ch_msg.next().fuse() => {
+                let pressure = match msg {
+                    Some(BrokerMessage::StoragePressure(pressure)) => pressure,
+                    Some(_) => continue,
+                    None => break,
+                };
+                if sink.send(SubscriptionMessage::from_json(&pressure)?).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    let _ = ch_broker.send(BrokerEvent::Disconnect { actor_id }).await;
+    let _ = ch_terminated.send(Void {});
+
+    Ok(())
+}
+
+/// Forward the social events broadcast on the broker to the given
+/// subscriber until the subscription is closed or the node is stopped.
+async fn forward_social_events(pending: PendingSubscriptionSink) -> SubscriptionResult {
+    let ActorEndpoint {
+        actor_id,
+        mut ch_broker,
+        ch_terminate,
+        ch_terminated,
+        ch_msg,
+    } = BROKER
+        .lock()
+        .await
+        .register("jsonrpc-social-events", true)
+        .await?;
+    let mut ch_msg = ch_msg.ok_or(Error::OptionIsNone)?;
+    let mut ch_terminate = ch_terminate.fuse();
+
+    let sink = pending.accept().await?;
+    let closed = sink.closed().fuse();
+    pin_mut!(closed);
+
+    loop {
+        select_biased! {
+            _ = ch_terminate => break,
+            _ = closed => break,
+            msg = ch_msg.next().fuse() => {
+                let event = match msg {
+                    Some(BrokerMessage::Social(event)) => event,
+                    Some(_) => continue,
+                    None => break,
+                };
+                if sink.send(SubscriptionMessage::from_json(&event)?).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    let _ = ch_broker.send(BrokerEvent::Disconnect { actor_id }).await;
+    let _ = ch_terminated.send(Void {});
+
+    Ok(())
+}
+
+/// Register the JSON-RPC server endpoint, define the JSON-RPC methods
+/// and spawn the server.
+///
+/// Listens for a termination signal from the broker. When received, the
+/// JSON-RPC server is closed and a terminated signal is sent to the broker.
+pub async fn actor(server_id: OwnedIdentity, server_addr: SocketAddr) -> Result<()> {
+    let broker = BROKER
+        .lock()
+        .await
+        .register("jsonrpc-listener", false)
+        .await?;
+
+    let ch_terminate = broker.ch_terminate.fuse();
+
+    // Serve both HTTP and WebSocket connections (subscriptions require the
+    // latter).
+    let server = ServerBuilder::default().build(&server_addr).await?;
+
+    let rpc_module = rpc_module(server_id)?;
+
     let addr = server.local_addr()?;
     let handle = server.start(rpc_module)?;
     info!("JSON-RPC server started on: {}", addr);
@@ -0,0 +1,220 @@
+//! RSS and Atom feeds.
+//!
+//! The public web viewer serves the public posts of each author and of each
+//! channel as RSS 2.0 and Atom feeds, so that people without an SSB client
+//! can follow them in a feed reader. Entries are identified by the SSB URI
+//! of their message, and link to the thread of the message in the viewer.
+
+use crate::actors::jsonrpc::viewer::{escape, format_date, MAX_TIMESTAMP};
+
+/// Maximum length (in characters) of the title of an entry.
+const MAX_TITLE_LENGTH: usize = 80;
+
+/// Abbreviated names of the days of the week, starting on Sunday.
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+
+/// Abbreviated names of the months.
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Format of a syndication feed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Syndication {
+    Atom,
+    Rss,
+}
+
+impl Syndication {
+    /// Return the format of the feed with the given file name (`atom.xml` or
+    /// `rss.xml`).
+    pub fn from_file_name(file_name: &str) -> Option<Self> {
+        match file_name {
+            "atom.xml" => Some(Syndication::Atom),
+            "rss.xml" => Some(Syndication::Rss),
+            _ => None,
+        }
+    }
+
+    /// Return the content type of feeds in this format.
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Syndication::Atom => "application/atom+xml; charset=utf-8",
+            Syndication::Rss => "application/rss+xml; charset=utf-8",
+        }
+    }
+}
+
+/// An entry of a syndication feed (a public post).
+#[derive(Debug)]
+pub struct Entry {
+    /// SSB URI of the message.
+    pub id: String,
+    /// URL of the thread of the message in the viewer.
+    pub link: String,
+    /// Name of the author.
+    pub author: String,
+    /// Title of the entry (the start of the post).
+    pub title: String,
+    /// Post rendered as HTML.
+    pub content: String,
+    /// Timestamp claimed by the author, in milliseconds.
+    pub published: u64,
+}
+
+/// Return the title of an entry for the given post text: the start of its
+/// first line.
+pub fn title_of(text: &str) -> String {
+    let line = text
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .unwrap_or_default();
+    if line.chars().count() <= MAX_TITLE_LENGTH {
+        return line.to_owned();
+    }
+
+    let mut title: String = line.chars().take(MAX_TITLE_LENGTH - 1).collect();
+    title.push('…');
+    title
+}
+
+/// Format the given timestamp (in milliseconds) as an RFC 2822 date, as
+/// required by RSS, clamped like [`format_date`].
+fn rfc2822(timestamp: u64) -> String {
+    let timestamp = timestamp.min(MAX_TIMESTAMP);
+    // `YYYY-MM-DDTHH:MM:SSZ`
+    let date = format_date(timestamp);
+    let month: usize = date[5..7].parse().unwrap_or(1);
+    // The UNIX epoch was a Thursday.
+    let weekday = (timestamp / 1000 / 86400 + 4) % 7;
+
+    format!(
+        "{}, {} {} {} {} GMT",
+        WEEKDAYS[weekday as usize],
+        &date[8..10],
+        MONTHS[month - 1],
+        &date[..4],
+        &date[11..19]
+    )
+}
+
+/// Render a feed in the given format, with the given title, URL (of the
+/// feed itself), link (to the page of the feed in the viewer) and entries
+/// (from newest to oldest).
+pub fn render(
+    format: Syndication,
+    title: &str,
+    url: &str,
+    link: &str,
+    entries: &[Entry],
+) -> String {
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>");
+
+    match format {
+        Syndication::Atom => {
+            let updated = entries.first().map_or(0, |entry| entry.published);
+            xml.push_str(&format!(
+                "<feed xmlns=\"http://www.w3.org/2005/Atom\"><id>{url}</id>\
+                 <title>{title}</title><updated>{updated}</updated>\
+                 <link rel=\"self\" href=\"{url}\"/>\
+                 <link rel=\"alternate\" type=\"text/html\" href=\"{link}\"/>",
+                url = escape(url),
+                title = escape(title),
+                updated = format_date(updated),
+                link = escape(link)
+            ));
+            for entry in entries {
+                xml.push_str(&format!(
+                    "<entry><id>{}</id><title>{}</title><author><name>{}</name></author>\
+                     <published>{published}</published><updated>{published}</updated>\
+                     <link rel=\"alternate\" type=\"text/html\" href=\"{}\"/>\
+                     <content type=\"html\">{}</content></entry>",
+                    escape(&entry.id),
+                    escape(&entry.title),
+                    escape(&entry.author),
+                    escape(&entry.link),
+                    escape(&entry.content),
+                    published = format_date(entry.published)
+                ));
+            }
+            xml.push_str("</feed>");
+        }
+        Syndication::Rss => {
+            xml.push_str(&format!(
+                "<rss version=\"2.0\" xmlns:dc=\"http://purl.org/dc/elements/1.1/\">\
+                 <channel><title>{title}</title><link>{link}</link>\
+                 <description>{title}</description>",
+                title = escape(title),
+                link = escape(link)
+            ));
+            for entry in entries {
+                xml.push_str(&format!(
+                    "<item><title>{}</title><link>{}</link>\
+                     <guid isPermaLink=\"false\">{}</guid><dc:creator>{}</dc:creator>\
+                     <pubDate>{}</pubDate><description>{}</description></item>",
+                    escape(&entry.title),
+                    escape(&entry.link),
+                    escape(&entry.id),
+                    escape(&entry.author),
+                    rfc2822(entry.published),
+                    escape(&entry.content)
+                ));
+            }
+            xml.push_str("</channel></rss>");
+        }
+    }
+
+    xml
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_dates() {
+        assert_eq!(format_date(0), "1970-01-01T00:00:00Z");
+        assert_eq!(rfc2822(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(rfc2822(1_700_000_000_000), "Tue, 14 Nov 2023 22:13:20 GMT");
+
+        // Timestamps are claimed by authors and may be out of range.
+        assert_eq!(format_date(u64::MAX / 1000), "9999-12-31T23:59:59Z");
+        assert_eq!(rfc2822(u64::MAX / 1000), "Fri, 31 Dec 9999 23:59:59 GMT");
+    }
+
+    #[test]
+    fn test_title() {
+        assert_eq!(title_of("\n  Hello world \nand more"), "Hello world");
+        let title = title_of(&"a".repeat(100));
+        assert_eq!(title.chars().count(), MAX_TITLE_LENGTH);
+        assert!(title.ends_with('…'));
+    }
+
+    #[test]
+    fn test_render() {
+        let entries = vec![Entry {
+            id: "ssb:message/classic/abc=".to_string(),
+            link: "http://localhost/thread/abc=".to_string(),
+            author: "glyph".to_string(),
+            title: "Fish & chips".to_string(),
+            content: "<p>Fish &amp; chips</p>".to_string(),
+            published: 0,
+        }];
+
+        let atom = render(
+            Syndication::Atom,
+            "glyph",
+            "http://localhost/feed/key/atom.xml",
+            "http://localhost/feed/key",
+            &entries,
+        );
+        assert!(atom.contains("<title>Fish &amp; chips</title>"));
+        assert!(atom.contains("<content type=\"html\">&lt;p&gt;Fish &amp;amp; chips"));
+        assert!(atom.ends_with("</entry></feed>"));
+
+        let rss = render(Syndication::Rss, "glyph", "", "http://localhost/", &entries);
+        assert!(rss.contains("<pubDate>Thu, 01 Jan 1970 00:00:00 GMT</pubDate>"));
+        assert!(rss.ends_with("</item></channel></rss>"));
+    }
+}
@@ -0,0 +1,723 @@
+//! Public web viewer.
+//!
+//! An optional read-only HTTP endpoint rendering the feeds, threads and
+//! profiles stored by the node as plain HTML, so that a pub can serve a
+//! public web view of its community. The blobs referenced by stored
+//! messages are served as well, so that images are displayed inline, and
+//! the posts of each feed and channel are served as RSS and Atom feeds.
+//!
+//! Only public posts are rendered: muted messages, and the messages of
+//! authors who asked not to be shown on public web pages (with
+//! `publicWebHosting: false` in a self-assigned about message), are left
+//! out. Unlike the dashboard, the viewer reads the database directly rather
+//! than through the JSON-RPC server, so that exposing it publicly does not
+//! expose the JSON-RPC API.
+
+use std::{
+    net::SocketAddr,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use async_std::{
+    io::{self, WriteExt},
+    net::{TcpListener, TcpStream},
+};
+use futures::{select_biased, stream::StreamExt, FutureExt};
+use kuska_ssb::feed::Feed as MessageKvt;
+use log::{debug, info, warn};
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+use crate::{
+    actors::jsonrpc::{
+        dashboard::{read_request, RequestHead, Response, REQUEST_TIMEOUT},
+        syndication::{self, Entry, Syndication},
+    },
+    broker::*,
+    error::Error,
+    node::{BLOB_STORE, KV_STORE},
+    ssb_uri,
+    storage::{
+        indexes::{Indexes, TimelineOrder},
+        kv::KvStorage,
+    },
+    Result,
+};
+
+/// Maximum number of posts rendered on a page or in a syndication feed.
+const PAGE_SIZE: usize = 50;
+
+/// Maximum number of messages scanned for the posts of a page or of a
+/// syndication feed.
+const MAX_SCANNED: usize = 1000;
+
+/// Latest timestamp (in milliseconds) which can be formatted as an RFC 3339
+/// date: 9999-12-31T23:59:59.999Z.
+pub(super) const MAX_TIMESTAMP: u64 = 253_402_300_799_999;
+
+/// Content type of the rendered pages.
+const HTML: &str = "text/html; charset=utf-8";
+
+/// The stylesheet of the rendered pages.
+const STYLE: &str = "body{max-width:42em;margin:0 auto;padding:1em;\
+    font-family:sans-serif;line-height:1.5;color:#222}\
+    a{color:#1a5fb4}nav{border-bottom:1px solid #ddd;padding-bottom:.5em}\
+    article{border-bottom:1px solid #eee;padding:.5em 0}\
+    article header{font-size:.9em;color:#666}img{max-width:100%}\
+    .profile img{max-width:8em;float:right}.note{color:#666}";
+
+/// Markdown links to feeds, messages and blobs (`[label](@...)`), and
+/// images (`![label](&...)`).
+static LINK_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(!?)\[([^\]]*)\]\(([@%&][0-9A-Za-z/+=]+\.(?:ed25519|sha256))\)").unwrap()
+});
+
+/// Escape the given text for inclusion in HTML (or XML).
+pub(super) fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// Return the viewer path of the given feed, message or blob link
+/// (`/feed/<key>`, `/thread/<hash>` or `/blob/<hash>`, where the key or
+/// hash is URL-safe base64), or `None` if the link is invalid.
+fn path_of(link: &str) -> Option<String> {
+    let uri = ssb_uri::to_uri(link).ok()?;
+    let mut parts = uri.trim_start_matches("ssb:").splitn(3, '/');
+    let kind = match parts.next()? {
+        "message" => "thread",
+        kind => kind,
+    };
+
+    Some(format!("/{}/{}", kind, parts.nth(1)?))
+}
+
+/// Return the sigil link of the feed, message or blob with the given kind
+/// (`feed`, `message` or `blob`) and URL-safe base64 key or hash, or `None`
+/// if the key or hash is invalid.
+fn link_of(kind: &str, key: &str) -> Option<String> {
+    ssb_uri::to_sigil(&format!("ssb:{}/classic/{}", kind, key)).ok()
+}
+
+/// Render the given post text (or profile description) as HTML paragraphs,
+/// with links to the referenced feeds, messages and blobs, and referenced
+/// images displayed inline.
+fn render_text(text: &str) -> String {
+    let mut html = String::new();
+    let mut last = 0;
+
+    for caps in LINK_REGEX.captures_iter(text) {
+        let whole = caps.get(0).expect("the whole match is always captured");
+        html.push_str(&escape(&text[last..whole.start()]));
+        last = whole.end();
+
+        let label = escape(&caps[2].replace('\n', " "));
+        let link = &caps[3];
+        match path_of(link) {
+            Some(path) if &caps[1] == "!" && link.starts_with('&') => {
+                html.push_str(&format!("<img src=\"{}\" alt=\"{}\">", path, label))
+            }
+            Some(path) => html.push_str(&format!("<a href=\"{}\">{}</a>", path, label)),
+            None => html.push_str(&escape(whole.as_str())),
+        }
+    }
+    html.push_str(&escape(&text[last..]));
+
+    html.split("\n\n")
+        .map(str::trim)
+        .filter(|paragraph| !paragraph.is_empty())
+        .map(|paragraph| format!("<p>{}</p>", paragraph.replace('\n', "<br>")))
+        .collect()
+}
+
+/// Render a whole page with the given title and body. If the path of the
+/// page is given, the RSS and Atom feeds of the page are linked.
+fn render_page(title: &str, path: Option<&str>, body: &str) -> String {
+    let (head_links, body_links) = match path {
+        Some(path) => (
+            format!(
+                "<link rel=\"alternate\" type=\"application/atom+xml\" href=\"{path}/atom.xml\">\
+                 <link rel=\"alternate\" type=\"application/rss+xml\" href=\"{path}/rss.xml\">",
+                path = path
+            ),
+            format!(
+                "<p class=\"note\">Follow with <a href=\"{path}/rss.xml\">RSS</a> or \
+                 <a href=\"{path}/atom.xml\">Atom</a></p>",
+                path = path
+            ),
+        ),
+        None => (String::new(), String::new()),
+    };
+
+    format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\">\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\
+         <title>{title}</title><style>{STYLE}</style>{head_links}</head>\
+         <body><nav><a href=\"/\">Latest posts</a></nav><h1>{title}</h1>{body_links}{body}\
+         </body></html>",
+        title = escape(title),
+        STYLE = STYLE,
+        head_links = head_links,
+        body_links = body_links,
+        body = body
+    )
+}
+
+/// Return the name of the given feed, or the start of its public key if it
+/// has none.
+fn display_name(indexes: &Indexes, feed_id: &str) -> Result<String> {
+    Ok(indexes
+        .get_display_name(feed_id)?
+        .unwrap_or_else(|| feed_id.chars().take(10).collect()))
+}
+
+/// Return the viewer path of the given channel.
+fn channel_path(channel: &str) -> String {
+    format!(
+        "/channel/{}",
+        encode_segment(channel.trim_start_matches('#'))
+    )
+}
+
+/// Percent-encode the given path segment.
+fn encode_segment(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// Decode the given percent-encoded path segment, or return `None` if it
+/// is not validly encoded.
+fn decode_segment(segment: &str) -> Option<String> {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = segment.get(i + 1..i + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+/// Format the given timestamp (in milliseconds) as an RFC 3339 date.
+///
+/// Timestamps are claimed by authors, so the ones past the end of year 9999,
+/// which RFC 3339 cannot represent, are clamped to it.
+pub(super) fn format_date(timestamp: u64) -> String {
+    let timestamp = timestamp.min(MAX_TIMESTAMP);
+    humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_millis(timestamp)).to_string()
+}
+
+/// A public post to be shown on the web.
+struct Post<'a> {
+    msg_ref: &'a str,
+    author: &'a str,
+    /// Name of the author.
+    name: String,
+    text: &'a str,
+    channel: Option<&'a str>,
+    /// Timestamp claimed by the author, in milliseconds.
+    timestamp: u64,
+}
+
+impl<'a> Post<'a> {
+    /// Return the given message as a post, or `None` if it is not a public
+    /// post to be shown on the web.
+    fn from_kvt(indexes: &Indexes, msg_kvt: &'a MessageKvt) -> Result<Option<Post<'a>>> {
+        let value = &msg_kvt.value;
+        let content = &value["content"];
+        let (author, text) = match (value["author"].as_str(), content["text"].as_str()) {
+            (Some(author), Some(text)) if content["type"] == "post" => (author, text),
+            _ => return Ok(None),
+        };
+        if indexes.is_muted(content) || indexes.is_hidden_from_web(author)? {
+            return Ok(None);
+        }
+
+        Ok(Some(Post {
+            msg_ref: &msg_kvt.key,
+            author,
+            name: display_name(indexes, author)?,
+            text,
+            channel: content["channel"].as_str(),
+            timestamp: value["timestamp"].as_f64().unwrap_or_default() as u64,
+        }))
+    }
+
+    /// Render the post as an article.
+    fn render(&self) -> String {
+        let date = format_date(self.timestamp);
+        let mut html = format!(
+            "<article><header><a href=\"{}\">{}</a> · <a href=\"{}\">{}</a>",
+            path_of(self.author).unwrap_or_default(),
+            escape(&self.name),
+            path_of(self.msg_ref).unwrap_or_default(),
+            date
+        );
+        if let Some(channel) = self.channel {
+            html.push_str(&format!(
+                " in <a href=\"{}\">#{}</a>",
+                channel_path(channel),
+                escape(channel.trim_start_matches('#'))
+            ));
+        }
+        html.push_str("</header>");
+        html.push_str(&render_text(self.text));
+        html.push_str("</article>");
+
+        html
+    }
+
+    /// Return the post as an entry of a syndication feed, linking to the
+    /// viewer at the given base URL.
+    fn to_entry(&self, base_url: &str) -> Entry {
+        Entry {
+            id: ssb_uri::to_uri(self.msg_ref).unwrap_or_else(|_| self.msg_ref.to_owned()),
+            link: format!("{}{}", base_url, path_of(self.msg_ref).unwrap_or_default()),
+            author: self.name.to_owned(),
+            title: syndication::title_of(self.text),
+            content: render_text(self.text),
+            published: self.timestamp,
+        }
+    }
+}
+
+/// Return the messages of the given timeline entries (authors and sequence
+/// numbers) which are public posts to be shown on the web, up to a page.
+fn public_posts(
+    db: &KvStorage,
+    indexes: &Indexes,
+    entries: impl Iterator<Item = (String, u64)>,
+) -> Result<Vec<MessageKvt>> {
+    let mut posts = Vec::new();
+    for (author, sequence) in entries {
+        if posts.len() == PAGE_SIZE {
+            break;
+        }
+        if let Some(msg_kvt) = db.get_msg_kvt(&author, sequence)? {
+            if Post::from_kvt(indexes, &msg_kvt)?.is_some() {
+                posts.push(msg_kvt);
+            }
+        }
+    }
+
+    Ok(posts)
+}
+
+/// Respond with the given public posts, either as a page with the given
+/// title, path and header or, if a syndication format is given, as a feed
+/// in that format linking to the viewer at the given base URL.
+fn posts_response(
+    indexes: &Indexes,
+    posts: &[MessageKvt],
+    title: &str,
+    path: &str,
+    header: &str,
+    format: Option<Syndication>,
+    base_url: &str,
+) -> Result<Response> {
+    let mut rendered = Vec::new();
+    for msg_kvt in posts {
+        rendered.extend(Post::from_kvt(indexes, msg_kvt)?);
+    }
+
+    Ok(match format {
+        Some(format) => {
+            let entries: Vec<Entry> = rendered
+                .iter()
+                .map(|post| post.to_entry(base_url))
+                .collect();
+            let file_name = match format {
+                Syndication::Atom => "atom.xml",
+                Syndication::Rss => "rss.xml",
+            };
+            let link = format!("{}{}", base_url, path);
+            let feed = syndication::render(
+                format,
+                title,
+                &format!("{}/{}", link, file_name),
+                &link,
+                &entries,
+            );
+            Response::ok(format.content_type(), feed)
+        }
+        None => {
+            let body: String = rendered.iter().map(Post::render).collect();
+            Response::ok(
+                HTML,
+                render_page(title, Some(path), &format!("{}{}", header, body)),
+            )
+        }
+    })
+}
+
+/// Render the front page, listing the latest public posts.
+fn front_page(db: &KvStorage) -> Result<Response> {
+    let indexes = db.indexes.as_ref().ok_or(Error::Indexes)?;
+    let timeline = indexes.get_timeline(TimelineOrder::Claimed, None, MAX_SCANNED)?;
+    let entries = timeline
+        .entries
+        .into_iter()
+        .map(|entry| (entry.author, entry.sequence));
+
+    let mut body = String::new();
+    for msg_kvt in public_posts(db, indexes, entries)? {
+        body.extend(Post::from_kvt(indexes, &msg_kvt)?.map(|post| post.render()));
+    }
+
+    Ok(Response::ok(HTML, render_page("Latest posts", None, &body)))
+}
+
+/// Respond with the profile and the latest public posts of the given feed.
+fn feed_page(
+    db: &KvStorage,
+    feed_id: &str,
+    format: Option<Syndication>,
+    base_url: &str,
+) -> Result<Response> {
+    let indexes = db.indexes.as_ref().ok_or(Error::Indexes)?;
+    let latest_seq = match db.get_latest_seq(feed_id)? {
+        Some(latest_seq) if !indexes.is_hidden_from_web(feed_id)? => latest_seq,
+        _ => return Ok(Response::error(404, "Not Found")),
+    };
+
+    let about = indexes.get_about(feed_id)?;
+    let mut header = String::from("<section class=\"profile\">");
+    if let Some(path) = about.image.resolve().and_then(path_of) {
+        header.push_str(&format!("<img src=\"{}\" alt=\"\">", path));
+    }
+    header.push_str(&format!("<p class=\"note\">{}</p>", escape(feed_id)));
+    if let Some(description) = about.description.resolve() {
+        header.push_str(&render_text(description));
+    }
+    header.push_str("</section>");
+
+    let entries = (1..=latest_seq)
+        .rev()
+        .take(MAX_SCANNED)
+        .map(|sequence| (feed_id.to_owned(), sequence));
+    let posts = public_posts(db, indexes, entries)?;
+
+    posts_response(
+        indexes,
+        &posts,
+        &display_name(indexes, feed_id)?,
+        &path_of(feed_id).unwrap_or_default(),
+        &header,
+        format,
+        base_url,
+    )
+}
+
+/// Respond with the latest public posts of the given channel.
+fn channel_page(
+    db: &KvStorage,
+    channel: &str,
+    format: Option<Syndication>,
+    base_url: &str,
+) -> Result<Response> {
+    let indexes = db.indexes.as_ref().ok_or(Error::Indexes)?;
+    if indexes.is_channel_muted(channel) {
+        return Ok(Response::error(404, "Not Found"));
+    }
+
+    let entries = indexes
+        .get_channel_posts(channel, MAX_SCANNED)?
+        .into_iter()
+        .map(|entry| (entry.author, entry.sequence));
+    let posts = public_posts(db, indexes, entries)?;
+
+    posts_response(
+        indexes,
+        &posts,
+        &format!("#{}", channel.trim_start_matches('#')),
+        &channel_path(channel),
+        "",
+        format,
+        base_url,
+    )
+}
+
+/// Render the thread of the given message: its root message and the
+/// replies to it, from oldest to newest.
+fn thread_page(db: &KvStorage, msg_ref: &str) -> Result<Response> {
+    let indexes = db.indexes.as_ref().ok_or(Error::Indexes)?;
+    let msg_val = match db.get_msg_val(msg_ref)? {
+        Some(msg_val) => msg_val,
+        None => return Ok(Response::error(404, "Not Found")),
+    };
+    let root = match msg_val.value["content"]["root"].as_str() {
+        Some(root) => ssb_uri::to_sigil(root)?,
+        None => msg_ref.to_owned(),
+    };
+
+    let mut html = String::new();
+    let root_kvt = match db.get_msg_val(&root)? {
+        Some(root_val) => db.get_msg_kvt(&root_val.author().to_string(), root_val.sequence())?,
+        None => None,
+    };
+    let root_post = match &root_kvt {
+        Some(root_kvt) => Post::from_kvt(indexes, root_kvt)?,
+        None => None,
+    };
+    let title = match root_post {
+        Some(root_post) => {
+            html.push_str(&root_post.render());
+            "Thread"
+        }
+        None => {
+            html.push_str("<p class=\"note\">The start of this thread is not shown.</p>");
+            "Replies"
+        }
+    };
+
+    for reply in indexes.get_replies(&root)? {
+        if let Some(msg_kvt) = db.get_msg_kvt(&reply.author, reply.sequence)? {
+            html.extend(Post::from_kvt(indexes, &msg_kvt)?.map(|post| post.render()));
+        }
+    }
+
+    Ok(Response::ok(HTML, render_page(title, None, &html)))
+}
+
+/// Return the content type of the given blob, for the image formats which
+/// browsers display inline.
+fn content_type(data: &[u8]) -> &'static str {
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        "image/png"
+    } else if data.starts_with(&[0xff, 0xd8, 0xff]) {
+        "image/jpeg"
+    } else if data.starts_with(b"GIF8") {
+        "image/gif"
+    } else if data.len() >= 12 && data.starts_with(b"RIFF") && &data[8..12] == b"WEBP" {
+        "image/webp"
+    } else {
+        "application/octet-stream"
+    }
+}
+
+/// Serve the given blob, if it is referenced by an author whose messages
+/// are shown on the web.
+async fn blob(blob_id: &str) -> Result<Response> {
+    {
+        let db = KV_STORE.read().await;
+        let indexes = db.indexes.as_ref().ok_or(Error::Indexes)?;
+        let mut shown = false;
+        for author in indexes.get_blob_authors(blob_id)? {
+            if !indexes.is_hidden_from_web(&author)? {
+                shown = true;
+                break;
+            }
+        }
+        if !shown {
+            return Ok(Response::error(404, "Not Found"));
+        }
+    }
+
+    match BLOB_STORE.read().await.get(blob_id) {
+        Ok(data) => Ok(Response::ok(content_type(&data), data)),
+        Err(_) => Ok(Response::error(404, "Not Found")),
+    }
+}
+
+/// Return the syndication format requested by the given trailing path
+/// segments (none for a page, or the file name of a feed), or `None` if
+/// they do not name a feed.
+fn format_of(segments: &[&str]) -> Option<Option<Syndication>> {
+    match segments {
+        [] => Some(None),
+        [file_name] => Syndication::from_file_name(file_name).map(Some),
+        _ => None,
+    }
+}
+
+/// Respond to the given request.
+async fn route(head: &RequestHead) -> Result<Response> {
+    // Feeds link to the viewer as it was requested.
+    let base_url = format!(
+        "{}://{}",
+        head.forwarded_proto.as_deref().unwrap_or("http"),
+        head.host.as_deref().unwrap_or("localhost")
+    );
+
+    let segments: Vec<&str> = head.path.trim_start_matches('/').split('/').collect();
+    match segments.as_slice() {
+        [""] => front_page(&KV_STORE.read().await),
+        ["feed", key, rest @ ..] => match (link_of("feed", key), format_of(rest)) {
+            (Some(feed_id), Some(format)) => {
+                feed_page(&KV_STORE.read().await, &feed_id, format, &base_url)
+            }
+            _ => Ok(Response::error(404, "Not Found")),
+        },
+        ["channel", channel, rest @ ..] => match (decode_segment(channel), format_of(rest)) {
+            (Some(channel), Some(format)) if !channel.is_empty() => {
+                channel_page(&KV_STORE.read().await, &channel, format, &base_url)
+            }
+            _ => Ok(Response::error(404, "Not Found")),
+        },
+        ["thread", key] => match link_of("message", key) {
+            Some(msg_ref) => thread_page(&KV_STORE.read().await, &msg_ref),
+            None => Ok(Response::error(404, "Not Found")),
+        },
+        ["blob", key] => match link_of("blob", key) {
+            Some(blob_id) => blob(&blob_id).await,
+            None => Ok(Response::error(404, "Not Found")),
+        },
+        _ => Ok(Response::error(404, "Not Found")),
+    }
+}
+
+/// Serve a single request on the given stream.
+async fn serve(mut stream: TcpStream) -> Result<()> {
+    let (head, _body) = match io::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await? {
+        Some(request) => request,
+        None => return Ok(()),
+    };
+    debug!("Viewer request: {} {}", head.method, head.path);
+
+    let response = if head.method != "GET" {
+        Response::error(405, "Method Not Allowed")
+    } else {
+        match route(&head).await {
+            Ok(response) => response,
+            Err(err) => {
+                warn!("Failed to render {}: {}", head.path, err);
+                Response::error(500, "Internal Server Error")
+            }
+        }
+    };
+
+    stream.write_all(&response.to_bytes()).await?;
+    stream.flush().await?;
+
+    Ok(())
+}
+
+/// Serve the public web viewer on the given address.
+pub async fn actor(addr: SocketAddr) -> Result<()> {
+    let broker = BROKER.lock().await.register("viewer", false).await?;
+
+    let mut ch_terminate = broker.ch_terminate.fuse();
+
+    let listener = TcpListener::bind(addr).await?;
+    let mut incoming = listener.incoming();
+    info!("Viewer started on: http://{}", listener.local_addr()?);
+
+    loop {
+        select_biased! {
+            _ = ch_terminate => break,
+            stream = incoming.next().fuse() => {
+                match stream {
+                    Some(Ok(stream)) => {
+                        Broker::spawn("viewer-request", serve(stream));
+                    }
+                    Some(Err(err)) => warn!("Failed to accept viewer connection: {}", err),
+                    None => break,
+                }
+            },
+        }
+    }
+
+    let _ = broker.ch_terminated.send(Void {});
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const FEED: &str = "@HEqy940T6uB+T+d9Jaa58aNfRzLx9eRWqkZljBmnkmk=.ed25519";
+    const BLOB: &str = "&HEqy940T6uB+T+d9Jaa58aNfRzLx9eRWqkZljBmnkmk=.sha256";
+
+    #[test]
+    fn test_paths() {
+        let feed_path = path_of(FEED).unwrap();
+        assert_eq!(
+            feed_path,
+            "/feed/HEqy940T6uB-T-d9Jaa58aNfRzLx9eRWqkZljBmnkmk="
+        );
+        assert_eq!(link_of("feed", &feed_path[6..]).as_deref(), Some(FEED));
+
+        let blob_path = path_of(BLOB).unwrap();
+        assert!(blob_path.starts_with("/blob/"));
+        assert_eq!(link_of("blob", &blob_path[6..]).as_deref(), Some(BLOB));
+
+        assert_eq!(path_of("#solar"), None);
+        assert_eq!(link_of("feed", "not-a-key"), None);
+    }
+
+    #[test]
+    fn test_channel_paths() {
+        assert_eq!(channel_path("#solar"), "/channel/solar");
+        assert_eq!(channel_path("été/2"), "/channel/%C3%A9t%C3%A9%2F2");
+        assert_eq!(
+            decode_segment("%C3%A9t%C3%A9%2F2").as_deref(),
+            Some("été/2")
+        );
+        assert_eq!(decode_segment("%C3"), None);
+        assert_eq!(decode_segment("%zz"), None);
+        assert_eq!(format_of(&[]), Some(None));
+        assert_eq!(format_of(&["rss.xml"]), Some(Some(Syndication::Rss)));
+        assert_eq!(format_of(&["index.html"]), None);
+    }
+
+    #[test]
+    fn test_render_text() {
+        assert_eq!(
+            render_text("<b>hi</b>\n\nsecond\nline"),
+            "<p>&lt;b&gt;hi&lt;/b&gt;</p><p>second<br>line</p>"
+        );
+
+        let html = render_text(&format!("hi [glyph]({FEED})! ![cat]({BLOB})"));
+        assert_eq!(
+            html,
+            format!(
+                "<p>hi <a href=\"{}\">glyph</a>! <img src=\"{}\" alt=\"cat\"></p>",
+                path_of(FEED).unwrap(),
+                path_of(BLOB).unwrap()
+            )
+        );
+
+        // Invalid links are rendered as text.
+        assert_eq!(render_text("[x](@abc.ed25519)"), "<p>[x](@abc.ed25519)</p>");
+    }
+
+    #[test]
+    fn test_format_date() {
+        assert_eq!(format_date(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_date(MAX_TIMESTAMP), "9999-12-31T23:59:59Z");
+        assert_eq!(format_date(u64::MAX), "9999-12-31T23:59:59Z");
+    }
+
+    #[test]
+    fn test_content_type() {
+        assert_eq!(content_type(b"\x89PNG\r\n\x1a\n...."), "image/png");
+        assert_eq!(content_type(b"RIFF....WEBPVP8 "), "image/webp");
+        assert_eq!(content_type(b"<svg></svg>"), "application/octet-stream");
+    }
+}
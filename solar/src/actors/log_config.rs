@@ -0,0 +1,93 @@
+//! Log Configuration Watcher
+//!
+//! Watches the `log.toml` file in the root data directory and applies the
+//! log filter it contains each time the file is modified. This allows log
+//! levels to be changed without restarting the node (and losing its state).
+//!
+//! The file contains a single `filter` entry, using the same directive format
+//! as the `--log-filter` CLI option:
+//!
+//! ```toml
+//! filter = "info,ebt-handler=trace"
+//! ```
+//!
+//! If the file exists when the node is started, its filter takes precedence
+//! over the filter supplied via the CLI.
+use std::{
+    fs,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use async_std::stream;
+use futures::{select_biased, stream::StreamExt, FutureExt};
+use log::{info, warn};
+use serde::Deserialize;
+
+use crate::{
+    broker::{ActorEndpoint, BROKER},
+    logger, Result,
+};
+
+/// Name of the log configuration file in the root data directory.
+pub const LOG_CONFIG_FILE: &str = "log.toml";
+
+/// Contents of the log configuration file.
+#[derive(Debug, Deserialize)]
+struct LogFileConfig {
+    filter: String,
+}
+
+/// Read the log configuration file and apply the filter it contains.
+fn apply(path: &PathBuf) -> Result<()> {
+    let config: LogFileConfig = toml::from_str(&fs::read_to_string(path)?)?;
+    logger::set_filter(&config.filter)?;
+
+    info!("Applied log filter from {:?}: {}", path, config.filter);
+
+    Ok(())
+}
+
+/// Start the log configuration watcher.
+///
+/// Register the watcher with the broker (as an actor) and check the
+/// modification time of the log configuration file at the given interval.
+/// Apply the filter whenever the file has been created or modified.
+pub async fn actor(path: PathBuf, interval: Duration) -> Result<()> {
+    // Register the log configuration watcher actor with the broker.
+    let ActorEndpoint { ch_terminate, .. } = BROKER
+        .lock()
+        .await
+        .register("log-config-watcher", false)
+        .await?;
+
+    let mut ch_terminate_fuse = ch_terminate.fuse();
+    let mut ticker = stream::interval(interval).fuse();
+
+    // Modification time of the file when the filter was last applied.
+    let mut last_modified: Option<SystemTime> = None;
+
+    loop {
+        // Check the file before waiting for the first tick so that an
+        // existing configuration is applied on startup.
+        let modified = fs::metadata(&path).and_then(|metadata| metadata.modified());
+        if let Ok(modified) = modified {
+            if last_modified != Some(modified) {
+                last_modified = Some(modified);
+                if let Err(err) = apply(&path) {
+                    warn!("Failed to apply log filter from {:?}: {}", path, err)
+                }
+            }
+        }
+
+        select_biased! {
+            // Received termination signal. Break out of the loop.
+            _value = ch_terminate_fuse => {
+                break;
+            },
+            _tick = ticker.next() => {}
+        }
+    }
+
+    Ok(())
+}
@@ -1,5 +1,12 @@
 pub mod ctrlc;
+pub mod email;
+pub mod follow_back;
 pub mod jsonrpc;
+pub mod log_config;
 pub mod muxrpc;
 pub mod network;
+pub mod outbox;
 pub mod replication;
+pub mod retention;
+pub mod social;
+pub mod webhooks;
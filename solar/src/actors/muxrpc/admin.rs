@@ -0,0 +1,261 @@
+//! Admin handler.
+//!
+//! Serves the non-standard `admin` methods with which a headless node can be
+//! managed from another SSB device over a regular connection, without
+//! exposing the JSON-RPC server. Only peers listed under `admins` in
+//! `replication.toml` may call them (the identity of the peer is
+//! authenticated by the secret handshake); other peers receive an error.
+//!
+//! All methods are async:
+//!
+//! - `admin.status`: returns the ID of the node, the number of connections
+//!   and stored feeds, and the storage pressure
+//! - `admin.connect(address)`: dials the peer at the given multiserver
+//!   address (`net:<host>:<port>~shs:<key>`)
+//! - `admin.prune(hops)`: deletes the stored feeds of authors further than
+//!   the given number of hops from the local identity, returning their IDs
+//! - `admin.replicateAdd(id)`: replicates the given feed over EBT until the
+//!   node is restarted
+
+use std::marker::PhantomData;
+
+use async_std::io::Write;
+use async_trait::async_trait;
+use futures::SinkExt;
+use kuska_ssb::{api::ApiCaller, crypto::ToSodiumObject, rpc};
+use log::info;
+use serde_json::{json, Value};
+
+use crate::{
+    actors::{
+        muxrpc::handler::{RpcHandler, RpcInput},
+        network::{
+            connection_manager::CONNECTION_MANAGER, connection_scheduler::DialRequest, room_invite,
+        },
+        replication::ebt::EbtEvent,
+        retention::{pressure, prune},
+    },
+    broker::{BrokerEvent, BrokerMessage, ChBrokerSend, Destination},
+    config::{ADMIN_PEERS, SECRET_CONFIG},
+    error::Error,
+    node::KV_STORE,
+    util::prefixed,
+    Result,
+};
+
+/// Namespace of the admin MUXRPC methods.
+const ADMIN_NAMESPACE: &str = "admin";
+
+/// Return the name of the admin method called by the given request, if any.
+fn admin_method(req: &rpc::Body) -> Option<&str> {
+    match req.name.as_slice() {
+        [namespace, method] if namespace == ADMIN_NAMESPACE => Some(method.as_str()),
+        _ => None,
+    }
+}
+
+/// Return the first argument of the given request as a string.
+fn string_arg(args: &[Value]) -> Result<&str> {
+    args.first()
+        .and_then(Value::as_str)
+        .ok_or_else(|| Error::Other("expected a string argument".to_string()))
+}
+
+/// Admin handler. Serves the admin methods to the admins of the node.
+pub struct AdminHandler<W>
+where
+    W: Write + Unpin + Send + Sync,
+{
+    /// Public key of the local identity.
+    local_id: String,
+    /// Public key of the remote peer.
+    peer_ssb_id: String,
+    /// Whether the remote peer is an admin.
+    is_admin: bool,
+    phantom: PhantomData<W>,
+}
+
+impl<W> AdminHandler<W>
+where
+    W: Write + Unpin + Send + Sync,
+{
+    /// Instantiate a new handler for a connection with the given peer.
+    pub fn new(peer_ssb_id: &str) -> Self {
+        let local_id = SECRET_CONFIG
+            .get()
+            .map(|secret| secret.public_key.to_owned())
+            .unwrap_or_default();
+        let peer_ssb_id = prefixed(&peer_ssb_id);
+        let is_admin = ADMIN_PEERS
+            .get()
+            .map(|admins| admins.contains(&peer_ssb_id))
+            .unwrap_or(false);
+
+        Self {
+            local_id,
+            peer_ssb_id,
+            is_admin,
+            phantom: PhantomData,
+        }
+    }
+}
+
+#[async_trait]
+impl<W> RpcHandler<W> for AdminHandler<W>
+where
+    W: Write + Unpin + Send + Sync,
+{
+    fn name(&self) -> &'static str {
+        "AdminHandler"
+    }
+
+    async fn handle(
+        &mut self,
+        api: &mut ApiCaller<W>,
+        op: &RpcInput,
+        ch_broker: &mut ChBrokerSend,
+    ) -> Result<bool> {
+        match op {
+            RpcInput::Network(req_no, rpc::RecvMsg::RpcRequest(req)) => match admin_method(req) {
+                Some(method) => {
+                    self.recv_admin_request(api, *req_no, req, method, ch_broker)
+                        .await
+                }
+                None => Ok(false),
+            },
+            _ => Ok(false),
+        }
+    }
+}
+
+impl<W> AdminHandler<W>
+where
+    W: Write + Unpin + Send + Sync,
+{
+    /// Respond to a call of the given admin method. Only admins are
+    /// answered; the outcome of the call is sent as the response or error.
+    async fn recv_admin_request(
+        &mut self,
+        api: &mut ApiCaller<W>,
+        req_no: i32,
+        req: &rpc::Body,
+        method: &str,
+        ch_broker: &mut ChBrokerSend,
+    ) -> Result<bool> {
+        if !self.is_admin {
+            api.rpc()
+                .send_error(req_no, req.rpc_type, "not permitted")
+                .await?;
+
+            return Ok(true);
+        }
+
+        info!("Admin {} called admin.{}", self.peer_ssb_id, method);
+
+        let args: Vec<Value> = serde_json::from_value(req.args.clone()).unwrap_or_default();
+        let result = match method {
+            "status" => self.status().await,
+            "connect" => self.connect(&args, ch_broker).await,
+            "prune" => self.prune(&args, ch_broker).await,
+            "replicateAdd" => self.replicate_add(&args, ch_broker).await,
+            _ => Err(Error::Other(format!("unknown admin method: {method}"))),
+        };
+
+        match result {
+            Ok(response) => {
+                api.rpc()
+                    .send_response(
+                        req_no,
+                        rpc::RpcType::Async,
+                        rpc::BodyType::JSON,
+                        &serde_json::to_vec(&response)?,
+                    )
+                    .await?
+            }
+            Err(err) => {
+                api.rpc()
+                    .send_error(req_no, req.rpc_type, &err.to_string())
+                    .await?
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Return the status of the node.
+    async fn status(&self) -> Result<Value> {
+        let connections = CONNECTION_MANAGER.read().await.connections().len();
+        let feeds = KV_STORE.read().await.get_peers().await?.len();
+
+        Ok(json!({
+            "id": self.local_id,
+            "connections": connections,
+            "feeds": feeds,
+            "storage": pressure::status(),
+        }))
+    }
+
+    /// Dial the peer at the multiserver address given as argument.
+    async fn connect(&self, args: &[Value], ch_broker: &mut ChBrokerSend) -> Result<Value> {
+        let (public_key, addr) = room_invite::parse_multiserver_address(string_arg(args)?)?;
+
+        ch_broker
+            .send(BrokerEvent::new(
+                Destination::Broadcast,
+                BrokerMessage::Dial(DialRequest((public_key, addr))),
+            ))
+            .await?;
+
+        Ok(json!(true))
+    }
+
+    /// Prune the feeds beyond the number of hops given as argument and
+    /// revoke their replication.
+    async fn prune(&self, args: &[Value], ch_broker: &mut ChBrokerSend) -> Result<Value> {
+        let max_hops = args
+            .first()
+            .and_then(Value::as_u64)
+            .ok_or_else(|| Error::Other("expected the number of hops".to_string()))?;
+
+        let pruned = prune::prune_and_revoke(&self.local_id, max_hops as usize, ch_broker).await?;
+
+        Ok(json!(pruned))
+    }
+
+    /// Replicate the feed given as argument.
+    async fn replicate_add(&self, args: &[Value], ch_broker: &mut ChBrokerSend) -> Result<Value> {
+        let feed_id = string_arg(args)?.trim_start_matches('@');
+        feed_id.to_ed25519_pk()?;
+
+        ch_broker
+            .send(BrokerEvent::new(
+                Destination::Broadcast,
+                BrokerMessage::Ebt(EbtEvent::Replicate(format!("@{feed_id}"))),
+            ))
+            .await?;
+
+        Ok(json!(true))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_admin_method() {
+        let body = |name: &[&str]| rpc::Body {
+            name: name.iter().map(|part| part.to_string()).collect(),
+            rpc_type: rpc::RpcType::Async,
+            args: json!([]),
+        };
+
+        assert_eq!(admin_method(&body(&["admin", "status"])), Some("status"));
+        assert_eq!(
+            admin_method(&body(&["admin", "replicateAdd"])),
+            Some("replicateAdd")
+        );
+        assert_eq!(admin_method(&body(&["whoami"])), None);
+        assert_eq!(admin_method(&body(&["blobs", "get"])), None);
+    }
+}
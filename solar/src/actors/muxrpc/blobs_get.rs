@@ -12,9 +12,13 @@ use kuska_ssb::{
     rpc,
 };
 use log::{info, trace, warn};
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    actors::muxrpc::handler::{RpcHandler, RpcInput},
+    actors::{
+        muxrpc::handler::{RpcHandler, RpcInput},
+        replication::blobs,
+    },
     broker::{BrokerMessage, ChBrokerSend},
     node::BLOB_STORE,
     storage::blob::ToBlobHashId,
@@ -24,6 +28,26 @@ use crate::{
 #[derive(Debug, Clone)]
 pub struct RpcBlobsGetEvent(pub dto::BlobsGetIn);
 
+/// Method used to request a byte range of a blob, allowing an interrupted
+/// download to resume.
+pub const BLOBS_GET_SLICE_METHOD: [&str; 2] = ["blobs", "getSlice"];
+
+/// Arguments of a `blobs.getSlice` request. The slice covers the bytes from
+/// `start` (inclusive) to `end` (exclusive, defaulting to the end of the
+/// blob).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlobsGetSliceIn {
+    pub key: String,
+    #[serde(default)]
+    pub start: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max: Option<u64>,
+}
+
 pub struct BlobsGetHandler<W>
 where
     W: Write + Unpin + Send + Sync,
@@ -62,6 +86,11 @@ where
         _ch_broker: &mut ChBrokerSend,
     ) -> Result<bool> {
         match op {
+            RpcInput::Network(req_no, rpc::RecvMsg::RpcRequest(req))
+                if req.name == BLOBS_GET_SLICE_METHOD =>
+            {
+                return self.recv_get_slice(api, *req_no, req).await;
+            }
             RpcInput::Network(req_no, rpc::RecvMsg::RpcRequest(req)) => {
                 match ApiMethod::from_rpc_body(req) {
                     Some(ApiMethod::BlobsGet) => return self.recv_get(api, *req_no, req).await,
@@ -129,6 +158,60 @@ where
         Ok(true)
     }
 
+    async fn recv_get_slice(
+        &mut self,
+        api: &mut ApiCaller<W>,
+        req_no: i32,
+        req: &rpc::Body,
+    ) -> Result<bool> {
+        let mut args: Vec<BlobsGetSliceIn> = serde_json::from_value(req.args.clone())?;
+        let args = match args.pop() {
+            Some(args) => args,
+            None => {
+                api.rpc()
+                    .send_error(req_no, req.rpc_type, "missing slice arguments")
+                    .await?;
+                return Ok(true);
+            }
+        };
+
+        trace!(target: "ssb-blob", "requested slice {}..{:?} of blob {}", args.start, args.end, args.key);
+
+        if !BLOB_STORE.read().await.exists(&args.key) {
+            api.rpc()
+                .send_error(req_no, req.rpc_type, "blob not found")
+                .await?;
+            return Ok(true);
+        }
+
+        let data = BLOB_STORE.read().await.get(&args.key)?;
+        let len = data.len() as u64;
+
+        let error = if args.size.map_or(false, |size| size != len) {
+            Some("blob.len != expected")
+        } else if args.max.map_or(false, |max| len > max) {
+            Some("blob.len > max")
+        } else if args.start > len || args.end.map_or(false, |end| end < args.start) {
+            Some("invalid slice")
+        } else {
+            None
+        };
+        if let Some(error) = error {
+            trace!(target: "ssb-blob", "not sending blob slice: {}", error);
+            api.rpc().send_error(req_no, req.rpc_type, error).await?;
+            return Ok(true);
+        }
+
+        let end = args.end.map_or(len, |end| end.min(len));
+        api.blobs_get_res_send(req_no, &data[args.start as usize..end as usize])
+            .await?;
+        self.incoming_reqs.insert(req_no);
+
+        info!("Sent slice {}..{} of blob {}", args.start, end, args.key);
+
+        Ok(true)
+    }
+
     async fn recv_cancelstream(&mut self, _api: &mut ApiCaller<W>, req_no: i32) -> Result<bool> {
         Ok(self.incoming_reqs.remove(&req_no))
     }
@@ -157,6 +240,11 @@ where
     }
 
     async fn event_get(&mut self, api: &mut ApiCaller<W>, req: &dto::BlobsGetIn) -> Result<bool> {
+        if !blobs::is_fetch_allowed(&req.key, None).await? {
+            trace!(target: "ssb-blob", "not requesting blob {} (blob fetch policy)", req.key);
+            return Ok(true);
+        }
+
         info!("Requesting blob {}", req.key);
 
         let req_no = api.blobs_get_req_send(req).await?;
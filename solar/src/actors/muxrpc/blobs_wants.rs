@@ -1,6 +1,12 @@
 #![allow(clippy::single_match)]
 
-use std::{collections::HashMap, marker::PhantomData};
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    marker::PhantomData,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
 
 use async_std::io::Write;
 use async_trait::async_trait;
@@ -9,13 +15,26 @@ use kuska_ssb::{
     api::{dto, ApiCaller, ApiMethod},
     rpc,
 };
-use log::{trace, warn};
+use log::{info, trace, warn};
+use once_cell::sync::Lazy;
 
 use crate::{
-    actors::muxrpc::handler::{RpcHandler, RpcInput},
+    actors::{
+        muxrpc::{
+            blobs_get::{BlobsGetSliceIn, BLOBS_GET_SLICE_METHOD},
+            handler::{RpcHandler, RpcInput},
+        },
+        replication::{
+            blob_events::{self, BlobEvent},
+            blobs,
+            capabilities::{self, Capability},
+        },
+        retention::pressure,
+    },
     broker::{BrokerEvent, BrokerMessage, ChBrokerSend, Destination},
-    node::BLOB_STORE,
+    node::{BLOB_STORE, KV_STORE},
     storage::blob::{StoreBlobEvent, ToBlobHashId},
+    util::prefixed,
     Result,
 };
 
@@ -26,9 +45,93 @@ pub struct RpcBlobsWantsEvent(Vec<(String, i64)>);
 enum Wants {
     Pending,
     Requested(i32),
+    /// The peer has the blob, which is being fetched from another peer. The
+    /// blob is fetched from this peer if the other fetch fails.
+    Deferred,
     Available,
 }
 
+/// Time after which a blob fetch which has not completed is considered
+/// failed, allowing the blob to be fetched from another peer.
+const BLOB_FETCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Size of the slices in which large blobs are fetched. Blobs larger than a
+/// single slice are fetched with `blobs.getSlice`, so that an interrupted
+/// download resumes after the last completed slice. Blobs are fetched
+/// whole from peers which are known not to support slices.
+const BLOB_SLICE_SIZE: u64 = 512 * 1024;
+
+/// A slice of a blob being fetched.
+struct BlobSlice {
+    blob_id: String,
+    /// Offset up to which the blob has been received.
+    offset: u64,
+    /// Offset at which the slice ends.
+    end: u64,
+    /// Size of the blob.
+    size: u64,
+}
+
+/// Blobs being fetched, shared by the handlers of all connections so that
+/// each blob is only downloaded from one peer at a time.
+static BLOB_FETCHES: Lazy<Mutex<BlobFetches>> = Lazy::new(|| Mutex::new(BlobFetches::default()));
+
+/// Blobs being fetched, along with the handler (identified by actor ID)
+/// fetching each blob and the time at which the fetch started.
+#[derive(Default)]
+struct BlobFetches {
+    in_flight: HashMap<String, (usize, Instant)>,
+}
+
+impl BlobFetches {
+    /// Claim the fetch of the given blob for the given handler. Returns
+    /// `false` if another handler is fetching the blob and has not timed
+    /// out.
+    fn claim(&mut self, blob_id: &str, actor_id: usize, now: Instant) -> bool {
+        match self.in_flight.get(blob_id) {
+            Some((owner, started))
+                if *owner != actor_id && now.duration_since(*started) < BLOB_FETCH_TIMEOUT =>
+            {
+                false
+            }
+            _ => {
+                self.in_flight.insert(blob_id.to_owned(), (actor_id, now));
+                true
+            }
+        }
+    }
+
+    /// Release the claim of the given handler on the given blob, once the
+    /// fetch has completed or failed.
+    fn release(&mut self, blob_id: &str, actor_id: usize) {
+        if let Some((owner, _)) = self.in_flight.get(blob_id) {
+            if *owner == actor_id {
+                self.in_flight.remove(blob_id);
+            }
+        }
+    }
+
+    /// Release all claims of the given handler.
+    fn release_all(&mut self, actor_id: usize) {
+        self.in_flight.retain(|_, (owner, _)| *owner != actor_id);
+    }
+}
+
+/// Run the given function on the shared blob fetches.
+fn with_blob_fetches<T>(f: impl FnOnce(&mut BlobFetches) -> T) -> T {
+    let mut blob_fetches = BLOB_FETCHES.lock().unwrap_or_else(|err| err.into_inner());
+    f(&mut blob_fetches)
+}
+
+/// Return the event of a failed fetch of the given blob from the given peer.
+fn failed(peer_ssb_id: &str, blob_id: &str, err: impl ToString) -> BlobEvent {
+    BlobEvent::Failed {
+        blob_id: blob_id.to_owned(),
+        peer: prefixed(peer_ssb_id),
+        error: err.to_string(),
+    }
+}
+
 /*
 +-------+                 +-------------+                +---------+           +-------------+       +-------+
 | peer1 |                 | actor_peer1 |                | storage |           | actor_peer2 |       | peer2 |
@@ -83,28 +186,51 @@ pub struct BlobsWantsHandler<W>
 where
     W: Write + Unpin + Send + Sync,
 {
+    /// ID of the actor running the handler, identifying its blob fetches.
+    actor_id: usize,
     initialized: bool,
+    /// Public key of the peer, checked against the blob fetch policy.
+    peer_ssb_id: String,
     peer_wants_req_no: Option<i32>,
     my_wants_req_no: Option<i32>,
     peer_wants: HashMap<String, Wants>,
+    /// Sizes of the blobs announced by the peer.
+    blob_sizes: HashMap<String, u64>,
+    /// Slices being fetched, keyed by request number.
+    slices: HashMap<i32, BlobSlice>,
     phantom: PhantomData<W>,
 }
 
-impl<W> Default for BlobsWantsHandler<W>
+impl<W> BlobsWantsHandler<W>
 where
     W: Write + Unpin + Send + Sync,
 {
-    fn default() -> Self {
+    pub fn new(actor_id: usize, peer_ssb_id: &str) -> Self {
         Self {
+            actor_id,
             initialized: false,
+            peer_ssb_id: peer_ssb_id.to_owned(),
             my_wants_req_no: None,
             peer_wants_req_no: None,
             phantom: PhantomData,
             peer_wants: HashMap::new(),
+            blob_sizes: HashMap::new(),
+            slices: HashMap::new(),
         }
     }
 }
 
+impl<W> Drop for BlobsWantsHandler<W>
+where
+    W: Write + Unpin + Send + Sync,
+{
+    fn drop(&mut self) {
+        // Let other peers provide the blobs which were being fetched when
+        // the connection closed.
+        with_blob_fetches(|fetches| fetches.release_all(self.actor_id));
+    }
+}
+
 #[async_trait]
 impl<W> RpcHandler<W> for BlobsWantsHandler<W>
 where
@@ -134,6 +260,8 @@ where
                     return self.recv_wants(api, *req_no, *xtype, data, ch_broker).await;
                 } else if self.peer_wants_req_no == Some(*req_no) {
                     return self.recv_haves(api, *req_no, *xtype, data, ch_broker).await;
+                } else if self.slices.contains_key(req_no) {
+                    return self.recv_blob_slice(api, *req_no, data).await;
                 } else if self
                     .peer_wants
                     .values()
@@ -150,6 +278,34 @@ where
                     warn!("BlobsHandler got error {}", err);
                     return Ok(true);
                 }
+
+                if let Some(slice) = self.slices.remove(req_no) {
+                    // The peer may not support slices; fetch the whole blob
+                    // instead.
+                    warn!("Failed to fetch slice of blob {}: {}", slice.blob_id, err);
+                    capabilities::record(&self.peer_ssb_id, Capability::BlobSlices, false).await;
+                    let req_no = api
+                        .blobs_get_req_send(&dto::BlobsGetIn::new(slice.blob_id.clone()))
+                        .await?;
+                    self.peer_wants
+                        .insert(slice.blob_id, Wants::Requested(req_no));
+                    return Ok(true);
+                }
+
+                let actor_id = self.actor_id;
+                if let Some((blob_id, wants)) = self
+                    .peer_wants
+                    .iter_mut()
+                    .find(|(_, wants)| **wants == Wants::Requested(*req_no))
+                {
+                    // Let another peer provide the blob.
+                    warn!("Failed to fetch blob {}: {}", blob_id, err);
+                    with_blob_fetches(|fetches| fetches.release(blob_id, actor_id));
+                    *wants = Wants::Pending;
+                    let event = failed(&self.peer_ssb_id, blob_id, err);
+                    blob_events::emit(event).await;
+                    return Ok(true);
+                }
             }
             RpcInput::Message(msg) => {
                 if let BrokerMessage::RpcBlobsWants(RpcBlobsWantsEvent(ids)) = msg {
@@ -164,8 +320,25 @@ where
                     let req_no = api.blob_create_wants_req_send().await?;
                     self.my_wants_req_no = Some(req_no);
                     self.initialized = true;
+
+                    // Announce the persisted want-list to the new peer.
+                    let wants: Vec<(String, i64)> = KV_STORE
+                        .read()
+                        .await
+                        .get_blob_wants()?
+                        .into_iter()
+                        .map(|(blob_id, _)| (blob_id, -1))
+                        .collect();
+                    if !wants.is_empty() {
+                        self.event_wants_broadcast(api, &wants).await?;
+                    }
+
                     return Ok(false);
                 }
+
+                // The timer is not consumed, since other handlers rely on
+                // it too.
+                self.retry_deferred(api).await?;
             }
             _ => {}
         };
@@ -255,12 +428,17 @@ where
 
         trace!(target: "ssb-blob", "wants:{:?}", wants);
 
+        // Wants of denied peers are answered but not forwarded.
+        let forward = !blobs::is_denied_peer(&self.peer_ssb_id);
+
         for (want, distance) in wants {
             if let Some(size) = BLOB_STORE.read().await.size_of(&want)? {
                 haves.insert(want, size);
-            } else {
+            } else if forward && blobs::is_fetch_allowed(&want, None).await? {
                 self.peer_wants.insert(want.clone(), Wants::Pending);
                 broadcast.push((want, distance + 1));
+            } else {
+                trace!(target: "ssb-blob", "not forwarding want {} (blob fetch policy)", want);
             }
         }
 
@@ -299,18 +477,246 @@ where
 
         trace!(target: "ssb-blob", "haves:{:?}", haves);
 
-        for (blob_id, _) in haves {
-            if let Some(wants) = self.peer_wants.get_mut(&blob_id) {
-                let req_no = api
-                    .blobs_get_req_send(&dto::BlobsGetIn::new(blob_id.clone()))
-                    .await?;
-                *wants = Wants::Requested(req_no);
+        if blobs::is_denied_peer(&self.peer_ssb_id) {
+            trace!(target: "ssb-blob", "not fetching blobs from denied peer {}", self.peer_ssb_id);
+            return Ok(true);
+        }
+
+        for (blob_id, size) in haves {
+            if !self.peer_wants.contains_key(&blob_id) {
+                continue;
+            }
+            let size = u64::try_from(size).ok();
+            if !blobs::is_fetch_allowed(&blob_id, size).await? {
+                trace!(target: "ssb-blob", "not fetching blob {} (blob fetch policy)", blob_id);
+                continue;
             }
+            if let Some(size) = size {
+                self.blob_sizes.insert(blob_id.clone(), size);
+            }
+            self.fetch(api, &blob_id).await?;
+        }
+
+        Ok(true)
+    }
+
+    /// Request the given blob from the peer, unless it is being fetched from
+    /// another peer, in which case the request is deferred, or storage is
+    /// critically low.
+    /// Large blobs are fetched in slices, resuming any previously
+    /// interrupted download.
+    async fn fetch(&mut self, api: &mut ApiCaller<W>, blob_id: &str) -> Result<()> {
+        if !self.peer_wants.contains_key(blob_id) {
+            return Ok(());
+        }
+        if pressure::is_critical() {
+            trace!(target: "ssb-blob", "storage is critically low; not fetching blob {}", blob_id);
+            return Ok(());
+        }
+
+        let claimed =
+            with_blob_fetches(|fetches| fetches.claim(blob_id, self.actor_id, Instant::now()));
+
+        let wants = if claimed {
+            let slices_unsupported =
+                capabilities::is_unsupported(&self.peer_ssb_id, Capability::BlobSlices).await;
+            let size = self.blob_sizes.get(blob_id).copied();
+            let (req_no, offset) = match size {
+                Some(size) if size > BLOB_SLICE_SIZE && !slices_unsupported => {
+                    let offset = blobs::download_offset(blob_id).await?;
+                    let req_no = self.request_slice(api, blob_id, offset, size).await?;
+                    (req_no, offset)
+                }
+                _ => {
+                    let req_no = api
+                        .blobs_get_req_send(&dto::BlobsGetIn::new(blob_id.to_owned()))
+                        .await?;
+                    (req_no, 0)
+                }
+            };
+            blob_events::emit(BlobEvent::FetchStarted {
+                blob_id: blob_id.to_owned(),
+                peer: prefixed(&self.peer_ssb_id),
+                offset,
+                size,
+            })
+            .await;
+            Wants::Requested(req_no)
+        } else {
+            trace!(target: "ssb-blob", "deferring fetch of blob {} being fetched from another peer", blob_id);
+            Wants::Deferred
+        };
+        self.peer_wants.insert(blob_id.to_owned(), wants);
+
+        Ok(())
+    }
+
+    /// Request the slice of the given blob starting at the given offset.
+    async fn request_slice(
+        &mut self,
+        api: &mut ApiCaller<W>,
+        blob_id: &str,
+        offset: u64,
+        size: u64,
+    ) -> Result<i32> {
+        let end = (offset + BLOB_SLICE_SIZE).min(size);
+        let args = [BlobsGetSliceIn {
+            key: blob_id.to_owned(),
+            start: offset,
+            end: Some(end),
+            size: Some(size),
+            max: None,
+        }];
+
+        trace!(target: "ssb-blob", "requesting slice {}..{} of blob {}", offset, end, blob_id);
+
+        let req_no = api
+            .rpc()
+            .send_request(
+                &BLOBS_GET_SLICE_METHOD,
+                rpc::RpcType::Source,
+                rpc::ArgType::Array,
+                &args,
+                &None::<()>,
+            )
+            .await?;
+        self.slices.insert(
+            req_no,
+            BlobSlice {
+                blob_id: blob_id.to_owned(),
+                offset,
+                end,
+                size,
+            },
+        );
+
+        Ok(req_no)
+    }
+
+    /// Write the received slice data to the partial download of the blob.
+    /// Once the slice is complete, the progress is recorded in the blob
+    /// metadata and the next slice is requested, or the completed blob is
+    /// moved into the store.
+    async fn recv_blob_slice(
+        &mut self,
+        api: &mut ApiCaller<W>,
+        req_no: i32,
+        data: &[u8],
+    ) -> Result<bool> {
+        let actor_id = self.actor_id;
+        let slice = match self.slices.get_mut(&req_no) {
+            Some(slice) => slice,
+            None => return Ok(false),
+        };
+
+        if slice.offset + data.len() as u64 > slice.end {
+            warn!(
+                "Received more data than requested for slice of blob {}",
+                slice.blob_id
+            );
+            let blob_id = slice.blob_id.clone();
+            self.slices.remove(&req_no);
+            // Let another peer provide the blob.
+            with_blob_fetches(|fetches| fetches.release(&blob_id, actor_id));
+            blob_events::emit(failed(
+                &self.peer_ssb_id,
+                &blob_id,
+                "received more data than requested",
+            ))
+            .await;
+            self.peer_wants.insert(blob_id, Wants::Pending);
+            return Ok(true);
         }
 
+        BLOB_STORE
+            .write()
+            .await
+            .write_partial(&slice.blob_id, slice.offset, data)?;
+        slice.offset += data.len() as u64;
+        if slice.offset < slice.end {
+            return Ok(true);
+        }
+
+        let slice = self.slices.remove(&req_no).unwrap();
+        KV_STORE
+            .read()
+            .await
+            .set_blob_offset(&slice.blob_id, slice.offset)?;
+        capabilities::record(&self.peer_ssb_id, Capability::BlobSlices, true).await;
+        blob_events::emit(BlobEvent::Progress {
+            blob_id: slice.blob_id.clone(),
+            peer: prefixed(&self.peer_ssb_id),
+            received: slice.offset,
+            size: slice.size,
+        })
+        .await;
+
+        if slice.offset < slice.size {
+            // Renew the claim on the blob, so that the download is not taken
+            // over while it progresses.
+            let claimed = with_blob_fetches(|fetches| {
+                fetches.claim(&slice.blob_id, actor_id, Instant::now())
+            });
+            let wants = if claimed {
+                let req_no = self
+                    .request_slice(api, &slice.blob_id, slice.offset, slice.size)
+                    .await?;
+                Wants::Requested(req_no)
+            } else {
+                Wants::Deferred
+            };
+            self.peer_wants.insert(slice.blob_id, wants);
+            return Ok(true);
+        }
+
+        with_blob_fetches(|fetches| fetches.release(&slice.blob_id, actor_id));
+
+        let completed = BLOB_STORE
+            .write()
+            .await
+            .complete_partial(&slice.blob_id)
+            .await;
+        let wants = match completed {
+            Ok(_) => {
+                KV_STORE.read().await.set_blob_retrieved(&slice.blob_id)?;
+                info!("Received blob {}", slice.blob_id);
+                Wants::Available
+            }
+            Err(err) => {
+                warn!("Failed to complete blob {}: {}", slice.blob_id, err);
+                // Let another peer provide the blob.
+                blobs::discard_download(&slice.blob_id).await?;
+                blob_events::emit(failed(&self.peer_ssb_id, &slice.blob_id, err)).await;
+                Wants::Pending
+            }
+        };
+        self.peer_wants.insert(slice.blob_id, wants);
+
         Ok(true)
     }
 
+    /// Fetch the deferred blobs which have neither been stored nor claimed
+    /// by another handler since they were deferred (for example, because
+    /// the other fetch failed).
+    async fn retry_deferred(&mut self, api: &mut ApiCaller<W>) -> Result<()> {
+        let deferred: Vec<String> = self
+            .peer_wants
+            .iter()
+            .filter(|(_, wants)| **wants == Wants::Deferred)
+            .map(|(blob_id, _)| blob_id.to_owned())
+            .collect();
+
+        for blob_id in deferred {
+            if BLOB_STORE.read().await.exists(&blob_id) {
+                self.peer_wants.insert(blob_id, Wants::Available);
+            } else {
+                self.fetch(api, &blob_id).await?;
+            }
+        }
+
+        Ok(())
+    }
+
     async fn recv_blobs_get(
         &mut self,
         _api: &mut ApiCaller<W>,
@@ -319,6 +725,7 @@ where
         data: &[u8],
         _ch_broker: &mut ChBrokerSend,
     ) -> Result<bool> {
+        let actor_id = self.actor_id;
         let wants = self
             .peer_wants
             .iter_mut()
@@ -326,16 +733,60 @@ where
             .unwrap();
         let current_blob_id = data.blob_hash_id();
 
+        with_blob_fetches(|fetches| fetches.release(wants.0, actor_id));
+
+        BLOB_STORE.write().await.insert(&data).await?;
+
         if &current_blob_id != wants.0 {
             warn!(
                 "Recieved blob hash is not the expected current={} expected={}",
                 wants.0, current_blob_id
             );
+            // Let another peer provide the expected blob.
+            *wants.1 = Wants::Pending;
+            let event = failed(
+                &self.peer_ssb_id,
+                wants.0,
+                "received blob does not match the requested hash",
+            );
+            blob_events::emit(event).await;
+        } else {
+            *wants.1 = Wants::Available;
+            // The blob may have been partially fetched in slices before.
+            blobs::discard_download(&current_blob_id).await?;
         }
 
-        BLOB_STORE.write().await.insert(&data).await?;
-        *wants.1 = Wants::Available;
-
         Ok(true)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_blob_fetches() {
+        let mut fetches = BlobFetches::default();
+        let now = Instant::now();
+
+        // A blob is only fetched by one handler at a time.
+        assert!(fetches.claim("&blob", 1, now));
+        assert!(!fetches.claim("&blob", 2, now));
+        assert!(fetches.claim("&blob", 1, now));
+
+        // Only the fetching handler releases the claim.
+        fetches.release("&blob", 2);
+        assert!(!fetches.claim("&blob", 2, now));
+        fetches.release("&blob", 1);
+        assert!(fetches.claim("&blob", 2, now));
+
+        // A stalled fetch can be taken over.
+        assert!(fetches.claim("&other", 2, now));
+        assert!(!fetches.claim("&other", 3, now + BLOB_FETCH_TIMEOUT / 2));
+        assert!(fetches.claim("&other", 3, now + BLOB_FETCH_TIMEOUT));
+
+        fetches.release_all(2);
+        assert!(fetches.claim("&blob", 3, now));
+        assert!(!fetches.claim("&other", 1, now + BLOB_FETCH_TIMEOUT));
+    }
+}
@@ -1,6 +1,6 @@
 //! Epidemic Broadcast Tree (EBT) Replication Handler.
 
-use std::marker::PhantomData;
+use std::{marker::PhantomData, sync::Arc};
 
 use async_std::io::Write;
 use futures::SinkExt;
@@ -17,23 +17,35 @@ use log::{trace, warn};
 use crate::{
     actors::{
         muxrpc::{ReqNo, RpcInput},
-        replication::ebt::{EbtEvent, SessionRole},
+        network::reputation,
+        replication::{
+            capabilities, duplicates,
+            ebt::{
+                bendybutt, buttwoo, ActiveRequest, EbtEvent, FeedFormat, SessionRole, VectorClock,
+                EBT_REQUESTS,
+            },
+            identity_guard, quirks,
+        },
     },
     broker::{BrokerEvent, BrokerMessage, ChBrokerSend, Destination, BROKER},
     error::Error,
     Result,
 };
 
-/// EBT replicate handler. Tracks active requests and peer connections.
+/// EBT replicate handler. The active requests of each connection are
+/// tracked in the shared request registry (`EBT_REQUESTS`), keyed by
+/// connection ID and request number, so that overlapping sessions on a
+/// connection are routed correctly.
+///
+/// Classic feeds are replicated by the EBT session (via broker events),
+/// while the streams replicating Bendy Butt and buttwoo feeds are served by
+/// the handler itself.
 pub struct EbtReplicateHandler<W>
 where
     W: Write + Unpin + Send + Sync,
 {
-    /// EBT-related requests which are known and allowed.
-    // TODO: Include connection ID as key. Then we can remove request ID from
-    // all `EbtEvent` variants and simply look-up the request ID associated
-    // with the connection ID (as defined in the `EbtEvent` data).
-    active_request: ReqNo,
+    /// Tolerate the protocol quirks of legacy pubs.
+    legacy_quirks: bool,
     phantom: PhantomData<W>,
 }
 
@@ -44,11 +56,39 @@ where
     /// Instantiate a new instance of `EbtReplicateHandler`.
     pub fn new() -> Self {
         Self {
-            active_request: 0,
+            legacy_quirks: false,
             phantom: PhantomData,
         }
     }
 
+    /// Tolerate the protocol quirks of legacy pubs when parsing vector
+    /// clocks.
+    pub fn legacy_quirks(mut self, enabled: bool) -> Self {
+        self.legacy_quirks = enabled;
+        self
+    }
+
+    /// Return the replicate request of the given connection on which clocks
+    /// and messages are sent.
+    async fn active_request(connection_id: usize) -> Option<ActiveRequest> {
+        EBT_REQUESTS.read().await.get(connection_id)
+    }
+
+    /// Return the replicate request of the given connection to which the
+    /// packet received with the given request number belongs.
+    async fn request_for(connection_id: usize, req_no: ReqNo) -> Option<ActiveRequest> {
+        EBT_REQUESTS.read().await.find(connection_id, req_no)
+    }
+
+    /// Attempt to deserialize the given bytes into a vector clock.
+    fn parse_clock(&self, data: &[u8]) -> Option<VectorClock> {
+        match serde_json::from_slice(data) {
+            Ok(clock) => Some(clock),
+            Err(_) if self.legacy_quirks => quirks::parse_clock(data),
+            Err(_) => None,
+        }
+    }
+
     /// Handle an RPC event.
     pub async fn handle(
         &mut self,
@@ -57,16 +97,9 @@ where
         ch_broker: &mut ChBrokerSend,
         peer_ssb_id: String,
         connection_id: usize,
-        active_req_no: Option<ReqNo>,
     ) -> Result<bool> {
         trace!(target: "muxrpc-ebt-handler", "Received MUXRPC input: {:?}", op);
 
-        // An outbound EBT replicate request was made before the handler was
-        // called.
-        if let Some(req_no) = active_req_no {
-            self.active_request = req_no
-        }
-
         match op {
             // Handle an incoming MUXRPC request.
             RpcInput::Network(req_no, rpc::RecvMsg::RpcRequest(req)) => {
@@ -75,98 +108,80 @@ where
             }
             // Hanlde an incoming 'other' MUXRPC request.
             RpcInput::Network(req_no, rpc::RecvMsg::OtherRequest(_type, req)) => {
-                self.recv_other_request(ch_broker, *req_no, req, peer_ssb_id, connection_id)
+                self.recv_other_request(api, ch_broker, *req_no, req, peer_ssb_id, connection_id)
                     .await
             }
             // Handle an incoming MUXRPC response.
             RpcInput::Network(req_no, rpc::RecvMsg::RpcResponse(_type, res)) => {
-                self.recv_rpc_response(ch_broker, *req_no, res, peer_ssb_id, connection_id)
+                self.recv_rpc_response(api, ch_broker, *req_no, res, peer_ssb_id, connection_id)
                     .await
             }
             // Handle an incoming MUXRPC 'cancel stream' response.
             RpcInput::Network(req_no, rpc::RecvMsg::CancelStreamResponse()) => {
-                self.recv_cancelstream(api, *req_no).await
+                self.recv_cancelstream(api, *req_no, connection_id).await
             }
             // Handle an incoming MUXRPC error response.
             RpcInput::Network(req_no, rpc::RecvMsg::ErrorResponse(err)) => {
-                self.recv_error_response(*req_no, err).await
+                self.recv_error_response(*req_no, err, connection_id).await
             }
             // Handle a broker message.
             RpcInput::Message(msg) => match msg {
-                BrokerMessage::Ebt(EbtEvent::TerminateSession(conn_id, session_role)) => {
+                BrokerMessage::Ebt(EbtEvent::TerminateSession(conn_id, _session_role)) => {
                     if conn_id == &connection_id {
-                        let req_no = match session_role {
-                            SessionRole::Requester => self.active_request,
-                            SessionRole::Responder => -(self.active_request),
-                        };
-
-                        return self.send_cancelstream(api, req_no).await;
+                        // Close every replicate stream of the connection. No
+                        // stream is closed if no session was ever initiated
+                        // on the connection.
+                        let requests = EBT_REQUESTS.read().await.requests(connection_id);
+                        for request in requests {
+                            self.send_cancelstream(api, request.cancel_req_no()).await?;
+                        }
+
+                        return Ok(true);
                     }
 
                     Ok(false)
                 }
-                BrokerMessage::Ebt(EbtEvent::SendClock(conn_id, req_no, clock, session_role)) => {
-                    // This is, regrettably, rather unintuitive.
-                    //
-                    // `api.ebt_clock_res_send()` internally calls
-                    // `send_response()` which applies the negative sign to the
-                    // given request number. However, the request number should
-                    // always be positive when we are acting as the requester -
-                    // even though we are sending a "response". This is why we
-                    // apply a negative here for the session requester: so that
-                    // the double negation results in a response with a
-                    // positive request number.
-                    let req_no = match session_role {
-                        SessionRole::Requester => -(*req_no),
-                        SessionRole::Responder => *req_no,
-                    };
-
-                    // Only send the clock if the associated connection is
-                    // being handled by this instance of the handler.
-                    //
-                    // This prevents the clock being sent to every peer with
-                    // whom we have an active session and matching request
-                    // number.
+                // Clocks and messages are only sent if the associated
+                // connection is being handled by this instance of the handler
+                // (and a replicate request is active on it). This prevents
+                // them being sent to every peer with whom we have an active
+                // session.
+                BrokerMessage::Ebt(EbtEvent::SendClock(conn_id, clock)) => {
                     if *conn_id == connection_id {
-                        // Serialize the vector clock as a JSON string.
-                        let json_clock = serde_json::to_string(&clock)?;
-                        // The request number must be negative (response).
-                        api.ebt_clock_res_send(req_no, &json_clock).await?;
+                        if let Some(request) = Self::active_request(connection_id).await {
+                            api.ebt_clock_res_send(request.response_req_no(), clock.json())
+                                .await?;
 
-                        trace!(target: "ebt", "Sent clock to connection {} with request number {} as {}", conn_id, req_no, session_role);
+                            trace!(target: "ebt", "Sent clock to connection {} with request number {} as {}", conn_id, request.req_no, request.session_role);
+                        }
                     }
 
                     Ok(false)
                 }
-                BrokerMessage::Ebt(EbtEvent::SendMessage(
-                    conn_id,
-                    req_no,
-                    ssb_id,
-                    msg,
-                    session_role,
-                )) => {
-                    // Define the sign of the request number based on session
-                    // role (note: this is the opposite sign of the number
-                    // that will ultimately be sent.
-                    //
-                    // See the comment in the `SendClock` event above for
-                    // further explantation.
-                    let req_no = match session_role {
-                        SessionRole::Requester => -(*req_no),
-                        SessionRole::Responder => *req_no,
-                    };
-
-                    // Only send the message if the associated connection is
-                    // being handled by this instance of the handler.
-                    //
-                    // This prevents the message being sent to every peer with
-                    // whom we have an active session and matching request
-                    // number.
+                BrokerMessage::Ebt(EbtEvent::SendMessage(conn_id, ssb_id, msg)) => {
                     if *conn_id == connection_id {
-                        let json_msg = msg.to_string();
-                        api.ebt_feed_res_send(req_no, &json_msg).await?;
+                        if let Some(request) = Self::active_request(connection_id).await {
+                            api.ebt_feed_res_send(request.response_req_no(), msg.json())
+                                .await?;
 
-                        trace!(target: "ebt", "Sent message to {} on connection {}", ssb_id, conn_id);
+                            trace!(target: "ebt", "Sent message to {} on connection {}", ssb_id, conn_id);
+                        }
+                    }
+
+                    Ok(false)
+                }
+                BrokerMessage::Ebt(EbtEvent::SendMessages(conn_id, ssb_id, msgs)) => {
+                    // Push the batch back-to-back, without handing control
+                    // back to the broker between messages.
+                    if *conn_id == connection_id {
+                        if let Some(request) = Self::active_request(connection_id).await {
+                            for msg in msgs {
+                                api.ebt_feed_res_send(request.response_req_no(), msg.json())
+                                    .await?;
+                            }
+
+                            trace!(target: "ebt", "Sent {} messages to {} on connection {}", msgs.len(), ssb_id, conn_id);
+                        }
                     }
 
                     Ok(false)
@@ -205,11 +220,31 @@ where
         connection_id: usize,
     ) -> Result<bool> {
         // Deserialize the args from an incoming EBT replicate request.
-        let mut args: Vec<dto::EbtReplicate> = serde_json::from_value(req.args.clone())?;
+        // Terminate the stream with an error response if they are invalid.
+        let mut args: Vec<dto::EbtReplicate> = match serde_json::from_value(req.args.clone()) {
+            Ok(args) => args,
+            Err(err) => {
+                let err = Error::from(err);
+                api.rpc()
+                    .send_error(req_no, req.rpc_type, &err.to_string())
+                    .await?;
+
+                return Err(err);
+            }
+        };
         trace!(target: "ebt-handler", "Received replicate request: {:?}", args);
 
         // Retrieve the `EbtReplicate` args from the array.
-        let args = args.pop().unwrap();
+        // Terminate the stream with an error response if none were supplied.
+        let args = match args.pop() {
+            Some(args) => args,
+            None => {
+                let err_msg = String::from("ebt replicate args missing");
+                api.rpc().send_error(req_no, req.rpc_type, &err_msg).await?;
+
+                return Err(Error::EbtReplicate((req_no, err_msg)));
+            }
+        };
 
         let mut ch_broker = BROKER.lock().await.create_sender();
 
@@ -220,25 +255,45 @@ where
             let err_msg = String::from("ebt version != 3");
             api.rpc().send_error(req_no, req.rpc_type, &err_msg).await?;
 
-            return Err(Error::EbtReplicate((req_no, err_msg)));
-        } else if args.format.as_str() != "classic" {
-            let err_msg = String::from("ebt format != classic");
-            api.rpc().send_error(req_no, req.rpc_type, &err_msg).await?;
-
             return Err(Error::EbtReplicate((req_no, err_msg)));
         }
+        let format = match FeedFormat::from_name(&args.format) {
+            Some(format) => format,
+            None => {
+                let err_msg = format!("unsupported ebt format: {}", args.format);
+                api.rpc().send_error(req_no, req.rpc_type, &err_msg).await?;
+
+                return Err(Error::EbtReplicate((req_no, err_msg)));
+            }
+        };
 
         trace!(target: "ebt-handler", "Successfully validated replicate request arguments");
 
-        // Set the request number for this session.
-        self.active_request = req_no;
+        // Register the request of this session.
+        EBT_REQUESTS
+            .write()
+            .await
+            .register(connection_id, req_no, SessionRole::Responder, format);
+
+        // Bendy Butt and buttwoo feeds are replicated alongside the
+        // session, which is only initiated by requests of classic feeds.
+        let clock = match format {
+            FeedFormat::Classic => None,
+            FeedFormat::BendyButt => Some(bendybutt::local_clock().await?),
+            FeedFormat::Buttwoo => Some(buttwoo::local_clock().await?),
+        };
+        if let Some(clock) = clock {
+            api.ebt_clock_res_send(req_no, &serde_json::to_string(&clock)?)
+                .await?;
+
+            return Ok(false);
+        }
 
         ch_broker
             .send(BrokerEvent::new(
                 Destination::Broadcast,
                 BrokerMessage::Ebt(EbtEvent::SessionInitiated(
                     connection_id,
-                    req_no,
                     peer_ssb_id,
                     SessionRole::Responder,
                 )),
@@ -251,25 +306,25 @@ where
     /// Process an incoming MUXRPC request containing a vector clock.
     async fn recv_other_request(
         &mut self,
+        api: &mut ApiCaller<W>,
         ch_broker: &mut ChBrokerSend,
         req_no: ReqNo,
         req: &[u8],
         peer_ssb_id: String,
         connection_id: usize,
     ) -> Result<bool> {
+        if let Some(request) = Self::binary_request(connection_id, req_no).await {
+            return self.recv_binary(api, &request, req, &peer_ssb_id).await;
+        }
+
         // Attempt to deserialize bytes into vector clock hashmap.
         // If the deserialization is successful, emit a 'received clock'
         // event.
-        if let Ok(clock) = serde_json::from_slice(req) {
+        if let Some(clock) = self.parse_clock(req) {
             ch_broker
                 .send(BrokerEvent::new(
                     Destination::Broadcast,
-                    BrokerMessage::Ebt(EbtEvent::ReceivedClock(
-                        connection_id,
-                        req_no,
-                        peer_ssb_id,
-                        clock,
-                    )),
+                    BrokerMessage::Ebt(EbtEvent::ReceivedClock(connection_id, peer_ssb_id, clock)),
                 ))
                 .await?;
         }
@@ -281,6 +336,7 @@ where
     /// The response is expected to contain a vector clock or an SSB message.
     async fn recv_rpc_response(
         &mut self,
+        api: &mut ApiCaller<W>,
         ch_broker: &mut ChBrokerSend,
         req_no: ReqNo,
         res: &[u8],
@@ -289,28 +345,39 @@ where
     ) -> Result<bool> {
         trace!(target: "ebt-handler", "Received RPC response: {}", req_no);
 
+        if let Some(request) = Self::binary_request(connection_id, req_no).await {
+            return self.recv_binary(api, &request, res, &peer_ssb_id).await;
+        }
+
         // Only handle the response if the associated request number is known
         // to us, either because we sent or received the initiating replicate
         // request.
-        if self.active_request == req_no || self.active_request == -(req_no) {
+        let is_active = Self::request_for(connection_id, req_no).await.is_some();
+        if is_active {
             // The response may be a vector clock (aka. notes) or an SSB message.
             //
             // Since there is no explicit way to determine which was received,
             // we first attempt deserialization of a vector clock and move on
             // to attempting message deserialization if that fails.
-            if let Ok(clock) = serde_json::from_slice(res) {
+            if let Some(clock) = self.parse_clock(res) {
                 ch_broker
                     .send(BrokerEvent::new(
                         Destination::Broadcast,
                         BrokerMessage::Ebt(EbtEvent::ReceivedClock(
                             connection_id,
-                            req_no,
                             peer_ssb_id,
                             clock,
                         )),
                     ))
                     .await?;
             } else {
+                // Skip messages which have already been stored (for example,
+                // when delivered by another replication session) before
+                // performing the comparatively expensive validation.
+                if duplicates::is_stored(res).await? {
+                    return Ok(false);
+                }
+
                 // First try to deserialize the response into a message value.
                 // If that fails, try to deserialize into a message KVT and then
                 // convert that into a message value. Return an error if that fails.
@@ -321,13 +388,30 @@ where
                 // as part of the call to `from_slice`.
                 let msg = match Message::from_slice(res) {
                     Ok(msg) => msg,
-                    Err(_) => MessageKvt::from_slice(res)?.into_message()?,
+                    Err(_) => {
+                        let msg = MessageKvt::from_slice(res)
+                            .map_err(Error::from)
+                            .and_then(|kvt| kvt.into_message().map_err(Error::from));
+                        match msg {
+                            Ok(msg) => msg,
+                            Err(err) => {
+                                reputation::record_invalid_message(&peer_ssb_id);
+                                return Err(err);
+                            }
+                        }
+                    }
                 };
 
+                // Do not store messages of the local feed which are evidence
+                // of the local identity being in use on another device.
+                if identity_guard::check_received(&msg, &peer_ssb_id).await? {
+                    return Ok(false);
+                }
+
                 ch_broker
                     .send(BrokerEvent::new(
                         Destination::Broadcast,
-                        BrokerMessage::Ebt(EbtEvent::ReceivedMessage(msg)),
+                        BrokerMessage::Ebt(EbtEvent::ReceivedMessage(peer_ssb_id, Arc::new(msg))),
                     ))
                     .await?;
             }
@@ -336,13 +420,90 @@ where
         Ok(false)
     }
 
-    /// Receive close-stream request.
-    async fn recv_cancelstream(&mut self, api: &mut ApiCaller<W>, req_no: ReqNo) -> Result<bool> {
+    /// Return the replicate request of Bendy Butt or buttwoo feeds of the
+    /// given connection to which the packet received with the given request
+    /// number belongs, if any.
+    async fn binary_request(connection_id: usize, req_no: ReqNo) -> Option<ActiveRequest> {
+        Self::request_for(connection_id, req_no)
+            .await
+            .filter(|request| request.format != FeedFormat::Classic)
+    }
+
+    /// Process a packet received on a replicate stream of Bendy Butt or
+    /// buttwoo feeds. The packet is expected to contain a vector clock, which
+    /// is answered with the messages the peer is missing, or an encoded
+    /// message of the format of the stream.
+    async fn recv_binary(
+        &mut self,
+        api: &mut ApiCaller<W>,
+        request: &ActiveRequest,
+        data: &[u8],
+        peer_ssb_id: &str,
+    ) -> Result<bool> {
+        if let Ok(clock) = serde_json::from_slice::<VectorClock>(data) {
+            capabilities::record_feed_format(peer_ssb_id, request.format.name()).await;
+
+            let msgs = match request.format {
+                FeedFormat::Buttwoo => buttwoo::missing_messages(&clock).await?,
+                _ => bendybutt::missing_messages(&clock).await?,
+            };
+            for msg in &msgs {
+                api.rpc()
+                    .send_response(
+                        request.response_req_no(),
+                        rpc::RpcType::Source,
+                        rpc::BodyType::Binary,
+                        msg,
+                    )
+                    .await?;
+            }
+
+            trace!(
+                target: "ebt",
+                "Sent {} {} messages to {}",
+                msgs.len(),
+                request.format,
+                peer_ssb_id
+            );
+        } else {
+            let received = match request.format {
+                FeedFormat::Buttwoo => buttwoo::receive(data, peer_ssb_id).await.map(|_| ()),
+                _ => bendybutt::receive(data, peer_ssb_id).await.map(|_| ()),
+            };
+            if let Err(err) = received {
+                warn!(
+                    "Rejected {} message received from {}: {}",
+                    request.format, peer_ssb_id, err
+                );
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Receive close-stream request. The session only concludes once no
+    /// other replicate stream remains active on the connection.
+    async fn recv_cancelstream(
+        &mut self,
+        api: &mut ApiCaller<W>,
+        req_no: ReqNo,
+        connection_id: usize,
+    ) -> Result<bool> {
         trace!(target: "ebt-handler", "Received cancel stream RPC response: {}", req_no);
 
         api.rpc().send_stream_eof(-req_no).await?;
 
-        Ok(true)
+        Ok(!Self::close_request(connection_id, req_no).await)
+    }
+
+    /// Remove the replicate request to which the given received request
+    /// number belongs from the registry. Returns `true` if other requests of
+    /// classic feeds remain active on the connection, in which case the
+    /// session goes on.
+    async fn close_request(connection_id: usize, req_no: ReqNo) -> bool {
+        let mut requests = EBT_REQUESTS.write().await;
+        requests.remove_request(connection_id, req_no).is_some()
+            && requests.get(connection_id).is_some()
     }
 
     /// Send close-stream request.
@@ -355,10 +516,20 @@ where
     }
 
     /// Report a MUXRPC error and remove the associated request from the map of
-    /// active requests.
-    async fn recv_error_response(&mut self, req_no: ReqNo, err_msg: &str) -> Result<bool> {
+    /// active requests. The session fails unless other replicate streams
+    /// remain active on the connection.
+    async fn recv_error_response(
+        &mut self,
+        req_no: ReqNo,
+        err_msg: &str,
+        connection_id: usize,
+    ) -> Result<bool> {
         warn!("Received MUXRPC error response: {}", err_msg);
 
+        if Self::close_request(connection_id, req_no).await {
+            return Ok(false);
+        }
+
         Err(Error::EbtReplicate((req_no, err_msg.to_string())))
     }
 }
@@ -4,6 +4,7 @@ use async_std::io::Write;
 use async_trait::async_trait;
 use kuska_ssb::{
     api::{ApiCaller, ApiMethod},
+    feed::Message,
     rpc,
 };
 
@@ -71,7 +72,12 @@ where
     ) -> Result<bool> {
         let args: Vec<String> = serde_json::from_value(req.args.clone())?;
 
-        let msg_val = KV_STORE.read().await.get_msg_val(&args[0]);
+        // Messages fetched out of order are served as well, so that they
+        // propagate to the peers which ask for them.
+        let msg_val = match KV_STORE.read().await.get_msg_val(&args[0]) {
+            Ok(None) => self.get_ooo_msg_val(&args[0]).await,
+            result => result,
+        };
         match msg_val {
             Ok(Some(msg)) => api.get_res_send(req_no, &msg).await?,
             Ok(None) => {
@@ -87,4 +93,12 @@ where
 
         Ok(true)
     }
+
+    /// Get the cached out-of-order message with the given ID.
+    async fn get_ooo_msg_val(&self, msg_id: &str) -> Result<Option<Message>> {
+        match KV_STORE.read().await.get_ooo_msg_kvt(msg_id)? {
+            Some(msg_kvt) => Ok(Some(msg_kvt.into_message()?)),
+            None => Ok(None),
+        }
+    }
 }
@@ -2,7 +2,6 @@ use std::{collections::HashMap, marker::PhantomData, string::ToString};
 
 use async_std::io::Write;
 use async_trait::async_trait;
-use futures::SinkExt;
 use kuska_ssb::{
     api::{dto, ApiCaller, ApiMethod},
     feed::{Feed as MessageKvt, Message},
@@ -12,26 +11,34 @@ use log::{debug, info, warn};
 
 use crate::{
     actors::{
-        muxrpc::{
-            blobs_get::RpcBlobsGetEvent,
-            handler::{RpcHandler, RpcInput},
-        },
-        replication::blobs,
+        muxrpc::handler::{RpcHandler, RpcInput},
+        network::{clock_skew, reputation, stats},
+        replication::{config::ReplicationDirection, duplicates, ingest, quirks, trace, want_list},
+        retention::pressure,
     },
-    broker::{BrokerEvent, BrokerMessage, ChBrokerSend, Destination},
+    broker::{BrokerMessage, ChBrokerSend},
     config::{PEERS_TO_REPLICATE, RESYNC_CONFIG, SECRET_CONFIG},
     error::Error,
-    node::BLOB_STORE,
     node::KV_STORE,
-    storage::kv::StoreKvEvent,
+    storage::{
+        kv::{StoreKvEvent, TraceDirection},
+        validation::{self, Source},
+    },
+    util::prefixed,
     Result,
 };
 
+/// An incoming history stream request.
 #[derive(Debug)]
 struct HistoryStreamRequest {
     req_no: i32,
     args: dto::CreateHistoryStreamIn,
-    from: u64, // check, not sure if ok
+    /// SSB ID of the requested feed (`@`-prefixed).
+    feed_id: String,
+    /// Sequence number of the next message to be sent.
+    from: u64,
+    /// Number of messages which may still be sent, if limited.
+    remaining: Option<u64>,
 }
 
 /// History stream handler. Tracks active requests and peer connections.
@@ -40,8 +47,17 @@ where
     W: Write + Unpin + Send + Sync,
 {
     initialized: bool,
+    /// Tolerate the protocol quirks of legacy pubs.
+    legacy_quirks: bool,
+    /// Direction in which the peer is replicated.
+    direction: ReplicationDirection,
+    /// SSB ID of the peer, in whose reputation the received messages are
+    /// counted.
+    peer_ssb_id: Option<String>,
     _actor_id: usize,
-    reqs: HashMap<String, HistoryStreamRequest>,
+    /// Live history streams requested by the peer, by request number. They
+    /// are kept open to forward the messages appended to the local store.
+    reqs: HashMap<i32, HistoryStreamRequest>,
     peers: HashMap<i32, String>,
     phantom: PhantomData<W>,
 }
@@ -89,7 +105,7 @@ where
                 // Notification from the key-value store indicating that
                 // a new message has just been appended to the feed
                 // identified by `ssb_id`.
-                return self.recv_storageevent_idchanged(api, ssb_id).await;
+                self.recv_storageevent_idchanged(api, ssb_id).await
             }
             // Handle a timer event.
             RpcInput::Timer => self.on_timer(api).await,
@@ -108,17 +124,56 @@ where
         Self {
             _actor_id: actor_id,
             initialized: false,
+            legacy_quirks: false,
+            direction: ReplicationDirection::Both,
+            peer_ssb_id: None,
             peers: HashMap::new(),
             reqs: HashMap::new(),
             phantom: PhantomData,
         }
     }
 
+    /// Instantiate a new instance of `HistoryStreamHandler` which only serves
+    /// history stream requests, without requesting any feeds in return.
+    /// Used for sessions with local clients.
+    pub fn serve_only(actor_id: usize) -> Self {
+        Self {
+            initialized: true,
+            ..Self::new(actor_id)
+        }
+    }
+
+    /// Count the messages received in the reputation of the peer with the
+    /// given SSB ID.
+    pub fn peer(mut self, peer_ssb_id: &str) -> Self {
+        self.peer_ssb_id = Some(peer_ssb_id.to_owned());
+        self
+    }
+
+    /// Tolerate the protocol quirks of legacy pubs when parsing requests.
+    pub fn legacy_quirks(mut self, enabled: bool) -> Self {
+        self.legacy_quirks = enabled;
+        self
+    }
+
+    /// Replicate the peer in the given direction only: feeds are not
+    /// requested from push-only peers and the requests of pull-only peers
+    /// are refused.
+    pub fn direction(mut self, direction: ReplicationDirection) -> Self {
+        self.direction = direction;
+        self
+    }
+
     /// Initialize the history stream handler.
     ///
     /// Calls `create_history_stream` for every peer in the replication list,
     /// requesting the latest messages.
     async fn on_timer(&mut self, api: &mut ApiCaller<W>) -> Result<bool> {
+        if !self.initialized && !self.direction.pulls() {
+            debug!("not requesting feeds from push-only peer");
+            self.initialized = true;
+        }
+
         if !self.initialized {
             debug!("initializing history stream handler");
 
@@ -182,6 +237,13 @@ where
     ) -> Result<bool> {
         // Only handle the response if we made the request.
         if self.peers.contains_key(&req_no) {
+            // Skip messages which have already been stored (for example,
+            // when delivered by another replication session) before
+            // performing the comparatively expensive validation.
+            if duplicates::is_stored(res).await? {
+                return Ok(true);
+            }
+
             // First try to deserialize the response into a message value.
             // If that fails, try to deserialize into a message KVT and then
             // convert that into a message value. Return an error if that fails.
@@ -192,54 +254,90 @@ where
             // as part of the call to `from_slice`.
             let msg = match Message::from_slice(res) {
                 Ok(msg) => msg,
-                Err(_) => MessageKvt::from_slice(res)?.into_message()?,
+                Err(_) => {
+                    let msg = MessageKvt::from_slice(res)
+                        .map_err(Error::from)
+                        .and_then(|kvt| kvt.into_message().map_err(Error::from));
+                    match msg {
+                        Ok(msg) => msg,
+                        Err(err) => {
+                            self.record(reputation::record_invalid_message);
+                            return Err(err);
+                        }
+                    }
+                }
             };
 
-            // Retrieve the sequence number of the most recent message for
-            // the peer that authored the received message.
-            let last_seq = KV_STORE
-                .read()
-                .await
-                .get_latest_seq(&msg.author().to_string())?
-                .unwrap_or(0);
-
-            // Validate the sequence number.
-            if msg.sequence() == last_seq + 1 {
-                // Append the message to the feed.
-                KV_STORE.write().await.append_feed(msg.clone()).await?;
-
-                info!(
-                    "received msg number {} from {}",
+            // Drop the messages of distant feeds while storage is critically
+            // low.
+            if !pressure::accepts_feed(&msg.author().to_string()) {
+                debug!(
+                    "storage is critically low; dropping msg {} of {}",
                     msg.sequence(),
                     msg.author()
                 );
+                return Ok(true);
+            }
 
-                // Extract blob references from the received message and
-                // request those blobs if they are not already in the local
-                // blobstore.
-                for key in blobs::extract_blob_refs(&msg) {
-                    if !BLOB_STORE.read().await.exists(&key) {
-                        let event = RpcBlobsGetEvent(dto::BlobsGetIn::new(key));
-                        let broker_msg = BrokerEvent::new(
-                            Destination::Broadcast,
-                            BrokerMessage::RpcBlobsGet(event),
-                        );
-                        ch_broker.send(broker_msg).await.unwrap();
-                    }
-                }
-            } else {
-                warn!(
-                    "received out-of-order msg from {}; recv: {} db: {}",
-                    &msg.author().to_string(),
+            // Hold back the messages of distant authors in excess of the
+            // ingest rate limits.
+            let peer_ssb_id = self.peer_ssb_id.clone().unwrap_or_default();
+            if !ingest::admit(&peer_ssb_id, &msg).await? {
+                debug!(
+                    "holding back msg {} of {} over the ingest limit",
                     msg.sequence(),
-                    last_seq
+                    msg.author()
                 );
+                return Ok(true);
+            }
+
+            // Retrieve the most recent message of the feed of the peer that
+            // authored the received message.
+            let latest_msg = KV_STORE
+                .read()
+                .await
+                .get_latest_msg_val(&msg.author().to_string())?;
+
+            // Validate the message, including its sequence number.
+            if let Err(err) = validation::validate(&msg, latest_msg.as_ref(), Source::Replicated) {
+                warn!("rejected msg received via history stream: {}", err);
+                self.record(reputation::record_invalid_message);
 
-                // Return to avoid handling multiple successive out-of-order
+                // Return to avoid handling multiple successive rejected
                 // messages.
                 return Ok(true);
             }
 
+            // Check the clock of the peer before the message is indexed.
+            if let Some(peer_ssb_id) = &self.peer_ssb_id {
+                clock_skew::observe(peer_ssb_id, &msg);
+            }
+
+            // Append the message to the feed.
+            KV_STORE.write().await.append_feed(msg.clone()).await?;
+            stats::record_message(latest_msg.is_none());
+            self.record(reputation::record_message);
+            if let Some(peer_ssb_id) = &self.peer_ssb_id {
+                trace::record(
+                    &msg.author().to_string(),
+                    msg.sequence(),
+                    peer_ssb_id,
+                    TraceDirection::Received,
+                    "classic",
+                )
+                .await;
+            }
+
+            info!(
+                "received msg number {} from {}",
+                msg.sequence(),
+                msg.author()
+            );
+
+            // Add the blobs referenced by the received message to the
+            // want-list if they are not already in the local blobstore.
+            want_list::want_referenced(&msg, ch_broker).await?;
+
             Ok(true)
         } else {
             Ok(false)
@@ -254,22 +352,50 @@ where
         req_no: i32,
         req: &rpc::Body,
     ) -> Result<bool> {
-        // Deserialize the args from an incoming history stream request.
-        let mut args: Vec<dto::CreateHistoryStreamIn> = serde_json::from_value(req.args.clone())?;
-        // Retrieve the `CreateHistoryStreamIn` args from the array.
-        let args = args.pop().unwrap();
+        if !self.direction.pushes() {
+            api.rpc()
+                .send_error(req_no, req.rpc_type, "not permitted")
+                .await?;
+
+            return Ok(true);
+        }
+
+        let args = if self.legacy_quirks {
+            quirks::parse_history_stream_args(&req.args)?
+        } else {
+            // Deserialize the args from an incoming history stream request.
+            let mut args: Vec<dto::CreateHistoryStreamIn> =
+                serde_json::from_value(req.args.clone())?;
+            // Retrieve the `CreateHistoryStreamIn` args from the array.
+            args.pop().unwrap()
+        };
 
-        // Define the first message in the sequence to be sent to the requester.
-        let from = args.seq.unwrap_or(1u64);
+        // Determine the public key of the feed being requested.
+        let feed_id = prefixed(&args.id);
 
-        let mut req = HistoryStreamRequest { args, from, req_no };
+        // Define the first message in the sequence to be sent to the
+        // requester. Only messages appended from now on are sent if the
+        // stored messages were not requested (`old: false`).
+        let from = if args.old == Some(false) {
+            KV_STORE.read().await.get_latest_seq(&feed_id)?.unwrap_or(0) + 1
+        } else {
+            args.seq.unwrap_or(1u64).max(1)
+        };
+
+        let mut req = HistoryStreamRequest {
+            req_no,
+            remaining: args.limit,
+            args,
+            feed_id,
+            from,
+        };
 
         // Send the requested messages from the local feed.
         self.send_history(api, &mut req).await?;
 
-        if req.args.live.unwrap_or(false) {
-            // Keep the stream open for communication.
-            self.reqs.insert(req.args.id.clone(), req);
+        if req.args.live.unwrap_or(false) && req.remaining != Some(0) {
+            // Keep the stream open to forward the messages appended later.
+            self.reqs.insert(req_no, req);
         } else {
             // Send an end of file response to the caller.
             api.rpc().send_stream_eof(req_no).await?;
@@ -278,82 +404,90 @@ where
         Ok(true)
     }
 
-    /// Close the stream and remove the public key of the peer from the list
-    /// of active streams (`reqs`).
+    /// Close the stream and remove the request from the list of live
+    /// streams (`reqs`).
     async fn recv_cancelstream(&mut self, api: &mut ApiCaller<W>, req_no: i32) -> Result<bool> {
-        if let Some(key) = self.find_key_by_req_no(req_no) {
+        if self.reqs.remove(&req_no).is_some() {
             api.rpc().send_stream_eof(-req_no).await?;
-            self.reqs.remove(&key);
             Ok(true)
         } else {
             Ok(false)
         }
     }
 
-    /// Report a MUXRPC error and remove the public key of the peer from the
-    /// list of active streams (`reqs`).
+    /// Report a MUXRPC error and remove the request from the list of live
+    /// streams (`reqs`).
     async fn recv_error_response(
         &mut self,
         _api: &mut ApiCaller<W>,
         req_no: i32,
         error_msg: &str,
     ) -> Result<bool> {
-        if let Some(key) = self.find_key_by_req_no(req_no) {
+        if self.reqs.remove(&req_no).is_some() {
             warn!("MUXRPC error {}", error_msg);
-            self.reqs.remove(&key);
             Ok(true)
         } else {
             Ok(false)
         }
     }
 
-    /// Respond to a key-value store state change for the given peer.
-    /// This is triggered when a new message is appended to the local feed.
-    /// Remove the peer from the list of active streams, send the requested
-    /// messages from the local feed to the peer and then reinsert the public
-    /// key of the peer to the list of active streams.
+    /// Respond to a key-value store state change for the given feed.
+    /// This is triggered when a new message is appended to a feed in the
+    /// local store. The new messages are sent on every live stream of the
+    /// feed, and the streams which reached their limit are closed.
     async fn recv_storageevent_idchanged(
         &mut self,
         api: &mut ApiCaller<W>,
         ssb_id: &str,
     ) -> Result<bool> {
-        // Attempt to remove the peer from the list of active streams.
-        if let Some(mut req) = self.reqs.remove(ssb_id) {
-            // Send local messages to the peer.
-            self.send_history(api, &mut req).await?;
-            // Reinsert the peer into the list of active streams.
-            self.reqs.insert(ssb_id.to_string(), req);
-            Ok(true)
-        } else {
-            Ok(false)
+        let req_nos: Vec<i32> = self
+            .reqs
+            .values()
+            .filter(|req| req.feed_id == ssb_id)
+            .map(|req| req.req_no)
+            .collect();
+
+        for req_no in &req_nos {
+            // Take the request out of the list of live streams while the
+            // messages are sent.
+            if let Some(mut req) = self.reqs.remove(req_no) {
+                self.send_history(api, &mut req).await?;
+                if req.remaining == Some(0) {
+                    api.rpc().send_stream_eof(*req_no).await?;
+                } else {
+                    self.reqs.insert(*req_no, req);
+                }
+            }
         }
+
+        Ok(!req_nos.is_empty())
     }
 
-    /// Return the public key matching a given MUXRPC request.
-    /// In other words, return the author ID of a request.
-    fn find_key_by_req_no(&self, req_no: i32) -> Option<String> {
-        self.reqs
-            .iter()
-            .find(|(_, val)| val.req_no == req_no)
-            .map(|(key, _)| key.clone())
+    /// Count an event in the reputation of the peer, if known.
+    fn record(&self, record: fn(&str)) {
+        if let Some(peer_ssb_id) = &self.peer_ssb_id {
+            record(peer_ssb_id)
+        }
     }
 
-    /// Send a stream of messages from the local key-value database to a peer.
+    /// Send a stream of messages from the local key-value database to a peer:
+    /// the stored messages of the requested feed from the next sequence
+    /// number of the request, up to its remaining limit.
     async fn send_history(
         &mut self,
         api: &mut ApiCaller<W>,
         req: &mut HistoryStreamRequest,
     ) -> Result<()> {
-        // Determine the public key of the feed being requested.
-        let req_id = if req.args.id.starts_with('@') {
-            req.args.id.clone()
-        } else {
-            format!("@{}", req.args.id).to_string()
-        };
-
         // Lookup the sequence number of the most recently published message
         // in the local feed.
-        let last_seq = KV_STORE.read().await.get_latest_seq(&req_id)?.unwrap_or(0);
+        let mut last_seq = KV_STORE
+            .read()
+            .await
+            .get_latest_seq(&req.feed_id)?
+            .unwrap_or(0);
+        if let Some(remaining) = req.remaining {
+            last_seq = last_seq.min(req.from.saturating_add(remaining).saturating_sub(1));
+        }
 
         // Determine if the messages should be sent as message values or as
         // message KVTs (Key Value Timestamp).
@@ -365,23 +499,22 @@ where
         // equal to the latest sequence number for that feed in the local
         // database).
         if req.from <= last_seq {
-            // Determine the public key of the peer who requested the history
-            // stream.
-            let requester = self
-                .find_key_by_req_no(req.req_no)
-                .unwrap_or_else(|| "unknown".to_string());
-
             info!(
                 "sending messages authored by {} to {} (from sequence {} to {})",
-                req.args.id, requester, req.from, last_seq
+                req.feed_id,
+                self.peer_ssb_id.as_deref().unwrap_or("unknown"),
+                req.from,
+                last_seq
             );
 
             // Iterate over the range of requested messages, read them from the
             // local key-value database and send them to the requesting peer.
-            // The "to" value (`last_seq`) is exclusive so we need to add one to
-            // include it in the range.
-            for n in req.from..(last_seq + 1) {
-                let data = KV_STORE.read().await.get_msg_kvt(&req_id, n)?.unwrap();
+            for n in req.from..=last_seq {
+                let data = KV_STORE
+                    .read()
+                    .await
+                    .get_msg_kvt(&req.feed_id, n)?
+                    .ok_or(Error::OptionIsNone)?;
                 // Send either the whole KVT or just the value.
                 let data = if with_keys {
                     data.to_string()
@@ -389,11 +522,259 @@ where
                     data.value.to_string()
                 };
                 api.feed_res_send(req.req_no, &data).await?;
+
+                if let Some(peer_ssb_id) = &self.peer_ssb_id {
+                    trace::record(
+                        &req.feed_id,
+                        n,
+                        peer_ssb_id,
+                        TraceDirection::Sent,
+                        "classic",
+                    )
+                    .await;
+                }
             }
 
-            // Update the starting sequence number for the request.
-            req.from = last_seq;
+            // Update the request, so that live streams resume with the next
+            // message.
+            if let Some(remaining) = req.remaining.as_mut() {
+                *remaining -= last_seq + 1 - req.from;
+            }
+            req.from = last_seq + 1;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use kuska_ssb::keystore::OwnedIdentity;
+    use serde_json::{json, Value};
+
+    use crate::{
+        conformance::{self, HistoryStreamSession, Packet},
+        secret_config::SecretConfig,
+    };
+
+    /// A feed of the store, to which posts are appended.
+    struct TestFeed {
+        identity: OwnedIdentity,
+        messages: Vec<Message>,
+    }
+
+    impl TestFeed {
+        /// Create a feed with the given number of posts.
+        async fn new(posts: usize) -> Result<Self> {
+            conformance::open_store().await?;
+
+            let mut feed = TestFeed {
+                identity: SecretConfig::create().to_owned_identity()?,
+                messages: Vec::new(),
+            };
+            for _ in 0..posts {
+                feed.post().await?;
+            }
+
+            Ok(feed)
+        }
+
+        /// Append a post to the feed, returning its sequence number.
+        async fn post(&mut self) -> Result<u64> {
+            let text = format!("Post #{}", self.messages.len() + 1);
+            let msg = Message::sign(
+                self.messages.last(),
+                &self.identity,
+                json!({ "type": "post", "text": text }),
+            )?;
+            self.messages.push(msg.clone());
+
+            conformance::append(msg).await
+        }
+
+        /// Return the value of the message of the feed with the given
+        /// sequence number.
+        fn value(&self, seq: u64) -> Value {
+            self.messages[seq as usize - 1].value.clone()
+        }
+    }
+
+    /// Send a history stream request with the given request number and
+    /// arguments on the given session.
+    async fn request(session: &mut HistoryStreamSession, req_no: i32, args: Value) -> Result<()> {
+        let request = json!({
+            "name": ["createHistoryStream"],
+            "type": "source",
+            "args": [args],
+        });
+
+        session
+            .receive(&conformance::json_packet(
+                req_no,
+                request.to_string().as_bytes(),
+            ))
+            .await
+    }
+
+    /// Return the request number of the given packet, whether it ends the
+    /// stream, and the message value it carries (if any).
+    fn decode(packet: &Packet) -> (i32, bool, Option<Value>) {
+        let value = if packet.end {
+            None
+        } else {
+            Some(serde_json::from_slice(&packet.body).unwrap())
+        };
+
+        (packet.req_no.abs(), packet.end, value)
+    }
+
+    #[async_std::test]
+    async fn test_live_stream_forwards_appended_messages() -> Result<()> {
+        let mut feed = TestFeed::new(2).await?;
+        let other = TestFeed::new(1).await?;
+        let mut session = HistoryStreamSession::new();
+
+        request(
+            &mut session,
+            1,
+            json!({ "id": feed.identity.id, "seq": 1, "live": true, "keys": false }),
+        )
+        .await?;
+        // The stored messages are sent and the stream is kept open.
+        let sent: Vec<_> = session.sent().iter().map(decode).collect();
+        assert_eq!(
+            sent,
+            vec![
+                (1, false, Some(feed.value(1))),
+                (1, false, Some(feed.value(2))),
+            ]
+        );
+
+        // Messages appended to other feeds are not forwarded.
+        session.appended(&other.identity.id, 1).await?;
+        assert!(session.sent().is_empty());
+
+        let seq = feed.post().await?;
+        session.appended(&feed.identity.id, seq).await?;
+        let sent: Vec<_> = session.sent().iter().map(decode).collect();
+        assert_eq!(sent, vec![(1, false, Some(feed.value(3)))]);
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_live_stream_without_old_messages() -> Result<()> {
+        let mut feed = TestFeed::new(2).await?;
+        let mut session = HistoryStreamSession::new();
+
+        request(
+            &mut session,
+            1,
+            json!({ "id": feed.identity.id, "old": false, "live": true, "keys": false }),
+        )
+        .await?;
+        // Only the messages appended from now on are sent.
+        assert!(session.sent().is_empty());
+
+        let seq = feed.post().await?;
+        session.appended(&feed.identity.id, seq).await?;
+        let sent: Vec<_> = session.sent().iter().map(decode).collect();
+        assert_eq!(sent, vec![(1, false, Some(feed.value(3)))]);
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_limit() -> Result<()> {
+        let mut feed = TestFeed::new(3).await?;
+        let mut session = HistoryStreamSession::new();
+
+        // The stream ends once the limit is reached.
+        request(
+            &mut session,
+            1,
+            json!({ "id": feed.identity.id, "seq": 2, "limit": 1, "keys": false }),
+        )
+        .await?;
+        let sent: Vec<_> = session.sent().iter().map(decode).collect();
+        assert_eq!(sent, vec![(1, false, Some(feed.value(2))), (1, true, None)]);
+
+        // A live stream ends once the limit is reached by the messages
+        // appended later.
+        request(
+            &mut session,
+            2,
+            json!({ "id": feed.identity.id, "seq": 3, "limit": 2, "live": true, "keys": false }),
+        )
+        .await?;
+        let sent: Vec<_> = session.sent().iter().map(decode).collect();
+        assert_eq!(sent, vec![(2, false, Some(feed.value(3)))]);
+
+        let seq = feed.post().await?;
+        session.appended(&feed.identity.id, seq).await?;
+        let sent: Vec<_> = session.sent().iter().map(decode).collect();
+        assert_eq!(sent, vec![(2, false, Some(feed.value(4))), (2, true, None)]);
+
+        let seq = feed.post().await?;
+        session.appended(&feed.identity.id, seq).await?;
+        assert!(session.sent().is_empty());
+
+        // The largest limit is as good as none.
+        request(
+            &mut session,
+            3,
+            json!({ "id": feed.identity.id, "seq": 4, "limit": u64::MAX, "keys": false }),
+        )
+        .await?;
+        let sent: Vec<_> = session.sent().iter().map(decode).collect();
+        assert_eq!(
+            sent,
+            vec![
+                (3, false, Some(feed.value(4))),
+                (3, false, Some(feed.value(5))),
+                (3, true, None)
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_concurrent_live_streams() -> Result<()> {
+        let mut feed = TestFeed::new(1).await?;
+        let mut session = HistoryStreamSession::new();
+
+        for req_no in [1, 2] {
+            request(
+                &mut session,
+                req_no,
+                json!({ "id": feed.identity.id, "seq": 1, "live": true, "keys": false }),
+            )
+            .await?;
         }
+        let sent: Vec<_> = session.sent().iter().map(decode).collect();
+        assert_eq!(
+            sent,
+            vec![
+                (1, false, Some(feed.value(1))),
+                (2, false, Some(feed.value(1))),
+            ]
+        );
+
+        // An appended message is forwarded once on each stream.
+        let seq = feed.post().await?;
+        session.appended(&feed.identity.id, seq).await?;
+        let mut sent: Vec<_> = session.sent().iter().map(decode).collect();
+        sent.sort_by_key(|(req_no, _, _)| *req_no);
+        assert_eq!(
+            sent,
+            vec![
+                (1, false, Some(feed.value(2))),
+                (2, false, Some(feed.value(2))),
+            ]
+        );
 
         Ok(())
     }
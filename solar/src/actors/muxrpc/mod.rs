@@ -1,18 +1,30 @@
+mod admin;
 mod blobs_get;
 mod blobs_wants;
 mod ebt;
 mod get;
 mod handler;
 mod history_stream;
+mod ooo;
+mod peer_exchange;
+mod preview;
+mod publish;
+mod room;
 mod whoami;
 
 /// The unique identifier of a MUXRPC request.
 pub type ReqNo = i32;
 
+pub use admin::AdminHandler;
 pub use blobs_get::{BlobsGetHandler, RpcBlobsGetEvent};
 pub use blobs_wants::{BlobsWantsHandler, RpcBlobsWantsEvent};
 pub use ebt::EbtReplicateHandler;
 pub use get::GetHandler;
 pub use handler::{RpcHandler, RpcInput};
 pub use history_stream::HistoryStreamHandler;
+pub use ooo::{fetch_message, OooHandler, OooRequest};
+pub use peer_exchange::PeerExchangeHandler;
+pub use preview::{fetch_preview, PreviewHandler, PreviewRequest};
+pub use publish::PublishHandler;
+pub use room::RoomHandler;
 pub use whoami::WhoAmIHandler;
@@ -0,0 +1,325 @@
+//! Out-of-order message handler.
+//!
+//! Fetches single messages by ID from the connected peers (MUXRPC `get`), in
+//! the manner of `ssb-ooo`, so that threads referencing messages of feeds
+//! which are not replicated can still be displayed.
+//!
+//! A fetch is requested by broadcasting an `OooRequest` to the replication
+//! loops, each of which asks its peer for the message. The first message
+//! received whose signature is valid and whose ID matches the requested ID
+//! is cached in a tree of its own (apart from the replicated feeds, since
+//! the earlier messages of its feed are missing) and handed back to the
+//! caller. Cached messages are served to peers in turn.
+
+use std::{
+    collections::HashMap,
+    marker::PhantomData,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use async_std::{future, io::Write};
+use async_trait::async_trait;
+use futures::{channel::oneshot, SinkExt};
+use kuska_ssb::{
+    api::ApiCaller,
+    feed::{Feed as MessageKvt, Message},
+    rpc,
+};
+use log::{debug, info};
+use once_cell::sync::Lazy;
+
+use crate::{
+    actors::{
+        muxrpc::{
+            handler::{RpcHandler, RpcInput},
+            preview::parse_message,
+        },
+        network::connection_manager::CONNECTION_MANAGER,
+    },
+    broker::{BrokerEvent, BrokerMessage, ChBrokerSend, Destination, BROKER},
+    node::KV_STORE,
+    ssb_uri,
+    util::now_millis,
+    Result,
+};
+
+/// Time after which a fetch is abandoned if no peer has returned the
+/// message.
+const OOO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Method used to request a message by ID.
+const GET_METHOD: [&str; 1] = ["get"];
+
+/// ID of the most recently requested fetch.
+static LAST_FETCH_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Pending fetches, keyed by fetch ID.
+static PENDING_FETCHES: Lazy<Mutex<HashMap<u64, PendingFetch>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// A fetch awaiting the answers of the connected peers.
+struct PendingFetch {
+    /// Channel on which the fetched message is returned.
+    sender: oneshot::Sender<Option<Message>>,
+    /// Number of peers which have not answered yet.
+    remaining: usize,
+}
+
+/// A request for the message with the given ID, to be fetched from any
+/// connected peer.
+#[derive(Debug, Clone)]
+pub struct OooRequest {
+    /// ID of the fetch.
+    pub id: u64,
+    /// ID (key) of the message.
+    pub msg_ref: String,
+}
+
+/// Return the pending fetches.
+fn pending_fetches() -> std::sync::MutexGuard<'static, HashMap<u64, PendingFetch>> {
+    PENDING_FETCHES
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+}
+
+/// Record the answer of a peer to the fetch with the given ID. The first
+/// message received is handed back to the caller; the caller is told that
+/// the message was not found once every peer has answered without it.
+fn complete(id: u64, msg: Option<Message>) {
+    let mut pending = pending_fetches();
+
+    let done = match pending.get_mut(&id) {
+        Some(fetch) => {
+            fetch.remaining = fetch.remaining.saturating_sub(1);
+            msg.is_some() || fetch.remaining == 0
+        }
+        None => false,
+    };
+    if done {
+        if let Some(fetch) = pending.remove(&id) {
+            let _ = fetch.sender.send(msg);
+        }
+    }
+}
+
+/// Return the message KVT with the given ID (key), fetching it from the
+/// connected peers (and caching it) unless it is stored or cached already.
+/// The ID may be given as a sigil link or an SSB URI.
+///
+/// Returns `None` if no connected peer returned the message in time.
+pub async fn fetch_message(msg_ref: &str) -> Result<Option<MessageKvt>> {
+    let msg_ref = ssb_uri::to_sigil(msg_ref)?;
+
+    {
+        let db = KV_STORE.read().await;
+        if let Some(msg) = db.get_msg_val(&msg_ref)? {
+            return db.get_msg_kvt(msg.author(), msg.sequence());
+        }
+        if let Some(msg_kvt) = db.get_ooo_msg_kvt(&msg_ref)? {
+            return Ok(Some(msg_kvt));
+        }
+    }
+
+    let peers = CONNECTION_MANAGER
+        .read()
+        .await
+        .connections()
+        .iter()
+        .filter(|record| record.state.is_connected())
+        .count();
+    if peers == 0 {
+        return Ok(None);
+    }
+
+    let id = LAST_FETCH_ID.fetch_add(1, Ordering::Relaxed) + 1;
+    let (sender, receiver) = oneshot::channel();
+    pending_fetches().insert(
+        id,
+        PendingFetch {
+            sender,
+            remaining: peers,
+        },
+    );
+
+    let request = OooRequest {
+        id,
+        msg_ref: msg_ref.to_owned(),
+    };
+    let mut ch_broker = BROKER.lock().await.create_sender();
+    ch_broker
+        .send(BrokerEvent::new(
+            Destination::Broadcast,
+            BrokerMessage::Ooo(request),
+        ))
+        .await?;
+
+    let result = future::timeout(OOO_TIMEOUT, receiver).await;
+    pending_fetches().remove(&id);
+
+    match result {
+        Ok(Ok(Some(msg))) => {
+            let db = KV_STORE.read().await;
+            db.set_ooo_msg(&msg, now_millis())?;
+            info!("cached out-of-order msg {}", msg_ref);
+
+            db.get_ooo_msg_kvt(&msg_ref)
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Out-of-order message handler. Fetches the messages requested from the
+/// peer of the connection.
+pub struct OooHandler<W>
+where
+    W: Write + Unpin + Send + Sync,
+{
+    /// Fetches awaiting the answer of the peer, keyed by request number.
+    requests: HashMap<i32, OooRequest>,
+    phantom: PhantomData<W>,
+}
+
+impl<W> Default for OooHandler<W>
+where
+    W: Write + Unpin + Send + Sync,
+{
+    fn default() -> Self {
+        Self {
+            requests: HashMap::new(),
+            phantom: PhantomData,
+        }
+    }
+}
+
+#[async_trait]
+impl<W> RpcHandler<W> for OooHandler<W>
+where
+    W: Write + Unpin + Send + Sync,
+{
+    fn name(&self) -> &'static str {
+        "OooHandler"
+    }
+
+    async fn handle(
+        &mut self,
+        api: &mut ApiCaller<W>,
+        op: &RpcInput,
+        _ch_broker: &mut ChBrokerSend,
+    ) -> Result<bool> {
+        match op {
+            RpcInput::Message(BrokerMessage::Ooo(request)) => self.send_request(api, request).await,
+            RpcInput::Network(req_no, rpc::RecvMsg::RpcResponse(_type, res)) => {
+                match self.requests.remove(req_no) {
+                    Some(request) => {
+                        complete(request.id, verified_message(&request, res));
+                        Ok(true)
+                    }
+                    None => Ok(false),
+                }
+            }
+            RpcInput::Network(req_no, rpc::RecvMsg::ErrorResponse(_)) => {
+                match self.requests.remove(req_no) {
+                    Some(request) => {
+                        complete(request.id, None);
+                        Ok(true)
+                    }
+                    None => Ok(false),
+                }
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+impl<W> OooHandler<W>
+where
+    W: Write + Unpin + Send + Sync,
+{
+    /// Ask the peer for the message of the given fetch.
+    async fn send_request(&mut self, api: &mut ApiCaller<W>, request: &OooRequest) -> Result<bool> {
+        let req_no = api
+            .rpc()
+            .send_request(
+                &GET_METHOD,
+                rpc::RpcType::Async,
+                rpc::ArgType::Array,
+                &[&request.msg_ref],
+                &None::<()>,
+            )
+            .await?;
+        self.requests.insert(req_no, request.clone());
+
+        Ok(true)
+    }
+}
+
+/// Parse the message received in response to the given request, provided
+/// its signature is valid and its ID matches the requested ID.
+fn verified_message(request: &OooRequest, res: &[u8]) -> Option<Message> {
+    match parse_message(res) {
+        Ok(msg) if msg.id().to_string() == request.msg_ref => Some(msg),
+        Ok(msg) => {
+            debug!(
+                "dropping out-of-order msg {}: expected {}",
+                msg.id().to_string(),
+                request.msg_ref
+            );
+            None
+        }
+        Err(err) => {
+            debug!("dropping invalid out-of-order msg: {}", err);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use kuska_ssb::keystore::OwnedIdentity;
+    use serde_json::json;
+
+    #[test]
+    fn test_verified_message() -> Result<()> {
+        let identity = OwnedIdentity::create();
+        let msg = Message::sign(None, &identity, json!({ "type": "post", "text": "hi" }))?;
+        let other = Message::sign(
+            Some(&msg),
+            &identity,
+            json!({ "type": "post", "text": "yo" }),
+        )?;
+
+        let request = OooRequest {
+            id: 1,
+            msg_ref: msg.id().to_string(),
+        };
+        assert!(verified_message(&request, msg.to_string().as_bytes()).is_some());
+        assert!(verified_message(&request, other.to_string().as_bytes()).is_none());
+        assert!(verified_message(&request, b"not a message").is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_complete() {
+        let (sender, mut receiver) = oneshot::channel();
+        pending_fetches().insert(
+            42,
+            PendingFetch {
+                sender,
+                remaining: 2,
+            },
+        );
+
+        // The caller is only told once every peer has answered.
+        complete(42, None);
+        assert!(receiver.try_recv().unwrap().is_none());
+        complete(42, None);
+        assert!(matches!(receiver.try_recv(), Ok(Some(None))));
+    }
+}
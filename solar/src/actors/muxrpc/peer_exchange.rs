@@ -0,0 +1,282 @@
+//! Peer exchange.
+//!
+//! Trusted peers (those listed in `replication.toml`) share the addresses of
+//! pubs and rooms known to be reachable: peers which were recently dialed
+//! successfully and pubs announced in `pub` messages. Addresses received
+//! from a trusted peer are added to the connection scheduler, extending the
+//! dial list beyond the static configuration. Received addresses are not
+//! shared any further until they have been dialed successfully.
+//!
+//! The exchange uses the non-standard `peerExchange.addresses` async method.
+//! Peers which do not support it respond with an error, which is ignored.
+
+use std::{collections::HashSet, marker::PhantomData};
+
+use async_std::io::Write;
+use async_trait::async_trait;
+use futures::SinkExt;
+use kuska_ssb::{
+    api::ApiCaller,
+    crypto::{ed25519::PublicKey, ToSodiumObject},
+    rpc,
+};
+use log::{debug, trace};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    actors::{
+        muxrpc::handler::{RpcHandler, RpcInput},
+        network::{connection_manager::CONNECTION_MANAGER, connection_scheduler::ScheduleRequest},
+    },
+    broker::{BrokerEvent, BrokerMessage, ChBrokerSend, Destination},
+    config::{PEERS_TO_REPLICATE, SECRET_CONFIG},
+    node::KV_STORE,
+    util::prefixed,
+    Result,
+};
+
+/// Name of the peer exchange MUXRPC method.
+const PEER_EXCHANGE_METHOD: [&str; 2] = ["peerExchange", "addresses"];
+
+/// Maximum number of addresses shared with a peer.
+const MAX_SHARED_ADDRESSES: usize = 64;
+
+/// Maximum number of addresses accepted from a peer.
+const MAX_ACCEPTED_ADDRESSES: usize = 32;
+
+/// The public key and address of a pub or room.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct PeerAddress {
+    pub id: String,
+    pub addr: String,
+}
+
+/// Return the addresses known to be reachable: peers whose most recent dial
+/// attempt succeeded, followed by pubs announced in `pub` messages.
+async fn known_addresses() -> Result<Vec<PeerAddress>> {
+    let mut addresses = Vec::new();
+    let mut seen = HashSet::new();
+
+    let reachable = CONNECTION_MANAGER.read().await.reachable_peers();
+    let announced = match &KV_STORE.read().await.indexes {
+        Some(indexes) => indexes.get_pub_addresses()?,
+        None => Vec::new(),
+    };
+
+    for (id, addr) in reachable.into_iter().chain(announced) {
+        if seen.insert(id.to_owned()) {
+            addresses.push(PeerAddress { id, addr });
+        }
+        if addresses.len() >= MAX_SHARED_ADDRESSES {
+            break;
+        }
+    }
+
+    Ok(addresses)
+}
+
+/// Parse the addresses shared by a peer, discarding invalid entries and the
+/// address of the local identity.
+pub fn parse_addresses(data: &[u8], local_id: &str) -> Vec<(PublicKey, String)> {
+    let addresses: Vec<PeerAddress> = match serde_json::from_slice(data) {
+        Ok(addresses) => addresses,
+        Err(_) => return Vec::new(),
+    };
+
+    addresses
+        .into_iter()
+        .filter(|address| address.id != local_id && !address.addr.is_empty())
+        .filter_map(|address| {
+            address
+                .id
+                .to_ed25519_pk()
+                .ok()
+                .map(|public_key| (public_key, address.addr))
+        })
+        .take(MAX_ACCEPTED_ADDRESSES)
+        .collect()
+}
+
+/// Peer exchange handler. Shares known addresses with trusted peers and
+/// schedules the addresses they share in return.
+pub struct PeerExchangeHandler<W>
+where
+    W: Write + Unpin + Send + Sync,
+{
+    /// Public key of the local identity.
+    local_id: String,
+    /// Whether the remote peer is trusted.
+    trusted: bool,
+    /// Request number of the outbound exchange request.
+    req_no: Option<i32>,
+    phantom: PhantomData<W>,
+}
+
+impl<W> PeerExchangeHandler<W>
+where
+    W: Write + Unpin + Send + Sync,
+{
+    /// Instantiate a new handler for a connection with the given peer.
+    pub fn new(peer_ssb_id: &str) -> Self {
+        let local_id = SECRET_CONFIG
+            .get()
+            .map(|secret| secret.public_key.to_owned())
+            .unwrap_or_default();
+        let peer_ssb_id = prefixed(&peer_ssb_id);
+        let trusted = PEERS_TO_REPLICATE
+            .get()
+            .map(|peers| peers.contains_key(&peer_ssb_id))
+            .unwrap_or(false);
+
+        Self {
+            local_id,
+            trusted,
+            req_no: None,
+            phantom: PhantomData,
+        }
+    }
+}
+
+#[async_trait]
+impl<W> RpcHandler<W> for PeerExchangeHandler<W>
+where
+    W: Write + Unpin + Send + Sync,
+{
+    fn name(&self) -> &'static str {
+        "PeerExchangeHandler"
+    }
+
+    async fn handle(
+        &mut self,
+        api: &mut ApiCaller<W>,
+        op: &RpcInput,
+        ch_broker: &mut ChBrokerSend,
+    ) -> Result<bool> {
+        match op {
+            RpcInput::Network(req_no, rpc::RecvMsg::RpcRequest(req))
+                if req.name == PEER_EXCHANGE_METHOD =>
+            {
+                self.recv_addresses_request(api, *req_no, req).await
+            }
+            RpcInput::Network(req_no, rpc::RecvMsg::RpcResponse(_type, res))
+                if self.req_no == Some(*req_no) =>
+            {
+                self.recv_addresses(res, ch_broker).await
+            }
+            RpcInput::Network(req_no, rpc::RecvMsg::ErrorResponse(err))
+                if self.req_no == Some(*req_no) =>
+            {
+                debug!("Peer exchange not supported by peer: {}", err);
+                Ok(true)
+            }
+            RpcInput::Timer if self.trusted && self.req_no.is_none() => {
+                trace!(target: "peer-exchange", "sending peer exchange request");
+                let args: [&str; 0] = [];
+                let req_no = api
+                    .rpc()
+                    .send_request(
+                        &PEER_EXCHANGE_METHOD,
+                        rpc::RpcType::Async,
+                        rpc::ArgType::Array,
+                        &args,
+                        &None::<()>,
+                    )
+                    .await?;
+                self.req_no = Some(req_no);
+
+                Ok(false)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+impl<W> PeerExchangeHandler<W>
+where
+    W: Write + Unpin + Send + Sync,
+{
+    /// Respond to a request for known addresses. Only trusted peers are
+    /// answered.
+    async fn recv_addresses_request(
+        &mut self,
+        api: &mut ApiCaller<W>,
+        req_no: i32,
+        req: &rpc::Body,
+    ) -> Result<bool> {
+        if !self.trusted {
+            api.rpc()
+                .send_error(req_no, req.rpc_type, "not permitted")
+                .await?;
+
+            return Ok(true);
+        }
+
+        let addresses = known_addresses().await?;
+        trace!(target: "peer-exchange", "sharing {} addresses", addresses.len());
+
+        api.rpc()
+            .send_response(
+                req_no,
+                rpc::RpcType::Async,
+                rpc::BodyType::JSON,
+                &serde_json::to_vec(&addresses)?,
+            )
+            .await?;
+
+        Ok(true)
+    }
+
+    /// Add the addresses shared by the peer to the connection scheduler.
+    async fn recv_addresses(&mut self, res: &[u8], ch_broker: &mut ChBrokerSend) -> Result<bool> {
+        let addresses = parse_addresses(res, &self.local_id);
+        debug!("Received {} addresses via peer exchange", addresses.len());
+
+        for peer in addresses {
+            ch_broker
+                .send(BrokerEvent::new(
+                    Destination::Broadcast,
+                    BrokerMessage::Schedule(ScheduleRequest(peer)),
+                ))
+                .await?;
+        }
+
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::secret_config::SecretConfig;
+
+    #[test]
+    fn test_parse_addresses() -> Result<()> {
+        let local = SecretConfig::create().to_owned_identity()?;
+        let remote = SecretConfig::create().to_owned_identity()?;
+
+        let addresses = vec![
+            PeerAddress {
+                id: local.id.to_owned(),
+                addr: "local.example.com:8008".to_string(),
+            },
+            PeerAddress {
+                id: remote.id.to_owned(),
+                addr: "pub.example.com:8008".to_string(),
+            },
+            PeerAddress {
+                id: "@invalid".to_string(),
+                addr: "invalid.example.com:8008".to_string(),
+            },
+        ];
+        let data = serde_json::to_vec(&addresses)?;
+
+        assert_eq!(
+            parse_addresses(&data, &local.id),
+            vec![(remote.pk, "pub.example.com:8008".to_string())]
+        );
+        assert!(parse_addresses(b"not json", &local.id).is_empty());
+
+        Ok(())
+    }
+}
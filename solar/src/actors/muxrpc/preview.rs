@@ -0,0 +1,345 @@
+//! Feed preview handler.
+//!
+//! Fetches the latest messages of an arbitrary feed from a connected peer on
+//! demand (for example, to show the profile of a stranger), without adding
+//! the feed to the replication set or storing its messages.
+//!
+//! A preview is requested by broadcasting a `PreviewRequest` to the
+//! replication loops. The loop of the connection with the chosen peer sends a
+//! `createHistoryStream` request for the feed, asking for the most recent
+//! messages first (`reverse`). The received messages are verified (but not
+//! validated against the stored feed, since none is stored) and handed back
+//! to the caller once the peer ends the stream. Peers which do not support
+//! `reverse` return the earliest messages of the feed instead.
+
+use std::{
+    collections::HashMap,
+    marker::PhantomData,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use async_std::{future, io::Write};
+use async_trait::async_trait;
+use futures::{channel::oneshot, SinkExt};
+use kuska_ssb::{
+    api::ApiCaller,
+    feed::{Feed as MessageKvt, Message},
+    rpc,
+};
+use log::{debug, info};
+use once_cell::sync::Lazy;
+use serde_json::json;
+
+use crate::{
+    actors::{
+        muxrpc::handler::{RpcHandler, RpcInput},
+        network::connection_manager::CONNECTION_MANAGER,
+    },
+    broker::{BrokerEvent, BrokerMessage, ChBrokerSend, Destination, BROKER},
+    error::Error,
+    Result,
+};
+
+/// Maximum number of messages of a feed preview.
+const MAX_PREVIEW_LIMIT: u64 = 100;
+
+/// Time after which a feed preview is abandoned if the peer has not ended
+/// the stream.
+const PREVIEW_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Method used to request the messages of a feed.
+const CREATE_HISTORY_STREAM_METHOD: [&str; 1] = ["createHistoryStream"];
+
+/// ID of the most recently requested preview.
+static LAST_PREVIEW_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Channels on which the messages of the pending previews are returned,
+/// keyed by preview ID.
+type PendingPreviews = HashMap<u64, oneshot::Sender<Vec<Message>>>;
+
+static PENDING_PREVIEWS: Lazy<Mutex<PendingPreviews>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// A request for the latest messages of a feed, to be fetched from the given
+/// peer.
+#[derive(Debug, Clone)]
+pub struct PreviewRequest {
+    /// ID of the preview.
+    pub id: u64,
+    /// SSB ID of the peer from which the messages are fetched.
+    pub peer: String,
+    /// SSB ID of the feed.
+    pub feed: String,
+    /// Maximum number of messages.
+    pub limit: u64,
+}
+
+/// Return the SSB ID of the connected peer from which the given feed is to
+/// be previewed: the given peer, else the author of the feed if connected,
+/// else any connected peer.
+async fn choose_peer(feed: &str, peer: Option<&str>) -> Result<String> {
+    let connected: Vec<String> = CONNECTION_MANAGER
+        .read()
+        .await
+        .connections()
+        .into_iter()
+        .filter(|record| record.state.is_connected())
+        .filter_map(|record| record.peer)
+        .collect();
+
+    let chosen = match peer {
+        Some(peer) => connected.into_iter().find(|connected| connected == peer),
+        None => connected
+            .iter()
+            .find(|connected| *connected == feed)
+            .or_else(|| connected.first())
+            .cloned(),
+    };
+
+    chosen.ok_or_else(|| Error::Other("no connected peer to preview the feed from".to_string()))
+}
+
+/// Fetch the latest messages (at most `limit`) of the given feed from the
+/// given connected peer, or from any connected peer if none is given.
+/// Returns the messages ordered by sequence number.
+pub async fn fetch_preview(feed: &str, peer: Option<&str>, limit: u64) -> Result<Vec<Message>> {
+    let peer = choose_peer(feed, peer).await?;
+
+    let id = LAST_PREVIEW_ID.fetch_add(1, Ordering::Relaxed) + 1;
+    let (sender, receiver) = oneshot::channel();
+    pending_previews().insert(id, sender);
+
+    let request = PreviewRequest {
+        id,
+        peer: peer.to_owned(),
+        feed: feed.to_owned(),
+        limit: limit.clamp(1, MAX_PREVIEW_LIMIT),
+    };
+    let mut ch_broker = BROKER.lock().await.create_sender();
+    ch_broker
+        .send(BrokerEvent::new(
+            Destination::Broadcast,
+            BrokerMessage::Preview(request),
+        ))
+        .await?;
+
+    let result = future::timeout(PREVIEW_TIMEOUT, receiver).await;
+    pending_previews().remove(&id);
+
+    match result {
+        Ok(Ok(mut msgs)) => {
+            msgs.sort_by_key(|msg| msg.sequence());
+            Ok(msgs)
+        }
+        Ok(Err(_)) => Err(Error::Other(format!(
+            "connection with {} closed before the preview completed",
+            peer
+        ))),
+        Err(_) => Err(Error::Other(format!(
+            "timed out waiting for {} to preview the feed",
+            peer
+        ))),
+    }
+}
+
+/// Return the channels of the pending previews.
+fn pending_previews() -> std::sync::MutexGuard<'static, PendingPreviews> {
+    PENDING_PREVIEWS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+}
+
+/// Hand the given messages back to the caller of the preview with the given
+/// ID, if it is still waiting.
+fn complete(id: u64, msgs: Vec<Message>) {
+    if let Some(sender) = pending_previews().remove(&id) {
+        let _ = sender.send(msgs);
+    }
+}
+
+/// Parse a message received in response to a history stream request, sent
+/// either as a message value or as a message KVT. Parsing verifies the
+/// signature of the message.
+pub(super) fn parse_message(res: &[u8]) -> Result<Message> {
+    match Message::from_slice(res) {
+        Ok(msg) => Ok(msg),
+        Err(_) => Ok(MessageKvt::from_slice(res)?.into_message()?),
+    }
+}
+
+/// A preview being fetched over the connection.
+struct ActivePreview {
+    request: PreviewRequest,
+    msgs: Vec<Message>,
+}
+
+/// Feed preview handler. Fetches the previews requested from the peer of
+/// the connection.
+pub struct PreviewHandler<W>
+where
+    W: Write + Unpin + Send + Sync,
+{
+    /// SSB ID of the remote peer.
+    peer_ssb_id: String,
+    /// Previews being fetched, keyed by request number.
+    previews: HashMap<i32, ActivePreview>,
+    phantom: PhantomData<W>,
+}
+
+impl<W> PreviewHandler<W>
+where
+    W: Write + Unpin + Send + Sync,
+{
+    /// Instantiate a new handler for a connection with the given peer.
+    pub fn new(peer_ssb_id: &str) -> Self {
+        Self {
+            peer_ssb_id: peer_ssb_id.to_owned(),
+            previews: HashMap::new(),
+            phantom: PhantomData,
+        }
+    }
+}
+
+#[async_trait]
+impl<W> RpcHandler<W> for PreviewHandler<W>
+where
+    W: Write + Unpin + Send + Sync,
+{
+    fn name(&self) -> &'static str {
+        "PreviewHandler"
+    }
+
+    async fn handle(
+        &mut self,
+        api: &mut ApiCaller<W>,
+        op: &RpcInput,
+        _ch_broker: &mut ChBrokerSend,
+    ) -> Result<bool> {
+        match op {
+            RpcInput::Message(BrokerMessage::Preview(request))
+                if request.peer == self.peer_ssb_id =>
+            {
+                self.send_request(api, request).await
+            }
+            RpcInput::Network(req_no, rpc::RecvMsg::RpcResponse(_type, res))
+                if self.previews.contains_key(req_no) =>
+            {
+                self.recv_message(api, *req_no, res).await
+            }
+            RpcInput::Network(req_no, rpc::RecvMsg::CancelStreamResponse())
+            | RpcInput::Network(req_no, rpc::RecvMsg::ErrorResponse(_))
+                if self.previews.contains_key(req_no) =>
+            {
+                self.finish(*req_no);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+impl<W> PreviewHandler<W>
+where
+    W: Write + Unpin + Send + Sync,
+{
+    /// Request the latest messages of the feed of the given preview.
+    async fn send_request(
+        &mut self,
+        api: &mut ApiCaller<W>,
+        request: &PreviewRequest,
+    ) -> Result<bool> {
+        let args = json!({
+            "id": request.feed,
+            "limit": request.limit,
+            "reverse": true,
+        });
+        let req_no = api
+            .rpc()
+            .send_request(
+                &CREATE_HISTORY_STREAM_METHOD,
+                rpc::RpcType::Source,
+                rpc::ArgType::Array,
+                &[args],
+                &None::<()>,
+            )
+            .await?;
+
+        info!(
+            "requesting a preview of {} from {}",
+            request.feed, self.peer_ssb_id
+        );
+
+        self.previews.insert(
+            req_no,
+            ActivePreview {
+                request: request.clone(),
+                msgs: Vec::new(),
+            },
+        );
+
+        Ok(true)
+    }
+
+    /// Collect a message received for the preview requested with the given
+    /// request number. Messages of other feeds and invalid messages are
+    /// dropped.
+    async fn recv_message(
+        &mut self,
+        api: &mut ApiCaller<W>,
+        req_no: i32,
+        res: &[u8],
+    ) -> Result<bool> {
+        let preview = match self.previews.get_mut(&req_no) {
+            Some(preview) => preview,
+            None => return Ok(false),
+        };
+
+        match parse_message(res) {
+            Ok(msg) if msg.author().to_string() == preview.request.feed => preview.msgs.push(msg),
+            Ok(msg) => debug!("dropping preview msg of unrequested feed {}", msg.author()),
+            Err(err) => debug!("dropping invalid preview msg: {}", err),
+        }
+
+        // End the stream once enough messages have been received, in case
+        // the peer ignores the limit.
+        if preview.msgs.len() as u64 >= preview.request.limit {
+            api.rpc().send_stream_eof(req_no).await?;
+            self.finish(req_no);
+        }
+
+        Ok(true)
+    }
+
+    /// Hand the messages of the preview requested with the given request
+    /// number back to its caller.
+    fn finish(&mut self, req_no: i32) {
+        if let Some(preview) = self.previews.remove(&req_no) {
+            complete(preview.request.id, preview.msgs);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use kuska_ssb::keystore::OwnedIdentity;
+
+    #[test]
+    fn test_parse_message() -> Result<()> {
+        let identity = OwnedIdentity::create();
+        let msg = Message::sign(None, &identity, json!({ "type": "post", "text": "hi" }))?;
+
+        let parsed = parse_message(msg.to_string().as_bytes())?;
+        assert_eq!(parsed.id().to_string(), msg.id().to_string());
+
+        let mut tampered = msg.value.clone();
+        tampered["content"]["text"] = json!("bye");
+        assert!(parse_message(tampered.to_string().as_bytes()).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,131 @@
+//! Publish handler.
+//!
+//! Serves the `publish` method used by local clients (for example
+//! applications built on `ssb-client`) to author messages on the local feed.
+//! Only offered on sessions authenticated with the local identity.
+
+use std::marker::PhantomData;
+
+use async_std::io::Write;
+use async_trait::async_trait;
+use kuska_ssb::{api::ApiCaller, keystore::OwnedIdentity, rpc};
+use serde_json::Value;
+
+use crate::{
+    actors::muxrpc::handler::{RpcHandler, RpcInput},
+    broker::ChBrokerSend,
+    node::KV_STORE,
+    storage::publish,
+    Result,
+};
+
+/// Name of the publish MUXRPC method.
+const PUBLISH_METHOD: [&str; 1] = ["publish"];
+
+pub struct PublishHandler<W>
+where
+    W: Write + Unpin + Send + Sync,
+{
+    /// Identity used to sign published messages.
+    identity: OwnedIdentity,
+    phantom: PhantomData<W>,
+}
+
+impl<W> PublishHandler<W>
+where
+    W: Write + Unpin + Send + Sync,
+{
+    /// Instantiate a new handler which publishes messages as the given
+    /// identity.
+    pub fn new(identity: OwnedIdentity) -> Self {
+        Self {
+            identity,
+            phantom: PhantomData,
+        }
+    }
+}
+
+#[async_trait]
+impl<W> RpcHandler<W> for PublishHandler<W>
+where
+    W: Write + Unpin + Send + Sync,
+{
+    fn name(&self) -> &'static str {
+        "PublishHandler"
+    }
+
+    async fn handle(
+        &mut self,
+        api: &mut ApiCaller<W>,
+        op: &RpcInput,
+        _ch_broker: &mut ChBrokerSend,
+    ) -> Result<bool> {
+        match op {
+            RpcInput::Network(req_no, rpc::RecvMsg::RpcRequest(req))
+                if req.name == PUBLISH_METHOD =>
+            {
+                self.recv_publish(api, *req_no, req).await
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+impl<W> PublishHandler<W>
+where
+    W: Write + Unpin + Send + Sync,
+{
+    /// Sign the given message content, append it to the local feed and
+    /// respond with the stored message.
+    async fn recv_publish(
+        &mut self,
+        api: &mut ApiCaller<W>,
+        req_no: i32,
+        req: &rpc::Body,
+    ) -> Result<bool> {
+        let args: Vec<Value> = serde_json::from_value(req.args.clone())?;
+        let content = match args.into_iter().next() {
+            Some(content) if content.get("type").is_some() => content,
+            _ => {
+                api.rpc()
+                    .send_error(req_no, req.rpc_type, "content must have a type")
+                    .await?;
+
+                return Ok(true);
+            }
+        };
+
+        // Concurrent publishes are serialized by the publish lock.
+        let seq = match publish::publish(&self.identity, content, None).await {
+            Ok((_msg, seq)) => seq,
+            Err(err) => {
+                api.rpc()
+                    .send_error(req_no, req.rpc_type, &err.to_string())
+                    .await?;
+
+                return Ok(true);
+            }
+        };
+
+        let msg_kvt = KV_STORE.read().await.get_msg_kvt(&self.identity.id, seq)?;
+        match msg_kvt {
+            Some(msg_kvt) => {
+                api.rpc()
+                    .send_response(
+                        req_no,
+                        rpc::RpcType::Async,
+                        rpc::BodyType::JSON,
+                        &serde_json::to_vec(&msg_kvt)?,
+                    )
+                    .await?
+            }
+            None => {
+                api.rpc()
+                    .send_error(req_no, req.rpc_type, "message not stored")
+                    .await?
+            }
+        }
+
+        Ok(true)
+    }
+}
@@ -0,0 +1,444 @@
+//! Room server probing.
+//!
+//! Peers which are not in the replication list are probed for room support
+//! on connection: `room.metadata` is requested first (Rooms 2.0), falling
+//! back to `tunnel.isRoom` (Rooms 1.0 and older 2.0 servers). The
+//! capabilities of identified rooms are recorded in the connection manager,
+//! which then skips EBT replication on later connections with the room.
+//! The outcome of the probe is also cached in the peer capabilities, so
+//! that peers which are known not to be rooms are not probed again.
+//!
+//! Once a room has been identified the local node announces itself as an
+//! attendant: via `room.attendants` for Rooms 2.0 and `tunnel.announce` for
+//! Rooms 1.0.
+//!
+//! The `room.attendants` stream reports the attendants of the room and
+//! their arrivals and departures, which are recorded in the connection
+//! manager. Attendants in the replication set are passed on to the
+//! connection scheduler, which dials them at their known address. Tunneled
+//! connections through the room are not supported, so attendants without a
+//! known address are only recorded.
+
+use std::marker::PhantomData;
+
+use async_std::io::Write;
+use async_trait::async_trait;
+use futures::SinkExt;
+use kuska_ssb::{api::ApiCaller, crypto::ToSodiumObject, rpc};
+use log::{debug, info, trace};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{
+    actors::{
+        muxrpc::handler::{RpcHandler, RpcInput},
+        network::{
+            connection_manager::{RoomInfo, CONNECTION_MANAGER},
+            connection_scheduler::AttendantEvent,
+        },
+        replication::capabilities::{self, Capability},
+    },
+    broker::{BrokerEvent, BrokerMessage, ChBrokerSend, Destination},
+    config::PEERS_TO_REPLICATE,
+    util::prefixed,
+    Result,
+};
+
+/// Name of the Rooms 2.0 metadata MUXRPC method.
+const ROOM_METADATA_METHOD: [&str; 2] = ["room", "metadata"];
+
+/// Name of the room detection MUXRPC method.
+const TUNNEL_IS_ROOM_METHOD: [&str; 2] = ["tunnel", "isRoom"];
+
+/// Name of the Rooms 2.0 attendants MUXRPC method.
+const ROOM_ATTENDANTS_METHOD: [&str; 2] = ["room", "attendants"];
+
+/// Name of the Rooms 1.0 announcement MUXRPC method.
+const TUNNEL_ANNOUNCE_METHOD: [&str; 2] = ["tunnel", "announce"];
+
+/// Response to a `room.metadata` request.
+#[derive(Debug, Deserialize)]
+struct RoomMetadata {
+    name: Option<String>,
+    #[serde(default)]
+    membership: bool,
+    #[serde(default)]
+    features: Vec<String>,
+}
+
+/// An event of the `room.attendants` stream.
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum AttendantsUpdate {
+    /// The attendants of the room when the stream was opened.
+    State { ids: Vec<String> },
+    /// An attendant joined the room.
+    Joined { id: String },
+    /// An attendant left the room.
+    Left { id: String },
+}
+
+/// Parse the response to a `room.metadata` or `tunnel.isRoom` request,
+/// returning the capabilities of the room or `None` if the peer is not a
+/// room. Rooms 1.0 servers respond to `tunnel.isRoom` with `true`.
+pub fn parse_room_response(data: &[u8]) -> Option<RoomInfo> {
+    match serde_json::from_slice(data).ok()? {
+        Value::Bool(true) => Some(RoomInfo {
+            features: vec!["tunnel".to_string(), "room1".to_string()],
+            ..RoomInfo::default()
+        }),
+        value @ Value::Object(_) => {
+            let metadata: RoomMetadata = serde_json::from_value(value).ok()?;
+
+            Some(RoomInfo {
+                name: metadata.name,
+                membership: metadata.membership,
+                features: metadata.features,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// State of the room probe.
+#[derive(Debug, PartialEq)]
+enum Probe {
+    /// The probe has not been sent yet.
+    Pending,
+    /// Awaiting a response to `room.metadata`.
+    Metadata(i32),
+    /// Awaiting a response to `tunnel.isRoom`.
+    IsRoom(i32),
+    /// The probe has concluded.
+    Done,
+}
+
+/// Room probing handler. Detects room servers and announces the local node
+/// as an attendant.
+pub struct RoomHandler<W>
+where
+    W: Write + Unpin + Send + Sync,
+{
+    /// SSB ID of the remote peer.
+    peer_ssb_id: String,
+    probe: Probe,
+    /// Request number of the attendant announcement.
+    announce_req_no: Option<i32>,
+    phantom: PhantomData<W>,
+}
+
+impl<W> RoomHandler<W>
+where
+    W: Write + Unpin + Send + Sync,
+{
+    /// Instantiate a new handler for a connection with the given peer.
+    /// Peers in the replication list are not probed.
+    pub fn new(peer_ssb_id: &str) -> Self {
+        let peer_ssb_id = prefixed(peer_ssb_id);
+        let trusted = PEERS_TO_REPLICATE
+            .get()
+            .map(|peers| peers.contains_key(&peer_ssb_id))
+            .unwrap_or(false);
+
+        Self {
+            peer_ssb_id,
+            probe: if trusted { Probe::Done } else { Probe::Pending },
+            announce_req_no: None,
+            phantom: PhantomData,
+        }
+    }
+}
+
+#[async_trait]
+impl<W> RpcHandler<W> for RoomHandler<W>
+where
+    W: Write + Unpin + Send + Sync,
+{
+    fn name(&self) -> &'static str {
+        "RoomHandler"
+    }
+
+    async fn handle(
+        &mut self,
+        api: &mut ApiCaller<W>,
+        op: &RpcInput,
+        ch_broker: &mut ChBrokerSend,
+    ) -> Result<bool> {
+        match op {
+            RpcInput::Timer if self.probe == Probe::Pending => {
+                // Previously identified rooms are not probed again.
+                if let Some(room) = CONNECTION_MANAGER.read().await.room(&self.peer_ssb_id) {
+                    self.probe = Probe::Done;
+                    self.announce(api, &room).await?;
+
+                    return Ok(false);
+                }
+
+                if capabilities::is_unsupported(&self.peer_ssb_id, Capability::Room).await {
+                    trace!(target: "room", "not probing {}, which is not a room", self.peer_ssb_id);
+                    self.probe = Probe::Done;
+
+                    return Ok(false);
+                }
+
+                trace!(target: "room", "probing {} for room support", self.peer_ssb_id);
+                let req_no = self.send_request(api, &ROOM_METADATA_METHOD).await?;
+                self.probe = Probe::Metadata(req_no);
+
+                Ok(false)
+            }
+            RpcInput::Network(req_no, rpc::RecvMsg::RpcResponse(_type, res))
+                if self.is_probe(*req_no) =>
+            {
+                self.probe = Probe::Done;
+                match parse_room_response(res) {
+                    Some(room) => self.recv_room(api, room).await?,
+                    None => {
+                        debug!("Peer {} is not a room", self.peer_ssb_id);
+                        capabilities::record(&self.peer_ssb_id, Capability::Room, false).await;
+                    }
+                }
+
+                Ok(true)
+            }
+            RpcInput::Network(req_no, rpc::RecvMsg::ErrorResponse(_err))
+                if self.probe == Probe::Metadata(*req_no) =>
+            {
+                // Fall back to the Rooms 1.0 detection method.
+                let req_no = self.send_request(api, &TUNNEL_IS_ROOM_METHOD).await?;
+                self.probe = Probe::IsRoom(req_no);
+
+                Ok(true)
+            }
+            RpcInput::Network(req_no, rpc::RecvMsg::ErrorResponse(_err))
+                if self.probe == Probe::IsRoom(*req_no) =>
+            {
+                debug!("Peer {} is not a room", self.peer_ssb_id);
+                capabilities::record(&self.peer_ssb_id, Capability::Room, false).await;
+                self.probe = Probe::Done;
+
+                Ok(true)
+            }
+            RpcInput::Network(req_no, rpc::RecvMsg::RpcResponse(_type, res))
+                if self.announce_req_no == Some(*req_no) =>
+            {
+                // Rooms 1.0 respond to the announcement with a single
+                // message, which is not an attendants update.
+                if let Ok(update) = serde_json::from_slice::<AttendantsUpdate>(res) {
+                    self.recv_attendants(update, ch_broker).await?;
+                }
+
+                Ok(true)
+            }
+            // The closing of the attendants stream is not acted upon.
+            RpcInput::Network(
+                req_no,
+                rpc::RecvMsg::ErrorResponse(_) | rpc::RecvMsg::CancelStreamResponse(),
+            ) if self.announce_req_no == Some(*req_no) => Ok(true),
+            _ => Ok(false),
+        }
+    }
+}
+
+impl<W> RoomHandler<W>
+where
+    W: Write + Unpin + Send + Sync,
+{
+    /// Query whether the given request number belongs to the room probe.
+    fn is_probe(&self, req_no: i32) -> bool {
+        matches!(self.probe, Probe::Metadata(probe) | Probe::IsRoom(probe) if probe == req_no)
+    }
+
+    /// Send an argument-less async request for the given method.
+    async fn send_request(&mut self, api: &mut ApiCaller<W>, method: &[&str]) -> Result<i32> {
+        let args: [&str; 0] = [];
+        let req_no = api
+            .rpc()
+            .send_request(
+                method,
+                rpc::RpcType::Async,
+                rpc::ArgType::Array,
+                &args,
+                &None::<()>,
+            )
+            .await?;
+
+        Ok(req_no)
+    }
+
+    /// Record the capabilities of the room and announce the local node as an
+    /// attendant.
+    async fn recv_room(&mut self, api: &mut ApiCaller<W>, room: RoomInfo) -> Result<()> {
+        info!(
+            "Peer {} is a room (features: {})",
+            self.peer_ssb_id,
+            room.features.join(", ")
+        );
+
+        CONNECTION_MANAGER
+            .write()
+            .await
+            .record_room(&self.peer_ssb_id, room.clone());
+        capabilities::record(&self.peer_ssb_id, Capability::Room, true).await;
+        capabilities::record(
+            &self.peer_ssb_id,
+            Capability::Tunnel,
+            room.supports("tunnel"),
+        )
+        .await;
+
+        self.announce(api, &room).await
+    }
+
+    /// Record a change in the attendants of the room and pass the
+    /// attendants in the replication set on to the connection scheduler.
+    async fn recv_attendants(
+        &mut self,
+        update: AttendantsUpdate,
+        ch_broker: &mut ChBrokerSend,
+    ) -> Result<()> {
+        let (joined, left) = match update {
+            AttendantsUpdate::State { ids } => {
+                CONNECTION_MANAGER
+                    .write()
+                    .await
+                    .set_attendants(&self.peer_ssb_id, ids.to_owned());
+                (ids, Vec::new())
+            }
+            AttendantsUpdate::Joined { id } => {
+                CONNECTION_MANAGER
+                    .write()
+                    .await
+                    .update_attendant(&self.peer_ssb_id, &id, true);
+                (vec![id], Vec::new())
+            }
+            AttendantsUpdate::Left { id } => {
+                CONNECTION_MANAGER
+                    .write()
+                    .await
+                    .update_attendant(&self.peer_ssb_id, &id, false);
+                (Vec::new(), vec![id])
+            }
+        };
+
+        let peers = match PEERS_TO_REPLICATE.get() {
+            Some(peers) => peers,
+            None => return Ok(()),
+        };
+
+        for id in joined {
+            let configured = match peers.get(&id) {
+                Some(addr) => !addr.is_empty(),
+                None => continue,
+            };
+            let addr = match CONNECTION_MANAGER.read().await.peer_addr(&id) {
+                Some(addr) => addr,
+                None => {
+                    debug!(
+                        "No address known for attendant {} of room {}",
+                        id, self.peer_ssb_id
+                    );
+                    continue;
+                }
+            };
+            let public_key = id.trim_start_matches('@').to_ed25519_pk()?;
+
+            ch_broker
+                .send(BrokerEvent::new(
+                    Destination::Broadcast,
+                    BrokerMessage::Attendant(AttendantEvent::Joined {
+                        room: self.peer_ssb_id.to_owned(),
+                        peer: (public_key, addr),
+                        ephemeral: !configured,
+                    }),
+                ))
+                .await?;
+        }
+
+        for id in left.iter().filter(|id| peers.contains_key(*id)) {
+            let public_key = id.trim_start_matches('@').to_ed25519_pk()?;
+
+            ch_broker
+                .send(BrokerEvent::new(
+                    Destination::Broadcast,
+                    BrokerMessage::Attendant(AttendantEvent::Left {
+                        room: self.peer_ssb_id.to_owned(),
+                        public_key,
+                    }),
+                ))
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Announce the local node as an attendant of the room.
+    async fn announce(&mut self, api: &mut ApiCaller<W>, room: &RoomInfo) -> Result<()> {
+        let args: [&str; 0] = [];
+        let req_no = if room.supports("room2") {
+            api.rpc()
+                .send_request(
+                    &ROOM_ATTENDANTS_METHOD,
+                    rpc::RpcType::Source,
+                    rpc::ArgType::Array,
+                    &args,
+                    &None::<()>,
+                )
+                .await?
+        } else {
+            self.send_request(api, &TUNNEL_ANNOUNCE_METHOD).await?
+        };
+        self.announce_req_no = Some(req_no);
+
+        debug!("Announced as attendant of room {}", self.peer_ssb_id);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_room_response() {
+        let room = parse_room_response(
+            br#"{"name":"Solar Room","membership":true,"features":["tunnel","room2","alias"]}"#,
+        )
+        .unwrap();
+        assert_eq!(room.name.as_deref(), Some("Solar Room"));
+        assert!(room.membership);
+        assert!(room.supports("room2"));
+        assert!(room.supports_alias());
+
+        let room = parse_room_response(b"true").unwrap();
+        assert!(room.supports("room1"));
+        assert!(!room.supports_alias());
+
+        assert!(parse_room_response(b"false").is_none());
+        assert!(parse_room_response(b"not json").is_none());
+    }
+
+    #[test]
+    fn test_parse_attendants_update() {
+        let update: AttendantsUpdate =
+            serde_json::from_slice(br#"{"type":"state","ids":["@a","@b"]}"#).unwrap();
+        assert_eq!(
+            update,
+            AttendantsUpdate::State {
+                ids: vec!["@a".to_string(), "@b".to_string()]
+            }
+        );
+
+        let update: AttendantsUpdate =
+            serde_json::from_slice(br#"{"type":"left","id":"@a"}"#).unwrap();
+        assert_eq!(
+            update,
+            AttendantsUpdate::Left {
+                id: "@a".to_string()
+            }
+        );
+
+        assert!(serde_json::from_slice::<AttendantsUpdate>(b"true").is_err());
+    }
+}
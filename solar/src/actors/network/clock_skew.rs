@@ -0,0 +1,138 @@
+//! Clock skew detection.
+//!
+//! Messages assert the time at which they were published, according to the
+//! clock of their author. The secret handshake carries no timestamp, so the
+//! clock of a peer is instead compared against the local clock using the
+//! messages of its own feed, as they are received from it: a message which
+//! asserts a time further in the future than `CLOCK_SKEW_THRESHOLD` reveals
+//! a clock running ahead. A message received within the threshold of its
+//! asserted time clears the flag, while older messages say nothing of the
+//! clock of the peer (they may have been published long ago).
+//!
+//! Flagged peers are reported by the connection manager (see the
+//! `connections` JSON-RPC method), and the receive time of their messages
+//! is used in place of the asserted time for ordering (in the timeline and
+//! notifications).
+use std::{collections::HashMap, sync::RwLock};
+
+use kuska_ssb::feed::Message;
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use serde_json::Value;
+
+use crate::util::{now_millis, prefixed};
+
+/// Difference (in milliseconds) between the asserted time of a message and
+/// the local time above which the clock of its author is considered skewed.
+pub const CLOCK_SKEW_THRESHOLD: i64 = 10 * 60 * 1000;
+
+/// Estimated clock skew (in milliseconds) of the peers whose clock is
+/// considered skewed, keyed by `@`-prefixed SSB ID.
+static SKEWED: Lazy<RwLock<HashMap<String, i64>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// Estimate the clock skew of the author of a message which asserts the
+/// given time and was received at the given local time (both in
+/// milliseconds).
+///
+/// Returns `Some(skew)` if the message reveals a skewed clock, `Some(0)` if
+/// it reveals a clock in sync with the local clock and `None` if it reveals
+/// nothing.
+pub fn estimate(asserted: f64, received: u64) -> Option<i64> {
+    let skew = asserted as i64 - received as i64;
+
+    if skew > CLOCK_SKEW_THRESHOLD {
+        Some(skew)
+    } else if skew >= -CLOCK_SKEW_THRESHOLD {
+        Some(0)
+    } else {
+        None
+    }
+}
+
+/// Compare the asserted time of the given message, received from the peer
+/// with the given SSB ID, against the local time if the message belongs to
+/// the feed of the peer, and flag (or clear) the clock skew of the peer
+/// accordingly.
+pub fn observe(peer_ssb_id: &str, msg: &Message) {
+    if prefixed(msg.author()) != prefixed(peer_ssb_id) {
+        return;
+    }
+    if let Some(asserted) = msg.value.get("timestamp").and_then(Value::as_f64) {
+        observe_asserted(peer_ssb_id, asserted)
+    }
+}
+
+/// Compare the given asserted time of a message of the peer with the given
+/// SSB ID against the local time.
+fn observe_asserted(peer_ssb_id: &str, asserted: f64) {
+    let received = now_millis();
+
+    let peer = prefixed(peer_ssb_id);
+    let mut skewed = SKEWED.write().unwrap_or_else(|err| err.into_inner());
+    match estimate(asserted, received) {
+        Some(0) => {
+            if skewed.remove(&peer).is_some() {
+                info!("Clock of peer {} is back in sync", peer);
+            }
+        }
+        Some(skew) => {
+            if skewed.insert(peer.clone(), skew).is_none() {
+                warn!("Clock of peer {} is {} seconds ahead", peer, skew / 1000);
+            }
+        }
+        None => (),
+    }
+}
+
+/// Return the estimated clock skew (in milliseconds) of the peer with the
+/// given SSB ID, if its clock is considered skewed.
+pub fn skew_of(ssb_id: &str) -> Option<i64> {
+    SKEWED
+        .read()
+        .unwrap_or_else(|err| err.into_inner())
+        .get(&prefixed(ssb_id))
+        .copied()
+}
+
+/// Query whether the clock of the peer with the given SSB ID is considered
+/// skewed.
+pub fn is_skewed(ssb_id: &str) -> bool {
+    skew_of(ssb_id).is_some()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_estimate() {
+        let now = 1_700_000_000_000;
+
+        // A fresh message reveals a clock in sync.
+        assert_eq!(estimate(now as f64 - 1_000.0, now), Some(0));
+        assert_eq!(estimate(now as f64 + 60_000.0, now), Some(0));
+
+        // A message from the future reveals a clock running ahead.
+        let ahead = 3_600_000;
+        assert_eq!(estimate((now + ahead) as f64, now), Some(ahead as i64));
+
+        // An old message reveals nothing.
+        assert_eq!(estimate(now as f64 - 86_400_000.0, now), None);
+    }
+
+    #[test]
+    fn test_observe_asserted() {
+        let peer = "@skewed=.ed25519";
+        let now = now_millis() as f64;
+
+        observe_asserted(peer, now + 3_600_000.0);
+        assert!(is_skewed(peer));
+        assert!(is_skewed("skewed=.ed25519"));
+
+        observe_asserted(peer, now - 86_400_000.0);
+        assert!(is_skewed(peer));
+
+        observe_asserted(peer, now);
+        assert!(!is_skewed(peer));
+    }
+}
@@ -1,7 +1,15 @@
-use std::net::{IpAddr, Ipv4Addr};
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr},
+    time::Duration,
+};
 
+use async_std::net::TcpStream;
 use kuska_sodiumoxide::crypto::auth::Key as NetworkKey;
 use kuska_ssb::{crypto::ed25519::PublicKey, discovery};
+use socket2::SockRef;
+
+use crate::config::TRANSPORT_CONFIG;
 
 #[derive(Debug, Clone)]
 pub struct NetworkConfig {
@@ -9,27 +17,119 @@ pub struct NetworkConfig {
     /// the corresponding address (IP / hostname and port).
     pub connect: Vec<(PublicKey, String)>,
 
+    /// HTTP room invite(s) to consume on startup. The address of each room
+    /// is added to the connection scheduler.
+    pub join_invites: Vec<String>,
+
     /// Secret handshake HMAC key (aka. network key, caps key, SHS key).
     pub key: NetworkKey,
 
     /// Run LAN discovery (default: false).
     pub lan_discovery: bool,
 
+    /// Address (host and port) announced by LAN discovery in place of the
+    /// addresses of the local network interfaces, eg. the address of the
+    /// host when running in a container (default: none).
+    pub lan_announce_addr: Option<String>,
+
+    /// Interval between LAN discovery announcements (default: 15 seconds).
+    pub lan_interval: Duration,
+
+    /// Listen for LAN discovery announcements without announcing the local
+    /// peer (default: false).
+    pub lan_listen_only: bool,
+
+    /// Maximum number of outbound dials (address resolution, TCP connection
+    /// and secret handshake) in flight at once (default: 8).
+    pub max_concurrent_dials: usize,
+
+    /// Port on which to serve local SSB clients (eg. Patchwork or Oasis)
+    /// over MUXRPC. The endpoint binds to 127.0.0.1 (default: disabled).
+    pub local_rpc_port: Option<u16>,
+
     /// IP to bind for TCP server (default: 0.0.0.0).
     pub ip: IpAddr,
 
     /// Port to bind for TCP server (default: 8008).
     pub port: u16,
+
+    /// Tuning of TCP connections and box streams.
+    pub transport: TransportConfig,
 }
 
 impl Default for NetworkConfig {
     fn default() -> Self {
         Self {
             connect: Vec::new(),
+            join_invites: Vec::new(),
             key: discovery::ssb_net_id(),
             lan_discovery: false,
+            lan_announce_addr: None,
+            lan_interval: Duration::from_secs(15),
+            lan_listen_only: false,
+            max_concurrent_dials: 8,
+            local_rpc_port: None,
             ip: IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
             port: 8008,
+            transport: TransportConfig::default(),
+        }
+    }
+}
+
+/// Tuning of TCP connections and of the box streams carried over them.
+///
+/// Suitable values differ between deployments: a pub in a datacenter benefits
+/// from large buffers, while a phone on a flaky wireless network is better
+/// served by small buffers and small writes being sent without delay.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransportConfig {
+    /// Capacity in bytes of the read and write buffers of each box stream,
+    /// which must be at least 8 KiB to hold a full box (default: 32 KiB).
+    pub box_stream_buffer_size: usize,
+
+    /// Disable Nagle's algorithm, sending small writes (such as vector
+    /// clocks) without waiting for more data to be written (default: false).
+    pub tcp_nodelay: bool,
+
+    /// Size in bytes of the send buffer of TCP sockets (default: chosen by
+    /// the operating system).
+    pub tcp_send_buffer_size: Option<usize>,
+
+    /// Size in bytes of the receive buffer of TCP sockets (default: chosen
+    /// by the operating system).
+    pub tcp_recv_buffer_size: Option<usize>,
+}
+
+impl Default for TransportConfig {
+    fn default() -> Self {
+        Self {
+            box_stream_buffer_size: 0x8000,
+            tcp_nodelay: false,
+            tcp_send_buffer_size: None,
+            tcp_recv_buffer_size: None,
+        }
+    }
+}
+
+impl TransportConfig {
+    /// Return the transport configuration of the running node, or the
+    /// default configuration if no node has been started.
+    pub fn current() -> Self {
+        TRANSPORT_CONFIG.get().copied().unwrap_or_default()
+    }
+
+    /// Apply the TCP options to the given connection.
+    pub fn configure(&self, stream: &TcpStream) -> io::Result<()> {
+        stream.set_nodelay(self.tcp_nodelay)?;
+
+        let socket = SockRef::from(stream);
+        if let Some(size) = self.tcp_send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.tcp_recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
         }
+
+        Ok(())
     }
 }
@@ -18,6 +18,7 @@ use kuska_ssb::{
 use crate::{
     actors::network::connection_manager::{ConnectionEvent, CONNECTION_MANAGER},
     broker::{ActorEndpoint, BrokerEvent, BrokerMessage, Destination, BROKER},
+    util::prefixed,
     Result,
 };
 
@@ -39,12 +40,7 @@ impl Display for TcpConnection {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match &self {
             TcpConnection::Dial { addr, public_key } => {
-                let public_key_as_id = public_key.to_ssb_id();
-                let peer_public_key = if public_key_as_id.starts_with('@') {
-                    public_key_as_id
-                } else {
-                    format!("@{}", public_key_as_id)
-                };
+                let peer_public_key = prefixed(&public_key.to_ssb_id());
 
                 write!(f, "<TCP Dialer {} / {}>", peer_public_key, addr)
             }
@@ -88,14 +84,7 @@ impl Display for ConnectionData {
         };
 
         let peer_public_key = match &self.peer_public_key {
-            Some(key) => {
-                let ssb_id = key.to_ssb_id();
-                if ssb_id.starts_with('@') {
-                    ssb_id
-                } else {
-                    format!("@{}", ssb_id)
-                }
-            }
+            Some(key) => prefixed(&key.to_ssb_id()),
             None => "_".to_string(),
         };
 
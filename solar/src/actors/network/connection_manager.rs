@@ -9,36 +9,49 @@
 //! Connection data, including the underlying TCP stream, is passed around with
 //! each event variant - allowing the handlers to take ownership of the data.
 
-use std::net::Shutdown;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::{Shutdown, SocketAddr},
+    time::Instant,
+};
 
 use async_std::{
-    net::TcpStream,
+    net::{TcpStream, ToSocketAddrs},
     sync::{Arc, RwLock},
     task,
     task::JoinHandle,
 };
 use futures::{select_biased, stream::StreamExt, FutureExt, SinkExt};
 use kuska_ssb::{
-    crypto::{ed25519, ToSsbId},
-    handshake::async_std::{handshake_client, handshake_server},
+    crypto::{ed25519, ToSodiumObject, ToSsbId},
     keystore::OwnedIdentity,
 };
-use log::{debug, error, info, trace};
+use log::{debug, error, info, trace, warn};
 use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
 
 use crate::{
     actors::{
         network::{
-            connection,
-            connection::{ConnectionData, TcpConnection},
+            clock_skew,
+            config::TransportConfig,
+            connection::ConnectionData,
+            connection_scheduler::DialRequest,
+            connection_state::{ConnectionRecord, ConnectionState, ConnectionTransition},
+            handshake::{self, HandshakeError, HandshakeRole},
+            reputation, stats,
+        },
+        replication::{
+            capabilities::{self, Capability},
+            ebt::EbtEvent,
         },
-        replication::ebt::EbtEvent,
     },
     broker::{
         ActorEndpoint, Broker, BrokerEvent, BrokerMessage, ChBrokerSend, Destination, BROKER,
     },
     config::{NETWORK_KEY, PEERS_TO_REPLICATE},
     error::Error,
+    util::{now_millis, prefixed},
     Result,
 };
 
@@ -46,13 +59,103 @@ use crate::{
 pub static CONNECTION_MANAGER: Lazy<Arc<RwLock<ConnectionManager>>> =
     Lazy::new(|| Arc::new(RwLock::new(ConnectionManager::new())));
 
+/// Maximum number of dial attempts retained in the history of each peer.
+/// The oldest attempts are discarded once the limit is reached.
+const DIAL_HISTORY_CAPACITY: usize = 32;
+
+/// Maximum number of handshakes retained in the handshake history.
+const HANDSHAKE_HISTORY_CAPACITY: usize = 64;
+
 type EnableSelectiveReplication = bool;
 type IsListener = bool;
 
+/// The outcome of an outbound connection attempt.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "outcome", content = "error", rename_all = "snake_case")]
+pub enum DialOutcome {
+    /// The TCP connection was established and the secret handshake succeeded.
+    Connected,
+    /// The address of the peer could not be resolved (DNS failure).
+    AddressResolutionFailed(String),
+    /// The peer refused the TCP connection.
+    ConnectionRefused,
+    /// The TCP connection failed for another reason (eg. timeout or
+    /// unreachable network).
+    ConnectionFailed(String),
+    /// The secret handshake failed (eg. the peer has another public key or
+    /// network key).
+    HandshakeFailed(HandshakeError),
+}
+
+/// Metrics of a single secret handshake, inbound or outbound.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HandshakeRecord {
+    /// Milliseconds since the UNIX epoch.
+    pub timestamp: u64,
+    /// ID of the connection.
+    pub connection_id: usize,
+    /// SSB ID of the peer, if known (an inbound peer is only identified by
+    /// a successful handshake).
+    pub peer: Option<String>,
+    /// Address of the peer.
+    pub addr: Option<String>,
+    /// Role of the local peer in the handshake.
+    pub role: HandshakeRole,
+    /// Duration of the handshake in milliseconds.
+    pub duration_ms: u64,
+    /// The failure of the handshake, if any.
+    pub error: Option<HandshakeError>,
+}
+
+/// A single outbound connection attempt.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DialAttempt {
+    /// Milliseconds since the UNIX epoch.
+    pub timestamp: u64,
+    /// The address which was dialed.
+    pub addr: String,
+    #[serde(flatten)]
+    pub outcome: DialOutcome,
+}
+
+/// The capabilities of a room server, as reported by `room.metadata` or
+/// `tunnel.isRoom`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct RoomInfo {
+    /// Name of the room, if any.
+    pub name: Option<String>,
+    /// Whether the local identity is a member of the room.
+    pub membership: bool,
+    /// Features supported by the room (eg. `tunnel`, `room1`, `room2`,
+    /// `alias`, `httpAuth`, `httpInvite`).
+    pub features: Vec<String>,
+}
+
+impl RoomInfo {
+    /// Query whether the room supports the given feature.
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|supported| supported == feature)
+    }
+
+    /// Query whether the room supports aliases.
+    pub fn supports_alias(&self) -> bool {
+        self.supports("alias")
+    }
+}
+
+/// Replication strategy of a session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplicationStrategy {
+    /// Epidemic broadcast tree replication.
+    Ebt,
+    /// Classic replication (`createHistoryStream`).
+    Classic,
+}
+
 /// Connection events with associated connection data.
 #[derive(Debug, Clone)]
 pub enum ConnectionEvent {
-    LanDiscovery(TcpConnection, OwnedIdentity, EnableSelectiveReplication),
     Staging(ConnectionData, OwnedIdentity, EnableSelectiveReplication),
     Connecting(ConnectionData, OwnedIdentity, EnableSelectiveReplication),
     Handshaking(
@@ -73,11 +176,23 @@ pub enum ConnectionEvent {
 /// Connection manager (broker).
 #[derive(Debug)]
 pub struct ConnectionManager {
-    /// The public keys of all peers to whom we are currently connected.
-    pub connected_peers: Vec<(ed25519::PublicKey, usize)>,
-    /// The public keys of all peers to whom we are currently attempting a
-    /// connection
-    pub connecting_peers: Vec<(ed25519::PublicKey, usize)>,
+    /// The connections which are not yet closed, keyed by connection ID.
+    connections: HashMap<usize, ConnectionRecord>,
+    /// The most recent dial attempts for each peer, keyed by SSB ID.
+    dial_history: HashMap<String, VecDeque<DialAttempt>>,
+    /// The most recent secret handshakes, inbound and outbound.
+    handshakes: VecDeque<HandshakeRecord>,
+    /// Capabilities of the peers identified as room servers, keyed by SSB
+    /// ID.
+    rooms: HashMap<String, RoomInfo>,
+    /// SSB IDs of the attendants of each room to which the local peer is
+    /// connected, keyed by the SSB ID of the room.
+    attendants: HashMap<String, HashSet<String>>,
+    /// Streams of the active connections, keyed by connection ID.
+    streams: HashMap<usize, TcpStream>,
+    /// Replication strategies to be used in the next session with each peer,
+    /// keyed by SSB ID.
+    forced_strategies: HashMap<String, ReplicationStrategy>,
     /// Idle connection timeout limit.
     pub idle_timeout_limit: u8,
     /// ID number of the most recently registered connection.
@@ -93,8 +208,13 @@ impl ConnectionManager {
         let msgloop = task::spawn(Self::msg_loop());
 
         Self {
-            connected_peers: Vec::new(),
-            connecting_peers: Vec::new(),
+            connections: HashMap::new(),
+            dial_history: HashMap::new(),
+            handshakes: VecDeque::new(),
+            rooms: HashMap::new(),
+            attendants: HashMap::new(),
+            streams: HashMap::new(),
+            forced_strategies: HashMap::new(),
             idle_timeout_limit: 30,
             last_connection_id: 0,
             msgloop: Some(msgloop),
@@ -103,72 +223,426 @@ impl ConnectionManager {
 
     /// Query the number of active peer connections.
     fn _count_connections(&self) -> usize {
-        self.connected_peers.len()
+        self.connections
+            .values()
+            .filter(|record| record.state.is_connected())
+            .count()
     }
 
-    /// Query whether the list of connected peers contains the given peer.
-    /// Returns `true` if the peer is in the list, otherwise a `false` value is
-    /// returned.
+    /// Query whether there is an established connection with the given
+    /// peer.
     pub fn contains_connected_peer(&self, peer_id: &ed25519::PublicKey) -> bool {
-        self.connected_peers
-            .iter()
-            .any(|(connected_peer_id, _)| connected_peer_id == peer_id)
+        self.connections.values().any(|record| {
+            record.public_key.as_ref() == Some(peer_id) && record.state.is_connected()
+        })
     }
 
-    /// Add a peer to the list of connected peers.
-    /// Returns `true` if the peer was not already in the list, otherwise a
-    /// `false` value is returned.
-    fn insert_connected_peer(&mut self, peer_id: ed25519::PublicKey, connection_id: usize) {
-        self.connected_peers.push((peer_id, connection_id));
+    /// Query whether a connection with the given peer is being established.
+    pub fn contains_connecting_peer(&self, peer_id: &ed25519::PublicKey) -> bool {
+        self.connections.values().any(|record| {
+            record.public_key.as_ref() == Some(peer_id) && record.state.is_connecting()
+        })
     }
 
-    /// Remove a peer from the list of connected peers.
-    /// Returns `true` if the peer was in the list, otherwise a `false` value
-    /// is returned.
-    fn remove_connected_peer(&mut self, peer_id: ed25519::PublicKey, connection_id: usize) {
-        if let Some(index) = self
-            .connected_peers
-            .iter()
-            .position(|&entry| entry == (peer_id, connection_id))
-        {
-            // Ignore the return value.
-            let _ = self.connected_peers.remove(index);
+    /// Return the connections which are not yet closed, ordered by
+    /// connection ID, flagging the peers whose clock is skewed.
+    pub fn connections(&self) -> Vec<ConnectionRecord> {
+        let mut connections: Vec<ConnectionRecord> = self.connections.values().cloned().collect();
+        connections.sort_by_key(|record| record.connection_id);
+        for record in connections.iter_mut() {
+            record.clock_skew = record.peer.as_deref().and_then(clock_skew::skew_of);
         }
+
+        connections
     }
 
-    /// Query whether the list of connecting peers contains the given peer.
-    /// Returns `true` if the peer is in the list, otherwise a `false` value is
-    /// returned.
-    pub fn contains_connecting_peer(&self, peer_id: &ed25519::PublicKey) -> bool {
-        self.connecting_peers
+    /// Move the given connection to the given state. A connection which is
+    /// not yet tracked enters the state machine when dialing or when
+    /// handshaking (`inbound` is only taken into account then), and a closed
+    /// connection is no longer tracked.
+    ///
+    /// Returns the transition, or `None` if the connection cannot pass from
+    /// its current state to the given state (in which case the state is left
+    /// unchanged).
+    fn transition(
+        &mut self,
+        connection_data: &ConnectionData,
+        inbound: bool,
+        to: ConnectionState,
+    ) -> Option<ConnectionTransition> {
+        let from = self
+            .connections
+            .get(&connection_data.id)
+            .map(|record| record.state);
+
+        let valid = match &from {
+            Some(from) => from.can_transition_to(&to),
+            None => matches!(to, ConnectionState::Dialing | ConnectionState::Handshaking),
+        };
+        if !valid {
+            debug!(
+                "Ignoring transition of connection {} from {:?} to {}",
+                connection_data.id, from, to
+            );
+
+            return None;
+        }
+
+        let public_key = connection_data.peer_public_key;
+        let peer = public_key.as_ref().map(Self::ssb_id);
+
+        if to == ConnectionState::Closed {
+            self.connections.remove(&connection_data.id);
+        } else {
+            let since = now_millis();
+            let record = self
+                .connections
+                .entry(connection_data.id)
+                .or_insert_with(|| ConnectionRecord {
+                    connection_id: connection_data.id,
+                    public_key: None,
+                    peer: None,
+                    addr: None,
+                    inbound,
+                    state: to,
+                    since,
+                    clock_skew: None,
+                });
+
+            // An inbound peer is only identified by the handshake.
+            if public_key.is_some() {
+                record.public_key = public_key;
+                record.peer = peer.clone();
+            }
+            if connection_data.peer_addr.is_some() {
+                record.addr = connection_data.peer_addr.clone();
+            }
+            record.state = to;
+            record.since = since;
+        }
+
+        trace!(target: "connection-manager", "Connection {} is {}", connection_data.id, to);
+
+        Some(ConnectionTransition {
+            connection_id: connection_data.id,
+            peer,
+            from,
+            to,
+        })
+    }
+
+    /// Update the state of the connection concerned by the given event and
+    /// broadcast the resulting transition via the broker.
+    async fn track(event: &ConnectionEvent, ch_broker: &mut ChBrokerSend) -> Result<()> {
+        let (connection_data, inbound, state) = match event {
+            ConnectionEvent::Staging(..) | ConnectionEvent::Replicate(..) => return Ok(()),
+            ConnectionEvent::Connecting(data, ..) => (data, false, ConnectionState::Dialing),
+            ConnectionEvent::Handshaking(data, _, _, listener) => {
+                (data, *listener, ConnectionState::Handshaking)
+            }
+            ConnectionEvent::Connected(data, _, listener) => {
+                (data, *listener, ConnectionState::Connected)
+            }
+            ConnectionEvent::ReplicatingEbt(data, listener) => (
+                data,
+                *listener,
+                ConnectionState::Replicating(ReplicationStrategy::Ebt),
+            ),
+            ConnectionEvent::ReplicatingClassic(data) => (
+                data,
+                false,
+                ConnectionState::Replicating(ReplicationStrategy::Classic),
+            ),
+            ConnectionEvent::Disconnecting(data) => (data, false, ConnectionState::Draining),
+            ConnectionEvent::Disconnected(data) | ConnectionEvent::Error(data, _) => {
+                (data, false, ConnectionState::Closed)
+            }
+        };
+
+        let transition =
+            CONNECTION_MANAGER
+                .write()
+                .await
+                .transition(connection_data, inbound, state);
+        if let Some(transition) = transition {
+            match (&transition.to, &transition.peer) {
+                (ConnectionState::Connected, Some(peer)) => {
+                    stats::record_peer(peer);
+                    reputation::record_connected(transition.connection_id, peer);
+                }
+                (ConnectionState::Closed, _) => {
+                    reputation::record_disconnected(transition.connection_id)
+                }
+                _ => (),
+            }
+            ch_broker
+                .send(BrokerEvent::new(
+                    Destination::Broadcast,
+                    BrokerMessage::Transition(transition),
+                ))
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Format the given public key as an `@`-prefixed SSB ID.
+    fn ssb_id(peer_id: &ed25519::PublicKey) -> String {
+        prefixed(&peer_id.to_ssb_id())
+    }
+
+    /// Record the outcome of a dial attempt to the given peer.
+    fn record_dial_attempt(
+        &mut self,
+        peer_id: &ed25519::PublicKey,
+        addr: &str,
+        outcome: DialOutcome,
+    ) {
+        let timestamp = now_millis();
+
+        let history = self.dial_history.entry(Self::ssb_id(peer_id)).or_default();
+        history.push_back(DialAttempt {
+            timestamp,
+            addr: addr.to_owned(),
+            outcome,
+        });
+
+        // Discard the oldest attempts to keep the history bounded.
+        while history.len() > DIAL_HISTORY_CAPACITY {
+            history.pop_front();
+        }
+    }
+
+    /// Return the dial history of the peer with the given SSB ID, ordered
+    /// from oldest to newest attempt.
+    pub fn dial_history(&self, ssb_id: &str) -> Vec<DialAttempt> {
+        self.dial_history
+            .get(ssb_id)
+            .map(|history| history.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Record the metrics of a secret handshake.
+    fn record_handshake(&mut self, record: HandshakeRecord) {
+        self.handshakes.push_back(record);
+
+        // Discard the oldest handshakes to keep the history bounded.
+        while self.handshakes.len() > HANDSHAKE_HISTORY_CAPACITY {
+            self.handshakes.pop_front();
+        }
+    }
+
+    /// Return the most recent secret handshakes, ordered from oldest to
+    /// newest.
+    pub fn handshakes(&self) -> Vec<HandshakeRecord> {
+        self.handshakes.iter().cloned().collect()
+    }
+
+    /// Return the SSB ID and address of every peer whose most recent dial
+    /// attempt succeeded.
+    pub fn reachable_peers(&self) -> Vec<(String, String)> {
+        self.dial_history
             .iter()
-            .any(|(connecting_peer_id, _)| connecting_peer_id == peer_id)
+            .filter_map(|(ssb_id, history)| match history.back() {
+                Some(attempt) if attempt.outcome == DialOutcome::Connected => {
+                    Some((ssb_id.to_owned(), attempt.addr.to_owned()))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Record the capabilities of the room server with the given SSB ID.
+    pub fn record_room(&mut self, ssb_id: &str, info: RoomInfo) {
+        self.rooms.insert(ssb_id.to_owned(), info);
     }
 
-    /// Add a peer to the list of connecting peers.
-    /// Returns `true` if the peer was not already in the list, otherwise a
-    /// `false` value is returned.
-    fn insert_connecting_peer(&mut self, peer_id: ed25519::PublicKey, connection_id: usize) {
-        self.connecting_peers.push((peer_id, connection_id))
+    /// Return the capabilities of the peer with the given SSB ID if it has
+    /// been identified as a room server.
+    pub fn room(&self, ssb_id: &str) -> Option<RoomInfo> {
+        self.rooms.get(ssb_id).cloned()
     }
 
-    /// Remove a peer from the list of connecting peers.
-    /// Returns `true` if the peer was in the list, otherwise a `false` value
-    /// is returned.
-    fn remove_connecting_peer(&mut self, peer_id: ed25519::PublicKey, connection_id: usize) {
-        if let Some(index) = self
-            .connecting_peers
+    /// Return the SSB IDs of the rooms to which the local peer is connected
+    /// and which support tunneled connections, in alphabetical order.
+    pub fn tunnel_rooms(&self) -> Vec<String> {
+        let mut rooms: Vec<String> = self
+            .rooms
             .iter()
-            .position(|&entry| entry == (peer_id, connection_id))
-        {
-            // Ignore the return value.
-            let _ = self.connecting_peers.remove(index);
+            .filter(|(ssb_id, room)| {
+                room.supports("tunnel")
+                    && self.connections.values().any(|record| {
+                        record.peer.as_ref() == Some(ssb_id) && record.state.is_connected()
+                    })
+            })
+            .map(|(ssb_id, _)| ssb_id.to_owned())
+            .collect();
+        rooms.sort();
+
+        rooms
+    }
+
+    /// Record the attendants of the room with the given SSB ID, replacing
+    /// any previously recorded.
+    pub fn set_attendants(&mut self, room: &str, attendants: Vec<String>) {
+        self.attendants
+            .insert(room.to_owned(), attendants.into_iter().collect());
+    }
+
+    /// Record a change in the attendants of the room with the given SSB ID.
+    pub fn update_attendant(&mut self, room: &str, attendant: &str, joined: bool) {
+        let attendants = self.attendants.entry(room.to_owned()).or_default();
+        if joined {
+            attendants.insert(attendant.to_owned());
+        } else {
+            attendants.remove(attendant);
+        }
+    }
+
+    /// Return the SSB IDs of the attendants of the room with the given SSB
+    /// ID, in alphabetical order.
+    pub fn attendants(&self, room: &str) -> Vec<String> {
+        let mut attendants: Vec<String> = self
+            .attendants
+            .get(room)
+            .map(|attendants| attendants.iter().cloned().collect())
+            .unwrap_or_default();
+        attendants.sort();
+
+        attendants
+    }
+
+    /// Return the address of the given peer: the address listed in the
+    /// replication configuration or, failing that, the most recent address
+    /// at which the peer was dialed successfully.
+    pub fn peer_addr(&self, ssb_id: &str) -> Option<String> {
+        let configured = PEERS_TO_REPLICATE
+            .get()
+            .and_then(|peers| peers.get(ssb_id))
+            .filter(|addr| !addr.is_empty());
+
+        configured.cloned().or_else(|| {
+            self.dial_history.get(ssb_id).and_then(|history| {
+                history
+                    .iter()
+                    .rev()
+                    .find(|attempt| attempt.outcome == DialOutcome::Connected)
+                    .map(|attempt| attempt.addr.to_owned())
+            })
+        })
+    }
+
+    /// Start a new replication session with the given peer using the given
+    /// strategy, regardless of whether the peer is trusted or a room.
+    ///
+    /// A session cannot be restarted on an established connection, so any
+    /// active connection with the peer is closed and the peer is dialed
+    /// again. Returns `false` if no address is known for the peer (for
+    /// example, if it only ever connected to the local node); the strategy
+    /// is then used when the peer next connects.
+    pub async fn replicate_now(ssb_id: &str, strategy: ReplicationStrategy) -> Result<bool> {
+        let public_key = ssb_id.trim_start_matches('@').to_ed25519_pk()?;
+        let ssb_id = Self::ssb_id(&public_key);
+
+        let mut ch_broker = BROKER.lock().await.create_sender();
+
+        let (addr, transitions) = {
+            let mut connection_manager = CONNECTION_MANAGER.write().await;
+            connection_manager
+                .forced_strategies
+                .insert(ssb_id.to_owned(), strategy);
+
+            // Close the active connections with the peer. The connections
+            // start draining immediately so that the peer can be dialed again
+            // while the sessions wind down.
+            let connections: Vec<ConnectionData> = connection_manager
+                .connections
+                .values()
+                .filter(|record| {
+                    record.public_key == Some(public_key) && record.state.is_connected()
+                })
+                .map(|record| ConnectionData {
+                    id: record.connection_id,
+                    peer_public_key: record.public_key,
+                    ..ConnectionData::default()
+                })
+                .collect();
+            let mut transitions = Vec::new();
+            for connection_data in connections {
+                transitions.extend(connection_manager.transition(
+                    &connection_data,
+                    false,
+                    ConnectionState::Draining,
+                ));
+                if let Some(stream) = connection_manager.streams.remove(&connection_data.id) {
+                    let _ = stream.shutdown(Shutdown::Both);
+                }
+            }
+
+            (connection_manager.peer_addr(&ssb_id), transitions)
+        };
+
+        for transition in transitions {
+            ch_broker
+                .send(BrokerEvent::new(
+                    Destination::Broadcast,
+                    BrokerMessage::Transition(transition),
+                ))
+                .await?;
+        }
+
+        match addr {
+            Some(addr) => {
+                info!("Replicating with {} via {:?} now", ssb_id, strategy);
+                ch_broker
+                    .send(BrokerEvent::new(
+                        Destination::Broadcast,
+                        BrokerMessage::Dial(DialRequest((public_key, addr))),
+                    ))
+                    .await?;
+
+                Ok(true)
+            }
+            None => {
+                info!(
+                    "No address known for {}; replicating via {:?} on next connection",
+                    ssb_id, strategy
+                );
+
+                Ok(false)
+            }
+        }
+    }
+
+    /// Resolve the given address and attempt a TCP connection, classifying
+    /// any failure as a dial outcome.
+    async fn dial(peer_addr: &str) -> std::result::Result<TcpStream, DialOutcome> {
+        let addrs: Vec<SocketAddr> = peer_addr
+            .to_socket_addrs()
+            .await
+            .map_err(|err| DialOutcome::AddressResolutionFailed(err.to_string()))?
+            .collect();
+
+        let stream = TcpStream::connect(&addrs[..]).await.map_err(|err| {
+            if err.kind() == std::io::ErrorKind::ConnectionRefused {
+                DialOutcome::ConnectionRefused
+            } else {
+                DialOutcome::ConnectionFailed(err.to_string())
+            }
+        })?;
+
+        // The connection remains usable with the default TCP options.
+        if let Err(err) = TransportConfig::current().configure(&stream) {
+            warn!("Failed to configure connection to {}: {}", peer_addr, err);
         }
+
+        Ok(stream)
     }
 
-    /// Return a handle for the connection event message loop.
-    pub fn take_msgloop(&mut self) -> JoinHandle<()> {
-        self.msgloop.take().unwrap()
+    /// Return a handle for the connection event message loop, or `None` if
+    /// it has already been taken by a running node.
+    pub fn take_msgloop(&mut self) -> Option<JoinHandle<()>> {
+        self.msgloop.take()
     }
 
     /// Register a new connection with the connection manager.
@@ -181,39 +655,6 @@ impl ConnectionManager {
         self.last_connection_id
     }
 
-    /// Handle a LAN discovery event.
-    async fn handle_lan_discovery(
-        tcp_connection: TcpConnection,
-        identity: OwnedIdentity,
-        selective_replication: EnableSelectiveReplication,
-    ) -> Result<()> {
-        // First ensure there is no active or in-progress connection
-        // with the given peer.
-        if let TcpConnection::Dial { public_key, .. } = &tcp_connection {
-            if !CONNECTION_MANAGER
-                .read()
-                .await
-                .contains_connected_peer(public_key)
-                && !CONNECTION_MANAGER
-                    .read()
-                    .await
-                    .contains_connecting_peer(public_key)
-            {
-                // Spawn a connection actor with the given connection parameters.
-                //
-                // The connection actor is responsible for initiating the
-                // outbound TCP connection.
-                Broker::spawn(connection::actor(
-                    tcp_connection,
-                    identity,
-                    selective_replication,
-                ));
-            }
-        }
-
-        Ok(())
-    }
-
     /// Handle a staging event.
     async fn handle_staging(
         connection_data: ConnectionData,
@@ -262,42 +703,48 @@ impl ConnectionManager {
     ) -> Result<()> {
         if let Some(peer_public_key) = &connection_data.peer_public_key {
             if let Some(peer_addr) = &connection_data.peer_addr {
-                CONNECTION_MANAGER
-                    .write()
-                    .await
-                    .insert_connecting_peer(*peer_public_key, connection_data.id);
-
                 // Attempt connection.
-                if let Ok(stream) = TcpStream::connect(&peer_addr).await {
-                    connection_data.stream = Some(stream);
-
-                    // Send 'handshaking' connection event message via the broker.
-                    ch_broker
-                        .send(BrokerEvent::new(
-                            Destination::Broadcast,
-                            BrokerMessage::Connection(ConnectionEvent::Handshaking(
-                                connection_data,
-                                identity,
-                                selective_replication,
-                                false, // Dialer.
-                            )),
-                        ))
-                        .await?;
-                } else {
-                    // If the connection attempt fails, send 'disconnecting'
-                    // connection event message via the broker.
-                    //
-                    // This removes the connection from the list of in-progress
-                    // attempts, ensuring that future connection attempts to
-                    // this peer are not blocked.
-                    ch_broker
-                        .send(BrokerEvent::new(
-                            Destination::Broadcast,
-                            BrokerMessage::Connection(ConnectionEvent::Disconnecting(
-                                connection_data,
-                            )),
-                        ))
-                        .await?;
+                match ConnectionManager::dial(peer_addr).await {
+                    Ok(stream) => {
+                        connection_data.stream = Some(stream);
+
+                        // Send 'handshaking' connection event message via the broker.
+                        ch_broker
+                            .send(BrokerEvent::new(
+                                Destination::Broadcast,
+                                BrokerMessage::Connection(ConnectionEvent::Handshaking(
+                                    connection_data,
+                                    identity,
+                                    selective_replication,
+                                    false, // Dialer.
+                                )),
+                            ))
+                            .await?;
+                    }
+                    Err(outcome) => {
+                        debug!("Failed to connect to {}: {:?}", peer_addr, outcome);
+
+                        CONNECTION_MANAGER.write().await.record_dial_attempt(
+                            peer_public_key,
+                            peer_addr,
+                            outcome,
+                        );
+
+                        // If the connection attempt fails, send 'disconnecting'
+                        // connection event message via the broker.
+                        //
+                        // This closes the connection attempt, ensuring that
+                        // future connection attempts to this peer are not
+                        // blocked.
+                        ch_broker
+                            .send(BrokerEvent::new(
+                                Destination::Broadcast,
+                                BrokerMessage::Connection(ConnectionEvent::Disconnecting(
+                                    connection_data,
+                                )),
+                            ))
+                            .await?;
+                    }
                 }
             }
         }
@@ -321,13 +768,65 @@ impl ConnectionManager {
         let mut stream = connection_data.stream.clone().ok_or(Error::OptionIsNone)?;
 
         // Attempt a secret handshake as server or client.
-        let handshake = if listener {
+        let start = Instant::now();
+        let (role, result) = if listener {
             debug!("Attempting secret handshake as server...");
-            handshake_server(&mut stream, network_key, pk, sk).await?
+            let result = handshake::server(&mut stream, network_key, pk, sk).await;
+
+            (HandshakeRole::Server, result)
         } else {
             let peer_public_key = connection_data.peer_public_key.ok_or(Error::OptionIsNone)?;
+            let peer_addr = connection_data.peer_addr.clone().unwrap_or_default();
             debug!("Attempting secret handshake as client...");
-            handshake_client(&mut stream, network_key, pk, sk, peer_public_key).await?
+
+            let result = handshake::client(&mut stream, network_key, pk, sk, peer_public_key).await;
+
+            // Record the outcome of the handshake in the dial history.
+            let outcome = match &result {
+                Ok(_) => DialOutcome::Connected,
+                Err(err) => DialOutcome::HandshakeFailed(err.to_owned()),
+            };
+            CONNECTION_MANAGER.write().await.record_dial_attempt(
+                &peer_public_key,
+                &peer_addr,
+                outcome,
+            );
+
+            (HandshakeRole::Client, result)
+        };
+
+        // Record the metrics of the handshake.
+        let peer_public_key = match &result {
+            Ok(handshake) => Some(handshake.peer_pk),
+            Err(_) => connection_data.peer_public_key,
+        };
+        CONNECTION_MANAGER
+            .write()
+            .await
+            .record_handshake(HandshakeRecord {
+                timestamp: now_millis(),
+                connection_id: connection_data.id,
+                peer: peer_public_key.as_ref().map(Self::ssb_id),
+                addr: connection_data.peer_addr.clone(),
+                role,
+                duration_ms: start.elapsed().as_millis() as u64,
+                error: result.as_ref().err().cloned(),
+            });
+
+        let handshake = match result {
+            Ok(handshake) => handshake,
+            Err(err) => {
+                // Close the connection, ensuring that future connection
+                // attempts to this peer are not blocked.
+                ch_broker
+                    .send(BrokerEvent::new(
+                        Destination::Broadcast,
+                        BrokerMessage::Connection(ConnectionEvent::Disconnecting(connection_data)),
+                    ))
+                    .await?;
+
+                return Err(err.into());
+            }
         };
 
         debug!("Secret handshake complete");
@@ -358,19 +857,18 @@ impl ConnectionManager {
         listener: IsListener,
         mut ch_broker: ChBrokerSend,
     ) -> Result<()> {
-        // Add the peer to the list of connected peers.
         if let Some(public_key) = connection_data.peer_public_key {
             info!("💃 connected to peer {}", public_key.to_ssb_id());
+        }
 
+        // Keep the stream so that the connection can be closed when a new
+        // session is requested (see `replicate_now`).
+        if let Some(stream) = &connection_data.stream {
             CONNECTION_MANAGER
                 .write()
                 .await
-                .remove_connecting_peer(public_key, connection_data.id);
-
-            CONNECTION_MANAGER
-                .write()
-                .await
-                .insert_connected_peer(public_key, connection_data.id);
+                .streams
+                .insert(connection_data.id, stream.clone());
         }
 
         // Send 'replicate' connection event message via the broker.
@@ -395,20 +893,60 @@ impl ConnectionManager {
         listener: IsListener,
         mut ch_broker: ChBrokerSend,
     ) -> Result<()> {
-        let peer_public_key = connection_data
-            .peer_public_key
+        let peer_id = connection_data.peer_public_key.ok_or(Error::OptionIsNone)?;
+        let peer_public_key = peer_id.to_ssb_id();
+
+        // A strategy requested via `replicate_now` takes precedence.
+        let forced_strategy = CONNECTION_MANAGER
+            .write()
+            .await
+            .forced_strategies
+            .remove(&Self::ssb_id(&peer_id));
+        if let Some(strategy) = forced_strategy {
+            let event = match strategy {
+                ReplicationStrategy::Ebt => {
+                    ConnectionEvent::ReplicatingEbt(connection_data, listener)
+                }
+                ReplicationStrategy::Classic => {
+                    ConnectionEvent::ReplicatingClassic(connection_data)
+                }
+            };
+            ch_broker
+                .send(BrokerEvent::new(
+                    Destination::Broadcast,
+                    BrokerMessage::Connection(event),
+                ))
+                .await?;
+
+            return Ok(());
+        }
+
+        let is_trusted = PEERS_TO_REPLICATE
+            .get()
             .ok_or(Error::OptionIsNone)?
-            .to_ssb_id();
-
-        // Shutdown the connection if the peer is not in the list of peers
-        // to be replicated, unless replication is set to nonselective.
-        // This ensures we do not replicate with unknown peers.
-        if selective_replication
-            & !PEERS_TO_REPLICATE
-                .get()
-                .ok_or(Error::OptionIsNone)?
-                .contains_key(&peer_public_key)
-        {
+            .contains_key(&peer_public_key);
+        let is_room = CONNECTION_MANAGER
+            .read()
+            .await
+            .room(&peer_public_key)
+            .is_some();
+
+        // Rooms do not replicate feeds, so the local node announces itself
+        // as an attendant instead of requesting an EBT session. Unknown
+        // peers which were dialed (eg. after consuming a room invite or via
+        // peer exchange) are first probed for room support. Both take place
+        // in a classic MUXRPC session.
+        if is_room || (!is_trusted && !listener) {
+            ch_broker
+                .send(BrokerEvent::new(
+                    Destination::Broadcast,
+                    BrokerMessage::Connection(ConnectionEvent::ReplicatingClassic(connection_data)),
+                ))
+                .await?;
+        } else if selective_replication & !is_trusted {
+            // Shutdown the connection if the peer is not in the list of peers
+            // to be replicated, unless replication is set to nonselective.
+            // This ensures we do not replicate with unknown peers.
             info!(
                 "peer {} is not in replication list and selective replication is enabled; dropping connection",
                 peer_public_key
@@ -421,6 +959,20 @@ impl ConnectionManager {
                     BrokerMessage::Connection(ConnectionEvent::Disconnecting(connection_data)),
                 ))
                 .await?;
+        } else if capabilities::is_unsupported(&peer_public_key, Capability::Ebt).await {
+            // Skip the EBT session request if the peer is known not to
+            // support EBT.
+            debug!(
+                "peer {} does not support EBT; replicating via classic replication",
+                peer_public_key
+            );
+
+            ch_broker
+                .send(BrokerEvent::new(
+                    Destination::Broadcast,
+                    BrokerMessage::Connection(ConnectionEvent::ReplicatingClassic(connection_data)),
+                ))
+                .await?;
         } else {
             // Send 'replicating ebt' connection event message via the broker.
             //
@@ -445,7 +997,11 @@ impl ConnectionManager {
         debug!("Attempting classic replication with peer...");
 
         // Spawn the classic replication actor and await the result.
-        Broker::spawn(crate::actors::replication::classic::actor(connection_data)).await;
+        Broker::spawn(
+            "replication",
+            crate::actors::replication::classic::actor(connection_data),
+        )
+        .await;
 
         Ok(())
     }
@@ -502,16 +1058,20 @@ impl ConnectionManager {
 
     /// Handle a disconnected event.
     async fn handle_disconnected(connection_data: ConnectionData) -> Result<()> {
-        if let Some(public_key) = connection_data.peer_public_key {
-            CONNECTION_MANAGER
-                .write()
-                .await
-                .remove_connected_peer(public_key, connection_data.id);
+        CONNECTION_MANAGER
+            .write()
+            .await
+            .streams
+            .remove(&connection_data.id);
 
+        if let Some(public_key) = connection_data.peer_public_key {
+            // The attendants of a room are no longer known once the
+            // connection with the room is closed.
             CONNECTION_MANAGER
                 .write()
                 .await
-                .remove_connecting_peer(public_key, connection_data.id);
+                .attendants
+                .remove(&Self::ssb_id(&public_key));
         }
 
         Ok(())
@@ -525,7 +1085,7 @@ impl ConnectionManager {
         // Register the connection manager actor with the broker.
         let ActorEndpoint {
             ch_terminate,
-            ch_broker,
+            mut ch_broker,
             ch_msg,
             actor_id: _,
             ..
@@ -551,16 +1111,11 @@ impl ConnectionManager {
                 },
                 msg = broker_msg_ch.next().fuse() => {
                     if let Some(BrokerMessage::Connection(event)) = msg {
+                        if let Err(err) = ConnectionManager::track(&event, &mut ch_broker).await {
+                            error!("Error while tracking connection state: {}", err)
+                        }
+
                         match event {
-                            ConnectionEvent::LanDiscovery(tcp_connection, identity, selective_replication) => {
-                                if let Err(err) = ConnectionManager::handle_lan_discovery(
-                                    tcp_connection,
-                                    identity,
-                                    selective_replication,
-                                ).await {
-                                    error!("Error while handling 'lan discovery' event: {}", err)
-                                }
-                            }
                             ConnectionEvent::Staging(connection_data, identity, selective_replication,) => {
                                 trace!(target: "connection-manager", "Staging: {connection_data}");
 
@@ -707,8 +1262,98 @@ mod test {
         let msgloop = &connection_manager.read().await.msgloop;
         assert!(msgloop.is_some());
 
-        let connected_peers = &connection_manager.read().await.connected_peers;
-        assert!(connected_peers.is_empty());
+        let connections = connection_manager.read().await.connections();
+        assert!(connections.is_empty());
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_dial_history() -> Result<()> {
+        let connection_manager = instantiate_new_connection_manager();
+
+        let keypair = SecretConfig::create().to_owned_identity().unwrap();
+
+        // A peer without any dial attempts has an empty history.
+        let history = connection_manager.read().await.dial_history(&keypair.id);
+        assert!(history.is_empty());
+
+        connection_manager.write().await.record_dial_attempt(
+            &keypair.pk,
+            "127.0.0.1:8008",
+            DialOutcome::ConnectionRefused,
+        );
+        connection_manager.write().await.record_dial_attempt(
+            &keypair.pk,
+            "127.0.0.1:8008",
+            DialOutcome::Connected,
+        );
+
+        let history = connection_manager.read().await.dial_history(&keypair.id);
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].outcome, DialOutcome::ConnectionRefused);
+        assert_eq!(history[1].outcome, DialOutcome::Connected);
+
+        // The peer is reachable, since the most recent attempt succeeded.
+        assert_eq!(
+            connection_manager.read().await.reachable_peers(),
+            vec![(keypair.id.to_owned(), "127.0.0.1:8008".to_string())]
+        );
+
+        // Ensure the history is bounded and retains the newest attempts.
+        for _ in 0..DIAL_HISTORY_CAPACITY {
+            connection_manager.write().await.record_dial_attempt(
+                &keypair.pk,
+                "127.0.0.1:8008",
+                DialOutcome::HandshakeFailed(HandshakeError::WrongTargetKey),
+            );
+        }
+
+        let history = connection_manager.read().await.dial_history(&keypair.id);
+        assert_eq!(history.len(), DIAL_HISTORY_CAPACITY);
+        assert!(history.iter().all(|attempt| attempt.outcome
+            == DialOutcome::HandshakeFailed(HandshakeError::WrongTargetKey)));
+        assert!(connection_manager.read().await.reachable_peers().is_empty());
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_peer_addr() -> Result<()> {
+        let connection_manager = instantiate_new_connection_manager();
+
+        let keypair = SecretConfig::create().to_owned_identity().unwrap();
+        assert_eq!(connection_manager.read().await.peer_addr(&keypair.id), None);
+
+        // Only addresses at which the peer was reached are used.
+        connection_manager.write().await.record_dial_attempt(
+            &keypair.pk,
+            "127.0.0.1:8008",
+            DialOutcome::Connected,
+        );
+        connection_manager.write().await.record_dial_attempt(
+            &keypair.pk,
+            "127.0.0.1:8009",
+            DialOutcome::ConnectionRefused,
+        );
+        assert_eq!(
+            connection_manager.read().await.peer_addr(&keypair.id),
+            Some("127.0.0.1:8008".to_string())
+        );
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_dial_refused() -> Result<()> {
+        // Bind a listener to obtain a free port, then drop it so that the
+        // connection attempt is refused.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?.to_string();
+        drop(listener);
+
+        let outcome = ConnectionManager::dial(&addr).await.err();
+        assert_eq!(outcome, Some(DialOutcome::ConnectionRefused));
 
         Ok(())
     }
@@ -746,48 +1391,159 @@ mod test {
         let keypair_1 = SecretConfig::create().to_owned_identity().unwrap();
         let keypair_2 = SecretConfig::create().to_owned_identity().unwrap();
 
-        // Insert a new connected peer.
+        let connection_1 = ConnectionData {
+            id: 1,
+            peer_addr: Some("127.0.0.1:8008".to_string()),
+            peer_public_key: Some(keypair_1.pk),
+            ..ConnectionData::default()
+        };
+
+        // Dial the first peer.
         connection_manager
             .write()
             .await
-            .insert_connected_peer(keypair_1.pk, 1);
-
-        // Query the list of connected peers.
-        let query_result = connection_manager
+            .transition(&connection_1, false, ConnectionState::Dialing);
+        assert!(connection_manager
+            .read()
+            .await
+            .contains_connecting_peer(&keypair_1.pk));
+        assert!(!connection_manager
             .read()
             .await
-            .contains_connected_peer(&keypair_1.pk);
-        assert_eq!(query_result, true);
+            .contains_connected_peer(&keypair_1.pk));
 
-        // Insert the a second connected peer.
-        connection_manager
-            .write()
+        for state in &[ConnectionState::Handshaking, ConnectionState::Connected] {
+            connection_manager
+                .write()
+                .await
+                .transition(&connection_1, false, *state);
+        }
+        assert!(connection_manager
+            .read()
             .await
-            .insert_connected_peer(keypair_2.pk, 2);
+            .contains_connected_peer(&keypair_1.pk));
+
+        // Accept a connection from the second peer, which is only identified
+        // by the handshake.
+        let mut connection_2 = ConnectionData {
+            id: 2,
+            ..ConnectionData::default()
+        };
+        connection_manager.write().await.transition(
+            &connection_2,
+            true,
+            ConnectionState::Handshaking,
+        );
+        connection_2.peer_public_key = Some(keypair_2.pk);
+        connection_manager.write().await.transition(
+            &connection_2,
+            true,
+            ConnectionState::Connected,
+        );
 
         // Count the active connections.
         let connections = connection_manager.read().await._count_connections();
         assert_eq!(connections, 2);
 
-        // Remove the first peer from the list of connected peers.
+        let connections = connection_manager.read().await.connections();
+        assert_eq!(
+            connections[1].peer,
+            Some(ConnectionManager::ssb_id(&keypair_2.pk))
+        );
+        assert!(connections[1].inbound);
+
+        // Drain and close the first connection.
+        connection_manager.write().await.transition(
+            &connection_1,
+            false,
+            ConnectionState::Draining,
+        );
+        assert!(!connection_manager
+            .read()
+            .await
+            .contains_connected_peer(&keypair_1.pk));
         connection_manager
             .write()
             .await
-            .remove_connected_peer(keypair_1.pk, 1);
+            .transition(&connection_1, false, ConnectionState::Closed);
 
         // Count the active connections.
         let connections = connection_manager.read().await._count_connections();
         assert_eq!(connections, 1);
 
-        // Remove the second peer from the list of connected peers.
+        // Close the second connection.
         connection_manager
             .write()
             .await
-            .remove_connected_peer(keypair_2.pk, 2);
+            .transition(&connection_2, true, ConnectionState::Closed);
 
         // Count the active connections.
         let connections = connection_manager.read().await._count_connections();
         assert_eq!(connections, 0);
+        assert!(connection_manager.read().await.connections().is_empty());
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_invalid_transitions() -> Result<()> {
+        let connection_manager = instantiate_new_connection_manager();
+
+        let keypair = SecretConfig::create().to_owned_identity().unwrap();
+        let connection_data = ConnectionData {
+            id: 1,
+            peer_public_key: Some(keypair.pk),
+            ..ConnectionData::default()
+        };
+
+        // An untracked connection only enters the state machine by dialing
+        // or handshaking.
+        let transition = connection_manager.write().await.transition(
+            &connection_data,
+            false,
+            ConnectionState::Draining,
+        );
+        assert_eq!(transition, None);
+        assert!(connection_manager.read().await.connections().is_empty());
+
+        let transition = connection_manager.write().await.transition(
+            &connection_data,
+            false,
+            ConnectionState::Dialing,
+        );
+        assert_eq!(
+            transition.map(|transition| (transition.from, transition.to)),
+            Some((None, ConnectionState::Dialing))
+        );
+
+        // A connection cannot replicate before the handshake.
+        let transition = connection_manager.write().await.transition(
+            &connection_data,
+            false,
+            ConnectionState::Replicating(ReplicationStrategy::Ebt),
+        );
+        assert_eq!(transition, None);
+        assert_eq!(
+            connection_manager.read().await.connections()[0].state,
+            ConnectionState::Dialing
+        );
+
+        // A failed dial drains the connection, once.
+        let transition = connection_manager.write().await.transition(
+            &connection_data,
+            false,
+            ConnectionState::Draining,
+        );
+        assert_eq!(
+            transition.map(|transition| (transition.from, transition.to)),
+            Some((Some(ConnectionState::Dialing), ConnectionState::Draining))
+        );
+        let transition = connection_manager.write().await.transition(
+            &connection_data,
+            false,
+            ConnectionState::Draining,
+        );
+        assert_eq!(transition, None);
 
         Ok(())
     }
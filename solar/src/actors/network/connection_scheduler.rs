@@ -16,7 +16,29 @@
 //!
 //! The success or failure of each dial attempt is determined by listening to connection events from
 //! the connection manager. This allows peers to be moved between queues when required.
-use std::{collections::VecDeque, fmt::Display, time::Duration};
+//!
+//! Peers discovered on the LAN form an ephemeral tier: they are queued and dialed like any other
+//! peer, but each announcement only keeps a LAN peer in the scheduler for 3 minutes. Once a LAN
+//! peer stops announcing itself, it is dropped from the queues instead of being dialed again.
+//!
+//! Peers in the replication set which join a room attended by the local peer are dialed next,
+//! since they have just been seen online. Attendants without a configured address (dialed at the
+//! address of an earlier connection) form another ephemeral tier: they are dropped from the queues
+//! once they have left every room (or the connection with the room has been closed).
+//!
+//! Peers with which replication failed (for example, because they sent an invalid message) are
+//! penalized: they are not dialed for 10 minutes, so that a failing peer is not redialed in a
+//! tight loop. A penalized peer is pushed to the back of the "lazy" queue when its turn comes.
+//!
+//! Peers are also ranked by the score of their reputation: the initial peers are dialed from the
+//! highest to the lowest score, and a peer with a low score (one which sends invalid messages or
+//! fails replication) is pushed to the back of the "lazy" queue even after a successful
+//! connection.
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt::Display,
+    time::{Duration, Instant},
+};
 
 use async_std::stream;
 use futures::{select_biased, stream::StreamExt, FutureExt, SinkExt};
@@ -24,8 +46,12 @@ use kuska_ssb::crypto::{ed25519::PublicKey, ToSsbId};
 use log::debug;
 
 use crate::{
-    actors::network::connection_manager::{ConnectionEvent, CONNECTION_MANAGER},
+    actors::network::{
+        connection_manager::{ConnectionEvent, CONNECTION_MANAGER},
+        reputation,
+    },
     broker::{ActorEndpoint, BrokerEvent, BrokerMessage, Destination, BROKER},
+    util::prefixed,
     Result,
 };
 
@@ -38,12 +64,7 @@ impl Display for DialRequest {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let (peer_public_key, peer_addr) = match &self {
             DialRequest((key, addr)) => {
-                let ssb_id = key.to_ssb_id();
-                let peer_public_key = if ssb_id.starts_with('@') {
-                    ssb_id
-                } else {
-                    format!("@{}", ssb_id)
-                };
+                let peer_public_key = prefixed(&key.to_ssb_id());
 
                 let peer_addr = addr.to_string();
 
@@ -55,6 +76,44 @@ impl Display for DialRequest {
     }
 }
 
+/// A request to add the peer identified by the given public key and address
+/// to the scheduler, for peers discovered while the node is running.
+#[derive(Debug, Clone)]
+pub struct ScheduleRequest(pub (PublicKey, String));
+
+/// A request to add the peer identified by the given public key and address
+/// to the scheduler (or to keep it there) as a peer discovered on the LAN.
+#[derive(Debug, Clone)]
+pub struct LanScheduleRequest(pub (PublicKey, String));
+
+/// A request to refrain from dialing the peer identified by the given public
+/// key for a while, after replication with it failed.
+#[derive(Debug, Clone)]
+pub struct PenaltyRequest(pub PublicKey);
+
+/// A change in the attendants of a room, for attendants in the replication
+/// set.
+#[derive(Debug, Clone)]
+pub enum AttendantEvent {
+    /// The peer (public key and address) joined the room with the given SSB
+    /// ID. An ephemeral peer is only scheduled while it attends a room.
+    Joined {
+        room: String,
+        peer: (PublicKey, String),
+        ephemeral: bool,
+    },
+    /// The peer with the given public key left the room with the given SSB
+    /// ID.
+    Left { room: String, public_key: PublicKey },
+}
+
+/// Period after which a peer discovered on the LAN is dropped from the
+/// scheduler, unless it has been announced again.
+const LAN_PEER_TTL: Duration = Duration::from_secs(180);
+
+/// Period during which a peer with which replication failed is not dialed.
+const REPLICATION_ERROR_PENALTY: Duration = Duration::from_secs(600);
+
 #[derive(Debug)]
 struct ConnectionScheduler {
     /// Peers with whom the last connection attempt was successful.
@@ -63,6 +122,17 @@ struct ConnectionScheduler {
     /// Peers with whom the last connection attempt was unsuccessful.
     /// These peers are dialed less frequently than the eager peers.
     lazy_peers: VecDeque<(PublicKey, String)>,
+    /// Peers discovered on the LAN, along with the time at which each was
+    /// last announced. The set of peers on a LAN is small, so the entries
+    /// of expired peers are kept in case they are announced again.
+    lan_peers: HashMap<(PublicKey, String), Instant>,
+    /// Ephemeral peers scheduled because they attend a room, along with the
+    /// SSB IDs of the rooms they attend. Entries of peers which have left
+    /// every room are kept (with no rooms) so that they are not queued again.
+    room_peers: HashMap<(PublicKey, String), HashSet<String>>,
+    /// Peers with which replication failed, along with the time until which
+    /// they are not dialed.
+    penalties: HashMap<PublicKey, Instant>,
     /// The interval in seconds between dial attempts for eager peers.
     /// Defaults to 5 seconds.
     eager_interval: Duration,
@@ -76,6 +146,9 @@ impl Default for ConnectionScheduler {
         Self {
             eager_peers: VecDeque::new(),
             lazy_peers: VecDeque::new(),
+            lan_peers: HashMap::new(),
+            room_peers: HashMap::new(),
+            penalties: HashMap::new(),
             eager_interval: Duration::from_secs(5),
             lazy_interval: Duration::from_secs(61),
         }
@@ -94,6 +167,131 @@ impl ConnectionScheduler {
         }
     }
 
+    /// Add the given peers to the scheduler, in order of the given scores
+    /// from the highest to the lowest.
+    fn add_ranked_peers(
+        &mut self,
+        mut peers: Vec<(PublicKey, String)>,
+        score: impl Fn(&PublicKey) -> f64,
+    ) {
+        peers.sort_by(|(a, _), (b, _)| score(b).total_cmp(&score(a)));
+        for peer in peers {
+            self.add_peer(peer)
+        }
+    }
+
+    /// Add a peer discovered on the LAN to the scheduler at the given time,
+    /// or extend its stay in the scheduler if it has already been added.
+    fn add_lan_peer(&mut self, peer: (PublicKey, String), now: Instant) {
+        self.lan_peers.insert(peer.to_owned(), now);
+        self.add_peer(peer)
+    }
+
+    /// Query whether the given peer was discovered on the LAN and has not
+    /// been announced within the LAN peer TTL at the given time.
+    fn is_expired(&self, peer: &(PublicKey, String), now: Instant) -> bool {
+        self.lan_peers.get(peer).map_or(false, |announced| {
+            now.saturating_duration_since(*announced) >= LAN_PEER_TTL
+        })
+    }
+
+    /// Query whether the given peer was scheduled because it attended a room
+    /// and has since left every room.
+    fn has_left(&self, peer: &(PublicKey, String)) -> bool {
+        self.room_peers
+            .get(peer)
+            .map_or(false, |rooms| rooms.is_empty())
+    }
+
+    /// Query whether the given peer belongs to an ephemeral tier and is no
+    /// longer available at the given time. Stale peers are neither dialed
+    /// nor queued again.
+    fn is_stale(&self, peer: &(PublicKey, String), now: Instant) -> bool {
+        self.is_expired(peer, now) || self.has_left(peer)
+    }
+
+    /// Penalize the peer with the given public key at the given time, so
+    /// that it is not dialed until the penalty has expired.
+    fn penalize(&mut self, public_key: PublicKey, now: Instant) {
+        // Forget the penalties which have expired.
+        self.penalties.retain(|_, until| *until > now);
+        self.penalties
+            .insert(public_key, now + REPLICATION_ERROR_PENALTY);
+    }
+
+    /// Query whether the peer with the given public key is penalized at the
+    /// given time.
+    fn is_penalized(&self, public_key: &PublicKey, now: Instant) -> bool {
+        self.penalties
+            .get(public_key)
+            .map_or(false, |until| *until > now)
+    }
+
+    /// Push the given peer to the back of the queue of lazy peers, unless it
+    /// is already queued there.
+    fn defer_peer(&mut self, peer: (PublicKey, String)) {
+        if !self.lazy_peers.contains(&peer) {
+            self.lazy_peers.push_back(peer)
+        }
+    }
+
+    /// Move the given peer to the front of the queue of eager peers, so that
+    /// it is dialed next.
+    fn prioritize_peer(&mut self, peer: (PublicKey, String)) {
+        self.eager_peers.retain(|queued| *queued != peer);
+        self.lazy_peers.retain(|queued| *queued != peer);
+        self.eager_peers.push_front(peer)
+    }
+
+    /// Handle a change in the attendants of a room.
+    fn handle_attendant_event(&mut self, event: AttendantEvent) {
+        match event {
+            AttendantEvent::Joined {
+                room,
+                peer,
+                ephemeral,
+            } => {
+                if ephemeral {
+                    self.room_peers
+                        .entry(peer.to_owned())
+                        .or_default()
+                        .insert(room);
+                }
+                self.prioritize_peer(peer)
+            }
+            AttendantEvent::Left { room, public_key } => {
+                for (peer, rooms) in self.room_peers.iter_mut() {
+                    if peer.0 == public_key {
+                        rooms.remove(&room);
+                    }
+                }
+                self.drop_departed_peers()
+            }
+        }
+    }
+
+    /// Handle the closing of the connection with the room with the given
+    /// SSB ID: its attendants are no longer known to attend it.
+    fn handle_room_closed(&mut self, room: &str) {
+        for rooms in self.room_peers.values_mut() {
+            rooms.remove(room);
+        }
+        self.drop_departed_peers()
+    }
+
+    /// Remove the peers which have left every room from the queues.
+    fn drop_departed_peers(&mut self) {
+        let departed: Vec<(PublicKey, String)> = self
+            .room_peers
+            .iter()
+            .filter(|(_, rooms)| rooms.is_empty())
+            .map(|(peer, _)| peer.to_owned())
+            .collect();
+
+        self.eager_peers.retain(|peer| !departed.contains(peer));
+        self.lazy_peers.retain(|peer| !departed.contains(peer));
+    }
+
     /// Remove a peer from the scheduler, checking both the eager and lazy
     /// queues.
     fn _remove_peer(&mut self, peer: (PublicKey, String)) {
@@ -139,12 +337,13 @@ pub async fn actor(peers: Vec<(PublicKey, String)>) -> Result<()> {
     // Create a new connection scheduler.
     let mut scheduler = ConnectionScheduler::default();
 
-    // Populate the scheduler with the peers to be dialed.
+    // Populate the scheduler with the peers to be dialed, the peers with
+    // the best reputation first.
     // These peers are added to the queue of eager peers if they have not
     // previously been added to the scheduler.
-    for peer in peers {
-        scheduler.add_peer(peer)
-    }
+    scheduler.add_ranked_peers(peers, |public_key| {
+        reputation::score_of(&public_key.to_ssb_id())
+    });
 
     // Create the tickers (aka. metronomes) which will emit messages at
     // the predetermined interval. These tickers control the rates at which
@@ -172,9 +371,20 @@ pub async fn actor(peers: Vec<(PublicKey, String)>) -> Result<()> {
                 if let Some(_tick) = eager_tick {
                     // Pop a peer from the queue of eager peers.
                     if let Some((public_key, addr)) = scheduler.eager_peers.pop_front() {
+                        // Drop the peer if it belongs to an ephemeral tier and
+                        // is no longer available.
+                        if scheduler.is_stale(&(public_key, addr.to_owned()), Instant::now()) {
+                            debug!("Dropping stale peer {}", addr)
+                        }
+                        // Defer the peer if replication with it has recently
+                        // failed.
+                        else if scheduler.is_penalized(&public_key, Instant::now()) {
+                            debug!("Deferring penalized peer {}", addr);
+                            scheduler.defer_peer((public_key, addr))
+                        }
                         // Check if we're already connected to this peer. If so,
                         // push them to the back of the eager queue.
-                        if CONNECTION_MANAGER.read().await.contains_connected_peer(&public_key) {
+                        else if CONNECTION_MANAGER.read().await.contains_connected_peer(&public_key) {
                             scheduler.eager_peers.push_back((public_key, addr))
                         } else {
                             // Otherwise, send a dial request to the dialer.
@@ -190,9 +400,20 @@ pub async fn actor(peers: Vec<(PublicKey, String)>) -> Result<()> {
                 if let Some(_tick) = lazy_tick {
                     // Pop a peer from the queue of lazy peers.
                     if let Some((public_key, addr)) = scheduler.lazy_peers.pop_front() {
+                        // Drop the peer if it belongs to an ephemeral tier and
+                        // is no longer available.
+                        if scheduler.is_stale(&(public_key, addr.to_owned()), Instant::now()) {
+                            debug!("Dropping stale peer {}", addr)
+                        }
+                        // Defer the peer if replication with it has recently
+                        // failed.
+                        else if scheduler.is_penalized(&public_key, Instant::now()) {
+                            debug!("Deferring penalized peer {}", addr);
+                            scheduler.defer_peer((public_key, addr))
+                        }
                         // Check if we're already connected to this peer. If so,
                         // push them to the back of the eager queue.
-                        if CONNECTION_MANAGER.read().await.contains_connected_peer(&public_key) {
+                        else if CONNECTION_MANAGER.read().await.contains_connected_peer(&public_key) {
                             scheduler.eager_peers.push_back((public_key, addr))
                         } else {
                             // Otherwise, send a dial request to the dialer.
@@ -203,32 +424,61 @@ pub async fn actor(peers: Vec<(PublicKey, String)>) -> Result<()> {
                     }
                 }
             },
-            // Received a message from the connection manager via the broker.
+            // Received a message from the connection manager (or a request
+            // to schedule a peer) via the broker.
             msg = broker_msg_ch.next().fuse() => {
-                if let Some(BrokerMessage::Connection(event)) = msg {
+                if let Some(BrokerMessage::Schedule(ScheduleRequest(peer))) = msg {
+                    // Add the peer to the queue of eager peers.
+                    scheduler.add_peer(peer)
+                } else if let Some(BrokerMessage::ScheduleLan(LanScheduleRequest(peer))) = msg {
+                    // Add the peer to the queue of eager peers (if it is not
+                    // already queued) and record the announcement.
+                    scheduler.add_lan_peer(peer, Instant::now())
+                } else if let Some(BrokerMessage::Penalize(PenaltyRequest(public_key))) = msg {
+                    debug!("Penalizing peer {}", public_key.to_ssb_id());
+                    scheduler.penalize(public_key, Instant::now())
+                } else if let Some(BrokerMessage::Attendant(event)) = msg {
+                    scheduler.handle_attendant_event(event)
+                } else if let Some(BrokerMessage::Connection(event)) = msg {
                     match event {
                         ConnectionEvent::Replicate(data, _selective_replication, _listener) => {
                             // This connection was "successful".
-                            // Push the peer to the back of the eager queue.
+                            // Push the peer to the back of the eager queue,
+                            // unless its reputation is poor.
                             if let Some(public_key) = data.peer_public_key {
                                 if let Some(addr) = &data.peer_addr {
-                                    // Only push if the peer is not already in the queue.
-                                    if !scheduler.eager_peers.contains(&(public_key, addr.to_string())) {
-                                        scheduler.eager_peers.push_back((public_key, addr.to_owned()))
+                                    // Only push if the peer is not already in the queue
+                                    // (or is stale).
+                                    let peer = (public_key, addr.to_string());
+                                    if !scheduler.is_stale(&peer, Instant::now()) {
+                                        if reputation::score_of(&public_key.to_ssb_id()) < reputation::LOW_SCORE {
+                                            scheduler.defer_peer(peer)
+                                        } else if !scheduler.eager_peers.contains(&peer) {
+                                            scheduler.eager_peers.push_back(peer)
+                                        }
                                     }
                                 }
                             }
                         }
                         ConnectionEvent::Disconnected(data) => {
+                            // The attendants of a room are no longer known
+                            // once the connection with the room is closed.
+                            if let Some(public_key) = data.peer_public_key {
+                                let room = prefixed(&public_key.to_ssb_id());
+                                scheduler.handle_room_closed(&room)
+                            }
+
                             // This connection may or may not have been "successful".
                             // If it was successful (ie. replication took place) then
                             // the peer should have already been pushed back to the eager
                             // queue. If not, push the peer to the back of the lazy queue.
                             if let Some(public_key) = data.peer_public_key {
                                 if let Some(addr) = &data.peer_addr {
-                                    // Only push if the peer is not in the eager queue.
-                                    if !scheduler.eager_peers.contains(&(public_key, addr.to_string())) {
-                                        scheduler.lazy_peers.push_back((public_key, addr.to_owned()))
+                                    // Only push if the peer is not in either queue
+                                    // (or is stale).
+                                    let peer = (public_key, addr.to_string());
+                                    if !scheduler.eager_peers.contains(&peer) && !scheduler.is_stale(&peer, Instant::now()) {
+                                        scheduler.defer_peer(peer)
                                     }
                                 }
                             }
@@ -238,9 +488,11 @@ pub async fn actor(peers: Vec<(PublicKey, String)>) -> Result<()> {
                             // Push the peer to the back of the lazy queue.
                             if let Some(public_key) = data.peer_public_key {
                                 if let Some(addr) = &data.peer_addr {
-                                    // Only push if the peer is not already in the queue.
-                                    if !scheduler.lazy_peers.contains(&(public_key, addr.to_string())) {
-                                        scheduler.lazy_peers.push_back((public_key, addr.to_owned()))
+                                    // Only push if the peer is not already in the queue
+                                    // (or is stale).
+                                    let peer = (public_key, addr.to_string());
+                                    if !scheduler.lazy_peers.contains(&peer) && !scheduler.is_stale(&peer, Instant::now()) {
+                                        scheduler.lazy_peers.push_back(peer)
                                     }
                                 }
                             }
@@ -309,4 +561,141 @@ mod test {
 
         Ok(())
     }
+
+    #[async_std::test]
+    async fn test_lan_peer_expiry() -> Result<()> {
+        let mut connection_scheduler = ConnectionScheduler::default();
+        let now = Instant::now();
+
+        let lan_peer = (
+            "HEqy940T6uB+T+d9Jaa58aNfRzLx9eRWqkZljBmnkmk=.ed25519".to_ed25519_pk()?,
+            "192.168.1.10:8008".to_string(),
+        );
+        let peer = (
+            "QlQwWaj48J1Du5rHQXTPfifUFsPKLrOo6T5EfWfkqXU=.ed25519".to_ed25519_pk()?,
+            "ssb.mycelial.technology:8008".to_string(),
+        );
+        connection_scheduler.add_peer(peer.to_owned());
+        connection_scheduler.add_lan_peer(lan_peer.to_owned(), now);
+        assert_eq!(connection_scheduler.eager_peers.len(), 2);
+
+        // Peers which were not discovered on the LAN never expire.
+        assert!(!connection_scheduler.is_expired(&peer, now + LAN_PEER_TTL));
+
+        assert!(!connection_scheduler.is_expired(&lan_peer, now));
+        assert!(connection_scheduler.is_expired(&lan_peer, now + LAN_PEER_TTL));
+
+        // A new announcement extends the stay of the peer without queueing
+        // it twice.
+        connection_scheduler.add_lan_peer(lan_peer.to_owned(), now + LAN_PEER_TTL);
+        assert_eq!(connection_scheduler.eager_peers.len(), 2);
+        assert!(!connection_scheduler.is_expired(&lan_peer, now + LAN_PEER_TTL));
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_penalized_peers() -> Result<()> {
+        let mut connection_scheduler = ConnectionScheduler::default();
+        let now = Instant::now();
+
+        let peer = (
+            "QlQwWaj48J1Du5rHQXTPfifUFsPKLrOo6T5EfWfkqXU=.ed25519".to_ed25519_pk()?,
+            "ssb.mycelial.technology:8008".to_string(),
+        );
+        assert!(!connection_scheduler.is_penalized(&peer.0, now));
+
+        connection_scheduler.penalize(peer.0, now);
+        assert!(connection_scheduler.is_penalized(&peer.0, now));
+        assert!(!connection_scheduler.is_penalized(&peer.0, now + REPLICATION_ERROR_PENALTY));
+
+        // A deferred peer is only queued once.
+        connection_scheduler.defer_peer(peer.to_owned());
+        connection_scheduler.defer_peer(peer.to_owned());
+        assert_eq!(connection_scheduler.lazy_peers, vec![peer.to_owned()]);
+
+        // Expired penalties are forgotten.
+        let other_peer = "HEqy940T6uB+T+d9Jaa58aNfRzLx9eRWqkZljBmnkmk=.ed25519".to_ed25519_pk()?;
+        connection_scheduler.penalize(other_peer, now + REPLICATION_ERROR_PENALTY);
+        assert_eq!(connection_scheduler.penalties.len(), 1);
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_ranked_peers() -> Result<()> {
+        let mut connection_scheduler = ConnectionScheduler::default();
+
+        let reliable_peer = (
+            "QlQwWaj48J1Du5rHQXTPfifUFsPKLrOo6T5EfWfkqXU=.ed25519".to_ed25519_pk()?,
+            "ssb.mycelial.technology:8008".to_string(),
+        );
+        let unknown_peer = (
+            "HEqy940T6uB+T+d9Jaa58aNfRzLx9eRWqkZljBmnkmk=.ed25519".to_ed25519_pk()?,
+            "127.0.0.1:8008".to_string(),
+        );
+
+        // Peers with a higher score are dialed first.
+        connection_scheduler.add_ranked_peers(
+            vec![unknown_peer.to_owned(), reliable_peer.to_owned()],
+            |public_key| {
+                if *public_key == reliable_peer.0 {
+                    5.0
+                } else {
+                    0.0
+                }
+            },
+        );
+        assert_eq!(
+            connection_scheduler.eager_peers,
+            vec![reliable_peer, unknown_peer]
+        );
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_room_attendants() -> Result<()> {
+        let mut connection_scheduler = ConnectionScheduler::default();
+        let room = "@MDErHCTxklXc7QZ43fnyzERbRJ7fccRfCYF11EqIFEI=.ed25519".to_string();
+
+        let peer = (
+            "QlQwWaj48J1Du5rHQXTPfifUFsPKLrOo6T5EfWfkqXU=.ed25519".to_ed25519_pk()?,
+            "ssb.mycelial.technology:8008".to_string(),
+        );
+        let attendant = (
+            "HEqy940T6uB+T+d9Jaa58aNfRzLx9eRWqkZljBmnkmk=.ed25519".to_ed25519_pk()?,
+            "192.168.1.10:8008".to_string(),
+        );
+        connection_scheduler.add_peer(peer.to_owned());
+
+        // A joining attendant is dialed next.
+        connection_scheduler.handle_attendant_event(AttendantEvent::Joined {
+            room: room.to_owned(),
+            peer: attendant.to_owned(),
+            ephemeral: true,
+        });
+        assert_eq!(connection_scheduler.eager_peers.front(), Some(&attendant));
+        assert_eq!(connection_scheduler.eager_peers.len(), 2);
+
+        // An ephemeral attendant is dropped once it has left the room.
+        connection_scheduler.handle_attendant_event(AttendantEvent::Left {
+            room: room.to_owned(),
+            public_key: attendant.0,
+        });
+        assert!(connection_scheduler.has_left(&attendant));
+        assert_eq!(connection_scheduler.eager_peers, vec![peer.to_owned()]);
+
+        // Other peers are only prioritized.
+        connection_scheduler.handle_attendant_event(AttendantEvent::Joined {
+            room: room.to_owned(),
+            peer: peer.to_owned(),
+            ephemeral: false,
+        });
+        connection_scheduler.handle_room_closed(&room);
+        assert!(!connection_scheduler.has_left(&peer));
+        assert_eq!(connection_scheduler.eager_peers, vec![peer]);
+
+        Ok(())
+    }
 }
@@ -0,0 +1,185 @@
+//! Connection state machine.
+//!
+//! Each connection tracked by the connection manager is in exactly one state
+//! at a time:
+//!
+//! ```text
+//! Dialing → Handshaking → Connected → Replicating{strategy} → Draining → Closed
+//! ```
+//!
+//! Inbound connections begin in the `Handshaking` state. Any connection which
+//! is not yet closed may start draining (eg. when the dial or the handshake
+//! fails, or when the peer is not to be replicated), and any connection may
+//! be closed outright (eg. on a connection error). An EBT session which
+//! cannot be established falls back to classic replication.
+//!
+//! Every transition is broadcast on the broker as a
+//! `BrokerMessage::Transition`, so that other actors can follow the
+//! lifecycle of connections without interpreting connection events.
+use std::fmt;
+
+use kuska_ssb::crypto::ed25519;
+use serde::Serialize;
+
+use crate::actors::network::{connection::ConnectionId, connection_manager::ReplicationStrategy};
+
+/// State of a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(tag = "state", content = "strategy", rename_all = "snake_case")]
+pub enum ConnectionState {
+    /// A TCP connection to the peer is being established.
+    Dialing,
+    /// The secret handshake is in progress.
+    Handshaking,
+    /// The secret handshake succeeded; no replication session has started
+    /// yet.
+    Connected,
+    /// A replication session is active.
+    Replicating(ReplicationStrategy),
+    /// The connection is being closed.
+    Draining,
+    /// The connection is closed.
+    Closed,
+}
+
+impl ConnectionState {
+    /// Query whether a connection in this state is being established.
+    pub fn is_connecting(&self) -> bool {
+        matches!(
+            self,
+            ConnectionState::Dialing | ConnectionState::Handshaking
+        )
+    }
+
+    /// Query whether a connection in this state is established (and not
+    /// being closed).
+    pub fn is_connected(&self) -> bool {
+        matches!(
+            self,
+            ConnectionState::Connected | ConnectionState::Replicating(_)
+        )
+    }
+
+    /// Query whether a connection may pass from this state to the given
+    /// state.
+    pub fn can_transition_to(&self, next: &ConnectionState) -> bool {
+        use ConnectionState::*;
+
+        match (self, next) {
+            (Closed, _) => false,
+            (_, Closed) => true,
+            (Draining, _) => false,
+            (_, Draining) => true,
+            (Dialing, Handshaking) => true,
+            (Handshaking, Connected) => true,
+            (Connected, Replicating(_)) => true,
+            (Replicating(ReplicationStrategy::Ebt), Replicating(ReplicationStrategy::Classic)) => {
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionState::Dialing => write!(f, "dialing"),
+            ConnectionState::Handshaking => write!(f, "handshaking"),
+            ConnectionState::Connected => write!(f, "connected"),
+            ConnectionState::Replicating(ReplicationStrategy::Ebt) => {
+                write!(f, "replicating (ebt)")
+            }
+            ConnectionState::Replicating(ReplicationStrategy::Classic) => {
+                write!(f, "replicating (classic)")
+            }
+            ConnectionState::Draining => write!(f, "draining"),
+            ConnectionState::Closed => write!(f, "closed"),
+        }
+    }
+}
+
+/// A change in the state of a connection.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ConnectionTransition {
+    /// ID of the connection.
+    pub connection_id: ConnectionId,
+    /// SSB ID of the peer, if known.
+    pub peer: Option<String>,
+    /// The previous state (`None` for a newly tracked connection).
+    pub from: Option<ConnectionState>,
+    /// The new state.
+    pub to: ConnectionState,
+}
+
+/// A connection tracked by the connection manager.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ConnectionRecord {
+    /// ID of the connection.
+    pub connection_id: ConnectionId,
+    /// Public key of the peer, if known.
+    #[serde(skip)]
+    pub public_key: Option<ed25519::PublicKey>,
+    /// SSB ID of the peer, if known (an inbound peer is only identified by
+    /// a successful handshake).
+    pub peer: Option<String>,
+    /// Address of the peer.
+    pub addr: Option<String>,
+    /// Whether the connection was initiated by the peer.
+    pub inbound: bool,
+    /// Current state of the connection.
+    #[serde(flatten)]
+    pub state: ConnectionState,
+    /// Milliseconds since the UNIX epoch at which the connection entered its
+    /// current state.
+    pub since: u64,
+    /// Estimated skew of the clock of the peer in milliseconds, if
+    /// significant.
+    pub clock_skew: Option<i64>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use ConnectionState::*;
+
+    #[test]
+    fn test_transitions() {
+        let ebt = Replicating(ReplicationStrategy::Ebt);
+        let classic = Replicating(ReplicationStrategy::Classic);
+
+        // The regular lifecycle of an outbound connection.
+        assert!(Dialing.can_transition_to(&Handshaking));
+        assert!(Handshaking.can_transition_to(&Connected));
+        assert!(Connected.can_transition_to(&ebt));
+        assert!(ebt.can_transition_to(&classic));
+        assert!(classic.can_transition_to(&Draining));
+        assert!(Draining.can_transition_to(&Closed));
+
+        // Failures.
+        assert!(Dialing.can_transition_to(&Draining));
+        assert!(Handshaking.can_transition_to(&Closed));
+
+        // Skipped, reversed and repeated transitions.
+        assert!(!Dialing.can_transition_to(&Connected));
+        assert!(!Connected.can_transition_to(&Handshaking));
+        assert!(!classic.can_transition_to(&ebt));
+        assert!(!Draining.can_transition_to(&Draining));
+        assert!(!Draining.can_transition_to(&Connected));
+        assert!(!Closed.can_transition_to(&Closed));
+        assert!(!Closed.can_transition_to(&Dialing));
+    }
+
+    #[test]
+    fn test_state_predicates() {
+        assert!(Dialing.is_connecting());
+        assert!(Handshaking.is_connecting());
+        assert!(!Connected.is_connecting());
+
+        assert!(Connected.is_connected());
+        assert!(Replicating(ReplicationStrategy::Classic).is_connected());
+        assert!(!Draining.is_connected());
+        assert!(!Closed.is_connected());
+    }
+}
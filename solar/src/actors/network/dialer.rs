@@ -3,22 +3,114 @@
 //! Dial requests are received from the connection scheduler via the broker
 //! message bus. Each request includes the public key and address of the peer
 //! to be dialed. Upon receiving a request, the dialer spawns the connection actor.
+//!
+//! The number of dials in flight (address resolution, TCP connection and
+//! secret handshake) is limited; requests received while the limit is reached
+//! are queued until a dial completes. A dial is complete once the connection
+//! manager reports the handshake as successful or the connection as failed.
+//! Since some failures are not reported, a dial which has not completed
+//! within 60 seconds is assumed to have failed.
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use async_std::stream;
 use futures::{select_biased, FutureExt, StreamExt};
-use kuska_ssb::keystore::OwnedIdentity;
+use kuska_ssb::{crypto::ed25519::PublicKey, keystore::OwnedIdentity};
+use log::debug;
 
 use crate::{
-    actors::network::{connection, connection::TcpConnection, connection_scheduler::DialRequest},
+    actors::network::{
+        connection, connection::TcpConnection, connection_manager::ConnectionEvent,
+        connection_scheduler::DialRequest,
+    },
     broker::{ActorEndpoint, Broker, BrokerMessage, BROKER},
     Result,
 };
 
+/// Period after which a dial which has not completed is assumed to have
+/// failed, releasing its slot.
+const DIAL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Interval at which the dials in flight are checked for timeouts.
+const DIAL_TIMEOUT_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Dials in flight and dial requests waiting for a free slot.
+#[derive(Debug)]
+struct DialQueue {
+    /// Maximum number of dials in flight.
+    max_in_flight: usize,
+    /// Peers being dialed, along with the time at which each dial started.
+    in_flight: HashMap<PublicKey, Instant>,
+    /// Dial requests waiting for a free slot, in the order received.
+    pending: VecDeque<DialRequest>,
+}
+
+impl DialQueue {
+    fn new(max_in_flight: usize) -> Self {
+        Self {
+            max_in_flight: max_in_flight.max(1),
+            in_flight: HashMap::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Queue the given dial request. Requests for peers which are already
+    /// being dialed (or waiting to be) are ignored.
+    fn push(&mut self, request: DialRequest) {
+        let DialRequest((public_key, _)) = &request;
+        let is_queued = self
+            .pending
+            .iter()
+            .any(|DialRequest((queued_key, _))| queued_key == public_key);
+
+        if !self.in_flight.contains_key(public_key) && !is_queued {
+            self.pending.push_back(request)
+        }
+    }
+
+    /// Release the slot of the dial to the given peer, if any.
+    fn complete(&mut self, public_key: &PublicKey) {
+        self.in_flight.remove(public_key);
+    }
+
+    /// Release the slots of the dials which started before the timeout.
+    fn expire(&mut self, now: Instant) {
+        self.in_flight
+            .retain(|_, started| now.saturating_duration_since(*started) < DIAL_TIMEOUT);
+    }
+
+    /// Take the queued requests which can be dialed now, marking them as in
+    /// flight.
+    fn ready(&mut self, now: Instant) -> Vec<DialRequest> {
+        let mut ready = Vec::new();
+        while self.in_flight.len() < self.max_in_flight {
+            match self.pending.pop_front() {
+                Some(request) => {
+                    let DialRequest((public_key, _)) = &request;
+                    self.in_flight.insert(*public_key, now);
+                    ready.push(request)
+                }
+                None => break,
+            }
+        }
+
+        ready
+    }
+}
+
 /// Start the dialer.
 ///
 /// Register the connection dialer with the broker (as an actor) and listen
 /// for dial requests from the scheduler. Once received, use the attached
 /// public key and outbound address to dial the peer by spawning the connection
-/// actor.
-pub async fn actor(owned_identity: OwnedIdentity, selective_replication: bool) -> Result<()> {
+/// actor. At most `max_concurrent_dials` dials are in flight at once.
+pub async fn actor(
+    owned_identity: OwnedIdentity,
+    selective_replication: bool,
+    max_concurrent_dials: usize,
+) -> Result<()> {
     // Register the connection dialer actor with the broker.
     let ActorEndpoint {
         ch_terminate,
@@ -35,6 +127,9 @@ pub async fn actor(owned_identity: OwnedIdentity, selective_replication: bool) -
 
     let mut broker_msg_ch = ch_msg.unwrap();
 
+    let mut queue = DialQueue::new(max_concurrent_dials);
+    let mut ticker = stream::interval(DIAL_TIMEOUT_CHECK_INTERVAL).fuse();
+
     // Listen for dial request events via the broker message bus and dial peers.
     loop {
         select_biased! {
@@ -42,21 +137,86 @@ pub async fn actor(owned_identity: OwnedIdentity, selective_replication: bool) -
             _value = ch_terminate_fuse => {
                 break;
             },
-            // Received a message from the connection scheduler via the broker.
+            // Received a message from the connection scheduler (or a
+            // connection event from the connection manager) via the broker.
             msg = broker_msg_ch.next().fuse() => {
-                if let Some(BrokerMessage::Dial(DialRequest((public_key, addr)))) = msg {
-                    Broker::spawn(connection::actor(
-                        TcpConnection::Dial {
-                            addr: addr.to_string(),
-                            public_key,
-                        },
-                        owned_identity.clone(),
-                        selective_replication,
-                    ));
+                match msg {
+                    Some(BrokerMessage::Dial(dial_request)) => queue.push(dial_request),
+                    Some(BrokerMessage::Connection(event)) => {
+                        // Release the slot once the outcome of the dial is known.
+                        let data = match event {
+                            ConnectionEvent::Connected(data, _, false) => Some(data),
+                            ConnectionEvent::Disconnecting(data) => Some(data),
+                            ConnectionEvent::Disconnected(data) => Some(data),
+                            ConnectionEvent::Error(data, _) => Some(data),
+                            _ => None,
+                        };
+                        if let Some(public_key) = data.and_then(|data| data.peer_public_key) {
+                            queue.complete(&public_key)
+                        }
+                    }
+                    _ => (),
                 }
-            }
+            },
+            _tick = ticker.next() => queue.expire(Instant::now()),
+        }
+
+        for DialRequest((public_key, addr)) in queue.ready(Instant::now()) {
+            debug!("Dialing {}", addr);
+            Broker::spawn(
+                "connection",
+                connection::actor(
+                    TcpConnection::Dial {
+                        addr: addr.to_string(),
+                        public_key,
+                    },
+                    owned_identity.clone(),
+                    selective_replication,
+                ),
+            );
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use kuska_ssb::crypto::ToSodiumObject;
+
+    #[test]
+    fn test_dial_queue() -> Result<()> {
+        let mut queue = DialQueue::new(2);
+        let now = Instant::now();
+
+        let peers = [
+            "HEqy940T6uB+T+d9Jaa58aNfRzLx9eRWqkZljBmnkmk=.ed25519".to_ed25519_pk()?,
+            "QlQwWaj48J1Du5rHQXTPfifUFsPKLrOo6T5EfWfkqXU=.ed25519".to_ed25519_pk()?,
+            "MDErHCTxklXc7QZ43fnyzERbRJ7fccRfCYF11EqIFEI=.ed25519".to_ed25519_pk()?,
+        ];
+        for public_key in &peers {
+            queue.push(DialRequest((*public_key, "127.0.0.1:8008".to_string())));
+        }
+        // Requests for a peer which is already queued are ignored.
+        queue.push(DialRequest((peers[0], "127.0.0.1:8008".to_string())));
+
+        // Only two dials may be in flight at once.
+        assert_eq!(queue.ready(now).len(), 2);
+        assert!(queue.ready(now).is_empty());
+
+        // The third dial starts once a slot is released.
+        queue.complete(&peers[0]);
+        assert_eq!(queue.ready(now).len(), 1);
+        assert!(queue.ready(now).is_empty());
+
+        // Slots of dials which have timed out are released.
+        queue.push(DialRequest((peers[0], "127.0.0.1:8008".to_string())));
+        queue.expire(now + DIAL_TIMEOUT);
+        assert_eq!(queue.in_flight.len(), 0);
+        assert_eq!(queue.ready(now).len(), 1);
+
+        Ok(())
+    }
+}
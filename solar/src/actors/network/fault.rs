@@ -0,0 +1,405 @@
+//! Inject faults into outbound MUXRPC packets.
+//!
+//! Only compiled when the `fault-injection` feature is enabled. Faults are
+//! queued on demand - either directly from tests or via the `inject_fault`
+//! JSON-RPC method, for all peers or for a single one - and are applied to
+//! the MUXRPC packets written to peers by the replication loops, one fault
+//! per packet. Since the layer sits above the box stream, a faulty packet is
+//! still encrypted and authenticated, so the peer sees exactly the packets
+//! which a misbehaving peer would send. This makes it possible to exercise
+//! replication error paths without a misbehaving peer.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use async_std::{
+    io::{Read, Write},
+    task,
+};
+use log::debug;
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+
+/// Length of the header of a MUXRPC packet (flags, body length and request
+/// number).
+const HEADER_LEN: usize = 9;
+
+/// The fault injector shared by all connections.
+pub static FAULT_INJECTOR: Lazy<Arc<Mutex<FaultInjector>>> =
+    Lazy::new(|| Arc::new(Mutex::new(FaultInjector::default())));
+
+/// A fault to be applied to a single outbound packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(tag = "fault", rename_all = "snake_case")]
+pub enum Fault {
+    /// Silently discard the packet.
+    Drop,
+    /// Wait for the given number of milliseconds before writing the packet.
+    Delay { millis: u64 },
+    /// Write the packet twice.
+    Duplicate,
+    /// Only write the first `length` bytes of the packet.
+    Truncate { length: usize },
+}
+
+/// Queues of faults waiting to be applied.
+#[derive(Debug, Default)]
+pub struct FaultInjector {
+    /// Faults to be applied to the packets written to any peer.
+    faults: VecDeque<Fault>,
+    /// Faults to be applied to the packets written to a given peer, keyed by
+    /// public key. These are applied before those for any peer.
+    peer_faults: HashMap<String, VecDeque<Fault>>,
+}
+
+impl FaultInjector {
+    /// Queue the given fault to be applied to the next `count` packets
+    /// written to the given peer (or to any peer, if `None`).
+    pub fn inject(&mut self, fault: Fault, count: usize, peer: Option<&str>) {
+        debug!(
+            target: "fault-injection",
+            "Injecting {:?} fault x{} for {}",
+            fault,
+            count,
+            peer.unwrap_or("any peer")
+        );
+        let faults = match peer {
+            Some(peer) => self.peer_faults.entry(peer.to_owned()).or_default(),
+            None => &mut self.faults,
+        };
+        faults.extend(std::iter::repeat(fault).take(count))
+    }
+
+    /// Remove all pending faults, returning the number of faults removed.
+    pub fn clear(&mut self) -> usize {
+        let cleared = self.pending();
+        self.faults.clear();
+        self.peer_faults.clear();
+
+        cleared
+    }
+
+    /// Return the number of pending faults.
+    pub fn pending(&self) -> usize {
+        self.faults.len() + self.peer_faults.values().map(VecDeque::len).sum::<usize>()
+    }
+
+    /// Take the fault to be applied to the next packet written to the given
+    /// peer (if any).
+    fn next_fault(&mut self, peer: &str) -> Option<Fault> {
+        if let Some(faults) = self.peer_faults.get_mut(peer) {
+            let fault = faults.pop_front();
+            if faults.is_empty() {
+                self.peer_faults.remove(peer);
+            }
+            if fault.is_some() {
+                return fault;
+            }
+        }
+
+        self.faults.pop_front()
+    }
+}
+
+/// Return the length of the MUXRPC packet at the start of the given buffer,
+/// or `None` if the buffer does not yet hold its header.
+fn packet_len(buf: &[u8]) -> Option<usize> {
+    let header = buf.get(..HEADER_LEN)?;
+    let mut u32_buffer = [0u8; 4];
+    u32_buffer.copy_from_slice(&header[1..5]);
+
+    Some(HEADER_LEN + u32::from_be_bytes(u32_buffer) as usize)
+}
+
+type DelayFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Writer wrapper which applies queued faults to the MUXRPC packets written
+/// to a peer. It is meant to be placed between the RPC writer and the box
+/// stream writer.
+///
+/// Reads are passed through to the inner stream untouched.
+pub struct FaultyStream<S> {
+    inner: S,
+    injector: Arc<Mutex<FaultInjector>>,
+    /// Public key of the peer to which packets are written.
+    peer: String,
+    /// Bytes accepted from the caller which do not yet make up a whole
+    /// packet (or which follow a delayed packet).
+    packets: Vec<u8>,
+    /// Bytes of whole packets (possibly duplicated or truncated) not yet
+    /// written to the inner stream.
+    backlog: Vec<u8>,
+    /// Delay to await before writing the given delayed packet. The mutex is
+    /// only used to make the stream `Sync`; it is never contended.
+    delayed: Option<(Mutex<DelayFuture>, Vec<u8>)>,
+}
+
+impl<S> FaultyStream<S> {
+    /// Wrap the given stream to the given peer, applying faults from the
+    /// shared injector.
+    pub fn new(inner: S, peer: &str) -> Self {
+        Self::with_injector(inner, peer, FAULT_INJECTOR.clone())
+    }
+
+    /// Wrap the given stream to the given peer, applying faults from the
+    /// given injector.
+    pub fn with_injector(inner: S, peer: &str, injector: Arc<Mutex<FaultInjector>>) -> Self {
+        Self {
+            inner,
+            injector,
+            peer: peer.to_owned(),
+            packets: Vec::new(),
+            backlog: Vec::new(),
+            delayed: None,
+        }
+    }
+
+    /// Apply faults to the whole packets accepted so far, moving them to the
+    /// backlog, until a packet is delayed.
+    fn split_packets(&mut self) {
+        while self.delayed.is_none() {
+            let len = match packet_len(&self.packets) {
+                Some(len) if self.packets.len() >= len => len,
+                _ => break,
+            };
+            let packet: Vec<u8> = self.packets.drain(..len).collect();
+
+            let fault = self.injector.lock().unwrap().next_fault(&self.peer);
+            match fault {
+                None => self.backlog.extend_from_slice(&packet),
+                Some(Fault::Drop) => (),
+                Some(Fault::Delay { millis }) => {
+                    let delay: DelayFuture = Box::pin(task::sleep(Duration::from_millis(millis)));
+                    self.delayed = Some((Mutex::new(delay), packet));
+                }
+                Some(Fault::Duplicate) => {
+                    self.backlog.extend_from_slice(&packet);
+                    self.backlog.extend_from_slice(&packet);
+                }
+                Some(Fault::Truncate { length }) => {
+                    self.backlog.extend_from_slice(&packet[..length.min(len)]);
+                }
+            }
+        }
+    }
+}
+
+impl<S: Write + Unpin> FaultyStream<S> {
+    /// Write any backlogged bytes to the inner stream.
+    fn poll_backlog(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        while !self.backlog.is_empty() {
+            match Pin::new(&mut self.inner).poll_write(cx, &self.backlog) {
+                Poll::Ready(Ok(0)) => {
+                    return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()))
+                }
+                Poll::Ready(Ok(n)) => {
+                    self.backlog.drain(..n);
+                }
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    /// Await any delayed packet and write the backlogged bytes, along with
+    /// the packets which follow.
+    fn poll_packets(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        loop {
+            if let Poll::Pending = self.poll_backlog(cx)? {
+                return Poll::Pending;
+            }
+
+            match self.delayed.as_mut() {
+                Some((delay, _)) => {
+                    if delay.get_mut().unwrap().as_mut().poll(cx).is_pending() {
+                        return Poll::Pending;
+                    }
+                    if let Some((_, packet)) = self.delayed.take() {
+                        self.backlog.extend_from_slice(&packet);
+                    }
+                    self.split_packets();
+                }
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+}
+
+impl<S: Read + Unpin> Read for FaultyStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: Write + Unpin> Write for FaultyStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = &mut *self;
+
+        // Bytes are only accepted once the earlier packets have been written
+        // (or dropped), so that the buffers remain bounded.
+        if let Poll::Pending = this.poll_packets(cx)? {
+            return Poll::Pending;
+        }
+
+        // The header and body of a packet may be written separately, so the
+        // fault is applied once the whole packet has been accepted.
+        this.packets.extend_from_slice(buf);
+        this.split_packets();
+        // The packets are written on the next write or flush; any error is
+        // reported at that point.
+        let _ = this.poll_packets(cx);
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        if let Poll::Pending = self.poll_packets(cx)? {
+            return Poll::Pending;
+        }
+
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        if let Poll::Pending = self.poll_packets(cx)? {
+            return Poll::Pending;
+        }
+
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::time::Instant;
+
+    use futures::io::{AsyncWriteExt, Cursor};
+
+    use crate::Result;
+
+    const PEER: &str = "@peer";
+
+    /// Return a MUXRPC packet with the given body.
+    fn packet(body: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0b0000_0010];
+        packet.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        packet.extend_from_slice(&1i32.to_be_bytes());
+        packet.extend_from_slice(body);
+
+        packet
+    }
+
+    async fn write_packets(
+        faults: &[(Fault, usize, Option<&str>)],
+        bodies: &[&[u8]],
+    ) -> Result<Vec<u8>> {
+        let injector = Arc::new(Mutex::new(FaultInjector::default()));
+        for (fault, count, peer) in faults {
+            injector.lock().unwrap().inject(*fault, *count, *peer);
+        }
+
+        let mut stream = FaultyStream::with_injector(Cursor::new(Vec::new()), PEER, injector);
+        for body in bodies {
+            // The header and body are written separately, as by the RPC
+            // writer.
+            let packet = packet(body);
+            stream.write_all(&packet[..HEADER_LEN]).await?;
+            stream.write_all(&packet[HEADER_LEN..]).await?;
+            stream.flush().await?;
+        }
+
+        Ok(stream.inner.into_inner())
+    }
+
+    #[async_std::test]
+    async fn test_no_faults() -> Result<()> {
+        let written = write_packets(&[], &[b"abc", b"def"]).await?;
+        assert_eq!(written, [packet(b"abc"), packet(b"def")].concat());
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_drop_fault() -> Result<()> {
+        let written = write_packets(&[(Fault::Drop, 1, None)], &[b"abc", b"def"]).await?;
+        assert_eq!(written, packet(b"def"));
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_duplicate_fault() -> Result<()> {
+        let written =
+            write_packets(&[(Fault::Duplicate, 2, None)], &[b"abc", b"def", b"ghi"]).await?;
+        let (abc, def, ghi) = (packet(b"abc"), packet(b"def"), packet(b"ghi"));
+        assert_eq!(written, [&abc[..], &abc, &def, &def, &ghi].concat());
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_truncate_fault() -> Result<()> {
+        let truncate = Fault::Truncate { length: 10 };
+        let written = write_packets(&[(truncate, 1, None)], &[b"abc", b"def"]).await?;
+        let (abc, def) = (packet(b"abc"), packet(b"def"));
+        assert_eq!(written, [&abc[..10], &def[..]].concat());
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_delay_fault() -> Result<()> {
+        let start = Instant::now();
+        let delay = Fault::Delay { millis: 50 };
+        let written = write_packets(&[(delay, 1, None)], &[b"abc", b"def"]).await?;
+
+        assert_eq!(written, [packet(b"abc"), packet(b"def")].concat());
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_peer_faults() -> Result<()> {
+        // Faults for other peers are not applied, and those for the peer
+        // are applied before those for any peer.
+        let faults = [
+            (Fault::Duplicate, 1, None),
+            (Fault::Drop, 1, Some("@other")),
+            (Fault::Drop, 1, Some(PEER)),
+        ];
+        let written = write_packets(&faults, &[b"abc", b"def", b"ghi"]).await?;
+        let (def, ghi) = (packet(b"def"), packet(b"ghi"));
+        assert_eq!(written, [&def[..], &def, &ghi].concat());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_clear_faults() {
+        let mut injector = FaultInjector::default();
+        injector.inject(Fault::Drop, 3, None);
+        injector.inject(Fault::Duplicate, 2, Some(PEER));
+        assert_eq!(injector.pending(), 5);
+        assert_eq!(injector.clear(), 5);
+        assert_eq!(injector.pending(), 0);
+    }
+}
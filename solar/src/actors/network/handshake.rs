@@ -0,0 +1,322 @@
+//! Secret handshake with classified failures.
+//!
+//! A failed secret handshake is reported as an opaque error, and a peer
+//! which rejects a handshake simply closes the connection. The handshake is
+//! therefore performed on a stream which counts the bytes read from the
+//! peer, so that failures can be classified by the stage at which they
+//! occurred:
+//!
+//! - Wrong network key: the server closes the connection without answering
+//!   the client hello (the server cannot verify a hello sent with another
+//!   network key)
+//! - Wrong target key: the server closes the connection after the client
+//!   authentication (the authentication is sealed for the key the client
+//!   expects the server to have)
+//! - Timeout: the peer does not complete the handshake in time
+//! - Malformed challenge: the peer sends a message which cannot be verified
+//!
+//! The server sees the other side of the same failures: a client hello
+//! which cannot be verified was sent with another network key, and a client
+//! authentication which cannot be opened was sealed for another key.
+use std::{
+    fmt, io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use async_std::{
+    future,
+    io::{Read, Write},
+};
+use kuska_sodiumoxide::crypto::auth::Key as NetworkKey;
+use kuska_ssb::{
+    crypto::ed25519,
+    handshake::{
+        async_std::{handshake_client, handshake_server, Error},
+        HandshakeComplete,
+    },
+};
+use serde::Serialize;
+
+/// Maximum duration of a secret handshake.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Length of a client or server hello (the challenge).
+const HELLO_LEN: usize = 64;
+
+/// Length of the client authentication.
+const CLIENT_AUTH_LEN: usize = 112;
+
+/// Role of the local peer in a secret handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HandshakeRole {
+    Client,
+    Server,
+}
+
+/// A classified secret handshake failure.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "code", content = "message", rename_all = "snake_case")]
+pub enum HandshakeError {
+    /// The peers use different network keys.
+    WrongNetworkKey,
+    /// The server is not the peer the client intended to reach.
+    WrongTargetKey,
+    /// The peer did not complete the handshake in time.
+    Timeout,
+    /// The peer sent a message which could not be verified.
+    MalformedChallenge(String),
+    /// The peer closed the connection at an unexpected stage.
+    ConnectionClosed,
+    /// The handshake failed for another reason (eg. an I/O error).
+    Other(String),
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeError::WrongNetworkKey => write!(f, "wrong network key"),
+            HandshakeError::WrongTargetKey => write!(f, "wrong target key"),
+            HandshakeError::Timeout => write!(f, "timed out"),
+            HandshakeError::MalformedChallenge(err) => write!(f, "malformed challenge: {err}"),
+            HandshakeError::ConnectionClosed => write!(f, "connection closed by peer"),
+            HandshakeError::Other(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+/// The way in which a secret handshake failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Failure {
+    /// The peer closed the connection.
+    Closed,
+    /// Another I/O error occurred.
+    Io(String),
+    /// A message of the peer could not be verified.
+    Rejected(String),
+}
+
+impl From<&Error> for Failure {
+    fn from(err: &Error) -> Self {
+        match err {
+            Error::Io(err) => match err.kind() {
+                io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset => Failure::Closed,
+                _ => Failure::Io(err.to_string()),
+            },
+            err => Failure::Rejected(format!("{:?}", err)),
+        }
+    }
+}
+
+/// Classify a handshake failure given the role of the local peer and the
+/// number of bytes read from the peer before the failure occurred.
+fn classify(role: HandshakeRole, failure: Failure, bytes_read: usize) -> HandshakeError {
+    match (role, failure) {
+        (HandshakeRole::Client, Failure::Closed) => match bytes_read {
+            0 => HandshakeError::WrongNetworkKey,
+            HELLO_LEN => HandshakeError::WrongTargetKey,
+            _ => HandshakeError::ConnectionClosed,
+        },
+        (HandshakeRole::Server, Failure::Closed) => HandshakeError::ConnectionClosed,
+        (_, Failure::Io(err)) => HandshakeError::Other(err),
+        (HandshakeRole::Server, Failure::Rejected(_)) if bytes_read == HELLO_LEN => {
+            HandshakeError::WrongNetworkKey
+        }
+        (HandshakeRole::Server, Failure::Rejected(_))
+            if bytes_read == HELLO_LEN + CLIENT_AUTH_LEN =>
+        {
+            HandshakeError::WrongTargetKey
+        }
+        (_, Failure::Rejected(err)) => HandshakeError::MalformedChallenge(err),
+    }
+}
+
+/// A stream counting the bytes read from the inner stream.
+struct CountingStream<'a, S> {
+    inner: &'a mut S,
+    bytes_read: usize,
+}
+
+impl<S: Read + Unpin> Read for CountingStream<'_, S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let poll = Pin::new(&mut *self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            self.bytes_read += n;
+        }
+
+        poll
+    }
+}
+
+impl<S: Write + Unpin> Write for CountingStream<'_, S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut *self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_close(cx)
+    }
+}
+
+/// Perform a secret handshake with the given server as client.
+pub async fn client<S: Read + Write + Unpin>(
+    stream: &mut S,
+    network_key: NetworkKey,
+    pk: ed25519::PublicKey,
+    sk: ed25519::SecretKey,
+    peer_pk: ed25519::PublicKey,
+) -> Result<HandshakeComplete, HandshakeError> {
+    let mut stream = CountingStream {
+        inner: stream,
+        bytes_read: 0,
+    };
+    let handshake = handshake_client(&mut stream, network_key, pk, sk, peer_pk);
+
+    match future::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+        Ok(Ok(handshake)) => Ok(handshake),
+        Ok(Err(err)) => Err(classify(
+            HandshakeRole::Client,
+            Failure::from(&err),
+            stream.bytes_read,
+        )),
+        Err(_) => Err(HandshakeError::Timeout),
+    }
+}
+
+/// Perform a secret handshake with a client as server.
+pub async fn server<S: Read + Write + Unpin>(
+    stream: &mut S,
+    network_key: NetworkKey,
+    pk: ed25519::PublicKey,
+    sk: ed25519::SecretKey,
+) -> Result<HandshakeComplete, HandshakeError> {
+    let mut stream = CountingStream {
+        inner: stream,
+        bytes_read: 0,
+    };
+    let handshake = handshake_server(&mut stream, network_key, pk, sk);
+
+    match future::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+        Ok(Ok(handshake)) => Ok(handshake),
+        Ok(Err(err)) => Err(classify(
+            HandshakeRole::Server,
+            Failure::from(&err),
+            stream.bytes_read,
+        )),
+        Err(_) => Err(HandshakeError::Timeout),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use async_std::{
+        net::{TcpListener, TcpStream},
+        task,
+    };
+    use kuska_ssb::{discovery, keystore::OwnedIdentity};
+
+    #[test]
+    fn test_failure() {
+        let eof = Error::Io(io::Error::from(io::ErrorKind::UnexpectedEof));
+        assert_eq!(Failure::from(&eof), Failure::Closed);
+
+        let reset = Error::Io(io::Error::from(io::ErrorKind::ConnectionReset));
+        assert_eq!(Failure::from(&reset), Failure::Closed);
+
+        let refused = Error::Io(io::Error::from(io::ErrorKind::ConnectionRefused));
+        assert!(matches!(Failure::from(&refused), Failure::Io(_)));
+    }
+
+    #[test]
+    fn test_classify() {
+        let client = HandshakeRole::Client;
+        let server = HandshakeRole::Server;
+        let rejected = |err: &str| Failure::Rejected(err.to_string());
+
+        assert_eq!(
+            classify(client, Failure::Closed, 0),
+            HandshakeError::WrongNetworkKey
+        );
+        assert_eq!(
+            classify(client, Failure::Closed, HELLO_LEN),
+            HandshakeError::WrongTargetKey
+        );
+        assert_eq!(
+            classify(client, Failure::Closed, 10),
+            HandshakeError::ConnectionClosed
+        );
+        assert_eq!(
+            classify(server, Failure::Closed, 0),
+            HandshakeError::ConnectionClosed
+        );
+        assert_eq!(
+            classify(server, rejected("InvalidHello"), HELLO_LEN),
+            HandshakeError::WrongNetworkKey
+        );
+        assert_eq!(
+            classify(server, rejected("InvalidAuth"), HELLO_LEN + CLIENT_AUTH_LEN),
+            HandshakeError::WrongTargetKey
+        );
+        assert_eq!(
+            classify(client, rejected("InvalidAccept"), HELLO_LEN),
+            HandshakeError::MalformedChallenge("InvalidAccept".to_string())
+        );
+        assert_eq!(
+            classify(client, Failure::Io("refused".to_string()), 0),
+            HandshakeError::Other("refused".to_string())
+        );
+    }
+
+    #[async_std::test]
+    async fn test_wrong_target_key() -> crate::Result<()> {
+        let server_id = OwnedIdentity::create();
+        let client_id = OwnedIdentity::create();
+        let other_id = OwnedIdentity::create();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        let server_task = task::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let _ = server(
+                &mut stream,
+                discovery::ssb_net_id(),
+                server_id.pk,
+                server_id.sk,
+            )
+            .await;
+        });
+
+        // The client expects the server to have another key.
+        let mut stream = TcpStream::connect(addr).await?;
+        let result = client(
+            &mut stream,
+            discovery::ssb_net_id(),
+            client_id.pk,
+            client_id.sk,
+            other_id.pk,
+        )
+        .await;
+        server_task.await;
+
+        assert_eq!(result.err(), Some(HandshakeError::WrongTargetKey));
+
+        Ok(())
+    }
+}
@@ -1,24 +1,79 @@
 #![allow(clippy::single_match)]
 
-use std::time::Duration;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
 
-use async_std::{net::UdpSocket, task};
-use futures::{select_biased, FutureExt, SinkExt};
+use async_std::{net::UdpSocket, stream};
+use futures::{select_biased, stream::StreamExt, FutureExt, SinkExt};
 use kuska_ssb::{discovery::LanBroadcast, keystore::OwnedIdentity};
 use log::{trace, warn};
 
 use crate::{
-    actors::network::{connection::TcpConnection, connection_manager::ConnectionEvent},
+    actors::network::{connection_scheduler::LanScheduleRequest, multiserver},
     broker::*,
     Result,
 };
 
+/// Period during which repeated announcements from the same peer (with the
+/// same address) are ignored.
+const DEDUP_WINDOW: Duration = Duration::from_secs(60);
+
+/// Announcements received recently, along with the time at which each was
+/// last processed.
+#[derive(Debug, Default)]
+struct SeenAnnouncements(HashMap<String, Instant>);
+
+impl SeenAnnouncements {
+    /// Query whether the given announcement should be processed at the given
+    /// time, ie. it has not been processed within the deduplication window.
+    fn is_new(&mut self, announcement: &str, now: Instant) -> bool {
+        // Forget the announcements which are outside of the window.
+        self.0
+            .retain(|_, seen| now.saturating_duration_since(*seen) < DEDUP_WINDOW);
+
+        if self.0.contains_key(announcement) {
+            false
+        } else {
+            self.0.insert(announcement.to_owned(), now);
+            true
+        }
+    }
+}
+
+/// Size of the buffer in which broadcasts are received. Broadcasts
+/// announcing several addresses are longer than a single address.
+const BROADCAST_BUFFER_LEN: usize = 1024;
+
+/// Send a UDP broadcast announcing the given (multiserver) addresses.
+async fn announce(addresses: &[String], rpc_port: u16) -> Result<()> {
+    let msg = multiserver::join(addresses);
+
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.set_broadcast(true)?;
+    socket
+        .send_to(msg.as_bytes(), format!("255.255.255.255:{rpc_port}"))
+        .await?;
+
+    Ok(())
+}
+
 /// Register the LAN discovery endpoint, send and receive UDP broadcasts and
-/// spawn a secret handshake actor for each successfully parsed broadcast message.
+/// add the peer of each successfully parsed broadcast message to the
+/// connection scheduler.
+///
+/// The local peer is announced at the given interval, with the given address
+/// if any (or else with the address of each local network interface), unless
+/// `listen_only` is set. The addresses of the local peer through the rooms it
+/// attends are announced along with it. Repeated announcements from the same
+/// peer are ignored.
 pub async fn actor(
     server_id: OwnedIdentity,
     rpc_port: u16,
-    selective_replication: bool,
+    announce_addr: Option<String>,
+    interval: Duration,
+    listen_only: bool,
 ) -> Result<()> {
     // Instantiate a new LAN broadcaster with the given public key and port.
     let broadcaster = LanBroadcast::new(&server_id.pk, rpc_port).await?;
@@ -31,6 +86,9 @@ pub async fn actor(
     // this function.
     let mut ch_terminate = broker.ch_terminate.fuse();
 
+    let mut ticker = stream::interval(interval).fuse();
+    let mut seen = SeenAnnouncements::default();
+
     loop {
         // Create a UDP socket with the given address.
         let socket = UdpSocket::bind(format!("0.0.0.0:{rpc_port}")).await?;
@@ -38,37 +96,60 @@ pub async fn actor(
         socket.set_broadcast(true)?;
 
         // Create an empty buffer to store received messages.
-        let mut buf = [0; 256];
+        let mut buf = [0; BROADCAST_BUFFER_LEN];
 
         // Poll multiple futures and streams simultaneously, executing the
         // branch for the future that finishes first. If multiple futures are
         // ready, one will be selected in order of declaration.
-        select_biased! {
+        let tick = select_biased! {
             _ = ch_terminate => break,
             // Receive data from the socket.
             recv = socket.recv_from(&mut buf).fuse() => {
                 // `amt` is the number of bytes read.
                 if let Ok((amt, _)) = recv {
-                    // Process the received data. Log any errors.
-                    if let Err(err) = process_broadcast(
-                        &server_id,
-                        &buf[..amt],
-                        selective_replication
-                        ).await {
-                            warn!("failed to process broadcast: {:?}", err);
-                        }
+                    let msg = String::from_utf8_lossy(&buf[..amt]);
+                    if seen.is_new(&msg, Instant::now()) {
+                        // Process the received data. Log any errors.
+                        if let Err(err) = process_broadcast(&server_id, &msg).await {
+                                warn!("failed to process broadcast: {:?}", err);
+                            }
+                    } else {
+                        trace!(target: "lan-discovery", "Ignoring repeated broadcast {}", msg);
+                    }
                 }
+                false
             }
-            // Sleep for 15 seconds.
-            _ = task::sleep(Duration::from_secs(15)).fuse() => {}
-        }
+            _ = ticker.next() => true,
+        };
 
         // Drop the socket connection.
         drop(socket);
-        // Send out a UDP broadcast advertising the local public key and IP
-        // address. This allows other nodes on the network to discover this
-        // one.
-        broadcaster.send().await;
+
+        if tick && !listen_only {
+            // Send out a UDP broadcast advertising the local public key and
+            // IP address. This allows other nodes on the network to discover
+            // this one.
+            let result = match &announce_addr {
+                Some(addr) => {
+                    let addresses = multiserver::local_addresses(addr, &server_id.pk).await;
+                    announce(&addresses, rpc_port).await
+                }
+                None => {
+                    broadcaster.send().await;
+
+                    // The broadcaster only announces TCP addresses.
+                    let tunnels = multiserver::tunnel_addresses(&server_id.pk).await;
+                    if tunnels.is_empty() {
+                        Ok(())
+                    } else {
+                        announce(&tunnels, rpc_port).await
+                    }
+                }
+            };
+            if let Err(err) = result {
+                warn!("failed to send broadcast: {:?}", err);
+            }
+        }
     }
 
     // Send terminated signal back to the broker.
@@ -77,38 +158,65 @@ pub async fn actor(
     Ok(())
 }
 
-/// Process a UDP broadcast message and spawn a peer actor if the broadcast
-/// parsing is successful. This will result in a TCP connection attempt with
-/// the peer whose details are contained in the broadcast message.
-async fn process_broadcast(
-    server_id: &OwnedIdentity,
-    buff: &[u8],
-    selective_replication: bool,
-) -> Result<()> {
-    let msg = String::from_utf8_lossy(buff);
-
+/// Process a UDP broadcast message and add the announced peer to the
+/// connection scheduler if the broadcast parsing is successful. The scheduler
+/// dials the peer for as long as it keeps being announced. Broadcasts
+/// announcing the local peer are ignored.
+///
+/// A broadcast may announce several (multiserver) addresses, of which the
+/// first TCP address is dialed; peers only reachable through other
+/// transports are ignored.
+async fn process_broadcast(server_id: &OwnedIdentity, msg: &str) -> Result<()> {
     // Attempt to parse the IP / hostname, port and public key from the received
     // UDP broadcast message.
-    if let Some((server, port, public_key)) = LanBroadcast::parse(&msg) {
+    let parsed = multiserver::split(msg)
+        .filter(|address| address.starts_with("net:"))
+        .find_map(LanBroadcast::parse);
+    if let Some((server, port, public_key)) = parsed {
+        if public_key == server_id.pk {
+            return Ok(());
+        }
+
         let addr = format!("{server}:{port}");
 
         // Create a sender channel to the broker.
         let mut ch_broker = BROKER.lock().await.create_sender();
 
-        // Send 'lan discovery' connection event message via the broker.
+        // Send a LAN schedule request to the connection scheduler via the
+        // broker.
         ch_broker
             .send(BrokerEvent::new(
                 Destination::Broadcast,
-                BrokerMessage::Connection(ConnectionEvent::LanDiscovery(
-                    TcpConnection::Dial { addr, public_key },
-                    server_id.to_owned(),
-                    selective_replication,
-                )),
+                BrokerMessage::ScheduleLan(LanScheduleRequest((public_key, addr))),
             ))
             .await?;
-    } else {
+    } else if multiserver::split(msg).any(|address| address.starts_with("net:")) {
         warn!("failed to parse broadcast {}", msg);
+    } else {
+        trace!(target: "lan-discovery", "Ignoring broadcast without TCP address {}", msg);
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_seen_announcements() {
+        let mut seen = SeenAnnouncements::default();
+        let now = Instant::now();
+        let msg = "net:192.168.1.10:8008~shs:HEqy940T6uB+T+d9Jaa58aNfRzLx9eRWqkZljBmnkmk=";
+
+        assert!(seen.is_new(msg, now));
+        assert!(!seen.is_new(msg, now + Duration::from_secs(1)));
+        assert!(seen.is_new(
+            "net:192.168.1.11:8008~shs:HEqy940T6uB+T+d9Jaa58aNfRzLx9eRWqkZljBmnkmk=",
+            now
+        ));
+
+        // The announcement is processed again once the window has elapsed.
+        assert!(seen.is_new(msg, now + DEDUP_WINDOW));
+    }
+}
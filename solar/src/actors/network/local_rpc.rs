@@ -0,0 +1,256 @@
+//! Local MUXRPC endpoint for SSB clients.
+//!
+//! Applications built for ssb-server or go-ssb (eg. Patchwork, Oasis or bots
+//! using `ssb-client`) connect to their node over MUXRPC, authenticating with
+//! the secret handshake using the identity of the node itself. This endpoint
+//! listens on the loopback interface and accepts such connections, serving
+//! `whoami`, `createHistoryStream`, `get`, `publish` and `blobs.get`.
+//!
+//! Sessions are not subject to the connection idle timeout and are not
+//! tracked by the connection manager, since no replication takes place.
+
+use std::time::Duration;
+
+use async_std::{
+    io::Write,
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    task,
+};
+use futures::{pin_mut, select_biased, stream::StreamExt, FutureExt, SinkExt};
+use kuska_ssb::{
+    api::ApiCaller,
+    handshake::async_std::{handshake_server, BoxStream},
+    keystore::OwnedIdentity,
+    rpc::{RecvMsg, RpcReader, RpcWriter},
+};
+use log::{debug, error, info, trace, warn};
+
+use crate::{
+    actors::{
+        muxrpc::{
+            BlobsGetHandler, GetHandler, HistoryStreamHandler, PublishHandler, RpcHandler,
+            RpcInput, WhoAmIHandler,
+        },
+        network::config::TransportConfig,
+    },
+    broker::*,
+    config::NETWORK_KEY,
+    error::Error,
+    Result,
+};
+
+/// Listen for local client connections on the given address.
+pub async fn actor(server_id: OwnedIdentity, addr: impl ToSocketAddrs) -> Result<()> {
+    let broker = BROKER
+        .lock()
+        .await
+        .register("local-rpc-server", false)
+        .await?;
+
+    let mut ch_terminate = broker.ch_terminate.fuse();
+
+    let listener = TcpListener::bind(addr).await?;
+    let mut incoming = listener.incoming();
+    debug!("Listening for local MUXRPC clients...");
+
+    loop {
+        select_biased! {
+            _ = ch_terminate => break,
+            stream = incoming.next().fuse() => {
+                if let Some(stream) = stream {
+                    if let Ok(stream) = stream {
+                        debug!("Received local MUXRPC connection");
+                        Broker::spawn("local-rpc-session", session(stream, server_id.clone()));
+                    }
+                } else {
+                    break;
+                }
+            },
+        }
+    }
+
+    let _ = broker.ch_terminated.send(Void {});
+
+    Ok(())
+}
+
+/// Authenticate a local client and serve its requests until either side
+/// closes the connection.
+async fn session(mut stream: TcpStream, server_id: OwnedIdentity) -> Result<()> {
+    let transport = TransportConfig::current();
+    if let Err(err) = transport.configure(&stream) {
+        warn!("Failed to configure local MUXRPC connection: {}", err);
+    }
+
+    let network_key = NETWORK_KEY.get().ok_or(Error::OptionIsNone)?.to_owned();
+    let handshake = handshake_server(
+        &mut stream,
+        network_key,
+        server_id.pk.to_owned(),
+        server_id.sk.to_owned(),
+    )
+    .await?;
+
+    // Only clients holding the keys of the local identity are served.
+    if handshake.peer_pk != server_id.pk {
+        warn!("Rejected local MUXRPC connection from a foreign identity");
+        return Ok(());
+    }
+    info!("Local MUXRPC client connected");
+
+    // Register the session with the broker to be notified of new messages,
+    // which are forwarded on live history streams.
+    let ActorEndpoint {
+        ch_terminate,
+        mut ch_broker,
+        ch_msg,
+        actor_id,
+        ..
+    } = BROKER
+        .lock()
+        .await
+        .register("local-rpc-session", true)
+        .await?;
+    let mut ch_msg = ch_msg.ok_or(Error::OptionIsNone)?;
+
+    let (box_stream_read, box_stream_write) = BoxStream::from_handshake(
+        stream.clone(),
+        stream,
+        handshake,
+        transport.box_stream_buffer_size,
+    )
+    .split_read_write();
+    let rpc_reader = RpcReader::new(box_stream_read);
+    let mut api = ApiCaller::new(RpcWriter::new(box_stream_write));
+
+    // Local clients are served the feeds of the node itself.
+    let mut history_stream_handler = HistoryStreamHandler::serve_only(actor_id);
+    let mut whoami_handler = WhoAmIHandler::new(&server_id.id);
+    let mut get_handler = GetHandler::default();
+    let mut publish_handler = PublishHandler::new(server_id.clone());
+    let mut blobs_get_handler = BlobsGetHandler::default();
+
+    let mut handlers: Vec<&mut dyn RpcHandler<_>> = vec![
+        &mut history_stream_handler,
+        &mut whoami_handler,
+        &mut get_handler,
+        &mut publish_handler,
+        &mut blobs_get_handler,
+    ];
+
+    let mut ch_broker_send = BROKER.lock().await.create_sender();
+    let mut ch_terminate_fuse = ch_terminate.fuse();
+
+    let rpc_recv_stream = rpc_reader.into_stream().fuse();
+    pin_mut!(rpc_recv_stream);
+
+    loop {
+        let input = select_biased! {
+            _value = ch_terminate_fuse => break,
+            packet = rpc_recv_stream.next().fuse() => {
+                match packet {
+                    Some((rpc_id, packet)) => RpcInput::Network(rpc_id, packet),
+                    // The client closed the connection.
+                    None => break,
+                }
+            },
+            msg = ch_msg.next().fuse() => {
+                match msg {
+                    // Only feed updates are relevant to a local client; blob
+                    // requests are addressed to remote peers.
+                    Some(msg @ BrokerMessage::StoreKv(_)) => RpcInput::Message(msg),
+                    _ => RpcInput::None,
+                }
+            },
+            _ = task::sleep(Duration::from_secs(1)).fuse() => RpcInput::Timer,
+        };
+
+        let handled = dispatch(&mut handlers, &mut api, &input, &mut ch_broker_send).await?;
+        if !handled {
+            trace!(target: "local-rpc", "message not processed: {:?}", input);
+        }
+    }
+
+    info!("Local MUXRPC client disconnected");
+    let _ = ch_broker.send(BrokerEvent::Disconnect { actor_id }).await;
+
+    Ok(())
+}
+
+/// Hand the given input to the handlers until one of them handles it.
+///
+/// A request whose handler fails is answered with an error response carrying
+/// the error, so that the client is not left waiting for a response.
+async fn dispatch<W>(
+    handlers: &mut [&mut dyn RpcHandler<W>],
+    api: &mut ApiCaller<W>,
+    input: &RpcInput,
+    ch_broker: &mut ChBrokerSend,
+) -> Result<bool>
+where
+    W: Write + Unpin + Send + Sync,
+{
+    for handler in handlers.iter_mut() {
+        match handler.handle(api, input, ch_broker).await {
+            Ok(true) => return Ok(true),
+            Ok(false) => (),
+            Err(err) => {
+                error!("handler {} failed with {:?}", handler.name(), err);
+
+                if let RpcInput::Network(req_no, RecvMsg::RpcRequest(req)) = input {
+                    api.rpc()
+                        .send_error(*req_no, req.rpc_type, &err.to_string())
+                        .await?;
+
+                    return Ok(true);
+                }
+            }
+        }
+    }
+
+    Ok(false)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use futures::channel::mpsc;
+    use kuska_ssb::keystore::OwnedIdentity;
+    use serde_json::json;
+
+    use crate::conformance::{self, SharedBuffer};
+
+    #[async_std::test]
+    async fn test_handler_error_is_sent_to_client() -> Result<()> {
+        let output = SharedBuffer::default();
+        let mut api = ApiCaller::new(RpcWriter::new(output.clone()));
+        let (mut ch_broker, _) = mpsc::unbounded();
+
+        let mut publish_handler = PublishHandler::new(OwnedIdentity::create());
+        let mut handlers: Vec<&mut dyn RpcHandler<_>> = vec![&mut publish_handler];
+
+        // The content must be passed as an array of arguments.
+        let request = json!({
+            "name": ["publish"],
+            "type": "async",
+            "args": { "type": "post", "text": "hello" },
+        });
+        let inputs =
+            conformance::decode(&conformance::json_packet(1, request.to_string().as_bytes())).await;
+        assert_eq!(inputs.len(), 1);
+
+        assert!(dispatch(&mut handlers, &mut api, &inputs[0], &mut ch_broker).await?);
+
+        let sent = output.take_packets();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].req_no.abs(), 1);
+        assert!(sent[0].end);
+
+        // Inputs which are not requests are not answered.
+        assert!(!dispatch(&mut handlers, &mut api, &RpcInput::Timer, &mut ch_broker).await?);
+        assert!(output.take_packets().is_empty());
+
+        Ok(())
+    }
+}
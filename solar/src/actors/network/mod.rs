@@ -1,7 +1,17 @@
+pub mod clock_skew;
 pub mod config;
 pub mod connection;
 pub mod connection_manager;
 pub mod connection_scheduler;
+pub mod connection_state;
 pub mod dialer;
+#[cfg(feature = "fault-injection")]
+pub mod fault;
+pub mod handshake;
 pub mod lan_discovery;
+pub mod local_rpc;
+pub mod multiserver;
+pub mod reputation;
+pub mod room_invite;
+pub mod stats;
 pub mod tcp_server;
@@ -0,0 +1,137 @@
+//! Multiserver addresses of the local node.
+//!
+//! The local node tells peers how to reach it (in LAN discovery packets and
+//! `pub` messages) with multiserver addresses: one address per transport,
+//! each of the form `<transport>~shs:<key>`, joined by `;`. Peers pick the
+//! first transport they support.
+//!
+//! The local node is reachable over TCP (`net:<host>:<port>`) and, while
+//! attending a room which supports tunneled connections, through the room
+//! (`tunnel:<room>:<id>`). Solar does not serve WebSocket connections nor
+//! register room aliases, so neither is announced.
+use kuska_ssb::crypto::{ed25519, ToSsbId};
+
+use crate::{actors::network::connection_manager::CONNECTION_MANAGER, util::prefixed};
+
+/// Separator of the addresses of a multiserver address.
+const SEPARATOR: &str = ";";
+
+/// Format the given public key as an `@`-prefixed SSB ID.
+fn ssb_id(public_key: &ed25519::PublicKey) -> String {
+    prefixed(&public_key.to_ssb_id())
+}
+
+/// Format the given public key as the key of a secret handshake address
+/// (base64, without sigil or suffix).
+fn shs_key(public_key: &ed25519::PublicKey) -> String {
+    ssb_id(public_key)
+        .trim_start_matches('@')
+        .trim_end_matches(".ed25519")
+        .to_owned()
+}
+
+/// Return the address of the peer with the given public key over TCP at
+/// the given address (host and port).
+pub fn net_address(addr: &str, public_key: &ed25519::PublicKey) -> String {
+    format!("net:{}~shs:{}", addr, shs_key(public_key))
+}
+
+/// Return the address of the peer with the given public key through the
+/// room with the given SSB ID.
+pub fn tunnel_address(room: &str, public_key: &ed25519::PublicKey) -> String {
+    format!(
+        "tunnel:{}:{}~shs:{}",
+        room,
+        ssb_id(public_key),
+        shs_key(public_key)
+    )
+}
+
+/// Return the addresses of the local node through the rooms it currently
+/// attends which support tunneled connections.
+pub async fn tunnel_addresses(public_key: &ed25519::PublicKey) -> Vec<String> {
+    CONNECTION_MANAGER
+        .read()
+        .await
+        .tunnel_rooms()
+        .iter()
+        .map(|room| tunnel_address(room, public_key))
+        .collect()
+}
+
+/// Return the addresses of the local node: over TCP at the given address
+/// (host and port), followed by the addresses through the rooms it
+/// currently attends.
+pub async fn local_addresses(addr: &str, public_key: &ed25519::PublicKey) -> Vec<String> {
+    let mut addresses = vec![net_address(addr, public_key)];
+    addresses.extend(tunnel_addresses(public_key).await);
+
+    addresses
+}
+
+/// Join the given addresses into a multiserver address.
+pub fn join(addresses: &[String]) -> String {
+    addresses.join(SEPARATOR)
+}
+
+/// Split a multiserver address into its addresses.
+pub fn split(multiserver: &str) -> impl Iterator<Item = &str> {
+    multiserver
+        .split(SEPARATOR)
+        .map(str::trim)
+        .filter(|address| !address.is_empty())
+}
+
+/// Parse a `net:` address, returning the address (host and port) and the
+/// SSB ID of the peer. Returns `None` for addresses of other transports.
+pub fn parse_net(address: &str) -> Option<(String, String)> {
+    let (transport, key) = address.split_once("~shs:")?;
+    let addr = transport.strip_prefix("net:")?;
+    let (host, port) = addr.rsplit_once(':')?;
+    if host.is_empty() || port.parse::<u16>().is_err() || key.is_empty() {
+        return None;
+    }
+
+    Some((addr.to_owned(), format!("@{}.ed25519", key)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::secret_config::SecretConfig;
+
+    #[test]
+    fn test_addresses() -> crate::Result<()> {
+        let identity = SecretConfig::create().to_owned_identity()?;
+        let key = identity
+            .id
+            .trim_start_matches('@')
+            .trim_end_matches(".ed25519");
+
+        let net = net_address("192.168.1.10:8008", &identity.pk);
+        assert_eq!(net, format!("net:192.168.1.10:8008~shs:{}", key));
+        assert_eq!(
+            parse_net(&net),
+            Some(("192.168.1.10:8008".to_string(), identity.id.to_owned()))
+        );
+
+        let room = "@HEqy940T6uB+T+d9Jaa58aNfRzLx9eRWqkZljBmnkmk=.ed25519";
+        let tunnel = tunnel_address(room, &identity.pk);
+        assert_eq!(
+            tunnel,
+            format!("tunnel:{}:{}~shs:{}", room, identity.id, key)
+        );
+        assert_eq!(parse_net(&tunnel), None);
+
+        // Peers pick the addresses of the transports they support.
+        let multiserver = join(&[net.to_owned(), tunnel.to_owned()]);
+        let addresses: Vec<&str> = split(&multiserver).collect();
+        assert_eq!(addresses, vec![net.as_str(), tunnel.as_str()]);
+
+        assert_eq!(parse_net("net:8008~shs:key"), None);
+        assert_eq!(parse_net("net:example.com:port~shs:key"), None);
+
+        Ok(())
+    }
+}
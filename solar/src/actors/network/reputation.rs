@@ -0,0 +1,318 @@
+//! Peer reputation.
+//!
+//! The node keeps track of the behaviour of each peer: the new messages it
+//! delivered, the invalid messages it sent, the replication sessions held
+//! with it, the replication errors and the time spent connected to it. A
+//! score is computed from this reputation and used by the connection
+//! scheduler to dial useful, reliable peers first and to dial peers which
+//! send invalid messages or fail replication less frequently.
+//!
+//! As for the network statistics, activity is counted in memory and
+//! periodically added to the reputations persisted in the key-value store.
+//! The scores are cached in memory, so that they can be looked up without
+//! reading the database; they are updated when the activity is flushed.
+use std::{
+    collections::HashMap,
+    mem,
+    sync::{Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use async_std::stream;
+use futures::{select_biased, stream::StreamExt, FutureExt};
+use log::warn;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::{
+    actors::network::connection::ConnectionId,
+    broker::{ActorEndpoint, Void, BROKER},
+    node::KV_STORE,
+    storage::kv::PeerReputation,
+    util::prefixed,
+    Result,
+};
+
+/// Interval at which the counted activity is added to the persisted
+/// reputations.
+pub const REPUTATION_FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Score below which a peer is dialed as infrequently as a peer which could
+/// not be reached.
+pub const LOW_SCORE: f64 = -1.0;
+
+/// Weight of the share of invalid messages among the messages received from
+/// a peer in its score.
+const INVALID_MESSAGE_WEIGHT: f64 = 10.0;
+
+/// Weight of the number of replication errors per session in the score of
+/// a peer.
+const ERROR_WEIGHT: f64 = 5.0;
+
+/// Activity counted since the last flush, for each peer.
+static PENDING: Lazy<Mutex<HashMap<String, PeerReputation>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Peers connected on each connection, along with the time since which the
+/// uptime of the connection has not been counted.
+static CONNECTED: Lazy<Mutex<HashMap<ConnectionId, (String, Instant)>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Scores of the peers with a persisted reputation.
+static SCORES: Lazy<RwLock<HashMap<String, f64>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// The reputation and score of a peer, as returned by the `peer_scores`
+/// method.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PeerScore {
+    pub peer: String,
+    pub score: f64,
+    pub messages_received: u64,
+    pub invalid_messages: u64,
+    pub sessions: u64,
+    pub errors: u64,
+    pub uptime_secs: u64,
+}
+
+impl PeerScore {
+    fn new(peer: String, reputation: PeerReputation) -> Self {
+        PeerScore {
+            peer,
+            score: score(&reputation),
+            messages_received: reputation.messages_received,
+            invalid_messages: reputation.invalid_messages,
+            sessions: reputation.sessions,
+            errors: reputation.errors,
+            uptime_secs: reputation.uptime_secs,
+        }
+    }
+}
+
+/// Compute the score of a peer with the given reputation.
+///
+/// The messages delivered by the peer and the time spent connected to it
+/// raise its score, with diminishing returns, while the share of invalid
+/// messages and the replication errors per session lower it. A peer without
+/// any recorded activity has a score of zero.
+pub fn score(reputation: &PeerReputation) -> f64 {
+    let useful = (1.0 + reputation.messages_received as f64).ln();
+    let uptime = (1.0 + reputation.uptime_secs as f64 / 3600.0).ln();
+
+    let messages = reputation.messages_received + reputation.invalid_messages;
+    let invalid_share = reputation.invalid_messages as f64 / messages.max(1) as f64;
+    let error_rate = (reputation.errors as f64 / reputation.sessions.max(1) as f64).min(1.0);
+
+    useful + uptime - INVALID_MESSAGE_WEIGHT * invalid_share - ERROR_WEIGHT * error_rate
+}
+
+/// Run the given function on the activity of the peer with the given SSB ID
+/// counted since the last flush.
+fn with_pending(peer_ssb_id: &str, f: impl FnOnce(&mut PeerReputation)) {
+    let mut pending = PENDING.lock().unwrap_or_else(|err| err.into_inner());
+    f(pending.entry(prefixed(peer_ssb_id)).or_default())
+}
+
+/// Count a new, valid message received from the peer with the given SSB ID.
+pub fn record_message(peer_ssb_id: &str) {
+    with_pending(peer_ssb_id, |pending| pending.messages_received += 1)
+}
+
+/// Count an invalid message received from the peer with the given SSB ID.
+pub fn record_invalid_message(peer_ssb_id: &str) {
+    with_pending(peer_ssb_id, |pending| pending.invalid_messages += 1)
+}
+
+/// Count a replication session held with the peer with the given SSB ID.
+pub fn record_session(peer_ssb_id: &str) {
+    with_pending(peer_ssb_id, |pending| pending.sessions += 1)
+}
+
+/// Count a replication error with the peer with the given SSB ID.
+pub fn record_error(peer_ssb_id: &str) {
+    with_pending(peer_ssb_id, |pending| pending.errors += 1)
+}
+
+/// Start counting the uptime of the given connection with the peer with the
+/// given SSB ID.
+pub fn record_connected(connection_id: ConnectionId, peer_ssb_id: &str) {
+    let mut connected = CONNECTED.lock().unwrap_or_else(|err| err.into_inner());
+    connected.insert(connection_id, (prefixed(peer_ssb_id), Instant::now()));
+}
+
+/// Count the uptime of the given connection, which has been closed.
+pub fn record_disconnected(connection_id: ConnectionId) {
+    let closed = CONNECTED
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .remove(&connection_id);
+
+    if let Some((peer_ssb_id, since)) = closed {
+        with_pending(&peer_ssb_id, |pending| {
+            pending.uptime_secs += since.elapsed().as_secs()
+        })
+    }
+}
+
+/// Count the uptime of the open connections up to now.
+fn count_uptime() {
+    let mut connected = CONNECTED.lock().unwrap_or_else(|err| err.into_inner());
+    let now = Instant::now();
+
+    for (peer_ssb_id, since) in connected.values_mut() {
+        // Only whole seconds are counted, the remainder being counted later.
+        let secs = now.saturating_duration_since(*since).as_secs();
+        if secs > 0 {
+            with_pending(peer_ssb_id, |pending| pending.uptime_secs += secs);
+            *since += Duration::from_secs(secs);
+        }
+    }
+}
+
+/// Return the score of the peer with the given SSB ID, as of the last flush
+/// (zero if the peer has no recorded activity).
+pub fn score_of(peer_ssb_id: &str) -> f64 {
+    SCORES
+        .read()
+        .unwrap_or_else(|err| err.into_inner())
+        .get(&prefixed(peer_ssb_id))
+        .copied()
+        .unwrap_or(0.0)
+}
+
+/// Load the scores of the peers from the persisted reputations.
+pub async fn load() -> Result<()> {
+    let reputations = KV_STORE.read().await.get_peer_reputations()?;
+
+    let mut scores = SCORES.write().unwrap_or_else(|err| err.into_inner());
+    for (peer, reputation) in reputations {
+        scores.insert(peer, score(&reputation));
+    }
+
+    Ok(())
+}
+
+/// Add the activity counted since the last flush to the persisted
+/// reputations and update the scores of the peers concerned.
+pub async fn flush() -> Result<()> {
+    count_uptime();
+
+    let activity = mem::take(&mut *PENDING.lock().unwrap_or_else(|err| err.into_inner()));
+    if activity.is_empty() {
+        return Ok(());
+    }
+
+    // Acquire a write lock to serialize concurrent updates of a reputation.
+    let db = KV_STORE.write().await;
+    for (peer, pending) in activity {
+        let mut reputation = db.get_peer_reputation(&peer)?.unwrap_or_default();
+        reputation.merge(pending);
+        db.set_peer_reputation(&peer, &reputation)?;
+
+        SCORES
+            .write()
+            .unwrap_or_else(|err| err.into_inner())
+            .insert(peer, score(&reputation));
+    }
+
+    Ok(())
+}
+
+/// Return the reputations and scores of the peers, ordered from the highest
+/// to the lowest score.
+pub async fn scores() -> Result<Vec<PeerScore>> {
+    flush().await?;
+
+    let mut scores: Vec<PeerScore> = KV_STORE
+        .read()
+        .await
+        .get_peer_reputations()?
+        .into_iter()
+        .map(|(peer, reputation)| PeerScore::new(peer, reputation))
+        .collect();
+    scores.sort_by(|a, b| b.score.total_cmp(&a.score));
+
+    Ok(scores)
+}
+
+/// Start the peer reputation job.
+///
+/// Register the job with the broker (as an actor) and add the counted
+/// activity to the persisted reputations at the given interval and when the
+/// node is stopped.
+pub async fn actor(interval: Duration) -> Result<()> {
+    let ActorEndpoint {
+        ch_terminate,
+        ch_terminated,
+        ..
+    } = BROKER
+        .lock()
+        .await
+        .register("peer-reputation", false)
+        .await?;
+
+    let mut ch_terminate_fuse = ch_terminate.fuse();
+    let mut ticker = stream::interval(interval).fuse();
+
+    loop {
+        select_biased! {
+            // Received termination signal. Break out of the loop.
+            _value = ch_terminate_fuse => {
+                break;
+            },
+            _tick = ticker.next() => {
+                if let Err(err) = flush().await {
+                    warn!("Failed to record peer reputations: {}", err)
+                }
+            }
+        }
+    }
+
+    if let Err(err) = flush().await {
+        warn!("Failed to record peer reputations: {}", err)
+    }
+
+    let _ = ch_terminated.send(Void {});
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_score() {
+        let unknown = PeerReputation::default();
+        assert_eq!(score(&unknown), 0.0);
+
+        let useful = PeerReputation {
+            messages_received: 1_000,
+            sessions: 10,
+            uptime_secs: 36_000,
+            ..PeerReputation::default()
+        };
+        assert!(score(&useful) > 0.0);
+
+        // Errors lower the score, up to an error per session.
+        let failing = PeerReputation {
+            errors: 10,
+            ..useful.clone()
+        };
+        assert!(score(&failing) < score(&useful));
+        let erroring = PeerReputation {
+            errors: 100,
+            ..failing.clone()
+        };
+        assert_eq!(score(&erroring), score(&failing));
+
+        // A peer which mostly sends invalid messages scores below an unknown
+        // peer.
+        let misbehaving = PeerReputation {
+            messages_received: 1,
+            invalid_messages: 100,
+            sessions: 1,
+            ..PeerReputation::default()
+        };
+        assert!(score(&misbehaving) < LOW_SCORE);
+    }
+}
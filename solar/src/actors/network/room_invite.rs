@@ -0,0 +1,152 @@
+//! Rooms 2.0 HTTP invites.
+//!
+//! Room invites are shared as links of the form
+//! `https://<room>/join?token=<token>`. Consuming an invite is a two-step
+//! HTTP exchange: the invite is first claimed (by requesting the join page
+//! as JSON), which returns the endpoint to which the invite must be posted.
+//! The invite is then posted to that endpoint along with the public key of
+//! the local identity, to which the room responds with its multiserver
+//! address. That address is used to connect to the room.
+
+use kuska_ssb::crypto::{ed25519::PublicKey, ToSodiumObject};
+use log::info;
+use serde::Deserialize;
+use serde_json::json;
+use url::Url;
+
+use crate::{error::Error, Result};
+
+/// Response to an invite claim request.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ClaimResponse {
+    status: String,
+    invite: Option<String>,
+    post_to: Option<String>,
+    error: Option<String>,
+}
+
+/// Response to an invite consumption request.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ConsumeResponse {
+    status: String,
+    multiserver_address: Option<String>,
+    error: Option<String>,
+}
+
+/// Parse and validate an HTTP room invite.
+pub fn parse_invite(invite: &str) -> Result<Url> {
+    let url = Url::parse(invite)?;
+
+    if url.scheme() != "https" && url.scheme() != "http" {
+        return Err(Error::RoomInvite(format!(
+            "invite must be an HTTP(S) URL: {invite}"
+        )));
+    }
+    if !url.query_pairs().any(|(key, _)| key == "token") {
+        return Err(Error::RoomInvite(format!(
+            "invite is missing a token: {invite}"
+        )));
+    }
+
+    Ok(url)
+}
+
+/// Parse a multiserver address of the form `net:<host>:<port>~shs:<key>`,
+/// returning the public key and address of the peer. Only the first `net`
+/// address is used if several addresses are given.
+pub fn parse_multiserver_address(addr: &str) -> Result<(PublicKey, String)> {
+    for address in addr.split(';') {
+        let mut parts = address.split('~');
+        let (net, shs) = match (parts.next(), parts.next()) {
+            (Some(net), Some(shs)) => (net, shs),
+            _ => continue,
+        };
+
+        if let (Some(host_and_port), Some(key)) =
+            (net.strip_prefix("net:"), shs.strip_prefix("shs:"))
+        {
+            let public_key = key.to_ed25519_pk_no_suffix()?;
+
+            return Ok((public_key, host_and_port.to_owned()));
+        }
+    }
+
+    Err(Error::RoomInvite(format!(
+        "unsupported multiserver address: {addr}"
+    )))
+}
+
+/// Claim and consume the given HTTP room invite on behalf of the local
+/// identity, returning the public key and address of the room.
+pub async fn consume_invite(invite: &str, local_id: &str) -> Result<(PublicKey, String)> {
+    let mut claim_url = parse_invite(invite)?;
+    claim_url.query_pairs_mut().append_pair("encoding", "json");
+
+    let client = reqwest::Client::new();
+
+    let claim: ClaimResponse = client.get(claim_url).send().await?.json().await?;
+    let (token, post_to) = match (claim.status.as_str(), claim.invite, claim.post_to) {
+        ("successful", Some(token), Some(post_to)) => (token, post_to),
+        _ => {
+            return Err(Error::RoomInvite(format!(
+                "failed to claim invite: {}",
+                claim.error.unwrap_or(claim.status)
+            )))
+        }
+    };
+
+    let consume: ConsumeResponse = client
+        .post(post_to)
+        .json(&json!({ "id": local_id, "invite": token }))
+        .send()
+        .await?
+        .json()
+        .await?;
+    let addr = match (consume.status.as_str(), consume.multiserver_address) {
+        ("successful", Some(addr)) => addr,
+        _ => {
+            return Err(Error::RoomInvite(format!(
+                "failed to consume invite: {}",
+                consume.error.unwrap_or(consume.status)
+            )))
+        }
+    };
+
+    info!("Consumed room invite; room address is {}", addr);
+
+    parse_multiserver_address(&addr)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use kuska_ssb::crypto::ToSsbId;
+
+    #[test]
+    fn test_parse_invite() {
+        assert!(parse_invite("https://room.example.com/join?token=abc").is_ok());
+        assert!(parse_invite("https://room.example.com/join").is_err());
+        assert!(parse_invite("ssb:experimental?action=claim-http-invite").is_err());
+    }
+
+    #[test]
+    fn test_parse_multiserver_address() -> Result<()> {
+        let key = "HEqy940T6uB+T+d9Jaa58aNfRzLx9eRWqkZljBmnkmk=";
+
+        let (public_key, addr) = parse_multiserver_address(&format!(
+            "ws://room.example.com:80~shs:{key};net:room.example.com:8008~shs:{key}"
+        ))?;
+        assert_eq!(
+            public_key.to_ssb_id().trim_start_matches('@'),
+            format!("{key}.ed25519")
+        );
+        assert_eq!(addr, "room.example.com:8008");
+
+        assert!(parse_multiserver_address("ws://room.example.com:80").is_err());
+
+        Ok(())
+    }
+}
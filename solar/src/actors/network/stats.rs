@@ -0,0 +1,264 @@
+//! Network statistics.
+//!
+//! Solar nodes can serve as probes for the study of the gossip network. The
+//! node counts, for each day (in UTC), the feeds of which a first message was
+//! replicated, the messages replicated from peers, the unique peers with
+//! which a connection was established and the bytes exchanged with peers
+//! (on replication connections, after the secret handshake).
+//!
+//! Activity is counted in memory and periodically added to the statistics
+//! of the current day in the key-value store, so that replication is not
+//! slowed down by a database write for each event.
+use std::{
+    mem,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    task::{Context, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use async_std::{
+    io::{Read, Write},
+    stream,
+};
+use futures::{select_biased, stream::StreamExt, FutureExt};
+use log::warn;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::{
+    broker::{ActorEndpoint, Void, BROKER},
+    node::KV_STORE,
+    storage::kv::NetworkStats,
+    Result,
+};
+
+/// Interval at which the counted activity is added to the persisted
+/// statistics.
+pub const STATS_FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Number of seconds in a day.
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Activity counted since the last flush (excluding the bytes exchanged,
+/// which are counted separately to avoid locking on each read and write).
+static PENDING: Lazy<Mutex<NetworkStats>> = Lazy::new(|| Mutex::new(NetworkStats::default()));
+
+/// Bytes received from peers since the last flush.
+static BYTES_RECEIVED: AtomicU64 = AtomicU64::new(0);
+
+/// Bytes sent to peers since the last flush.
+static BYTES_SENT: AtomicU64 = AtomicU64::new(0);
+
+/// Network statistics of a day, as returned by the `network_stats` method.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DailyStats {
+    /// Date (`YYYY-MM-DD`, in UTC).
+    pub date: String,
+    pub new_feeds: u64,
+    pub messages_replicated: u64,
+    pub unique_peers: usize,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+}
+
+impl DailyStats {
+    fn new(day: u64, stats: NetworkStats) -> Self {
+        DailyStats {
+            date: format_day(day),
+            new_feeds: stats.new_feeds,
+            messages_replicated: stats.messages_replicated,
+            unique_peers: stats.peers.len(),
+            bytes_received: stats.bytes_received,
+            bytes_sent: stats.bytes_sent,
+        }
+    }
+}
+
+/// Return the current day, in days since the UNIX epoch.
+fn today() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() / SECS_PER_DAY)
+        .unwrap_or(0)
+}
+
+/// Format the given day (in days since the UNIX epoch) as a date.
+fn format_day(day: u64) -> String {
+    let time = UNIX_EPOCH + Duration::from_secs(day * SECS_PER_DAY);
+    humantime::format_rfc3339(time).to_string()[..10].to_owned()
+}
+
+/// Run the given function on the activity counted since the last flush.
+fn with_pending<T>(f: impl FnOnce(&mut NetworkStats) -> T) -> T {
+    let mut pending = PENDING.lock().unwrap_or_else(|err| err.into_inner());
+    f(&mut pending)
+}
+
+/// Count a message replicated from a peer, which is the first message of
+/// its feed if `new_feed` is true.
+pub fn record_message(new_feed: bool) {
+    with_pending(|pending| {
+        pending.messages_replicated += 1;
+        if new_feed {
+            pending.new_feeds += 1;
+        }
+    })
+}
+
+/// Count a connection established with the peer with the given SSB ID.
+pub fn record_peer(peer_ssb_id: &str) {
+    with_pending(|pending| {
+        pending.peers.insert(peer_ssb_id.to_owned());
+    })
+}
+
+/// A stream counting the bytes exchanged with a peer.
+pub struct MeteredStream<S> {
+    inner: S,
+}
+
+impl<S> MeteredStream<S> {
+    pub fn new(inner: S) -> Self {
+        MeteredStream { inner }
+    }
+}
+
+impl<S: Read + Unpin> Read for MeteredStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            BYTES_RECEIVED.fetch_add(n as u64, Ordering::Relaxed);
+        }
+
+        poll
+    }
+}
+
+impl<S: Write + Unpin> Write for MeteredStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            BYTES_SENT.fetch_add(n as u64, Ordering::Relaxed);
+        }
+
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+/// Add the activity counted since the last flush to the persisted
+/// statistics of the current day.
+pub async fn flush() -> Result<()> {
+    let mut stats = with_pending(mem::take);
+    stats.bytes_received = BYTES_RECEIVED.swap(0, Ordering::Relaxed);
+    stats.bytes_sent = BYTES_SENT.swap(0, Ordering::Relaxed);
+    if stats.is_empty() {
+        return Ok(());
+    }
+
+    let day = today();
+    // Acquire a write lock to serialize concurrent updates of the day.
+    let db = KV_STORE.write().await;
+    let mut day_stats = db.get_network_stats(day)?.unwrap_or_default();
+    day_stats.merge(stats);
+    db.set_network_stats(day, &day_stats)
+}
+
+/// Return the network statistics of the given number of most recent days
+/// (including the current day), ordered from oldest to newest. Days without
+/// any activity are omitted.
+pub async fn daily(days: u64) -> Result<Vec<DailyStats>> {
+    flush().await?;
+
+    let first_day = (today() + 1).saturating_sub(days);
+    let stats = KV_STORE
+        .read()
+        .await
+        .get_network_stats_since(first_day)?
+        .into_iter()
+        .map(|(day, stats)| DailyStats::new(day, stats))
+        .collect();
+
+    Ok(stats)
+}
+
+/// Start the network statistics job.
+///
+/// Register the job with the broker (as an actor) and add the counted
+/// activity to the persisted statistics at the given interval and when the
+/// node is stopped.
+pub async fn actor(interval: Duration) -> Result<()> {
+    let ActorEndpoint {
+        ch_terminate,
+        ch_terminated,
+        ..
+    } = BROKER.lock().await.register("network-stats", false).await?;
+
+    let mut ch_terminate_fuse = ch_terminate.fuse();
+    let mut ticker = stream::interval(interval).fuse();
+
+    loop {
+        select_biased! {
+            // Received termination signal. Break out of the loop.
+            _value = ch_terminate_fuse => {
+                break;
+            },
+            _tick = ticker.next() => {
+                if let Err(err) = flush().await {
+                    warn!("Failed to record network statistics: {}", err)
+                }
+            }
+        }
+    }
+
+    if let Err(err) = flush().await {
+        warn!("Failed to record network statistics: {}", err)
+    }
+
+    let _ = ch_terminated.send(Void {});
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_daily_stats() {
+        assert_eq!(format_day(0), "1970-01-01");
+        assert_eq!(format_day(20_743), "2026-10-17");
+
+        let mut stats = NetworkStats {
+            new_feeds: 1,
+            messages_replicated: 10,
+            bytes_sent: 512,
+            ..NetworkStats::default()
+        };
+        stats.peers.insert("@peer".to_string());
+
+        let daily = DailyStats::new(20_743, stats);
+        assert_eq!(daily.date, "2026-10-17");
+        assert_eq!(daily.unique_peers, 1);
+        assert_eq!(daily.bytes_received, 0);
+    }
+}
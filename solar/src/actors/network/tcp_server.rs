@@ -4,10 +4,10 @@ use async_std::{
 };
 use futures::{select_biased, FutureExt};
 use kuska_ssb::keystore::OwnedIdentity;
-use log::debug;
+use log::{debug, warn};
 
 use crate::{
-    actors::network::{connection, connection::TcpConnection},
+    actors::network::{config::TransportConfig, connection, connection::TcpConnection},
     broker::*,
     Result,
 };
@@ -32,7 +32,13 @@ pub async fn actor(
                 if let Some(stream) = stream {
                     if let Ok(stream) = stream {
                         debug!("Received inbound TCP connection");
+                        // The connection remains usable with the default TCP
+                        // options.
+                        if let Err(err) = TransportConfig::current().configure(&stream) {
+                            warn!("Failed to configure inbound TCP connection: {}", err);
+                        }
                         Broker::spawn(
+                            "connection",
                             connection::actor(
                                 TcpConnection::Listen { stream },
                                 server_id.clone(),
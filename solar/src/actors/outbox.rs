@@ -0,0 +1,147 @@
+//! Outbox
+//!
+//! Messages can be added to the outbox rather than being published right
+//! away, either as drafts or scheduled to be published at a given time. The
+//! outbox is persisted in the database, so that scheduled messages survive a
+//! restart of the node (or a device which is only intermittently running).
+//! The outbox job periodically publishes the scheduled messages which are
+//! due; drafts are only published on request.
+//!
+//! An entry is removed from the outbox before its message is published, so
+//! that it cannot be edited or published twice in the meantime. If the
+//! publish fails (for example, if the content does not pass validation), the
+//! entry is returned to the outbox as a draft, along with the error, to be
+//! edited or cancelled.
+use std::time::Duration;
+
+use async_std::stream;
+use futures::{select_biased, stream::StreamExt, FutureExt};
+use kuska_ssb::{feed::Message, keystore::OwnedIdentity};
+use log::{info, warn};
+
+use crate::{
+    actors::replication::identity_guard,
+    broker::{ActorEndpoint, BROKER},
+    node::KV_STORE,
+    storage::{kv::OutboxEntry, publish},
+    util::now_millis,
+    Result,
+};
+
+/// Interval at which the outbox is checked for scheduled messages which are
+/// due.
+pub const OUTBOX_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Query whether the given outbox entry is due to be published at the given
+/// time (in milliseconds since the UNIX epoch).
+fn is_due(entry: &OutboxEntry, now: u64) -> bool {
+    entry
+        .publish_at
+        .map_or(false, |publish_at| publish_at <= now)
+}
+
+/// Publish the message of the outbox entry with the given ID on the local
+/// feed and remove the entry from the outbox. Returns `None` if there is no
+/// such entry.
+///
+/// If the publish fails, the entry is turned into a draft recording the
+/// error and the error is returned.
+pub async fn publish_entry(
+    identity: &OwnedIdentity,
+    entry_id: u64,
+) -> Result<Option<(Message, u64)>> {
+    let entry = match KV_STORE.read().await.remove_outbox_entry(entry_id)? {
+        Some(entry) => entry,
+        None => return Ok(None),
+    };
+
+    match publish::publish(identity, entry.content.clone(), None).await {
+        Ok(published) => Ok(Some(published)),
+        Err(err) => {
+            let draft = OutboxEntry {
+                publish_at: None,
+                error: Some(err.to_string()),
+                ..entry
+            };
+            KV_STORE.read().await.set_outbox_entry(entry_id, &draft)?;
+
+            Err(err)
+        }
+    }
+}
+
+/// Publish the scheduled messages of the outbox which are due. Scheduled
+/// messages are held back while an identity conflict is recorded.
+pub async fn publish_due(identity: &OwnedIdentity) -> Result<()> {
+    identity_guard::ensure_no_conflict().await?;
+
+    let now = now_millis();
+
+    let entries = KV_STORE.read().await.get_outbox_entries()?;
+    for (entry_id, entry) in entries {
+        if !is_due(&entry, now) {
+            continue;
+        }
+
+        match publish_entry(identity, entry_id).await {
+            Ok(Some((msg, _seq))) => info!(
+                "published scheduled outbox entry {} as {}",
+                entry_id,
+                msg.id().to_string()
+            ),
+            Ok(None) => (),
+            Err(err) => warn!("Failed to publish outbox entry {}: {}", entry_id, err),
+        }
+    }
+
+    Ok(())
+}
+
+/// Start the outbox job.
+///
+/// Register the job with the broker (as an actor) and publish the scheduled
+/// messages which are due at the given interval.
+pub async fn actor(identity: OwnedIdentity, interval: Duration) -> Result<()> {
+    // Register the outbox actor with the broker.
+    let ActorEndpoint { ch_terminate, .. } = BROKER.lock().await.register("outbox", false).await?;
+
+    let mut ch_terminate_fuse = ch_terminate.fuse();
+    let mut ticker = stream::interval(interval).fuse();
+
+    loop {
+        select_biased! {
+            // Received termination signal. Break out of the loop.
+            _value = ch_terminate_fuse => {
+                break;
+            },
+            _tick = ticker.next() => {
+                if let Err(err) = publish_due(&identity).await {
+                    warn!("Failed to publish scheduled messages: {}", err)
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    fn test_is_due() {
+        let entry = |publish_at| OutboxEntry {
+            content: json!({ "type": "post", "text": "hi" }),
+            publish_at,
+            error: None,
+        };
+
+        assert!(!is_due(&entry(None), 1_000));
+        assert!(!is_due(&entry(Some(2_000)), 1_000));
+        assert!(is_due(&entry(Some(1_000)), 1_000));
+        assert!(is_due(&entry(Some(500)), 1_000));
+    }
+}
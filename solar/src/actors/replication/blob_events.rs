@@ -0,0 +1,99 @@
+//! Blob lifecycle events.
+//!
+//! The progress of each wanted blob is broadcast on the broker as a
+//! `BrokerMessage::Blob`, so that clients can follow attachment downloads
+//! (see the `subscribe_blob_events` JSON-RPC subscription) instead of
+//! polling the blob store:
+//!
+//! - `want_registered`: the blob was added to the want-list
+//! - `fetch_started`: the blob (or a slice of it) was requested from a peer
+//! - `progress`: a slice of the blob was received from a peer
+//! - `stored`: the blob was added to the store
+//! - `failed`: the fetch from a peer failed
+//!
+//! A failed fetch is not final: the blob remains wanted and is fetched from
+//! another peer (or requested again later), until it is stored.
+use futures::SinkExt;
+use serde::Serialize;
+
+use crate::broker::{BrokerEvent, BrokerMessage, Destination, BROKER};
+
+/// An event in the lifecycle of a wanted blob.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum BlobEvent {
+    /// The blob was added to the want-list.
+    WantRegistered { blob_id: String },
+    /// The blob was requested from the given peer, starting at the given
+    /// offset. The size is only known if announced by the peer.
+    FetchStarted {
+        blob_id: String,
+        peer: String,
+        offset: u64,
+        size: Option<u64>,
+    },
+    /// The given number of bytes of the blob have been received from the
+    /// given peer (reported as each slice completes).
+    Progress {
+        blob_id: String,
+        peer: String,
+        received: u64,
+        size: u64,
+    },
+    /// The blob was added to the store.
+    Stored { blob_id: String },
+    /// The fetch of the blob from the given peer failed.
+    Failed {
+        blob_id: String,
+        peer: String,
+        error: String,
+    },
+}
+
+/// Broadcast the given blob event.
+pub async fn emit(event: BlobEvent) {
+    let mut ch_broker = BROKER.lock().await.create_sender();
+    let _ = ch_broker
+        .send(BrokerEvent::new(
+            Destination::Broadcast,
+            BrokerMessage::Blob(event),
+        ))
+        .await;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    fn test_serialize_blob_event() -> crate::Result<()> {
+        let event = BlobEvent::FetchStarted {
+            blob_id: "&blob=.sha256".to_string(),
+            peer: "@peer=.ed25519".to_string(),
+            offset: 0,
+            size: None,
+        };
+        assert_eq!(
+            serde_json::to_value(&event)?,
+            json!({
+                "event": "fetch_started",
+                "blob_id": "&blob=.sha256",
+                "peer": "@peer=.ed25519",
+                "offset": 0,
+                "size": null
+            })
+        );
+
+        let event = BlobEvent::Stored {
+            blob_id: "&blob=.sha256".to_string(),
+        };
+        assert_eq!(
+            serde_json::to_value(&event)?,
+            json!({ "event": "stored", "blob_id": "&blob=.sha256" })
+        );
+
+        Ok(())
+    }
+}
@@ -1,23 +1,253 @@
-use kuska_ssb::{api::dto::content::TypedMessage, feed::Message};
+use kuska_ssb::feed::Message;
 use once_cell::sync::Lazy;
 use regex::Regex;
+use serde_json::Value;
+
+use crate::{
+    actors::replication::config::BlobPolicy,
+    config::{BLOB_POLICY, SECRET_CONFIG},
+    error::Error,
+    node::{BLOB_STORE, KV_STORE},
+    ssb_uri, Result,
+};
 
 /// Regex pattern used to match blob references.
 pub static BLOB_REGEX: Lazy<Regex> =
     Lazy::new(|| Regex::new(r"(&[0-9A-Za-z/+=]*.sha256)").unwrap());
 
-/// Extract blob references from post-type messages.
+/// Add the given link to the list of blob references, if it refers to a
+/// blob (either as a sigil link or as an SSB URI) which is not listed yet.
+fn push_blob_ref(refs: &mut Vec<String>, link: &str) {
+    if let Ok(key) = ssb_uri::to_sigil(link) {
+        if key.starts_with('&') && !refs.contains(&key) {
+            refs.push(key);
+        }
+    }
+}
+
+/// Extract blob references from a message: blobs linked in the text or
+/// listed in the mentions of a post, and the image set by an about message.
 pub fn extract_blob_refs(msg: &Message) -> Vec<String> {
     let mut refs = Vec::new();
 
-    let msg = serde_json::from_value(msg.content().clone());
-
-    if let Ok(TypedMessage::Post { text, .. }) = msg {
-        for cap in BLOB_REGEX.captures_iter(&text) {
-            let key = cap.get(0).unwrap().as_str().to_owned();
-            refs.push(key);
+    let content = msg.content();
+    match content.get("type").and_then(Value::as_str) {
+        Some("post") => {
+            if let Some(text) = content.get("text").and_then(Value::as_str) {
+                for cap in BLOB_REGEX.captures_iter(text) {
+                    push_blob_ref(&mut refs, cap.get(0).unwrap().as_str());
+                }
+            }
+            if let Some(mentions) = content.get("mentions").and_then(Value::as_array) {
+                for mention in mentions {
+                    if let Some(link) = mention.get("link").and_then(Value::as_str) {
+                        push_blob_ref(&mut refs, link);
+                    }
+                }
+            }
+        }
+        Some("about") => {
+            // The image is either a link or an object containing the link.
+            let image = content
+                .get("image")
+                .map(|image| image.get("link").unwrap_or(image));
+            if let Some(link) = image.and_then(Value::as_str) {
+                push_blob_ref(&mut refs, link);
+            }
         }
+        _ => (),
     }
 
     refs
 }
+
+/// Query whether the blobs referenced by the given author are wanted
+/// automatically, given the hops range of the blob fetch policy.
+pub async fn is_auto_wanted(author_id: &str) -> Result<bool> {
+    let want_hops = match BLOB_POLICY.get().and_then(|policy| policy.want_hops) {
+        Some(want_hops) => want_hops,
+        None => return Ok(true),
+    };
+
+    let local_id = &SECRET_CONFIG.get().ok_or(Error::OptionIsNone)?.public_key;
+    if author_id == local_id {
+        return Ok(true);
+    }
+
+    let db = KV_STORE.read().await;
+    let indexes = db.indexes.as_ref().ok_or(Error::Indexes)?;
+
+    Ok(indexes
+        .get_hops(local_id, want_hops)?
+        .contains_key(author_id))
+}
+
+/// Query whether the given message was posted in a channel to which the
+/// local identity is subscribed. The blobs referenced by such messages are
+/// wanted regardless of the hops range of the blob fetch policy.
+pub async fn is_in_subscribed_channel(msg: &Message) -> Result<bool> {
+    let channel = match msg.content().get("channel").and_then(Value::as_str) {
+        Some(channel) => channel.trim_start_matches('#'),
+        None => return Ok(false),
+    };
+
+    let local_id = &SECRET_CONFIG.get().ok_or(Error::OptionIsNone)?.public_key;
+    let db = KV_STORE.read().await;
+    let indexes = db.indexes.as_ref().ok_or(Error::Indexes)?;
+
+    Ok(indexes
+        .get_channel_subscriptions(local_id)?
+        .contains(channel))
+}
+
+/// Return the blob fetch policy, unless it does not restrict fetching.
+fn restrictive_policy() -> Option<&'static BlobPolicy> {
+    BLOB_POLICY.get().filter(|policy| !policy.is_unrestricted())
+}
+
+/// Query whether the given peer is denied by the blob fetch policy.
+pub fn is_denied_peer(peer_ssb_id: &str) -> bool {
+    restrictive_policy()
+        .map(|policy| policy.deny.iter().any(|id| id == peer_ssb_id))
+        .unwrap_or(false)
+}
+
+/// Query whether the blob fetch policy allows the given blob to be fetched.
+/// The size of the blob is taken into account if known.
+///
+/// Blobs referenced only by denied authors are never fetched. If a hops
+/// limit is set, at least one author referencing the blob must be within
+/// range of the local identity; if a size limit is set, at least one such
+/// author must have room left for the blob. Blobs which are not referenced
+/// by any stored message are only subject to the hops limit.
+pub async fn is_fetch_allowed(blob_id: &str, size: Option<u64>) -> Result<bool> {
+    let policy = match restrictive_policy() {
+        Some(policy) => policy,
+        None => return Ok(true),
+    };
+
+    let db = KV_STORE.read().await;
+    let indexes = db.indexes.as_ref().ok_or(Error::Indexes)?;
+
+    let referencing_authors = indexes.get_blob_authors(blob_id)?;
+    let mut authors: Vec<String> = referencing_authors
+        .iter()
+        .filter(|author| !policy.deny.contains(author))
+        .cloned()
+        .collect();
+    if authors.is_empty() && !referencing_authors.is_empty() {
+        return Ok(false);
+    }
+
+    if let Some(max_hops) = policy.max_hops {
+        let local_id = &SECRET_CONFIG.get().ok_or(Error::OptionIsNone)?.public_key;
+        let hops = indexes.get_hops(local_id, max_hops)?;
+        authors.retain(|author| hops.contains_key(author));
+        if authors.is_empty() {
+            return Ok(false);
+        }
+    }
+
+    // Unreferenced blobs cannot be attributed to any author's size limit.
+    if let Some(max_size) = policy.max_size_per_author {
+        let blob_store = BLOB_STORE.read().await;
+        for author in &authors {
+            let mut stored_size = 0;
+            for stored_blob_id in indexes.get_author_blobs(author)? {
+                if stored_blob_id != blob_id {
+                    stored_size += blob_store.size_of(&stored_blob_id)?.unwrap_or(0);
+                }
+            }
+            if stored_size + size.unwrap_or(0) <= max_size {
+                return Ok(true);
+            }
+        }
+
+        return Ok(authors.is_empty());
+    }
+
+    Ok(true)
+}
+
+/// Return the offset from which the download of the given blob resumes: the
+/// progress recorded in the blob metadata, provided the partial download
+/// still holds the recorded bytes.
+pub async fn download_offset(blob_id: &str) -> Result<u64> {
+    let recorded = KV_STORE.read().await.get_blob_offset(blob_id)?;
+    let written = BLOB_STORE.read().await.partial_len(blob_id)?;
+
+    Ok(if written >= recorded { recorded } else { 0 })
+}
+
+/// Discard the progress of the download of the given blob, along with any
+/// partially downloaded content.
+pub async fn discard_download(blob_id: &str) -> Result<()> {
+    BLOB_STORE.write().await.remove_partial(blob_id)?;
+
+    let db = KV_STORE.read().await;
+    if db.get_blob_offset(blob_id)? > 0 {
+        db.set_blob_offset(blob_id, 0)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    use crate::secret_config::SecretConfig;
+
+    #[test]
+    fn test_extract_blob_refs() -> Result<()> {
+        let keypair = SecretConfig::create().to_owned_identity()?;
+        let blob_id = "&S7+CwHM6dZ9si5Vn4ftpk/l/ldbRMqzzJos+spZbWf4=.sha256";
+        let other_blob_id = "&cFSLzGRhEc0xEE5kV8NqdWHLm5yAQ2Shk2OHNV5zHyo=.sha256";
+
+        // Blobs linked in the text and mentions of a post are extracted
+        // once each; links to feeds are ignored.
+        let post = Message::sign(
+            None,
+            &keypair,
+            json!({
+                "type": "post",
+                "text": format!("![solar]({}) and [a friend]({})", blob_id, keypair.id),
+                "mentions": [
+                    { "link": blob_id, "name": "solar" },
+                    { "link": other_blob_id, "type": "image/png" },
+                    { "link": keypair.id, "name": "a friend" },
+                ],
+            }),
+        )?;
+        assert_eq!(
+            extract_blob_refs(&post),
+            [blob_id.to_string(), other_blob_id.to_string()].to_vec()
+        );
+
+        // The image of an about message is either a link or an object.
+        let about = Message::sign(
+            Some(&post),
+            &keypair,
+            json!({ "type": "about", "about": keypair.id, "image": blob_id }),
+        )?;
+        assert_eq!(extract_blob_refs(&about), [blob_id.to_string()].to_vec());
+
+        let about = Message::sign(
+            Some(&about),
+            &keypair,
+            json!({
+                "type": "about",
+                "about": keypair.id,
+                "image": { "link": other_blob_id, "size": 1024 },
+            }),
+        )?;
+        assert_eq!(
+            extract_blob_refs(&about),
+            [other_blob_id.to_string()].to_vec()
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,182 @@
+//! Cache of the capabilities of each peer.
+//!
+//! The capabilities advertised or observed in a session with a peer (EBT
+//! support, room and tunnel support, blob slices and feed formats) are
+//! persisted in the key-value store, so that negotiation steps which are
+//! known to fail can be skipped when the peer reconnects: peers which do not
+//! support EBT are replicated with classic replication right away, peers
+//! which are not rooms are not probed for room support and blobs are not
+//! requested in slices from peers which do not support them.
+//!
+//! Peers may be upgraded, so a capability observed as unsupported is only
+//! relied upon for `CAPABILITY_TTL`, after which the negotiation is
+//! attempted again.
+
+use std::time::Duration;
+
+use log::warn;
+
+use crate::{
+    node::KV_STORE,
+    storage::kv::{CapabilityObservation, PeerCapabilities},
+    util::{now_millis, prefixed},
+};
+
+/// Time for which a capability observed as unsupported is relied upon.
+pub const CAPABILITY_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// A capability of a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    /// EBT replication (version 3).
+    Ebt,
+    /// Room server.
+    Room,
+    /// Tunneled connections through the room.
+    Tunnel,
+    /// Blob slices (`blobs.getSlice`).
+    BlobSlices,
+}
+
+/// Return the observation of the given capability, if any.
+fn observation(
+    capabilities: &PeerCapabilities,
+    capability: Capability,
+) -> Option<CapabilityObservation> {
+    match capability {
+        Capability::Ebt => capabilities.ebt,
+        Capability::Room => capabilities.room,
+        Capability::Tunnel => capabilities.tunnel,
+        Capability::BlobSlices => capabilities.blob_slices,
+    }
+}
+
+/// Return a mutable reference to the observation of the given capability.
+fn observation_mut(
+    capabilities: &mut PeerCapabilities,
+    capability: Capability,
+) -> &mut Option<CapabilityObservation> {
+    match capability {
+        Capability::Ebt => &mut capabilities.ebt,
+        Capability::Room => &mut capabilities.room,
+        Capability::Tunnel => &mut capabilities.tunnel,
+        Capability::BlobSlices => &mut capabilities.blob_slices,
+    }
+}
+
+/// Query whether the given capability is known to be unsupported at the
+/// given time (in milliseconds since the UNIX epoch).
+fn is_known_unsupported(capabilities: &PeerCapabilities, capability: Capability, now: u64) -> bool {
+    match observation(capabilities, capability) {
+        Some(observation) if !observation.supported => {
+            now.saturating_sub(observation.observed_at) < CAPABILITY_TTL.as_millis() as u64
+        }
+        _ => false,
+    }
+}
+
+/// Update the cached capabilities of the peer with the given SSB ID.
+///
+/// Failure to update the cache is logged but otherwise ignored; the cache
+/// only serves to shorten negotiation.
+async fn update(peer_ssb_id: &str, f: impl FnOnce(&mut PeerCapabilities)) {
+    let peer_ssb_id = prefixed(peer_ssb_id);
+
+    // Acquire a write lock to serialize concurrent updates to the cache.
+    let db = KV_STORE.write().await;
+    let result = db
+        .get_peer_capabilities(&peer_ssb_id)
+        .and_then(|capabilities| {
+            let mut capabilities = capabilities.unwrap_or_default();
+            f(&mut capabilities);
+            db.set_peer_capabilities(&peer_ssb_id, &capabilities)
+        });
+
+    if let Err(err) = result {
+        warn!(
+            "Failed to update the capabilities of {}: {}",
+            peer_ssb_id, err
+        )
+    }
+}
+
+/// Record whether the peer with the given SSB ID supports the given
+/// capability.
+pub async fn record(peer_ssb_id: &str, capability: Capability, supported: bool) {
+    update(peer_ssb_id, |capabilities| {
+        *observation_mut(capabilities, capability) = Some(CapabilityObservation {
+            supported,
+            observed_at: now_millis(),
+        })
+    })
+    .await
+}
+
+/// Record that the given feed format is replicated with the peer with the
+/// given SSB ID.
+pub async fn record_feed_format(peer_ssb_id: &str, format: &str) {
+    update(peer_ssb_id, |capabilities| {
+        if !capabilities
+            .feed_formats
+            .iter()
+            .any(|known| known == format)
+        {
+            capabilities.feed_formats.push(format.to_owned());
+        }
+    })
+    .await
+}
+
+/// Query whether the peer with the given SSB ID is known not to support the
+/// given capability, in which case the corresponding negotiation step is to
+/// be skipped.
+pub async fn is_unsupported(peer_ssb_id: &str, capability: Capability) -> bool {
+    match KV_STORE
+        .read()
+        .await
+        .get_peer_capabilities(&prefixed(peer_ssb_id))
+    {
+        Ok(Some(capabilities)) => is_known_unsupported(&capabilities, capability, now_millis()),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_is_known_unsupported() {
+        let ttl = CAPABILITY_TTL.as_millis() as u64;
+        let capabilities = PeerCapabilities {
+            ebt: Some(CapabilityObservation {
+                supported: false,
+                observed_at: 1_000,
+            }),
+            room: Some(CapabilityObservation {
+                supported: true,
+                observed_at: 1_000,
+            }),
+            ..PeerCapabilities::default()
+        };
+
+        assert!(is_known_unsupported(&capabilities, Capability::Ebt, 1_000));
+        assert!(!is_known_unsupported(
+            &capabilities,
+            Capability::Room,
+            1_000
+        ));
+        // Unobserved capabilities are not known to be unsupported.
+        assert!(!is_known_unsupported(
+            &capabilities,
+            Capability::BlobSlices,
+            1_000
+        ));
+        // Observations of unsupported capabilities expire.
+        assert!(!is_known_unsupported(
+            &capabilities,
+            Capability::Ebt,
+            1_000 + ttl
+        ));
+    }
+}
@@ -9,43 +9,70 @@ use kuska_ssb::{
     api::ApiCaller,
     crypto::ToSsbId,
     handshake::{async_std::BoxStream, HandshakeComplete},
-    rpc::{RpcReader, RpcWriter},
+    rpc::{RecvMsg, RpcReader, RpcWriter},
 };
 use log::{error, info, trace, warn};
 
 use crate::{
     actors::{
         muxrpc::{
-            BlobsGetHandler, BlobsWantsHandler, GetHandler, HistoryStreamHandler, RpcHandler,
-            RpcInput, WhoAmIHandler,
+            AdminHandler, BlobsGetHandler, BlobsWantsHandler, GetHandler, HistoryStreamHandler,
+            OooHandler, PeerExchangeHandler, PreviewHandler, RoomHandler, RpcHandler, RpcInput,
+            WhoAmIHandler,
         },
         network::{
+            config::TransportConfig,
             connection::ConnectionData,
             connection_manager::{ConnectionEvent, CONNECTION_MANAGER},
+            connection_scheduler::PenaltyRequest,
+            reputation,
+            stats::MeteredStream,
         },
+        replication::{direction, journal, quirks},
     },
     broker::{
         ActorEndpoint, BrokerEvent, BrokerMessage, ChMsgRecv, ChSigRecv, Destination, BROKER,
     },
     error::Error,
+    storage::kv::ReplicationEvent,
     Result,
 };
 
+#[cfg(feature = "fault-injection")]
+use crate::actors::network::fault::FaultyStream;
+
 pub async fn actor(connection_data: ConnectionData) -> Result<()> {
     let mut ch_broker = BROKER.lock().await.create_sender();
 
-    // Attempt replication.
-    let replication_result = actor_inner(connection_data.to_owned()).await;
-
     let peer_pk = connection_data
         .peer_public_key
         .ok_or(Error::OptionIsNone)?
         .to_ssb_id();
 
+    journal::record(
+        &peer_pk,
+        ReplicationEvent::SessionStarted {
+            protocol: "classic".to_string(),
+        },
+    )
+    .await;
+    reputation::record_session(&peer_pk);
+
+    // Attempt replication.
+    let replication_result = actor_inner(connection_data.to_owned()).await;
+
     match replication_result {
         Ok(connection_data) => {
             info!("👋 finished replication with {}", peer_pk);
 
+            journal::record(
+                &peer_pk,
+                ReplicationEvent::SessionEnded {
+                    protocol: "classic".to_string(),
+                },
+            )
+            .await;
+
             // Send 'disconnecting' connection event message via the broker.
             ch_broker
                 .send(BrokerEvent::new(
@@ -61,6 +88,15 @@ pub async fn actor(connection_data: ConnectionData) -> Result<()> {
                 "💀 replication with {} terminated with error {:?}",
                 peer_pk, err
             );
+            reputation::record_error(&peer_pk);
+
+            journal::record(
+                &peer_pk,
+                ReplicationEvent::Error {
+                    message: err.to_string(),
+                },
+            )
+            .await;
 
             // Send 'error' connection event message via the broker.
             ch_broker
@@ -94,8 +130,11 @@ pub async fn actor_inner(connection_data: ConnectionData) -> Result<ConnectionDa
     // replication loop after n consecutive idle seconds.
     let connection_idle_timeout_limit = CONNECTION_MANAGER.read().await.idle_timeout_limit;
 
-    let stream_reader = connection_data.stream.clone().ok_or(Error::OptionIsNone)?;
-    let stream_writer = connection_data.stream.clone().ok_or(Error::OptionIsNone)?;
+    // Count the bytes exchanged with the peer in the network statistics.
+    let stream_reader =
+        MeteredStream::new(connection_data.stream.clone().ok_or(Error::OptionIsNone)?);
+    let stream_writer =
+        MeteredStream::new(connection_data.stream.clone().ok_or(Error::OptionIsNone)?);
     let handshake = connection_data
         .handshake
         .clone()
@@ -128,12 +167,17 @@ async fn replication_loop<R: Read + Unpin + Send + Sync, W: Write + Unpin + Send
     connection_idle_timeout_limit: u8,
 ) -> Result<()> {
     // Parse the peer public key from the handshake.
-    let peer_ssb_id = handshake.peer_pk.to_ssb_id();
+    let peer_public_key = handshake.peer_pk;
+    let peer_ssb_id = peer_public_key.to_ssb_id();
 
     // Instantiate a box stream and split it into reader and writer streams.
+    let buffer_size = TransportConfig::current().box_stream_buffer_size;
     let (box_stream_read, box_stream_write) =
-        BoxStream::from_handshake(stream_reader, stream_writer, handshake, 0x8000)
+        BoxStream::from_handshake(stream_reader, stream_writer, handshake, buffer_size)
             .split_read_write();
+    // Wrap the writer so that faults can be injected into outbound packets.
+    #[cfg(feature = "fault-injection")]
+    let box_stream_write = FaultyStream::new(box_stream_write, &peer_ssb_id);
 
     // Instantiate RPC reader and writer using the box streams.
     let rpc_reader = RpcReader::new(box_stream_read);
@@ -141,11 +185,19 @@ async fn replication_loop<R: Read + Unpin + Send + Sync, W: Write + Unpin + Send
     let mut api = ApiCaller::new(rpc_writer);
 
     // Instantiate the MUXRPC handlers.
-    let mut history_stream_handler = HistoryStreamHandler::new(actor_id);
+    let mut history_stream_handler = HistoryStreamHandler::new(actor_id)
+        .peer(&peer_ssb_id)
+        .legacy_quirks(quirks::is_legacy_peer(&peer_ssb_id))
+        .direction(direction::direction(&peer_ssb_id));
     let mut whoami_handler = WhoAmIHandler::new(&peer_ssb_id);
     let mut get_handler = GetHandler::default();
     let mut blobs_get_handler = BlobsGetHandler::default();
-    let mut blobs_wants_handler = BlobsWantsHandler::default();
+    let mut blobs_wants_handler = BlobsWantsHandler::new(actor_id, &peer_ssb_id);
+    let mut peer_exchange_handler = PeerExchangeHandler::new(&peer_ssb_id);
+    let mut room_handler = RoomHandler::new(&peer_ssb_id);
+    let mut admin_handler = AdminHandler::new(&peer_ssb_id);
+    let mut preview_handler = PreviewHandler::new(&peer_ssb_id);
+    let mut ooo_handler = OooHandler::default();
 
     let mut handlers: Vec<&mut dyn RpcHandler<W>> = vec![
         &mut history_stream_handler,
@@ -153,6 +205,11 @@ async fn replication_loop<R: Read + Unpin + Send + Sync, W: Write + Unpin + Send
         &mut get_handler,
         &mut blobs_get_handler,
         &mut blobs_wants_handler,
+        &mut peer_exchange_handler,
+        &mut room_handler,
+        &mut admin_handler,
+        &mut preview_handler,
+        &mut ooo_handler,
     ];
 
     // Create channel to send messages to broker.
@@ -174,6 +231,10 @@ async fn replication_loop<R: Read + Unpin + Send + Sync, W: Write + Unpin + Send
     // activity (ie. no incoming packets or messages).
     let mut timer_counter = 0;
 
+    // Set once the peer has been penalized for a failed request, so that the
+    // scheduler is only notified once per session.
+    let mut penalized = false;
+
     trace!(target: "replication-loop", "initiating replication loop with: {}", peer_ssb_id);
 
     loop {
@@ -223,6 +284,29 @@ async fn replication_loop<R: Read + Unpin + Send + Sync, W: Write + Unpin + Send
                 }
                 Err(err) => {
                     error!("handler {} failed with {:?}", handler.name(), err);
+
+                    // Answer a failed request of the peer with an error
+                    // response rather than leaving it unanswered.
+                    if let RpcInput::Network(req_no, RecvMsg::RpcRequest(req)) = &input {
+                        api.rpc()
+                            .send_error(*req_no, req.rpc_type, &err.to_string())
+                            .await?;
+                    }
+
+                    // Refrain from redialing the peer for a while.
+                    if !penalized {
+                        reputation::record_error(&peer_ssb_id);
+                        ch_broker
+                            .send(BrokerEvent::new(
+                                Destination::Broadcast,
+                                BrokerMessage::Penalize(PenaltyRequest(peer_public_key)),
+                            ))
+                            .await?;
+                        penalized = true;
+                    }
+
+                    handled = true;
+                    break;
                 }
             }
         }
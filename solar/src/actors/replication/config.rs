@@ -10,6 +10,152 @@ use serde::{Deserialize, Serialize};
 
 use crate::{error::Error, Result};
 
+/// Policy controlling which blobs are fetched, and for and from which peers.
+/// No restrictions apply by default.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct BlobPolicy {
+    /// Only fetch blobs referenced by feeds within the given number of hops
+    /// from the local identity in the follow graph.
+    #[serde(default)]
+    pub max_hops: Option<usize>,
+
+    /// Automatically want the blobs referenced by the messages of feeds
+    /// within the given number of hops from the local identity (default: the
+    /// blobs referenced by all replicated feeds are wanted).
+    #[serde(default)]
+    pub want_hops: Option<usize>,
+
+    /// Stop fetching blobs referenced by an author once the blobs stored for
+    /// that author reach the given total size in bytes.
+    #[serde(default)]
+    pub max_size_per_author: Option<u64>,
+
+    /// List of public keys of denied peers. Wants of these peers are not
+    /// forwarded, blobs are not fetched from them and blobs referenced only
+    /// by them are not fetched.
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+impl BlobPolicy {
+    /// Query whether the policy does not restrict blob fetching at all.
+    pub fn is_unrestricted(&self) -> bool {
+        self.max_hops.is_none() && self.max_size_per_author.is_none() && self.deny.is_empty()
+    }
+}
+
+/// Strictness of message validation.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Strictness {
+    /// Reject messages failing any check.
+    Strict,
+    /// Tolerate messages failing the size or schema checks.
+    Lenient,
+}
+
+/// Policy controlling the strictness of message validation for each source
+/// of messages.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ValidationPolicy {
+    /// Strictness applied to messages published by the local identity
+    /// (default: strict).
+    #[serde(default = "default_local_strictness")]
+    pub local: Strictness,
+
+    /// Strictness applied to replicated messages (default: lenient).
+    #[serde(default = "default_replicated_strictness")]
+    pub replicated: Strictness,
+}
+
+fn default_local_strictness() -> Strictness {
+    Strictness::Strict
+}
+
+fn default_replicated_strictness() -> Strictness {
+    Strictness::Lenient
+}
+
+impl Default for ValidationPolicy {
+    fn default() -> Self {
+        Self {
+            local: default_local_strictness(),
+            replicated: default_replicated_strictness(),
+        }
+    }
+}
+
+/// Action taken on the messages of an author in excess of the ingest rate
+/// limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExcessAction {
+    /// Drop the messages, which are replicated again later on.
+    #[default]
+    Drop,
+    /// Hold the messages in memory and store them once the limit allows.
+    Queue,
+}
+
+/// Policy limiting the rate at which the messages of distant authors are
+/// accepted, protecting storage from floods of messages. No limits apply by
+/// default.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct IngestPolicy {
+    /// Maximum number of messages of an author accepted per hour (default:
+    /// unlimited).
+    #[serde(default)]
+    pub max_messages_per_hour: Option<usize>,
+
+    /// Authors within the given number of hops from the local identity in
+    /// the follow graph are exempt from the limit (default: 1).
+    #[serde(default = "default_exempt_hops")]
+    pub exempt_hops: usize,
+
+    /// Action taken on the messages in excess of the limit (default: drop).
+    #[serde(default)]
+    pub excess: ExcessAction,
+}
+
+fn default_exempt_hops() -> usize {
+    1
+}
+
+impl Default for IngestPolicy {
+    fn default() -> Self {
+        Self {
+            max_messages_per_hour: None,
+            exempt_hops: default_exempt_hops(),
+            excess: ExcessAction::default(),
+        }
+    }
+}
+
+/// Direction in which messages are replicated with a peer.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReplicationDirection {
+    /// Serve local data to the peer and accept its data.
+    #[default]
+    Both,
+    /// Serve local data to the peer without accepting its data.
+    Push,
+    /// Accept the data of the peer without serving local data to it.
+    Pull,
+}
+
+impl ReplicationDirection {
+    /// Query whether local data is served to the peer.
+    pub fn pushes(&self) -> bool {
+        *self != ReplicationDirection::Pull
+    }
+
+    /// Query whether the data of the peer is accepted.
+    pub fn pulls(&self) -> bool {
+        *self != ReplicationDirection::Push
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct ReplicationConfig {
     /// Resync the local database by requesting the local feed from peers
@@ -22,9 +168,68 @@ pub struct ReplicationConfig {
     #[serde(skip)]
     pub selective: bool,
 
+    /// Replicate every feed offered by connected peers, regardless of the
+    /// follow graph, as an archive or mirror (default: false).
+    #[serde(skip)]
+    pub mirror: bool,
+
+    /// List of public keys of legacy pubs. Known protocol quirks of old
+    /// ssb-server pubs are tolerated when replicating with these peers.
+    #[serde(default)]
+    pub legacy_peers: Vec<String>,
+
+    /// List of public keys of admins. These peers may call the `admin`
+    /// MUXRPC methods to manage the node remotely (default: none).
+    #[serde(default)]
+    pub admins: Vec<String>,
+
+    /// List of public keys of feeds whose messages are traced. The peer
+    /// from which each message of these feeds is received, and the peers to
+    /// which it is sent, are recorded along with the time (default: none).
+    #[serde(default)]
+    pub traced_feeds: Vec<String>,
+
+    /// Maximum number of bytes of consecutive messages of a feed pushed to a
+    /// peer at once during EBT replication (default: 65536). Messages are
+    /// pushed one at a time if set to 0.
+    #[serde(default = "default_push_batch_bytes")]
+    pub push_batch_bytes: usize,
+
+    /// Send known peers only the vector clock entries which changed since
+    /// the previous EBT session, and merge the clocks received from peers
+    /// into the clocks received before (default: false). Meant for peers
+    /// which also keep the clocks received across sessions.
+    #[serde(default)]
+    pub incremental_clocks: bool,
+
     /// List of peers to be replicated. Each entry includes a public key and
     /// a URL. The URL contains the host and port of the peer's node.
     pub peers: HashMap<String, String>,
+
+    /// Direction of replication with specific peers, keyed by public key
+    /// (default: both directions for every peer). Push-only peers are served
+    /// local data without their data being accepted (for example, archive
+    /// mirrors); pull-only peers are the reverse (for example, one-way
+    /// bridges).
+    #[serde(default)]
+    pub directions: HashMap<String, ReplicationDirection>,
+
+    /// Blob fetch policy.
+    #[serde(default)]
+    pub blobs: BlobPolicy,
+
+    /// Message validation policy.
+    #[serde(default)]
+    pub validation: ValidationPolicy,
+
+    /// Ingest rate limits.
+    #[serde(default)]
+    pub ingest: IngestPolicy,
+}
+
+/// Default byte budget of a batch of messages pushed during EBT replication.
+fn default_push_batch_bytes() -> usize {
+    64 * 1024
 }
 
 impl Default for ReplicationConfig {
@@ -32,7 +237,17 @@ impl Default for ReplicationConfig {
         Self {
             resync: false,
             selective: true,
+            mirror: false,
+            legacy_peers: Vec::new(),
+            admins: Vec::new(),
+            traced_feeds: Vec::new(),
+            push_batch_bytes: default_push_batch_bytes(),
+            incremental_clocks: false,
             peers: HashMap::default(),
+            directions: HashMap::new(),
+            blobs: BlobPolicy::default(),
+            validation: ValidationPolicy::default(),
+            ingest: IngestPolicy::default(),
         }
     }
 }
@@ -48,24 +263,43 @@ impl ReplicationConfig {
         Ok(toml::from_str::<ReplicationConfig>(serialized_config)?)
     }
 
+    /// Validate a public key listed in the replication config file.
+    fn validate_public_key(public_key: &str) -> Result<()> {
+        // Ensure that each public key is without a prefix.
+        if public_key.starts_with('@') {
+            return Err(Error::Config(format!(
+                "Peer public key in replication.toml file must not include the '@' prefix: {}",
+                public_key
+            )));
+        }
+
+        // Ensure that each public key has a suffix.
+        if !public_key.ends_with(".ed25519") {
+            return Err(Error::Config(format!(
+                "Peer public key in replication.toml file must include the '.ed25519' suffix: {}",
+                public_key
+            )));
+        }
+
+        // Ensure the public key is valid (base64, for example).
+        //
+        // We run the prefix and suffix checks separately (above) because
+        // the error message returned by `.to_ed25519_pk` does not always
+        // provide clear, actionable feedback.
+        if let Err(err) = public_key.to_ed25519_pk() {
+            return Err(Error::Config(format!(
+                "Peer public key {} is invalid: {}",
+                public_key, err
+            )));
+        }
+
+        Ok(())
+    }
+
     /// Validate the contents of the replication config file.
     fn validate(&self) -> Result<()> {
         for (public_key, addr) in self.peers.iter() {
-            // Ensure that each public key is without a prefix.
-            if public_key.starts_with('@') {
-                return Err(Error::Config(format!(
-                    "Peer public key in replication.toml file must not include the '@' prefix: {}",
-                    public_key
-                )));
-            }
-
-            // Ensure that each public key has a suffix.
-            if !public_key.ends_with(".ed25519") {
-                return Err(Error::Config(format!(
-                    "Peer public key in replication.toml file must include the '.ed25519' suffix: {}",
-                    public_key
-                )));
-            }
+            Self::validate_public_key(public_key)?;
 
             // Ensure that the address is not a TCP URL.
             if !addr.is_empty() & addr.starts_with("tcp://") {
@@ -74,18 +308,17 @@ impl ReplicationConfig {
                     addr
                 )));
             }
+        }
 
-            // Ensure the public key is valid (base64, for example).
-            //
-            // We run the prefix and suffix checks separately (above) because
-            // the error message returned by `.to_ed25519_pk` does not always
-            // provide clear, actionable feedback.
-            if let Err(err) = public_key.to_ed25519_pk() {
-                return Err(Error::Config(format!(
-                    "Peer public key {} is invalid: {}",
-                    public_key, err
-                )));
-            }
+        for public_key in self
+            .legacy_peers
+            .iter()
+            .chain(self.admins.iter())
+            .chain(self.traced_feeds.iter())
+            .chain(self.directions.keys())
+            .chain(self.blobs.deny.iter())
+        {
+            Self::validate_public_key(public_key)?;
         }
 
         Ok(())
@@ -0,0 +1,85 @@
+//! Replication direction of peers.
+//!
+//! Peers listed under `directions` in `replication.toml` may be replicated
+//! in one direction only:
+//!
+//! - `push`: local data is served to the peer, but its data is not accepted
+//!   (for example, an archive mirror). The local vector clock is sent to the
+//!   peer with the receive flag of every feed unset, no history streams are
+//!   requested from the peer and any messages it sends are dropped.
+//! - `pull`: the data of the peer is accepted, but local data is not served
+//!   to it (for example, a one-way bridge). No messages are forwarded to the
+//!   peer over EBT and its history stream requests are refused.
+//!
+//! Other peers are replicated in both directions.
+
+use crate::{
+    actors::replication::{
+        config::ReplicationDirection,
+        ebt::{clock, VectorClock},
+    },
+    config::REPLICATION_DIRECTIONS,
+    util::prefixed,
+    Result,
+};
+
+/// Return the direction in which the given peer is replicated.
+pub fn direction(peer_ssb_id: &str) -> ReplicationDirection {
+    let peer_ssb_id = prefixed(peer_ssb_id);
+
+    REPLICATION_DIRECTIONS
+        .get()
+        .and_then(|directions| directions.get(&peer_ssb_id).copied())
+        .unwrap_or_default()
+}
+
+/// Return the given vector clock with the receive flag of every replicated
+/// feed unset, so that the peer to which it is sent does not push messages
+/// in return.
+pub fn notes_only(clock: &VectorClock) -> Result<VectorClock> {
+    clock
+        .iter()
+        .map(|(feed_id, value)| {
+            let value = match clock::decode(*value)? {
+                (true, _receive, seq) => clock::encode(true, Some(false), seq)?,
+                _ => *value,
+            };
+
+            Ok((feed_id.to_owned(), value))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_notes_only() -> Result<()> {
+        let mut local_clock = VectorClock::new();
+        local_clock.insert(
+            "@a=.ed25519".to_string(),
+            clock::encode(true, Some(true), Some(3))?,
+        );
+        local_clock.insert("@b=.ed25519".to_string(), clock::encode(false, None, None)?);
+
+        let notes = notes_only(&local_clock)?;
+        assert_eq!(
+            clock::decode(notes["@a=.ed25519"])?,
+            (true, Some(false), Some(3))
+        );
+        assert_eq!(notes["@b=.ed25519"], local_clock["@b=.ed25519"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_direction() {
+        assert!(ReplicationDirection::Both.pushes() && ReplicationDirection::Both.pulls());
+        assert!(ReplicationDirection::Push.pushes() && !ReplicationDirection::Push.pulls());
+        assert!(!ReplicationDirection::Pull.pushes() && ReplicationDirection::Pull.pulls());
+
+        // Peers without a configured direction are replicated both ways.
+        assert_eq!(direction("@unknown=.ed25519"), ReplicationDirection::Both);
+    }
+}
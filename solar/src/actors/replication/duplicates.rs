@@ -0,0 +1,66 @@
+//! Detect messages which have already been stored.
+//!
+//! Overlapping EBT and classic replication sessions frequently deliver the
+//! same message more than once. Since a feed is append-only, a message is
+//! uniquely identified by its author and sequence number; both can be read
+//! from the raw response with a plain JSON parse, which is far cheaper than
+//! the signature validation performed when deserializing a message. Stored
+//! messages can therefore be skipped before validation and indexing.
+
+use log::trace;
+use serde_json::Value;
+
+use crate::{node::KV_STORE, Result};
+
+/// Read the author and sequence number from a raw message value or KVT
+/// without validating it.
+pub fn peek_author_and_sequence(res: &[u8]) -> Option<(String, u64)> {
+    let msg: Value = serde_json::from_slice(res).ok()?;
+    // Unwrap the message value if the message was sent as a KVT.
+    let msg = msg.get("value").unwrap_or(&msg);
+
+    let author = msg.get("author")?.as_str()?.to_owned();
+    let sequence = msg.get("sequence")?.as_u64()?;
+
+    Some((author, sequence))
+}
+
+/// Check whether the message contained in the given raw response has
+/// already been stored. Responses which cannot be parsed are never
+/// considered duplicates, leaving them to be rejected by validation.
+pub async fn is_stored(res: &[u8]) -> Result<bool> {
+    if let Some((author, sequence)) = peek_author_and_sequence(res) {
+        if KV_STORE.read().await.contains_msg(&author, sequence)? {
+            trace!(target: "replication", "Skipping duplicate message {} from {}", sequence, author);
+
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_peek_author_and_sequence() {
+        let author = "@HEqy940T6uB+T+d9Jaa58aNfRzLx9eRWqkZljBmnkmk=.ed25519";
+
+        let value = format!(r#"{{"author":"{author}","sequence":3,"content":{{}}}}"#);
+        assert_eq!(
+            peek_author_and_sequence(value.as_bytes()),
+            Some((author.to_string(), 3))
+        );
+
+        let kvt = format!(r#"{{"key":"%abc.sha256","value":{value},"timestamp":0}}"#);
+        assert_eq!(
+            peek_author_and_sequence(kvt.as_bytes()),
+            Some((author.to_string(), 3))
+        );
+
+        assert_eq!(peek_author_and_sequence(br#"{"sequence":3}"#), None);
+        assert_eq!(peek_author_and_sequence(b"not json"), None);
+    }
+}
@@ -0,0 +1,171 @@
+//! Batching of vector clock notes and pushed messages.
+//!
+//! A burst of appended messages results in one note update per message. The
+//! updates are collected per session and sent as a single vector clock once
+//! no update has been queued for `NOTE_BATCH_WINDOW`, or at the latest
+//! `NOTE_BATCH_MAX_DELAY` after the first update of the batch.
+//!
+//! Likewise, the messages requested by a peer are pushed in batches of
+//! consecutive messages of a feed, bounded by a byte budget, rather than one
+//! at a time.
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use crate::actors::{
+    network::connection::ConnectionId,
+    replication::ebt::{EncodedClockValue, EncodedMessage, VectorClock},
+};
+
+/// Time without note updates after which a batch is sent.
+pub const NOTE_BATCH_WINDOW: Duration = Duration::from_millis(100);
+
+/// Maximum time a note update is held back for.
+pub const NOTE_BATCH_MAX_DELAY: Duration = Duration::from_secs(1);
+
+/// Note updates waiting to be sent, keyed by the connection ID of the
+/// session.
+#[derive(Debug, Default)]
+pub struct NoteBatch {
+    notes: HashMap<ConnectionId, VectorClock>,
+    /// Time at which the first update of the batch was queued.
+    first_queued: Option<Instant>,
+    /// Time at which the latest update of the batch was queued.
+    last_queued: Option<Instant>,
+}
+
+impl NoteBatch {
+    /// Queue a note update for the given session, replacing any queued
+    /// update for the same feed.
+    pub fn queue(
+        &mut self,
+        connection_id: ConnectionId,
+        feed_id: &str,
+        value: EncodedClockValue,
+        now: Instant,
+    ) {
+        self.notes
+            .entry(connection_id)
+            .or_default()
+            .insert(feed_id.to_owned(), value);
+        self.first_queued.get_or_insert(now);
+        self.last_queued = Some(now);
+    }
+
+    /// Query whether the batch is due to be sent.
+    pub fn is_due(&self, now: Instant) -> bool {
+        match (self.first_queued, self.last_queued) {
+            (Some(first), Some(last)) => {
+                now.duration_since(last) >= NOTE_BATCH_WINDOW
+                    || now.duration_since(first) >= NOTE_BATCH_MAX_DELAY
+            }
+            _ => false,
+        }
+    }
+
+    /// Take the queued note updates, leaving the batch empty.
+    pub fn take(&mut self) -> HashMap<ConnectionId, VectorClock> {
+        self.first_queued = None;
+        self.last_queued = None;
+        std::mem::take(&mut self.notes)
+    }
+
+    /// Drop the queued note updates of the given session.
+    pub fn remove_session(&mut self, connection_id: ConnectionId) {
+        self.notes.remove(&connection_id);
+    }
+}
+
+/// Split the given messages into batches of consecutive messages of the
+/// same feed, each batch serializing to at most `budget` bytes (a message
+/// larger than the budget forms a batch of its own). Each message forms a
+/// batch of its own if the budget is 0.
+pub fn batch_messages(msgs: Vec<EncodedMessage>, budget: usize) -> Vec<Vec<EncodedMessage>> {
+    let mut batches: Vec<Vec<EncodedMessage>> = Vec::new();
+    let mut batch_bytes = 0;
+
+    for msg in msgs {
+        let msg_bytes = msg.size();
+        let fits = batches.last().map_or(false, |batch| {
+            batch_bytes + msg_bytes <= budget && batch[0].author() == msg.author()
+        });
+
+        if fits {
+            batch_bytes += msg_bytes;
+            batches.last_mut().unwrap().push(msg);
+        } else {
+            batch_bytes = msg_bytes;
+            batches.push(vec![msg]);
+        }
+    }
+
+    batches
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    fn test_batch_messages() {
+        let msg = |author: &str, sequence: u64| {
+            EncodedMessage::from_value(&json!({ "author": author, "sequence": sequence })).unwrap()
+        };
+        let msg_bytes = msg("@a", 1).size();
+        let msgs = vec![msg("@a", 1), msg("@a", 2), msg("@a", 3), msg("@b", 1)];
+
+        // Messages of different feeds are never batched together.
+        let batches = batch_messages(msgs.clone(), usize::MAX);
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[0].len(), 3);
+        assert_eq!(batches[1], vec![msg("@b", 1)]);
+
+        // Batches are bounded by the budget.
+        let batches = batch_messages(msgs.clone(), msg_bytes * 2);
+        let sizes: Vec<usize> = batches.iter().map(Vec::len).collect();
+        assert_eq!(sizes, vec![2, 1, 1]);
+
+        // Messages are not batched with a budget of 0.
+        assert_eq!(batch_messages(msgs, 0).len(), 4);
+    }
+
+    #[test]
+    fn test_note_batch() {
+        let mut batch = NoteBatch::default();
+        let start = Instant::now();
+        assert!(!batch.is_due(start));
+
+        // Updates of the same feed are merged.
+        batch.queue(1, "@a", 2, start);
+        batch.queue(1, "@a", 4, start);
+        batch.queue(1, "@b", 6, start);
+        batch.queue(2, "@a", 4, start);
+        assert!(!batch.is_due(start + NOTE_BATCH_WINDOW / 2));
+
+        // The window is reset by each update.
+        batch.queue(2, "@b", 6, start + NOTE_BATCH_WINDOW / 2);
+        assert!(!batch.is_due(start + NOTE_BATCH_WINDOW));
+        assert!(batch.is_due(start + NOTE_BATCH_WINDOW * 3 / 2));
+
+        let notes = batch.take();
+        assert_eq!(notes.len(), 2);
+        assert_eq!(notes[&1].len(), 2);
+        assert_eq!(notes[&1]["@a"], 4);
+        assert!(!batch.is_due(start + NOTE_BATCH_MAX_DELAY));
+
+        // A steady stream of updates is sent after the maximum delay.
+        let mut now = start;
+        while now < start + NOTE_BATCH_MAX_DELAY {
+            batch.queue(1, "@a", 8, now);
+            assert!(!batch.is_due(now));
+            now += NOTE_BATCH_WINDOW / 2;
+        }
+        assert!(batch.is_due(now));
+
+        batch.remove_session(1);
+        assert!(batch.take().is_empty());
+    }
+}
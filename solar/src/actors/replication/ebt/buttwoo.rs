@@ -0,0 +1,89 @@
+//! Replication of buttwoo feeds.
+//!
+//! Buttwoo feeds are replicated like Bendy Butt feeds, on an EBT replicate
+//! stream of their own (requested with the `buttwoo-v1` format): each peer
+//! sends the vector clock of its replicated buttwoo feeds and then sends the
+//! BIPF-encoded messages which the other peer is missing, as binary packets.
+//! Received messages are validated before being stored.
+//!
+//! The buttwoo feeds which are replicated are those added as subfeeds by
+//! stored Bendy Butt messages.
+
+use log::debug;
+use solar_core::buttwoo::ButtwooMessage;
+
+use crate::{
+    actors::replication::{
+        ebt::{clock, VectorClock},
+        trace,
+    },
+    node::KV_STORE,
+    storage::kv::TraceDirection,
+    Result,
+};
+
+/// Return the vector clock of the replicated buttwoo feeds.
+pub async fn local_clock() -> Result<VectorClock> {
+    let mut clock = VectorClock::new();
+
+    for (feed_id, sequence) in KV_STORE.read().await.get_buttwoo_feeds()? {
+        clock.insert(feed_id, clock::encode(true, Some(true), Some(sequence))?);
+    }
+
+    Ok(clock)
+}
+
+/// Return the BIPF-encoded messages of the replicated buttwoo feeds which
+/// are missing from the given vector clock of a peer, for the feeds which
+/// the peer wishes to receive.
+pub async fn missing_messages(peer_clock: &VectorClock) -> Result<Vec<Vec<u8>>> {
+    let db = KV_STORE.read().await;
+    let mut messages = Vec::new();
+
+    for (feed_id, value) in peer_clock {
+        let peer_seq = match clock::decode(*value)? {
+            (true, Some(true), Some(sequence)) => sequence,
+            _ => continue,
+        };
+
+        // Feeds which are not replicated (or not buttwoo feeds) are
+        // skipped.
+        let latest_seq = match db.get_latest_buttwoo_seq(feed_id) {
+            Ok(Some(latest_seq)) => latest_seq,
+            _ => continue,
+        };
+
+        for sequence in peer_seq + 1..=latest_seq {
+            if let Some(msg) = db.get_buttwoo_msg(feed_id, sequence)? {
+                messages.push(msg);
+            }
+        }
+    }
+
+    Ok(messages)
+}
+
+/// Validate and store the given BIPF-encoded message received from the peer
+/// with the given SSB ID. Messages which are already stored are ignored.
+pub async fn receive(raw: &[u8], peer_ssb_id: &str) -> Result<Option<ButtwooMessage>> {
+    let msg = KV_STORE.write().await.append_buttwoo_msg(raw)?;
+
+    if let Some(msg) = &msg {
+        debug!(
+            "Stored message {} of {} received from {}",
+            msg.sequence,
+            msg.author_id(),
+            peer_ssb_id
+        );
+        trace::record(
+            &msg.author_id(),
+            msg.sequence,
+            peer_ssb_id,
+            TraceDirection::Received,
+            "ebt",
+        )
+        .await;
+    }
+
+    Ok(msg)
+}
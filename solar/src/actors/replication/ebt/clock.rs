@@ -1,15 +1,76 @@
-use std::{collections::HashMap, convert::TryInto};
+use std::collections::HashMap;
 
 use kuska_ssb::api::dto::content::SsbId;
 
-use crate::Result;
+pub use solar_core::clock::EncodedClockValue;
 
-/// The encoded vector clock value.
-pub type EncodedClockValue = i64;
+use crate::{actors::replication::ebt::EncodedClock, Result};
 
 /// A vector clock which maps an SSB ID to an encoded vector clock value.
 pub type VectorClock = HashMap<SsbId, EncodedClockValue>;
 
+/// The local vector clock, sent to peers at the start of every EBT session.
+///
+/// The clock is updated in place as replicated feeds change, and its
+/// serialization is cached until the next change, so that sessions started
+/// in the meantime share it rather than serializing the clock again.
+#[derive(Debug, Default)]
+pub struct LocalClock {
+    clock: VectorClock,
+    encoded: Option<EncodedClock>,
+}
+
+impl LocalClock {
+    /// Return the vector clock.
+    pub fn clock(&self) -> &VectorClock {
+        &self.clock
+    }
+
+    /// Replace the vector clock.
+    pub fn set(&mut self, clock: VectorClock) {
+        self.clock = clock;
+        self.encoded = None;
+    }
+
+    /// Query whether the feed represented by the given SSB ID is in the
+    /// clock.
+    pub fn contains(&self, ssb_id: &SsbId) -> bool {
+        self.clock.contains_key(ssb_id)
+    }
+
+    /// Set the value of the feed represented by the given SSB ID.
+    pub fn insert(&mut self, ssb_id: &SsbId, value: EncodedClockValue) {
+        if self.clock.insert(ssb_id.to_owned(), value) != Some(value) {
+            self.encoded = None;
+        }
+    }
+
+    /// Remove the feed represented by the given SSB ID from the clock.
+    /// Returns `false` if the feed was not in the clock.
+    pub fn remove(&mut self, ssb_id: &SsbId) -> bool {
+        let removed = self.clock.remove(ssb_id).is_some();
+        if removed {
+            self.encoded = None;
+        }
+
+        removed
+    }
+
+    /// Return the serialized clock, serializing it if it changed since it
+    /// was last serialized.
+    pub fn encode(&mut self) -> Result<EncodedClock> {
+        match &self.encoded {
+            Some(encoded) => Ok(encoded.clone()),
+            None => {
+                let encoded = EncodedClock::new(self.clock.clone())?;
+                self.encoded = Some(encoded.clone());
+
+                Ok(encoded)
+            }
+        }
+    }
+}
+
 /// Decode a value from a control message (aka. note), returning the values
 /// of the replicate flag, receive flag and sequence.
 ///
@@ -21,23 +82,7 @@ pub type VectorClock = HashMap<SsbId, EncodedClockValue>;
 ///
 /// The sequence refers to a sequence number of the referenced feed.
 pub fn decode(value: EncodedClockValue) -> Result<(bool, Option<bool>, Option<u64>)> {
-    let (replicate_flag, receive_flag, sequence) = if value < 0 {
-        // Replicate flag is `false`.
-        // Peer does not wish to receive messages for this feed.
-        (false, None, None)
-    } else {
-        // Get the least-significant bit (aka. rightmost bit).
-        let lsb = value & 1;
-        // Set the receive flag value.
-        let receive_flag = lsb == 0;
-        // Perform a single bit arithmetic right shift to obtain the sequence
-        // number.
-        let sequence: u64 = (value >> 1).try_into()?;
-
-        (true, Some(receive_flag), Some(sequence))
-    };
-
-    Ok((replicate_flag, receive_flag, sequence))
+    Ok(solar_core::clock::decode(value)?)
 }
 
 /// Encode a replicate flag, receive flag and sequence number as a control
@@ -57,55 +102,37 @@ pub fn encode(
     receive_flag: Option<bool>,
     sequence: Option<u64>,
 ) -> Result<EncodedClockValue> {
-    let value = if replicate_flag {
-        // Perform a single bit arithmetic left shift.
-        let mut signed: i64 = (sequence.unwrap() << 1).try_into()?;
-        // Get the least-significant bit (aka. rightmost bit).
-        let lsb = signed & 1;
-        // Set the least-significant bit based on the value of the receive flag.
-        if let Some(_flag @ true) = receive_flag {
-            // Set the LSB to 0.
-            signed |= 0 << lsb;
-        } else {
-            // Set the LSB to 1.
-            signed |= 1 << lsb;
-        }
-        signed
-    } else {
-        -1
-    };
-
-    Ok(value)
+    Ok(solar_core::clock::encode(
+        replicate_flag,
+        receive_flag,
+        sequence,
+    )?)
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
 
-    const VALUES: [i64; 7] = [-1, 0, 1, 2, 3, 12, 450];
-    const NOTES: [(bool, std::option::Option<bool>, std::option::Option<u64>); 7] = [
-        (false, None, None),
-        (true, Some(true), Some(0)),
-        (true, Some(false), Some(0)),
-        (true, Some(true), Some(1)),
-        (true, Some(false), Some(1)),
-        (true, Some(true), Some(6)),
-        (true, Some(true), Some(225)),
-    ];
-
     #[test]
-    fn test_decode() {
-        VALUES
-            .iter()
-            .zip(NOTES)
-            .for_each(|(value, note)| assert_eq!(decode(*value).unwrap(), note));
-    }
+    fn test_local_clock_encoding() -> Result<()> {
+        let feed_id = "@HEqy940T6uB+T+d9Jaa58aNfRzLx9eRWqkZljBmnkmk=.ed25519".to_string();
 
-    #[test]
-    fn test_encode() {
-        VALUES
-            .iter()
-            .zip(NOTES)
-            .for_each(|(value, note)| assert_eq!(encode(note.0, note.1, note.2).unwrap(), *value));
+        let mut local_clock = LocalClock::default();
+        local_clock.insert(&feed_id, encode(true, Some(true), Some(1))?);
+        let encoded = local_clock.encode()?;
+        assert_eq!(encoded.json(), format!("{{\"{feed_id}\":2}}"));
+
+        // The serialization is shared until the clock changes.
+        local_clock.insert(&feed_id, encode(true, Some(true), Some(1))?);
+        assert!(std::ptr::eq(local_clock.encode()?.json(), encoded.json()));
+
+        local_clock.insert(&feed_id, encode(true, Some(true), Some(2))?);
+        assert_eq!(local_clock.encode()?.json(), format!("{{\"{feed_id}\":4}}"));
+
+        assert!(local_clock.remove(&feed_id));
+        assert!(!local_clock.remove(&feed_id));
+        assert_eq!(local_clock.encode()?.json(), "{}");
+
+        Ok(())
     }
 }
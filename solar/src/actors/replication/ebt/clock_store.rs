@@ -0,0 +1,86 @@
+//! Incremental vector clocks.
+//!
+//! Every EBT session starts with the vector clock of the local node, which
+//! lists every replicated feed. With incremental clocks enabled, the values
+//! of the clock sent to each peer are stored, and sessions with a known peer
+//! start with the entries which changed since (feeds no longer replicated
+//! being sent as `-1`). The full clock is still sent to a peer the first
+//! time and at least once every [`FULL_CLOCK_INTERVAL`], in case the peer
+//! lost the clocks it received.
+use kuska_ssb::api::dto::content::SsbId;
+
+use crate::{actors::replication::ebt::VectorClock, node::KV_STORE, util::now_millis, Result};
+
+/// Maximum time (in milliseconds) between two full vector clocks sent to a
+/// peer.
+pub const FULL_CLOCK_INTERVAL: u64 = 24 * 60 * 60 * 1000;
+
+/// Return the entries of the given clock which differ from the given clock
+/// sent before, with the feeds which are no longer in the clock set to `-1`.
+pub fn diff(sent: &VectorClock, clock: &VectorClock) -> VectorClock {
+    let mut diff: VectorClock = clock
+        .iter()
+        .filter(|(feed_id, value)| sent.get(*feed_id) != Some(value))
+        .map(|(feed_id, value)| (feed_id.to_owned(), *value))
+        .collect();
+
+    for feed_id in sent.keys() {
+        if !clock.contains_key(feed_id) {
+            diff.insert(feed_id.to_owned(), -1);
+        }
+    }
+
+    diff
+}
+
+/// Return the vector clock to be sent to the given peer at the start of a
+/// session, based on the given local clock: the entries which changed since
+/// the clock previously sent to the peer, or the full clock if it is due.
+pub async fn session_clock(peer_ssb_id: &SsbId, clock: &VectorClock) -> Result<VectorClock> {
+    let db = KV_STORE.read().await;
+    let mut update = diff(&db.get_sent_clock(peer_ssb_id)?, clock);
+
+    let now = now_millis();
+    let due = match db.get_full_clock_sent_at(peer_ssb_id)? {
+        Some(sent_at) => now.saturating_sub(sent_at) >= FULL_CLOCK_INTERVAL,
+        None => true,
+    };
+    if due {
+        update.extend(clock.to_owned());
+        db.set_full_clock_sent_at(peer_ssb_id, now)?;
+    }
+
+    Ok(update)
+}
+
+/// Record the given vector clock (or note) sent to the given peer.
+pub async fn record(peer_ssb_id: &SsbId, clock: &VectorClock) -> Result<()> {
+    KV_STORE.read().await.update_sent_clock(peer_ssb_id, clock)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_clock_diff() {
+        let alice = "@HEqy940T6uB+T+d9Jaa58aNfRzLx9eRWqkZljBmnkmk=.ed25519".to_string();
+        let bob = "@o8lWpyLeSqV/BJV9pbxFhKpwm6Lw5k+sqexYK+zT9Tc=.ed25519".to_string();
+        let carol = "@qK93G/R9R5J2fiqK+kxV72HqqPUcss+rth8rACcYr4s=.ed25519".to_string();
+
+        let sent = VectorClock::from([(alice.clone(), 2), (bob.clone(), 6)]);
+
+        // Nothing is sent again if the clock did not change.
+        assert!(diff(&sent, &sent).is_empty());
+
+        // Changed and new feeds are sent, and removed feeds are sent as -1.
+        let clock = VectorClock::from([(alice.clone(), 4), (carol.clone(), 1)]);
+        assert_eq!(
+            diff(&sent, &clock),
+            VectorClock::from([(alice, 4), (bob, -1), (carol, 1)])
+        );
+
+        // Every feed is sent to a peer to which no clock was sent.
+        assert_eq!(diff(&VectorClock::new(), &clock), clock);
+    }
+}
@@ -0,0 +1,95 @@
+//! Messages and vector clocks encoded for sending to peers.
+//!
+//! The messages pushed to peers are read from the database already
+//! serialized and passed, as shared buffers, from the EBT manager through the
+//! batching and scheduling of pushes to the MUXRPC handler which writes them
+//! to the connection. Vector clocks are serialized by the EBT manager and
+//! passed in the same way. Broker messages are cloned for every registered
+//! actor, so cloning an encoded message or clock only increments a reference
+//! count.
+use std::sync::Arc;
+
+use kuska_ssb::api::dto::content::SsbId;
+use serde_json::Value;
+
+use crate::{actors::replication::ebt::VectorClock, error::Error, Result};
+
+#[derive(Debug, PartialEq, Eq)]
+struct Inner {
+    author: SsbId,
+    sequence: u64,
+    json: String,
+}
+
+/// A message value, serialized as JSON.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncodedMessage(Arc<Inner>);
+
+impl EncodedMessage {
+    /// Wrap the given JSON serialization of the message with the given
+    /// author and sequence number.
+    pub fn new(author: &str, sequence: u64, json: String) -> Self {
+        EncodedMessage(Arc::new(Inner {
+            author: author.to_owned(),
+            sequence,
+            json,
+        }))
+    }
+
+    /// Serialize the given message value.
+    pub fn from_value(msg: &Value) -> Result<Self> {
+        let author = msg["author"].as_str().ok_or(Error::OptionIsNone)?;
+        let sequence = msg["sequence"].as_u64().ok_or(Error::OptionIsNone)?;
+
+        Ok(EncodedMessage::new(author, sequence, msg.to_string()))
+    }
+
+    /// Return the SSB ID of the author of the message.
+    pub fn author(&self) -> &SsbId {
+        &self.0.author
+    }
+
+    /// Return the sequence number of the message.
+    pub fn sequence(&self) -> u64 {
+        self.0.sequence
+    }
+
+    /// Return the JSON serialization of the message.
+    pub fn json(&self) -> &str {
+        &self.0.json
+    }
+
+    /// Return the size of the JSON serialization of the message, in bytes.
+    pub fn size(&self) -> usize {
+        self.0.json.len()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct ClockInner {
+    clock: VectorClock,
+    json: String,
+}
+
+/// A vector clock, along with its serialization as JSON.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EncodedClock(Arc<ClockInner>);
+
+impl EncodedClock {
+    /// Serialize the given vector clock.
+    pub fn new(clock: VectorClock) -> Result<Self> {
+        let json = serde_json::to_string(&clock)?;
+
+        Ok(EncodedClock(Arc::new(ClockInner { clock, json })))
+    }
+
+    /// Return the vector clock.
+    pub fn clock(&self) -> &VectorClock {
+        &self.0.clock
+    }
+
+    /// Return the JSON serialization of the vector clock.
+    pub fn json(&self) -> &str {
+        &self.0.json
+    }
+}
@@ -0,0 +1,192 @@
+//! Forwarding audit.
+//!
+//! A peer may request (in its vector clock) feeds which it is not entitled
+//! to receive from the local node. Before messages are queued for a peer,
+//! each feed is checked against the entitlement of the peer, so that the
+//! local node honours the block semantics of the gossip network:
+//!
+//! - the messages of an author who blocks the peer are not forwarded to it,
+//! - the messages of an author blocked by the peer are not forwarded to it,
+//! - the messages of feeds outside the local replication set (eg. beyond the
+//!   replication hops, or revoked) are not forwarded at all,
+//! - no messages are forwarded to pull-only peers (see the `direction`
+//!   module).
+//!
+//! Refused feeds are recorded in the replication log of the peer when its
+//! vector clock is received.
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{actors::replication::direction, storage::indexes::Indexes, util::prefixed, Result};
+
+/// The reason for which the messages of a feed are not forwarded to a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Refusal {
+    /// The feed is not replicated by the local node.
+    NotReplicated,
+    /// The author of the feed blocks the peer.
+    BlockedByAuthor,
+    /// The peer blocks the author of the feed.
+    BlockedByPeer,
+    /// The peer is replicated pull-only.
+    PullOnly,
+}
+
+/// The feeds which a peer is not entitled to receive, according to the
+/// contact graph and the replication direction of the peer.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Entitlement {
+    /// Authors who block the peer.
+    blockers: HashSet<String>,
+    /// Authors blocked by the peer.
+    blocked: HashSet<String>,
+    /// Whether the peer is replicated pull-only.
+    pull_only: bool,
+}
+
+impl Entitlement {
+    /// Load the entitlement of the peer with the given SSB ID from the
+    /// contact indexes and the replication configuration.
+    pub fn load(indexes: &Indexes, peer_ssb_id: &str) -> Result<Self> {
+        let peer_ssb_id = prefixed(peer_ssb_id);
+
+        Ok(Entitlement {
+            blockers: indexes.get_blockers(&peer_ssb_id)?,
+            blocked: indexes.get_blocks(&peer_ssb_id)?,
+            pull_only: !direction::direction(&peer_ssb_id).pushes(),
+        })
+    }
+
+    /// Return the reason for which the messages of the given feed must not
+    /// be forwarded to the peer, or `None` if the peer is entitled to them.
+    /// `replicated` tells whether the feed is replicated by the local node.
+    pub fn refusal(&self, feed_id: &str, replicated: bool) -> Option<Refusal> {
+        if self.pull_only {
+            Some(Refusal::PullOnly)
+        } else if !replicated {
+            Some(Refusal::NotReplicated)
+        } else if self.blockers.contains(feed_id) {
+            Some(Refusal::BlockedByAuthor)
+        } else if self.blocked.contains(feed_id) {
+            Some(Refusal::BlockedByPeer)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use kuska_ssb::{
+        api::dto::content::TypedMessage, feed::Message as MessageValue, keystore::OwnedIdentity,
+    };
+    use serde_json::json;
+    use sled::Config;
+
+    use crate::{secret_config::SecretConfig, storage::kv::KvStorage};
+
+    fn open_temporary_kv() -> Result<KvStorage> {
+        let mut kv = KvStorage::default();
+        let (sender, _) = futures::channel::mpsc::unbounded();
+        let path = tempdir::TempDir::new("solardb")?;
+        let config = Config::new().path(path.path());
+        kv.open(config, sender)?;
+
+        Ok(kv)
+    }
+
+    /// Index a contact message by which the given author blocks the given
+    /// contact.
+    fn block(kv: &KvStorage, author: &OwnedIdentity, contact: &str) -> Result<()> {
+        let content = TypedMessage::Contact {
+            contact: Some(contact.to_owned()),
+            blocking: Some(true),
+            following: Some(false),
+            autofollow: None,
+        };
+        let last_msg = kv.get_latest_msg_val(&author.id)?;
+        let msg = MessageValue::sign(last_msg.as_ref(), author, json!(content))?;
+
+        kv.indexes
+            .as_ref()
+            .expect("indexes are initialised")
+            .index_msg(&author.id, msg)
+    }
+
+    #[test]
+    fn test_refusal() {
+        let entitlement = Entitlement {
+            blockers: HashSet::from(["@blocker".to_owned()]),
+            blocked: HashSet::from(["@blocked".to_owned()]),
+            pull_only: false,
+        };
+
+        assert_eq!(entitlement.refusal("@friend", true), None);
+        assert_eq!(
+            entitlement.refusal("@friend", false),
+            Some(Refusal::NotReplicated)
+        );
+        assert_eq!(
+            entitlement.refusal("@blocker", true),
+            Some(Refusal::BlockedByAuthor)
+        );
+        assert_eq!(
+            entitlement.refusal("@blocked", true),
+            Some(Refusal::BlockedByPeer)
+        );
+
+        // Feeds outside the replication set are never forwarded, whatever the
+        // contact graph.
+        assert_eq!(
+            entitlement.refusal("@blocker", false),
+            Some(Refusal::NotReplicated)
+        );
+
+        // A peer with no recorded blocks is entitled to all replicated feeds.
+        let unrestricted = Entitlement::default();
+        assert_eq!(unrestricted.refusal("@blocker", true), None);
+
+        // Nothing is forwarded to a pull-only peer.
+        let pull_only = Entitlement {
+            pull_only: true,
+            ..Entitlement::default()
+        };
+        assert_eq!(pull_only.refusal("@friend", true), Some(Refusal::PullOnly));
+    }
+
+    #[async_std::test]
+    async fn test_load() -> Result<()> {
+        let kv = open_temporary_kv()?;
+        let peer = SecretConfig::create().to_owned_identity()?;
+        let blocker = SecretConfig::create().to_owned_identity()?;
+        let blocked = SecretConfig::create().to_owned_identity()?;
+        let friend = SecretConfig::create().to_owned_identity()?;
+
+        block(&kv, &blocker, &peer.id)?;
+        block(&kv, &peer, &blocked.id)?;
+
+        let indexes = kv.indexes.as_ref().expect("indexes are initialised");
+        let entitlement = Entitlement::load(indexes, &peer.id)?;
+
+        assert_eq!(entitlement.refusal(&friend.id, true), None);
+        assert_eq!(
+            entitlement.refusal(&blocker.id, true),
+            Some(Refusal::BlockedByAuthor)
+        );
+        assert_eq!(
+            entitlement.refusal(&blocked.id, true),
+            Some(Refusal::BlockedByPeer)
+        );
+
+        // The entitlement is the same whether or not the SSB ID of the peer
+        // is prefixed.
+        let unprefixed = Entitlement::load(indexes, peer.id.trim_start_matches('@'))?;
+        assert_eq!(unprefixed, entitlement);
+
+        Ok(())
+    }
+}
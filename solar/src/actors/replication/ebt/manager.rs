@@ -6,41 +6,66 @@
 //!  - Feed messages
 //!
 //! Each vector clock is a JSON object containing one or more name/value pairs.
+//!
+//! In mirror mode, every feed offered in the vector clock of a peer is added
+//! to the local clock (and recorded as mirrored, so that its storage is
+//! accounted), unless storage is running low. Feeds blocked by the local
+//! identity are not mirrored, and neither are those which the pruning job
+//! would delete (and revoke) again.
 
 use std::{
     collections::{HashMap, HashSet},
     fmt::Display,
     fs::{self, File},
     io::Read,
-    path::PathBuf,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Instant,
 };
 
-use async_std::task;
+use async_std::stream;
 use futures::{select_biased, FutureExt, SinkExt, StreamExt};
 use kuska_ssb::{
-    api::dto::{content::SsbId, BlobsGetIn},
-    crypto::ToSsbId,
+    api::dto::content::SsbId,
+    crypto::{ToSodiumObject, ToSsbId},
     feed::Message,
 };
 use log::{debug, error, trace, warn};
-use serde_json::Value;
 
 use crate::{
     actors::{
-        muxrpc::{ReqNo, RpcBlobsGetEvent},
         network::{
+            clock_skew,
             connection::{ConnectionData, ConnectionId},
             connection_manager::ConnectionEvent,
+            connection_scheduler::PenaltyRequest,
+            reputation, stats,
         },
         replication::{
-            blobs,
-            ebt::{clock, replicator, EncodedClockValue, VectorClock},
+            capabilities::{self, Capability},
+            direction,
+            ebt::{
+                batch::{self, NoteBatch, NOTE_BATCH_WINDOW},
+                clock, clock_store, query, replicator,
+                scheduler::{PushScheduler, PUSH_ROUND_BYTES, PUSH_ROUND_INTERVAL},
+                EncodedClock, EncodedClockValue, EncodedMessage, Entitlement, LocalClock,
+                VectorClock,
+            },
+            ingest, journal, trace, want_list,
+        },
+        retention::{
+            pressure::{self, PressureLevel},
+            prune,
         },
     },
-    broker::{ActorEndpoint, BrokerEvent, BrokerMessage, Destination, BROKER},
-    config::PEERS_TO_REPLICATE,
-    node::{BLOB_STORE, KV_STORE},
-    storage::kv::StoreKvEvent,
+    broker::{ActorEndpoint, Broker, BrokerEvent, BrokerMessage, Destination, BROKER},
+    config::{MIRROR_MODE, PEERS_TO_REPLICATE, PUBLISH_POLICY},
+    node::KV_STORE,
+    storage::{
+        kv::{ReplicationEvent, StoreKvEvent, TraceDirection},
+        validation::{self, Source},
+    },
+    util::now_millis,
     Error, Result,
 };
 
@@ -51,17 +76,37 @@ type ErrorMsg = String;
 pub enum EbtEvent {
     WaitForSessionRequest(ConnectionData),
     RequestSession(ConnectionData),
-    // TODO: See if we can remove `ReqNo` from all these events.
-    // Then `ReqNo` lives purely inside the MUXRPC EBT handler.
-    SessionInitiated(ConnectionId, ReqNo, SsbId, SessionRole),
-    SendClock(ConnectionId, ReqNo, VectorClock, SessionRole),
-    SendMessage(ConnectionId, ReqNo, SsbId, Value, SessionRole),
-    ReceivedClock(ConnectionId, ReqNo, SsbId, VectorClock),
-    ReceivedMessage(Message),
-    SessionConcluded(ConnectionId, SsbId),
+    // Sessions are identified by connection ID. The request number of each
+    // session lives in the request registry of the MUXRPC EBT handler.
+    SessionInitiated(ConnectionId, SsbId, SessionRole),
+    /// A vector clock (the local clock or batched note updates) to be sent
+    /// to the peer, shared rather than copied for each actor to which the
+    /// event is broadcast.
+    SendClock(ConnectionId, EncodedClock),
+    SendMessage(ConnectionId, SsbId, EncodedMessage),
+    /// Consecutive messages of a feed to be pushed to the peer in one write.
+    SendMessages(ConnectionId, SsbId, Vec<EncodedMessage>),
+    ReceivedClock(ConnectionId, SsbId, VectorClock),
+    /// A message received from the peer with the given SSB ID, shared rather
+    /// than copied for each actor to which the event is broadcast.
+    ReceivedMessage(SsbId, Arc<Message>),
+    /// The session on the given connection has concluded. The connection
+    /// is kept open if the local peer acted as the responder, awaiting a new
+    /// replicate request.
+    SessionConcluded(ConnectionId, SsbId, SessionRole),
     SessionTimeout(ConnectionData, SsbId),
     TerminateSession(ConnectionId, SessionRole),
     Error(ConnectionData, SsbId, ErrorMsg),
+    /// The feed represented by the given SSB ID has left the replication
+    /// set (for example, because it was pruned).
+    Revoke(SsbId),
+    /// The feed represented by the given SSB ID has joined the replication
+    /// set (for example, at the request of an admin).
+    Replicate(SsbId),
+    /// A query (with the given ID) of the latest vector clock received from
+    /// the peer with the given SSB ID, or of the local clock if none is
+    /// given.
+    QueryClock(u64, Option<SsbId>),
 }
 
 /// Role of a peer in an EBT session.
@@ -83,17 +128,30 @@ impl Display for SessionRole {
 #[derive(Debug)]
 pub struct EbtManager {
     /// Active EBT peer sessions.
-    active_sessions: HashMap<ConnectionId, (SsbId, SessionRole, ReqNo)>,
+    active_sessions: HashMap<ConnectionId, (SsbId, SessionRole)>,
     /// Duration to wait before switching feed request to a different peer.
     _feed_wait_timeout: u64,
     /// The state of the replication loop.
     _is_replication_loop_active: bool,
     /// The local vector clock.
-    local_clock: VectorClock,
+    local_clock: LocalClock,
     /// The SSB ID of the local node.
     local_id: SsbId,
+    /// Note updates waiting to be sent to the active sessions.
+    note_batch: NoteBatch,
+    /// Messages waiting to be pushed to the active sessions.
+    push_scheduler: PushScheduler,
+    /// Maximum size in bytes of a batch of messages pushed to a peer in
+    /// one write (0 to push messages one at a time).
+    push_batch_bytes: usize,
+    /// Whether known peers are sent only the vector clock entries which
+    /// changed since the previous session, and received clocks are merged
+    /// into the stored clock of the peer.
+    incremental_clocks: bool,
     /// The vector clock for each known peer.
     peer_clocks: HashMap<SsbId, VectorClock>,
+    /// Directory in which the peer clocks are persisted, if any.
+    ebt_config_path: Option<PathBuf>,
     /// A set of all the feeds for which active requests are open.
     ///
     /// This allows us to avoid requesting a feed from multiple peers
@@ -107,10 +165,19 @@ pub struct EbtManager {
     // each peer across multiple sessions.
     //
     // Based on current usage, this could just be a HashSet of ConnectionId.
-    sent_clocks: HashMap<ConnectionId, VectorClock>,
+    sent_clocks: HashMap<ConnectionId, EncodedClock>,
+    /// The sessions (identified by connection ID) in which a vector clock
+    /// was received.
+    received_clocks: HashSet<ConnectionId>,
     /// The sequence number of the latest message sent to each peer
     /// for each requested feed.
     sent_messages: HashMap<SsbId, HashMap<SsbId, u64>>,
+    /// The feeds which the peer of each session is not entitled to receive,
+    /// as of the latest vector clock received from the peer.
+    entitlements: HashMap<ConnectionId, Entitlement>,
+    /// Number of hops from the local identity beyond which stored feeds are
+    /// pruned, if any.
+    prune_hops: Option<usize>,
 }
 
 impl Default for EbtManager {
@@ -119,23 +186,52 @@ impl Default for EbtManager {
             active_sessions: HashMap::new(),
             _feed_wait_timeout: 3,
             _is_replication_loop_active: false,
-            local_clock: HashMap::new(),
+            local_clock: LocalClock::default(),
             local_id: String::new(),
+            note_batch: NoteBatch::default(),
+            push_batch_bytes: 0,
+            incremental_clocks: false,
+            push_scheduler: PushScheduler::default(),
             peer_clocks: HashMap::new(),
+            ebt_config_path: None,
             _requested_feeds: HashSet::new(),
             session_wait_timeout: 5,
             sent_clocks: HashMap::new(),
+            received_clocks: HashSet::new(),
             sent_messages: HashMap::new(),
+            entitlements: HashMap::new(),
+            prune_hops: None,
         }
     }
 }
 
 impl EbtManager {
+    /// Set the maximum size in bytes of a batch of messages pushed to a
+    /// peer in one write.
+    pub fn push_batch_bytes(mut self, push_batch_bytes: usize) -> Self {
+        self.push_batch_bytes = push_batch_bytes;
+        self
+    }
+
+    /// Set whether known peers are sent only the vector clock entries which
+    /// changed since the previous session.
+    pub fn incremental_clocks(mut self, incremental_clocks: bool) -> Self {
+        self.incremental_clocks = incremental_clocks;
+        self
+    }
+
+    /// Set the number of hops from the local identity beyond which stored
+    /// feeds are pruned, so that such feeds are not mirrored.
+    pub fn prune_hops(mut self, prune_hops: Option<usize>) -> Self {
+        self.prune_hops = prune_hops;
+        self
+    }
+
     /// Initialise the local clock based on peers to be replicated.
     ///
     /// This defines the public keys of all feeds we wish to replicate,
     /// along with the latest sequence number for each.
-    async fn init_local_clock(&mut self, ebt_config_path: &PathBuf) -> Result<()> {
+    async fn init_local_clock(&mut self) -> Result<()> {
         debug!("Initialising local EBT clock");
 
         let local_id = self.local_id.to_owned();
@@ -151,8 +247,34 @@ impl EbtManager {
             }
         }
 
+        // The feeds of remote signers are published through the local node,
+        // so they are replicated like the local feed.
+        if let Some(policy) = PUBLISH_POLICY.get() {
+            for signer in &policy.remote_signers {
+                let signer = format!("@{}", signer.trim_start_matches('@'));
+                self.replicate(&signer).await?;
+            }
+        }
+
+        // Feeds mirrored before the node was restarted remain replicated,
+        // unless they have been blocked since.
+        if MIRROR_MODE.get().copied().unwrap_or(false) {
+            let (mirrored, blocked) = {
+                let db = KV_STORE.read().await;
+                let indexes = db.indexes.as_ref().ok_or(Error::Indexes)?;
+                (db.get_mirrored_feeds()?, indexes.get_blocks(&local_id)?)
+            };
+            for (feed_id, _feed) in mirrored {
+                if !blocked.contains(&feed_id) {
+                    self.replicate(&feed_id).await?;
+                }
+            }
+        }
+
         // Load peer clocks from file and update `peer_clocks`.
-        self.load_peer_clocks(ebt_config_path)?;
+        if let Some(ebt_config_path) = self.ebt_config_path.to_owned() {
+            self.load_peer_clocks(&ebt_config_path)?;
+        }
 
         Ok(())
     }
@@ -162,14 +284,14 @@ impl EbtManager {
     fn get_clock(&self, ssb_id: Option<&SsbId>) -> Option<VectorClock> {
         match ssb_id {
             Some(id) => self.peer_clocks.get(id).cloned(),
-            None => Some(self.local_clock.to_owned()),
+            None => Some(self.local_clock.clock().to_owned()),
         }
     }
 
     /// Set or update the vector clock for the given SSB ID.
     fn set_clock(&mut self, ssb_id: &SsbId, clock: VectorClock) {
         if ssb_id == &self.local_id {
-            self.local_clock = clock
+            self.local_clock.set(clock)
         } else {
             self.peer_clocks.insert(ssb_id.to_owned(), clock);
         }
@@ -215,18 +337,24 @@ impl EbtManager {
         Ok(())
     }
 
-    /// Persist all peer clocks to disk (`ebt` directory).
-    fn persist_peer_clocks(&self, ebt_config_path: PathBuf) -> Result<()> {
-        for (ssb_id, clock) in self.peer_clocks.iter() {
-            // Format the SSB ID as: @<PUBLIC_KEY>.ed25519, replacing any `/`
-            // characters with `-`.
-            let clock_author_id =
-                format!("@{}", ssb_id.to_string().replace('/', "-").replace('=', ""));
-
-            let clock_filepath = ebt_config_path.join(clock_author_id);
-            let json_clock = serde_json::to_string(clock)?;
+    /// Return the path of the file in which the clock of the given peer is
+    /// persisted.
+    fn peer_clock_path(ebt_config_path: &Path, ssb_id: &SsbId) -> PathBuf {
+        // Format the SSB ID as: @<PUBLIC_KEY>.ed25519, replacing any `/`
+        // characters with `-`.
+        let clock_author_id = format!("@{}", ssb_id.to_string().replace('/', "-").replace('=', ""));
+
+        ebt_config_path.join(clock_author_id)
+    }
 
-            fs::write(clock_filepath, json_clock)?;
+    /// Persist the clock of the given peer to disk (`ebt` directory), if
+    /// peer clocks are persisted.
+    fn persist_peer_clock(&self, ssb_id: &SsbId) -> Result<()> {
+        if let (Some(ebt_config_path), Some(clock)) =
+            (&self.ebt_config_path, self.peer_clocks.get(ssb_id))
+        {
+            let json_clock = serde_json::to_string(clock)?;
+            fs::write(Self::peer_clock_path(ebt_config_path, ssb_id), json_clock)?;
 
             debug!("Wrote vector clock to file for: {}", ssb_id);
         }
@@ -234,6 +362,15 @@ impl EbtManager {
         Ok(())
     }
 
+    /// Persist all peer clocks to disk (`ebt` directory).
+    fn persist_peer_clocks(&self) -> Result<()> {
+        for ssb_id in self.peer_clocks.keys() {
+            self.persist_peer_clock(ssb_id)?;
+        }
+
+        Ok(())
+    }
+
     /// Retrieve the stored vector clock for the first peer, check for the
     /// second peer in the vector clock. If the receive flag is set to true,
     /// return the decoded sequence number.
@@ -272,12 +409,12 @@ impl EbtManager {
             // Encode the replicate flag, receive flag and sequence.
             let encoded_value: EncodedClockValue = clock::encode(true, Some(true), Some(seq))?;
             // Insert the ID and encoded sequence into the local clock.
-            self.local_clock.insert(peer_id.to_owned(), encoded_value);
+            self.local_clock.insert(peer_id, encoded_value);
         } else {
             // No messages are stored in the local database for this feed.
             // Set replicate flag to `true`, receive to `true` and `seq` to 0.
             let encoded_value: EncodedClockValue = clock::encode(true, Some(true), Some(0))?;
-            self.local_clock.insert(peer_id.to_owned(), encoded_value);
+            self.local_clock.insert(peer_id, encoded_value);
         }
 
         Ok(())
@@ -289,22 +426,25 @@ impl EbtManager {
         connection_id: ConnectionId,
         peer_ssb_id: SsbId,
         session_role: SessionRole,
-        req_no: ReqNo,
     ) {
         trace!(target: "ebt-session", "Registered new EBT session for connection {} with {}", connection_id, peer_ssb_id);
         self.active_sessions
-            .insert(connection_id, (peer_ssb_id, session_role, req_no));
+            .insert(connection_id, (peer_ssb_id, session_role));
     }
 
     /// Remove the given peer from the list of active session.
     fn remove_session(&mut self, connection_id: ConnectionId) {
         let _ = self.active_sessions.remove(&connection_id);
+        let _ = self.entitlements.remove(&connection_id);
+        let _ = self.received_clocks.remove(&connection_id);
+        self.note_batch.remove_session(connection_id);
+        self.push_scheduler.remove_session(connection_id);
     }
 
     /// Return the role of the local peer for the active session (represented
     /// by connection ID).
     fn session_role(&self, connection_id: ConnectionId) -> Option<SessionRole> {
-        if let Some((_ssb_id, session_role, _req_no)) = self.active_sessions.get(&connection_id) {
+        if let Some((_ssb_id, session_role)) = self.active_sessions.get(&connection_id) {
             Some(session_role.to_owned())
         } else {
             None
@@ -312,9 +452,9 @@ impl EbtManager {
     }
 
     /// Revoke a replication request for the feed represented by the given SSB
-    /// ID.
-    fn _revoke(&mut self, peer_id: &SsbId) {
-        self.local_clock.remove(peer_id);
+    /// ID. Returns `false` if the feed was not being replicated.
+    fn revoke(&mut self, peer_id: &SsbId) -> bool {
+        self.local_clock.remove(peer_id)
     }
 
     /// Request the feed represented by the given SSB ID from a peer.
@@ -330,14 +470,14 @@ impl EbtManager {
     async fn retrieve_latest_messages(
         encoded_seq_no: i64,
         feed_id: &SsbId,
-        messages: &mut Vec<Value>,
+        messages: &mut Vec<EncodedMessage>,
     ) -> Result<()> {
         if encoded_seq_no != -1 {
             if let (_replicate_flag, Some(true), Some(seq)) = clock::decode(encoded_seq_no)? {
                 if let Some(last_seq) = KV_STORE.read().await.get_latest_seq(feed_id)? {
                     for n in (seq + 1)..=last_seq {
-                        if let Some(msg_kvt) = KV_STORE.read().await.get_msg_kvt(feed_id, n)? {
-                            messages.push(msg_kvt.value)
+                        if let Some(msg_json) = KV_STORE.read().await.get_msg_json(feed_id, n)? {
+                            messages.push(EncodedMessage::new(feed_id, n, msg_json))
                         }
                     }
                 }
@@ -357,7 +497,7 @@ impl EbtManager {
     async fn retrieve_requested_messages(
         peer_ssb_id: Option<&SsbId>,
         clock: VectorClock,
-    ) -> Result<Vec<Value>> {
+    ) -> Result<Vec<EncodedMessage>> {
         let mut messages_to_be_sent = Vec::new();
 
         // We only want to retrieve messages authored by `peer_ssb_id`.
@@ -392,11 +532,14 @@ impl EbtManager {
     async fn handle_wait_for_session_request(&self, connection_data: ConnectionData) {
         trace!(target: "ebt", "Waiting for EBT session request");
 
-        task::spawn(replicator::run(
-            connection_data,
-            SessionRole::Responder,
-            self.session_wait_timeout,
-        ));
+        Broker::spawn(
+            "ebt-replication-loop",
+            replicator::run(
+                connection_data,
+                SessionRole::Responder,
+                self.session_wait_timeout,
+            ),
+        );
     }
 
     async fn handle_request_session(&self, connection_data: ConnectionData) {
@@ -412,11 +555,14 @@ impl EbtManager {
                     peer_ssb_id
                 );
 
-                task::spawn(replicator::run(
-                    connection_data,
-                    SessionRole::Requester,
-                    self.session_wait_timeout,
-                ));
+                Broker::spawn(
+                    "ebt-replication-loop",
+                    replicator::run(
+                        connection_data,
+                        SessionRole::Requester,
+                        self.session_wait_timeout,
+                    ),
+                );
             }
         }
     }
@@ -424,36 +570,44 @@ impl EbtManager {
     async fn handle_session_initiated(
         &mut self,
         connection_id: ConnectionId,
-        req_no: ReqNo,
         peer_ssb_id: SsbId,
         session_role: SessionRole,
     ) -> Result<()> {
         trace!(target: "ebt-replication", "Initiated EBT session with {} as {}", peer_ssb_id, session_role);
 
-        self.register_session(connection_id, peer_ssb_id, session_role.to_owned(), req_no);
-        let local_clock = self.local_clock.to_owned();
+        journal::record(
+            &peer_ssb_id,
+            ReplicationEvent::SessionStarted {
+                protocol: "ebt".to_string(),
+            },
+        )
+        .await;
+
+        reputation::record_session(&peer_ssb_id);
+
+        // Only classic feeds are replicated via EBT.
+        capabilities::record(&peer_ssb_id, Capability::Ebt, true).await;
+        capabilities::record_feed_format(&peer_ssb_id, "classic").await;
+
+        let local_clock = self.session_clock_for(&peer_ssb_id).await?;
+        self.register_session(connection_id, peer_ssb_id, session_role.to_owned());
 
         match session_role {
             SessionRole::Responder => {
                 // Create channel to send messages to broker.
                 let mut ch_broker = BROKER.lock().await.create_sender();
 
-                trace!(target: "ebt-replication", "Sending clock as responder for request {}", req_no);
+                trace!(target: "ebt-replication", "Sending clock as responder on connection {}", connection_id);
 
                 ch_broker
                     .send(BrokerEvent::new(
                         Destination::Broadcast,
-                        BrokerMessage::Ebt(EbtEvent::SendClock(
-                            connection_id,
-                            req_no,
-                            local_clock,
-                            SessionRole::Responder,
-                        )),
+                        BrokerMessage::Ebt(EbtEvent::SendClock(connection_id, local_clock)),
                     ))
                     .await?;
             }
             SessionRole::Requester => {
-                trace!(target: "ebt-replication", "EBT session requester: {}", req_no);
+                trace!(target: "ebt-replication", "EBT session requester on connection {}", connection_id);
                 // The requester waits for a clock to be sent by the responder.
             }
         }
@@ -461,49 +615,104 @@ impl EbtManager {
         Ok(())
     }
 
-    fn handle_send_clock(
+    /// Return the local clock as sent to the given peer, with the receive
+    /// flags unset if the peer is replicated push-only.
+    fn encode_clock_for(&mut self, peer_ssb_id: &SsbId) -> Result<EncodedClock> {
+        if direction::direction(peer_ssb_id).pulls() {
+            self.local_clock.encode()
+        } else {
+            EncodedClock::new(direction::notes_only(self.local_clock.clock())?)
+        }
+    }
+
+    /// Return the clock sent to the given peer at the start of a session:
+    /// only the entries which changed since the clock previously sent to the
+    /// peer if incremental clocks are enabled.
+    async fn session_clock_for(&mut self, peer_ssb_id: &SsbId) -> Result<EncodedClock> {
+        let clock = self.encode_clock_for(peer_ssb_id)?;
+        if !self.incremental_clocks {
+            return Ok(clock);
+        }
+
+        EncodedClock::new(clock_store::session_clock(peer_ssb_id, clock.clock()).await?)
+    }
+
+    async fn handle_send_clock(
         &mut self,
         connection_id: ConnectionId,
-        clock: VectorClock,
-    ) -> Option<VectorClock> {
+        clock: EncodedClock,
+    ) -> Option<EncodedClock> {
+        if let Some((peer_ssb_id, _session_role)) = self.active_sessions.get(&connection_id) {
+            journal::record(
+                peer_ssb_id,
+                ReplicationEvent::ClockSent {
+                    clock: clock.clock().to_owned(),
+                },
+            )
+            .await;
+
+            if self.incremental_clocks {
+                if let Err(err) = clock_store::record(peer_ssb_id, clock.clock()).await {
+                    warn!("Failed to record clock sent to {}: {}", peer_ssb_id, err);
+                }
+            }
+        }
+
         self.sent_clocks.insert(connection_id, clock)
     }
 
     async fn handle_received_clock(
         &mut self,
         connection_id: ConnectionId,
-        req_no: ReqNo,
         peer_ssb_id: SsbId,
         clock: VectorClock,
     ) -> Result<()> {
         trace!(target: "ebt-replication", "Received vector clock: {:?}", clock);
 
-        // Update the stored vector clock for the remote peer.
-        self.set_clock(&peer_ssb_id, clock.to_owned());
+        journal::record(
+            &peer_ssb_id,
+            ReplicationEvent::ClockReceived {
+                clock: clock.to_owned(),
+            },
+        )
+        .await;
+
+        // Update the stored vector clock for the remote peer. With
+        // incremental clocks, the peer may only send the entries which
+        // changed since the previous session, so the received clock is
+        // merged into the stored one, and the messages requested by the
+        // first clock of a session are retrieved based on the merged clock.
+        let first_clock = self.received_clocks.insert(connection_id);
+        let clock = if self.incremental_clocks {
+            let stored = self.peer_clocks.entry(peer_ssb_id.to_owned()).or_default();
+            stored.extend(clock.to_owned());
+            if first_clock {
+                stored.to_owned()
+            } else {
+                clock
+            }
+        } else {
+            self.set_clock(&peer_ssb_id, clock.to_owned());
+            clock
+        };
 
         // Create channel to send messages to broker.
         let mut ch_broker = BROKER.lock().await.create_sender();
 
         // TODO: What if we initiated a session as requester when sending
         // replicate request? That might simply things.
-        let session_role = match self.session_role(connection_id) {
-            Some(role) => role,
-            None => {
-                ch_broker
-                    .send(BrokerEvent::new(
-                        Destination::Broadcast,
-                        BrokerMessage::Ebt(EbtEvent::SessionInitiated(
-                            connection_id,
-                            req_no,
-                            peer_ssb_id.to_owned(),
-                            SessionRole::Requester,
-                        )),
-                    ))
-                    .await?;
-
-                SessionRole::Requester
-            }
-        };
+        if self.session_role(connection_id).is_none() {
+            ch_broker
+                .send(BrokerEvent::new(
+                    Destination::Broadcast,
+                    BrokerMessage::Ebt(EbtEvent::SessionInitiated(
+                        connection_id,
+                        peer_ssb_id.to_owned(),
+                        SessionRole::Requester,
+                    )),
+                ))
+                .await?;
+        }
 
         // If we have not previously sent a clock during this connection,
         // send one now. Connection is used here as a proxy for session.
@@ -511,42 +720,132 @@ impl EbtManager {
         // This indicates that the local peer is acting as the session
         // requester.
         if self.sent_clocks.get(&connection_id).is_none() {
-            let local_clock = self.local_clock.to_owned();
+            let local_clock = self.session_clock_for(&peer_ssb_id).await?;
             ch_broker
                 .send(BrokerEvent::new(
                     Destination::Broadcast,
-                    BrokerMessage::Ebt(EbtEvent::SendClock(
-                        connection_id,
-                        req_no,
-                        local_clock,
-                        SessionRole::Requester,
-                    )),
+                    BrokerMessage::Ebt(EbtEvent::SendClock(connection_id, local_clock)),
                 ))
                 .await?;
         }
 
+        // In mirror mode, replicate every feed offered by the peer (unless
+        // its data is not accepted).
+        let mirror = MIRROR_MODE.get().copied().unwrap_or(false);
+        if mirror && direction::direction(&peer_ssb_id).pulls() {
+            self.mirror_feeds(&peer_ssb_id, &clock).await?;
+        }
+
+        // Only forward the messages of the feeds to which the peer is
+        // entitled.
+        let clock = self.audit_clock(connection_id, &peer_ssb_id, clock).await?;
+
         // We want messages for all feeds in the clock, therefore the
         // `peer_ssb_id` parameter is set to `None`.
         let msgs = EbtManager::retrieve_requested_messages(None, clock).await?;
-        for msg in msgs {
-            ch_broker
-                .send(BrokerEvent::new(
-                    Destination::Broadcast,
-                    BrokerMessage::Ebt(EbtEvent::SendMessage(
-                        connection_id,
-                        req_no,
-                        peer_ssb_id.to_owned(),
-                        msg,
-                        session_role.to_owned(),
-                    )),
-                ))
-                .await?;
+
+        // Queue consecutive messages of each feed in batches bounded by the
+        // configured byte budget. The batches are pushed by the scheduler.
+        for msgs in batch::batch_messages(msgs, self.push_batch_bytes) {
+            self.push_scheduler.queue(connection_id, &peer_ssb_id, msgs);
+        }
+
+        Ok(())
+    }
+
+    /// Replicate the feeds offered in the given vector clock of the given
+    /// peer which are not replicated yet, recording them as mirrored. No new
+    /// feeds are mirrored while storage is running low.
+    ///
+    /// Feeds blocked by the local identity are skipped, as are those beyond
+    /// the pruning range: these would otherwise be pruned and revoked, only
+    /// to be mirrored again as soon as a peer offers them.
+    async fn mirror_feeds(&mut self, peer_ssb_id: &SsbId, clock: &VectorClock) -> Result<()> {
+        let blocked = {
+            let db = KV_STORE.read().await;
+            let indexes = db.indexes.as_ref().ok_or(Error::Indexes)?;
+            indexes.get_blocks(&self.local_id)?
+        };
+        let mut offered: Vec<SsbId> = clock
+            .iter()
+            .filter(|(feed_id, _value)| !self.local_clock.contains(feed_id))
+            .filter(|(_feed_id, value)| matches!(clock::decode(**value), Ok((true, _, _))))
+            .filter(|(feed_id, _value)| feed_id.trim_start_matches('@').to_ed25519_pk().is_ok())
+            .filter(|(feed_id, _value)| !blocked.contains(*feed_id))
+            .map(|(feed_id, _value)| feed_id.to_owned())
+            .collect();
+        if let Some(max_hops) = self.prune_hops {
+            let out_of_range: HashSet<SsbId> =
+                prune::out_of_range(&self.local_id, max_hops, offered.iter().cloned())
+                    .await?
+                    .into_iter()
+                    .collect();
+            offered.retain(|feed_id| !out_of_range.contains(feed_id));
+        }
+        if offered.is_empty() {
+            return Ok(());
+        }
+
+        if pressure::status().level != PressureLevel::Normal {
+            debug!(
+                "Storage is running low; not mirroring {} feeds offered by {}",
+                offered.len(),
+                peer_ssb_id
+            );
+            return Ok(());
+        }
+
+        let added = now_millis();
+        for feed_id in offered {
+            KV_STORE.read().await.add_mirrored_feed(&feed_id, added)?;
+            trace!(target: "ebt-replication", "Mirroring {} offered by {}", feed_id, peer_ssb_id);
+            self.handle_replicate(feed_id).await?;
         }
 
         Ok(())
     }
 
-    async fn handle_send_message(&mut self, peer_ssb_id: SsbId, msg: Value) -> Result<()> {
+    /// Refresh the entitlement of the peer of the given session and remove
+    /// the feeds which the peer is not entitled to receive from the given
+    /// vector clock, recording each refusal in the replication log.
+    async fn audit_clock(
+        &mut self,
+        connection_id: ConnectionId,
+        peer_ssb_id: &SsbId,
+        mut clock: VectorClock,
+    ) -> Result<VectorClock> {
+        let entitlement = {
+            let db = KV_STORE.read().await;
+            let indexes = db.indexes.as_ref().ok_or(Error::Indexes)?;
+            Entitlement::load(indexes, peer_ssb_id)?
+        };
+
+        let mut refusals = Vec::new();
+        clock.retain(|feed_id, _| {
+            let replicated = self.local_clock.contains(feed_id);
+            match entitlement.refusal(feed_id, replicated) {
+                Some(reason) => {
+                    refusals.push((feed_id.to_owned(), reason));
+                    false
+                }
+                None => true,
+            }
+        });
+        self.entitlements.insert(connection_id, entitlement);
+
+        for (feed, reason) in refusals {
+            trace!(target: "ebt-replication", "Not forwarding {} to {}: {:?}", feed, peer_ssb_id, reason);
+            journal::record(
+                peer_ssb_id,
+                ReplicationEvent::ForwardingRefused { feed, reason },
+            )
+            .await;
+        }
+
+        Ok(clock)
+    }
+
+    async fn handle_send_message(&mut self, peer_ssb_id: SsbId, msg: EncodedMessage) -> Result<()> {
         // Update the hashmap of sent messages.
         //
         // For each peer, keep a list of feed ID's and the sequence of the
@@ -554,11 +853,17 @@ impl EbtManager {
         // message is appended to the local store and may need to be sent to
         // peers with whom we have an active EBT session.
 
-        let msg_author = msg["author"]
-            .as_str()
-            .ok_or(Error::OptionIsNone)?
-            .to_string();
-        let msg_sequence = msg["sequence"].as_u64().ok_or(Error::OptionIsNone)?;
+        let msg_author = msg.author().to_owned();
+        let msg_sequence = msg.sequence();
+
+        trace::record(
+            &msg_author,
+            msg_sequence,
+            &peer_ssb_id,
+            TraceDirection::Sent,
+            "ebt",
+        )
+        .await;
 
         if let Some(feeds) = self.sent_messages.get_mut(&peer_ssb_id) {
             feeds.insert(msg_author, msg_sequence);
@@ -571,21 +876,75 @@ impl EbtManager {
         Ok(())
     }
 
-    async fn handle_received_message(&mut self, msg: Message) -> Result<()> {
+    async fn handle_received_message(
+        &mut self,
+        peer_ssb_id: SsbId,
+        msg: Arc<Message>,
+    ) -> Result<()> {
         trace!(target: "ebt-replication", "Received message: {:?}", msg);
 
-        // Retrieve the sequence number of the most recent message for
-        // the peer that authored the received message.
-        let last_seq = KV_STORE
+        // Drop the messages of push-only peers.
+        if !direction::direction(&peer_ssb_id).pulls() {
+            debug!(
+                "Dropping message number {} from push-only peer {}",
+                msg.sequence(),
+                peer_ssb_id
+            );
+            return Ok(());
+        }
+
+        // Drop the messages of distant feeds while storage is critically low.
+        if !pressure::accepts_feed(&msg.author().to_string()) {
+            debug!(
+                "Storage is critically low; dropping message number {} from {}",
+                msg.sequence(),
+                msg.author()
+            );
+            return Ok(());
+        }
+
+        // Hold back the messages of distant authors in excess of the ingest
+        // rate limits.
+        if !ingest::admit(&peer_ssb_id, &msg).await? {
+            debug!(
+                "Holding back message number {} from {} over the ingest limit",
+                msg.sequence(),
+                msg.author()
+            );
+            return Ok(());
+        }
+
+        // Retrieve the most recent message of the feed of the peer that
+        // authored the received message.
+        let latest_msg = KV_STORE
             .read()
             .await
-            .get_latest_seq(&msg.author().to_string())?
-            .unwrap_or(0);
+            .get_latest_msg_val(&msg.author().to_string())?;
+
+        // Validate the message, including its sequence number.
+        if let Err(err) = validation::validate(&msg, latest_msg.as_ref(), Source::Replicated) {
+            warn!("Rejected message received via EBT: {}", err);
+            reputation::record_invalid_message(&peer_ssb_id);
+        } else {
+            // Check the clock of the peer before the message is indexed.
+            clock_skew::observe(&peer_ssb_id, &msg);
 
-        // Validate the sequence number.
-        if msg.sequence() == last_seq + 1 {
             // Append the message to the feed.
-            KV_STORE.write().await.append_feed(msg.clone()).await?;
+            KV_STORE
+                .write()
+                .await
+                .append_feed(Message::clone(&msg))
+                .await?;
+            stats::record_message(latest_msg.is_none());
+            reputation::record_message(&peer_ssb_id);
+            trace::record(
+                &msg.author().to_string(),
+                msg.sequence(),
+                &peer_ssb_id,
+                TraceDirection::Received,
+                "ebt",
+            )
+            .await;
 
             debug!(
                 "Received message number {} from {}",
@@ -596,56 +955,48 @@ impl EbtManager {
             // Create channel to send messages to broker.
             let mut ch_broker = BROKER.lock().await.create_sender();
 
-            // Extract blob references from the received message and
-            // request those blobs if they are not already in the local
-            // blobstore.
-            for key in blobs::extract_blob_refs(&msg) {
-                if !BLOB_STORE.read().await.exists(&key) {
-                    let event = RpcBlobsGetEvent(BlobsGetIn::new(key));
-                    let broker_msg =
-                        BrokerEvent::new(Destination::Broadcast, BrokerMessage::RpcBlobsGet(event));
-                    ch_broker.send(broker_msg).await?;
-                }
-            }
-        } else {
-            warn!(
-                "Received out-of-order message from {}; received: {}, expected: {} + 1",
-                &msg.author().to_string(),
-                msg.sequence(),
-                last_seq
-            );
+            // Add the blobs referenced by the received message to the
+            // want-list if they are not already in the local blobstore.
+            want_list::want_referenced(&msg, &mut ch_broker).await?;
         }
         Ok(())
     }
 
     /// Check if any active session peers are interested in the updated feed.
-    /// If so, send them the appended message.
-    async fn handle_local_store_updated(&self, ssb_id: SsbId, msg_seq: u64) -> Result<()> {
-        // TODO: This is all radically inefficient, but it's a start.
+    /// If so, queue the appended message to be pushed to them.
+    async fn handle_local_store_updated(&mut self, ssb_id: SsbId, msg_seq: u64) -> Result<()> {
+        // The message is retrieved from the key-value store once, when a
+        // first session peer is found to be interested in it, and shared
+        // between sessions.
+        let mut msg: Option<EncodedMessage> = None;
 
         // Iterate over all active EBT sessions.
-        for (connection_id, (peer_ssb_id, session_role, req_no)) in self.active_sessions.iter() {
+        for (connection_id, (peer_ssb_id, _session_role)) in self.active_sessions.iter() {
+            // Skip the peers which are not entitled to the feed.
+            let replicated = self.local_clock.contains(&ssb_id);
+            let refused = self
+                .entitlements
+                .get(connection_id)
+                .map_or(!replicated, |entitlement| {
+                    entitlement.refusal(&ssb_id, replicated).is_some()
+                });
+            if refused {
+                continue;
+            }
+
             // Check if `peer_ssb_id` wants to replicate `ssb_id`.
             if let Some(seq) = self.is_receiving(peer_ssb_id, &ssb_id)? {
                 if msg_seq > seq {
-                    // Retrieve the message from the key-value store.
-                    if let Some(msg_kvt) = KV_STORE.read().await.get_msg_kvt(&ssb_id, msg_seq)? {
-                        // Create channel to send messages to broker.
-                        let mut ch_broker = BROKER.lock().await.create_sender();
-
-                        // Send the single-entry vector clock to the active session.
-                        ch_broker
-                            .send(BrokerEvent::new(
-                                Destination::Broadcast,
-                                BrokerMessage::Ebt(EbtEvent::SendMessage(
-                                    *connection_id,
-                                    *req_no,
-                                    peer_ssb_id.to_owned(),
-                                    msg_kvt.value,
-                                    session_role.to_owned(),
-                                )),
-                            ))
-                            .await?;
+                    if msg.is_none() {
+                        msg = KV_STORE
+                            .read()
+                            .await
+                            .get_msg_json(&ssb_id, msg_seq)?
+                            .map(|msg_json| EncodedMessage::new(&ssb_id, msg_seq, msg_json));
+                    }
+                    if let Some(msg) = &msg {
+                        self.push_scheduler
+                            .queue(*connection_id, peer_ssb_id, vec![msg.clone()]);
                     }
                 }
             }
@@ -654,8 +1005,189 @@ impl EbtManager {
         Ok(())
     }
 
-    async fn handle_session_concluded(&mut self, connection_id: ConnectionId, peer_ssb_id: SsbId) {
-        trace!(target: "ebt-replication", "Session concluded for connection {} with {}", connection_id, peer_ssb_id);
+    /// Stop replicating the feed represented by the given SSB ID.
+    ///
+    /// The feed is removed from the local clock and from the stored peer
+    /// clocks (in memory and on disk), and a negative note is sent for the
+    /// feed on every active session so that peers stop sending its messages.
+    async fn handle_revoke(&mut self, feed_id: SsbId) -> Result<()> {
+        if feed_id == self.local_id {
+            return Ok(());
+        }
+
+        let mut known = self.revoke(&feed_id);
+        let updated_peers: Vec<SsbId> = self
+            .peer_clocks
+            .iter_mut()
+            .filter_map(|(peer_ssb_id, clock)| {
+                clock.remove(&feed_id).map(|_| peer_ssb_id.to_owned())
+            })
+            .collect();
+        for peer_ssb_id in &updated_peers {
+            self.persist_peer_clock(peer_ssb_id)?;
+        }
+        known |= !updated_peers.is_empty();
+        for feeds in self.sent_messages.values_mut() {
+            feeds.remove(&feed_id);
+        }
+        self.push_scheduler.remove_feed(&feed_id);
+        if !known {
+            return Ok(());
+        }
+
+        trace!(target: "ebt-replication", "Revoked replication of {}", feed_id);
+
+        let value = clock::encode(false, None, None)?;
+        let now = Instant::now();
+        for connection_id in self.active_sessions.keys() {
+            self.note_batch.queue(*connection_id, &feed_id, value, now);
+        }
+
+        Ok(())
+    }
+
+    /// Start replicating the feed represented by the given SSB ID.
+    ///
+    /// The feed is added to the local clock and a note is sent for the feed
+    /// on every active session so that peers start sending its messages.
+    async fn handle_replicate(&mut self, feed_id: SsbId) -> Result<()> {
+        if self.local_clock.contains(&feed_id) {
+            return Ok(());
+        }
+
+        self.replicate(&feed_id).await?;
+
+        trace!(target: "ebt-replication", "Requested replication of {}", feed_id);
+
+        if let Some(value) = self.local_clock.clock().get(&feed_id).copied() {
+            let now = Instant::now();
+            for connection_id in self.active_sessions.keys() {
+                self.note_batch.queue(*connection_id, &feed_id, value, now);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Update the local clock with the latest sequence number of the given
+    /// replicated feed and queue a note update for the active sessions whose
+    /// peer replicates the feed.
+    fn handle_local_clock_updated(&mut self, ssb_id: &SsbId, msg_seq: u64) -> Result<()> {
+        if !self.local_clock.contains(ssb_id) {
+            return Ok(());
+        }
+
+        let value = clock::encode(true, Some(true), Some(msg_seq))?;
+        self.local_clock.insert(ssb_id, value);
+
+        let now = Instant::now();
+        for (connection_id, (peer_ssb_id, _session_role)) in self.active_sessions.iter() {
+            let replicated_by_peer = self
+                .peer_clocks
+                .get(peer_ssb_id)
+                .map_or(false, |clock| clock.contains_key(ssb_id));
+            if replicated_by_peer {
+                self.note_batch.queue(*connection_id, ssb_id, value, now);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Send the batched note updates, as a single vector clock per active
+    /// session.
+    async fn flush_notes(&mut self) -> Result<()> {
+        // Create channel to send messages to broker.
+        let mut ch_broker = BROKER.lock().await.create_sender();
+
+        for (connection_id, notes) in self.note_batch.take() {
+            if let Some((peer_ssb_id, _session_role)) = self.active_sessions.get(&connection_id) {
+                trace!(target: "ebt-replication", "Sending {} batched notes on connection {}", notes.len(), connection_id);
+
+                let notes = if direction::direction(peer_ssb_id).pulls() {
+                    EncodedClock::new(notes)?
+                } else {
+                    EncodedClock::new(direction::notes_only(&notes)?)?
+                };
+                ch_broker
+                    .send(BrokerEvent::new(
+                        Destination::Broadcast,
+                        BrokerMessage::Ebt(EbtEvent::SendClock(connection_id, notes)),
+                    ))
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Send the messages scheduled for the next push round.
+    async fn push_round(&mut self) -> Result<()> {
+        // Create channel to send messages to broker.
+        let mut ch_broker = BROKER.lock().await.create_sender();
+
+        for event in self.push_scheduler.next_round(PUSH_ROUND_BYTES) {
+            ch_broker
+                .send(BrokerEvent::new(
+                    Destination::Broadcast,
+                    BrokerMessage::Ebt(event),
+                ))
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Stop replicating the feed blocked or unfollowed by the given message
+    /// of the local feed, if any, and delete its read marks. Peers listed in
+    /// the replication configuration remain replicated when unfollowed, but
+    /// not when blocked.
+    async fn handle_local_contact(&mut self, msg_seq: u64) -> Result<()> {
+        let msg_kvt = match KV_STORE.read().await.get_msg_kvt(&self.local_id, msg_seq)? {
+            Some(msg_kvt) => msg_kvt,
+            None => return Ok(()),
+        };
+
+        let content = &msg_kvt.value["content"];
+        let contact = match content["contact"].as_str() {
+            Some(contact) if content["type"] == "contact" => contact.to_owned(),
+            _ => return Ok(()),
+        };
+
+        let blocking = content["blocking"].as_bool() == Some(true);
+        let unfollowing = content["following"].as_bool() == Some(false);
+        let configured = PEERS_TO_REPLICATE
+            .get()
+            .map_or(false, |peers| peers.contains_key(&contact));
+
+        // Clients no longer keep track of the messages of the feed.
+        if blocking || unfollowing {
+            KV_STORE.read().await.remove_read_marks(&contact)?;
+        }
+
+        if blocking || (unfollowing && !configured) {
+            self.handle_revoke(contact).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn handle_session_concluded(
+        &mut self,
+        connection_id: ConnectionId,
+        peer_ssb_id: SsbId,
+        session_role: SessionRole,
+    ) {
+        trace!(target: "ebt-replication", "Session concluded for connection {} with {} as {}", connection_id, peer_ssb_id, session_role);
+
+        journal::record(
+            &peer_ssb_id,
+            ReplicationEvent::SessionEnded {
+                protocol: "ebt".to_string(),
+            },
+        )
+        .await;
+
         self.remove_session(connection_id);
     }
 
@@ -666,6 +1198,14 @@ impl EbtManager {
     ) -> Result<()> {
         trace!(target: "ebt-replication", "Session timeout while waiting for request from {} on connection {}", peer_ssb_id, connection_data.id);
 
+        journal::record(
+            &peer_ssb_id,
+            ReplicationEvent::Error {
+                message: "Timed out waiting for EBT session request".to_string(),
+            },
+        )
+        .await;
+
         // Session should not have been initiated in the first place, meaning
         // that this removal action should be unnecessary. Keeping it here
         // for now out of caution.
@@ -673,6 +1213,10 @@ impl EbtManager {
         // TODO: Remove this line when it's clear that it's not needed.
         self.remove_session(connection_data.id);
 
+        // The peer did not request a session; classic replication is used
+        // right away on later connections.
+        capabilities::record(&peer_ssb_id, Capability::Ebt, false).await;
+
         // Create channel to send messages to broker.
         let mut ch_broker = BROKER.lock().await.create_sender();
 
@@ -699,6 +1243,14 @@ impl EbtManager {
     ) -> Result<()> {
         trace!(target: "ebt-replication", "Session error with {}: {}", peer_ssb_id, error_msg);
 
+        journal::record(
+            &peer_ssb_id,
+            ReplicationEvent::Error {
+                message: error_msg.to_owned(),
+            },
+        )
+        .await;
+
         self.remove_session(connection_data.id);
 
         // Create channel to send messages to broker.
@@ -711,7 +1263,9 @@ impl EbtManager {
             // EBT replicate request received an error response from the remote
             // peer.
             //
-            // Fallback to classic replication.
+            // Fallback to classic replication, which is used right away on
+            // later connections.
+            capabilities::record(&peer_ssb_id, Capability::Ebt, false).await;
             ch_broker
                 .send(BrokerEvent::new(
                     Destination::Broadcast,
@@ -719,10 +1273,20 @@ impl EbtManager {
                 ))
                 .await?;
         } else {
-            // Something else went wrong. Kill the connection.
+            // Something else went wrong. Kill the connection and refrain
+            // from redialing the peer for a while.
             //
             // TODO: In the future we may want to match on other specific error
             // variants. For now, this is good enough.
+            reputation::record_error(&peer_ssb_id);
+            if let Some(public_key) = connection_data.peer_public_key {
+                ch_broker
+                    .send(BrokerEvent::new(
+                        Destination::Broadcast,
+                        BrokerMessage::Penalize(PenaltyRequest(public_key)),
+                    ))
+                    .await?;
+            }
             ch_broker
                 .send(BrokerEvent::new(
                     Destination::Broadcast,
@@ -737,15 +1301,21 @@ impl EbtManager {
     /// Start the EBT event loop.
     ///
     /// Listen for EBT event messages via the broker and update EBT session
-    /// state accordingly.
-    pub async fn event_loop(mut self, local_id: SsbId, ebt_config_path: PathBuf) -> Result<()> {
+    /// state accordingly. Peer clocks are loaded from and persisted to the
+    /// given directory, or only kept in memory if no directory is given.
+    pub async fn event_loop(
+        mut self,
+        local_id: SsbId,
+        ebt_config_path: Option<PathBuf>,
+    ) -> Result<()> {
         debug!("Started EBT event loop");
 
         // Set the ID (@-prefixed public key) of the local node.
         self.local_id = local_id;
+        self.ebt_config_path = ebt_config_path;
 
         // Initialise the local clock based on peers to be replicated.
-        self.init_local_clock(&ebt_config_path).await?;
+        self.init_local_clock().await?;
 
         // Register the EBT event loop actor with the broker.
         let ActorEndpoint {
@@ -757,12 +1327,32 @@ impl EbtManager {
         let mut ch_terminate_fuse = ch_terminate.fuse();
         let mut broker_msg_ch = ch_msg.unwrap();
 
+        // Check for batched note updates which are due to be sent.
+        let mut note_ticker = stream::interval(NOTE_BATCH_WINDOW / 2).fuse();
+
+        // Serve the queued message pushes, round-robin across sessions.
+        let mut push_ticker = stream::interval(PUSH_ROUND_INTERVAL).fuse();
+
         // Listen for EBT events via the broker message bus.
         loop {
             select_biased! {
                 _value = ch_terminate_fuse => {
                     break;
                 },
+                _tick = note_ticker.next() => {
+                    if self.note_batch.is_due(Instant::now()) {
+                        if let Err(err) = self.flush_notes().await {
+                            error!("Error while sending batched notes: {}", err)
+                        }
+                    }
+                },
+                _tick = push_ticker.next() => {
+                    if !self.push_scheduler.is_empty() {
+                        if let Err(err) = self.push_round().await {
+                            error!("Error while pushing queued messages: {}", err)
+                        }
+                    }
+                },
                 msg = broker_msg_ch.next().fuse() => {
                     if let Some(BrokerMessage::Ebt(event)) = msg {
                         debug!("Received EBT event message from broker");
@@ -773,33 +1363,41 @@ impl EbtManager {
                             EbtEvent::RequestSession(connection_data) => {
                                 self.handle_request_session(connection_data).await;
                             }
-                            EbtEvent::SessionInitiated(connection_id, req_no, peer_ssb_id, session_role) => {
-                                if let Err(err) = self.handle_session_initiated(connection_id, req_no, peer_ssb_id, session_role).await {
+                            EbtEvent::SessionInitiated(connection_id, peer_ssb_id, session_role) => {
+                                if let Err(err) = self.handle_session_initiated(connection_id, peer_ssb_id, session_role).await {
                                     error!("Error while handling 'session initiated' event: {}", err)
                                 }
                             }
-                            EbtEvent::SendClock(connection_id, _req_no, clock, _session_role) => {
-                                trace!(target: "ebt-replication", "Sending vector clock: {:?}", clock);
-                                let _ = self.handle_send_clock(connection_id, clock);
+                            EbtEvent::SendClock(connection_id, clock) => {
+                                trace!(target: "ebt-replication", "Sending vector clock: {}", clock.json());
+                                let _ = self.handle_send_clock(connection_id, clock).await;
                             }
-                            EbtEvent::ReceivedClock(connection_id, req_no, peer_ssb_id, clock) => {
-                                if let Err(err) = self.handle_received_clock(connection_id, req_no, peer_ssb_id, clock).await {
+                            EbtEvent::ReceivedClock(connection_id, peer_ssb_id, clock) => {
+                                if let Err(err) = self.handle_received_clock(connection_id, peer_ssb_id, clock).await {
                                     error!("Error while handling 'received clock' event: {}", err)
                                 }
                             }
-                            EbtEvent::ReceivedMessage(msg) => {
-                                if let Err(err) = self.handle_received_message(msg).await {
+                            EbtEvent::ReceivedMessage(peer_ssb_id, msg) => {
+                                if let Err(err) = self.handle_received_message(peer_ssb_id, msg).await {
                                     error!("Error while handling 'received message' event: {}", err)
                                 }
                             }
-                            EbtEvent::SendMessage(_connection_id, _req_no, peer_ssb_id, msg, _session_role) => {
+                            EbtEvent::SendMessage(_connection_id, peer_ssb_id, msg) => {
                                 trace!(target: "ebt-replication", "Sending message: {:?}...", msg);
                                 if let Err(err) = self.handle_send_message(peer_ssb_id, msg).await {
                                     error!("Error while handling 'send message' event: {}", err)
                                 }
                             }
-                            EbtEvent::SessionConcluded(connection_id, peer_ssb_id) => {
-                                self.handle_session_concluded(connection_id, peer_ssb_id).await;
+                            EbtEvent::SendMessages(_connection_id, peer_ssb_id, msgs) => {
+                                trace!(target: "ebt-replication", "Sending {} messages...", msgs.len());
+                                for msg in msgs {
+                                    if let Err(err) = self.handle_send_message(peer_ssb_id.to_owned(), msg).await {
+                                        error!("Error while handling 'send messages' event: {}", err)
+                                    }
+                                }
+                            }
+                            EbtEvent::SessionConcluded(connection_id, peer_ssb_id, session_role) => {
+                                self.handle_session_concluded(connection_id, peer_ssb_id, session_role).await;
                             }
                             EbtEvent::SessionTimeout(connection_data, peer_ssb_id) => {
                                 if let Err(err) = self.handle_session_timeout(connection_data, peer_ssb_id).await {
@@ -814,10 +1412,35 @@ impl EbtManager {
                                     error!("Error while handling 'error' event: {}", err)
                                 }
                             }
+                            EbtEvent::Revoke(feed_id) => {
+                                if let Err(err) = self.handle_revoke(feed_id).await {
+                                    error!("Error while handling 'revoke' event: {}", err)
+                                }
+                            }
+                            EbtEvent::Replicate(feed_id) => {
+                                if let Err(err) = self.handle_replicate(feed_id).await {
+                                    error!("Error while handling 'replicate' event: {}", err)
+                                }
+                            }
+                            EbtEvent::QueryClock(query_id, peer_ssb_id) => {
+                                query::answer(query_id, self.get_clock(peer_ssb_id.as_ref()));
+                            }
                         }
                     } else if let Some(BrokerMessage::StoreKv(StoreKvEvent((ssb_id, seq)))) = msg {
                         debug!("Received KV store event from broker");
 
+                        // Queue a note update for the new sequence number.
+                        if let Err(err) = self.handle_local_clock_updated(&ssb_id, seq) {
+                            error!("Error while updating the local clock: {}", err)
+                        }
+
+                        // Stop replicating feeds blocked or unfollowed by the local identity.
+                        if ssb_id == self.local_id {
+                            if let Err(err) = self.handle_local_contact(seq).await {
+                                error!("Error while handling local contact message: {}", err)
+                            }
+                        }
+
                         // Respond to a key-value store state change for the given peer.
                         // This is triggered when a new message is appended to the local feed.
                         if let Err(err) = self.handle_local_store_updated(ssb_id, seq).await {
@@ -829,7 +1452,156 @@ impl EbtManager {
         }
 
         // Write all peer clocks to disk before exiting.
-        self.persist_peer_clocks(ebt_config_path)?;
+        self.persist_peer_clocks()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    use crate::{conformance, secret_config::SecretConfig};
+
+    /// Read the clock of the given peer persisted in the given directory.
+    fn read_peer_clock(ebt_config_path: &Path, ssb_id: &SsbId) -> Result<VectorClock> {
+        let json_clock = fs::read_to_string(EbtManager::peer_clock_path(ebt_config_path, ssb_id))?;
+
+        Ok(serde_json::from_str(&json_clock)?)
+    }
+
+    /// Return a manager persisting its peer clocks in the given directory,
+    /// replicating the given feed which the given peer also replicates.
+    fn manager_replicating(
+        ebt_config_path: &Path,
+        local_id: &SsbId,
+        peer_ssb_id: &SsbId,
+        feed_id: &SsbId,
+    ) -> Result<EbtManager> {
+        let mut manager = EbtManager {
+            local_id: local_id.to_owned(),
+            ebt_config_path: Some(ebt_config_path.to_path_buf()),
+            ..EbtManager::default()
+        };
+        manager.local_clock.insert(feed_id, 2);
+        manager.set_clock(
+            peer_ssb_id,
+            VectorClock::from([(feed_id.to_owned(), 2), (local_id.to_owned(), 4)]),
+        );
+        manager.persist_peer_clocks()?;
+
+        Ok(manager)
+    }
+
+    #[async_std::test]
+    async fn test_revoke_updates_persisted_clocks() -> Result<()> {
+        let dir = tempdir::TempDir::new("solarebt")?;
+        let local_id = SecretConfig::create().to_owned_identity()?.id;
+        let peer_ssb_id = SecretConfig::create().to_owned_identity()?.id;
+        let feed_id = SecretConfig::create().to_owned_identity()?.id;
+
+        let mut manager = manager_replicating(dir.path(), &local_id, &peer_ssb_id, &feed_id)?;
+        manager.handle_revoke(feed_id.to_owned()).await?;
+
+        assert!(!manager.local_clock.contains(&feed_id));
+        let expected = VectorClock::from([(local_id, 4)]);
+        assert_eq!(
+            manager.get_clock(Some(&peer_ssb_id)),
+            Some(expected.to_owned())
+        );
+        // The revoked feed is not loaded again after a restart.
+        assert_eq!(read_peer_clock(dir.path(), &peer_ssb_id)?, expected);
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_local_contact_revokes_unfollowed_feed() -> Result<()> {
+        conformance::open_store().await?;
+
+        let dir = tempdir::TempDir::new("solarebt")?;
+        let local = SecretConfig::create().to_owned_identity()?;
+        let peer_ssb_id = SecretConfig::create().to_owned_identity()?.id;
+        let feed = SecretConfig::create().to_owned_identity()?;
+        let feed_id = feed.id.to_owned();
+
+        let mut manager = manager_replicating(dir.path(), &local.id, &peer_ssb_id, &feed_id)?;
+
+        // A message of the feed is read by a client.
+        let feed_post = Message::sign(None, &feed, json!({ "type": "post", "text": "hello" }))?;
+        conformance::append(feed_post.to_owned()).await?;
+        KV_STORE
+            .read()
+            .await
+            .mark_read("token", &feed_post.id().to_string())?;
+
+        // A post does not change the replicated feeds.
+        let post = Message::sign(None, &local, json!({ "type": "post", "text": "hi" }))?;
+        let seq = conformance::append(post.to_owned()).await?;
+        manager.handle_local_contact(seq).await?;
+        assert!(manager.local_clock.contains(&feed_id));
+
+        let unfollow = Message::sign(
+            Some(&post),
+            &local,
+            json!({ "type": "contact", "contact": feed_id, "following": false }),
+        )?;
+        let seq = conformance::append(unfollow).await?;
+        manager.handle_local_contact(seq).await?;
+
+        assert!(!manager.local_clock.contains(&feed_id));
+        assert_eq!(
+            read_peer_clock(dir.path(), &peer_ssb_id)?,
+            VectorClock::from([(local.id, 4)])
+        );
+        // The read marks of the feed are deleted.
+        let unread = KV_STORE.read().await.get_unread_counts("token").await?;
+        assert_eq!(unread.get(&feed_id), Some(&1));
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_mirror_skips_blocked_and_pruned_feeds() -> Result<()> {
+        conformance::open_store().await?;
+
+        let local = SecretConfig::create().to_owned_identity()?;
+        let peer_ssb_id = SecretConfig::create().to_owned_identity()?.id;
+        let blocked_id = SecretConfig::create().to_owned_identity()?.id;
+        let offered_id = SecretConfig::create().to_owned_identity()?.id;
+
+        let block = Message::sign(
+            None,
+            &local,
+            json!({ "type": "contact", "contact": blocked_id, "blocking": true }),
+        )?;
+        conformance::append(block).await?;
+
+        let value = clock::encode(true, Some(true), Some(1))?;
+        let clock = VectorClock::from([
+            (blocked_id.to_owned(), value),
+            (offered_id.to_owned(), value),
+        ]);
+
+        let mut manager = EbtManager {
+            local_id: local.id.to_owned(),
+            ..EbtManager::default()
+        };
+        manager.mirror_feeds(&peer_ssb_id, &clock).await?;
+        assert!(manager.local_clock.contains(&offered_id));
+        assert!(!manager.local_clock.contains(&blocked_id));
+
+        // The offered feed is not followed, so it would be pruned.
+        let mut manager = EbtManager {
+            local_id: local.id.to_owned(),
+            ..EbtManager::default()
+        }
+        .prune_hops(Some(1));
+        manager.mirror_feeds(&peer_ssb_id, &clock).await?;
+        assert!(!manager.local_clock.contains(&offered_id));
 
         Ok(())
     }
@@ -1,6 +1,18 @@
-mod clock;
+mod batch;
+pub mod bendybutt;
+pub mod buttwoo;
+pub mod clock;
+mod clock_store;
+mod encoded;
+mod forwarding;
 mod manager;
+pub mod query;
 mod replicator;
+mod requests;
+mod scheduler;
 
-pub use clock::{EncodedClockValue, VectorClock};
+pub use clock::{EncodedClockValue, LocalClock, VectorClock};
+pub use encoded::{EncodedClock, EncodedMessage};
+pub use forwarding::{Entitlement, Refusal};
 pub use manager::{EbtEvent, EbtManager, SessionRole};
+pub use requests::{ActiveRequest, FeedFormat, EBT_REQUESTS};
@@ -0,0 +1,125 @@
+//! Queries of the vector clocks held by the EBT manager.
+//!
+//! The local clock and the latest clock received from each peer are owned by
+//! the EBT event loop. A copy of either is requested by broadcasting an
+//! `EbtEvent::QueryClock` event, which the event loop answers on the channel
+//! registered here under the ID of the query.
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use async_std::future;
+use futures::{channel::oneshot, SinkExt};
+use once_cell::sync::Lazy;
+use serde_json::{json, Map, Value};
+
+use crate::{
+    actors::replication::ebt::{clock, EbtEvent, VectorClock},
+    broker::{BrokerEvent, BrokerMessage, Destination, BROKER},
+    error::Error,
+    Result,
+};
+
+/// Time after which a query is abandoned if the event loop has not answered.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// ID of the most recent query.
+static LAST_QUERY_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Channels on which the clocks of the pending queries are returned, keyed
+/// by query ID.
+type PendingQueries = HashMap<u64, oneshot::Sender<Option<VectorClock>>>;
+
+static PENDING_QUERIES: Lazy<Mutex<PendingQueries>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Return the channels of the pending queries.
+fn pending_queries() -> std::sync::MutexGuard<'static, PendingQueries> {
+    PENDING_QUERIES
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+}
+
+/// Return the latest vector clock received from the peer with the given SSB
+/// ID, or the local clock if no peer is given. Returns `None` if no clock
+/// has been received from the peer.
+pub async fn query_clock(peer_ssb_id: Option<&str>) -> Result<Option<VectorClock>> {
+    let id = LAST_QUERY_ID.fetch_add(1, Ordering::Relaxed) + 1;
+    let (sender, receiver) = oneshot::channel();
+    pending_queries().insert(id, sender);
+
+    let mut ch_broker = BROKER.lock().await.create_sender();
+    ch_broker
+        .send(BrokerEvent::new(
+            Destination::Broadcast,
+            BrokerMessage::Ebt(EbtEvent::QueryClock(id, peer_ssb_id.map(str::to_owned))),
+        ))
+        .await?;
+
+    let result = future::timeout(QUERY_TIMEOUT, receiver).await;
+    pending_queries().remove(&id);
+
+    match result {
+        Ok(Ok(clock)) => Ok(clock),
+        _ => Err(Error::Other(
+            "timed out waiting for the EBT manager to return the clock".to_string(),
+        )),
+    }
+}
+
+/// Hand the given clock back to the caller of the query with the given ID,
+/// if it is still waiting.
+pub fn answer(id: u64, clock: Option<VectorClock>) {
+    if let Some(sender) = pending_queries().remove(&id) {
+        let _ = sender.send(clock);
+    }
+}
+
+/// Describe each value of the given vector clock: the encoded value along
+/// with its replicate flag, receive flag and sequence number.
+pub fn describe(clock: &VectorClock) -> Result<Value> {
+    let mut feeds = Map::new();
+    for (feed_id, value) in clock {
+        let (replicate, receive, seq) = clock::decode(*value)?;
+        feeds.insert(
+            feed_id.to_owned(),
+            json!({
+                "value": value,
+                "replicate": replicate,
+                "receive": receive,
+                "seq": seq,
+            }),
+        );
+    }
+
+    Ok(Value::Object(feeds))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_describe() -> Result<()> {
+        let mut local_clock = VectorClock::new();
+        local_clock.insert(
+            "@a=.ed25519".to_string(),
+            clock::encode(true, Some(true), Some(3))?,
+        );
+        local_clock.insert("@b=.ed25519".to_string(), clock::encode(false, None, None)?);
+
+        assert_eq!(
+            describe(&local_clock)?,
+            json!({
+                "@a=.ed25519": { "value": 6, "replicate": true, "receive": true, "seq": 3 },
+                "@b=.ed25519": { "value": -1, "replicate": false, "receive": null, "seq": null },
+            })
+        );
+
+        Ok(())
+    }
+}
@@ -1,48 +1,56 @@
 use std::time::{Duration, Instant};
 
-use futures::{pin_mut, select_biased, FutureExt, SinkExt, StreamExt};
+use async_std::{io::Write, task};
+use futures::{pin_mut, select_biased, FutureExt, SinkExt, Stream, StreamExt};
 use kuska_ssb::{
-    api::{dto::EbtReplicate, ApiCaller},
+    api::{
+        dto::{content::SsbId, EbtReplicate},
+        ApiCaller,
+    },
     crypto::ToSsbId,
     handshake::async_std::BoxStream,
-    rpc::{RpcReader, RpcWriter},
+    rpc::{RecvMsg, RpcReader, RpcWriter},
 };
 use log::{error, trace};
 
 use crate::{
     actors::{
-        muxrpc::{EbtReplicateHandler, RpcInput},
-        network::connection::ConnectionData,
-        replication::ebt::{EbtEvent, SessionRole},
+        muxrpc::{
+            AdminHandler, EbtReplicateHandler, OooHandler, PreviewHandler, ReqNo, RpcHandler,
+            RpcInput,
+        },
+        network::{config::TransportConfig, connection::ConnectionData, stats::MeteredStream},
+        replication::{
+            ebt::{bendybutt, buttwoo, EbtEvent, FeedFormat, SessionRole, EBT_REQUESTS},
+            quirks,
+        },
     },
     broker::{ActorEndpoint, BrokerEvent, BrokerMessage, Destination, Void, BROKER},
     Error, Result,
 };
 
+#[cfg(feature = "fault-injection")]
+use crate::actors::network::fault::FaultyStream;
+
 pub async fn run(
     connection_data: ConnectionData,
     session_role: SessionRole,
     session_wait_timeout: u64,
 ) -> Result<()> {
     // Register the EBT replication loop actor with the broker.
-    let ActorEndpoint {
-        ch_terminate,
-        ch_terminated,
-        ch_msg,
-        mut ch_broker,
-        ..
-    } = BROKER
+    let endpoint = BROKER
         .lock()
         .await
         .register("ebt-replication-loop", true)
         .await?;
 
-    let mut ch_msg = ch_msg.ok_or(Error::OptionIsNone)?;
-
     let connection_id = connection_data.id;
 
-    let stream_reader = connection_data.stream.clone().ok_or(Error::OptionIsNone)?;
-    let stream_writer = connection_data.stream.clone().ok_or(Error::OptionIsNone)?;
+    // Count the bytes exchanged with the peer in the network statistics.
+    let stream_reader =
+        MeteredStream::new(connection_data.stream.clone().ok_or(Error::OptionIsNone)?);
+    let stream_writer =
+        MeteredStream::new(connection_data.stream.clone().ok_or(Error::OptionIsNone)?);
     let handshake = connection_data
         .handshake
         .clone()
@@ -50,46 +58,140 @@ pub async fn run(
     let peer_ssb_id = handshake.peer_pk.to_ssb_id();
 
     // Instantiate a box stream and split it into reader and writer streams.
+    let buffer_size = TransportConfig::current().box_stream_buffer_size;
     let (box_stream_read, box_stream_write) =
-        BoxStream::from_handshake(stream_reader, stream_writer, handshake, 0x8000)
+        BoxStream::from_handshake(stream_reader, stream_writer, handshake, buffer_size)
             .split_read_write();
+    // Wrap the writer so that faults can be injected into outbound packets.
+    #[cfg(feature = "fault-injection")]
+    let box_stream_write = FaultyStream::new(box_stream_write, &peer_ssb_id);
 
     // Instantiate RPC reader and writer using the box streams.
     let rpc_reader = RpcReader::new(box_stream_read);
     let rpc_writer = RpcWriter::new(box_stream_write);
     let mut api = ApiCaller::new(rpc_writer);
 
-    // Instantiate the MUXRPC handler.
-    let mut ebt_replicate_handler = EbtReplicateHandler::new();
+    // Convert the box stream reader into a stream.
+    let rpc_recv_stream = rpc_reader.into_stream();
+
+    if let SessionRole::Requester = session_role {
+        // Send EBT request.
+        let ebt_args = EbtReplicate::default();
+        let req_no = api.ebt_replicate_req_send(&ebt_args).await?;
+
+        // Register the request of this session.
+        EBT_REQUESTS.write().await.register(
+            connection_id,
+            req_no,
+            SessionRole::Requester,
+            FeedFormat::Classic,
+        );
+
+        // Request the replication of the Bendy Butt and buttwoo feeds (if
+        // any) on streams of their own. Peers which do not support a format
+        // close its stream with an error, which leaves the session
+        // unaffected.
+        let clocks = [
+            (FeedFormat::BendyButt, bendybutt::local_clock().await?),
+            (FeedFormat::Buttwoo, buttwoo::local_clock().await?),
+        ];
+        for (format, clock) in clocks {
+            if clock.is_empty() {
+                continue;
+            }
+
+            let format_args = EbtReplicate {
+                format: format.name().to_owned(),
+                ..EbtReplicate::default()
+            };
+            let req_no = api.ebt_replicate_req_send(&format_args).await?;
+
+            EBT_REQUESTS.write().await.register(
+                connection_id,
+                req_no,
+                SessionRole::Requester,
+                format,
+            );
+            api.ebt_clock_res_send(-req_no, &serde_json::to_string(&clock)?)
+                .await?;
+        }
+    }
+
+    serve(
+        endpoint,
+        rpc_recv_stream,
+        &mut api,
+        connection_data,
+        peer_ssb_id,
+        session_role,
+        Duration::from_secs(session_wait_timeout),
+    )
+    .await
+}
+
+/// Serve the EBT sessions of a connection, handling the packets received
+/// from the peer and the messages of the broker.
+///
+/// The replication loop ends once it is terminated, once the peer
+/// disconnects and once a session fails or concludes (except a session in
+/// which the local peer acted as the responder, after which a new replicate
+/// request is awaited on the same connection). As the responder, the loop
+/// also ends with a session timeout if the peer does not initiate a session
+/// within the given duration, counted from the start of the loop or from
+/// the conclusion of the previous session.
+async fn serve<S, W>(
+    endpoint: ActorEndpoint,
+    rpc_recv_stream: S,
+    api: &mut ApiCaller<W>,
+    connection_data: ConnectionData,
+    peer_ssb_id: SsbId,
+    session_role: SessionRole,
+    session_wait_timeout: Duration,
+) -> Result<()>
+where
+    S: Stream<Item = (ReqNo, RecvMsg)>,
+    W: Write + Unpin + Send + Sync,
+{
+    let ActorEndpoint {
+        ch_terminate,
+        ch_terminated,
+        ch_msg,
+        mut ch_broker,
+        ..
+    } = endpoint;
+
+    let mut ch_msg = ch_msg.ok_or(Error::OptionIsNone)?;
+
+    let connection_id = connection_data.id;
+
+    // Instantiate the MUXRPC handlers.
+    let mut ebt_replicate_handler =
+        EbtReplicateHandler::new().legacy_quirks(quirks::is_legacy_peer(&peer_ssb_id));
+    let mut admin_handler = AdminHandler::new(&peer_ssb_id);
+    let mut preview_handler = PreviewHandler::new(&peer_ssb_id);
+    let mut ooo_handler = OooHandler::default();
 
     // Fuse internal termination channel with external channel.
     // This allows termination of the peer loop to be initiated from outside
     // this function.
     let mut ch_terminate_fuse = ch_terminate.fuse();
 
-    // Convert the box stream reader into a stream.
-    let rpc_recv_stream = rpc_reader.into_stream().fuse();
+    let rpc_recv_stream = rpc_recv_stream.fuse();
     pin_mut!(rpc_recv_stream);
 
     trace!(target: "ebt-session", "Initiating EBT replication session with: {}", peer_ssb_id);
 
     let mut session_initiated = false;
-    let mut active_req_no = None;
 
-    // Record the time at which we begin the EBT session.
+    // Set once the termination of the replication loop has been requested.
+    let mut terminating = false;
+
+    // Record the time at which we begin waiting for the EBT session.
     //
     // This is later used to implement a timeout if no request or response is
-    // received.
-    let ebt_session_start = Instant::now();
-
-    if let SessionRole::Requester = session_role {
-        // Send EBT request.
-        let ebt_args = EbtReplicate::default();
-        let req_no = api.ebt_replicate_req_send(&ebt_args).await?;
-
-        // Set the request number for this session.
-        active_req_no = Some(req_no);
-    }
+    // received. It is reset once a session in which the local peer acted as
+    // the responder concludes, to await a new replicate request.
+    let mut ebt_session_start = Instant::now();
 
     loop {
         // Poll multiple futures and streams simultaneously, executing the
@@ -97,6 +199,7 @@ pub async fn run(
         // ready, one will be selected in order of declaration.
         let input = select_biased! {
             _value = ch_terminate_fuse =>  {
+                terminating = true;
                 // Communicate stream termination to the session peer.
                 RpcInput::Message(
                     BrokerMessage::Ebt(
@@ -104,24 +207,30 @@ pub async fn run(
                     )
                 )
             },
-            packet = rpc_recv_stream.select_next_some() => {
-                let (req_no, packet) = packet;
-                RpcInput::Network(req_no, packet)
+            packet = rpc_recv_stream.next().fuse() => {
+                match packet {
+                    Some((req_no, packet)) => RpcInput::Network(req_no, packet),
+                    // The peer closed the connection.
+                    None => {
+                        trace!(target: "ebt-session", "{} disconnected", peer_ssb_id);
+                        break
+                    }
+                }
             },
             msg = ch_msg.next().fuse() => {
                 // Listen for a 'session concluded' event and terminate the
                 // replicator if the connection ID of the event matches the
-                // ID of this instance of the replicator.
-                if let Some(BrokerMessage::Ebt(EbtEvent::SessionConcluded(conn_id, _))) = msg {
+                // ID of this instance of the replicator. A concluded
+                // responder session leaves the connection open.
+                if let Some(BrokerMessage::Ebt(EbtEvent::SessionConcluded(conn_id, _, SessionRole::Requester))) = msg {
                     if connection_id == conn_id {
                         break
                     }
                 }
                 // Listen for a 'session initiated' event.
-                if let Some(BrokerMessage::Ebt(EbtEvent::SessionInitiated(_connection_id, ref req_no, ref ssb_id, ref session_role))) = msg {
-                    if peer_ssb_id == *ssb_id && *session_role == SessionRole::Responder {
+                if let Some(BrokerMessage::Ebt(EbtEvent::SessionInitiated(conn_id, _, SessionRole::Responder))) = msg {
+                    if connection_id == conn_id {
                         session_initiated = true;
-                        active_req_no = Some(*req_no);
                     }
                 }
                 if let Some(msg) = msg {
@@ -130,21 +239,65 @@ pub async fn run(
                     RpcInput::None
                 }
             },
+            // Check the session wait timeout while no input is received.
+            _ = task::sleep(Duration::from_secs(1)).fuse() => RpcInput::Timer,
         };
 
+        // Serve the admin methods alongside the EBT session.
+        match admin_handler.handle(api, &input, &mut ch_broker).await {
+            Ok(true) => continue,
+            Err(err) => error!("Admin handler failed: {:?}", err),
+            _ => (),
+        }
+
+        // Fetch the feed previews requested from the peer.
+        match preview_handler.handle(api, &input, &mut ch_broker).await {
+            Ok(true) => continue,
+            Err(err) => error!("Preview handler failed: {:?}", err),
+            _ => (),
+        }
+
+        // Fetch the messages requested out of order from the peer.
+        match ooo_handler.handle(api, &input, &mut ch_broker).await {
+            Ok(true) => continue,
+            Err(err) => error!("Out-of-order handler failed: {:?}", err),
+            _ => (),
+        }
+
         match ebt_replicate_handler
             .handle(
-                &mut api,
+                api,
                 &input,
                 &mut ch_broker,
                 // TODO: Can we remove this?
                 // We could look it up from the connection ID instead.
                 peer_ssb_id.to_owned(),
-                connection_data.id,
-                active_req_no,
+                connection_id,
             )
             .await
         {
+            // The peer closed the stream of a session in which the local peer
+            // acted as the responder. Conclude the session but keep the
+            // replication loop alive, awaiting a new replicate request on
+            // the same connection.
+            Ok(true) if !terminating && session_initiated => {
+                trace!(target: "ebt-session", "Awaiting a new EBT replication request from {}", peer_ssb_id);
+
+                ch_broker
+                    .send(BrokerEvent::new(
+                        Destination::Broadcast,
+                        BrokerMessage::Ebt(EbtEvent::SessionConcluded(
+                            connection_id,
+                            peer_ssb_id.to_owned(),
+                            SessionRole::Responder,
+                        )),
+                    ))
+                    .await?;
+
+                EBT_REQUESTS.write().await.remove(connection_id);
+                session_initiated = false;
+                ebt_session_start = Instant::now();
+            }
             Ok(true) => break,
             Err(err) => {
                 error!("EBT replicate handler failed: {:?}", err);
@@ -166,12 +319,13 @@ pub async fn run(
             _ => (),
         }
 
-        // If no active session has been initiated within 5 seconds of
-        // waiting to receive a replicate request, broadcast a session timeout
-        // event (leading to initiation of classic replication).
+        // If no active session has been initiated within the session wait
+        // timeout while waiting to receive a replicate request, broadcast a
+        // session timeout event (leading to initiation of classic
+        // replication).
         if !session_initiated
             && session_role == SessionRole::Responder
-            && ebt_session_start.elapsed() >= Duration::from_secs(session_wait_timeout)
+            && ebt_session_start.elapsed() >= session_wait_timeout
         {
             trace!(target: "ebt-session", "Timeout while waiting for {} to initiate EBT replication session", peer_ssb_id);
 
@@ -191,12 +345,16 @@ pub async fn run(
         }
     }
 
-    // TODO: Consider including session role in SessionConcluded so that we can
-    // await another request if acting as the responder.
+    EBT_REQUESTS.write().await.remove(connection_id);
+
     ch_broker
         .send(BrokerEvent::new(
             Destination::Broadcast,
-            BrokerMessage::Ebt(EbtEvent::SessionConcluded(connection_id, peer_ssb_id)),
+            BrokerMessage::Ebt(EbtEvent::SessionConcluded(
+                connection_id,
+                peer_ssb_id,
+                session_role,
+            )),
         ))
         .await?;
 
@@ -205,3 +363,167 @@ pub async fn run(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use async_std::future;
+    use futures::channel::{mpsc, oneshot};
+    use serde_json::json;
+
+    use crate::{
+        actors::network::connection::ConnectionId,
+        broker::{ChMsgSend, ChSigSend},
+        conformance::{self, SharedBuffer},
+        secret_config::SecretConfig,
+    };
+
+    /// Return the endpoint of a replication loop, along with the channels
+    /// of the broker on the other end.
+    fn endpoint() -> (
+        ActorEndpoint,
+        ChMsgSend,
+        mpsc::UnboundedReceiver<BrokerEvent>,
+        ChSigSend,
+    ) {
+        let (ch_broker, events) = mpsc::unbounded();
+        let (ch_msg, msgs) = mpsc::unbounded();
+        let (ch_terminate, terminate) = oneshot::channel();
+        let (terminated, _) = oneshot::channel();
+
+        let endpoint = ActorEndpoint {
+            actor_id: 0,
+            ch_broker,
+            ch_terminate: terminate,
+            ch_terminated: terminated,
+            ch_msg: Some(msgs),
+        };
+
+        (endpoint, ch_msg, events, ch_terminate)
+    }
+
+    /// Return the replicate request of classic feeds sent by a peer with the
+    /// given request number, as received.
+    async fn replicate_request(req_no: ReqNo) -> (ReqNo, RecvMsg) {
+        let body = json!({
+            "name": ["ebt", "replicate"],
+            "type": "duplex",
+            "args": [{ "version": 3, "format": "classic" }],
+        });
+        let packet = conformance::json_packet(req_no, body.to_string().as_bytes());
+
+        match conformance::decode(&packet).await.pop() {
+            Some(RpcInput::Network(req_no, msg)) => (req_no, msg),
+            _ => panic!("the replicate request could not be decoded"),
+        }
+    }
+
+    /// Let the replication loop handle the inputs sent so far.
+    async fn settle() {
+        for _ in 0..10 {
+            task::yield_now().await;
+        }
+    }
+
+    /// Return the session events sent to the broker by the replication loop.
+    fn session_events(events: &mut mpsc::UnboundedReceiver<BrokerEvent>) -> Vec<&'static str> {
+        let mut session_events = Vec::new();
+        while let Ok(Some(event)) = events.try_next() {
+            match event {
+                BrokerEvent::Message {
+                    msg: BrokerMessage::Ebt(EbtEvent::SessionConcluded(..)),
+                    ..
+                } => session_events.push("concluded"),
+                BrokerEvent::Message {
+                    msg: BrokerMessage::Ebt(EbtEvent::SessionTimeout(..)),
+                    ..
+                } => session_events.push("timeout"),
+                BrokerEvent::Message {
+                    msg: BrokerMessage::Ebt(EbtEvent::Error(..)),
+                    ..
+                } => session_events.push("error"),
+                _ => (),
+            }
+        }
+
+        session_events
+    }
+
+    /// Run a replication loop as the responder, in which the peer initiates
+    /// a session and closes it, and then either disconnects or stays idle.
+    /// Returns the session events sent by the loop once it has ended.
+    async fn responder_session(
+        connection_id: ConnectionId,
+        session_wait_timeout: Duration,
+        disconnect: bool,
+    ) -> Result<Vec<&'static str>> {
+        let (endpoint, mut ch_msg, mut events, _ch_terminate) = endpoint();
+        let (mut packets, rpc_recv_stream) = mpsc::unbounded();
+        let mut api = ApiCaller::new(RpcWriter::new(SharedBuffer::default()));
+        let peer_ssb_id = SecretConfig::create().public_key;
+
+        let replication_loop = serve(
+            endpoint,
+            rpc_recv_stream,
+            &mut api,
+            ConnectionData::new(connection_id),
+            peer_ssb_id.to_owned(),
+            SessionRole::Responder,
+            session_wait_timeout,
+        );
+        let peer = async {
+            packets.send(replicate_request(1).await).await?;
+            ch_msg
+                .send(BrokerMessage::Ebt(EbtEvent::SessionInitiated(
+                    connection_id,
+                    peer_ssb_id.to_owned(),
+                    SessionRole::Responder,
+                )))
+                .await?;
+            settle().await;
+
+            // The peer closes the stream of the session.
+            packets.send((1, RecvMsg::CancelStreamResponse())).await?;
+            settle().await;
+
+            if disconnect {
+                packets.close_channel();
+            }
+
+            Ok::<_, Error>(packets)
+        };
+
+        let (result, _packets) = future::timeout(
+            Duration::from_secs(5),
+            futures::future::join(replication_loop, peer),
+        )
+        .await
+        .expect("the replication loop did not end");
+        result?;
+
+        Ok(session_events(&mut events))
+    }
+
+    #[async_std::test]
+    async fn test_disconnect_after_concluded_session() -> Result<()> {
+        // The loop awaits a new replicate request once the session has
+        // concluded, and ends once the peer disconnects.
+        let session_events =
+            responder_session(usize::MAX - 1, Duration::from_secs(60), true).await?;
+        assert_eq!(session_events, vec!["concluded", "concluded"]);
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_session_wait_timeout_after_concluded_session() -> Result<()> {
+        // The session wait timeout is re-armed once the session has
+        // concluded.
+        let session_events =
+            responder_session(usize::MAX - 2, Duration::from_millis(500), false).await?;
+        assert_eq!(session_events, vec!["concluded", "timeout", "concluded"]);
+
+        Ok(())
+    }
+}
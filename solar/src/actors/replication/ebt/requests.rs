@@ -0,0 +1,315 @@
+//! Registry of active EBT replicate requests.
+//!
+//! Each EBT session runs on a replicate request (a duplex stream). The
+//! request number of the stream and the role of the local peer in the
+//! session are registered here, keyed by connection ID and request number,
+//! when the request is sent or received. A peer may open more than one
+//! replicate stream on a connection (and the local peer may act as both
+//! requester and responder), so several requests may be active on a
+//! connection at once: received clocks and messages are routed by request
+//! number, while clocks and messages are sent on the most recently
+//! registered request of the connection. This allows EBT events to identify
+//! a session by connection ID alone, leaving the request numbers (and their
+//! signs) to the MUXRPC handler.
+//!
+//! Each request replicates feeds of a single format: classic feeds are
+//! replicated by the EBT session, while Bendy Butt feeds (metafeeds) are
+//! replicated on a stream of their own, handled by the MUXRPC handler.
+use std::{collections::HashMap, fmt};
+
+use async_std::sync::{Arc, RwLock};
+use once_cell::sync::Lazy;
+
+use solar_core::{bendybutt, buttwoo};
+
+use crate::actors::{
+    muxrpc::ReqNo, network::connection::ConnectionId, replication::ebt::SessionRole,
+};
+
+/// The registry of active EBT replicate requests.
+pub static EBT_REQUESTS: Lazy<Arc<RwLock<RequestRegistry>>> =
+    Lazy::new(|| Arc::new(RwLock::new(RequestRegistry::default())));
+
+/// The format of the feeds replicated by an EBT replicate request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FeedFormat {
+    Classic,
+    BendyButt,
+    Buttwoo,
+}
+
+impl FeedFormat {
+    /// Return the feed format with the given name, as given in replicate
+    /// requests.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "classic" => Some(FeedFormat::Classic),
+            bendybutt::FORMAT => Some(FeedFormat::BendyButt),
+            buttwoo::FORMAT => Some(FeedFormat::Buttwoo),
+            _ => None,
+        }
+    }
+
+    /// Return the name of the feed format, as given in replicate requests.
+    pub fn name(&self) -> &'static str {
+        match self {
+            FeedFormat::Classic => "classic",
+            FeedFormat::BendyButt => bendybutt::FORMAT,
+            FeedFormat::Buttwoo => buttwoo::FORMAT,
+        }
+    }
+}
+
+impl fmt::Display for FeedFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// An active EBT replicate request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ActiveRequest {
+    /// The number of the request, as sent (requester) or received
+    /// (responder).
+    pub req_no: ReqNo,
+    /// The role of the local peer in the session.
+    pub session_role: SessionRole,
+    /// The format of the replicated feeds.
+    pub format: FeedFormat,
+}
+
+impl ActiveRequest {
+    /// Return the request number to be passed to the response-sending
+    /// methods of the API (which negate it) when sending a vector clock or a
+    /// message on the stream: the requester sends with a positive request
+    /// number and the responder with a negative one.
+    pub fn response_req_no(&self) -> ReqNo {
+        match self.session_role {
+            SessionRole::Requester => -self.req_no,
+            SessionRole::Responder => self.req_no,
+        }
+    }
+
+    /// Return the request number with which the stream is closed.
+    pub fn cancel_req_no(&self) -> ReqNo {
+        match self.session_role {
+            SessionRole::Requester => self.req_no,
+            SessionRole::Responder => -self.req_no,
+        }
+    }
+
+    /// Return the request number with which the packets of the stream are
+    /// received: responses to a sent request carry its negated number.
+    fn recv_req_no(&self) -> ReqNo {
+        match self.session_role {
+            SessionRole::Requester => -self.req_no,
+            SessionRole::Responder => self.req_no,
+        }
+    }
+
+    /// Query whether the given received request number belongs to the
+    /// stream of this request.
+    pub fn matches(&self, req_no: ReqNo) -> bool {
+        self.req_no == req_no || self.req_no == -req_no
+    }
+}
+
+/// Active EBT replicate requests, keyed by connection ID and received
+/// request number.
+#[derive(Debug, Default)]
+pub struct RequestRegistry {
+    requests: HashMap<(ConnectionId, ReqNo), ActiveRequest>,
+    /// Received request number of the most recently registered request of
+    /// each connection and feed format, on which clocks and messages are
+    /// sent.
+    current: HashMap<(ConnectionId, FeedFormat), ReqNo>,
+}
+
+impl RequestRegistry {
+    /// Register a replicate request of feeds of the given format on the
+    /// given connection, alongside any other active requests of the
+    /// connection.
+    pub fn register(
+        &mut self,
+        connection_id: ConnectionId,
+        req_no: ReqNo,
+        session_role: SessionRole,
+        format: FeedFormat,
+    ) {
+        let request = ActiveRequest {
+            req_no,
+            session_role,
+            format,
+        };
+        let recv_req_no = request.recv_req_no();
+
+        self.requests.insert((connection_id, recv_req_no), request);
+        self.current.insert((connection_id, format), recv_req_no);
+    }
+
+    /// Return the most recently registered replicate request of classic
+    /// feeds of the given connection, on which the clocks and messages of
+    /// the EBT session are sent.
+    pub fn get(&self, connection_id: ConnectionId) -> Option<ActiveRequest> {
+        self.get_format(connection_id, FeedFormat::Classic)
+    }
+
+    /// Return the most recently registered replicate request of feeds of the
+    /// given format of the given connection.
+    pub fn get_format(
+        &self,
+        connection_id: ConnectionId,
+        format: FeedFormat,
+    ) -> Option<ActiveRequest> {
+        let recv_req_no = self.current.get(&(connection_id, format))?;
+        self.requests.get(&(connection_id, *recv_req_no)).cloned()
+    }
+
+    /// Return the replicate request of the given connection to which the
+    /// packet received with the given request number belongs. Packets whose
+    /// request number has an unexpected sign are tolerated.
+    pub fn find(&self, connection_id: ConnectionId, req_no: ReqNo) -> Option<ActiveRequest> {
+        self.requests
+            .get(&(connection_id, req_no))
+            .or_else(|| self.requests.get(&(connection_id, -req_no)))
+            .cloned()
+    }
+
+    /// Return the replicate requests of the given connection.
+    pub fn requests(&self, connection_id: ConnectionId) -> Vec<ActiveRequest> {
+        self.requests
+            .iter()
+            .filter(|((id, _), _)| *id == connection_id)
+            .map(|(_, request)| request.clone())
+            .collect()
+    }
+
+    /// Remove the replicate request of the given connection to which the
+    /// packet received with the given request number belongs. Clocks and
+    /// messages are then sent on another active request of the connection
+    /// (of the same feed format), if any. Returns the removed request.
+    pub fn remove_request(
+        &mut self,
+        connection_id: ConnectionId,
+        req_no: ReqNo,
+    ) -> Option<ActiveRequest> {
+        let request = self.find(connection_id, req_no)?;
+        let recv_req_no = request.recv_req_no();
+        self.requests.remove(&(connection_id, recv_req_no));
+
+        let current_key = (connection_id, request.format);
+        if self.current.get(&current_key) == Some(&recv_req_no) {
+            let other = self
+                .requests
+                .iter()
+                .find(|((id, _), other)| *id == connection_id && other.format == request.format);
+            match other {
+                Some(((_, other), _)) => self.current.insert(current_key, *other),
+                None => self.current.remove(&current_key),
+            };
+        }
+
+        Some(request)
+    }
+
+    /// Remove the replicate requests of the given connection.
+    pub fn remove(&mut self, connection_id: ConnectionId) {
+        self.requests.retain(|(id, _), _| *id != connection_id);
+        self.current.retain(|(id, _), _| *id != connection_id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_request_registry() {
+        let mut registry = RequestRegistry::default();
+        registry.register(1, 3, SessionRole::Requester, FeedFormat::Classic);
+        registry.register(2, 5, SessionRole::Responder, FeedFormat::Classic);
+
+        let requester = registry.get(1).unwrap();
+        assert_eq!(requester.response_req_no(), -3);
+        assert_eq!(requester.cancel_req_no(), 3);
+        assert!(requester.matches(3));
+        assert!(requester.matches(-3));
+        assert!(!requester.matches(5));
+
+        let responder = registry.get(2).unwrap();
+        assert_eq!(responder.response_req_no(), 5);
+        assert_eq!(responder.cancel_req_no(), -5);
+
+        registry.remove(1);
+        assert!(registry.get(1).is_none());
+    }
+
+    #[test]
+    fn test_concurrent_requests() {
+        let mut registry = RequestRegistry::default();
+
+        // The local peer requests a session on stream 3 while the peer opens
+        // streams 3 and 7.
+        registry.register(1, 3, SessionRole::Requester, FeedFormat::Classic);
+        registry.register(1, 3, SessionRole::Responder, FeedFormat::Classic);
+        registry.register(1, 7, SessionRole::Responder, FeedFormat::Classic);
+        registry.register(2, 7, SessionRole::Requester, FeedFormat::Classic);
+        assert_eq!(registry.requests(1).len(), 3);
+
+        // Received packets are routed to the stream they belong to.
+        assert_eq!(
+            registry.find(1, -3).unwrap().session_role,
+            SessionRole::Requester
+        );
+        assert_eq!(
+            registry.find(1, 3).unwrap().session_role,
+            SessionRole::Responder
+        );
+        assert_eq!(registry.find(1, 7).unwrap().req_no, 7);
+        assert!(registry.find(1, 5).is_none());
+
+        // Clocks and messages are sent on the latest request, then on the
+        // remaining ones once it is closed.
+        assert_eq!(registry.get(1).unwrap().req_no, 7);
+        registry.remove_request(1, 7);
+        assert!(registry.get(1).unwrap().matches(3));
+        registry.remove_request(1, -3);
+        registry.remove_request(1, 3);
+        assert!(registry.get(1).is_none());
+
+        // Requests of other connections are left alone.
+        assert!(registry.find(2, -7).is_some());
+    }
+
+    #[test]
+    fn test_feed_formats() {
+        let mut registry = RequestRegistry::default();
+        registry.register(1, 3, SessionRole::Requester, FeedFormat::Classic);
+        registry.register(1, 4, SessionRole::Requester, FeedFormat::BendyButt);
+
+        // Classic clocks and messages are not sent on the Bendy Butt stream.
+        assert_eq!(registry.get(1).unwrap().req_no, 3);
+        assert_eq!(
+            registry
+                .get_format(1, FeedFormat::BendyButt)
+                .unwrap()
+                .req_no,
+            4
+        );
+        assert_eq!(registry.find(1, -4).unwrap().format, FeedFormat::BendyButt);
+
+        registry.remove_request(1, -3);
+        assert!(registry.get(1).is_none());
+        assert!(registry.get_format(1, FeedFormat::BendyButt).is_some());
+
+        assert_eq!(
+            FeedFormat::from_name("bendybutt-v1"),
+            Some(FeedFormat::BendyButt)
+        );
+        assert_eq!(
+            FeedFormat::from_name("buttwoo-v1"),
+            Some(FeedFormat::Buttwoo)
+        );
+        assert!(FeedFormat::from_name("indexed-v1").is_none());
+    }
+}
@@ -0,0 +1,204 @@
+//! Fair scheduling of pushed messages.
+//!
+//! The messages to be pushed to each session are queued rather than sent as
+//! soon as they are requested. The queues are served in rounds, using
+//! deficit round-robin: on each round, every session with queued messages is
+//! granted a byte budget (`PUSH_ROUND_BYTES`) and is sent queued batches for
+//! as long as the budget allows, any unused budget being carried over to the
+//! next round. A peer performing a full sync thus cannot starve the updates
+//! pushed to other peers.
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
+
+use kuska_ssb::api::dto::content::SsbId;
+
+use crate::actors::{
+    network::connection::ConnectionId,
+    replication::ebt::{EbtEvent, EncodedMessage},
+};
+
+/// Interval between two scheduling rounds.
+pub const PUSH_ROUND_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Number of bytes granted to each session on every scheduling round.
+pub const PUSH_ROUND_BYTES: usize = 256 * 1024;
+
+/// A batch of messages waiting to be pushed.
+#[derive(Debug)]
+struct Push {
+    msgs: Vec<EncodedMessage>,
+    bytes: usize,
+}
+
+/// The messages waiting to be pushed to a session.
+#[derive(Debug)]
+struct SessionQueue {
+    /// The SSB ID of the session peer.
+    peer_ssb_id: SsbId,
+    pushes: VecDeque<Push>,
+    /// Budget carried over from the previous rounds.
+    deficit: usize,
+}
+
+/// Messages waiting to be pushed, keyed by the connection ID of the session.
+#[derive(Debug, Default)]
+pub struct PushScheduler {
+    queues: HashMap<ConnectionId, SessionQueue>,
+    /// Order in which the sessions are served.
+    order: VecDeque<ConnectionId>,
+}
+
+impl PushScheduler {
+    /// Queue a batch of consecutive messages of a feed to be pushed to the
+    /// given session.
+    pub fn queue(
+        &mut self,
+        connection_id: ConnectionId,
+        peer_ssb_id: &SsbId,
+        msgs: Vec<EncodedMessage>,
+    ) {
+        if msgs.is_empty() {
+            return;
+        }
+
+        let bytes = msgs.iter().map(EncodedMessage::size).sum();
+        let order = &mut self.order;
+        self.queues
+            .entry(connection_id)
+            .or_insert_with(|| {
+                order.push_back(connection_id);
+                SessionQueue {
+                    peer_ssb_id: peer_ssb_id.to_owned(),
+                    pushes: VecDeque::new(),
+                    deficit: 0,
+                }
+            })
+            .pushes
+            .push_back(Push { msgs, bytes });
+    }
+
+    /// Query whether any messages are waiting to be pushed.
+    pub fn is_empty(&self) -> bool {
+        self.queues.is_empty()
+    }
+
+    /// Serve one scheduling round, granting `quantum` bytes to each session,
+    /// and return the events to be sent. Sessions whose queue is emptied are
+    /// dropped from the schedule.
+    pub fn next_round(&mut self, quantum: usize) -> Vec<EbtEvent> {
+        let mut events = Vec::new();
+
+        for connection_id in self.order.iter() {
+            let queue = match self.queues.get_mut(connection_id) {
+                Some(queue) => queue,
+                None => continue,
+            };
+            queue.deficit = queue.deficit.saturating_add(quantum);
+
+            while let Some(push) = queue.pushes.front() {
+                if push.bytes > queue.deficit {
+                    break;
+                }
+                queue.deficit -= push.bytes;
+
+                let mut msgs = queue.pushes.pop_front().unwrap().msgs;
+                let peer_ssb_id = queue.peer_ssb_id.to_owned();
+                events.push(if msgs.len() == 1 {
+                    EbtEvent::SendMessage(*connection_id, peer_ssb_id, msgs.remove(0))
+                } else {
+                    EbtEvent::SendMessages(*connection_id, peer_ssb_id, msgs)
+                });
+            }
+        }
+
+        self.remove_empty_queues();
+
+        // Start the next round with the following session.
+        self.order.rotate_left(self.order.len().min(1));
+
+        events
+    }
+
+    /// Drop the messages waiting to be pushed to the given session.
+    pub fn remove_session(&mut self, connection_id: ConnectionId) {
+        self.queues.remove(&connection_id);
+        self.order.retain(|id| *id != connection_id);
+    }
+
+    /// Drop the messages of the given feed waiting to be pushed.
+    pub fn remove_feed(&mut self, feed_id: &str) {
+        for queue in self.queues.values_mut() {
+            queue.pushes.retain(|push| push.msgs[0].author() != feed_id);
+        }
+        self.remove_empty_queues();
+    }
+
+    /// Drop the sessions with no messages waiting to be pushed from the
+    /// schedule.
+    fn remove_empty_queues(&mut self) {
+        self.queues.retain(|_, queue| !queue.pushes.is_empty());
+        let queues = &self.queues;
+        self.order
+            .retain(|connection_id| queues.contains_key(connection_id));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    fn connection_ids(events: &[EbtEvent]) -> Vec<ConnectionId> {
+        events
+            .iter()
+            .map(|event| match event {
+                EbtEvent::SendMessage(connection_id, ..) => *connection_id,
+                EbtEvent::SendMessages(connection_id, ..) => *connection_id,
+                _ => panic!("unexpected event: {:?}", event),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_push_scheduler() {
+        let msg = |sequence: u64| {
+            EncodedMessage::from_value(&json!({ "author": "@a", "sequence": sequence })).unwrap()
+        };
+        let msg_bytes = msg(1).size();
+
+        let (peer1, peer2) = (String::from("@peer1"), String::from("@peer2"));
+
+        let mut scheduler = PushScheduler::default();
+        assert!(scheduler.is_empty());
+
+        // A full sync to the first peer does not starve the second peer.
+        for sequence in 1..=10 {
+            scheduler.queue(1, &peer1, vec![msg(sequence)]);
+        }
+        scheduler.queue(2, &peer2, vec![msg(10)]);
+
+        let events = scheduler.next_round(msg_bytes * 2);
+        assert_eq!(connection_ids(&events), vec![1, 1, 2]);
+
+        // Sessions whose queue is empty are dropped from the schedule.
+        let events = scheduler.next_round(msg_bytes * 2);
+        assert_eq!(connection_ids(&events), vec![1, 1]);
+
+        // Unused budget is carried over to the next round.
+        scheduler.queue(2, &peer2, vec![msg(11), msg(12), msg(13)]);
+        let events = scheduler.next_round(msg_bytes * 2);
+        assert_eq!(connection_ids(&events), vec![1, 1]);
+        let events = scheduler.next_round(msg_bytes * 2);
+        assert_eq!(connection_ids(&events), vec![2, 1, 1]);
+
+        scheduler.remove_feed("@a");
+        assert!(scheduler.is_empty());
+
+        scheduler.queue(1, &peer1, vec![msg(1)]);
+        scheduler.remove_session(1);
+        assert!(scheduler.is_empty());
+    }
+}
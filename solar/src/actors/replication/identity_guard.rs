@@ -0,0 +1,77 @@
+//! Guard against the local identity being in use on several devices.
+//!
+//! Publishing with the same keypair from two devices forks the local feed:
+//! both devices sign a different message with the same sequence number and
+//! peers permanently reject the feed from the fork onwards. A message of the
+//! local feed presented by a peer with a sequence number higher than that of
+//! the latest stored message is evidence of the keypair being in use
+//! elsewhere. When such a message is received, it is not stored, the
+//! conflict is recorded in the database (and in the replication log of the
+//! peer) and publishing is disabled until the conflict is cleared via the
+//! `clear_identity_conflict` JSON-RPC method.
+//!
+//! Messages of the local feed are expected from peers when the local
+//! database is being resynced, in which case the guard is disabled.
+use kuska_ssb::feed::Message;
+use log::error;
+
+use crate::{
+    actors::replication::journal,
+    config::{RESYNC_CONFIG, SECRET_CONFIG},
+    error::Error,
+    node::KV_STORE,
+    storage::kv::{IdentityConflict, ReplicationEvent},
+    util::now_millis,
+    Result,
+};
+
+/// Check a message received from the peer with the given SSB ID. Returns
+/// `true` if the message is evidence of the local identity being in use on
+/// another device, in which case the conflict is recorded and the message
+/// must not be stored.
+pub async fn check_received(msg: &Message, peer_ssb_id: &str) -> Result<bool> {
+    let local_id = &SECRET_CONFIG.get().ok_or(Error::OptionIsNone)?.public_key;
+    if msg.author() != local_id.as_str() || RESYNC_CONFIG.get().copied().unwrap_or(false) {
+        return Ok(false);
+    }
+
+    let local_sequence = KV_STORE.read().await.get_latest_seq(local_id)?.unwrap_or(0);
+    if msg.sequence() <= local_sequence {
+        return Ok(false);
+    }
+
+    let conflict = IdentityConflict {
+        peer: peer_ssb_id.to_owned(),
+        sequence: msg.sequence(),
+        local_sequence,
+        detected_at: now_millis(),
+    };
+    if KV_STORE.read().await.set_identity_conflict(&conflict)? {
+        error!(
+            "IDENTITY CONFLICT: peer {} presented message {} of the local feed, which only has {} messages; the keypair appears to be in use on another device. Publishing is disabled until the conflict is cleared",
+            peer_ssb_id, conflict.sequence, local_sequence
+        );
+    }
+
+    journal::record(
+        peer_ssb_id,
+        ReplicationEvent::IdentityConflict {
+            sequence: conflict.sequence,
+        },
+    )
+    .await;
+
+    Ok(true)
+}
+
+/// Return an error if an identity conflict has been recorded, in which case
+/// publishing on the local feed must not proceed.
+pub async fn ensure_no_conflict() -> Result<()> {
+    match KV_STORE.read().await.get_identity_conflict()? {
+        Some(conflict) => Err(Error::IdentityConflict(format!(
+            "peer {} presented message {} of the local feed; clear the conflict once the keypair is no longer in use on another device",
+            conflict.peer, conflict.sequence
+        ))),
+        None => Ok(()),
+    }
+}
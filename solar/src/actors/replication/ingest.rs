@@ -0,0 +1,424 @@
+//! Ingest rate limits.
+//!
+//! To protect storage from floods of messages (for example, on open pubs),
+//! the number of messages of an author accepted per hour can be limited with
+//! the `[ingest]` table of `replication.toml`. Authors within `exempt_hops`
+//! of the local identity in the follow graph are exempt from the limit.
+//!
+//! Messages in excess of the limit are either dropped, to be replicated
+//! again later on, or queued in memory and stored once the limit allows. In
+//! both cases, the later messages of the author are held back as well, so
+//! that they do not fail validation for not following the latest stored
+//! message. An `ingest_limited` event is recorded in the replication log of
+//! the peer from which the messages were received whenever an author reaches
+//! the limit.
+//!
+//! The ingest job periodically releases the queued messages which the limit
+//! allows, passing them to the EBT replication manager to be stored.
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_std::{stream, sync::RwLock};
+use futures::{select_biased, stream::StreamExt, FutureExt, SinkExt};
+use kuska_ssb::feed::Message;
+use log::warn;
+use once_cell::sync::Lazy;
+
+use crate::{
+    actors::replication::{config::ExcessAction, ebt::EbtEvent, journal},
+    broker::{ActorEndpoint, BrokerEvent, BrokerMessage, Destination, Void, BROKER},
+    config::{INGEST_POLICY, SECRET_CONFIG},
+    error::Error,
+    node::KV_STORE,
+    storage::kv::ReplicationEvent,
+    Result,
+};
+
+/// Interval between releases of queued messages.
+pub const INGEST_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Period over which the messages of an author are counted.
+const PERIOD: Duration = Duration::from_secs(60 * 60);
+
+/// Maximum number of messages queued for each author. Messages beyond the
+/// limit are dropped.
+const MAX_QUEUED_PER_AUTHOR: usize = 1000;
+
+/// Time for which the authors exempt from the limit are cached, since they
+/// are looked up for every received message.
+const EXEMPT_AUTHORS_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// State of the limits of the authors which sent messages recently.
+static LIMITER: Lazy<Mutex<Limiter>> = Lazy::new(|| Mutex::new(Limiter::default()));
+
+/// Authors exempt from the limit, along with the time at which they were
+/// looked up.
+static EXEMPT_AUTHORS: Lazy<RwLock<Option<(Instant, HashSet<String>)>>> =
+    Lazy::new(|| RwLock::new(None));
+
+/// Outcome of the admission of a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Admission {
+    /// The message is to be stored.
+    Accepted,
+    /// The message is held until the limit allows.
+    Queued,
+    /// The message is dropped.
+    Dropped,
+}
+
+/// State of the limit of an author.
+#[derive(Debug, Default)]
+struct AuthorState {
+    /// Times at which messages of the author were accepted within the
+    /// period.
+    accepted: VecDeque<Instant>,
+    /// Queued messages, along with the SSB ID of the peer from which each
+    /// was received.
+    queued: VecDeque<(String, Arc<Message>)>,
+    /// Sequence numbers of the queued messages which have been released and
+    /// are accepted on their way back.
+    released: HashSet<u64>,
+    /// Sequence number of the first dropped message of the author, from
+    /// which later messages are dropped as well.
+    dropped_from: Option<u64>,
+    /// Set once the author has reached the limit, until a message of the
+    /// author is accepted again.
+    limited: bool,
+}
+
+impl AuthorState {
+    /// Forget the accepted messages which fall out of the period.
+    fn forget(&mut self, now: Instant) {
+        while let Some(time) = self.accepted.front() {
+            if now.duration_since(*time) >= PERIOD {
+                self.accepted.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Query whether the state no longer affects the author.
+    fn is_idle(&self) -> bool {
+        self.accepted.is_empty()
+            && self.queued.is_empty()
+            && self.released.is_empty()
+            && self.dropped_from.is_none()
+    }
+}
+
+/// Limits of the messages accepted per author.
+#[derive(Debug, Default)]
+struct Limiter {
+    authors: HashMap<String, AuthorState>,
+}
+
+impl Limiter {
+    /// Admit the given message, received from the peer with the given SSB
+    /// ID at the given time, given the limit and the action taken on excess
+    /// messages. Returns the outcome and whether the author has just reached
+    /// the limit.
+    fn admit(
+        &mut self,
+        limit: usize,
+        excess: ExcessAction,
+        peer_ssb_id: &str,
+        msg: &Message,
+        now: Instant,
+    ) -> (Admission, bool) {
+        let state = self.authors.entry(msg.author().to_string()).or_default();
+        state.forget(now);
+
+        if state.released.remove(&msg.sequence()) {
+            if state.queued.is_empty() {
+                state.limited = false;
+            }
+
+            return (Admission::Accepted, false);
+        }
+
+        // A dropped message lifts the hold once it is received again.
+        let held_back = match state.dropped_from {
+            Some(dropped_from) => msg.sequence() > dropped_from,
+            None => !state.queued.is_empty(),
+        };
+        if !held_back && state.accepted.len() < limit {
+            state.accepted.push_back(now);
+            state.dropped_from = None;
+            state.limited = false;
+
+            return (Admission::Accepted, false);
+        }
+
+        let newly_limited = !state.limited;
+        state.limited = true;
+
+        if state.dropped_from.is_none() && excess == ExcessAction::Queue {
+            if state
+                .queued
+                .iter()
+                .any(|(_, queued)| queued.sequence() == msg.sequence())
+            {
+                return (Admission::Queued, newly_limited);
+            }
+            if state.queued.len() < MAX_QUEUED_PER_AUTHOR {
+                state
+                    .queued
+                    .push_back((peer_ssb_id.to_owned(), Arc::new(msg.clone())));
+
+                return (Admission::Queued, newly_limited);
+            }
+        }
+
+        if state.dropped_from.map_or(true, |seq| msg.sequence() < seq) {
+            state.dropped_from = Some(msg.sequence());
+        }
+
+        (Admission::Dropped, newly_limited)
+    }
+
+    /// Release the queued messages which the given limit allows at the given
+    /// time, counting them as accepted, and forget the authors whose state
+    /// no longer affects them.
+    fn release(&mut self, limit: usize, now: Instant) -> Vec<(String, Arc<Message>)> {
+        let mut released = Vec::new();
+
+        for state in self.authors.values_mut() {
+            state.forget(now);
+            // The messages released on the previous call have come back by
+            // now, unless they were rejected on the way.
+            state.released.clear();
+            while state.accepted.len() < limit {
+                match state.queued.pop_front() {
+                    Some((peer_ssb_id, msg)) => {
+                        state.accepted.push_back(now);
+                        state.released.insert(msg.sequence());
+                        released.push((peer_ssb_id, msg));
+                    }
+                    None => break,
+                }
+            }
+        }
+        self.authors.retain(|_, state| !state.is_idle());
+
+        released
+    }
+}
+
+/// Query whether the given author is exempt from the limit, being within the
+/// given number of hops from the local identity.
+async fn is_exempt(author: &str, exempt_hops: usize) -> Result<bool> {
+    if let Some((looked_up_at, authors)) = &*EXEMPT_AUTHORS.read().await {
+        if looked_up_at.elapsed() < EXEMPT_AUTHORS_TTL {
+            return Ok(authors.contains(author));
+        }
+    }
+
+    let local_id = &SECRET_CONFIG.get().ok_or(Error::OptionIsNone)?.public_key;
+    let authors: HashSet<String> = {
+        let db = KV_STORE.read().await;
+        let indexes = db.indexes.as_ref().ok_or(Error::Indexes)?;
+        indexes
+            .get_hops(local_id, exempt_hops)?
+            .into_keys()
+            .collect()
+    };
+    let exempt = authors.contains(author);
+    *EXEMPT_AUTHORS.write().await = Some((Instant::now(), authors));
+
+    Ok(exempt)
+}
+
+/// Admit the given message, received from the peer with the given SSB ID,
+/// under the ingest rate limits. Returns `true` if the message is to be
+/// stored now.
+pub async fn admit(peer_ssb_id: &str, msg: &Message) -> Result<bool> {
+    let policy = match INGEST_POLICY.get() {
+        Some(policy) => policy,
+        None => return Ok(true),
+    };
+    let limit = match policy.max_messages_per_hour {
+        Some(limit) => limit,
+        None => return Ok(true),
+    };
+
+    let author = msg.author().to_string();
+    if is_exempt(&author, policy.exempt_hops).await? {
+        return Ok(true);
+    }
+
+    let (admission, newly_limited) = LIMITER.lock().unwrap_or_else(|err| err.into_inner()).admit(
+        limit,
+        policy.excess,
+        peer_ssb_id,
+        msg,
+        Instant::now(),
+    );
+
+    if newly_limited {
+        warn!(
+            "{} exceeds the ingest limit of {} messages per hour; excess messages from {} are {}",
+            author,
+            limit,
+            peer_ssb_id,
+            if admission == Admission::Queued {
+                "queued"
+            } else {
+                "dropped"
+            }
+        );
+        journal::record(
+            peer_ssb_id,
+            ReplicationEvent::IngestLimited {
+                feed: author,
+                action: policy.excess,
+            },
+        )
+        .await;
+    }
+
+    Ok(admission == Admission::Accepted)
+}
+
+/// Start the ingest job.
+///
+/// Register the job with the broker (as an actor) and release the queued
+/// messages which the limit allows at the given interval, passing them to
+/// the EBT replication manager to be stored.
+pub async fn actor(interval: Duration) -> Result<()> {
+    // Register the ingest actor with the broker.
+    let ActorEndpoint {
+        ch_terminate,
+        mut ch_broker,
+        ch_terminated,
+        ..
+    } = BROKER.lock().await.register("ingest-queue", false).await?;
+
+    let mut ch_terminate_fuse = ch_terminate.fuse();
+    let mut ticker = stream::interval(interval).fuse();
+
+    let limit = INGEST_POLICY
+        .get()
+        .and_then(|policy| policy.max_messages_per_hour);
+
+    loop {
+        select_biased! {
+            // Received termination signal. Break out of the loop.
+            _value = ch_terminate_fuse => {
+                break;
+            },
+            _tick = ticker.next() => {
+                let limit = match limit {
+                    Some(limit) => limit,
+                    None => continue,
+                };
+
+                let released = LIMITER
+                    .lock()
+                    .unwrap_or_else(|err| err.into_inner())
+                    .release(limit, Instant::now());
+                for (peer_ssb_id, msg) in released {
+                    ch_broker
+                        .send(BrokerEvent::new(
+                            Destination::Broadcast,
+                            BrokerMessage::Ebt(EbtEvent::ReceivedMessage(peer_ssb_id, msg)),
+                        ))
+                        .await?;
+                }
+            }
+        }
+    }
+
+    let _ = ch_terminated.send(Void {});
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    use crate::secret_config::SecretConfig;
+
+    /// Return a feed of the given number of messages.
+    fn feed(len: usize) -> Result<Vec<Message>> {
+        let keypair = SecretConfig::create().to_owned_identity()?;
+        let mut msgs: Vec<Message> = Vec::new();
+        for _ in 0..len {
+            let msg = Message::sign(
+                msgs.last(),
+                &keypair,
+                json!({ "type": "post", "text": "hi" }),
+            )?;
+            msgs.push(msg);
+        }
+
+        Ok(msgs)
+    }
+
+    #[test]
+    fn test_drop_excess() -> Result<()> {
+        let msgs = feed(4)?;
+        let mut limiter = Limiter::default();
+        let now = Instant::now();
+        let mut admit = |msg, now| limiter.admit(2, ExcessAction::Drop, "@peer", msg, now);
+
+        assert_eq!(admit(&msgs[0], now), (Admission::Accepted, false));
+        assert_eq!(admit(&msgs[1], now), (Admission::Accepted, false));
+        assert_eq!(admit(&msgs[2], now), (Admission::Dropped, true));
+
+        // Later messages are held back, even once the limit allows, until
+        // the dropped message is received again.
+        let later = now + PERIOD;
+        assert_eq!(admit(&msgs[3], later), (Admission::Dropped, false));
+        assert_eq!(admit(&msgs[2], later), (Admission::Accepted, false));
+        assert_eq!(admit(&msgs[3], later), (Admission::Accepted, false));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_queue_excess() -> Result<()> {
+        let msgs = feed(4)?;
+        let mut limiter = Limiter::default();
+        let now = Instant::now();
+
+        for msg in &msgs[..2] {
+            limiter.admit(2, ExcessAction::Queue, "@peer", msg, now);
+        }
+        assert_eq!(
+            limiter.admit(2, ExcessAction::Queue, "@peer", &msgs[2], now),
+            (Admission::Queued, true)
+        );
+        assert_eq!(
+            limiter.admit(2, ExcessAction::Queue, "@peer", &msgs[3], now),
+            (Admission::Queued, false)
+        );
+        assert!(limiter.release(2, now).is_empty());
+
+        // Queued messages are released in order once the limit allows, and
+        // accepted on their way back.
+        let later = now + PERIOD;
+        let released = limiter.release(2, later);
+        assert_eq!(released.len(), 2);
+        assert_eq!(released[0].1.sequence(), 3);
+        for (_, msg) in &released {
+            assert_eq!(
+                limiter.admit(2, ExcessAction::Queue, "@peer", msg, later),
+                (Admission::Accepted, false)
+            );
+        }
+
+        // Idle authors are forgotten.
+        limiter.release(2, later + PERIOD);
+        assert!(limiter.authors.is_empty());
+
+        Ok(())
+    }
+}
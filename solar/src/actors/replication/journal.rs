@@ -0,0 +1,30 @@
+//! Record replication events in the replication log of each peer.
+//!
+//! The log is persisted in the key-value store and can be queried via the
+//! `replication_log` JSON-RPC method, allowing users to report exactly what
+//! happened when replication with a specific peer failed.
+
+use log::warn;
+
+use crate::{node::KV_STORE, storage::kv::ReplicationEvent, util::prefixed};
+
+/// Record a replication event for the peer with the given SSB ID.
+///
+/// Failure to record the event is logged but otherwise ignored; the
+/// replication log is a diagnostic aid and must not interrupt replication.
+pub async fn record(peer_ssb_id: &str, event: ReplicationEvent) {
+    // Peers are identified by their `@`-prefixed public key throughout the
+    // key-value store.
+    let peer_ssb_id = prefixed(peer_ssb_id);
+
+    if let Err(err) = KV_STORE
+        .read()
+        .await
+        .append_replication_log(&peer_ssb_id, event)
+    {
+        warn!(
+            "Failed to record replication event for {}: {}",
+            peer_ssb_id, err
+        )
+    }
+}
@@ -1,4 +1,14 @@
+pub mod blob_events;
 pub mod blobs;
+pub mod capabilities;
 pub mod classic;
 pub mod config;
+pub mod direction;
+pub mod duplicates;
 pub mod ebt;
+pub mod identity_guard;
+pub mod ingest;
+pub mod journal;
+pub mod quirks;
+pub mod trace;
+pub mod want_list;
@@ -0,0 +1,191 @@
+//! Compatibility quirks of legacy pubs.
+//!
+//! Old ssb-server (ssb-js) pubs deviate from the protocol as implemented
+//! today in a few known ways:
+//!
+//! - EBT notes may encode sequence numbers as strings (`"12"`) or floats
+//!   (`12.0`), may use `null` or `false` in place of `-1` to stop
+//!   replication of a feed, and may omit the `@` prefix of feed IDs.
+//! - `createHistoryStream` may be called with positional arguments
+//!   (`[id, seq, live]`), with a bare options object rather than an array,
+//!   with stringified numbers and flags, or with `sequence` in place of
+//!   `seq`.
+//!
+//! These quirks are only tolerated for peers listed under `legacy_peers` in
+//! `replication.toml`; messages from other peers are parsed strictly.
+
+use kuska_ssb::api::dto;
+use serde_json::{Map, Value};
+
+use crate::{
+    actors::replication::ebt::{EncodedClockValue, VectorClock},
+    config::LEGACY_PEERS,
+    util::prefixed,
+    Result,
+};
+
+/// Query whether the given peer is to be treated as a legacy pub.
+pub fn is_legacy_peer(peer_ssb_id: &str) -> bool {
+    let peer_ssb_id = prefixed(peer_ssb_id);
+
+    LEGACY_PEERS
+        .get()
+        .map(|peers| peers.contains(&peer_ssb_id))
+        .unwrap_or(false)
+}
+
+/// Parse a number which may have been stringified or encoded as a float.
+fn parse_number(value: &Value) -> Option<i64> {
+    match value {
+        Value::Number(number) => number.as_i64().or_else(|| {
+            number
+                .as_f64()
+                .filter(|float| float.fract() == 0.0)
+                .map(|float| float as i64)
+        }),
+        Value::String(string) => string.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+/// Parse a flag which may have been stringified.
+fn parse_flag(value: &Value) -> Option<bool> {
+    match value {
+        Value::Bool(flag) => Some(*flag),
+        Value::String(string) => string.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+/// Leniently parse a vector clock (aka. notes) sent by a legacy pub. Returns
+/// `None` if the data is not a vector clock (for example, when it is a
+/// message).
+pub fn parse_clock(data: &[u8]) -> Option<VectorClock> {
+    let notes: Map<String, Value> = serde_json::from_slice(data).ok()?;
+
+    let mut clock = VectorClock::new();
+    for (ssb_id, value) in notes {
+        if !ssb_id.ends_with(".ed25519") {
+            return None;
+        }
+        let ssb_id = prefixed(&ssb_id);
+
+        let value: EncodedClockValue = match value {
+            Value::Null | Value::Bool(false) => -1,
+            value => parse_number(&value)?,
+        };
+        clock.insert(ssb_id, value);
+    }
+
+    Some(clock)
+}
+
+/// Leniently parse the arguments of a `createHistoryStream` request sent by
+/// a legacy pub.
+pub fn parse_history_stream_args(args: &Value) -> Result<dto::CreateHistoryStreamIn> {
+    let mut options = Map::new();
+
+    match args {
+        // Options object, either bare or as the only element of an array.
+        Value::Object(object) => options = object.clone(),
+        Value::Array(array) => match array.as_slice() {
+            [Value::Object(object), ..] => options = object.clone(),
+            // Positional arguments: `[id, seq, live]`.
+            [id, rest @ ..] => {
+                options.insert("id".to_string(), id.clone());
+                if let Some(seq) = rest.first() {
+                    options.insert("seq".to_string(), seq.clone());
+                }
+                if let Some(live) = rest.get(1) {
+                    options.insert("live".to_string(), live.clone());
+                }
+            }
+            [] => (),
+        },
+        _ => (),
+    }
+
+    if let Some(seq) = options.remove("sequence") {
+        options.entry("seq").or_insert(seq);
+    }
+
+    for key in ["seq", "limit"] {
+        match options.get(key).map(parse_number) {
+            Some(Some(number)) if number >= 0 => {
+                options.insert(key.to_string(), Value::from(number));
+            }
+            Some(_) => {
+                options.remove(key);
+            }
+            None => (),
+        }
+    }
+    for key in ["live", "old", "keys"] {
+        match options.get(key).map(parse_flag) {
+            Some(Some(flag)) => {
+                options.insert(key.to_string(), Value::Bool(flag));
+            }
+            Some(None) => {
+                options.remove(key);
+            }
+            None => (),
+        }
+    }
+
+    Ok(serde_json::from_value(Value::Object(options))?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    const FEED: &str = "@FCX/tsDLpubCPKKfIrw4gc+SQkHcaD17s7GI6i/ziWY=.ed25519";
+
+    #[test]
+    fn test_parse_clock() {
+        let clock = parse_clock(
+            br#"{
+                "@FCX/tsDLpubCPKKfIrw4gc+SQkHcaD17s7GI6i/ziWY=.ed25519": "12",
+                "HEqy940T6uB+T+d9Jaa58aNfRzLx9eRWqkZljBmnkmk=.ed25519": 4.0,
+                "@o8lWpyLeSqV/BJV9pbxFhKpwm6Lw5k+sqexYK+zT9Tc=.ed25519": null
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(clock.get(FEED), Some(&12));
+        assert_eq!(
+            clock.get("@HEqy940T6uB+T+d9Jaa58aNfRzLx9eRWqkZljBmnkmk=.ed25519"),
+            Some(&4)
+        );
+        assert_eq!(
+            clock.get("@o8lWpyLeSqV/BJV9pbxFhKpwm6Lw5k+sqexYK+zT9Tc=.ed25519"),
+            Some(&-1)
+        );
+
+        // Messages are not mistaken for clocks.
+        assert!(parse_clock(br#"{"previous":null,"author":"@abc","sequence":1}"#).is_none());
+        assert!(parse_clock(br#"{"@abc.ed25519":"twelve"}"#).is_none());
+    }
+
+    #[test]
+    fn test_parse_history_stream_args() -> Result<()> {
+        let args = parse_history_stream_args(&json!([FEED, "5", "true"]))?;
+        assert_eq!(args.id, FEED);
+        assert_eq!(args.seq, Some(5));
+        assert_eq!(args.live, Some(true));
+
+        let args = parse_history_stream_args(&json!({ "id": FEED, "sequence": 3 }))?;
+        assert_eq!(args.seq, Some(3));
+        assert_eq!(args.live, None);
+
+        let args = parse_history_stream_args(&json!([{ "id": FEED, "seq": 2.0, "live": "no" }]))?;
+        assert_eq!(args.seq, Some(2));
+        assert_eq!(args.live, None);
+
+        assert!(parse_history_stream_args(&json!([])).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,53 @@
+//! Trace the replication of the messages of selected feeds.
+//!
+//! For each feed listed under `traced_feeds` in `replication.toml`, the peer
+//! from which each message is received and the peers to which it is sent
+//! are recorded, along with the time and the replication protocol. The trace
+//! can be queried via the `message_trace` JSON-RPC method, to find out where
+//! the messages of a feed went (for example, when posts are reported not to
+//! reach a given peer).
+
+use log::warn;
+
+use crate::{config::TRACED_FEEDS, node::KV_STORE, storage::kv::TraceDirection, util::prefixed};
+
+/// Query whether the messages of the given feed are traced.
+pub fn is_traced(feed_id: &str) -> bool {
+    TRACED_FEEDS
+        .get()
+        .map_or(false, |feeds| feeds.contains(&prefixed(feed_id)))
+}
+
+/// Record the given message of the given feed as received from or sent to
+/// the peer with the given SSB ID, if the feed is traced.
+///
+/// Failure to record the message is logged but otherwise ignored; the trace
+/// is a diagnostic aid and must not interrupt replication.
+pub async fn record(
+    feed_id: &str,
+    sequence: u64,
+    peer_ssb_id: &str,
+    direction: TraceDirection,
+    protocol: &str,
+) {
+    if !is_traced(feed_id) {
+        return;
+    }
+
+    let feed_id = prefixed(feed_id);
+    let peer_ssb_id = prefixed(peer_ssb_id);
+
+    // Acquire a write lock to serialize concurrent updates to the trace.
+    if let Err(err) = KV_STORE.write().await.append_message_trace(
+        &feed_id,
+        sequence,
+        &peer_ssb_id,
+        direction,
+        protocol,
+    ) {
+        warn!(
+            "Failed to trace msg {} of {} for {}: {}",
+            sequence, feed_id, peer_ssb_id, err
+        )
+    }
+}
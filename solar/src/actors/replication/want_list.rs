@@ -0,0 +1,195 @@
+//! Blob Want-List
+//!
+//! Blobs referenced by replicated messages (linked in posts or set as
+//! images in about messages) are wanted automatically, provided the author
+//! of the message is within the hops range of the blob fetch policy or the
+//! message was posted in a channel to which the local identity is
+//! subscribed. Wanted blobs are requested from the connected peers as soon as the messages are
+//! received. Since a blob may not be available from any peer at that time,
+//! the wanted blobs are persisted in the database, along with the number of
+//! requests made and the time of the next request. The want-list job
+//! periodically re-requests the wanted blobs whose next request is due
+//! (backing off exponentially between attempts) and removes blobs from the
+//! want-list once they are stored.
+//!
+//! The want-list is also announced to each newly connected peer, so that
+//! wants are not lost when the node is restarted.
+//!
+//! Wanted blobs are not requested while storage is critically low.
+use std::time::Duration;
+
+use async_std::stream;
+use futures::{select_biased, stream::StreamExt, FutureExt, SinkExt};
+use kuska_ssb::{api::dto::BlobsGetIn, feed::Message};
+use log::{trace, warn};
+
+use crate::{
+    actors::{
+        muxrpc::RpcBlobsGetEvent,
+        replication::{
+            blob_events::{self, BlobEvent},
+            blobs,
+        },
+        retention::pressure,
+    },
+    broker::{ActorEndpoint, BrokerEvent, BrokerMessage, ChBrokerSend, Destination, BROKER},
+    node::{BLOB_STORE, KV_STORE},
+    storage::{blob::StoreBlobEvent, kv::BlobWant},
+    util::now_millis,
+    Result,
+};
+
+/// Interval at which the want-list is checked for due requests.
+pub const WANT_LIST_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Delay before the first retry of a request, doubled with each attempt.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Maximum delay between two requests of a blob.
+const MAX_BACKOFF: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// Return the delay before the next request of a blob which has been
+/// requested the given number of times.
+fn backoff(attempts: u32) -> Duration {
+    let factor = 2u32.saturating_pow(attempts.saturating_sub(1));
+    INITIAL_BACKOFF
+        .checked_mul(factor)
+        .map_or(MAX_BACKOFF, |backoff| backoff.min(MAX_BACKOFF))
+}
+
+/// Request the given blob from the connected peers and schedule the next
+/// request.
+async fn request(blob_id: &str, mut want: BlobWant, ch_broker: &mut ChBrokerSend) -> Result<()> {
+    trace!(target: "ssb-blob", "requesting wanted blob {} (attempt {})", blob_id, want.attempts + 1);
+
+    let event = RpcBlobsGetEvent(BlobsGetIn::new(blob_id.to_owned()));
+    let broker_msg = BrokerEvent::new(Destination::Broadcast, BrokerMessage::RpcBlobsGet(event));
+    ch_broker.send(broker_msg).await?;
+
+    want.attempts = want.attempts.saturating_add(1);
+    want.next_attempt = now_millis() + backoff(want.attempts).as_millis() as u64;
+    KV_STORE.read().await.set_blob_want(blob_id, &want)?;
+
+    Ok(())
+}
+
+/// Add the given blob to the want-list and request it from the connected
+/// peers, unless it is already stored, already wanted or disallowed by the
+/// blob fetch policy.
+pub async fn want(blob_id: &str, ch_broker: &mut ChBrokerSend) -> Result<()> {
+    if BLOB_STORE.read().await.exists(blob_id) || !blobs::is_fetch_allowed(blob_id, None).await? {
+        return Ok(());
+    }
+
+    if KV_STORE.read().await.want_blob(blob_id)? {
+        blob_events::emit(BlobEvent::WantRegistered {
+            blob_id: blob_id.to_owned(),
+        })
+        .await;
+        request(blob_id, BlobWant::default(), ch_broker).await?;
+    }
+
+    Ok(())
+}
+
+/// Add the blobs referenced by the given message to the want-list, provided
+/// the author of the message is within the hops range of the blob fetch
+/// policy or the message was posted in a subscribed channel.
+pub async fn want_referenced(msg: &Message, ch_broker: &mut ChBrokerSend) -> Result<()> {
+    let blob_ids = blobs::extract_blob_refs(msg);
+    if blob_ids.is_empty() {
+        return Ok(());
+    }
+
+    if !blobs::is_auto_wanted(msg.author()).await? && !blobs::is_in_subscribed_channel(msg).await? {
+        trace!(target: "ssb-blob", "not wanting blobs referenced by {} (out of range)", msg.author());
+        return Ok(());
+    }
+
+    for blob_id in blob_ids {
+        want(&blob_id, ch_broker).await?;
+    }
+
+    Ok(())
+}
+
+/// Request the wanted blobs whose next request is due. Blobs which have been
+/// stored (or are no longer allowed by the blob fetch policy) are removed
+/// from the want-list.
+pub async fn retry_wants(ch_broker: &mut ChBrokerSend) -> Result<()> {
+    if pressure::is_critical() {
+        trace!(target: "ssb-blob", "storage is critically low; not requesting wanted blobs");
+        return Ok(());
+    }
+
+    let now = now_millis();
+
+    let wants = KV_STORE.read().await.get_blob_wants()?;
+    for (blob_id, want) in wants {
+        if BLOB_STORE.read().await.exists(&blob_id)
+            || !blobs::is_fetch_allowed(&blob_id, None).await?
+        {
+            KV_STORE.read().await.remove_blob_want(&blob_id)?;
+        } else if want.next_attempt <= now {
+            request(&blob_id, want, ch_broker).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Start the want-list job.
+///
+/// Register the job with the broker (as an actor), remove blobs from the
+/// want-list as they are stored and request the wanted blobs which are due
+/// at the given interval.
+pub async fn actor(interval: Duration) -> Result<()> {
+    // Register the want-list actor with the broker.
+    let ActorEndpoint {
+        ch_terminate,
+        mut ch_broker,
+        ch_msg,
+        ..
+    } = BROKER.lock().await.register("blob-want-list", true).await?;
+
+    let mut ch_terminate_fuse = ch_terminate.fuse();
+    let mut broker_msg_ch = ch_msg.unwrap();
+    let mut ticker = stream::interval(interval).fuse();
+
+    loop {
+        select_biased! {
+            // Received termination signal. Break out of the loop.
+            _value = ch_terminate_fuse => {
+                break;
+            },
+            msg = broker_msg_ch.next().fuse() => {
+                if let Some(BrokerMessage::StoreBlob(StoreBlobEvent(blob_id))) = msg {
+                    if let Err(err) = KV_STORE.read().await.remove_blob_want(&blob_id) {
+                        warn!("Failed to remove blob {} from the want-list: {}", blob_id, err)
+                    }
+                }
+            },
+            _tick = ticker.next() => {
+                if let Err(err) = retry_wants(&mut ch_broker).await {
+                    warn!("Failed to request wanted blobs: {}", err)
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(1), INITIAL_BACKOFF);
+        assert_eq!(backoff(2), INITIAL_BACKOFF * 2);
+        assert_eq!(backoff(4), INITIAL_BACKOFF * 8);
+        assert_eq!(backoff(20), MAX_BACKOFF);
+        assert_eq!(backoff(u32::MAX), MAX_BACKOFF);
+    }
+}
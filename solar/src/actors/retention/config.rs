@@ -0,0 +1,49 @@
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+pub struct RetentionConfig {
+    /// Delete stored feeds of authors who are further than the given number
+    /// of hops from the local identity in the follow graph (default: none).
+    /// Pinned feeds and feeds of peers listed in `replication.toml` are never
+    /// deleted.
+    pub prune_hops: Option<usize>,
+
+    /// Interval between pruning runs (default: 1 hour).
+    pub prune_interval: Duration,
+
+    /// Free disk space, in bytes, below which storage is reported to be
+    /// running low (default: 1 GiB).
+    pub low_free_space: u64,
+
+    /// Free disk space, in bytes, below which storage is critically low:
+    /// blob fetching is paused and only the feeds within `critical_hops` are
+    /// replicated (default: 256 MiB).
+    pub critical_free_space: u64,
+
+    /// Database size, in bytes, above which storage is reported to be
+    /// running low (default: none).
+    pub max_database_size: Option<u64>,
+
+    /// Database size, in bytes, above which storage is critically low
+    /// (default: none).
+    pub critical_database_size: Option<u64>,
+
+    /// Number of hops from the local identity within which feeds are still
+    /// replicated while storage is critically low (default: 1). The local
+    /// feed and pinned feeds are always replicated.
+    pub critical_hops: usize,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            prune_hops: None,
+            prune_interval: Duration::from_secs(3600),
+            low_free_space: 1 << 30,
+            critical_free_space: 256 << 20,
+            max_database_size: None,
+            critical_database_size: None,
+            critical_hops: 1,
+        }
+    }
+}
@@ -0,0 +1,251 @@
+//! Storage Pressure
+//!
+//! The storage pressure job periodically measures the free space on the disk
+//! holding the data directory and the size of the key-value database, and
+//! compares them against the thresholds of the retention configuration.
+//! Whenever the pressure level changes, a `BrokerMessage::StoragePressure`
+//! event is broadcast (see the `subscribe_storage_pressure` JSON-RPC
+//! subscription) and logged.
+//!
+//! While storage is critically low, the node degrades gracefully instead of
+//! failing mid-write once the disk is full: wanted blobs are no longer
+//! fetched and only the messages of the local feed, pinned feeds and feeds
+//! within `critical_hops` of the local identity are stored. Replication
+//! resumes as usual once space has been freed.
+use std::{collections::HashSet, path::PathBuf, sync::RwLock, time::Duration};
+
+use async_std::stream;
+use futures::{select_biased, stream::StreamExt, FutureExt, SinkExt};
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::{
+    actors::retention::config::RetentionConfig,
+    broker::{ActorEndpoint, BrokerEvent, BrokerMessage, Destination, Void, BROKER},
+    error::Error,
+    node::KV_STORE,
+    Result,
+};
+
+/// Interval between storage checks.
+pub const STORAGE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Storage pressure level.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PressureLevel {
+    /// Storage is within the configured thresholds.
+    #[default]
+    Normal,
+    /// Storage is running low.
+    Low,
+    /// Storage is critically low: blob fetching and the replication of
+    /// feeds beyond `critical_hops` are paused.
+    Critical,
+}
+
+/// Storage pressure of the node, broadcast as a `BrokerMessage` when the
+/// pressure level changes.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct StoragePressure {
+    pub level: PressureLevel,
+    /// Free space on the disk holding the data directory, in bytes (unknown
+    /// for ephemeral nodes).
+    pub free_bytes: Option<u64>,
+    /// Size of the key-value database on disk, in bytes.
+    pub database_bytes: u64,
+}
+
+/// Storage pressure as of the last check.
+static STATUS: Lazy<RwLock<StoragePressure>> =
+    Lazy::new(|| RwLock::new(StoragePressure::default()));
+
+/// Feeds whose messages are stored while storage is critically low (`None`
+/// if storage is not critically low).
+static ACCEPTED_FEEDS: Lazy<RwLock<Option<HashSet<String>>>> = Lazy::new(|| RwLock::new(None));
+
+/// Classify the given free disk space and database size against the
+/// thresholds of the given configuration.
+pub fn level(
+    free_bytes: Option<u64>,
+    database_bytes: u64,
+    config: &RetentionConfig,
+) -> PressureLevel {
+    let free_below = |threshold: u64| matches!(free_bytes, Some(free) if free < threshold);
+    let database_above =
+        |threshold: Option<u64>| matches!(threshold, Some(max) if database_bytes > max);
+
+    if free_below(config.critical_free_space) || database_above(config.critical_database_size) {
+        PressureLevel::Critical
+    } else if free_below(config.low_free_space) || database_above(config.max_database_size) {
+        PressureLevel::Low
+    } else {
+        PressureLevel::Normal
+    }
+}
+
+/// Return the storage pressure as of the last check.
+pub fn status() -> StoragePressure {
+    STATUS.read().unwrap_or_else(|err| err.into_inner()).clone()
+}
+
+/// Return `true` if storage is critically low.
+pub fn is_critical() -> bool {
+    status().level == PressureLevel::Critical
+}
+
+/// Return `true` if the messages of the given feed are to be stored, which
+/// is always the case unless storage is critically low.
+pub fn accepts_feed(author: &str) -> bool {
+    match &*ACCEPTED_FEEDS.read().unwrap_or_else(|err| err.into_inner()) {
+        Some(feeds) => feeds.contains(author),
+        None => true,
+    }
+}
+
+/// Return the feeds whose messages are stored while storage is critically
+/// low: the local feed, pinned feeds and feeds within `max_hops` of the
+/// local identity.
+async fn accepted_feeds(local_id: &str, max_hops: usize) -> Result<HashSet<String>> {
+    let db = KV_STORE.read().await;
+    let indexes = db.indexes.as_ref().ok_or(Error::Indexes)?;
+
+    let mut feeds: HashSet<String> = indexes.get_hops(local_id, max_hops)?.into_keys().collect();
+    feeds.extend(db.get_pinned_feeds()?);
+    feeds.insert(local_id.to_owned());
+
+    Ok(feeds)
+}
+
+/// Measure the storage of the node and update the storage pressure.
+///
+/// Returns the new storage pressure if the pressure level changed.
+async fn check(
+    base_path: Option<&PathBuf>,
+    local_id: &str,
+    config: &RetentionConfig,
+) -> Result<Option<StoragePressure>> {
+    let free_bytes = match base_path {
+        Some(path) => Some(fs2::available_space(path)?),
+        None => None,
+    };
+    let database_bytes = KV_STORE.read().await.size_on_disk()?;
+
+    let pressure = StoragePressure {
+        level: level(free_bytes, database_bytes, config),
+        free_bytes,
+        database_bytes,
+    };
+
+    // The accepted feeds are refreshed on each check, to follow the changes
+    // of the follow graph.
+    let accepted = match pressure.level {
+        PressureLevel::Critical => Some(accepted_feeds(local_id, config.critical_hops).await?),
+        _ => None,
+    };
+    *ACCEPTED_FEEDS
+        .write()
+        .unwrap_or_else(|err| err.into_inner()) = accepted;
+
+    let mut status = STATUS.write().unwrap_or_else(|err| err.into_inner());
+    let changed = status.level != pressure.level;
+    *status = pressure.clone();
+
+    Ok(changed.then_some(pressure))
+}
+
+/// Start the storage pressure job.
+///
+/// Register the job with the broker (as an actor), check the storage of the
+/// node at the given interval and broadcast the storage pressure when its
+/// level changes.
+pub async fn actor(
+    base_path: Option<PathBuf>,
+    local_id: String,
+    config: RetentionConfig,
+    interval: Duration,
+) -> Result<()> {
+    // Register the storage pressure actor with the broker.
+    let ActorEndpoint {
+        ch_terminate,
+        mut ch_broker,
+        ch_terminated,
+        ..
+    } = BROKER
+        .lock()
+        .await
+        .register("storage-pressure", false)
+        .await?;
+
+    let mut ch_terminate_fuse = ch_terminate.fuse();
+    let mut ticker = stream::interval(interval).fuse();
+
+    loop {
+        select_biased! {
+            // Received termination signal. Break out of the loop.
+            _value = ch_terminate_fuse => {
+                break;
+            },
+            _tick = ticker.next() => {
+                let pressure = match check(base_path.as_ref(), &local_id, &config).await {
+                    Ok(Some(pressure)) => pressure,
+                    Ok(None) => continue,
+                    Err(err) => {
+                        warn!("Failed to check storage pressure: {}", err);
+                        continue;
+                    }
+                };
+
+                match pressure.level {
+                    PressureLevel::Critical => warn!(
+                        "Storage is critically low ({:?} bytes free, database of {} bytes): pausing blob fetching and replication beyond {} hops",
+                        pressure.free_bytes, pressure.database_bytes, config.critical_hops
+                    ),
+                    PressureLevel::Low => warn!(
+                        "Storage is running low ({:?} bytes free, database of {} bytes)",
+                        pressure.free_bytes, pressure.database_bytes
+                    ),
+                    PressureLevel::Normal => info!("Storage pressure is back to normal"),
+                }
+
+                ch_broker
+                    .send(BrokerEvent::new(
+                        Destination::Broadcast,
+                        BrokerMessage::StoragePressure(pressure),
+                    ))
+                    .await?;
+            }
+        }
+    }
+
+    let _ = ch_terminated.send(Void {});
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_level() {
+        let config = RetentionConfig {
+            low_free_space: 1_000,
+            critical_free_space: 100,
+            max_database_size: Some(5_000),
+            critical_database_size: Some(10_000),
+            ..RetentionConfig::default()
+        };
+
+        assert_eq!(level(Some(2_000), 1_000, &config), PressureLevel::Normal);
+        assert_eq!(level(Some(500), 1_000, &config), PressureLevel::Low);
+        assert_eq!(level(Some(50), 1_000, &config), PressureLevel::Critical);
+        assert_eq!(level(Some(2_000), 6_000, &config), PressureLevel::Low);
+        assert_eq!(level(Some(2_000), 20_000, &config), PressureLevel::Critical);
+
+        // The free space of ephemeral nodes is unknown.
+        assert_eq!(level(None, 1_000, &config), PressureLevel::Normal);
+        assert_eq!(level(None, 20_000, &config), PressureLevel::Critical);
+    }
+}
@@ -0,0 +1,207 @@
+//! Feed Pruning
+//!
+//! Feeds are replicated from authors who are within range of the local
+//! identity in the follow graph. As the follow graph changes, authors may
+//! drift out of range; their feeds are no longer replicated but remain in
+//! the database. The pruning job periodically deletes the feeds (and the
+//! associated index entries) of authors who are further than the configured
+//! number of hops from the local identity.
+//!
+//! The local feed, pinned feeds and the feeds of peers listed in
+//! `replication.toml` are never pruned. Pruned feeds are also dropped from
+//! the EBT vector clocks, so that peers stop sending their messages, as are
+//! the replicated feeds out of range of which no message is stored (for
+//! example, after the number of hops was lowered).
+use std::{collections::HashSet, time::Duration};
+
+use async_std::stream;
+use futures::{select_biased, stream::StreamExt, FutureExt, SinkExt};
+use log::{info, warn};
+
+use crate::{
+    actors::replication::ebt::{query, EbtEvent},
+    broker::{ActorEndpoint, BrokerEvent, BrokerMessage, ChBrokerSend, Destination, BROKER},
+    config::PEERS_TO_REPLICATE,
+    error::Error,
+    node::KV_STORE,
+    storage::kv::KvStorage,
+    Result,
+};
+
+/// Query whether the feed of the given author is kept regardless of its
+/// distance from the local identity: the local feed, the feeds of peers
+/// listed in `replication.toml` and pinned feeds are.
+fn is_exempt(db: &KvStorage, local_id: &str, author: &str) -> Result<bool> {
+    let replicated = match PEERS_TO_REPLICATE.get() {
+        Some(peers) => peers.contains_key(author),
+        None => false,
+    };
+
+    Ok(author == local_id || replicated || db.is_feed_pinned(author)?)
+}
+
+/// Delete the stored feeds of all authors who are further than `max_hops`
+/// from the given local public key. The feeds are deleted together, so
+/// that the indexes are scanned once per run rather than once per feed.
+///
+/// Returns the public keys of the authors whose feeds were deleted.
+pub async fn prune_feeds(local_id: &str, max_hops: usize) -> Result<Vec<String>> {
+    let db = KV_STORE.write().await;
+    let indexes = db.indexes.as_ref().ok_or(Error::Indexes)?;
+
+    let hops = indexes.get_hops(local_id, max_hops)?;
+
+    let mut pruned = Vec::new();
+    for (author, _latest_seq) in db.get_peers().await? {
+        if !hops.contains_key(&author) && !is_exempt(&db, local_id, &author)? {
+            pruned.push(author);
+        }
+    }
+
+    let removed = db.remove_feeds(&pruned).await?;
+    for (author, removed) in pruned.iter().zip(removed) {
+        info!(
+            "Pruned {} messages from feed {} (beyond {} hops)",
+            removed, author, max_hops
+        );
+    }
+
+    Ok(pruned)
+}
+
+/// Return the given feeds of authors who are further than `max_hops` from
+/// the given local public key, and which are not exempt from pruning.
+pub async fn out_of_range(
+    local_id: &str,
+    max_hops: usize,
+    feeds: impl IntoIterator<Item = String>,
+) -> Result<Vec<String>> {
+    let db = KV_STORE.read().await;
+    let indexes = db.indexes.as_ref().ok_or(Error::Indexes)?;
+
+    let hops = indexes.get_hops(local_id, max_hops)?;
+
+    let mut out_of_range = Vec::new();
+    for author in feeds {
+        if !hops.contains_key(&author) && !is_exempt(&db, local_id, &author)? {
+            out_of_range.push(author);
+        }
+    }
+
+    Ok(out_of_range)
+}
+
+/// Prune the feeds beyond `max_hops` and revoke the EBT replication of the
+/// pruned feeds, along with that of the feeds of the local vector clock
+/// which are out of range. The latter may have no stored message to prune,
+/// for example if they were requested but never received.
+///
+/// Returns the public keys of the authors whose feeds were deleted.
+pub async fn prune_and_revoke(
+    local_id: &str,
+    max_hops: usize,
+    ch_broker: &mut ChBrokerSend,
+) -> Result<Vec<String>> {
+    let pruned = prune_feeds(local_id, max_hops).await?;
+
+    let mut revoked: HashSet<String> = pruned.iter().cloned().collect();
+    // The local clock can only be queried while the EBT manager is running.
+    match query::query_clock(None).await {
+        Ok(Some(clock)) => {
+            revoked.extend(out_of_range(local_id, max_hops, clock.into_keys()).await?)
+        }
+        Ok(None) => (),
+        Err(err) => warn!("Failed to query the local EBT clock: {}", err),
+    }
+
+    for author in revoked {
+        ch_broker
+            .send(BrokerEvent::new(
+                Destination::Broadcast,
+                BrokerMessage::Ebt(EbtEvent::Revoke(author)),
+            ))
+            .await?;
+    }
+
+    Ok(pruned)
+}
+
+/// Start the feed pruning job.
+///
+/// Register the pruning job with the broker (as an actor) and prune feeds
+/// beyond `max_hops` at the given interval. Replication of the pruned feeds
+/// and of the replicated feeds out of range is revoked.
+pub async fn actor(local_id: String, max_hops: usize, interval: Duration) -> Result<()> {
+    // Register the feed pruning actor with the broker.
+    let ActorEndpoint {
+        ch_terminate,
+        mut ch_broker,
+        ..
+    } = BROKER.lock().await.register("feed-pruning", false).await?;
+
+    let mut ch_terminate_fuse = ch_terminate.fuse();
+    let mut ticker = stream::interval(interval).fuse();
+
+    loop {
+        select_biased! {
+            // Received termination signal. Break out of the loop.
+            _value = ch_terminate_fuse => {
+                break;
+            },
+            _tick = ticker.next() => {
+                if let Err(err) = prune_and_revoke(&local_id, max_hops, &mut ch_broker).await {
+                    warn!("Failed to prune feeds: {}", err)
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use kuska_ssb::feed::Message;
+    use serde_json::json;
+
+    use crate::{conformance, secret_config::SecretConfig};
+
+    #[async_std::test]
+    async fn test_out_of_range() -> Result<()> {
+        conformance::open_store().await?;
+
+        let local = SecretConfig::create().to_owned_identity()?;
+        let friend = SecretConfig::create().to_owned_identity()?;
+        let stranger = SecretConfig::create().to_owned_identity()?;
+
+        let follow = Message::sign(
+            None,
+            &local,
+            json!({ "type": "contact", "contact": friend.id, "following": true }),
+        )?;
+        conformance::append(follow).await?;
+
+        // Feeds out of range are returned whether or not any of their
+        // messages are stored.
+        let feeds = vec![
+            local.id.to_owned(),
+            friend.id.to_owned(),
+            stranger.id.to_owned(),
+        ];
+        assert_eq!(
+            out_of_range(&local.id, 1, feeds.to_owned()).await?,
+            vec![stranger.id.to_owned()]
+        );
+
+        // Lowering the number of hops brings the followed feed out of range.
+        let mut revoked = out_of_range(&local.id, 0, feeds).await?;
+        revoked.sort();
+        let mut expected = vec![friend.id, stranger.id];
+        expected.sort();
+        assert_eq!(revoked, expected);
+
+        Ok(())
+    }
+}
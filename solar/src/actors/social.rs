@@ -0,0 +1,311 @@
+//! Social Events
+//!
+//! Reports the changes of the follow graph which involve the local identity:
+//! peers following, unfollowing, blocking or unblocking the local identity,
+//! and the local identity doing the same to peers. Each contact message
+//! appended to the database is compared against the contacts index: the
+//! contact state of the edge before the message (as last seen by the job)
+//! and after it yield the events, which are broadcast as
+//! `BrokerMessage::Social` (see the `subscribe_social_events` JSON-RPC
+//! subscription), so that bots and user interfaces can react to them (for
+//! example, by following back new followers).
+//!
+//! Events are only reported for messages claiming to have been published
+//! within the last day, so that the initial replication of a feed does not
+//! replay its history.
+use std::{collections::HashMap, time::Duration};
+
+use futures::{select_biased, stream::StreamExt, FutureExt, SinkExt};
+use kuska_ssb::feed::Message;
+use log::warn;
+use serde::Serialize;
+use serde_json::Value;
+use solar_core::index::ContactState;
+
+use crate::{
+    broker::{ActorEndpoint, BrokerEvent, BrokerMessage, Destination, Void, BROKER},
+    error::Error,
+    node::KV_STORE,
+    storage::kv::StoreKvEvent,
+    util::now_millis,
+    Result,
+};
+
+/// Maximum age of the messages for which social events are reported.
+const MAX_MESSAGE_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// A change of the follow graph involving the local identity.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum SocialEvent {
+    /// A peer followed the local identity.
+    NewFollower { peer: String, msg_ref: String },
+    /// A peer unfollowed the local identity.
+    LostFollower { peer: String, msg_ref: String },
+    /// A peer blocked the local identity.
+    BlockedBy { peer: String, msg_ref: String },
+    /// A peer unblocked the local identity.
+    UnblockedBy { peer: String, msg_ref: String },
+    /// The local identity followed a peer.
+    Followed { peer: String, msg_ref: String },
+    /// The local identity unfollowed a peer.
+    Unfollowed { peer: String, msg_ref: String },
+    /// The local identity blocked a peer.
+    Blocked { peer: String, msg_ref: String },
+    /// The local identity unblocked a peer.
+    Unblocked { peer: String, msg_ref: String },
+}
+
+/// Return the events caused by the change of the contact state of the edge
+/// from the given author to the given contact, published in the message
+/// with the given reference. Edges which do not involve the local identity
+/// cause no event.
+pub fn contact_events(
+    local_id: &str,
+    author: &str,
+    contact: &str,
+    previous: ContactState,
+    current: ContactState,
+    msg_ref: &str,
+) -> Vec<SocialEvent> {
+    let mut events = Vec::new();
+    let msg_ref = msg_ref.to_owned();
+
+    if author == contact {
+        return events;
+    }
+
+    if contact == local_id {
+        let peer = author.to_owned();
+        match (previous.following, current.following) {
+            (false, true) => events.push(SocialEvent::NewFollower {
+                peer: peer.clone(),
+                msg_ref: msg_ref.clone(),
+            }),
+            (true, false) => events.push(SocialEvent::LostFollower {
+                peer: peer.clone(),
+                msg_ref: msg_ref.clone(),
+            }),
+            _ => (),
+        }
+        match (previous.blocking, current.blocking) {
+            (false, true) => events.push(SocialEvent::BlockedBy { peer, msg_ref }),
+            (true, false) => events.push(SocialEvent::UnblockedBy { peer, msg_ref }),
+            _ => (),
+        }
+    } else if author == local_id {
+        let peer = contact.to_owned();
+        match (previous.following, current.following) {
+            (false, true) => events.push(SocialEvent::Followed {
+                peer: peer.clone(),
+                msg_ref: msg_ref.clone(),
+            }),
+            (true, false) => events.push(SocialEvent::Unfollowed {
+                peer: peer.clone(),
+                msg_ref: msg_ref.clone(),
+            }),
+            _ => (),
+        }
+        match (previous.blocking, current.blocking) {
+            (false, true) => events.push(SocialEvent::Blocked { peer, msg_ref }),
+            (true, false) => events.push(SocialEvent::Unblocked { peer, msg_ref }),
+            _ => (),
+        }
+    }
+
+    events
+}
+
+/// Contact states of the edges involving the local identity, as last seen
+/// by the job, keyed by author and contact.
+type Edges = HashMap<(String, String), ContactState>;
+
+/// Load the contact states of the edges involving the local identity from
+/// the contacts index.
+async fn load_edges(local_id: &str) -> Result<Edges> {
+    let db = KV_STORE.read().await;
+    let indexes = db.indexes.as_ref().ok_or(Error::Indexes)?;
+
+    let mut edges = Edges::new();
+    for (contact, state) in indexes.get_contacts(local_id)? {
+        edges.insert((local_id.to_owned(), contact), state);
+    }
+
+    let mut peers = indexes.get_followers(local_id)?;
+    peers.extend(indexes.get_blockers(local_id)?);
+    for peer in peers {
+        if let Some(state) = indexes.get_contact(&peer, local_id)? {
+            edges.insert((peer, local_id.to_owned()), state);
+        }
+    }
+
+    Ok(edges)
+}
+
+/// Return the author and contact of the given message if it is a contact
+/// message involving the local identity.
+fn local_edge(msg: &Message, local_id: &str) -> Option<(String, String)> {
+    let content = msg.content();
+    if content.get("type").and_then(Value::as_str) != Some("contact") {
+        return None;
+    }
+    let contact = content.get("contact").and_then(Value::as_str)?;
+    let author = msg.author();
+
+    if author == local_id || contact == local_id {
+        Some((author.to_owned(), contact.to_owned()))
+    } else {
+        None
+    }
+}
+
+/// Return the events caused by the message with the given author and
+/// sequence number, updating the given edges.
+async fn stored_message_events(
+    edges: &mut Edges,
+    author: &str,
+    seq: u64,
+    local_id: &str,
+) -> Result<Vec<SocialEvent>> {
+    let db = KV_STORE.read().await;
+    let msg = match db.get_msg_kvt(author, seq)? {
+        Some(msg_kvt) => msg_kvt.into_message()?,
+        None => return Ok(Vec::new()),
+    };
+    let (author, contact) = match local_edge(&msg, local_id) {
+        Some(edge) => edge,
+        None => return Ok(Vec::new()),
+    };
+
+    let indexes = db.indexes.as_ref().ok_or(Error::Indexes)?;
+    let current = indexes.get_contact(&author, &contact)?.unwrap_or_default();
+    let previous = edges
+        .insert((author.to_owned(), contact.to_owned()), current)
+        .unwrap_or_default();
+
+    let timestamp = msg
+        .value
+        .get("timestamp")
+        .and_then(Value::as_f64)
+        .unwrap_or_default() as u64;
+    if now_millis().saturating_sub(timestamp) > MAX_MESSAGE_AGE.as_millis() as u64 {
+        return Ok(Vec::new());
+    }
+
+    Ok(contact_events(
+        local_id,
+        &author,
+        &contact,
+        previous,
+        current,
+        &msg.id().to_string(),
+    ))
+}
+
+/// Start the social events job.
+///
+/// Register the job with the broker (as an actor) and broadcast the social
+/// events caused by the contact messages appended to the database.
+pub async fn actor(local_id: String) -> Result<()> {
+    let ActorEndpoint {
+        ch_terminate,
+        mut ch_broker,
+        ch_terminated,
+        ch_msg,
+        ..
+    } = BROKER.lock().await.register("social-events", true).await?;
+
+    let mut ch_msg = ch_msg.ok_or(Error::OptionIsNone)?;
+    let mut ch_terminate_fuse = ch_terminate.fuse();
+
+    let mut edges = load_edges(&local_id).await?;
+
+    loop {
+        select_biased! {
+            // Received termination signal. Break out of the loop.
+            _value = ch_terminate_fuse => {
+                break;
+            },
+            msg = ch_msg.next().fuse() => {
+                let (author, seq) = match msg {
+                    Some(BrokerMessage::StoreKv(StoreKvEvent((author, seq)))) => (author, seq),
+                    Some(_) => continue,
+                    None => break,
+                };
+                let events = match stored_message_events(&mut edges, &author, seq, &local_id).await {
+                    Ok(events) => events,
+                    Err(err) => {
+                        warn!("Failed to read message {}:{}: {}", author, seq, err);
+                        continue;
+                    }
+                };
+                for event in events {
+                    ch_broker
+                        .send(BrokerEvent::new(
+                            Destination::Broadcast,
+                            BrokerMessage::Social(event),
+                        ))
+                        .await?;
+                }
+            }
+        }
+    }
+
+    let _ = ch_terminated.send(Void {});
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const LOCAL: &str = "@local=.ed25519";
+    const PEER: &str = "@peer=.ed25519";
+
+    fn state(following: bool, blocking: bool) -> ContactState {
+        ContactState {
+            following,
+            blocking,
+        }
+    }
+
+    #[test]
+    fn test_contact_events() {
+        let none = state(false, false);
+        let following = state(true, false);
+        let blocking = state(false, true);
+
+        assert_eq!(
+            contact_events(LOCAL, PEER, LOCAL, none, following, "%a"),
+            vec![SocialEvent::NewFollower {
+                peer: PEER.to_owned(),
+                msg_ref: "%a".to_owned(),
+            }]
+        );
+        assert_eq!(
+            contact_events(LOCAL, PEER, LOCAL, following, blocking, "%b"),
+            vec![
+                SocialEvent::LostFollower {
+                    peer: PEER.to_owned(),
+                    msg_ref: "%b".to_owned(),
+                },
+                SocialEvent::BlockedBy {
+                    peer: PEER.to_owned(),
+                    msg_ref: "%b".to_owned(),
+                },
+            ]
+        );
+        assert_eq!(
+            contact_events(LOCAL, LOCAL, PEER, blocking, none, "%c"),
+            vec![SocialEvent::Unblocked {
+                peer: PEER.to_owned(),
+                msg_ref: "%c".to_owned(),
+            }]
+        );
+
+        // Repeated follows and edges between other peers cause no event.
+        assert!(contact_events(LOCAL, PEER, LOCAL, following, following, "%d").is_empty());
+        assert!(contact_events(LOCAL, PEER, "@other=.ed25519", none, following, "%e").is_empty());
+    }
+}
@@ -0,0 +1,461 @@
+//! Webhooks
+//!
+//! Posts a JSON payload to operator-defined URLs when selected node events
+//! occur, so that pubs can be integrated with chat-ops tooling without a
+//! custom client. The webhooks are configured in the `webhooks.toml` file in
+//! the root data directory, which is read when the node starts:
+//!
+//! ```toml
+//! # Size of the data directory (in bytes) above which the
+//! # `disk_threshold_exceeded` event is sent.
+//! disk_threshold = 10000000000
+//!
+//! [[hooks]]
+//! url = "https://chat.example.com/hooks/solar"
+//! events = ["new_follower", "mention", "peer_connected", "disk_threshold_exceeded"]
+//! ```
+//!
+//! Follows and mentions are only reported for messages claiming to have
+//! been published within the last day, so that the initial replication of a
+//! feed does not replay its history. The disk threshold event is sent once
+//! each time the size of the data directory rises above the threshold.
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use async_std::{stream, task};
+use futures::{select_biased, stream::StreamExt, FutureExt};
+use kuska_ssb::feed::Message;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use solar_core::index;
+
+use crate::{
+    actors::network::connection_state::ConnectionState,
+    broker::{ActorEndpoint, BrokerMessage, Void, BROKER},
+    node::KV_STORE,
+    storage::kv::StoreKvEvent,
+    util::now_millis,
+    Error, Result,
+};
+
+/// Name of the webhooks configuration file in the root data directory.
+pub const WEBHOOKS_CONFIG_FILE: &str = "webhooks.toml";
+
+/// Interval at which the size of the data directory is checked against the
+/// disk threshold.
+pub const DISK_CHECK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Maximum age of the messages for which follows and mentions are reported.
+const MAX_MESSAGE_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Maximum duration of a webhook request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Kind of a webhook event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEventKind {
+    NewFollower,
+    Mention,
+    PeerConnected,
+    DiskThresholdExceeded,
+}
+
+/// A URL to which the given events are posted.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Webhook {
+    pub url: String,
+    pub events: Vec<WebhookEventKind>,
+}
+
+/// Contents of the webhooks configuration file.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct WebhooksConfig {
+    /// Size of the data directory (in bytes) above which the disk threshold
+    /// event is sent.
+    #[serde(default)]
+    pub disk_threshold: Option<u64>,
+    #[serde(default)]
+    pub hooks: Vec<Webhook>,
+}
+
+impl WebhooksConfig {
+    /// Read the webhooks configuration file at the given path. Returns
+    /// `None` if the file does not exist.
+    pub fn read(path: &Path) -> Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let config = toml::from_str(&fs::read_to_string(path)?)?;
+
+        Ok(Some(config))
+    }
+
+    /// Return the webhooks to which events of the given kind are posted.
+    fn hooks_for(&self, kind: WebhookEventKind) -> impl Iterator<Item = &Webhook> {
+        self.hooks
+            .iter()
+            .filter(move |hook| hook.events.contains(&kind))
+    }
+}
+
+/// A node event reported to webhooks.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum WebhookEvent {
+    /// A peer published a follow of the local identity.
+    NewFollower { follower: String, msg_ref: String },
+    /// A peer published a message mentioning the local identity.
+    Mention { author: String, msg_ref: String },
+    /// A connection was established with a peer.
+    PeerConnected { peer: String },
+    /// The size of the data directory rose above the disk threshold.
+    DiskThresholdExceeded {
+        used_bytes: u64,
+        threshold_bytes: u64,
+    },
+}
+
+impl WebhookEvent {
+    fn kind(&self) -> WebhookEventKind {
+        match self {
+            WebhookEvent::NewFollower { .. } => WebhookEventKind::NewFollower,
+            WebhookEvent::Mention { .. } => WebhookEventKind::Mention,
+            WebhookEvent::PeerConnected { .. } => WebhookEventKind::PeerConnected,
+            WebhookEvent::DiskThresholdExceeded { .. } => WebhookEventKind::DiskThresholdExceeded,
+        }
+    }
+}
+
+/// Payload posted to a webhook: the event, along with the SSB ID of the
+/// local node and the time of the event.
+#[derive(Debug, Serialize)]
+struct Payload<'a> {
+    node: &'a str,
+    /// Milliseconds since the UNIX epoch.
+    timestamp: u64,
+    #[serde(flatten)]
+    event: &'a WebhookEvent,
+}
+
+/// Query whether the given message claims to have been published recently
+/// enough, at the given time (in milliseconds since the UNIX epoch), for
+/// its events to be reported.
+pub(crate) fn is_recent(msg: &Message, now: u64) -> bool {
+    let timestamp = msg
+        .value
+        .get("timestamp")
+        .and_then(Value::as_f64)
+        .unwrap_or_default() as u64;
+
+    now.saturating_sub(timestamp) <= MAX_MESSAGE_AGE.as_millis() as u64
+}
+
+/// Return the events to be reported for the given stored message, at the
+/// given time (in milliseconds since the UNIX epoch).
+pub(crate) fn message_events(msg: &Message, local_id: &str, now: u64) -> Vec<WebhookEvent> {
+    let mut events = Vec::new();
+    if msg.author() == local_id || !is_recent(msg, now) {
+        return events;
+    }
+
+    let content = msg.content();
+    let msg_ref = msg.id().to_string();
+    let is_follow = content.get("type").and_then(Value::as_str) == Some("contact")
+        && content.get("contact").and_then(Value::as_str) == Some(local_id)
+        && content.get("following").and_then(Value::as_bool) == Some(true);
+    if is_follow {
+        events.push(WebhookEvent::NewFollower {
+            follower: msg.author().to_owned(),
+            msg_ref: msg_ref.to_owned(),
+        });
+    }
+    if index::mentions(content, local_id) {
+        events.push(WebhookEvent::Mention {
+            author: msg.author().to_owned(),
+            msg_ref,
+        });
+    }
+
+    events
+}
+
+/// Return the total size (in bytes) of the files in the given directory and
+/// its subdirectories.
+fn dir_size(path: &Path) -> io::Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            size += dir_size(&entry.path())?;
+        } else {
+            size += metadata.len();
+        }
+    }
+
+    Ok(size)
+}
+
+/// Post the given event to the webhooks configured for its kind. Each
+/// request is sent from its own task, so that a slow endpoint does not hold
+/// back the dispatcher.
+fn dispatch(
+    client: &reqwest::Client,
+    config: &WebhooksConfig,
+    local_id: &str,
+    event: WebhookEvent,
+) {
+    let payload = Payload {
+        node: local_id,
+        timestamp: now_millis(),
+        event: &event,
+    };
+    let body = match serde_json::to_value(&payload) {
+        Ok(body) => body,
+        Err(err) => {
+            warn!("Failed to serialize webhook payload: {}", err);
+            return;
+        }
+    };
+
+    for hook in config.hooks_for(event.kind()) {
+        let request = client.post(&hook.url).timeout(REQUEST_TIMEOUT).json(&body);
+        let url = hook.url.to_owned();
+        task::spawn(async move {
+            match request.send().await.and_then(|res| res.error_for_status()) {
+                Ok(_) => info!("Posted webhook event to {}", url),
+                Err(err) => warn!("Failed to post webhook event to {}: {}", url, err),
+            }
+        });
+    }
+}
+
+/// Return the events to be reported for the message with the given author
+/// and sequence number.
+async fn stored_message_events(
+    author: &str,
+    seq: u64,
+    local_id: &str,
+) -> Result<Vec<WebhookEvent>> {
+    let msg = match KV_STORE.read().await.get_msg_kvt(author, seq)? {
+        Some(msg_kvt) => msg_kvt.into_message()?,
+        None => return Ok(Vec::new()),
+    };
+
+    Ok(message_events(&msg, local_id, now_millis()))
+}
+
+/// Start the webhook dispatcher.
+///
+/// Register the dispatcher with the broker (as an actor), post the stored
+/// messages and connection events matching the configured webhooks and
+/// check the size of the data directory at the given base path against the
+/// disk threshold at the given interval.
+pub async fn actor(
+    config: WebhooksConfig,
+    local_id: String,
+    base_path: PathBuf,
+    interval: Duration,
+) -> Result<()> {
+    let ActorEndpoint {
+        ch_terminate,
+        ch_terminated,
+        ch_msg,
+        ..
+    } = BROKER.lock().await.register("webhooks", true).await?;
+
+    let mut ch_msg = ch_msg.ok_or(Error::OptionIsNone)?;
+    let mut ch_terminate_fuse = ch_terminate.fuse();
+    let mut ticker = stream::interval(interval).fuse();
+
+    let client = reqwest::Client::new();
+    // Whether the data directory was above the disk threshold at the last
+    // check.
+    let mut above_threshold = false;
+
+    loop {
+        select_biased! {
+            // Received termination signal. Break out of the loop.
+            _value = ch_terminate_fuse => {
+                break;
+            },
+            msg = ch_msg.next().fuse() => {
+                match msg {
+                    Some(BrokerMessage::StoreKv(StoreKvEvent((author, seq)))) => {
+                        match stored_message_events(&author, seq, &local_id).await {
+                            Ok(events) => {
+                                for event in events {
+                                    dispatch(&client, &config, &local_id, event);
+                                }
+                            }
+                            Err(err) => warn!("Failed to read message {}:{}: {}", author, seq, err),
+                        }
+                    }
+                    Some(BrokerMessage::Transition(transition)) => {
+                        if let (ConnectionState::Connected, Some(peer)) =
+                            (transition.to, transition.peer)
+                        {
+                            let event = WebhookEvent::PeerConnected { peer };
+                            dispatch(&client, &config, &local_id, event);
+                        }
+                    }
+                    Some(_) => (),
+                    None => break,
+                }
+            },
+            _tick = ticker.next() => {
+                let threshold_bytes = match config.disk_threshold {
+                    Some(threshold_bytes) => threshold_bytes,
+                    None => continue,
+                };
+                let used_bytes = match dir_size(&base_path) {
+                    Ok(used_bytes) => used_bytes,
+                    Err(err) => {
+                        warn!("Failed to measure the size of the data directory: {}", err);
+                        continue;
+                    }
+                };
+                let was_above_threshold = above_threshold;
+                above_threshold = used_bytes > threshold_bytes;
+                if above_threshold && !was_above_threshold {
+                    dispatch(
+                        &client,
+                        &config,
+                        &local_id,
+                        WebhookEvent::DiskThresholdExceeded { used_bytes, threshold_bytes },
+                    );
+                }
+            }
+        }
+    }
+
+    let _ = ch_terminated.send(Void {});
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use kuska_ssb::api::dto::content::TypedMessage;
+    use serde_json::json;
+
+    use crate::secret_config::SecretConfig;
+
+    #[test]
+    fn test_webhooks_config() -> crate::Result<()> {
+        let config: WebhooksConfig = toml::from_str(
+            r#"
+            disk_threshold = 1000
+
+            [[hooks]]
+            url = "https://chat.example.com/a"
+            events = ["new_follower", "mention"]
+
+            [[hooks]]
+            url = "https://chat.example.com/b"
+            events = ["mention", "peer_connected"]
+            "#,
+        )?;
+        assert_eq!(config.disk_threshold, Some(1000));
+
+        let urls: Vec<&str> = config
+            .hooks_for(WebhookEventKind::Mention)
+            .map(|hook| hook.url.as_str())
+            .collect();
+        assert_eq!(
+            urls,
+            vec!["https://chat.example.com/a", "https://chat.example.com/b"]
+        );
+        assert_eq!(
+            config
+                .hooks_for(WebhookEventKind::DiskThresholdExceeded)
+                .count(),
+            0
+        );
+
+        assert!(
+            toml::from_str::<WebhooksConfig>("[[hooks]]\nurl = \"x\"\nevents = [\"unknown\"]")
+                .is_err()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_serialize_payload() -> crate::Result<()> {
+        let event = WebhookEvent::PeerConnected {
+            peer: "@peer=.ed25519".to_string(),
+        };
+        let payload = Payload {
+            node: "@node=.ed25519",
+            timestamp: 1,
+            event: &event,
+        };
+        assert_eq!(
+            serde_json::to_value(&payload)?,
+            json!({
+                "node": "@node=.ed25519",
+                "timestamp": 1,
+                "event": "peer_connected",
+                "peer": "@peer=.ed25519"
+            })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_message_events() -> crate::Result<()> {
+        let local_id = SecretConfig::create().to_owned_identity()?.id;
+        let peer = SecretConfig::create().to_owned_identity()?;
+
+        let follow = TypedMessage::Contact {
+            contact: Some(local_id.to_owned()),
+            blocking: None,
+            following: Some(true),
+            autofollow: None,
+        };
+        let msg = Message::sign(None, &peer, json!(follow))?;
+        let now = msg.value["timestamp"].as_f64().unwrap_or_default() as u64;
+
+        let events = message_events(&msg, &local_id, now);
+        assert_eq!(
+            events,
+            vec![WebhookEvent::NewFollower {
+                follower: peer.id.to_owned(),
+                msg_ref: msg.id().to_string(),
+            }]
+        );
+
+        // Old messages, replicated during the sync of a feed, are ignored.
+        let later = now + MAX_MESSAGE_AGE.as_millis() as u64 + 1;
+        assert!(message_events(&msg, &local_id, later).is_empty());
+
+        let post = json!({
+            "type": "post",
+            "text": format!("Hello [@solar]({})", local_id),
+            "mentions": [{ "link": local_id, "name": "solar" }]
+        });
+        let msg = Message::sign(Some(&msg), &peer, post)?;
+        assert_eq!(
+            message_events(&msg, &local_id, now),
+            vec![WebhookEvent::Mention {
+                author: peer.id.to_owned(),
+                msg_ref: msg.id().to_string(),
+            }]
+        );
+
+        // Messages of the local identity are ignored.
+        assert!(message_events(&msg, &peer.id, now).is_empty());
+
+        Ok(())
+    }
+}
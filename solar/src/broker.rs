@@ -1,18 +1,26 @@
-use std::collections::hash_map::HashMap;
+use std::{any::Any, collections::hash_map::HashMap, panic::AssertUnwindSafe};
 
 use async_std::{prelude::*, sync::Mutex, task, task::JoinHandle};
 use futures::{
     channel::{mpsc, oneshot},
     select_biased, FutureExt, SinkExt,
 };
-use log::{info, trace};
+use log::{error, info, trace, warn};
 use once_cell::sync::Lazy;
 
 use crate::{
     actors::{
-        muxrpc::{RpcBlobsGetEvent, RpcBlobsWantsEvent},
-        network::{connection_manager::ConnectionEvent, connection_scheduler::DialRequest},
-        replication::ebt::EbtEvent,
+        muxrpc::{OooRequest, PreviewRequest, RpcBlobsGetEvent, RpcBlobsWantsEvent},
+        network::{
+            connection_manager::ConnectionEvent,
+            connection_scheduler::{
+                AttendantEvent, DialRequest, LanScheduleRequest, PenaltyRequest, ScheduleRequest,
+            },
+            connection_state::ConnectionTransition,
+        },
+        replication::{blob_events::BlobEvent, ebt::EbtEvent},
+        retention::pressure::StoragePressure,
+        social::SocialEvent,
     },
     storage::{blob::StoreBlobEvent, kv::StoreKvEvent},
     Result,
@@ -23,13 +31,23 @@ pub struct Void {}
 
 #[derive(Debug, Clone)]
 pub enum BrokerMessage {
+    Attendant(AttendantEvent),
+    Blob(BlobEvent),
     Connection(ConnectionEvent),
     Dial(DialRequest),
     Ebt(EbtEvent),
+    Ooo(OooRequest),
+    Penalize(PenaltyRequest),
+    Preview(PreviewRequest),
     RpcBlobsGet(RpcBlobsGetEvent),
     RpcBlobsWants(RpcBlobsWantsEvent),
+    Schedule(ScheduleRequest),
+    ScheduleLan(LanScheduleRequest),
+    Social(SocialEvent),
     StoreBlob(StoreBlobEvent),
     StoreKv(StoreKvEvent),
+    StoragePressure(StoragePressure),
+    Transition(ConnectionTransition),
 }
 
 pub type ChBrokerSend = mpsc::UnboundedSender<BrokerEvent>;
@@ -56,6 +74,8 @@ pub enum BrokerEvent {
     Disconnect { actor_id: usize },
     /// Actor message.
     Message { to: Destination, msg: BrokerMessage },
+    /// An actor panicked. The panic was caught and the actor task ended.
+    Panic { name: String, reason: String },
     /// Termination signal.
     Terminate,
 }
@@ -122,9 +142,10 @@ impl Broker {
         }
     }
 
-    /// Return a handle for the broker message loop.
-    pub fn take_msgloop(&mut self) -> JoinHandle<()> {
-        self.msgloop.take().unwrap()
+    /// Return a handle for the broker message loop, or `None` if it has
+    /// already been taken by a running node.
+    pub fn take_msgloop(&mut self) -> Option<JoinHandle<()>> {
+        self.msgloop.take()
     }
 
     /// Register a new actor with the broker.
@@ -184,17 +205,101 @@ impl Broker {
     }
 
     /// Spawn an asynchronous task.
-    pub fn spawn<F>(fut: F) -> task::JoinHandle<()>
+    ///
+    /// A panic in the task is caught, logged and reported to the broker
+    /// instead of unwinding through the executor.
+    pub fn spawn<F>(name: &'static str, fut: F) -> task::JoinHandle<()>
     where
         F: Future<Output = Result<()>> + Send + 'static,
     {
         task::spawn(async move {
-            if let Err(e) = fut.await {
-                eprintln!("{e}")
+            let _ = Self::run_isolated(name, fut).await;
+        })
+    }
+
+    /// Spawn an asynchronous task which is restarted if it panics, up to
+    /// `max_restarts` times.
+    ///
+    /// The task is created by calling `factory`, once for the initial run and
+    /// once for each restart. A task which returns (with or without an error)
+    /// is not restarted.
+    pub fn spawn_supervised<F, Fut>(
+        name: &'static str,
+        max_restarts: usize,
+        factory: F,
+    ) -> task::JoinHandle<()>
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        task::spawn(async move {
+            let mut restarts = 0;
+            while Self::run_isolated(name, factory()).await && restarts < max_restarts {
+                restarts += 1;
+                warn!(
+                    "Restarting actor {} after panic (restart {} of {})",
+                    name, restarts, max_restarts
+                );
             }
         })
     }
 
+    /// Run the given future to completion, catching any panic.
+    ///
+    /// Returns `true` if the future panicked.
+    async fn run_isolated<F>(name: &'static str, fut: F) -> bool
+    where
+        F: Future<Output = Result<()>> + Send + 'static,
+    {
+        match AssertUnwindSafe(fut).catch_unwind().await {
+            Ok(Ok(())) => false,
+            Ok(Err(err)) => {
+                eprintln!("{err}");
+                false
+            }
+            Err(panic) => {
+                let reason = Self::panic_reason(panic);
+                error!("Actor {} panicked: {}", name, reason);
+
+                // Report the panic to the broker.
+                let mut ch_broker = BROKER.lock().await.create_sender();
+                let _ = ch_broker
+                    .send(BrokerEvent::Panic {
+                        name: name.to_string(),
+                        reason,
+                    })
+                    .await;
+
+                true
+            }
+        }
+    }
+
+    /// Extract the message from a panic payload.
+    pub(crate) fn panic_reason(panic: Box<dyn Any + Send>) -> String {
+        if let Some(reason) = panic.downcast_ref::<&str>() {
+            reason.to_string()
+        } else if let Some(reason) = panic.downcast_ref::<String>() {
+            reason.to_owned()
+        } else {
+            "unknown panic payload".to_string()
+        }
+    }
+
+    /// Deregister the actors which panicked. The actor endpoint of a
+    /// panicked actor was dropped while unwinding (without deregistering),
+    /// closing its terminated signal channel.
+    fn remove_panicked(actors: &mut HashMap<usize, BrokerEndpoint>) {
+        actors.retain(|actor_id, actor| {
+            let panicked = actor.ch_terminated.try_recv().is_err();
+            if panicked {
+                trace!(target:"solar-actor", "Deregistering actor {}", actor_id);
+            }
+
+            !panicked
+        });
+    }
+
     /// Start the broker event loop.
     ///
     /// This is the switchboard of the application. It listens for `BrokerEvent`
@@ -225,6 +330,10 @@ impl Broker {
                     trace!(target:"solar-actor", "Deregistering actor {}", actor_id);
                     actors.remove(&actor_id);
                 }
+                BrokerEvent::Panic { name, reason } => {
+                    trace!(target:"solar-actor", "Actor {} panicked: {}", name, reason);
+                    Self::remove_panicked(&mut actors);
+                }
                 BrokerEvent::Message { to, msg } => {
                     for actor in actors.values_mut() {
                         // Send the message to a single, specific actor or to
@@ -270,3 +379,81 @@ impl Broker {
         drop(actors);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    /// Create a broker endpoint with the given ID, along with the sender of
+    /// its terminated signal.
+    fn endpoint(actor_id: usize) -> (BrokerEndpoint, ChSigSend) {
+        let (ch_terminate, _) = oneshot::channel();
+        let (ch_terminated_sender, ch_terminated) = oneshot::channel();
+        let endpoint = BrokerEndpoint {
+            actor_id,
+            ch_terminate,
+            ch_terminated,
+            ch_msg: None,
+        };
+
+        (endpoint, ch_terminated_sender)
+    }
+
+    #[test]
+    fn test_remove_panicked() {
+        // The terminated signal sender of the panicked actor is dropped.
+        let (panicked, _) = endpoint(1);
+        let (running, _running) = endpoint(2);
+
+        let mut actors = HashMap::from([(1, panicked), (2, running)]);
+        Broker::remove_panicked(&mut actors);
+
+        assert_eq!(actors.into_keys().collect::<Vec<usize>>(), vec![2]);
+    }
+
+    #[async_std::test]
+    async fn test_spawn_catches_panic() {
+        // The panic must not propagate to the task awaiting the handle.
+        Broker::spawn("panicking-actor", async { panic!("handler failed") }).await;
+    }
+
+    #[async_std::test]
+    async fn test_spawn_supervised_restarts_after_panic() {
+        let runs = Arc::new(AtomicUsize::new(0));
+
+        let counter = runs.clone();
+        Broker::spawn_supervised("panicking-actor", 2, move || {
+            let counter = counter.clone();
+            async move {
+                counter.fetch_add(1, Ordering::SeqCst);
+                panic!("handler failed")
+            }
+        })
+        .await;
+
+        // One initial run and two restarts.
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+    }
+
+    #[async_std::test]
+    async fn test_spawn_supervised_does_not_restart_on_return() {
+        let runs = Arc::new(AtomicUsize::new(0));
+
+        let counter = runs.clone();
+        Broker::spawn_supervised("returning-actor", 2, move || {
+            let counter = counter.clone();
+            async move {
+                counter.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            }
+        })
+        .await;
+
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+    }
+}
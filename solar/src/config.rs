@@ -1,28 +1,68 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    sync::Arc,
+};
 
 use kuska_sodiumoxide::crypto::auth::Key as NetworkKey;
 use log::{debug, info};
 use once_cell::sync::OnceCell;
+use serde_json::{json, Value};
 use sled::Config as DatabaseConfig;
 use xdg::BaseDirectories;
 
 use crate::{
     actors::{
-        jsonrpc::config::JsonRpcConfig, network::config::NetworkConfig,
-        replication::config::ReplicationConfig,
+        follow_back::FollowBackPolicy,
+        jsonrpc::config::JsonRpcConfig,
+        network::config::{NetworkConfig, TransportConfig},
+        replication::config::{
+            BlobPolicy, IngestPolicy, ReplicationConfig, ReplicationDirection, ValidationPolicy,
+        },
+        retention::config::RetentionConfig,
     },
+    error::Error,
+    lock::DataDirLock,
+    logger::LogConfig,
     secret_config::SecretConfig,
+    storage::publish::PublishPolicy,
     Result,
 };
 
 // Write once store for the network key (aka. SHS key or caps key).
 pub static NETWORK_KEY: OnceCell<NetworkKey> = OnceCell::new();
+// Write once store for the set of admins allowed to manage the node over
+// MUXRPC.
+pub static ADMIN_PEERS: OnceCell<HashSet<String>> = OnceCell::new();
+// Write once store for the blob fetch policy.
+pub static BLOB_POLICY: OnceCell<BlobPolicy> = OnceCell::new();
+// Write once store for the ingest rate limits.
+pub static INGEST_POLICY: OnceCell<IngestPolicy> = OnceCell::new();
+// Write once store for the set of legacy pubs whose protocol quirks are
+// tolerated.
+pub static LEGACY_PEERS: OnceCell<HashSet<String>> = OnceCell::new();
+// Write once store for the mirror mode configuration.
+pub static MIRROR_MODE: OnceCell<bool> = OnceCell::new();
+// Write once store for the publish policy.
+pub static PUBLISH_POLICY: OnceCell<PublishPolicy> = OnceCell::new();
+// Write once store for the direction of replication with specific peers.
+pub static REPLICATION_DIRECTIONS: OnceCell<HashMap<String, ReplicationDirection>> =
+    OnceCell::new();
 // Write once store for the list of Scuttlebutt peers to replicate.
 pub static PEERS_TO_REPLICATE: OnceCell<HashMap<String, String>> = OnceCell::new();
 // Write once store for the database resync configuration.
 pub static RESYNC_CONFIG: OnceCell<bool> = OnceCell::new();
+// Write once store for the set of feeds whose messages are traced.
+pub static TRACED_FEEDS: OnceCell<HashSet<String>> = OnceCell::new();
+// Write once store for the tuning of TCP connections and box streams.
+pub static TRANSPORT_CONFIG: OnceCell<TransportConfig> = OnceCell::new();
 // Write-once store for the public-private keypair.
 pub static SECRET_CONFIG: OnceCell<SecretConfig> = OnceCell::new();
+// Write once store for the message validation policy.
+pub static VALIDATION_POLICY: OnceCell<ValidationPolicy> = OnceCell::new();
+// Write once store for the settings from which the stores above were
+// derived, used to reject a node whose configuration differs.
+static GLOBALS_SOURCE: OnceCell<Value> = OnceCell::new();
 
 /// Application configuration for solar.
 #[derive(Debug, Default, Clone)]
@@ -30,21 +70,51 @@ pub struct ApplicationConfig {
     /// Root data directory.
     pub base_path: Option<PathBuf>,
 
+    /// Lock held on the data directory for as long as the configuration (or
+    /// a clone of it) is alive.
+    pub(crate) data_dir_lock: Option<Arc<DataDirLock>>,
+
     /// Sled key-value database configuration.
     pub database: DatabaseConfig,
 
     /// Sled key-value cache capacity.
     pub database_cache_capacity: u64,
 
+    /// Salvage readable feeds from a corrupted key-value database before
+    /// opening it.
+    pub database_repair: bool,
+
+    /// Stop the node when Ctrl+C (SIGINT) is received. Embedding
+    /// applications usually leave this disabled and stop the node with
+    /// `NodeHandle::shutdown`.
+    pub handle_ctrlc: bool,
+
+    /// Keep all data in memory, leaving no files behind once the node
+    /// exits. Used by integration tests and throwaway demo nodes.
+    pub ephemeral: bool,
+
+    /// Policy by which new followers of the local identity are followed
+    /// back.
+    pub follow_back: FollowBackPolicy,
+
     /// JSON-RPC configuration.
     pub jsonrpc: JsonRpcConfig,
 
+    /// Logging configuration.
+    pub log: LogConfig,
+
     /// Network configuration.
     pub network: NetworkConfig,
 
+    /// Policy constraining the messages published on the local feed.
+    pub publish: PublishPolicy,
+
     /// Replication configuration.
     pub replication: ReplicationConfig,
 
+    /// Retention (pruning) configuration.
+    pub retention: RetentionConfig,
+
     /// Public-private keypair configuration.
     pub secret: SecretConfig,
 }
@@ -81,30 +151,152 @@ impl ApplicationConfig {
 
         let mut config = ApplicationConfig::default();
 
+        // Fail early if another process is using the data directory.
+        config.data_dir_lock = Some(Arc::new(DataDirLock::acquire(&base_path)?));
+
         config.database = config.database.path(feeds_path);
         config.replication = ReplicationConfig::return_or_create_file(&base_path)?;
         config.secret = SecretConfig::return_or_create_file(&base_path)?;
         config.base_path = Some(base_path);
 
+        config.init_globals();
+
+        Ok(config)
+    }
+
+    /// Configure an ephemeral node with a new keypair and default settings.
+    /// No data directory is created: the key-value database is a temporary
+    /// sled database (deleted when the node exits) and blobs are kept in
+    /// memory.
+    pub fn new_ephemeral() -> Result<Self> {
+        info!("Running an ephemeral node; data will not be persisted");
+
+        let mut config = ApplicationConfig::default();
+
+        config.database = config.database.temporary(true);
+        config.ephemeral = true;
+        config.secret = SecretConfig::create();
+
+        config.init_globals();
+
+        Ok(config)
+    }
+
+    /// Return the settings from which the write-once stores set by
+    /// `init_globals` are derived.
+    fn globals_source(&self) -> Result<Value> {
+        Ok(json!({
+            "network_key": hex::encode(self.network.key),
+            "replication": serde_json::to_value(&self.replication)?,
+            "secret": serde_json::to_value(&self.secret)?,
+        }))
+    }
+
+    /// Ensure that the write-once stores hold the values derived from this
+    /// configuration, setting the publish policy and the transport tuning if
+    /// they are not set yet (these are set when the node starts rather than
+    /// when the configuration is created, so that embedders can change
+    /// them in between).
+    ///
+    /// The stores are shared by all the nodes started in the process and
+    /// cannot be reset, so this fails if they were set from a differing
+    /// configuration (for example, by an earlier node using another
+    /// keypair); the process must then be restarted to apply it.
+    pub(crate) fn check_globals(&self) -> Result<()> {
+        let source = self.globals_source()?;
+        let matches = GLOBALS_SOURCE.get_or_init(|| source.clone()) == &source
+            && PUBLISH_POLICY.get_or_init(|| self.publish.to_owned()) == &self.publish
+            && TRANSPORT_CONFIG.get_or_init(|| self.network.transport) == &self.network.transport;
+
+        if matches {
+            Ok(())
+        } else {
+            Err(Error::Other(
+                "the configuration differs from that of a node started earlier in this process"
+                    .to_string(),
+            ))
+        }
+    }
+
+    /// Set the write-once stores derived from the configuration.
+    fn init_globals(&self) {
         // Add @-prefix to all peer IDs. This is required for successful
         // replication when using either classic or EBT replication methods.
         let mut replication_peers = HashMap::new();
-        for (id, addr) in &config.replication.peers {
+        for (id, addr) in &self.replication.peers {
             replication_peers.insert(format!("@{}", id), addr.to_owned());
         }
 
+        // Likewise for the IDs of legacy pubs.
+        let legacy_peers: HashSet<String> = self
+            .replication
+            .legacy_peers
+            .iter()
+            .map(|id| format!("@{}", id))
+            .collect();
+
+        // Likewise for the IDs of admins.
+        let admin_peers: HashSet<String> = self
+            .replication
+            .admins
+            .iter()
+            .map(|id| format!("@{}", id))
+            .collect();
+
+        // Likewise for the IDs of traced feeds.
+        let traced_feeds: HashSet<String> = self
+            .replication
+            .traced_feeds
+            .iter()
+            .map(|id| format!("@{}", id))
+            .collect();
+
+        // Likewise for the IDs of peers with a replication direction.
+        let replication_directions: HashMap<String, ReplicationDirection> = self
+            .replication
+            .directions
+            .iter()
+            .map(|(id, direction)| (format!("@{}", id), *direction))
+            .collect();
+
+        // Likewise for the IDs of peers denied by the blob fetch policy.
+        let mut blob_policy = self.replication.blobs.to_owned();
+        blob_policy.deny = blob_policy
+            .deny
+            .iter()
+            .map(|id| format!("@{}", id))
+            .collect();
+
         // Log the list of public keys identifying peers whose data will be replicated.
         debug!("Peers to be replicated are {:?}", &replication_peers);
 
         // Set the value of the network key (aka. secret handshake key or caps key).
-        let _err = NETWORK_KEY.set(config.network.key.to_owned());
+        let _err = NETWORK_KEY.set(self.network.key.to_owned());
+        // Set the value of the admin peers cell.
+        let _err = ADMIN_PEERS.set(admin_peers);
+        // Set the value of the blob fetch policy cell.
+        let _err = BLOB_POLICY.set(blob_policy);
+        // Set the value of the ingest policy cell.
+        let _err = INGEST_POLICY.set(self.replication.ingest.to_owned());
+        // Set the value of the legacy peers cell.
+        let _err = LEGACY_PEERS.set(legacy_peers);
         // Set the value of the peers to replicate cell.
         let _err = PEERS_TO_REPLICATE.set(replication_peers);
+        // Set the value of the replication directions cell.
+        let _err = REPLICATION_DIRECTIONS.set(replication_directions);
+        // Set the value of the mirror mode configuration cell.
+        let _err = MIRROR_MODE.set(self.replication.mirror);
         // Set the value of the resync configuration cell.
-        let _err = RESYNC_CONFIG.set(config.replication.resync);
+        let _err = RESYNC_CONFIG.set(self.replication.resync);
+        // Set the value of the traced feeds cell.
+        let _err = TRACED_FEEDS.set(traced_feeds);
         // Set the value of the secret configuration cell.
-        let _err = SECRET_CONFIG.set(config.secret.to_owned());
-
-        Ok(config)
+        let _err = SECRET_CONFIG.set(self.secret.to_owned());
+        // Set the value of the message validation policy cell.
+        let _err = VALIDATION_POLICY.set(self.replication.validation.to_owned());
+        // Record the settings from which the cells above were set.
+        if let Ok(source) = self.globals_source() {
+            let _err = GLOBALS_SOURCE.set(source);
+        }
     }
 }
@@ -0,0 +1,294 @@
+//! Protocol conformance harness.
+//!
+//! Runs the MUXRPC handlers of solar against the packets of a peer, without
+//! a connection: packets are decoded from their wire bytes exactly as they
+//! are on a connection, handed to the handler, and the packets written in
+//! response are captured in memory. Used by the conformance tests, which
+//! replay the packets recorded from other Scuttlebutt implementations, and
+//! by the handler tests.
+//!
+//! The handlers read and write the node's key-value store, which must be
+//! opened with [`open_store`] first.
+
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+};
+
+use futures::{
+    channel::mpsc,
+    io::{AsyncWrite, Cursor},
+    StreamExt,
+};
+use kuska_ssb::{
+    api::ApiCaller,
+    feed::Message,
+    rpc::{RpcReader, RpcWriter},
+};
+
+use crate::{
+    actors::{
+        muxrpc::{EbtReplicateHandler, HistoryStreamHandler, RpcHandler, RpcInput},
+        replication::ebt::{EbtEvent, FeedFormat, SessionRole, EBT_REQUESTS},
+    },
+    broker::{BrokerEvent, BrokerMessage, ChBrokerSend},
+    config::SECRET_CONFIG,
+    node::KV_STORE,
+    secret_config::SecretConfig,
+    storage::kv::StoreKvEvent,
+    Result,
+};
+
+/// Packet flag of stream packets.
+const FLAG_STREAM: u8 = 0b1000;
+/// Packet flag of packets ending a stream (or carrying an error).
+const FLAG_END_OR_ERROR: u8 = 0b0100;
+/// Packet flag of JSON bodies.
+const FLAG_JSON: u8 = 0b0010;
+
+/// Length of the header of a MUXRPC packet.
+const HEADER_LEN: usize = 9;
+
+/// Connection IDs of the harness sessions, distinct from the connections of
+/// the node.
+static NEXT_CONNECTION_ID: AtomicUsize = AtomicUsize::new(usize::MAX / 2);
+
+/// Open a temporary key-value store as the store of the node, along with a
+/// secret configuration for the local identity. Does nothing if a store is
+/// already open.
+pub async fn open_store() -> Result<()> {
+    let mut kv = KV_STORE.write().await;
+    if kv.is_open() {
+        return Ok(());
+    }
+
+    let _err = SECRET_CONFIG.set(SecretConfig::create());
+    // The events of the store are delivered to the handlers by the
+    // harness itself.
+    let (sender, _) = mpsc::unbounded();
+    kv.open(sled::Config::new().temporary(true), sender)
+}
+
+/// Append the given message to the store.
+pub async fn append(msg: Message) -> Result<u64> {
+    KV_STORE.read().await.append_feed(msg).await
+}
+
+/// Encode a MUXRPC packet with a JSON body, as sent by a peer on a stream.
+pub fn json_packet(req_no: i32, body: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(HEADER_LEN + body.len());
+    packet.push(FLAG_STREAM | FLAG_JSON);
+    packet.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    packet.extend_from_slice(&req_no.to_be_bytes());
+    packet.extend_from_slice(body);
+
+    packet
+}
+
+/// A MUXRPC packet written by a handler.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    /// Request number, as written (negative for responses).
+    pub req_no: i32,
+    /// Whether the packet ends the stream.
+    pub end: bool,
+    /// Body of the packet, exactly as written.
+    pub body: Vec<u8>,
+}
+
+/// A writer which keeps the bytes written to it.
+#[derive(Clone, Default)]
+pub struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuffer {
+    /// Remove and decode the packets written so far.
+    pub fn take_packets(&self) -> Vec<Packet> {
+        let bytes = std::mem::take(&mut *self.0.lock().unwrap());
+
+        let mut packets = Vec::new();
+        let mut rest = &bytes[..];
+        while rest.len() >= HEADER_LEN {
+            let len = u32::from_be_bytes([rest[1], rest[2], rest[3], rest[4]]) as usize;
+            let req_no = i32::from_be_bytes([rest[5], rest[6], rest[7], rest[8]]);
+            packets.push(Packet {
+                req_no,
+                end: rest[0] & FLAG_END_OR_ERROR != 0,
+                body: rest[HEADER_LEN..HEADER_LEN + len].to_vec(),
+            });
+            rest = &rest[HEADER_LEN + len..];
+        }
+
+        packets
+    }
+}
+
+impl AsyncWrite for SharedBuffer {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Decode the MUXRPC packets contained in the given bytes, as the packets
+/// received on a connection are.
+pub(crate) async fn decode(bytes: &[u8]) -> Vec<RpcInput> {
+    RpcReader::new(Cursor::new(bytes.to_vec()))
+        .into_stream()
+        .map(|(req_no, msg)| RpcInput::Network(req_no, msg))
+        .collect()
+        .await
+}
+
+/// A session with a peer served by the history stream handler.
+pub struct HistoryStreamSession {
+    handler: HistoryStreamHandler<SharedBuffer>,
+    api: ApiCaller<SharedBuffer>,
+    output: SharedBuffer,
+    ch_broker: ChBrokerSend,
+}
+
+impl HistoryStreamSession {
+    /// Start a session which serves history stream requests.
+    pub fn new() -> Self {
+        let output = SharedBuffer::default();
+        let (ch_broker, _) = mpsc::unbounded();
+
+        Self {
+            handler: HistoryStreamHandler::serve_only(0),
+            api: ApiCaller::new(RpcWriter::new(output.clone())),
+            output,
+            ch_broker,
+        }
+    }
+
+    /// Handle the packets contained in the given bytes, as received from the
+    /// peer.
+    pub async fn receive(&mut self, bytes: &[u8]) -> Result<()> {
+        for input in decode(bytes).await {
+            self.handler
+                .handle(&mut self.api, &input, &mut self.ch_broker)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Notify the handler of a message appended to the given feed of the
+    /// store.
+    pub async fn appended(&mut self, ssb_id: &str, seq: u64) -> Result<()> {
+        let input = RpcInput::Message(BrokerMessage::StoreKv(StoreKvEvent((
+            ssb_id.to_owned(),
+            seq,
+        ))));
+        self.handler
+            .handle(&mut self.api, &input, &mut self.ch_broker)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Remove and return the packets written to the peer so far.
+    pub fn sent(&self) -> Vec<Packet> {
+        self.output.take_packets()
+    }
+}
+
+impl Default for HistoryStreamSession {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// An EBT session with a peer, initiated by the local node with the given
+/// replicate request.
+pub struct EbtSession {
+    handler: EbtReplicateHandler<SharedBuffer>,
+    api: ApiCaller<SharedBuffer>,
+    output: SharedBuffer,
+    ch_broker: ChBrokerSend,
+    events: mpsc::UnboundedReceiver<BrokerEvent>,
+    peer_ssb_id: String,
+    connection_id: usize,
+}
+
+impl EbtSession {
+    /// Start a session with the given peer, as if the local node had sent
+    /// a replicate request of classic feeds with the given request number.
+    pub async fn requested(peer_ssb_id: &str, req_no: i32) -> Self {
+        let connection_id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+        EBT_REQUESTS.write().await.register(
+            connection_id,
+            req_no,
+            SessionRole::Requester,
+            FeedFormat::Classic,
+        );
+
+        let output = SharedBuffer::default();
+        let (ch_broker, events) = mpsc::unbounded();
+
+        Self {
+            handler: EbtReplicateHandler::new(),
+            api: ApiCaller::new(RpcWriter::new(output.clone())),
+            output,
+            ch_broker,
+            events,
+            peer_ssb_id: peer_ssb_id.to_owned(),
+            connection_id,
+        }
+    }
+
+    /// Handle the packets contained in the given bytes, as received from the
+    /// peer.
+    pub async fn receive(&mut self, bytes: &[u8]) -> Result<()> {
+        for input in decode(bytes).await {
+            self.handler
+                .handle(
+                    &mut self.api,
+                    &input,
+                    &mut self.ch_broker,
+                    self.peer_ssb_id.clone(),
+                    self.connection_id,
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Remove and return the EBT events emitted by the handler so far.
+    pub fn events(&mut self) -> Vec<EbtEvent> {
+        let mut events = Vec::new();
+        while let Ok(Some(event)) = self.events.try_next() {
+            if let BrokerEvent::Message {
+                msg: BrokerMessage::Ebt(event),
+                ..
+            } = event
+            {
+                events.push(event);
+            }
+        }
+
+        events
+    }
+
+    /// Remove and return the packets written to the peer so far.
+    pub fn sent(&self) -> Vec<Packet> {
+        self.output.take_packets()
+    }
+}
@@ -1,4 +1,4 @@
-use std::{fmt, io, net, num};
+use std::{fmt, io, net, num, path::PathBuf};
 
 use futures::channel::mpsc;
 use jsonrpsee::types::error::ErrorObjectOwned as JsonRpcErrorOwned;
@@ -6,29 +6,46 @@ use jsonrpsee::types::error::SERVER_ERROR_MSG;
 use kuska_ssb::{api, crypto, discovery, feed, handshake, rpc};
 use toml::{de, ser};
 
-use crate::actors::muxrpc::ReqNo;
+use crate::actors::{muxrpc::ReqNo, network::handshake::HandshakeError};
 
 /// Possible solar errors.
 #[derive(Debug)]
 pub enum Error {
     /// IP address parsing error.
     AddrParse(net::AddrParseError),
+    /// Invalid backup archive or failed backup operation.
+    Backup(String),
     /// xdg::BaseDirectoriesError.
     BaseDirectories(xdg::BaseDirectoriesError),
     /// Configuration error.
     Config(String),
     /// SSB cryptograpy error.
     Crypto(crypto::Error),
+    /// Invalid pagination cursor.
+    Cursor(String),
     /// Sled database error.
     Database(sled::Error),
+    /// The data directory is in use by another running process (path of the
+    /// lock file and PID of the process, if known).
+    DataDirectoryLocked((PathBuf, Option<u32>)),
     /// Failed to deserialization TOML.
     DeserializeToml(de::Error),
     /// EBT replicate request received an error response.
     EbtReplicate((ReqNo, String)),
     /// Failed to send message on futures channel.
     FuturesChannel(mpsc::SendError),
+    /// Classified secret handshake failure.
+    Handshake(HandshakeError),
+    /// HTTP request error.
+    Http(reqwest::Error),
+    /// The keypair of the local identity appears to be in use on another
+    /// device.
+    IdentityConflict(String),
     /// Database indexes.
     Indexes,
+    /// Validation error; the message failed a check of the validation
+    /// pipeline.
+    InvalidMessage(String),
     /// Validation error; invalid message sequence number.
     InvalidSequence,
     /// io::Error.
@@ -37,6 +54,8 @@ pub enum Error {
     JsonRpc(jsonrpsee::core::Error),
     /// LAN UDP discovery error.
     LanDiscovery(discovery::Error),
+    /// Invalid local record or unavailable local records store.
+    LocalRecord(String),
     /// SSB message type field error.
     MessageType(String),
     /// SSB RPC error.
@@ -44,6 +63,12 @@ pub enum Error {
     /// None error (expected an `Option` to be `Some`).
     // TODO: Add context String.
     OptionIsNone,
+    /// The local feed is not in the state expected by a publish.
+    PublishConflict(String),
+    /// The message to be published breaks the publish policy.
+    PublishPolicy(String),
+    /// Room invite error.
+    RoomInvite(String),
     /// Secret handshake error.
     SecretHandshake(handshake::async_std::Error),
     /// Serde CBOR error.
@@ -54,6 +79,8 @@ pub enum Error {
     SerializeToml(ser::Error),
     /// SSB API error.
     SsbApi(api::Error),
+    /// Invalid SSB URI.
+    SsbUri(String),
     /// TryFromInt error.
     TryFromInt(num::TryFromIntError),
     /// URL parsing error.
@@ -70,10 +97,20 @@ impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Error::AddrParse(err) => write!(f, "Failed to parse IP address: {err}"),
+            Error::Backup(err) => write!(f, "Backup error: {err}"),
             Error::BaseDirectories(err) => write!(f, "Base directory error: {err}"),
             Error::Config(err) => write!(f, "Configuration error: {err}"),
             Error::Crypto(err) => write!(f, "SSB cryptographic error: {err}"),
+            Error::Cursor(err) => write!(f, "Invalid pagination cursor: {err}"),
             Error::Database(err) => write!(f, "Key-value database error: {err}"),
+            Error::DataDirectoryLocked((path, Some(pid))) => write!(
+                f,
+                "Data directory is in use by another solar process (PID {pid}, holding the lock file {path:?}); stop that process first"
+            ),
+            Error::DataDirectoryLocked((path, None)) => write!(
+                f,
+                "Data directory is in use by another solar process (holding the lock file {path:?}); stop that process first"
+            ),
             Error::DeserializeToml(err) => write!(f, "Failed to deserialize TOML: {err}"),
             Error::EbtReplicate((req_no, err)) => write!(
                 f,
@@ -82,7 +119,11 @@ impl fmt::Display for Error {
             Error::FuturesChannel(err) => {
                 write!(f, "Failed to send message on futures channel: {err}")
             }
+            Error::Handshake(err) => write!(f, "Secret handshake failed: {err}"),
+            Error::Http(err) => write!(f, "HTTP error: {err}"),
+            Error::IdentityConflict(err) => write!(f, "Identity conflict: {err}"),
             Error::Indexes => write!(f, "Indexes error: indexes not initialised"),
+            Error::InvalidMessage(err) => write!(f, "Validation error: {err}"),
             // TODO: Attach context so we know the identity of the offending message.
             Error::InvalidSequence => write!(
                 f,
@@ -91,14 +132,19 @@ impl fmt::Display for Error {
             Error::Io(err) => write!(f, "I/O error: {err}"),
             Error::JsonRpc(err) => write!(f, "JSON-RPC error: {err}"),
             Error::LanDiscovery(err) => write!(f, "LAN UDP discovery error: {err}"),
+            Error::LocalRecord(err) => write!(f, "Local record error: {err}"),
             Error::MessageType(err) => write!(f, "SSB message type field error: {err}"),
             Error::MuxRpc(err) => write!(f, "MUXRPC error: {err}"),
             Error::OptionIsNone => write!(f, "None error: expected Some"),
+            Error::PublishConflict(err) => write!(f, "Publish conflict: {err}"),
+            Error::PublishPolicy(err) => write!(f, "Publish denied by policy: {err}"),
+            Error::RoomInvite(err) => write!(f, "Room invite error: {err}"),
             Error::SecretHandshake(err) => write!(f, "Secret handshake error: {err}"),
             Error::SerdeCbor(err) => write!(f, "Serde CBOR error: {err}"),
             Error::SerdeJson(err) => write!(f, "Serde JSON error: {err}"),
             Error::SerializeToml(err) => write!(f, "Failed to serialize TOML: {err}"),
             Error::SsbApi(err) => write!(f, "SSB API error: {err}"),
+            Error::SsbUri(err) => write!(f, "Invalid SSB URI: {err}"),
             Error::TryFromInt(err) => write!(f, "Integer conversion error: {err}"),
             Error::UrlParse(err) => write!(f, "Failed to parse URL: {err}"),
             Error::Validation(err) => write!(f, "Message validation error: {err}"),
@@ -155,12 +201,24 @@ impl From<jsonrpsee::core::Error> for Error {
     }
 }
 
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Error {
+        Error::Http(err)
+    }
+}
+
 impl From<discovery::Error> for Error {
     fn from(err: discovery::Error) -> Error {
         Error::LanDiscovery(err)
     }
 }
 
+impl From<HandshakeError> for Error {
+    fn from(err: HandshakeError) -> Error {
+        Error::Handshake(err)
+    }
+}
+
 impl From<rpc::Error> for Error {
     fn from(err: rpc::Error) -> Error {
         Error::MuxRpc(err)
@@ -215,6 +273,17 @@ impl From<feed::Error> for Error {
     }
 }
 
+impl From<solar_core::Error> for Error {
+    fn from(err: solar_core::Error) -> Error {
+        match err {
+            solar_core::Error::BendyButt(err) => Error::InvalidMessage(err),
+            solar_core::Error::Buttwoo(err) => Error::InvalidMessage(err),
+            solar_core::Error::SsbUri(err) => Error::SsbUri(err),
+            solar_core::Error::TryFromInt(err) => Error::TryFromInt(err),
+        }
+    }
+}
+
 // Conversions for errors which occur in the context of a JSON-RPC method call.
 // Crate-local error variants are converted to JSON-RPC errors which are
 // then return to the caller.
@@ -230,7 +299,40 @@ impl From<Error> for JsonRpcErrorOwned {
             Error::Validation(err_msg) => {
                 JsonRpcErrorOwned::owned(-32002, SERVER_ERROR_MSG, Some(err_msg.to_string()))
             }
+            Error::InvalidMessage(err_msg) => {
+                JsonRpcErrorOwned::owned(-32002, SERVER_ERROR_MSG, Some(err_msg.to_string()))
+            }
             Error::Indexes => JsonRpcErrorOwned::owned(-32003, SERVER_ERROR_MSG, None::<String>),
+            Error::Config(err_msg) => {
+                JsonRpcErrorOwned::owned(-32004, SERVER_ERROR_MSG, Some(err_msg.to_string()))
+            }
+            Error::Cursor(err_msg) => {
+                JsonRpcErrorOwned::owned(-32005, SERVER_ERROR_MSG, Some(err_msg.to_string()))
+            }
+            Error::Http(err_msg) => {
+                JsonRpcErrorOwned::owned(-32006, SERVER_ERROR_MSG, Some(err_msg.to_string()))
+            }
+            Error::RoomInvite(err_msg) => {
+                JsonRpcErrorOwned::owned(-32006, SERVER_ERROR_MSG, Some(err_msg.to_string()))
+            }
+            Error::SsbUri(err_msg) => {
+                JsonRpcErrorOwned::owned(-32007, SERVER_ERROR_MSG, Some(err_msg.to_string()))
+            }
+            Error::PublishConflict(err_msg) => {
+                JsonRpcErrorOwned::owned(-32008, SERVER_ERROR_MSG, Some(err_msg.to_string()))
+            }
+            Error::IdentityConflict(err_msg) => {
+                JsonRpcErrorOwned::owned(-32009, SERVER_ERROR_MSG, Some(err_msg.to_string()))
+            }
+            Error::PublishPolicy(err_msg) => {
+                JsonRpcErrorOwned::owned(-32010, SERVER_ERROR_MSG, Some(err_msg.to_string()))
+            }
+            Error::LocalRecord(err_msg) => {
+                JsonRpcErrorOwned::owned(-32012, SERVER_ERROR_MSG, Some(err_msg.to_string()))
+            }
+            Error::Other(err_msg) => {
+                JsonRpcErrorOwned::owned(-32011, SERVER_ERROR_MSG, Some(err_msg.to_string()))
+            }
             _ => todo!(),
         }
     }
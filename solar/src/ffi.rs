@@ -0,0 +1,431 @@
+//! C foreign function interface.
+//!
+//! Allows solar to be embedded as the SSB engine of applications written in
+//! other languages (for example, Kotlin or Swift mobile apps via JNI or a C
+//! bridging header). Generate the header with cbindgen:
+//!
+//! `cbindgen --config cbindgen.toml --crate solar --output solar.h`
+//!
+//! Only one node can run per process at a time. Once stopped, a node can be
+//! started again with the same data directory and configuration; starting
+//! a node with another keypair or configuration requires a new process.
+//!
+//! Strings are passed as null-terminated UTF-8. Strings returned by solar
+//! must be released with `solar_string_free`. Functions returning an integer
+//! return `0` on success and `-1` on failure; functions returning a string
+//! return a null pointer on failure. The error of the last failed call on
+//! the calling thread is returned by `solar_last_error`. A panic is caught
+//! at the boundary and reported as a failure, since unwinding into the
+//! caller is undefined behaviour.
+
+use std::{
+    cell::RefCell,
+    ffi::{CStr, CString},
+    os::raw::{c_char, c_int, c_void},
+    panic::{self, AssertUnwindSafe},
+    path::PathBuf,
+    ptr,
+    sync::Mutex,
+};
+
+use async_std::task;
+use futures::{select_biased, FutureExt, StreamExt};
+use jsonrpsee::{core::params::ObjectParams, server::RpcModule};
+use log::warn;
+use once_cell::sync::Lazy;
+use serde_json::{json, Value};
+
+use crate::{
+    actors::jsonrpc,
+    broker::{ActorEndpoint, Broker, BrokerMessage, Void, BROKER},
+    error::Error,
+    node::KV_STORE,
+    storage::kv::StoreKvEvent,
+    ApplicationConfig, Node, NodeHandle, Result,
+};
+
+/// Callback invoked with the JSON-encoded KVT of each newly stored message,
+/// along with the user data given when subscribing.
+pub type SolarMessageCallback = extern "C" fn(msg_kvt: *const c_char, user_data: *mut c_void);
+
+/// A node started with `solar_start`.
+struct RunningNode {
+    /// Handle of the node.
+    handle: NodeHandle,
+    /// JSON-RPC methods of the node, called in-process by `solar_query`.
+    rpc_module: RpcModule<()>,
+}
+
+/// The running node, if any.
+static NODE: Lazy<Mutex<Option<RunningNode>>> = Lazy::new(|| Mutex::new(None));
+
+thread_local! {
+    /// Error of the last failed call on this thread.
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+/// Store the error of a failed call.
+fn set_last_error(err: &Error) {
+    let msg = CString::new(err.to_string().replace('\0', ""))
+        .expect("Error message contains no null bytes");
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = Some(msg));
+}
+
+/// Run the given call, converting a panic into an error.
+fn catch_panic<T>(call: impl FnOnce() -> Result<T>) -> Result<T> {
+    panic::catch_unwind(AssertUnwindSafe(call)).unwrap_or_else(|panic| {
+        Err(Error::Other(format!(
+            "panic: {}",
+            Broker::panic_reason(panic)
+        )))
+    })
+}
+
+/// Convert the result of a call into a return code.
+fn to_code(result: Result<()>) -> c_int {
+    match result {
+        Ok(()) => 0,
+        Err(err) => {
+            set_last_error(&err);
+            -1
+        }
+    }
+}
+
+/// Convert the result of a call into a string owned by the caller.
+fn to_c_string(result: Result<String>) -> *mut c_char {
+    match result
+        .and_then(|string| CString::new(string).map_err(|err| Error::Other(err.to_string())))
+    {
+        Ok(string) => string.into_raw(),
+        Err(err) => {
+            set_last_error(&err);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Read a string passed by the caller, returning `None` for a null pointer.
+///
+/// # Safety
+///
+/// The pointer must be null or point to a null-terminated string.
+unsafe fn from_c_str(string: *const c_char) -> Result<Option<String>> {
+    if string.is_null() {
+        return Ok(None);
+    }
+
+    CStr::from_ptr(string)
+        .to_str()
+        .map(|string| Some(string.to_owned()))
+        .map_err(|err| Error::Other(err.to_string()))
+}
+
+/// Start a node storing its data in the given directory (or the default
+/// data directory if null). The node runs in the background and does
+/// not serve JSON-RPC over HTTP; use `solar_query` instead.
+///
+/// # Safety
+///
+/// `data_dir` must be null or point to a null-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn solar_start(data_dir: *const c_char) -> c_int {
+    to_code(catch_panic(|| from_c_str(data_dir).and_then(start)))
+}
+
+/// Start a node running in the background.
+fn start(data_dir: Option<String>) -> Result<()> {
+    let mut node = NODE.lock().map_err(|err| Error::Other(err.to_string()))?;
+    if node.is_some() {
+        return Err(Error::Other("node has already been started".to_string()));
+    }
+
+    let mut config = ApplicationConfig::new(data_dir.map(PathBuf::from))?;
+    config.jsonrpc.server = false;
+
+    let rpc_module = jsonrpc::server::rpc_module(config.secret.to_owned_identity()?)?;
+    let handle = task::block_on(Node::start(config))?;
+
+    *node = Some(RunningNode { handle, rpc_module });
+
+    Ok(())
+}
+
+/// Stop the running node, blocking until it has shut down.
+#[no_mangle]
+pub extern "C" fn solar_stop() -> c_int {
+    to_code(catch_panic(stop))
+}
+
+/// Stop the running node and wait for it to terminate.
+fn stop() -> Result<()> {
+    let node = NODE
+        .lock()
+        .map_err(|err| Error::Other(err.to_string()))?
+        .take()
+        .ok_or_else(|| Error::Other("node is not running".to_string()))?;
+
+    task::block_on(async {
+        node.handle.shutdown().await;
+        node.handle.done().await
+    });
+
+    Ok(())
+}
+
+/// Return the JSON-RPC methods of the running node.
+fn rpc_module() -> Result<RpcModule<()>> {
+    NODE.lock()
+        .map_err(|err| Error::Other(err.to_string()))?
+        .as_ref()
+        .map(|node| node.rpc_module.clone())
+        .ok_or_else(|| Error::Other("node is not running".to_string()))
+}
+
+/// Call the given JSON-RPC method of the running node in-process.
+fn query(method: &str, params: Option<Value>) -> Result<String> {
+    let rpc_module = rpc_module()?;
+
+    let mut object_params = ObjectParams::new();
+    match params {
+        Some(Value::Object(params)) => {
+            for (name, value) in params {
+                object_params.insert(&name, value)?;
+            }
+        }
+        Some(_) => return Err(Error::Other("params must be a JSON object".to_string())),
+        None => (),
+    }
+
+    let response: Value = task::block_on(rpc_module.call(method, object_params))?;
+
+    Ok(response.to_string())
+}
+
+/// Call the given JSON-RPC method of the running node with the given
+/// JSON-encoded parameters object (or no parameters if null), returning the
+/// JSON-encoded result. Every method of the JSON-RPC API is available.
+///
+/// # Safety
+///
+/// `method` must point to a null-terminated string; `params` must be null or
+/// point to a null-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn solar_query(method: *const c_char, params: *const c_char) -> *mut c_char {
+    let result = catch_panic(|| {
+        let method = from_c_str(method)?.ok_or(Error::OptionIsNone)?;
+        let params = match from_c_str(params)? {
+            Some(params) => Some(serde_json::from_str(&params)?),
+            None => None,
+        };
+
+        query(&method, params)
+    });
+
+    to_c_string(result)
+}
+
+/// Publish a message with the given JSON-encoded content on the local feed,
+/// returning the JSON-encoded key and sequence number of the message.
+///
+/// # Safety
+///
+/// `content` must point to a null-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn solar_publish(content: *const c_char) -> *mut c_char {
+    let result = catch_panic(|| {
+        let content = from_c_str(content)?.ok_or(Error::OptionIsNone)?;
+        let content: Value = serde_json::from_str(&content)?;
+
+        query("publish", Some(json!({ "msg": content })))
+    });
+
+    to_c_string(result)
+}
+
+/// Callback and user data of a subscription.
+struct Subscriber {
+    callback: SolarMessageCallback,
+    user_data: *mut c_void,
+}
+
+// The caller is responsible for the user data being usable from the thread
+// on which the callback is invoked (see `solar_subscribe`).
+unsafe impl Send for Subscriber {}
+unsafe impl Sync for Subscriber {}
+
+impl Subscriber {
+    /// Invoke the callback with the stored message of the given feed and
+    /// sequence number.
+    async fn notify(&self, ssb_id: &str, seq: u64) -> Result<()> {
+        let msg_kvt = KV_STORE.read().await.get_msg_kvt(ssb_id, seq)?;
+        if let Some(msg_kvt) = msg_kvt {
+            let msg_kvt =
+                CString::new(msg_kvt.to_string()).map_err(|err| Error::Other(err.to_string()))?;
+            (self.callback)(msg_kvt.as_ptr(), self.user_data);
+        }
+
+        Ok(())
+    }
+}
+
+/// Invoke the callback of the subscriber for each newly stored message until
+/// the node is stopped.
+async fn subscription_actor(endpoint: ActorEndpoint, subscriber: Subscriber) -> Result<()> {
+    let ActorEndpoint {
+        ch_terminate,
+        ch_msg,
+        ch_terminated,
+        ..
+    } = endpoint;
+    let mut ch_msg = ch_msg.ok_or(Error::OptionIsNone)?;
+    let mut ch_terminate = ch_terminate.fuse();
+
+    loop {
+        select_biased! {
+            _ = ch_terminate => break,
+            msg = ch_msg.next().fuse() => {
+                if let Some(BrokerMessage::StoreKv(StoreKvEvent((ssb_id, seq)))) = msg {
+                    if let Err(err) = subscriber.notify(&ssb_id, seq).await {
+                        warn!("Failed to notify subscriber of message {} of {}: {}", seq, ssb_id, err);
+                    }
+                }
+            }
+        }
+    }
+
+    let _ = ch_terminated.send(Void {});
+
+    Ok(())
+}
+
+/// Invoke the given callback with the JSON-encoded KVT of each message stored
+/// by the running node (whether published locally or replicated), until the
+/// node is stopped. The callback is invoked on a background thread and the
+/// message is only valid for the duration of the call.
+///
+/// # Safety
+///
+/// `user_data` is passed to the callback as given; it must remain valid
+/// until the node is stopped and be usable from any thread.
+#[no_mangle]
+pub unsafe extern "C" fn solar_subscribe(
+    callback: SolarMessageCallback,
+    user_data: *mut c_void,
+) -> c_int {
+    to_code(catch_panic(|| {
+        subscribe(Subscriber {
+            callback,
+            user_data,
+        })
+    }))
+}
+
+/// Spawn an actor notifying the given subscriber of stored messages.
+fn subscribe(subscriber: Subscriber) -> Result<()> {
+    // Fail if no node is running.
+    rpc_module()?;
+
+    // Register with the broker before returning, so that no message stored
+    // after subscribing is missed.
+    let endpoint =
+        task::block_on(async { BROKER.lock().await.register("ffi-subscription", true).await })?;
+    Broker::spawn("ffi-subscription", subscription_actor(endpoint, subscriber));
+
+    Ok(())
+}
+
+/// Return the error of the last failed call on the calling thread, or null
+/// if no call has failed.
+#[no_mangle]
+pub extern "C" fn solar_last_error() -> *mut c_char {
+    panic::catch_unwind(|| {
+        LAST_ERROR.with(|last_error| {
+            last_error
+                .borrow()
+                .as_ref()
+                .map(|err| err.clone().into_raw())
+                .unwrap_or(ptr::null_mut())
+        })
+    })
+    .unwrap_or(ptr::null_mut())
+}
+
+/// Release a string returned by solar.
+///
+/// # Safety
+///
+/// `string` must be null or a string returned by solar which has not
+/// already been released.
+#[no_mangle]
+pub unsafe extern "C" fn solar_string_free(string: *mut c_char) {
+    let _ = panic::catch_unwind(|| {
+        if !string.is_null() {
+            drop(CString::from_raw(string));
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Return the error of the last failed call on this thread.
+    fn last_error() -> Option<String> {
+        let err = solar_last_error();
+        if err.is_null() {
+            return None;
+        }
+
+        let string = unsafe { CStr::from_ptr(err) }.to_str().unwrap().to_owned();
+        unsafe { solar_string_free(err) };
+
+        Some(string)
+    }
+
+    #[test]
+    fn test_catch_panic() {
+        let result: Result<()> = catch_panic(|| panic!("handler failed"));
+
+        assert!(result.unwrap_err().to_string().contains("handler failed"));
+    }
+
+    #[test]
+    fn test_calls_fail_without_node() {
+        let method = CString::new("whoami").unwrap();
+        let result = unsafe { solar_query(method.as_ptr(), ptr::null()) };
+        assert!(result.is_null());
+        assert!(last_error().unwrap().contains("node is not running"));
+
+        assert_eq!(solar_stop(), -1);
+        assert!(last_error().unwrap().contains("node is not running"));
+
+        extern "C" fn callback(_msg_kvt: *const c_char, _user_data: *mut c_void) {}
+        assert_eq!(unsafe { solar_subscribe(callback, ptr::null_mut()) }, -1);
+    }
+
+    #[test]
+    fn test_invalid_arguments() {
+        // A method is required.
+        assert!(unsafe { solar_query(ptr::null(), ptr::null()) }.is_null());
+        assert!(last_error().is_some());
+
+        // Parameters must be valid JSON.
+        let method = CString::new("whoami").unwrap();
+        let params = CString::new("{").unwrap();
+        assert!(unsafe { solar_query(method.as_ptr(), params.as_ptr()) }.is_null());
+        assert!(last_error().is_some());
+
+        // Content is required.
+        assert!(unsafe { solar_publish(ptr::null()) }.is_null());
+        assert!(last_error().is_some());
+
+        // Strings which are not UTF-8 are rejected.
+        let content = CString::new(vec![0xff, 0xfe]).unwrap();
+        assert!(unsafe { solar_publish(content.as_ptr()) }.is_null());
+        assert!(last_error().is_some());
+
+        // Releasing a null string is a no-op.
+        unsafe { solar_string_free(ptr::null_mut()) };
+    }
+}
@@ -3,18 +3,42 @@
 mod actors;
 mod broker;
 mod config;
+#[cfg(any(test, feature = "conformance"))]
+pub mod conformance;
 mod error;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+mod lock;
+pub mod logger;
+#[cfg(feature = "netsim")]
+pub mod netsim;
 mod node;
+mod private_box;
 // TODO: `pub` can be removed once blob-related functions are used.
 mod secret_config;
+pub mod ssb_uri;
 pub mod storage;
+mod subscription;
+mod util;
 
 /// Convenience Result that returns `solar::Error`.
 pub type Result<T> = std::result::Result<T, error::Error>;
 
+pub use actors::follow_back::FollowBackPolicy;
 pub use actors::jsonrpc::config::JsonRpcConfig;
-pub use actors::network::config::NetworkConfig;
+pub use actors::network::config::{NetworkConfig, TransportConfig};
+pub use actors::network::connection_state::{ConnectionState, ConnectionTransition};
+pub use actors::replication::blob_events::BlobEvent;
 pub use actors::replication::config::ReplicationConfig;
+pub use actors::replication::ebt::EbtEvent;
+pub use actors::replication::ebt::{clock as ebt_clock, EncodedClockValue, VectorClock};
+pub use actors::retention::config::RetentionConfig;
+pub use actors::retention::pressure::{PressureLevel, StoragePressure};
+pub use actors::social::SocialEvent;
 pub use config::ApplicationConfig;
 pub use error::Error;
-pub use node::Node;
+pub use logger::{LogConfig, LogFormat};
+pub use node::{Node, NodeHandle};
+pub use storage::publish::{PublishPolicy, RateLimit};
+pub use storage::{blob::StoreBlobEvent, kv::StoreKvEvent};
+pub use subscription::{NodeEvent, Subscription};
@@ -0,0 +1,133 @@
+//! Data directory lock.
+//!
+//! Only one solar process may use a data directory at a time: a second
+//! process would corrupt the sled database and fail to bind the same ports.
+//! The process using the directory holds an exclusive advisory lock (`flock`
+//! on Unix, `LockFileEx` on Windows) on a lock file (`solar.lock`) for as
+//! long as it runs. The operating system releases the lock when the process
+//! exits, including after a crash, so a lock is never left stale.
+//!
+//! The PID of the process holding the lock is written to the lock file, but
+//! only to name the process in the error reported to a second process; the
+//! lock itself does not depend on it.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    process,
+};
+
+use fs2::FileExt;
+use log::{debug, warn};
+
+use crate::{error::Error, Result};
+
+/// Name of the lock file (within the data directory).
+pub const LOCK_FILE: &str = "solar.lock";
+
+/// Lock held on a data directory. The lock is released when dropped.
+#[derive(Debug)]
+pub struct DataDirLock {
+    path: PathBuf,
+    /// The locked file, which must stay open for the lock to be held.
+    file: File,
+}
+
+impl DataDirLock {
+    /// Acquire the lock on the given data directory, failing if the
+    /// directory is in use by another running process.
+    pub fn acquire(base_path: &Path) -> Result<Self> {
+        let path = base_path.join(LOCK_FILE);
+
+        // The lock file is left in place when the lock is released, since
+        // removing it would let two processes lock different files.
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&path)?;
+        if let Err(err) = file.try_lock_exclusive() {
+            if err.raw_os_error() == fs2::lock_contended_error().raw_os_error() {
+                let pid = read_pid(&path);
+                return Err(Error::DataDirectoryLocked((path, pid)));
+            }
+            return Err(err.into());
+        }
+
+        // Record the PID of this process, for diagnostics only.
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        write!(file, "{}", process::id())?;
+        file.sync_all()?;
+        debug!("Acquired data directory lock {:?}", path);
+
+        Ok(Self { path, file })
+    }
+}
+
+impl Drop for DataDirLock {
+    fn drop(&mut self) {
+        // Clear the PID of this process before releasing the lock, so that
+        // it is not reported once the process is gone.
+        if let Err(err) = self.file.set_len(0) {
+            warn!(
+                "Failed to clear data directory lock {:?}: {}",
+                self.path, err
+            );
+        }
+        if let Err(err) = self.file.unlock() {
+            warn!(
+                "Failed to release data directory lock {:?}: {}",
+                self.path, err
+            );
+        }
+    }
+}
+
+/// Read the PID stored in the given lock file. Returns `None` if the file
+/// cannot be read or is malformed (for example, if the process holding the
+/// lock has not written its PID yet).
+fn read_pid(path: &Path) -> Option<u32> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_data_dir_lock() -> Result<()> {
+        let dir = tempdir::TempDir::new("solarlock").unwrap();
+
+        let lock = DataDirLock::acquire(dir.path())?;
+        assert_eq!(read_pid(&dir.path().join(LOCK_FILE)), Some(process::id()));
+
+        // The lock is held until dropped, even by the same process.
+        match DataDirLock::acquire(dir.path()) {
+            Err(Error::DataDirectoryLocked((_path, pid))) => assert_eq!(pid, Some(process::id())),
+            other => panic!("expected the data directory to be locked: {:?}", other),
+        }
+
+        // The lock is released when dropped.
+        drop(lock);
+        assert_eq!(read_pid(&dir.path().join(LOCK_FILE)), None);
+        let _lock = DataDirLock::acquire(dir.path())?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unlocked_lock_file() -> Result<()> {
+        let dir = tempdir::TempDir::new("solarlock").unwrap();
+
+        // A lock file which is not locked, whatever PID it holds (such as
+        // that of a running process, which may have reused the PID of a
+        // crashed node), does not prevent the lock from being acquired.
+        fs::write(dir.path().join(LOCK_FILE), "1")?;
+        let _lock = DataDirLock::acquire(dir.path())?;
+        assert_eq!(read_pid(&dir.path().join(LOCK_FILE)), Some(process::id()));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,560 @@
+//! Logger with plain text or JSON output and log-file rotation.
+//!
+//! Log records are written either to stderr or to a log file. Log files are
+//! rotated once they exceed a maximum size or a maximum age, whichever comes
+//! first; rotated files are renamed with a numeric suffix (`solar.log.1`,
+//! `solar.log.2` etc.) and the oldest are deleted.
+//!
+//! The log filter can be changed while the node is running, either for
+//! individual targets (see `set_level`) or by replacing the filter directives
+//! entirely (see `set_filter`).
+//!
+//! The most recent log lines are also kept in memory (see `recent_lines`),
+//! so that they can be shown by the dashboard without reading the log file.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Display,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use log::{LevelFilter, Log, Metadata, Record};
+use once_cell::sync::OnceCell;
+use serde_json::json;
+
+use crate::{error::Error, Result};
+
+/// The logger for the solar node.
+static LOGGER: OnceCell<Logger> = OnceCell::new();
+
+/// Number of log lines kept in memory.
+pub const RECENT_LINES_CAPACITY: usize = 500;
+
+/// Format of log records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human-readable plain text (one record per line).
+    Text,
+    /// JSON (one object per line), suitable for log aggregation systems.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = Error;
+
+    fn from_str(format: &str) -> Result<Self> {
+        match format {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(Error::Config(format!(
+                "Log format must be 'text' or 'json': {}",
+                format
+            ))),
+        }
+    }
+}
+
+impl Display for LogFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self {
+            LogFormat::Text => write!(f, "text"),
+            LogFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LogConfig {
+    /// Log filter directives in the form `level` or `target=level`,
+    /// separated by commas (default: error).
+    pub filter: String,
+
+    /// Format of log records (default: text).
+    pub format: LogFormat,
+
+    /// Write logs to the given file instead of stderr (default: none).
+    pub file: Option<PathBuf>,
+
+    /// Rotate the log file once it exceeds the given size in bytes
+    /// (default: none).
+    pub max_file_size: Option<u64>,
+
+    /// Rotate the log file once it is older than the given duration
+    /// (default: none).
+    pub rotation_interval: Option<Duration>,
+
+    /// Number of rotated log files to keep (default: 5).
+    pub max_files: usize,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            filter: "error".to_string(),
+            format: LogFormat::Text,
+            file: None,
+            max_file_size: None,
+            rotation_interval: None,
+            max_files: 5,
+        }
+    }
+}
+
+/// Log level filter for the default level and individual targets.
+#[derive(Debug, Clone, PartialEq)]
+struct Filter {
+    /// Level applied to targets without a specific directive.
+    default: LevelFilter,
+    /// Levels for specific targets (and target prefixes).
+    targets: HashMap<String, LevelFilter>,
+}
+
+impl Filter {
+    /// Parse filter directives in the form `level` or `target=level`,
+    /// separated by commas (the same format as `RUST_LOG`).
+    fn parse(directives: &str) -> Result<Self> {
+        let mut filter = Filter {
+            default: LevelFilter::Error,
+            targets: HashMap::new(),
+        };
+
+        for directive in directives.split(',').map(str::trim) {
+            if directive.is_empty() {
+                continue;
+            }
+
+            match directive.split_once('=') {
+                Some((target, level)) => {
+                    filter
+                        .targets
+                        .insert(target.to_string(), parse_level(level)?);
+                }
+                None => filter.default = parse_level(directive)?,
+            }
+        }
+
+        Ok(filter)
+    }
+
+    /// Return the level for the given target. The directive with the longest
+    /// matching target prefix takes precedence.
+    fn level(&self, target: &str) -> LevelFilter {
+        self.targets
+            .iter()
+            .filter(|(prefix, _)| target.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, level)| *level)
+            .unwrap_or(self.default)
+    }
+
+    /// Set the level for the given target, or the default level if no target
+    /// is given.
+    fn set(&mut self, target: Option<&str>, level: LevelFilter) {
+        match target {
+            Some(target) => {
+                self.targets.insert(target.to_string(), level);
+            }
+            None => self.default = level,
+        }
+    }
+}
+
+impl Display for Filter {
+    /// Format the filter as directives which can be parsed by
+    /// `Filter::parse`, with target directives in alphabetical order.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut targets: Vec<(&String, &LevelFilter)> = self.targets.iter().collect();
+        targets.sort();
+
+        write!(f, "{}", self.default.to_string().to_lowercase())?;
+        for (target, level) in targets {
+            write!(f, ",{}={}", target, level.to_string().to_lowercase())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Parse a log level (`off`, `error`, `warn`, `info`, `debug` or `trace`).
+pub fn parse_level(level: &str) -> Result<LevelFilter> {
+    LevelFilter::from_str(level.trim())
+        .map_err(|_| Error::Config(format!("Invalid log level: {}", level)))
+}
+
+/// Log file which is rotated by size and / or age.
+pub(crate) struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    /// Size of the current log file in bytes.
+    size: u64,
+    /// Time at which the current log file was opened.
+    opened: SystemTime,
+    max_size: Option<u64>,
+    interval: Option<Duration>,
+    max_files: usize,
+}
+
+impl RotatingFile {
+    pub(crate) fn open(
+        path: &Path,
+        max_size: Option<u64>,
+        interval: Option<Duration>,
+        max_files: usize,
+    ) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            file,
+            size,
+            opened: SystemTime::now(),
+            max_size,
+            interval,
+            max_files,
+        })
+    }
+
+    /// Return the path of the rotated log file with the given index.
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        path.into()
+    }
+
+    /// Query whether the log file must be rotated before writing a record of
+    /// the given length.
+    fn must_rotate(&self, len: u64) -> bool {
+        let too_large = match self.max_size {
+            // Never rotate an empty file, even if the record is too large.
+            Some(max_size) => self.size > 0 && self.size + len > max_size,
+            None => false,
+        };
+        let too_old = match self.interval {
+            Some(interval) => self.opened.elapsed().unwrap_or_default() >= interval,
+            None => false,
+        };
+
+        too_large || too_old
+    }
+
+    /// Shift all rotated files up by one index, deleting the oldest, and
+    /// start a new log file.
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(self.rotated_path(self.max_files));
+            for index in (1..self.max_files).rev() {
+                let from = self.rotated_path(index);
+                if from.exists() {
+                    fs::rename(from, self.rotated_path(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        self.opened = SystemTime::now();
+
+        Ok(())
+    }
+
+    pub(crate) fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.must_rotate(len) {
+            self.rotate()?;
+        }
+
+        writeln!(self.file, "{}", line)?;
+        self.size += len;
+
+        Ok(())
+    }
+}
+
+/// The most recent log lines, in the order in which they were logged.
+struct RecentLines {
+    lines: VecDeque<String>,
+    capacity: usize,
+}
+
+impl RecentLines {
+    fn new(capacity: usize) -> Self {
+        RecentLines {
+            lines: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Add a line, discarding the oldest line if the capacity is reached.
+    fn push(&mut self, line: String) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.lines.len() == self.capacity {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(line);
+    }
+
+    /// Return the given number of most recent lines, from oldest to newest.
+    fn last(&self, limit: usize) -> Vec<String> {
+        let skip = self.lines.len().saturating_sub(limit);
+        self.lines.iter().skip(skip).cloned().collect()
+    }
+}
+
+/// Destination of log records.
+enum Output {
+    Stderr,
+    File(RotatingFile),
+}
+
+/// Logger implementation.
+struct Logger {
+    filter: RwLock<Filter>,
+    format: LogFormat,
+    output: Mutex<Output>,
+    recent: Mutex<RecentLines>,
+}
+
+impl Logger {
+    /// Format a log record according to the configured format.
+    fn format(&self, record: &Record) -> String {
+        let timestamp = humantime::format_rfc3339_millis(SystemTime::now());
+
+        match self.format {
+            LogFormat::Text => format!(
+                "[{} {:<5} {}] {}",
+                timestamp,
+                record.level(),
+                record.target(),
+                record.args()
+            ),
+            LogFormat::Json => json!({
+                "timestamp": timestamp.to_string(),
+                "level": record.level().to_string(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            })
+            .to_string(),
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        match self.filter.read() {
+            Ok(filter) => metadata.level() <= filter.level(metadata.target()),
+            Err(_) => false,
+        }
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let line = self.format(record);
+
+        if let Ok(mut output) = self.output.lock() {
+            match &mut *output {
+                Output::Stderr => eprintln!("{}", line),
+                Output::File(file) => {
+                    if let Err(err) = file.write_line(&line) {
+                        eprintln!("Failed to write to log file: {}", err)
+                    }
+                }
+            }
+        }
+
+        if let Ok(mut recent) = self.recent.lock() {
+            recent.push(line);
+        }
+    }
+
+    fn flush(&self) {
+        if let Ok(mut output) = self.output.lock() {
+            if let Output::File(file) = &mut *output {
+                let _ = file.file.flush();
+            }
+        }
+    }
+}
+
+/// Initialise the logger using the given configuration.
+///
+/// May only be called once.
+pub fn init(config: &LogConfig) -> Result<()> {
+    let output = match &config.file {
+        Some(path) => Output::File(RotatingFile::open(
+            path,
+            config.max_file_size,
+            config.rotation_interval,
+            config.max_files,
+        )?),
+        None => Output::Stderr,
+    };
+
+    let logger = Logger {
+        filter: RwLock::new(Filter::parse(&config.filter)?),
+        format: config.format,
+        output: Mutex::new(output),
+        recent: Mutex::new(RecentLines::new(RECENT_LINES_CAPACITY)),
+    };
+
+    LOGGER
+        .set(logger)
+        .map_err(|_| Error::Config("Logger already initialised".to_string()))?;
+
+    log::set_logger(LOGGER.get().ok_or(Error::OptionIsNone)?)
+        .map_err(|err| Error::Config(err.to_string()))?;
+    // Filtering is performed by the logger, allowing levels to be changed
+    // at runtime.
+    log::set_max_level(LevelFilter::max());
+
+    Ok(())
+}
+
+/// Return the logger, or an error if it has not been initialised.
+fn logger() -> Result<&'static Logger> {
+    LOGGER
+        .get()
+        .ok_or_else(|| Error::Config("Logger has not been initialised".to_string()))
+}
+
+/// Set the log level for the given target (or the default level if no
+/// target is given) without restarting the node.
+///
+/// Returns the resulting filter directives.
+pub fn set_level(target: Option<&str>, level: &str) -> Result<String> {
+    let level = parse_level(level)?;
+    let mut filter = logger()?
+        .filter
+        .write()
+        .map_err(|_| Error::Config("Log filter lock is poisoned".to_string()))?;
+
+    filter.set(target.filter(|target| !target.is_empty()), level);
+
+    Ok(filter.to_string())
+}
+
+/// Replace the log filter with the given directives, discarding any levels
+/// previously set at runtime.
+pub fn set_filter(directives: &str) -> Result<()> {
+    let parsed = Filter::parse(directives)?;
+    let mut filter = logger()?
+        .filter
+        .write()
+        .map_err(|_| Error::Config("Log filter lock is poisoned".to_string()))?;
+
+    *filter = parsed;
+
+    Ok(())
+}
+
+/// Return the current log filter directives.
+pub fn filter() -> Result<String> {
+    let filter = logger()?
+        .filter
+        .read()
+        .map_err(|_| Error::Config("Log filter lock is poisoned".to_string()))?;
+
+    Ok(filter.to_string())
+}
+
+/// Return the given number of most recent log lines (at most
+/// `RECENT_LINES_CAPACITY`), from oldest to newest.
+pub fn recent_lines(limit: usize) -> Result<Vec<String>> {
+    let recent = logger()?
+        .recent
+        .lock()
+        .map_err(|_| Error::Config("Recent log lines lock is poisoned".to_string()))?;
+
+    Ok(recent.last(limit))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_filter() -> Result<()> {
+        let filter = Filter::parse("info,ebt-handler=trace,solar::actors=warn")?;
+
+        assert_eq!(filter.level("connection-manager"), LevelFilter::Info);
+        assert_eq!(filter.level("ebt-handler"), LevelFilter::Trace);
+        assert_eq!(filter.level("solar::actors::network"), LevelFilter::Warn);
+
+        assert!(Filter::parse("ebt-handler=loud").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_filter_level() -> Result<()> {
+        let mut filter = Filter::parse("info")?;
+
+        filter.set(Some("ebt-handler"), LevelFilter::Trace);
+        assert_eq!(filter.level("ebt-handler"), LevelFilter::Trace);
+        assert_eq!(filter.level("connection-manager"), LevelFilter::Info);
+
+        filter.set(None, LevelFilter::Warn);
+        assert_eq!(filter.level("connection-manager"), LevelFilter::Warn);
+
+        // The formatted filter can be parsed back into an identical filter.
+        assert_eq!(filter.to_string(), "warn,ebt-handler=trace");
+        assert_eq!(Filter::parse(&filter.to_string())?, filter);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_recent_lines() {
+        let mut recent = RecentLines::new(3);
+        for line in ["first", "second", "third", "fourth"] {
+            recent.push(line.to_string());
+        }
+
+        // The oldest line was discarded once the capacity was reached.
+        assert_eq!(recent.last(10), vec!["second", "third", "fourth"]);
+        assert_eq!(recent.last(2), vec!["third", "fourth"]);
+        assert!(recent.last(0).is_empty());
+    }
+
+    #[test]
+    fn test_rotation_by_size() -> Result<()> {
+        let dir = tempdir::TempDir::new("solarlog")?;
+        let path = dir.path().join("solar.log");
+
+        let mut file = RotatingFile::open(&path, Some(16), None, 2)?;
+        for line in ["first line", "second line", "third line", "fourth line"] {
+            file.write_line(line)?;
+        }
+
+        // Each line exceeds half of the maximum size, so every line is
+        // written to a new file and only the two latest rotations are kept.
+        assert_eq!(fs::read_to_string(&path)?, "fourth line\n");
+        assert_eq!(fs::read_to_string(file.rotated_path(1))?, "third line\n");
+        assert_eq!(fs::read_to_string(file.rotated_path(2))?, "second line\n");
+        assert!(!file.rotated_path(3).exists());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,269 @@
+//! Simulation driver interface.
+//!
+//! Network simulation suites (such as ssb-netsim) drive each node under test
+//! through a small set of operations: identify the node, load fixture feeds,
+//! connect to a peer and wait for replication to complete. When the driver is
+//! enabled the node reads one command per line from stdin and writes one
+//! response per line to stdout. Successful responses start with `ok` and
+//! failures with `error`. Once the database has been opened, the driver
+//! writes `ready` and starts reading commands. Other lines written to stdout
+//! by the node should be ignored.
+//!
+//! | Command | Response |
+//! | --- | --- |
+//! | `whoami` | `ok <@...=.ed25519>` |
+//! | `load <path>` | `ok <loaded> <skipped>` |
+//! | `connect <host:port> <@...=.ed25519>` | `ok` |
+//! | `wait <@...=.ed25519> <seq> [<timeout secs>]` | `ok <seq>` or `error timeout <latest seq>` |
+//! | `quit` | `ok` |
+//!
+//! Fixtures are JSON files containing messages (values or KVTs), either as
+//! a single array or one message per line, ordered as they were appended
+//! (ie. each feed in sequence order), as exported from ssb-fixtures.
+//! Messages which are already stored or out of sequence are skipped.
+//!
+//! The set of feeds to be replicated is read from `replication.toml` when the
+//! node starts; it must list the feeds of the simulated peers.
+
+use std::{path::Path, time::Duration};
+
+use async_std::{
+    fs,
+    io::{self, prelude::BufReadExt, BufReader},
+    task,
+};
+use futures::{SinkExt, StreamExt};
+use kuska_ssb::{
+    crypto::ToSodiumObject,
+    feed::{Feed as MessageKvt, Message},
+};
+use serde_json::Value;
+
+use crate::{
+    actors::network::connection_scheduler::DialRequest,
+    broker::{BrokerEvent, BrokerMessage, Destination, BROKER},
+    config::SECRET_CONFIG,
+    error::Error,
+    node::KV_STORE,
+    ssb_uri, Result,
+};
+
+/// Default number of seconds to wait for a feed to be replicated.
+const DEFAULT_WAIT_TIMEOUT: u64 = 60;
+
+/// Interval at which the local database is checked while waiting for it to
+/// be opened or for a feed to be replicated.
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Parse the messages contained in a fixture file. Both a single JSON array
+/// and newline-delimited JSON are accepted.
+pub fn parse_fixtures(contents: &str) -> Result<Vec<Message>> {
+    let values: Vec<Value> = match serde_json::from_str(contents) {
+        Ok(Value::Array(values)) => values,
+        _ => contents
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(serde_json::from_str)
+            .collect::<std::result::Result<_, _>>()?,
+    };
+
+    values
+        .iter()
+        .map(|value| {
+            let bytes = serde_json::to_vec(value)?;
+            match Message::from_slice(&bytes) {
+                Ok(msg) => Ok(msg),
+                Err(_) => Ok(MessageKvt::from_slice(&bytes)?.into_message()?),
+            }
+        })
+        .collect()
+}
+
+/// Append the messages of the given fixture file to the local database,
+/// returning the number of messages loaded and skipped.
+async fn load(path: &Path) -> Result<(usize, usize)> {
+    let contents = fs::read_to_string(path).await?;
+    let msgs = parse_fixtures(&contents)?;
+
+    let db = KV_STORE.write().await;
+    let (mut loaded, mut skipped) = (0, 0);
+    for msg in msgs {
+        let author = msg.author().to_string();
+        let last_seq = db.get_latest_seq(&author)?.unwrap_or(0);
+        if msg.sequence() == last_seq + 1 {
+            db.append_feed(msg).await?;
+            loaded += 1;
+        } else {
+            skipped += 1;
+        }
+    }
+
+    Ok((loaded, skipped))
+}
+
+/// Dial the peer with the given address and public key.
+async fn connect(addr: &str, public_key: &str) -> Result<()> {
+    let public_key = ssb_uri::to_sigil(public_key)?
+        .trim_start_matches('@')
+        .to_ed25519_pk()?;
+
+    BROKER
+        .lock()
+        .await
+        .create_sender()
+        .send(BrokerEvent::new(
+            Destination::Broadcast,
+            BrokerMessage::Dial(DialRequest((public_key, addr.to_owned()))),
+        ))
+        .await?;
+
+    Ok(())
+}
+
+/// Wait until the feed authored by the given public key has reached the
+/// given sequence number, returning the latest stored sequence number and
+/// whether it was reached before the timeout.
+async fn wait(public_key: &str, seq: u64, timeout: Duration) -> Result<(u64, bool)> {
+    let public_key = ssb_uri::to_sigil(public_key)?;
+    let mut waited = Duration::ZERO;
+
+    loop {
+        let latest_seq = KV_STORE
+            .read()
+            .await
+            .get_latest_seq(&public_key)?
+            .unwrap_or(0);
+        if latest_seq >= seq {
+            return Ok((latest_seq, true));
+        }
+        if waited >= timeout {
+            return Ok((latest_seq, false));
+        }
+
+        task::sleep(WAIT_POLL_INTERVAL).await;
+        waited += WAIT_POLL_INTERVAL;
+    }
+}
+
+/// Execute a single driver command, returning the response line and whether
+/// the driver should exit.
+async fn execute(line: &str) -> Result<(String, bool)> {
+    let args: Vec<&str> = line.split_whitespace().collect();
+
+    let response = match args.as_slice() {
+        ["whoami"] => {
+            let secret = SECRET_CONFIG.get().ok_or(Error::OptionIsNone)?;
+            format!("ok {}", secret.public_key)
+        }
+        ["load", path] => {
+            let (loaded, skipped) = load(Path::new(path)).await?;
+            format!("ok {loaded} {skipped}")
+        }
+        ["connect", addr, public_key] => {
+            connect(addr, public_key).await?;
+            "ok".to_string()
+        }
+        ["wait", public_key, seq, rest @ ..] if rest.len() <= 1 => {
+            let seq = seq
+                .parse()
+                .map_err(|_| Error::Other(format!("invalid sequence number: {seq}")))?;
+            let timeout = match rest.first() {
+                Some(secs) => secs
+                    .parse()
+                    .map_err(|_| Error::Other(format!("invalid timeout: {secs}")))?,
+                None => DEFAULT_WAIT_TIMEOUT,
+            };
+
+            match wait(public_key, seq, Duration::from_secs(timeout)).await? {
+                (latest_seq, true) => format!("ok {latest_seq}"),
+                (latest_seq, false) => format!("error timeout {latest_seq}"),
+            }
+        }
+        ["quit"] => {
+            let _ = BROKER
+                .lock()
+                .await
+                .create_sender()
+                .send(BrokerEvent::Terminate)
+                .await;
+
+            return Ok(("ok".to_string(), true));
+        }
+        _ => format!("error unknown command: {line}"),
+    };
+
+    Ok((response, false))
+}
+
+/// Read driver commands from stdin until `quit` is received or stdin is
+/// closed, writing the response to each command to stdout.
+pub async fn driver() -> Result<()> {
+    while !KV_STORE.read().await.is_open() {
+        task::sleep(WAIT_POLL_INTERVAL).await;
+    }
+    println!("ready");
+
+    let mut lines = BufReader::new(io::stdin()).lines();
+
+    while let Some(line) = lines.next().await {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let (response, exit) = match execute(line.trim()).await {
+            Ok(result) => result,
+            Err(err) => (format!("error {err}"), false),
+        };
+        println!("{response}");
+
+        if exit {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use kuska_ssb::keystore::OwnedIdentity;
+    use serde_json::json;
+    use sled::Config as DbConfig;
+
+    use crate::storage::kv::KvStorage;
+
+    #[async_std::test]
+    async fn test_parse_fixtures() -> Result<()> {
+        let dir = tempdir::TempDir::new("solardb").unwrap();
+        let mut kv = KvStorage::default();
+        let (sender, _) = futures::channel::mpsc::unbounded();
+        kv.open(DbConfig::new().path(dir.path()), sender)?;
+
+        let keypair = OwnedIdentity::create();
+        let msg_1 = Message::sign(None, &keypair, json!({ "type": "post", "text": "one" }))?;
+        let msg_2 = Message::sign(
+            Some(&msg_1),
+            &keypair,
+            json!({ "type": "post", "text": "two" }),
+        )?;
+        kv.append_feed(msg_1.clone()).await?;
+        kv.append_feed(msg_2.clone()).await?;
+
+        // Fixtures may contain message values or KVTs.
+        let kvt_1 = kv.get_msg_kvt(&keypair.id, 1)?.unwrap();
+        let kvt_2 = kv.get_msg_kvt(&keypair.id, 2)?.unwrap();
+
+        let array = json!([kvt_1.value, kvt_2.value]).to_string();
+        assert_eq!(parse_fixtures(&array)?, vec![msg_1.clone(), msg_2.clone()]);
+
+        let lines = [kvt_1.to_string(), String::new(), kvt_2.to_string()].join("\n");
+        assert_eq!(parse_fixtures(&lines)?, vec![msg_1, msg_2]);
+
+        assert!(parse_fixtures("not json").is_err());
+
+        Ok(())
+    }
+}
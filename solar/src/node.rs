@@ -1,25 +1,44 @@
-use std::net::SocketAddr;
+use std::{net::SocketAddr, time::Duration};
 
-use async_std::sync::{Arc, RwLock};
+use async_std::{
+    sync::{Arc, RwLock},
+    task::{self, JoinHandle},
+};
 use futures::SinkExt;
 use kuska_ssb::crypto::{ed25519::PublicKey, ToSodiumObject};
+use log::{error, warn};
 use once_cell::sync::Lazy;
 
 use crate::{
     actors::{
-        jsonrpc,
+        email::{self, EmailConfig},
+        follow_back, jsonrpc, log_config,
         network::{
-            connection_manager::CONNECTION_MANAGER, connection_scheduler, dialer, lan_discovery,
+            connection_manager::{ConnectionManager, CONNECTION_MANAGER},
+            connection_scheduler, dialer, lan_discovery, local_rpc, reputation, room_invite, stats,
             tcp_server,
         },
-        replication::ebt::EbtManager,
+        outbox,
+        replication::{ebt::EbtManager, ingest, want_list},
+        retention::{pressure, prune},
+        social,
+        webhooks::{self, WebhooksConfig},
     },
     broker::*,
     config::ApplicationConfig,
-    storage::{blob::BlobStorage, kv::KvStorage},
+    error::Error,
+    lock::DataDirLock,
+    storage::{blob::BlobStorage, kv::KvStorage, repair},
+    subscription::{self, NodeEvent, Subscription},
     Result,
 };
 
+/// Maximum number of times a long-running actor is restarted after a panic.
+const ACTOR_MAX_RESTARTS: usize = 3;
+
+/// Interval at which the log configuration file is checked for changes.
+const LOG_CONFIG_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
 // Instantiate the key-value store.
 pub static KV_STORE: Lazy<Arc<RwLock<KvStorage>>> =
     Lazy::new(|| Arc::new(RwLock::new(KvStorage::default())));
@@ -27,12 +46,118 @@ pub static KV_STORE: Lazy<Arc<RwLock<KvStorage>>> =
 pub static BLOB_STORE: Lazy<Arc<RwLock<BlobStorage>>> =
     Lazy::new(|| Arc::new(RwLock::new(BlobStorage::default())));
 
+/// Handle of a running node, returned by `Node::start`.
+///
+/// Dropping the handle does not stop the node.
+pub struct NodeHandle {
+    /// Sender of the broker of the node.
+    ch_broker: ChBrokerSend,
+    /// Task completing once all actors have terminated.
+    completion: JoinHandle<()>,
+}
+
+impl NodeHandle {
+    /// Send a termination signal to all actors. Returns immediately; await
+    /// `done` for the node to finish shutting down.
+    pub async fn shutdown(&self) {
+        // The signal is sent to the broker of this node, even if the node
+        // has already stopped and another one has been started since.
+        let _ = self.ch_broker.clone().send(BrokerEvent::Terminate).await;
+    }
+
+    /// Subscribe to the events of the given type (eg.
+    /// `handle.subscribe::<StoreKvEvent>()` for the messages appended to the
+    /// database). The subscription ends once the node has stopped.
+    pub async fn subscribe<T: NodeEvent>(&self) -> Result<Subscription<T>> {
+        subscription::subscribe().await
+    }
+
+    /// Wait until the node has fully terminated: all actors have stopped, the
+    /// database has been flushed and closed and the data directory has been
+    /// released. Another node can then be started in the same process, as
+    /// long as its configuration does not differ (see `Node::start`).
+    pub async fn done(self) {
+        self.completion.await
+    }
+}
+
 /// Main runtime managing the solar node process.
 pub struct Node;
 
 impl Node {
-    /// Start the solar node with full storage and networking capabilities.
-    pub async fn start(config: ApplicationConfig) -> Result<()> {
+    /// Start the solar node with full storage and networking capabilities,
+    /// returning a handle to the running node once all actors have been
+    /// spawned. Fails if another node is running in the process, or if the
+    /// configuration differs from that of a node started earlier in the
+    /// process (since the keypair, network key, replication settings and
+    /// publish policy are kept in process-wide stores which cannot be reset).
+    pub async fn start(mut config: ApplicationConfig) -> Result<NodeHandle> {
+        config.check_globals()?;
+
+        // Take the message loops of the broker and the connection manager,
+        // which complete once the node has terminated. They have already
+        // been taken if a node is running.
+        let (broker_msgloop, ch_broker) = {
+            let mut broker = BROKER.lock().await;
+            let msgloop = broker
+                .take_msgloop()
+                .ok_or_else(|| Error::Other("a node is already running".to_string()))?;
+            (msgloop, broker.create_sender())
+        };
+        let connection_manager_msgloop = CONNECTION_MANAGER
+            .write()
+            .await
+            .take_msgloop()
+            .ok_or_else(|| Error::Other("a node is already running".to_string()))?;
+
+        // The data directory remains locked until the node has terminated,
+        // even if the handle is dropped.
+        let data_dir_lock = config.data_dir_lock.take();
+
+        // Wait for the connection manager and broker message loops to finish.
+        let completion = async move {
+            connection_manager_msgloop.await;
+            broker_msgloop.await;
+
+            Self::release(data_dir_lock).await;
+        };
+
+        if let Err(err) = Self::spawn_actors(config).await {
+            // Stop the actors spawned so far.
+            let _ = ch_broker.clone().send(BrokerEvent::Terminate).await;
+            completion.await;
+
+            return Err(err);
+        }
+
+        let completion = task::spawn(async move {
+            completion.await;
+
+            println!("Gracefully finished");
+        });
+
+        Ok(NodeHandle {
+            ch_broker,
+            completion,
+        })
+    }
+
+    /// Open the stores and spawn the actors of the node.
+    async fn spawn_actors(config: ApplicationConfig) -> Result<()> {
+        let ephemeral = config.ephemeral;
+
+        // Replace a corrupted key-value database with its readable contents
+        // if a repair has been requested.
+        if config.database_repair {
+            let feeds_path = config
+                .base_path
+                .as_ref()
+                .expect("Base path not supplied")
+                .join("feeds");
+            let report = repair::repair_database(&feeds_path).await?;
+            println!("{}", report);
+        }
+
         // Open the key-value store using the given configuration parameters and
         // an unbounded sender channel for message passing.
         KV_STORE
@@ -40,22 +165,45 @@ impl Node {
             .await
             .open(config.database, BROKER.lock().await.create_sender())?;
 
-        // Define the directory name for the blob store.
-        let blobs_path = config
-            .base_path
-            .as_ref()
-            .expect("Base path not supplied")
-            .join("blobs");
+        if ephemeral {
+            // Keep blobs in memory.
+            BLOB_STORE
+                .write()
+                .await
+                .open_in_memory(BROKER.lock().await.create_sender());
+        } else {
+            // Define the directory name for the blob store.
+            let blobs_path = config
+                .base_path
+                .as_ref()
+                .expect("Base path not supplied")
+                .join("blobs");
 
-        // Open the blobstore using the given folder path and an unbounded sender
-        // channel for message passing.
-        BLOB_STORE
-            .write()
-            .await
-            .open(blobs_path, BROKER.lock().await.create_sender());
+            // Open the blobstore using the given folder path and an unbounded
+            // sender channel for message passing. Removes any blobs left
+            // partially written by an interrupted transfer.
+            BLOB_STORE
+                .write()
+                .await
+                .open(blobs_path, BROKER.lock().await.create_sender())?;
+        }
+
+        // Spawn the ctrlc actor if enabled. Listens for SIGINT termination
+        // signal.
+        if config.handle_ctrlc {
+            Broker::spawn("ctrlc", crate::actors::ctrlc::actor());
+        }
 
-        // Spawn the ctrlc actor. Listens for SIGINT termination signal.
-        Broker::spawn(crate::actors::ctrlc::actor());
+        // Spawn the log configuration watcher. Applies changes to the log
+        // filter in the `log.toml` file without restarting the node.
+        // An ephemeral node has no data directory to watch.
+        if let Some(base_path) = config.base_path.as_ref() {
+            let log_config_path = base_path.join(log_config::LOG_CONFIG_FILE);
+            Broker::spawn(
+                "log-config-watcher",
+                log_config::actor(log_config_path, LOG_CONFIG_CHECK_INTERVAL),
+            );
+        }
 
         // Print 'starting server' announcement.
         println!(
@@ -70,11 +218,28 @@ impl Node {
             format!("{}:{}", config.network.ip, config.network.port).parse()?;
 
         // Spawn the TCP server. Facilitates peer connections.
-        Broker::spawn(tcp_server::actor(
-            owned_identity.to_owned(),
-            tcp_server_addr,
-            config.replication.selective,
-        ));
+        let server_identity = owned_identity.to_owned();
+        let selective_replication = config.replication.selective;
+        let push_batch_bytes = config.replication.push_batch_bytes;
+        let incremental_clocks = config.replication.incremental_clocks;
+        let prune_hops = config.retention.prune_hops;
+        Broker::spawn_supervised("tcp-server", ACTOR_MAX_RESTARTS, move || {
+            tcp_server::actor(
+                server_identity.to_owned(),
+                tcp_server_addr,
+                selective_replication,
+            )
+        });
+
+        // Spawn the local MUXRPC server if a port has been configured.
+        // Serves SSB client applications authenticating as the local identity.
+        if let Some(port) = config.network.local_rpc_port {
+            let local_rpc_addr = SocketAddr::from(([127, 0, 0, 1], port));
+            let server_identity = owned_identity.to_owned();
+            Broker::spawn_supervised("local-rpc-server", ACTOR_MAX_RESTARTS, move || {
+                local_rpc::actor(server_identity.to_owned(), local_rpc_addr)
+            });
+        }
 
         // Print the network key.
         println!(
@@ -89,20 +254,75 @@ impl Node {
         // Spawn the JSON-RPC server if the option has been set to true in the
         // CLI arguments. Facilitates operator queries during runtime.
         if config.jsonrpc.server {
-            Broker::spawn(jsonrpc::server::actor(
-                owned_identity.to_owned(),
-                jsonrpc_server_addr,
-            ));
+            // Enable the audit log of JSON-RPC calls if a path has been given.
+            // Recorded callers are only meaningful if their API tokens are
+            // checked, so the audit log requires the list of accepted tokens.
+            if let Some(path) = &config.jsonrpc.audit_log {
+                let tokens = config.jsonrpc.api_tokens.as_ref().ok_or_else(|| {
+                    Error::Config("The audit log requires a file of API tokens".to_string())
+                })?;
+                jsonrpc::audit::init(
+                    path,
+                    config.jsonrpc.audit_log_max_size,
+                    config.jsonrpc.audit_log_max_files,
+                    tokens,
+                )?;
+            }
+
+            // Enable the filter profiles of JSON-RPC clients if a path has
+            // been given.
+            if let Some(path) = &config.jsonrpc.filters {
+                jsonrpc::filter::init(path)?;
+            }
+
+            let server_identity = owned_identity.to_owned();
+            Broker::spawn_supervised("jsonrpc-listener", ACTOR_MAX_RESTARTS, move || {
+                jsonrpc::server::actor(server_identity.to_owned(), jsonrpc_server_addr)
+            });
+        }
+
+        // Spawn the web dashboard if the option has been set to true in the
+        // CLI arguments. The dashboard queries the node through the JSON-RPC
+        // server.
+        if config.jsonrpc.dashboard {
+            if config.jsonrpc.server {
+                let dashboard_addr =
+                    SocketAddr::new(config.jsonrpc.ip, config.jsonrpc.dashboard_port);
+                Broker::spawn_supervised("dashboard", ACTOR_MAX_RESTARTS, move || {
+                    jsonrpc::dashboard::actor(dashboard_addr, jsonrpc_server_addr)
+                });
+            } else {
+                warn!("The dashboard requires the JSON-RPC server, which is disabled");
+            }
+        }
+
+        // Spawn the public web viewer if the option has been set to true in
+        // the CLI arguments. Unlike the dashboard, the viewer reads the
+        // database directly.
+        if config.jsonrpc.viewer {
+            let viewer_addr = SocketAddr::new(config.jsonrpc.viewer_ip, config.jsonrpc.viewer_port);
+            Broker::spawn_supervised("viewer", ACTOR_MAX_RESTARTS, move || {
+                jsonrpc::viewer::actor(viewer_addr)
+            });
         }
 
         // Spawn the LAN discovery actor. Listens for and broadcasts UDP packets
         // to allow LAN-local peer connections.
         if config.network.lan_discovery {
-            Broker::spawn(lan_discovery::actor(
-                owned_identity.to_owned(),
-                config.network.port,
-                config.replication.selective,
-            ));
+            let discovery_identity = owned_identity.to_owned();
+            let port = config.network.port;
+            let announce_addr = config.network.lan_announce_addr.to_owned();
+            let interval = config.network.lan_interval;
+            let listen_only = config.network.lan_listen_only;
+            Broker::spawn_supervised("lan-discovery", ACTOR_MAX_RESTARTS, move || {
+                lan_discovery::actor(
+                    discovery_identity.to_owned(),
+                    port,
+                    announce_addr.to_owned(),
+                    interval,
+                    listen_only,
+                )
+            });
         }
 
         // Convert the HashMap of peers to be replicated into a Vec.
@@ -125,46 +345,200 @@ impl Node {
         // Add any connection details supplied via the `--connect` CLI option.
         peers_to_dial.extend(config.network.connect);
 
+        // Consume any room invites supplied via the `--join-invite` CLI option
+        // and add the addresses of the rooms. A failed invite does not
+        // prevent the node from starting.
+        for invite in &config.network.join_invites {
+            match room_invite::consume_invite(invite, &owned_identity.id).await {
+                Ok(room) => peers_to_dial.push(room),
+                Err(err) => warn!("Failed to join room with invite {}: {}", invite, err),
+            }
+        }
+
         // Spawn the connection dialer actor. Dials remote peers as dial
         // requests are received from the connection scheduler.
-        Broker::spawn(dialer::actor(
-            owned_identity.to_owned(),
-            config.replication.selective,
-        ));
+        let dialer_identity = owned_identity.to_owned();
+        let max_concurrent_dials = config.network.max_concurrent_dials;
+        Broker::spawn_supervised("dialer", ACTOR_MAX_RESTARTS, move || {
+            dialer::actor(
+                dialer_identity.to_owned(),
+                selective_replication,
+                max_concurrent_dials,
+            )
+        });
+
+        // Load the scores of the peers, by which the connection scheduler
+        // orders the peers to be dialed.
+        if let Err(err) = reputation::load().await {
+            warn!("Failed to load peer reputations: {}", err)
+        }
 
         // Spawn the connection scheduler actor. Sends dial requests to the
         // dialer for remote peers on an ongoing basis (at `eager` or `lazy`
         // intervals).
-        Broker::spawn(connection_scheduler::actor(peers_to_dial));
+        Broker::spawn_supervised("connection-scheduler", ACTOR_MAX_RESTARTS, move || {
+            connection_scheduler::actor(peers_to_dial.to_owned())
+        });
+
+        // Spawn the feed pruning job if a hops threshold has been configured.
+        // Periodically deletes feeds of authors beyond the threshold.
+        if let Some(max_hops) = config.retention.prune_hops {
+            let local_id = owned_identity.id.to_owned();
+            let interval = config.retention.prune_interval;
+            Broker::spawn_supervised("feed-pruning", ACTOR_MAX_RESTARTS, move || {
+                prune::actor(local_id.to_owned(), max_hops, interval)
+            });
+        }
 
-        // Define the directory name for the ebt clock store.
-        let ebt_path = config
-            .base_path
-            .expect("Base path not supplied")
-            .join("ebt");
+        // Spawn the storage pressure job. Periodically checks the free disk
+        // space and database size, pausing blob fetching and the replication
+        // of distant feeds while storage is critically low.
+        let base_path = config.base_path.to_owned();
+        let local_id = owned_identity.id.to_owned();
+        let retention = config.retention.to_owned();
+        Broker::spawn_supervised("storage-pressure", ACTOR_MAX_RESTARTS, move || {
+            pressure::actor(
+                base_path.to_owned(),
+                local_id.to_owned(),
+                retention.to_owned(),
+                pressure::STORAGE_CHECK_INTERVAL,
+            )
+        });
 
-        // Spawn the EBT replication manager actor.
-        let ebt_replication_manager = EbtManager::default();
-        Broker::spawn(EbtManager::event_loop(
-            ebt_replication_manager,
-            owned_identity.id,
-            ebt_path,
-        ));
+        // Spawn the ingest job if an ingest rate limit has been configured.
+        // Periodically releases the queued messages which the limit allows.
+        if config.replication.ingest.max_messages_per_hour.is_some() {
+            Broker::spawn_supervised("ingest-queue", ACTOR_MAX_RESTARTS, || {
+                ingest::actor(ingest::INGEST_CHECK_INTERVAL)
+            });
+        }
+
+        // Spawn the blob want-list job. Periodically re-requests wanted blobs
+        // which have not yet been retrieved.
+        Broker::spawn_supervised("blob-want-list", ACTOR_MAX_RESTARTS, || {
+            want_list::actor(want_list::WANT_LIST_CHECK_INTERVAL)
+        });
+
+        // Spawn the network statistics job. Periodically records the activity
+        // of the node in the daily network statistics.
+        Broker::spawn_supervised("network-stats", ACTOR_MAX_RESTARTS, || {
+            stats::actor(stats::STATS_FLUSH_INTERVAL)
+        });
 
-        // Spawn the connection manager message loop.
-        let connection_manager_msgloop = CONNECTION_MANAGER.write().await.take_msgloop();
-        connection_manager_msgloop.await;
+        // Spawn the peer reputation job. Periodically records the activity of
+        // peers in their reputations.
+        Broker::spawn_supervised("peer-reputation", ACTOR_MAX_RESTARTS, || {
+            reputation::actor(reputation::REPUTATION_FLUSH_INTERVAL)
+        });
 
-        // Spawn the broker message loop.
-        let broker_msgloop = BROKER.lock().await.take_msgloop();
-        broker_msgloop.await;
+        // Spawn the outbox job. Periodically publishes the scheduled messages
+        // of the outbox which are due.
+        let outbox_identity = owned_identity.to_owned();
+        Broker::spawn_supervised("outbox", ACTOR_MAX_RESTARTS, move || {
+            outbox::actor(outbox_identity.to_owned(), outbox::OUTBOX_CHECK_INTERVAL)
+        });
 
-        println!("Gracefully finished");
+        // Spawn the social events job. Broadcasts the changes of the follow
+        // graph involving the local identity.
+        let social_id = owned_identity.id.to_owned();
+        Broker::spawn_supervised("social-events", ACTOR_MAX_RESTARTS, move || {
+            social::actor(social_id.to_owned())
+        });
+
+        // Spawn the follow-back job if a follow-back policy is configured.
+        // Follows back (or queues) new followers of the local identity.
+        if config.follow_back.is_enabled() {
+            let follow_back_identity = owned_identity.to_owned();
+            let follow_back_policy = config.follow_back.to_owned();
+            Broker::spawn_supervised("follow-back", ACTOR_MAX_RESTARTS, move || {
+                follow_back::actor(
+                    follow_back_identity.to_owned(),
+                    follow_back_policy.to_owned(),
+                )
+            });
+        }
+
+        // Spawn the webhook dispatcher if webhooks are configured in the
+        // `webhooks.toml` file. Posts selected node events to the configured
+        // URLs.
+        if let Some(base_path) = config.base_path.as_ref() {
+            let webhooks_path = base_path.join(webhooks::WEBHOOKS_CONFIG_FILE);
+            if let Some(webhooks_config) = WebhooksConfig::read(&webhooks_path)? {
+                let local_id = owned_identity.id.to_owned();
+                let base_path = base_path.to_owned();
+                Broker::spawn_supervised("webhooks", ACTOR_MAX_RESTARTS, move || {
+                    webhooks::actor(
+                        webhooks_config.to_owned(),
+                        local_id.to_owned(),
+                        base_path.to_owned(),
+                        webhooks::DISK_CHECK_INTERVAL,
+                    )
+                });
+            }
+
+            // Spawn the email notifier if notifications are configured in
+            // the `email.toml` file. Sends selected events to the operator
+            // through an SMTP server.
+            let email_path = base_path.join(email::EMAIL_CONFIG_FILE);
+            if let Some(email_config) = EmailConfig::read(&email_path)? {
+                let email_identity = owned_identity.to_owned();
+                Broker::spawn_supervised("email", ACTOR_MAX_RESTARTS, move || {
+                    email::actor(email_config.to_owned(), email_identity.to_owned())
+                });
+            }
+        }
+
+        // Define the directory name for the ebt clock store. An ephemeral
+        // node keeps the clocks in memory.
+        let ebt_path = config.base_path.map(|base_path| base_path.join("ebt"));
+
+        // Spawn the EBT replication manager actor.
+        //
+        // A restarted manager rebuilds its state from the database and the
+        // persisted peer clocks.
+        let local_id = owned_identity.id;
+        Broker::spawn_supervised("ebt-event-loop", ACTOR_MAX_RESTARTS, move || {
+            EbtManager::event_loop(
+                EbtManager::default()
+                    .push_batch_bytes(push_batch_bytes)
+                    .incremental_clocks(incremental_clocks)
+                    .prune_hops(prune_hops),
+                local_id.to_owned(),
+                ebt_path.to_owned(),
+            )
+        });
 
         Ok(())
     }
 
-    /// Shutdown the node by sending a termination signal to all actors.
+    /// Close the stores of a stopped node and release its data directory.
+    async fn release(data_dir_lock: Option<Arc<DataDirLock>>) {
+        // Flush and close the database before the data directory is
+        // released, so that a process opening it next finds every write.
+        // The temporary database of an ephemeral node is deleted once
+        // closed.
+        {
+            let mut kv = KV_STORE.write().await;
+            if let Err(err) = kv.flush().await {
+                error!("Failed to flush the database: {}", err);
+            }
+            kv.close();
+        }
+        *BLOB_STORE.write().await = BlobStorage::default();
+
+        drop(data_dir_lock);
+
+        // Replace the broker and the connection manager of the stopped node,
+        // so that another node can be started in the process. The message
+        // loop of the connection manager registers with the broker, which is
+        // replaced first.
+        *BROKER.lock().await = Broker::new();
+        *CONNECTION_MANAGER.write().await = ConnectionManager::new();
+    }
+
+    /// Shutdown the running node by sending a termination signal to all
+    /// actors. Prefer `NodeHandle::shutdown`, which cannot reach a node
+    /// started after the one it belongs to.
     pub async fn shutdown() {
         // Create a sender channel to pass messages to the broker message loop.
         let mut sender = BROKER.lock().await.create_sender();
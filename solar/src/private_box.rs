@@ -0,0 +1,134 @@
+//! Private box decryption.
+//!
+//! The content of a private message is a base64 string with the `.box`
+//! suffix, sealed with the private-box scheme for up to [`MAX_RECIPIENTS`]
+//! recipients: a nonce and a one-time curve25519 public key are followed by
+//! the key of the body sealed for each recipient (with the secret shared by
+//! the one-time key and the key of the recipient), and by the body sealed
+//! with that key. The curve25519 key of a recipient is derived from its
+//! ed25519 identity.
+
+use kuska_sodiumoxide::crypto::{
+    scalarmult::curve25519::{scalarmult, GroupElement, Scalar, GROUPELEMENTBYTES},
+    secretbox,
+    sign::ed25519,
+};
+use serde_json::Value;
+use sha2::{Digest, Sha512};
+
+/// Maximum number of recipients of a private message.
+pub const MAX_RECIPIENTS: usize = 7;
+
+/// Length of the key of the body sealed for a recipient: the number of
+/// recipients and the key, along with the authentication tag.
+const RECIPIENT_BYTES: usize = 1 + secretbox::KEYBYTES + secretbox::MACBYTES;
+
+/// Length of the header of a private box: the nonce and the one-time
+/// public key.
+const HEADER_BYTES: usize = secretbox::NONCEBYTES + GROUPELEMENTBYTES;
+
+/// Return the curve25519 secret key derived from the given ed25519 secret
+/// key.
+fn curve_secret_key(sk: &ed25519::SecretKey) -> Scalar {
+    // The first half of an ed25519 secret key is its seed.
+    let hash = Sha512::digest(&sk.0[..32]);
+    let mut key = [0; 32];
+    key.copy_from_slice(&hash[..32]);
+    key[0] &= 248;
+    key[31] &= 127;
+    key[31] |= 64;
+
+    Scalar(key)
+}
+
+/// Decrypt the given private message content (`<base64>.box`) with the
+/// given secret key. Returns `None` if the key is not one of the recipients
+/// or if the content is not a valid private box.
+pub fn open(boxed: &str, sk: &ed25519::SecretKey) -> Option<Value> {
+    let data = base64::decode(boxed.strip_suffix(".box")?).ok()?;
+    if data.len() < HEADER_BYTES + RECIPIENT_BYTES {
+        return None;
+    }
+
+    let nonce = secretbox::Nonce::from_slice(&data[..secretbox::NONCEBYTES])?;
+    let one_time_key = GroupElement::from_slice(&data[secretbox::NONCEBYTES..HEADER_BYTES])?;
+    let shared = scalarmult(&curve_secret_key(sk), &one_time_key).ok()?;
+    let shared = secretbox::Key::from_slice(&shared.0)?;
+
+    for sealed_key in data[HEADER_BYTES..]
+        .chunks_exact(RECIPIENT_BYTES)
+        .take(MAX_RECIPIENTS)
+    {
+        if let Ok(opened) = secretbox::open(sealed_key, &nonce, &shared) {
+            let recipients = opened[0] as usize;
+            let key = secretbox::Key::from_slice(&opened[1..])?;
+            let body = data.get(HEADER_BYTES + recipients * RECIPIENT_BYTES..)?;
+            let plaintext = secretbox::open(body, &nonce, &key).ok()?;
+
+            return serde_json::from_slice(&plaintext).ok();
+        }
+    }
+
+    None
+}
+
+/// Seal the given content for the given recipients (at most
+/// [`MAX_RECIPIENTS`]).
+#[cfg(test)]
+pub fn seal(content: &Value, recipients: &[&ed25519::SecretKey]) -> String {
+    use kuska_sodiumoxide::crypto::scalarmult::curve25519::scalarmult_base;
+
+    let nonce = secretbox::gen_nonce();
+    let one_time_key = Scalar(secretbox::gen_key().0);
+    let key = secretbox::gen_key();
+
+    let mut data = nonce.0.to_vec();
+    data.extend_from_slice(&scalarmult_base(&one_time_key).0);
+    for recipient in recipients {
+        let recipient_key = scalarmult_base(&curve_secret_key(recipient));
+        let shared = scalarmult(&one_time_key, &recipient_key).unwrap();
+        let mut sealed_key = vec![recipients.len() as u8];
+        sealed_key.extend_from_slice(&key.0);
+        data.extend(secretbox::seal(
+            &sealed_key,
+            &nonce,
+            &secretbox::Key(shared.0),
+        ));
+    }
+    data.extend(secretbox::seal(
+        content.to_string().as_bytes(),
+        &nonce,
+        &key,
+    ));
+
+    format!("{}.box", base64::encode(data))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    use crate::secret_config::SecretConfig;
+
+    #[test]
+    fn test_open() -> crate::Result<()> {
+        let alice = SecretConfig::create().to_owned_identity()?;
+        let bob = SecretConfig::create().to_owned_identity()?;
+        let carol = SecretConfig::create().to_owned_identity()?;
+
+        let content = json!({ "type": "post", "text": "Hi Bob", "recps": [alice.id, bob.id] });
+        let boxed = seal(&content, &[&alice.sk, &bob.sk]);
+
+        assert_eq!(open(&boxed, &alice.sk), Some(content.to_owned()));
+        assert_eq!(open(&boxed, &bob.sk), Some(content));
+        // Only the recipients can open the box.
+        assert_eq!(open(&boxed, &carol.sk), None);
+
+        assert_eq!(open("c2VjcmV0.box", &alice.sk), None);
+        assert_eq!(open("not base64!.box", &alice.sk), None);
+
+        Ok(())
+    }
+}
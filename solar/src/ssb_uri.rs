@@ -0,0 +1,28 @@
+//! SSB URIs.
+//!
+//! Newer clients refer to feeds, messages and blobs with SSB URIs
+//! (`ssb:feed/classic/<key>`, `ssb:message/classic/<hash>` and
+//! `ssb:blob/classic/<hash>`, where the key or hash is URL-safe base64)
+//! rather than sigil links (`@<key>.ed25519`, `%<hash>.sha256` and
+//! `&<hash>.sha256`). Feeds, messages and blobs are stored and indexed by
+//! sigil link; URIs are canonicalized to sigil links wherever a link is
+//! accepted. The older `ed25519` and `sha256` URI formats are also accepted.
+//!
+//! Links are handled by `solar_core::link`; the functions below return solar
+//! errors.
+
+use crate::Result;
+
+pub use solar_core::link::{deserialize_link, is_uri};
+
+/// Canonicalize the given feed, message or blob link to a sigil link.
+/// Sigil links are returned unchanged.
+pub fn to_sigil(link: &str) -> Result<String> {
+    Ok(solar_core::link::to_sigil(link)?)
+}
+
+/// Convert the given feed, message or blob link to an SSB URI. SSB URIs are
+/// returned in canonical form.
+pub fn to_uri(link: &str) -> Result<String> {
+    Ok(solar_core::link::to_uri(link)?)
+}
@@ -0,0 +1,308 @@
+//! Encrypted backup of the local identity and feed.
+//!
+//! A backup archive holds the keypair and every message of the local feed
+//! (as KVTs), sealed with a key derived from a passphrase (such as a
+//! mnemonic). Restoring an archive on a new machine writes the keypair to the
+//! data directory and appends the messages of the feed which are not yet
+//! stored, so that the node resumes publishing at the correct sequence
+//! number.
+//!
+//! The archive starts with a magic string and a version byte, followed by
+//! the salt used to derive the key (with Argon2id), the nonce and the
+//! ciphertext (XSalsa20-Poly1305) of the JSON-encoded backup.
+
+use std::{fmt, fs, path::Path};
+
+use kuska_sodiumoxide::crypto::{pwhash::argon2id13 as pwhash, secretbox};
+use kuska_ssb::feed::Feed as MessageKvt;
+use log::info;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sled::Config as DbConfig;
+
+use crate::{error::Error, secret_config::SecretConfig, storage::kv::KvStorage, Result};
+
+/// Magic string at the start of a backup archive.
+const MAGIC: &[u8] = b"SOLARBAK";
+
+/// Version of the archive format.
+const VERSION: u8 = 1;
+
+/// Contents of a backup archive.
+#[derive(Debug, Serialize, Deserialize)]
+struct Backup {
+    secret: SecretConfig,
+    /// Messages of the local feed as KVTs, in sequence order.
+    feed: Vec<Value>,
+}
+
+/// Summary of a backup or a restore, to be prefixed with the operation
+/// when displayed.
+#[derive(Debug, Default)]
+pub struct BackupReport {
+    /// Public key of the backed up identity.
+    pub id: String,
+    /// Number of messages backed up or restored.
+    pub messages: u64,
+    /// Sequence number of the latest message of the feed.
+    pub latest_seq: u64,
+}
+
+impl fmt::Display for BackupReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} messages of {} (latest sequence number: {})",
+            self.messages, self.id, self.latest_seq
+        )
+    }
+}
+
+/// Derive the archive key from the given passphrase and salt.
+fn derive_key(passphrase: &str, salt: &pwhash::Salt) -> Result<secretbox::Key> {
+    let mut key = [0; secretbox::KEYBYTES];
+    pwhash::derive_key(
+        &mut key,
+        passphrase.as_bytes(),
+        salt,
+        pwhash::OPSLIMIT_MODERATE,
+        pwhash::MEMLIMIT_MODERATE,
+    )
+    .map_err(|_| Error::Backup("failed to derive the archive key".to_string()))?;
+
+    Ok(secretbox::Key(key))
+}
+
+/// Seal the given plaintext with a key derived from the given passphrase.
+fn seal(plaintext: &[u8], passphrase: &str) -> Result<Vec<u8>> {
+    let salt = pwhash::gen_salt();
+    let nonce = secretbox::gen_nonce();
+    let key = derive_key(passphrase, &salt)?;
+
+    let mut archive = Vec::new();
+    archive.extend_from_slice(MAGIC);
+    archive.push(VERSION);
+    archive.extend_from_slice(&salt.0);
+    archive.extend_from_slice(&nonce.0);
+    archive.extend_from_slice(&secretbox::seal(plaintext, &nonce, &key));
+
+    Ok(archive)
+}
+
+/// Open the given archive with a key derived from the given passphrase.
+fn open(archive: &[u8], passphrase: &str) -> Result<Vec<u8>> {
+    let header_len = MAGIC.len() + 1;
+    let salt_end = header_len + pwhash::SALTBYTES;
+    let nonce_end = salt_end + secretbox::NONCEBYTES;
+
+    if archive.len() < nonce_end || !archive.starts_with(MAGIC) {
+        return Err(Error::Backup("not a backup archive".to_string()));
+    }
+    if archive[MAGIC.len()] != VERSION {
+        return Err(Error::Backup(format!(
+            "unsupported archive version {}",
+            archive[MAGIC.len()]
+        )));
+    }
+
+    // The lengths have been checked above, so it's safe to unwrap here.
+    let salt = pwhash::Salt::from_slice(&archive[header_len..salt_end]).unwrap();
+    let nonce = secretbox::Nonce::from_slice(&archive[salt_end..nonce_end]).unwrap();
+    let key = derive_key(passphrase, &salt)?;
+
+    secretbox::open(&archive[nonce_end..], &nonce, &key)
+        .map_err(|_| Error::Backup("wrong passphrase or corrupted archive".to_string()))
+}
+
+/// Write the keypair and the local feed stored in the solar data directory
+/// at `base_path` into an archive at `target`, sealed with the given
+/// passphrase. An existing file is never overwritten.
+pub async fn export_backup(
+    base_path: &Path,
+    target: &Path,
+    passphrase: &str,
+) -> Result<BackupReport> {
+    info!(
+        "Backing up the local feed of {:?} to {:?}",
+        base_path, target
+    );
+
+    if target.exists() {
+        return Err(Error::Backup(format!("{:?} already exists", target)));
+    }
+
+    let secret = SecretConfig::from_toml(&fs::read_to_string(base_path.join("secret.toml"))?)?;
+
+    let mut kv = KvStorage::default();
+    // The backup does not notify any actor of changes, so there is no need
+    // to keep the receiving end of the broker channel.
+    let (sender, _) = futures::channel::mpsc::unbounded();
+    kv.open(DbConfig::new().path(base_path.join("feeds")), sender)?;
+
+    let feed = kv
+        .get_feed(&secret.public_key)?
+        .iter()
+        .map(|msg_kvt| serde_json::from_str(&msg_kvt.to_string()))
+        .collect::<std::result::Result<Vec<Value>, _>>()?;
+
+    let report = BackupReport {
+        id: secret.public_key.to_owned(),
+        messages: feed.len() as u64,
+        latest_seq: kv.get_latest_seq(&secret.public_key)?.unwrap_or(0),
+    };
+
+    let plaintext = serde_json::to_vec(&Backup { secret, feed })?;
+    fs::write(target, seal(&plaintext, passphrase)?)?;
+
+    Ok(report)
+}
+
+/// Restore the keypair and the local feed held in the archive at `source`
+/// into the solar data directory at `base_path`, opening the archive with
+/// the given passphrase.
+///
+/// The keypair replaces that of the data directory, unless the data
+/// directory already holds messages published with another keypair. Messages
+/// of the feed which are already stored are skipped.
+pub async fn restore_backup(
+    base_path: &Path,
+    source: &Path,
+    passphrase: &str,
+) -> Result<BackupReport> {
+    info!("Restoring the backup at {:?} into {:?}", source, base_path);
+
+    let plaintext = open(&fs::read(source)?, passphrase)?;
+    let backup: Backup = serde_json::from_slice(&plaintext)?;
+    let id = backup.secret.to_owned_identity()?.id;
+
+    // Verify the messages (and their signatures) before anything is written.
+    let mut msgs = Vec::new();
+    for msg_kvt in &backup.feed {
+        let msg = MessageKvt::from_slice(msg_kvt.to_string().as_bytes())?.into_message()?;
+        if msg.author() != id.as_str() {
+            return Err(Error::Backup(format!(
+                "message {} of the archive was not authored by {}",
+                msg.sequence(),
+                id
+            )));
+        }
+        msgs.push(msg);
+    }
+
+    let mut kv = KvStorage::default();
+    // The restore does not notify any actor of changes, so there is no need
+    // to keep the receiving end of the broker channel.
+    let (sender, _) = futures::channel::mpsc::unbounded();
+    kv.open(DbConfig::new().path(base_path.join("feeds")), sender)?;
+
+    // Never replace a keypair which has been used to publish messages.
+    let secret_path = base_path.join("secret.toml");
+    if secret_path.is_file() {
+        let current = SecretConfig::from_toml(&fs::read_to_string(&secret_path)?)?;
+        if current.public_key != id && kv.get_latest_seq(&current.public_key)?.is_some() {
+            return Err(Error::Backup(format!(
+                "the data directory holds the feed of another identity ({})",
+                current.public_key
+            )));
+        }
+    }
+    fs::write(&secret_path, backup.secret.to_toml()?)?;
+
+    let mut report = BackupReport {
+        id: id.to_owned(),
+        ..BackupReport::default()
+    };
+
+    // Append the messages which are not yet stored. A gap between the
+    // stored feed and the archive is rejected when appending.
+    let latest_seq = kv.get_latest_seq(&id)?.unwrap_or(0);
+    for msg in msgs {
+        if msg.sequence() > latest_seq {
+            kv.append_feed(msg).await?;
+            report.messages += 1;
+        }
+    }
+    report.latest_seq = kv.get_latest_seq(&id)?.unwrap_or(0);
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use kuska_ssb::feed::Message as MessageValue;
+    use serde_json::json;
+
+    #[async_std::test]
+    async fn test_backup_and_restore() -> Result<()> {
+        let dir = tempdir::TempDir::new("solarbackup").unwrap();
+        let source_path = dir.path().join("source");
+        let target_path = dir.path().join("target");
+        let archive_path = dir.path().join("backup.solar");
+        fs::create_dir_all(&source_path)?;
+        fs::create_dir_all(&target_path)?;
+
+        let secret = SecretConfig::create();
+        let keypair = secret.to_owned_identity()?;
+        fs::write(source_path.join("secret.toml"), secret.to_toml()?)?;
+
+        {
+            let mut kv = KvStorage::default();
+            let (sender, _) = futures::channel::mpsc::unbounded();
+            kv.open(DbConfig::new().path(source_path.join("feeds")), sender)?;
+
+            let mut last_msg: Option<MessageValue> = None;
+            for i in 1..=3 {
+                let msg = MessageValue::sign(
+                    last_msg.as_ref(),
+                    &keypair,
+                    json!({ "type": "post", "text": format!("Post #{i}") }),
+                )?;
+                kv.append_feed(msg.clone()).await?;
+                last_msg = Some(msg);
+            }
+        }
+
+        let report = export_backup(&source_path, &archive_path, "correct horse").await?;
+        assert_eq!(report.messages, 3);
+        assert_eq!(report.latest_seq, 3);
+
+        // An existing archive is not overwritten.
+        assert!(export_backup(&source_path, &archive_path, "correct horse")
+            .await
+            .is_err());
+
+        assert!(restore_backup(&target_path, &archive_path, "wrong horse")
+            .await
+            .is_err());
+
+        // The restored feed resumes at the correct sequence number.
+        let report = restore_backup(&target_path, &archive_path, "correct horse").await?;
+        assert_eq!(report.id, keypair.id);
+        assert_eq!(report.messages, 3);
+        assert_eq!(report.latest_seq, 3);
+
+        let restored =
+            SecretConfig::from_toml(&fs::read_to_string(target_path.join("secret.toml"))?)?;
+        assert_eq!(restored.private_key, secret.private_key);
+
+        // Restoring again skips the stored messages.
+        let report = restore_backup(&target_path, &archive_path, "correct horse").await?;
+        assert_eq!(report.messages, 0);
+        assert_eq!(report.latest_seq, 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_invalid_archive() {
+        assert!(open(b"not an archive", "passphrase").is_err());
+
+        let mut archive = seal(b"plaintext", "passphrase").unwrap();
+        assert_eq!(open(&archive, "passphrase").unwrap(), b"plaintext");
+
+        archive[MAGIC.len()] = VERSION + 1;
+        assert!(open(&archive, "passphrase").is_err());
+    }
+}
@@ -1,22 +1,62 @@
+//! Blob storage.
+//!
+//! Each blob is stored in a file named after its hash. Blobs are first
+//! written to a temporary file in the `tmp` subdirectory and only moved into
+//! place (with an atomic rename) once the written content has been verified
+//! against the hash. An interrupted write therefore never leaves a partial
+//! blob in the store; orphaned temporary files are removed when the store is
+//! opened.
+//!
+//! Blobs which are downloaded in slices are assembled in the `partial`
+//! subdirectory, which is kept across restarts so that an interrupted
+//! download can resume where it left off. A partial blob is only moved into
+//! the store once complete and verified against its hash.
+//!
+//! An ephemeral store (see `open_in_memory`) keeps blobs in memory instead,
+//! leaving no files behind.
+
 use std::{
-    fs::File,
-    io::{Read, Result, Write},
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{Error, ErrorKind, Read, Result, Seek, SeekFrom, Write},
     path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, MutexGuard,
+    },
 };
 
 use futures::SinkExt;
+use log::warn;
 use sha2::{Digest, Sha256};
 
-use crate::broker::{BrokerEvent, BrokerMessage, ChBrokerSend, Destination};
+use crate::{
+    broker::{BrokerEvent, BrokerMessage, ChBrokerSend, Destination},
+    ssb_uri,
+};
 
 /// A blob has been added to the store.
 #[derive(Debug, Clone)]
 pub struct StoreBlobEvent(pub String);
 
+/// Name of the directory (within the blob store) holding blobs which are
+/// being written.
+const TMP_DIR: &str = "tmp";
+
+/// Name of the directory (within the blob store) holding partially
+/// downloaded blobs.
+const PARTIAL_DIR: &str = "partial";
+
 #[derive(Default)]
 pub struct BlobStorage {
     path: Option<PathBuf>,
     ch_broker: Option<ChBrokerSend>,
+    /// Counter used to generate unique temporary file names.
+    tmp_counter: AtomicU64,
+    /// Blobs of an ephemeral store, keyed by blob ID.
+    memory: Option<Mutex<HashMap<String, Vec<u8>>>>,
+    /// Partially downloaded blobs of an ephemeral store, keyed by blob ID.
+    memory_partials: Option<Mutex<HashMap<String, Vec<u8>>>>,
 }
 
 pub trait ToBlobHashId {
@@ -32,19 +72,69 @@ impl ToBlobHashId for &[u8] {
 }
 
 impl BlobStorage {
-    pub fn open(&mut self, path: PathBuf, ch_broker: ChBrokerSend) {
+    /// Open the blob store at the given path, removing any temporary files
+    /// left behind by interrupted writes.
+    pub fn open(&mut self, path: PathBuf, ch_broker: ChBrokerSend) -> Result<()> {
+        let tmp_path = path.join(TMP_DIR);
+        if tmp_path.exists() {
+            for entry in fs::read_dir(&tmp_path)? {
+                let orphan = entry?.path();
+                warn!("Removing orphaned temporary blob file {:?}", orphan);
+                fs::remove_file(orphan)?;
+            }
+        } else {
+            fs::create_dir_all(&tmp_path)?;
+        }
+        fs::create_dir_all(path.join(PARTIAL_DIR))?;
+
         self.path = Some(path);
         self.ch_broker = Some(ch_broker);
+
+        Ok(())
+    }
+
+    /// Open an ephemeral blob store, keeping blobs in memory.
+    pub fn open_in_memory(&mut self, ch_broker: ChBrokerSend) {
+        self.memory = Some(Mutex::new(HashMap::new()));
+        self.memory_partials = Some(Mutex::new(HashMap::new()));
+        self.ch_broker = Some(ch_broker);
+    }
+
+    /// Return the blobs of an ephemeral store.
+    fn memory(&self) -> Option<MutexGuard<HashMap<String, Vec<u8>>>> {
+        self.memory
+            .as_ref()
+            .map(|memory| memory.lock().unwrap_or_else(|err| err.into_inner()))
+    }
+
+    /// Return the partially downloaded blobs of an ephemeral store.
+    fn memory_partials(&self) -> Option<MutexGuard<HashMap<String, Vec<u8>>>> {
+        self.memory_partials
+            .as_ref()
+            .map(|partials| partials.lock().unwrap_or_else(|err| err.into_inner()))
+    }
+
+    /// Return the blob ID referred to by the given sigil link or SSB URI.
+    /// Invalid URIs are left as they are, and therefore never match a
+    /// stored blob.
+    fn id_of(id: &str) -> String {
+        ssb_uri::to_sigil(id).unwrap_or_else(|_| id.to_owned())
     }
 
     fn path_of(&self, id: &str) -> PathBuf {
-        let id = id.replace('&', "").replace('/', "_");
+        let id = Self::id_of(id).replace('&', "").replace('/', "_");
         [self.path.as_ref().unwrap(), Path::new(&id)]
             .iter()
             .collect()
     }
 
     pub fn size_of(&self, id: &str) -> Result<Option<u64>> {
+        if let Some(memory) = self.memory() {
+            return Ok(memory
+                .get(&Self::id_of(id))
+                .map(|content| content.len() as u64));
+        }
+
         if let Ok(metadata) = std::fs::metadata(self.path_of(id)) {
             Ok(Some(metadata.len()))
         } else {
@@ -52,9 +142,78 @@ impl BlobStorage {
         }
     }
 
+    /// Return the number of stored blobs and their total size in bytes.
+    /// Partially downloaded blobs are not included.
+    pub fn usage(&self) -> Result<(u64, u64)> {
+        if let Some(memory) = self.memory() {
+            let bytes = memory.values().map(|content| content.len() as u64).sum();
+            return Ok((memory.len() as u64, bytes));
+        }
+
+        let path = match self.path.as_ref() {
+            Some(path) => path,
+            None => return Ok((0, 0)),
+        };
+
+        let (mut count, mut bytes) = (0, 0);
+        for entry in fs::read_dir(path)? {
+            // Skip the `tmp` and `partial` subdirectories.
+            let metadata = entry?.metadata()?;
+            if metadata.is_file() {
+                count += 1;
+                bytes += metadata.len();
+            }
+        }
+
+        Ok((count, bytes))
+    }
+
+    /// Return a unique path for a temporary file holding the given blob.
+    fn tmp_path_of(&self, id: &str) -> PathBuf {
+        let counter = self.tmp_counter.fetch_add(1, Ordering::Relaxed);
+        let id = id.replace('&', "").replace('/', "_");
+        self.path
+            .as_ref()
+            .unwrap()
+            .join(TMP_DIR)
+            .join(format!("{}.{}", id, counter))
+    }
+
+    /// Write the given blob content to a temporary file, verify the written
+    /// content against the blob hash and move the file into place.
+    fn write_verified(&self, id: &str, content: &[u8]) -> Result<()> {
+        let tmp_path = self.tmp_path_of(id);
+
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(content)?;
+        file.sync_all()?;
+
+        let mut written = Vec::with_capacity(content.len());
+        File::open(&tmp_path)?.read_to_end(&mut written)?;
+        if written.as_slice().blob_hash_id() != id {
+            fs::remove_file(&tmp_path)?;
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Written content does not match blob hash {}", id),
+            ));
+        }
+
+        if let Err(err) = fs::rename(&tmp_path, self.path_of(id)) {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(err);
+        }
+
+        Ok(())
+    }
+
     pub async fn insert<D: AsRef<[u8]>>(&self, content: D) -> Result<String> {
         let id = content.as_ref().blob_hash_id();
-        File::create(self.path_of(&id))?.write_all(content.as_ref())?;
+        match self.memory() {
+            Some(mut memory) => {
+                memory.insert(id.clone(), content.as_ref().to_vec());
+            }
+            None => self.write_verified(&id, content.as_ref())?,
+        }
 
         let broker_msg = BrokerEvent::new(
             Destination::Broadcast,
@@ -72,6 +231,13 @@ impl BlobStorage {
     }
 
     pub fn get(&self, id: &str) -> Result<Vec<u8>> {
+        if let Some(memory) = self.memory() {
+            return memory
+                .get(&Self::id_of(id))
+                .cloned()
+                .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("Blob {} not found", id)));
+        }
+
         let mut file = File::open(self.path_of(id))?;
         let mut content = Vec::with_capacity(file.metadata()?.len() as usize);
         file.read_to_end(&mut content)?;
@@ -79,6 +245,212 @@ impl BlobStorage {
     }
 
     pub fn exists(&self, id: &str) -> bool {
+        if let Some(memory) = self.memory() {
+            return memory.contains_key(&Self::id_of(id));
+        }
+
         self.path_of(id).exists()
     }
+
+    fn partial_path_of(&self, id: &str) -> PathBuf {
+        let id = Self::id_of(id).replace('&', "").replace('/', "_");
+        self.path.as_ref().unwrap().join(PARTIAL_DIR).join(id)
+    }
+
+    /// Return the number of bytes written to the partial download of the
+    /// given blob.
+    pub fn partial_len(&self, id: &str) -> Result<u64> {
+        if let Some(partials) = self.memory_partials() {
+            return Ok(partials
+                .get(&Self::id_of(id))
+                .map(|content| content.len() as u64)
+                .unwrap_or_default());
+        }
+
+        match fs::metadata(self.partial_path_of(id)) {
+            Ok(metadata) => Ok(metadata.len()),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(0),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Write the given content to the partial download of the given blob at
+    /// the given offset, discarding anything written beyond that offset. The
+    /// content is synced to disk before returning.
+    pub fn write_partial(&self, id: &str, offset: u64, content: &[u8]) -> Result<()> {
+        if offset > self.partial_len(id)? {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Offset {} is beyond the partial blob {}", offset, id),
+            ));
+        }
+
+        if let Some(mut partials) = self.memory_partials() {
+            let partial = partials.entry(Self::id_of(id)).or_default();
+            partial.truncate(offset as usize);
+            partial.extend_from_slice(content);
+            return Ok(());
+        }
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .open(self.partial_path_of(id))?;
+        file.set_len(offset)?;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(content)?;
+        file.sync_data()?;
+
+        Ok(())
+    }
+
+    /// Move the completed partial download of the given blob into the store.
+    /// The partial download is discarded, even if its content does not match
+    /// the blob hash.
+    pub async fn complete_partial(&self, id: &str) -> Result<String> {
+        let content = match self.memory_partials() {
+            Some(mut partials) => partials.remove(&Self::id_of(id)).unwrap_or_default(),
+            None => {
+                let path = self.partial_path_of(id);
+                let content = fs::read(&path)?;
+                fs::remove_file(&path)?;
+                content
+            }
+        };
+
+        if content.as_slice().blob_hash_id() != Self::id_of(id) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Partial content does not match blob hash {}", id),
+            ));
+        }
+
+        self.insert(content).await
+    }
+
+    /// Discard the partial download of the given blob, if any.
+    pub fn remove_partial(&self, id: &str) -> Result<()> {
+        if let Some(mut partials) = self.memory_partials() {
+            partials.remove(&Self::id_of(id));
+            return Ok(());
+        }
+
+        match fs::remove_file(self.partial_path_of(id)) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use futures::channel::mpsc;
+
+    fn open_temporary_blobs(
+        path: &Path,
+    ) -> Result<(BlobStorage, mpsc::UnboundedReceiver<BrokerEvent>)> {
+        let mut blobs = BlobStorage::default();
+        let (sender, receiver) = mpsc::unbounded();
+        blobs.open(path.to_path_buf(), sender)?;
+
+        Ok((blobs, receiver))
+    }
+
+    #[async_std::test]
+    async fn test_insert_blob() -> Result<()> {
+        let dir = tempdir::TempDir::new("solarblobs")?;
+        let (blobs, _receiver) = open_temporary_blobs(dir.path())?;
+
+        let content = b"a very important blob";
+        let id = blobs.insert(content).await?;
+
+        assert_eq!(id, content.as_ref().blob_hash_id());
+        assert!(blobs.exists(&id));
+        assert_eq!(blobs.size_of(&id)?, Some(content.len() as u64));
+        assert_eq!(blobs.get(&id)?, content);
+
+        // No temporary files are left behind.
+        assert_eq!(fs::read_dir(dir.path().join(TMP_DIR))?.count(), 0);
+        assert_eq!(blobs.usage()?, (1, content.len() as u64));
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_insert_blob_in_memory() -> Result<()> {
+        let mut blobs = BlobStorage::default();
+        let (sender, _receiver) = mpsc::unbounded();
+        blobs.open_in_memory(sender);
+
+        let content = b"an ephemeral blob";
+        let id = blobs.insert(content).await?;
+
+        assert!(blobs.exists(&id));
+        assert_eq!(blobs.size_of(&id)?, Some(content.len() as u64));
+        assert_eq!(blobs.get(&id)?, content);
+        assert_eq!(blobs.usage()?, (1, content.len() as u64));
+
+        let missing = b"missing".as_ref().blob_hash_id();
+        assert!(!blobs.exists(&missing));
+        assert_eq!(blobs.get(&missing).unwrap_err().kind(), ErrorKind::NotFound);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_orphaned_tmp_files() -> Result<()> {
+        let dir = tempdir::TempDir::new("solarblobs")?;
+        let (blobs, _receiver) = open_temporary_blobs(dir.path())?;
+
+        // Simulate a write which was interrupted before the rename.
+        let id = b"interrupted".as_ref().blob_hash_id();
+        File::create(blobs.tmp_path_of(&id))?.write_all(b"interr")?;
+
+        let (blobs, _receiver) = open_temporary_blobs(dir.path())?;
+
+        assert_eq!(fs::read_dir(dir.path().join(TMP_DIR))?.count(), 0);
+        assert!(!blobs.exists(&id));
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_partial_blob() -> Result<()> {
+        let dir = tempdir::TempDir::new("solarblobs")?;
+        let (blobs, _receiver) = open_temporary_blobs(dir.path())?;
+
+        let content = b"a blob downloaded in slices";
+        let id = content.as_ref().blob_hash_id();
+        assert_eq!(blobs.partial_len(&id)?, 0);
+
+        blobs.write_partial(&id, 0, &content[..10])?;
+        // Bytes beyond the resumed offset are discarded.
+        blobs.write_partial(&id, 5, &content[5..8])?;
+        assert_eq!(blobs.partial_len(&id)?, 8);
+        assert!(blobs.write_partial(&id, 9, &content[9..]).is_err());
+
+        // Partial downloads are kept when the store is reopened.
+        let (blobs, _receiver) = open_temporary_blobs(dir.path())?;
+        assert_eq!(blobs.partial_len(&id)?, 8);
+        assert!(!blobs.exists(&id));
+
+        blobs.write_partial(&id, 8, &content[8..])?;
+        assert_eq!(blobs.complete_partial(&id).await?, id);
+        assert_eq!(blobs.get(&id)?, content);
+        assert_eq!(blobs.partial_len(&id)?, 0);
+
+        // A corrupted partial download is discarded.
+        let other = b"another blob".as_ref().blob_hash_id();
+        blobs.write_partial(&other, 0, b"not that blob")?;
+        assert_eq!(
+            blobs.complete_partial(&other).await.unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+        assert_eq!(blobs.partial_len(&other)?, 0);
+        assert!(!blobs.exists(&other));
+
+        Ok(())
+    }
 }
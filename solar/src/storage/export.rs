@@ -0,0 +1,300 @@
+//! Export to a flumelog-offset data directory.
+//!
+//! Writes the stored feeds, blobs and keypair of the node into a data
+//! directory in the flumelog-offset layout of the JavaScript implementation,
+//! allowing users to migrate without downloading the network again:
+//!
+//! - `secret`: the keypair, in the JSON format shared by all implementations.
+//! - `flume/log.offset`: every stored message as a KVT, in the flumelog-offset
+//!   format with 32-bit offsets.
+//! - `blobs/sha256/<xx>/<hex>`: every stored blob, named after the hex
+//!   encoding of its hash.
+//!
+//! The margaret log of go-ssb is not written: go-ssb imports the offset log
+//! with its `ssb-offset-converter` tool.
+//!
+//! Each record of the offset log is framed by its length (before and after
+//! the data) and followed by the length of the log file up to and including
+//! the record. Feeds are written one after another, each in sequence order.
+//! Since the log length is a 32-bit integer, logs which would exceed 4 GiB
+//! are not supported; the export fails before anything is written.
+
+use std::{
+    convert::TryFrom,
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use log::{info, warn};
+use serde_json::json;
+use sled::Config as DbConfig;
+
+use crate::{error::Error, secret_config::SecretConfig, storage::kv::KvStorage, Result};
+
+/// Length of the framing of a record of the offset log: the length of the
+/// data before and after it, and the length of the log after it.
+const RECORD_FRAMING: u64 = 12;
+
+/// Summary of an export.
+#[derive(Debug, Default)]
+pub struct ExportReport {
+    /// Number of feeds exported.
+    pub feeds: usize,
+    /// Total number of messages exported.
+    pub messages: u64,
+    /// Number of blobs exported.
+    pub blobs: usize,
+}
+
+impl fmt::Display for ExportReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Exported {} messages from {} feeds and {} blobs",
+            self.messages, self.feeds, self.blobs
+        )
+    }
+}
+
+/// Writer for a flumelog-offset log file.
+struct OffsetLogWriter {
+    writer: BufWriter<File>,
+    /// Length of the log file written so far.
+    len: u64,
+}
+
+impl OffsetLogWriter {
+    fn create(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+
+        Ok(Self {
+            writer: BufWriter::new(file),
+            len: 0,
+        })
+    }
+
+    /// Append a single record to the log.
+    fn append(&mut self, data: &[u8]) -> Result<()> {
+        let data_len = u32::try_from(data.len())?;
+        self.len += data.len() as u64 + RECORD_FRAMING;
+
+        self.writer.write_all(&data_len.to_be_bytes())?;
+        self.writer.write_all(data)?;
+        self.writer.write_all(&data_len.to_be_bytes())?;
+        self.writer
+            .write_all(&u32::try_from(self.len)?.to_be_bytes())?;
+
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+
+        Ok(())
+    }
+}
+
+/// Return the exported path of the blob stored in the given solar blob file,
+/// or `None` if the file name is not that of a blob.
+fn blob_path(blobs_dir: &Path, file_name: &str) -> Option<PathBuf> {
+    let hash = file_name.strip_suffix(".sha256")?.replace('_', "/");
+    let hex = hex::encode(base64::decode(hash).ok()?);
+
+    Some(blobs_dir.join("sha256").join(&hex[..2]).join(&hex[2..]))
+}
+
+/// Return an error if the offset log of the given feeds would exceed the
+/// largest length which can be recorded with 32-bit offsets.
+fn check_log_length(kv: &KvStorage, authors: &[String]) -> Result<()> {
+    let mut len = 0;
+    for author in authors {
+        for msg_kvt in kv.get_feed(author)? {
+            len += msg_kvt.to_string().len() as u64 + RECORD_FRAMING;
+        }
+    }
+
+    if len > u64::from(u32::MAX) {
+        return Err(Error::Other(format!(
+            "The offset log would be {len} bytes long, exceeding the 4 GiB \
+             supported by its 32-bit offsets"
+        )));
+    }
+
+    Ok(())
+}
+
+/// Export the feeds, blobs and keypair stored in the solar data directory at
+/// `base_path` into a flumelog-offset data directory at `target`. The offset
+/// log must not already exist in the target directory.
+pub async fn export_offset_log(base_path: &Path, target: &Path) -> Result<ExportReport> {
+    info!("Exporting {:?} to {:?}", base_path, target);
+
+    let mut report = ExportReport::default();
+
+    let mut kv = KvStorage::default();
+    // The export does not notify any actor of changes, so there is no need
+    // to keep the receiving end of the broker channel.
+    let (sender, _) = futures::channel::mpsc::unbounded();
+    kv.open(DbConfig::new().path(base_path.join("feeds")), sender)?;
+
+    let authors: Vec<String> = kv
+        .get_peers()
+        .await?
+        .into_iter()
+        .map(|(author, _latest_seq)| author)
+        .collect();
+    check_log_length(&kv, &authors)?;
+
+    fs::create_dir_all(target.join("flume"))?;
+
+    // Write the keypair in the JSON format used by ssb-keys and go-ssb.
+    let secret = SecretConfig::from_toml(&fs::read_to_string(base_path.join("secret.toml"))?)?;
+    let secret_json = json!({
+        "curve": "ed25519",
+        "public": secret.public_key.trim_start_matches('@'),
+        "private": secret.private_key,
+        "id": secret.public_key,
+    });
+    let secret_path = target.join("secret");
+    if secret_path.exists() {
+        warn!("Keeping existing keypair at {:?}", secret_path);
+    } else {
+        fs::write(&secret_path, serde_json::to_string_pretty(&secret_json)?)?;
+    }
+
+    let mut log = OffsetLogWriter::create(&target.join("flume").join("log.offset"))?;
+    for author in &authors {
+        let feed = kv.get_feed(author)?;
+        if feed.is_empty() {
+            continue;
+        }
+
+        for msg_kvt in &feed {
+            log.append(msg_kvt.to_string().as_bytes())?;
+        }
+        report.feeds += 1;
+        report.messages += feed.len() as u64;
+    }
+    log.finish()?;
+
+    let blobs_dir = target.join("blobs");
+    let entries = match fs::read_dir(base_path.join("blobs")) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(report),
+        Err(err) => return Err(err.into()),
+    };
+    for entry in entries {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        if let Some(path) = blob_path(&blobs_dir, &file_name) {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::copy(entry.path(), &path)?;
+            report.blobs += 1;
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::convert::TryInto;
+
+    use kuska_ssb::feed::Message as MessageValue;
+
+    use crate::storage::blob::ToBlobHashId;
+
+    #[async_std::test]
+    async fn test_export_offset_log() -> Result<()> {
+        let dir = tempdir::TempDir::new("solar").unwrap();
+        let base_path = dir.path().join("solar");
+        let target = dir.path().join("ssb");
+
+        let secret = SecretConfig::create();
+        let keypair = secret.to_owned_identity()?;
+        fs::create_dir_all(base_path.join("blobs"))?;
+        fs::write(base_path.join("secret.toml"), secret.to_toml()?)?;
+
+        {
+            let mut kv = KvStorage::default();
+            let (sender, _) = futures::channel::mpsc::unbounded();
+            kv.open(DbConfig::new().path(base_path.join("feeds")), sender)?;
+
+            let mut last_msg: Option<MessageValue> = None;
+            for i in 1..=3 {
+                let msg = MessageValue::sign(
+                    last_msg.as_ref(),
+                    &keypair,
+                    json!({ "type": "post", "text": format!("Post #{i}") }),
+                )?;
+                kv.append_feed(msg.clone()).await?;
+                last_msg = Some(msg);
+            }
+        }
+
+        let blob: &[u8] = b"solar blob";
+        let blob_id = blob.blob_hash_id();
+        let blob_file = blob_id.replace('&', "").replace('/', "_");
+        fs::write(base_path.join("blobs").join(&blob_file), blob)?;
+
+        let report = export_offset_log(&base_path, &target).await?;
+        assert_eq!(report.feeds, 1);
+        assert_eq!(report.messages, 3);
+        assert_eq!(report.blobs, 1);
+
+        // Read the records back from the offset log.
+        let log = fs::read(target.join("flume").join("log.offset"))?;
+        let mut offset = 0;
+        let mut records = 0;
+        while offset < log.len() {
+            let len = u32::from_be_bytes(log[offset..offset + 4].try_into().unwrap()) as usize;
+            let data = &log[offset + 4..offset + 4 + len];
+            let value: serde_json::Value = serde_json::from_slice(data)?;
+            assert_eq!(value["value"]["author"], json!(keypair.id));
+
+            let trailer = offset + 4 + len;
+            assert_eq!(&log[trailer..trailer + 4], &(len as u32).to_be_bytes());
+            offset = trailer + 8;
+            assert_eq!(
+                u32::from_be_bytes(log[trailer + 4..offset].try_into().unwrap()) as usize,
+                offset
+            );
+            records += 1;
+        }
+        assert_eq!(records, 3);
+
+        let secret_json: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(target.join("secret"))?)?;
+        assert_eq!(secret_json["id"], json!(keypair.id));
+
+        let hex = hex::encode(
+            base64::decode(blob_id.trim_start_matches('&').trim_end_matches(".sha256")).unwrap(),
+        );
+        assert_eq!(
+            fs::read(
+                target
+                    .join("blobs")
+                    .join("sha256")
+                    .join(&hex[..2])
+                    .join(&hex[2..])
+            )?,
+            blob
+        );
+
+        // The offset log is never overwritten.
+        assert!(export_offset_log(&base_path, &target).await.is_err());
+
+        Ok(())
+    }
+}
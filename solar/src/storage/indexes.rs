@@ -1,27 +1,67 @@
 //! Database indexes to allow for efficient look up of values extracted from
 //! messages.
 
-use std::collections::HashSet;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    convert::TryInto,
+};
 
 use kuska_ssb::{
     api::dto::content::{Image, TypedMessage as MessageContent},
     feed::Message as MessageValue,
 };
 use log::{debug, info};
+use serde_json::Value;
 use sled::{Db, Tree};
+use solar_core::index;
+
+use crate::{
+    actors::{
+        network::{clock_skew, multiserver},
+        replication::blobs,
+    },
+    config::SECRET_CONFIG,
+    error::Error,
+    storage::mutes::MuteList,
+    util::now_millis,
+    Result,
+};
 
-use crate::Result;
+pub use solar_core::index::{
+    About, AboutField, ContactState, Notification, NotificationKind, TimelineEntry, TimelineOrder,
+    TimelinePage, Vote,
+};
 
 /// Database indexes, each stored in a tree of the main database.
 pub struct Indexes {
+    /// Public key of the local identity, used to index notifications.
+    local_id: Option<String>,
+    /// Mute patterns, matching the messages left out of the timeline and
+    /// channel indexes.
+    mutes: MuteList,
+    /// Keys of messages authored by the local identity.
+    local_msgs: Tree,
+    /// Notifications for the local identity, keyed by message.
+    notifications: Tree,
+    /// Latest about values, updated as about-type messages are indexed.
+    abouts: Tree,
+    /// Blobs referenced by each author.
+    author_blobs: Tree,
+    /// Authors referencing each blob.
+    blob_authors: Tree,
     /// Blocks.
     blocks: Tree,
     /// Blockers.
     blockers: Tree,
+    /// Posts of each channel, keyed by channel and ordered by claimed
+    /// timestamp.
+    channel_posts: Tree,
     /// Channel subscribers.
     channel_subscribers: Tree,
     /// Channel subscriptions.
     channel_subscriptions: Tree,
+    /// Latest contact state of each edge, keyed by author and contact.
+    contacts: Tree,
     /// Descriptions.
     descriptions: Tree,
     /// Follows.
@@ -34,43 +74,191 @@ pub struct Indexes {
     images: Tree,
     /// Names.
     names: Tree,
+    /// Addresses announced in pub-type messages, keyed by the public key of
+    /// the pub.
+    pubs: Tree,
+    /// Replies to each thread, keyed by root message and ordered by claimed
+    /// timestamp.
+    replies: Tree,
+    /// All messages, ordered by claimed timestamp.
+    timeline_claimed: Tree,
+    /// All messages, ordered by received timestamp.
+    timeline_received: Tree,
+    /// Votes, keyed by the message voted on.
+    votes: Tree,
 }
 
 impl Indexes {
     /// Open a database tree for each index.
     pub fn open(db: &Db) -> Result<Indexes> {
         info!("Opening database index trees");
+        let abouts = db.open_tree("abouts")?;
+        let author_blobs = db.open_tree("author_blobs")?;
+        let blob_authors = db.open_tree("blob_authors")?;
         let blocks = db.open_tree("blocks")?;
         let blockers = db.open_tree("blockers")?;
+        let channel_posts = db.open_tree("channel_posts")?;
         let channel_subscribers = db.open_tree("channel_subscribers")?;
         let channel_subscriptions = db.open_tree("channel_subscriptions")?;
+        let contacts = db.open_tree("contacts")?;
         let descriptions = db.open_tree("descriptions")?;
         let follows = db.open_tree("follows")?;
         let followers = db.open_tree("followers")?;
         let friends = db.open_tree("friends")?;
         let images = db.open_tree("images")?;
         let names = db.open_tree("names")?;
+        let pubs = db.open_tree("pubs")?;
+        let replies = db.open_tree("replies")?;
+        let timeline_claimed = db.open_tree("timeline_claimed")?;
+        let timeline_received = db.open_tree("timeline_received")?;
+        let local_msgs = db.open_tree("local_msgs")?;
+        let notifications = db.open_tree("notifications")?;
+        let votes = db.open_tree("votes")?;
 
         let indexes = Indexes {
+            local_id: SECRET_CONFIG
+                .get()
+                .map(|secret| secret.public_key.to_owned()),
+            mutes: MuteList::default(),
+            local_msgs,
+            notifications,
+            abouts,
+            author_blobs,
+            blob_authors,
             blocks,
             blockers,
+            channel_posts,
             channel_subscribers,
             channel_subscriptions,
+            contacts,
             descriptions,
             follows,
             followers,
             friends,
             images,
             names,
+            pubs,
+            replies,
+            timeline_claimed,
+            timeline_received,
+            votes,
         };
 
+        // Databases created before the about index was introduced only have
+        // the name, image and description indexes; build the about index
+        // from those.
+        if indexes.abouts.is_empty() && !indexes.names_images_descriptions_empty() {
+            indexes.rebuild_about_index()?
+        }
+
+        // Likewise, build the contacts index from the follow and block
+        // indexes.
+        if indexes.contacts.is_empty() && !(indexes.follows.is_empty() && indexes.blocks.is_empty())
+        {
+            indexes.rebuild_contacts_index()?
+        }
+
         Ok(indexes)
     }
 
+    /// Query whether the name, image and description indexes are all empty.
+    fn names_images_descriptions_empty(&self) -> bool {
+        self.names.is_empty() && self.images.is_empty() && self.descriptions.is_empty()
+    }
+
+    /// Build the about index from the name, image and description indexes.
+    ///
+    /// Values in those indexes are stored in the order in which they were
+    /// indexed, so replaying them results in the same about index as
+    /// indexing the original messages.
+    fn rebuild_about_index(&self) -> Result<()> {
+        info!("Building about index");
+
+        self.replay_about_field(&self.descriptions, |about| &mut about.description)?;
+        self.replay_about_field(&self.images, |about| &mut about.image)?;
+        self.replay_about_field(&self.names, |about| &mut about.name)?;
+
+        Ok(())
+    }
+
+    /// Build the contacts index from the follow and block indexes.
+    fn rebuild_contacts_index(&self) -> Result<()> {
+        info!("Building contacts index");
+
+        for entry in self.follows.iter() {
+            let (author_id, raw) = entry?;
+            let author_id = String::from_utf8_lossy(&author_id);
+            for contact_id in serde_cbor::from_slice::<HashSet<String>>(&raw)? {
+                self.update_contact(&author_id, &contact_id, |state| state.following = true)?;
+            }
+        }
+
+        for entry in self.blocks.iter() {
+            let (author_id, raw) = entry?;
+            let author_id = String::from_utf8_lossy(&author_id);
+            for contact_id in serde_cbor::from_slice::<HashSet<String>>(&raw)? {
+                self.update_contact(&author_id, &contact_id, |state| state.blocking = true)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Apply the values stored in the given index (names, images or
+    /// descriptions) to the corresponding field of the about index.
+    fn replay_about_field<F>(&self, tree: &Tree, field: F) -> Result<()>
+    where
+        F: Fn(&mut About) -> &mut AboutField,
+    {
+        for key in tree.iter().keys() {
+            let about_id = String::from_utf8_lossy(&key?).into_owned();
+            self.replay_about_values(&about_id, tree, &field)?;
+        }
+
+        Ok(())
+    }
+
     /// Index a message based on the author (SSB ID) and content type.
+    ///
+    /// The current time is used as the receive time of the message.
     pub fn index_msg(&self, author_id: &str, msg_val: MessageValue) -> Result<()> {
+        let received = now_millis();
+
+        self.index_received_msg(author_id, msg_val, received)
+    }
+
+    /// Index a message based on the author (SSB ID) and content type, using
+    /// the given local receive time (in milliseconds).
+    pub fn index_received_msg(
+        &self,
+        author_id: &str,
+        msg_val: MessageValue,
+        received: u64,
+    ) -> Result<()> {
         debug!("Indexing message {} from {}", msg_val.sequence(), author_id);
+        let muted = msg_val
+            .value
+            .get("content")
+            .map_or(false, |content| self.is_muted(content));
+        self.index_notification(author_id, &msg_val, received)?;
+        if !muted {
+            self.index_timeline(author_id, &msg_val, received)?;
+            self.index_reply(author_id, &msg_val, received)?;
+            self.index_channel_post(author_id, &msg_val, received)?;
+        }
+        self.index_blob_refs(author_id, &msg_val)?;
+
         if let Some(content_val) = msg_val.value.get("content") {
+            if content_val.get("type").and_then(Value::as_str) == Some("about") {
+                self.index_public_web_hosting(author_id, content_val)?;
+            }
+            if content_val.get("type").and_then(Value::as_str) == Some("vote") {
+                return self.index_vote(author_id, &msg_val);
+            }
+            if content_val.get("type").and_then(Value::as_str) == Some("pub") {
+                return self.index_pub(content_val);
+            }
+
             let content: MessageContent = serde_json::from_value(content_val.to_owned())?;
 
             match content {
@@ -78,7 +266,7 @@ impl Indexes {
                 MessageContent::Channel {
                     channel,
                     subscribed,
-                } => self.index_channel(author_id, channel, subscribed)?,
+                } if !muted => self.index_channel(author_id, channel, subscribed)?,
                 MessageContent::Contact { .. } => self.index_contact(author_id, content)?,
                 _ => (),
             }
@@ -111,6 +299,58 @@ impl Indexes {
         Ok(())
     }
 
+    /// Index the public web hosting preference self-assigned in the given
+    /// about-type message content.
+    fn index_public_web_hosting(&self, author_id: &str, content: &Value) -> Result<()> {
+        if content.get("about").and_then(Value::as_str) != Some(author_id) {
+            return Ok(());
+        }
+        if let Some(hosting) = content.get("publicWebHosting").and_then(Value::as_bool) {
+            self.update_about(author_id, |about| about.public_web_hosting = Some(hosting))?;
+        }
+
+        Ok(())
+    }
+
+    /// Query whether the author with the given public key asked for their
+    /// messages not to be shown on public web pages.
+    pub fn is_hidden_from_web(&self, ssb_id: &str) -> Result<bool> {
+        Ok(self.get_about(ssb_id)?.public_web_hosting == Some(false))
+    }
+
+    /// Apply the given update to the about index entry for the given public
+    /// key.
+    fn update_about<F>(&self, about_id: &str, update: F) -> Result<()>
+    where
+        F: FnOnce(&mut About),
+    {
+        let mut about = self.get_about(about_id)?;
+        update(&mut about);
+        self.abouts.insert(about_id, serde_cbor::to_vec(&about)?)?;
+
+        Ok(())
+    }
+
+    /// Return the latest about values for the given public key.
+    pub fn get_about(&self, ssb_id: &str) -> Result<About> {
+        let about = if let Some(raw) = self.abouts.get(ssb_id)? {
+            serde_cbor::from_slice::<About>(&raw)?
+        } else {
+            About::default()
+        };
+
+        Ok(about)
+    }
+
+    /// Return the name to be displayed for the given public key: the most
+    /// recent self-assigned name if there is one, otherwise the most recent
+    /// name assigned by any author.
+    pub fn get_display_name(&self, ssb_id: &str) -> Result<Option<String>> {
+        let about = self.get_about(ssb_id)?;
+
+        Ok(about.name.resolve().map(str::to_owned))
+    }
+
     /// Add the given block to the block indexes.
     fn index_blocking(&self, author_id: &str, contact: &str, blocking: bool) -> Result<()> {
         self.index_block(author_id, contact, blocking)?;
@@ -261,16 +501,65 @@ impl Indexes {
         } = msg_content
         {
             if let Some(blocking) = blocking {
-                self.index_blocking(author_id, &contact, blocking)?
+                self.index_blocking(author_id, &contact, blocking)?;
+                self.update_contact(author_id, &contact, |state| state.blocking = blocking)?;
             }
             if let Some(following) = following {
-                self.index_following(author_id, &contact, following)?
+                self.index_following(author_id, &contact, following)?;
+                self.update_contact(author_id, &contact, |state| state.following = following)?;
             }
         }
 
         Ok(())
     }
 
+    /// Apply the given update to the contact state of the edge from the given
+    /// author to the given contact.
+    fn update_contact<F>(&self, author_id: &str, contact_id: &str, update: F) -> Result<()>
+    where
+        F: FnOnce(&mut ContactState),
+    {
+        let mut state = self.get_contact(author_id, contact_id)?.unwrap_or_default();
+        update(&mut state);
+        self.contacts.insert(
+            index::contact_key(author_id, contact_id),
+            serde_cbor::to_vec(&state)?,
+        )?;
+
+        Ok(())
+    }
+
+    /// Return the latest contact state of the edge from the given author to
+    /// the given contact, or `None` if the author has never published a
+    /// contact message about the contact.
+    pub fn get_contact(&self, author_id: &str, contact_id: &str) -> Result<Option<ContactState>> {
+        let state = if let Some(raw) = self
+            .contacts
+            .get(index::contact_key(author_id, contact_id))?
+        {
+            Some(serde_cbor::from_slice::<ContactState>(&raw)?)
+        } else {
+            None
+        };
+
+        Ok(state)
+    }
+
+    /// Return the latest contact state of every edge from the given author,
+    /// keyed by the public key of the contact.
+    pub fn get_contacts(&self, author_id: &str) -> Result<HashMap<String, ContactState>> {
+        let prefix = index::contact_key(author_id, "");
+        let mut contacts = HashMap::new();
+
+        for entry in self.contacts.scan_prefix(&prefix) {
+            let (key, raw) = entry?;
+            let contact_id = String::from_utf8_lossy(&key[prefix.len()..]).into_owned();
+            contacts.insert(contact_id, serde_cbor::from_slice::<ContactState>(&raw)?);
+        }
+
+        Ok(contacts)
+    }
+
     /// Add the given description to the description index for the associated
     /// public key.
     fn index_description(
@@ -280,10 +569,14 @@ impl Indexes {
         description: String,
     ) -> Result<()> {
         let mut descriptions = self.get_descriptions(about_id)?;
-        descriptions.push((author_id.to_owned(), description));
+        descriptions.push((author_id.to_owned(), description.to_owned()));
         self.descriptions
             .insert(about_id, serde_cbor::to_vec(&descriptions)?)?;
 
+        self.update_about(about_id, |about| {
+            about.description.update(about_id, author_id, description)
+        })?;
+
         Ok(())
     }
 
@@ -313,10 +606,10 @@ impl Indexes {
     /// Return the most recently indexed description for the given public key.
     pub fn get_latest_description(&self, ssb_id: &str) -> Result<Option<String>> {
         let description = self
-            .get_descriptions(ssb_id)?
-            .last()
-            .map(|(_ssb_id, description)| description)
-            .cloned();
+            .get_about(ssb_id)?
+            .description
+            .latest
+            .map(|(_ssb_id, description)| description);
 
         Ok(description)
     }
@@ -324,8 +617,7 @@ impl Indexes {
     /// Return the most recently indexed self-assigned description for the given
     /// public key.
     pub fn get_latest_self_assigned_description(&self, ssb_id: &str) -> Result<Option<String>> {
-        let self_descriptions = self.get_self_assigned_descriptions(ssb_id)?;
-        let description = self_descriptions.last().cloned();
+        let description = self.get_about(ssb_id)?.description.latest_self;
 
         Ok(description)
     }
@@ -339,6 +631,50 @@ impl Indexes {
         Ok(())
     }
 
+    /// Index the blobs referenced by the given message.
+    fn index_blob_refs(&self, author_id: &str, msg_val: &MessageValue) -> Result<()> {
+        let blob_ids = blobs::extract_blob_refs(msg_val);
+        if blob_ids.is_empty() {
+            return Ok(());
+        }
+
+        let mut author_blobs = self.get_author_blobs(author_id)?;
+        for blob_id in blob_ids {
+            let mut blob_authors = self.get_blob_authors(&blob_id)?;
+            if blob_authors.insert(author_id.to_owned()) {
+                self.blob_authors
+                    .insert(&blob_id, serde_cbor::to_vec(&blob_authors)?)?;
+            }
+            author_blobs.insert(blob_id);
+        }
+        self.author_blobs
+            .insert(author_id, serde_cbor::to_vec(&author_blobs)?)?;
+
+        Ok(())
+    }
+
+    /// Return the IDs of all blobs referenced by the given author.
+    pub fn get_author_blobs(&self, ssb_id: &str) -> Result<HashSet<String>> {
+        let blob_ids = if let Some(raw) = self.author_blobs.get(ssb_id)? {
+            serde_cbor::from_slice::<HashSet<String>>(&raw)?
+        } else {
+            HashSet::new()
+        };
+
+        Ok(blob_ids)
+    }
+
+    /// Return the public keys of all authors referencing the given blob.
+    pub fn get_blob_authors(&self, blob_id: &str) -> Result<HashSet<String>> {
+        let authors = if let Some(raw) = self.blob_authors.get(blob_id)? {
+            serde_cbor::from_slice::<HashSet<String>>(&raw)?
+        } else {
+            HashSet::new()
+        };
+
+        Ok(authors)
+    }
+
     /// Update the follows index for the given follower ID, followed ID and
     /// follow state.
     fn index_follow(&self, follower_id: &str, followed_id: &str, followed: bool) -> Result<()> {
@@ -444,8 +780,12 @@ impl Indexes {
         // TODO: Handle `Image::Complete { .. }` variant.
         if let Image::OnlyLink(ssb_hash) = image {
             let mut images = self.get_images(about_id)?;
-            images.push((author_id.to_owned(), ssb_hash));
+            images.push((author_id.to_owned(), ssb_hash.to_owned()));
             self.images.insert(about_id, serde_cbor::to_vec(&images)?)?;
+
+            self.update_about(about_id, |about| {
+                about.image.update(about_id, author_id, ssb_hash)
+            })?;
         }
 
         Ok(())
@@ -478,8 +818,7 @@ impl Indexes {
     /// Return the most recently indexed image reference for the given public
     /// key.
     pub fn get_latest_image(&self, ssb_id: &str) -> Result<Option<(String, String)>> {
-        let images = self.get_images(ssb_id)?;
-        let image = images.last().cloned();
+        let image = self.get_about(ssb_id)?.image.latest;
 
         Ok(image)
     }
@@ -487,8 +826,7 @@ impl Indexes {
     /// Return the most recently indexed self-assigned image reference for the
     /// given public key.
     pub fn get_latest_self_assigned_image(&self, ssb_id: &str) -> Result<Option<String>> {
-        let images = self.get_self_assigned_images(ssb_id)?;
-        let image = images.last().cloned();
+        let image = self.get_about(ssb_id)?.image.latest_self;
 
         Ok(image)
     }
@@ -497,9 +835,13 @@ impl Indexes {
     fn index_name(&self, author_id: &str, about_id: &str, name: String) -> Result<()> {
         // TODO: Do we also want to store the hash of the associated message?
         let mut names = self.get_names(about_id)?;
-        names.push((author_id.to_owned(), name));
+        names.push((author_id.to_owned(), name.to_owned()));
         self.names.insert(about_id, serde_cbor::to_vec(&names)?)?;
 
+        self.update_about(about_id, |about| {
+            about.name.update(about_id, author_id, name)
+        })?;
+
         Ok(())
     }
 
@@ -524,8 +866,7 @@ impl Indexes {
 
     /// Return the most recently indexed name for the given public key.
     pub fn get_latest_name(&self, ssb_id: &str) -> Result<Option<(String, String)>> {
-        let names = self.get_names(ssb_id)?;
-        let name = names.last().cloned();
+        let name = self.get_about(ssb_id)?.name.latest;
 
         Ok(name)
     }
@@ -533,11 +874,653 @@ impl Indexes {
     /// Return the most recently indexed self-assigned name for the given public
     /// key.
     pub fn get_latest_self_assigned_name(&self, ssb_id: &str) -> Result<Option<(String, String)>> {
-        let names = self.get_self_assigned_names(ssb_id)?;
-        let name = names.last().cloned();
+        let name = self
+            .get_about(ssb_id)?
+            .name
+            .latest_self
+            .map(|name| (ssb_id.to_owned(), name));
 
         Ok(name)
     }
+
+    /// Index the content of a vote-type message. Only the latest vote cast by
+    /// each voter on a message is retained.
+    fn index_vote(&self, author_id: &str, msg_val: &MessageValue) -> Result<()> {
+        let vote = match msg_val
+            .value
+            .get("content")
+            .and_then(|content| content.get("vote"))
+        {
+            Some(vote) => vote,
+            None => return Ok(()),
+        };
+        let link = match vote.get("link").and_then(Value::as_str) {
+            Some(link) => link,
+            None => return Ok(()),
+        };
+        // Some clients publish the vote value as a float.
+        let value = match vote.get("value") {
+            Some(value) => value
+                .as_i64()
+                .or_else(|| value.as_f64().map(|value| value.round() as i64))
+                .unwrap_or(0),
+            None => 0,
+        };
+
+        let mut votes = self.get_votes(link)?;
+        votes.retain(|vote| vote.voter != author_id);
+        votes.push(Vote {
+            voter: author_id.to_owned(),
+            value,
+            expression: vote
+                .get("expression")
+                .and_then(Value::as_str)
+                .map(str::to_owned),
+            timestamp: msg_val
+                .value
+                .get("timestamp")
+                .and_then(Value::as_f64)
+                .unwrap_or_default(),
+        });
+        self.votes.insert(link, serde_cbor::to_vec(&votes)?)?;
+
+        Ok(())
+    }
+
+    /// Return the latest vote cast by each voter on the given message.
+    pub fn get_votes(&self, msg_ref: &str) -> Result<Vec<Vote>> {
+        let votes = if let Some(raw) = self.votes.get(msg_ref)? {
+            serde_cbor::from_slice::<Vec<Vote>>(&raw)?
+        } else {
+            Vec::new()
+        };
+
+        Ok(votes)
+    }
+
+    /// Return the public keys of all peers who like the given message (ie.
+    /// whose latest vote on the message has a positive value).
+    pub fn get_likes(&self, msg_ref: &str) -> Result<Vec<String>> {
+        let likes = self
+            .get_votes(msg_ref)?
+            .into_iter()
+            .filter(|vote| vote.value > 0)
+            .map(|vote| vote.voter)
+            .collect();
+
+        Ok(likes)
+    }
+
+    /// Index the address announced in a pub-type message. Later
+    /// announcements for the same pub replace earlier ones.
+    ///
+    /// The address is given either as an object (`host`, `port` and `key`)
+    /// or as a multiserver address, possibly alongside a list of multiserver
+    /// addresses (`addresses`); only TCP addresses are indexed.
+    fn index_pub(&self, content_val: &Value) -> Result<()> {
+        let address = content_val.get("address").and_then(|address| {
+            match (
+                address.get("key").and_then(Value::as_str),
+                address.get("host").and_then(Value::as_str),
+                address.get("port").and_then(Value::as_u64),
+            ) {
+                (Some(key), Some(host), Some(port)) => {
+                    Some((key.to_owned(), format!("{host}:{port}")))
+                }
+                _ => address.as_str().and_then(Self::parse_pub_address),
+            }
+        });
+        let address = address.or_else(|| {
+            content_val
+                .get("addresses")
+                .and_then(Value::as_array)
+                .and_then(|addresses| {
+                    addresses
+                        .iter()
+                        .filter_map(Value::as_str)
+                        .find_map(Self::parse_pub_address)
+                })
+        });
+
+        if let Some((key, addr)) = address {
+            self.pubs.insert(key, addr.as_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    /// Parse the first TCP address of the given multiserver address,
+    /// returning the public key and address (host and port) of the pub.
+    fn parse_pub_address(multiserver: &str) -> Option<(String, String)> {
+        multiserver::split(multiserver)
+            .find_map(multiserver::parse_net)
+            .map(|(addr, key)| (key, addr))
+    }
+
+    /// Return the public key and address of every pub announced in a
+    /// pub-type message.
+    pub fn get_pub_addresses(&self) -> Result<Vec<(String, String)>> {
+        let mut addresses = Vec::new();
+        for entry in self.pubs.iter() {
+            let (key, addr) = entry?;
+            addresses.push((
+                String::from_utf8_lossy(&key).into_owned(),
+                String::from_utf8_lossy(&addr).into_owned(),
+            ));
+        }
+
+        Ok(addresses)
+    }
+
+    /// Return the public key of the local identity, if set.
+    pub fn local_id(&self) -> Option<&str> {
+        self.local_id.as_deref()
+    }
+
+    /// Set the public key of the local identity. Notifications are only
+    /// indexed for messages indexed after the local identity is set.
+    pub fn set_local_id(&mut self, ssb_id: &str) {
+        self.local_id = Some(ssb_id.to_owned())
+    }
+
+    /// Set the mute patterns. Messages indexed before are left in the
+    /// indexes.
+    pub fn set_mutes(&mut self, mutes: MuteList) {
+        self.mutes = mutes
+    }
+
+    /// Query whether the given message content is muted.
+    pub fn is_muted(&self, content: &Value) -> bool {
+        self.mutes.mutes(content)
+    }
+
+    /// Query whether the given channel is muted.
+    pub fn is_channel_muted(&self, channel: &str) -> bool {
+        self.mutes.matches(channel)
+    }
+
+    /// Index a notification if the given message mentions the local identity
+    /// or replies to a message authored by the local identity.
+    fn index_notification(
+        &self,
+        author_id: &str,
+        msg_val: &MessageValue,
+        received: u64,
+    ) -> Result<()> {
+        let local_id = match &self.local_id {
+            Some(local_id) => local_id,
+            None => return Ok(()),
+        };
+        let msg_ref = msg_val.id().to_string();
+
+        // Remember messages authored by the local identity so that replies
+        // can be recognised.
+        if author_id == local_id {
+            self.local_msgs.insert(&msg_ref, &[] as &[u8])?;
+            return Ok(());
+        }
+
+        let content = match msg_val.value.get("content") {
+            Some(content) => content,
+            None => return Ok(()),
+        };
+
+        let kind = if index::mentions(content, local_id) {
+            NotificationKind::Mention
+        } else if self.is_reply_to_local(content)? {
+            NotificationKind::Reply
+        } else {
+            return Ok(());
+        };
+
+        let notification = Notification {
+            msg_ref: msg_ref.to_owned(),
+            author: author_id.to_owned(),
+            kind,
+            timestamp: Self::ordering_timestamp(author_id, msg_val, received),
+            read: false,
+        };
+        self.notifications
+            .insert(&msg_ref, serde_cbor::to_vec(&notification)?)?;
+
+        Ok(())
+    }
+
+    /// Query whether the given message content replies to (has a `root` or
+    /// `branch` referencing) a message authored by the local identity.
+    fn is_reply_to_local(&self, content: &Value) -> Result<bool> {
+        for msg_ref in index::reply_refs(content) {
+            if self.local_msgs.contains_key(msg_ref)? {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Return all notifications for the local identity, ordered from newest
+    /// to oldest. Read notifications are omitted if `unread_only` is true.
+    pub fn get_notifications(&self, unread_only: bool) -> Result<Vec<Notification>> {
+        let mut notifications = Vec::new();
+        for entry in self.notifications.iter() {
+            let (_msg_ref, raw) = entry?;
+            let notification = serde_cbor::from_slice::<Notification>(&raw)?;
+            if !(unread_only && notification.read) {
+                notifications.push(notification)
+            }
+        }
+
+        notifications.sort_by(|a, b| b.timestamp.total_cmp(&a.timestamp));
+
+        Ok(notifications)
+    }
+
+    /// Mark the notifications for the given messages as read, or all
+    /// notifications if no messages are given. Returns the number of
+    /// notifications which were previously unread.
+    pub fn mark_notifications_read(&self, msg_refs: Option<&[String]>) -> Result<usize> {
+        let keys: Vec<sled::IVec> = match msg_refs {
+            Some(msg_refs) => msg_refs
+                .iter()
+                .map(|msg_ref| msg_ref.as_str().into())
+                .collect(),
+            None => self
+                .notifications
+                .iter()
+                .keys()
+                .collect::<std::result::Result<_, _>>()?,
+        };
+
+        let mut marked = 0;
+        for key in keys {
+            if let Some(raw) = self.notifications.get(&key)? {
+                let mut notification = serde_cbor::from_slice::<Notification>(&raw)?;
+                if !notification.read {
+                    notification.read = true;
+                    self.notifications
+                        .insert(&key, serde_cbor::to_vec(&notification)?)?;
+                    marked += 1;
+                }
+            }
+        }
+
+        Ok(marked)
+    }
+
+    /// Return the timestamp by which the given message is ordered: the time
+    /// asserted by its author or, if the clock of the author is known to be
+    /// skewed, the given receive time.
+    fn ordering_timestamp(author_id: &str, msg_val: &MessageValue, received: u64) -> f64 {
+        if clock_skew::is_skewed(author_id) {
+            return received as f64;
+        }
+
+        msg_val
+            .value
+            .get("timestamp")
+            .and_then(Value::as_f64)
+            .unwrap_or_default()
+    }
+
+    /// Add the given message to the claimed and received timelines.
+    fn index_timeline(&self, author_id: &str, msg_val: &MessageValue, received: u64) -> Result<()> {
+        let msg_ref = msg_val.id().to_string();
+        let value = serde_cbor::to_vec(&(author_id, msg_val.sequence()))?;
+
+        // Negative and fractional claimed timestamps are clamped to whole,
+        // positive milliseconds.
+        let claimed = Self::ordering_timestamp(author_id, msg_val, received) as u64;
+
+        self.timeline_claimed
+            .insert(index::timeline_key(claimed, &msg_ref), value.as_slice())?;
+        self.timeline_received
+            .insert(index::timeline_key(received, &msg_ref), value)?;
+
+        Ok(())
+    }
+
+    /// Add the given message to the replies of its thread, if it has a
+    /// `root`.
+    pub fn index_reply(
+        &self,
+        author_id: &str,
+        msg_val: &MessageValue,
+        received: u64,
+    ) -> Result<()> {
+        let root = match msg_val
+            .value
+            .get("content")
+            .and_then(|content| content.get("root"))
+            .and_then(Value::as_str)
+        {
+            Some(root) => root,
+            None => return Ok(()),
+        };
+
+        let msg_ref = msg_val.id().to_string();
+        let claimed = Self::ordering_timestamp(author_id, msg_val, received) as u64;
+        let mut key = root.as_bytes().to_vec();
+        key.extend_from_slice(&index::timeline_key(claimed, &msg_ref));
+        self.replies
+            .insert(key, serde_cbor::to_vec(&(author_id, msg_val.sequence()))?)?;
+
+        Ok(())
+    }
+
+    /// Query whether no reply is indexed.
+    pub fn replies_is_empty(&self) -> bool {
+        self.replies.is_empty()
+    }
+
+    /// Return the key prefix of the posts of the given channel. Channels are
+    /// named with or without a leading `#`.
+    fn channel_prefix(channel: &str) -> Vec<u8> {
+        let mut prefix = channel.trim_start_matches('#').as_bytes().to_vec();
+        prefix.push(0);
+        prefix
+    }
+
+    /// Add the given message to the posts of its channel, if it is a post
+    /// with a `channel`.
+    pub fn index_channel_post(
+        &self,
+        author_id: &str,
+        msg_val: &MessageValue,
+        received: u64,
+    ) -> Result<()> {
+        let content = match msg_val.value.get("content") {
+            Some(content) if content.get("type").and_then(Value::as_str) == Some("post") => content,
+            _ => return Ok(()),
+        };
+        let channel = match content.get("channel").and_then(Value::as_str) {
+            Some(channel) => channel,
+            None => return Ok(()),
+        };
+
+        let msg_ref = msg_val.id().to_string();
+        let claimed = Self::ordering_timestamp(author_id, msg_val, received) as u64;
+        let mut key = Self::channel_prefix(channel);
+        key.extend_from_slice(&index::timeline_key(claimed, &msg_ref));
+        self.channel_posts
+            .insert(key, serde_cbor::to_vec(&(author_id, msg_val.sequence()))?)?;
+
+        Ok(())
+    }
+
+    /// Query whether no channel post is indexed.
+    pub fn channel_posts_is_empty(&self) -> bool {
+        self.channel_posts.is_empty()
+    }
+
+    /// Return up to `limit` posts of the given channel, from newest to
+    /// oldest.
+    pub fn get_channel_posts(&self, channel: &str, limit: usize) -> Result<Vec<TimelineEntry>> {
+        let prefix = Self::channel_prefix(channel);
+        Self::read_entries(
+            self.channel_posts.scan_prefix(&prefix).rev().take(limit),
+            &prefix,
+        )
+    }
+
+    /// Return the replies to the thread with the given root message, from
+    /// oldest to newest.
+    pub fn get_replies(&self, root: &str) -> Result<Vec<TimelineEntry>> {
+        Self::read_entries(self.replies.scan_prefix(root.as_bytes()), root.as_bytes())
+    }
+
+    /// Read the given entries of a tree keyed by the given prefix followed by
+    /// a timeline key (the replies and channel posts indexes).
+    fn read_entries(
+        entries: impl Iterator<Item = sled::Result<(sled::IVec, sled::IVec)>>,
+        prefix: &[u8],
+    ) -> Result<Vec<TimelineEntry>> {
+        let mut timeline_entries = Vec::new();
+        for entry in entries {
+            let (key, raw) = entry?;
+            let key = &key[prefix.len()..];
+            let timestamp = u64::from_be_bytes(
+                key[..8]
+                    .try_into()
+                    .map_err(|_| Error::Cursor("Invalid timeline key".to_string()))?,
+            );
+            let (author, sequence) = serde_cbor::from_slice::<(String, u64)>(&raw)?;
+
+            timeline_entries.push(TimelineEntry {
+                msg_ref: String::from_utf8_lossy(&key[8..]).into_owned(),
+                author,
+                sequence,
+                timestamp,
+            });
+        }
+
+        Ok(timeline_entries)
+    }
+
+    /// Return up to `limit` timeline entries in the given order, from newest
+    /// to oldest, starting after the given cursor (or from the newest entry
+    /// if no cursor is given).
+    pub fn get_timeline(
+        &self,
+        order: TimelineOrder,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<TimelinePage> {
+        let tree = match order {
+            TimelineOrder::Claimed => &self.timeline_claimed,
+            TimelineOrder::Received => &self.timeline_received,
+        };
+
+        // Cursors take the form `<timestamp>:<message key>`.
+        let iter = match cursor {
+            Some(cursor) => {
+                let (timestamp, msg_ref) = cursor
+                    .split_once(':')
+                    .ok_or_else(|| Error::Cursor(cursor.to_owned()))?;
+                let timestamp: u64 = timestamp
+                    .parse()
+                    .map_err(|_| Error::Cursor(cursor.to_owned()))?;
+                tree.range(..index::timeline_key(timestamp, msg_ref))
+            }
+            None => tree.iter(),
+        };
+
+        let mut entries = Vec::new();
+        for entry in iter.rev().take(limit) {
+            let (key, raw) = entry?;
+            let timestamp = u64::from_be_bytes(
+                key[..8]
+                    .try_into()
+                    .map_err(|_| Error::Cursor("Invalid timeline key".to_string()))?,
+            );
+            let (author, sequence) = serde_cbor::from_slice::<(String, u64)>(&raw)?;
+
+            entries.push(TimelineEntry {
+                msg_ref: String::from_utf8_lossy(&key[8..]).into_owned(),
+                author,
+                sequence,
+                timestamp,
+            });
+        }
+
+        // Only return a cursor if there may be more entries.
+        let cursor = match entries.last() {
+            Some(entry) if entries.len() == limit => {
+                Some(format!("{}:{}", entry.timestamp, entry.msg_ref))
+            }
+            _ => None,
+        };
+
+        Ok(TimelinePage { entries, cursor })
+    }
+
+    /// Return the hop distance of every feed within `max_hops` of the given
+    /// public key in the follow graph. The given public key is at zero hops,
+    /// the feeds it follows are at one hop and so on.
+    pub fn get_hops(&self, ssb_id: &str, max_hops: usize) -> Result<HashMap<String, usize>> {
+        let mut hops = HashMap::new();
+        hops.insert(ssb_id.to_owned(), 0);
+
+        let mut queue = VecDeque::new();
+        queue.push_back((ssb_id.to_owned(), 0));
+
+        // Breadth-first traversal, so the first distance recorded for a
+        // feed is the shortest.
+        while let Some((peer, distance)) = queue.pop_front() {
+            if distance >= max_hops {
+                continue;
+            }
+            for followed in self.get_follows(&peer)? {
+                if !hops.contains_key(&followed) {
+                    hops.insert(followed.to_owned(), distance + 1);
+                    queue.push_back((followed, distance + 1));
+                }
+            }
+        }
+
+        Ok(hops)
+    }
+
+    /// Remove all index entries derived from messages authored by the given
+    /// public key.
+    pub fn remove_author(&self, author_id: &str) -> Result<()> {
+        self.remove_authors(&HashSet::from([author_id.to_owned()]))
+    }
+
+    /// Remove all index entries derived from messages authored by any of the
+    /// given public keys. Each index is scanned once, however many authors
+    /// are removed.
+    pub fn remove_authors(&self, author_ids: &HashSet<String>) -> Result<()> {
+        // Entries keyed by the authors.
+        for author_id in author_ids {
+            self.author_blobs.remove(author_id)?;
+            self.blocks.remove(author_id)?;
+            self.channel_subscriptions.remove(author_id)?;
+            self.follows.remove(author_id)?;
+            self.friends.remove(author_id)?;
+            for entry in self.contacts.scan_prefix(index::contact_key(author_id, "")) {
+                let (key, _) = entry?;
+                self.contacts.remove(key)?;
+            }
+        }
+
+        // Sets of which the authors are members.
+        for tree in [
+            &self.blob_authors,
+            &self.blockers,
+            &self.channel_subscribers,
+            &self.followers,
+            &self.friends,
+        ] {
+            Self::remove_set_members(tree, author_ids)?;
+        }
+
+        // Values assigned by the authors. The about index is rebuilt for
+        // each public key whose values have changed.
+        let mut about_ids = HashSet::new();
+        for tree in [&self.descriptions, &self.images, &self.names] {
+            about_ids.extend(Self::remove_assigned_values(tree, author_ids)?);
+        }
+        for about_id in about_ids {
+            // The public web hosting preference is only self-assigned.
+            let public_web_hosting = self.get_about(&about_id)?.public_web_hosting;
+            self.abouts.remove(&about_id)?;
+            if !author_ids.contains(&about_id) && public_web_hosting.is_some() {
+                self.update_about(&about_id, |about| {
+                    about.public_web_hosting = public_web_hosting
+                })?;
+            }
+            self.replay_about_values(&about_id, &self.descriptions, |about| {
+                &mut about.description
+            })?;
+            self.replay_about_values(&about_id, &self.images, |about| &mut about.image)?;
+            self.replay_about_values(&about_id, &self.names, |about| &mut about.name)?;
+        }
+
+        // Votes, notifications, timeline entries, replies and channel posts.
+        for entry in self.votes.iter() {
+            let (msg_ref, raw) = entry?;
+            let mut votes = serde_cbor::from_slice::<Vec<Vote>>(&raw)?;
+            let len = votes.len();
+            votes.retain(|vote| !author_ids.contains(&vote.voter));
+            if votes.len() != len {
+                self.votes.insert(msg_ref, serde_cbor::to_vec(&votes)?)?;
+            }
+        }
+        for entry in self.notifications.iter() {
+            let (msg_ref, raw) = entry?;
+            if author_ids.contains(&serde_cbor::from_slice::<Notification>(&raw)?.author) {
+                self.notifications.remove(msg_ref)?;
+            }
+        }
+        for tree in [
+            &self.timeline_claimed,
+            &self.timeline_received,
+            &self.replies,
+            &self.channel_posts,
+        ] {
+            for entry in tree.iter() {
+                let (key, raw) = entry?;
+                let (author, _sequence) = serde_cbor::from_slice::<(String, u64)>(&raw)?;
+                if author_ids.contains(&author) {
+                    tree.remove(key)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Remove the given public keys from every set stored in the given tree.
+    fn remove_set_members(tree: &Tree, ssb_ids: &HashSet<String>) -> Result<()> {
+        for entry in tree.iter() {
+            let (key, raw) = entry?;
+            let mut members = serde_cbor::from_slice::<HashSet<String>>(&raw)?;
+            let len = members.len();
+            members.retain(|member| !ssb_ids.contains(member));
+            if members.len() != len {
+                tree.insert(key, serde_cbor::to_vec(&members)?)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Remove all values assigned by the given authors from the given index
+    /// (names, images or descriptions). Returns the public keys to which the
+    /// removed values were assigned.
+    fn remove_assigned_values(tree: &Tree, author_ids: &HashSet<String>) -> Result<Vec<String>> {
+        let mut about_ids = Vec::new();
+        for entry in tree.iter() {
+            let (key, raw) = entry?;
+            let mut values = serde_cbor::from_slice::<Vec<(String, String)>>(&raw)?;
+            let len = values.len();
+            values.retain(|(author, _value)| !author_ids.contains(author));
+            if values.len() != len {
+                tree.insert(&key, serde_cbor::to_vec(&values)?)?;
+                about_ids.push(String::from_utf8_lossy(&key).into_owned());
+            }
+        }
+
+        Ok(about_ids)
+    }
+
+    /// Apply the values assigned to the given public key in the given index
+    /// (names, images or descriptions) to the corresponding field of the
+    /// about index.
+    fn replay_about_values<F>(&self, about_id: &str, tree: &Tree, field: F) -> Result<()>
+    where
+        F: Fn(&mut About) -> &mut AboutField,
+    {
+        if let Some(raw) = tree.get(about_id)? {
+            for (author_id, value) in serde_cbor::from_slice::<Vec<(String, String)>>(&raw)? {
+                self.update_about(about_id, |about| {
+                    field(about).update(about_id, &author_id, value)
+                })?;
+            }
+        }
+
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -553,7 +1536,7 @@ mod test {
     use sled::Config;
 
     use crate::secret_config::SecretConfig;
-    use crate::storage::kv::KvStorage;
+    use crate::storage::{kv::KvStorage, mutes::MutePattern};
 
     fn open_temporary_kv() -> Result<KvStorage> {
         let mut kv = KvStorage::default();
@@ -648,6 +1631,60 @@ mod test {
         Ok(())
     }
 
+    #[async_std::test]
+    async fn test_about_precedence() -> Result<()> {
+        let (keypair, kv) = initialise_keypair_and_kv()?;
+        let other_keypair = SecretConfig::create().to_owned_identity()?;
+
+        if let Some(indexes) = kv.indexes.as_ref() {
+            let self_name_content = json!({
+                "type": "about",
+                "about": keypair.id,
+                "name": "mycognosist",
+            });
+            let self_name_msg = MessageValue::sign(None, &keypair, self_name_content)?;
+            indexes.index_msg(&keypair.id, self_name_msg)?;
+
+            // Another peer assigns a name after the self-assigned name.
+            let other_name_content = json!({
+                "type": "about",
+                "about": keypair.id,
+                "name": "fungi fan",
+            });
+            let other_name_msg = MessageValue::sign(None, &other_keypair, other_name_content)?;
+            indexes.index_msg(&other_keypair.id, other_name_msg)?;
+
+            let about = indexes.get_about(&keypair.id)?;
+            assert_eq!(
+                about.name.latest,
+                Some((other_keypair.id.to_owned(), "fungi fan".to_string()))
+            );
+            assert_eq!(about.name.latest_self, Some("mycognosist".to_string()));
+
+            // The self-assigned name takes precedence.
+            assert_eq!(
+                indexes.get_display_name(&keypair.id)?,
+                Some("mycognosist".to_string())
+            );
+
+            // A name assigned to a peer without a self-assigned name is used.
+            let unnamed_content = json!({
+                "type": "about",
+                "about": other_keypair.id,
+                "name": "solar glyph",
+            });
+            let unnamed_msg = MessageValue::sign(None, &keypair, unnamed_content)?;
+            indexes.index_msg(&keypair.id, unnamed_msg)?;
+
+            assert_eq!(
+                indexes.get_display_name(&other_keypair.id)?,
+                Some("solar glyph".to_string())
+            );
+        }
+
+        Ok(())
+    }
+
     #[async_std::test]
     async fn test_channel_indexes() -> Result<()> {
         let (keypair, kv) = initialise_keypair_and_kv()?;
@@ -757,6 +1794,18 @@ mod test {
             let friends = indexes.get_friends(&keypair.id)?;
             assert!(!friends.contains(&blocked_keypair.id));
 
+            // The contact state of the edge reflects the latest message.
+            let contact = indexes.get_contact(&keypair.id, &blocked_keypair.id)?;
+            assert_eq!(
+                contact,
+                Some(ContactState {
+                    following: true,
+                    blocking: false
+                })
+            );
+            assert_eq!(indexes.get_contacts(&keypair.id)?.len(), 1);
+            assert_eq!(indexes.get_contact(&blocked_keypair.id, &keypair.id)?, None);
+
             // Create a contact-type message which defines a follow of the
             // initial keypair by the second keypair.
             let follow_back_msg_content = TypedMessage::Contact {
@@ -782,4 +1831,416 @@ mod test {
 
         Ok(())
     }
+
+    #[async_std::test]
+    async fn test_vote_indexes() -> Result<()> {
+        let (keypair, kv) = initialise_keypair_and_kv()?;
+        let voter_keypair = SecretConfig::create().to_owned_identity()?;
+
+        if let Some(indexes) = kv.indexes.as_ref() {
+            let post_content = json!({ "type": "post", "text": "mushrooms!" });
+            let post_msg = MessageValue::sign(None, &keypair, post_content)?;
+            let post_ref = post_msg.id().to_string();
+            indexes.index_msg(&keypair.id, post_msg)?;
+
+            let like_content = json!({
+                "type": "vote",
+                "vote": { "link": post_ref, "value": 1, "expression": "Like" },
+            });
+            let like_msg = MessageValue::sign(None, &voter_keypair, like_content)?;
+            indexes.index_msg(&voter_keypair.id, like_msg.clone())?;
+
+            let votes = indexes.get_votes(&post_ref)?;
+            assert_eq!(votes.len(), 1);
+            assert_eq!(votes[0].voter, voter_keypair.id);
+            assert_eq!(votes[0].expression, Some("Like".to_string()));
+            assert_eq!(
+                indexes.get_likes(&post_ref)?,
+                vec![voter_keypair.id.to_owned()]
+            );
+
+            // Retracting the like replaces the previous vote.
+            let unlike_content = json!({
+                "type": "vote",
+                "vote": { "link": post_ref, "value": 0, "expression": "Unlike" },
+            });
+            let unlike_msg = MessageValue::sign(Some(&like_msg), &voter_keypair, unlike_content)?;
+            indexes.index_msg(&voter_keypair.id, unlike_msg)?;
+
+            assert_eq!(indexes.get_votes(&post_ref)?.len(), 1);
+            assert!(indexes.get_likes(&post_ref)?.is_empty());
+        }
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_pub_index() -> Result<()> {
+        let (keypair, kv) = initialise_keypair_and_kv()?;
+        let pub_keypair = SecretConfig::create().to_owned_identity()?;
+
+        if let Some(indexes) = kv.indexes.as_ref() {
+            let pub_content = json!({
+                "type": "pub",
+                "address": { "host": "pub.example.com", "port": 8008, "key": pub_keypair.id },
+            });
+            let pub_msg = MessageValue::sign(None, &keypair, pub_content)?;
+            indexes.index_msg(&keypair.id, pub_msg)?;
+
+            assert_eq!(
+                indexes.get_pub_addresses()?,
+                vec![(
+                    pub_keypair.id.to_owned(),
+                    "pub.example.com:8008".to_string()
+                )]
+            );
+
+            // Pubs announced with multiserver addresses are indexed at their
+            // first TCP address.
+            let other_keypair = SecretConfig::create().to_owned_identity()?;
+            let other_key = other_keypair
+                .id
+                .trim_start_matches('@')
+                .trim_end_matches(".ed25519");
+            let address = format!(
+                "tunnel:@room.ed25519:{}~shs:{other_key};net:10.0.0.1:8008~shs:{other_key}",
+                other_keypair.id
+            );
+            let pub_content = json!({ "type": "pub", "address": address });
+            let pub_msg = MessageValue::sign(None, &keypair, pub_content)?;
+            indexes.index_msg(&keypair.id, pub_msg)?;
+
+            let addresses = indexes.get_pub_addresses()?;
+            let expected = (other_keypair.id.to_owned(), "10.0.0.1:8008".to_string());
+            assert!(addresses.contains(&expected));
+        }
+
+        Ok(())
+    }
+    #[async_std::test]
+    async fn test_notification_indexes() -> Result<()> {
+        let (keypair, mut kv) = initialise_keypair_and_kv()?;
+        let other_keypair = SecretConfig::create().to_owned_identity()?;
+
+        if let Some(indexes) = kv.indexes.as_mut() {
+            indexes.set_local_id(&keypair.id);
+
+            let post_content = json!({ "type": "post", "text": "hello" });
+            let post_msg = MessageValue::sign(None, &keypair, post_content)?;
+            let post_ref = post_msg.id().to_string();
+            indexes.index_msg(&keypair.id, post_msg)?;
+
+            // A reply to the local post.
+            let reply_content = json!({ "type": "post", "text": "hi!", "root": post_ref });
+            let reply_msg = MessageValue::sign(None, &other_keypair, reply_content)?;
+            indexes.index_msg(&other_keypair.id, reply_msg.clone())?;
+
+            // A mention of the local identity.
+            let mention_content = json!({
+                "type": "post",
+                "text": "have you met [mycognosist]?",
+                "mentions": [{ "link": keypair.id, "name": "mycognosist" }],
+            });
+            let mention_msg =
+                MessageValue::sign(Some(&reply_msg), &other_keypair, mention_content)?;
+            let mention_ref = mention_msg.id().to_string();
+            indexes.index_msg(&other_keypair.id, mention_msg)?;
+
+            // An unrelated message.
+            let other_content = json!({ "type": "post", "text": "nothing to see here" });
+            let other_msg = MessageValue::sign(None, &other_keypair, other_content)?;
+            indexes.index_msg(&other_keypair.id, other_msg)?;
+
+            let notifications = indexes.get_notifications(false)?;
+            assert_eq!(notifications.len(), 2);
+            assert!(notifications
+                .iter()
+                .any(|notification| notification.kind == NotificationKind::Reply));
+            assert!(notifications
+                .iter()
+                .any(|notification| notification.kind == NotificationKind::Mention));
+
+            assert_eq!(
+                indexes.mark_notifications_read(Some(&[mention_ref][..]))?,
+                1
+            );
+            assert_eq!(indexes.get_notifications(true)?.len(), 1);
+
+            assert_eq!(indexes.mark_notifications_read(None)?, 1);
+            assert!(indexes.get_notifications(true)?.is_empty());
+        }
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_timeline_indexes() -> Result<()> {
+        let (keypair, kv) = initialise_keypair_and_kv()?;
+
+        if let Some(indexes) = kv.indexes.as_ref() {
+            let mut last_msg: Option<MessageValue> = None;
+            for i in 1..=5 {
+                let content = json!({ "type": "post", "text": format!("post #{i}") });
+                let msg = MessageValue::sign(last_msg.as_ref(), &keypair, content)?;
+                indexes.index_msg(&keypair.id, msg.clone())?;
+                last_msg = Some(msg);
+
+                // Ensure each message has a distinct timestamp.
+                std::thread::sleep(std::time::Duration::from_millis(2));
+            }
+
+            // Page through the timeline, two entries at a time.
+            let first_page = indexes.get_timeline(TimelineOrder::Claimed, None, 2)?;
+            assert_eq!(first_page.entries.len(), 2);
+            assert_eq!(first_page.entries[0].sequence, 5);
+            assert_eq!(first_page.entries[1].sequence, 4);
+
+            let cursor = first_page.cursor.as_deref();
+            let second_page = indexes.get_timeline(TimelineOrder::Claimed, cursor, 2)?;
+            assert_eq!(second_page.entries[0].sequence, 3);
+            assert_eq!(second_page.entries[1].sequence, 2);
+
+            let cursor = second_page.cursor.as_deref();
+            let last_page = indexes.get_timeline(TimelineOrder::Claimed, cursor, 2)?;
+            assert_eq!(last_page.entries.len(), 1);
+            assert_eq!(last_page.entries[0].sequence, 1);
+            assert_eq!(last_page.cursor, None);
+
+            let received = indexes.get_timeline(TimelineOrder::Received, None, 10)?;
+            assert_eq!(received.entries.len(), 5);
+
+            assert!(indexes
+                .get_timeline(TimelineOrder::Claimed, Some("yesterday"), 2)
+                .is_err());
+        }
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_muted_indexes() -> Result<()> {
+        let (keypair, mut kv) = initialise_keypair_and_kv()?;
+
+        if let Some(indexes) = kv.indexes.as_mut() {
+            indexes.set_mutes(MuteList::new(vec![MutePattern::Word("spam".to_string())])?);
+
+            let post_msg =
+                MessageValue::sign(None, &keypair, json!({ "type": "post", "text": "Spam!" }))?;
+            indexes.index_msg(&keypair.id, post_msg.clone())?;
+
+            let channel_content = TypedMessage::Channel {
+                channel: "spam".to_string(),
+                subscribed: true,
+            };
+            let channel_msg =
+                MessageValue::sign(Some(&post_msg), &keypair, json!(channel_content))?;
+            indexes.index_msg(&keypair.id, channel_msg.clone())?;
+
+            // Muted messages are left out of the timeline and channel
+            // indexes.
+            let timeline = indexes.get_timeline(TimelineOrder::Claimed, None, 10)?;
+            assert!(timeline.entries.is_empty());
+            assert!(indexes.get_channel_subscribers("spam")?.is_empty());
+
+            indexes.set_mutes(MuteList::default());
+            let post_msg = MessageValue::sign(
+                Some(&channel_msg),
+                &keypair,
+                json!({ "type": "post", "text": "Spam again" }),
+            )?;
+            indexes.index_msg(&keypair.id, post_msg)?;
+
+            let timeline = indexes.get_timeline(TimelineOrder::Claimed, None, 10)?;
+            assert_eq!(timeline.entries.len(), 1);
+            assert_eq!(timeline.entries[0].sequence, 3);
+        }
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_reply_indexes() -> Result<()> {
+        let (keypair, kv) = initialise_keypair_and_kv()?;
+
+        if let Some(indexes) = kv.indexes.as_ref() {
+            let root_msg =
+                MessageValue::sign(None, &keypair, json!({ "type": "post", "text": "root" }))?;
+            indexes.index_msg(&keypair.id, root_msg.clone())?;
+            let root = root_msg.id().to_string();
+
+            let mut last_msg = root_msg;
+            for i in 1..=2 {
+                // Ensure each message has a distinct timestamp.
+                std::thread::sleep(std::time::Duration::from_millis(2));
+
+                let content =
+                    json!({ "type": "post", "text": format!("reply #{i}"), "root": root });
+                let msg = MessageValue::sign(Some(&last_msg), &keypair, content)?;
+                indexes.index_msg(&keypair.id, msg.clone())?;
+                last_msg = msg;
+            }
+
+            // Replies are returned from oldest to newest.
+            let replies = indexes.get_replies(&root)?;
+            assert_eq!(
+                replies
+                    .iter()
+                    .map(|reply| reply.sequence)
+                    .collect::<Vec<u64>>(),
+                vec![2, 3]
+            );
+            assert!(indexes.get_replies(&last_msg.id().to_string())?.is_empty());
+
+            // Authors may ask for their messages not to be shown on public
+            // web pages.
+            assert!(!indexes.is_hidden_from_web(&keypair.id)?);
+            let content =
+                json!({ "type": "about", "about": keypair.id, "publicWebHosting": false });
+            let about_msg = MessageValue::sign(Some(&last_msg), &keypair, content)?;
+            indexes.index_msg(&keypair.id, about_msg)?;
+            assert!(indexes.is_hidden_from_web(&keypair.id)?);
+        }
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_channel_post_indexes() -> Result<()> {
+        let (keypair, kv) = initialise_keypair_and_kv()?;
+
+        if let Some(indexes) = kv.indexes.as_ref() {
+            let mut last_msg: Option<MessageValue> = None;
+            for channel in ["solar", "#solar", "solarpunk"] {
+                let content = json!({ "type": "post", "text": "hi", "channel": channel });
+                let msg = MessageValue::sign(last_msg.as_ref(), &keypair, content)?;
+                indexes.index_msg(&keypair.id, msg.clone())?;
+                last_msg = Some(msg);
+
+                // Ensure each message has a distinct timestamp.
+                std::thread::sleep(std::time::Duration::from_millis(2));
+            }
+
+            // Posts are returned from newest to oldest, and channels are
+            // named with or without a leading '#'.
+            let posts = indexes.get_channel_posts("#solar", 10)?;
+            assert_eq!(
+                posts.iter().map(|post| post.sequence).collect::<Vec<u64>>(),
+                vec![2, 1]
+            );
+            assert_eq!(indexes.get_channel_posts("solar", 1)?.len(), 1);
+            assert_eq!(indexes.get_channel_posts("solarpunk", 10)?.len(), 1);
+            assert!(indexes.get_channel_posts("sol", 10)?.is_empty());
+        }
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_remove_author() -> Result<()> {
+        let (keypair, kv) = initialise_keypair_and_kv()?;
+        let pruned_keypair = SecretConfig::create().to_owned_identity()?;
+
+        if let Some(indexes) = kv.indexes.as_ref() {
+            let follow_content = json!({
+                "type": "contact",
+                "contact": keypair.id,
+                "following": true,
+            });
+            let follow_msg = MessageValue::sign(None, &pruned_keypair, follow_content)?;
+            indexes.index_msg(&pruned_keypair.id, follow_msg.clone())?;
+
+            let name_content = json!({
+                "type": "about",
+                "about": keypair.id,
+                "name": "sunbather",
+            });
+            let name_msg = MessageValue::sign(Some(&follow_msg), &pruned_keypair, name_content)?;
+            indexes.index_msg(&pruned_keypair.id, name_msg)?;
+
+            let self_name_content = json!({
+                "type": "about",
+                "about": keypair.id,
+                "name": "mycognosist",
+            });
+            let self_name_msg = MessageValue::sign(None, &keypair, self_name_content)?;
+            indexes.index_msg(&keypair.id, self_name_msg)?;
+
+            assert!(indexes
+                .get_followers(&keypair.id)?
+                .contains(&pruned_keypair.id));
+            assert_eq!(
+                indexes.get_latest_name(&keypair.id)?,
+                Some((keypair.id.to_owned(), "mycognosist".to_string()))
+            );
+
+            indexes.remove_author(&pruned_keypair.id)?;
+
+            assert!(indexes.get_follows(&pruned_keypair.id)?.is_empty());
+            assert!(indexes.get_contacts(&pruned_keypair.id)?.is_empty());
+            assert!(!indexes
+                .get_followers(&keypair.id)?
+                .contains(&pruned_keypair.id));
+            assert_eq!(indexes.get_names(&keypair.id)?.len(), 1);
+            assert_eq!(
+                indexes.get_about(&keypair.id)?.name.latest,
+                Some((keypair.id.to_owned(), "mycognosist".to_string()))
+            );
+            let timeline = indexes.get_timeline(TimelineOrder::Claimed, None, 10)?;
+            assert_eq!(timeline.entries.len(), 1);
+        }
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_hops() -> Result<()> {
+        let (keypair, kv) = initialise_keypair_and_kv()?;
+        let friend = SecretConfig::create().to_owned_identity()?;
+        let friend_of_friend = SecretConfig::create().to_owned_identity()?;
+
+        if let Some(indexes) = kv.indexes.as_ref() {
+            for (author, contact) in [(&keypair, &friend), (&friend, &friend_of_friend)] {
+                let content = json!({
+                    "type": "contact",
+                    "contact": contact.id,
+                    "following": true,
+                });
+                let msg = MessageValue::sign(None, author, content)?;
+                indexes.index_msg(&author.id, msg)?;
+            }
+
+            let hops = indexes.get_hops(&keypair.id, 1)?;
+            assert_eq!(hops.len(), 2);
+            assert_eq!(hops.get(&friend.id), Some(&1));
+
+            let hops = indexes.get_hops(&keypair.id, 2)?;
+            assert_eq!(hops.get(&friend_of_friend.id), Some(&2));
+        }
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_blob_refs() -> Result<()> {
+        let (keypair, kv) = initialise_keypair_and_kv()?;
+        let blob_id = "&S7+CwHM6dZ9si5Vn4ftpk/l/ldbRMqzzJos+spZbWf4=.sha256";
+
+        if let Some(indexes) = kv.indexes.as_ref() {
+            let content = json!({
+                "type": "post",
+                "text": format!("![solar]({})", blob_id),
+            });
+            let msg = MessageValue::sign(None, &keypair, content)?;
+            indexes.index_msg(&keypair.id, msg)?;
+
+            assert!(indexes.get_blob_authors(blob_id)?.contains(&keypair.id));
+            assert!(indexes.get_author_blobs(&keypair.id)?.contains(blob_id));
+
+            indexes.remove_author(&keypair.id)?;
+            assert!(indexes.get_blob_authors(blob_id)?.is_empty());
+            assert!(indexes.get_author_blobs(&keypair.id)?.is_empty());
+        }
+
+        Ok(())
+    }
 }
@@ -1,36 +1,214 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
 use futures::SinkExt;
 use kuska_ssb::feed::{Feed as MessageKvt, Message as MessageValue};
 use log::{debug, warn};
 use serde::{Deserialize, Serialize};
-use sled::{Config as DbConfig, Db};
+use serde_json::{value::RawValue, Value};
+use sha2::{Digest, Sha256};
+use sled::{Config as DbConfig, Db, Tree};
+use solar_core::{
+    bendybutt::{self, BendyButtMessage},
+    buttwoo::{self, ButtwooMessage},
+};
 
 use crate::{
+    actors::replication::{
+        config::ExcessAction,
+        ebt::{Refusal, VectorClock},
+    },
     broker::{BrokerEvent, BrokerMessage, ChBrokerSend, Destination},
     error::Error,
-    storage::indexes::Indexes,
+    ssb_uri,
+    storage::{
+        indexes::Indexes,
+        mutes::{MuteList, MutePattern},
+        records::LocalRecords,
+        repair::{LostFeed, RepairReport},
+        trees::{self, TreeSpec, Trees},
+        validation,
+    },
+    util::now_millis,
     Result,
 };
 
-// TODO: Consider replacing prefix-based approach with separate db trees.
-/// Prefix for a key to the latest sequence number for a stored feed.
-const PREFIX_LATEST_SEQ: u8 = 0u8;
-/// Prefix for a key to a message KVT (Key Value Timestamp).
-const PREFIX_MSG_KVT: u8 = 1u8;
-/// Prefix for a key to a message value (the 'V' in KVT).
-const PREFIX_MSG_VAL: u8 = 2u8;
-/// Prefix for a key to a blob.
-const PREFIX_BLOB: u8 = 3u8;
-/// Prefix for a key to a peer.
-const PREFIX_PEER: u8 = 4u8;
+/// Key of the detected identity conflict, the only record of its tree.
+const IDENTITY_CONFLICT_KEY: &[u8] = &[];
+
+/// Prefix of the keys (within the scope of a client) of the messages read
+/// by the client.
+const CLIENT_READ_PREFIX: &str = "read:";
+
+/// Length of the scope of a client (the hashed API token) within the keys
+/// of its values.
+const CLIENT_SCOPE_LEN: usize = 32;
+
+/// Maximum number of entries retained in the replication log of each peer.
+/// The oldest entries are discarded once the limit is reached.
+const REPLICATION_LOG_CAPACITY: usize = 256;
+
+/// Maximum number of entries retained in the message trace of each traced
+/// feed. The oldest entries are discarded once the limit is reached.
+const MESSAGE_TRACE_CAPACITY: usize = 1024;
 
 /// A new message has been appended to feed belonging to the given SSB ID.
 #[derive(Debug, Clone)]
 pub struct StoreKvEvent(pub (String, u64));
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// A stored message KVT whose value is left serialized.
+#[derive(Deserialize)]
+struct RawMessageKvt<'a> {
+    #[serde(borrow)]
+    value: &'a RawValue,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct BlobStatus {
     retrieved: bool,
     users: Vec<String>,
+    /// Number of bytes of a partially downloaded blob which have been
+    /// received and written to the blob store.
+    #[serde(default)]
+    offset: u64,
+}
+
+/// A blob wanted by the local node, along with the state of its retries.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BlobWant {
+    /// Number of times the blob has been requested from the connected peers.
+    pub attempts: u32,
+    /// Time of the next request, in milliseconds since the UNIX epoch.
+    pub next_attempt: u64,
+}
+
+/// An observation of whether a peer supports a capability.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CapabilityObservation {
+    pub supported: bool,
+    /// Time of the observation, in milliseconds since the UNIX epoch.
+    pub observed_at: u64,
+}
+
+/// Capabilities of a peer, as advertised or observed in earlier sessions.
+/// Capabilities which have not been observed are `None`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PeerCapabilities {
+    /// EBT replication (version 3).
+    #[serde(default)]
+    pub ebt: Option<CapabilityObservation>,
+    /// Room server.
+    #[serde(default)]
+    pub room: Option<CapabilityObservation>,
+    /// Tunneled connections through the room.
+    #[serde(default)]
+    pub tunnel: Option<CapabilityObservation>,
+    /// Blob slices (`blobs.getSlice`).
+    #[serde(default)]
+    pub blob_slices: Option<CapabilityObservation>,
+    /// Feed formats replicated with the peer.
+    #[serde(default)]
+    pub feed_formats: Vec<String>,
+}
+
+/// Network statistics gathered over a day.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NetworkStats {
+    /// Number of feeds of which a first message was replicated.
+    pub new_feeds: u64,
+    /// Number of messages replicated from peers.
+    pub messages_replicated: u64,
+    /// SSB IDs of the peers with which a connection was established.
+    pub peers: BTreeSet<String>,
+    /// Number of bytes received from peers.
+    pub bytes_received: u64,
+    /// Number of bytes sent to peers.
+    pub bytes_sent: u64,
+}
+
+impl NetworkStats {
+    /// Add the given statistics to these statistics.
+    pub fn merge(&mut self, other: NetworkStats) {
+        self.new_feeds += other.new_feeds;
+        self.messages_replicated += other.messages_replicated;
+        self.peers.extend(other.peers);
+        self.bytes_received += other.bytes_received;
+        self.bytes_sent += other.bytes_sent;
+    }
+
+    /// Query whether no activity was recorded.
+    pub fn is_empty(&self) -> bool {
+        *self == NetworkStats::default()
+    }
+}
+
+/// Behaviour of a peer observed in replication sessions, from which its
+/// score is computed.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerReputation {
+    /// Number of new, valid messages received from the peer.
+    pub messages_received: u64,
+    /// Number of invalid messages received from the peer.
+    pub invalid_messages: u64,
+    /// Number of replication sessions held with the peer.
+    pub sessions: u64,
+    /// Number of replication errors with the peer.
+    pub errors: u64,
+    /// Time spent connected to the peer, in seconds.
+    pub uptime_secs: u64,
+}
+
+impl PeerReputation {
+    /// Add the given behaviour to this behaviour.
+    pub fn merge(&mut self, other: PeerReputation) {
+        self.messages_received += other.messages_received;
+        self.invalid_messages += other.invalid_messages;
+        self.sessions += other.sessions;
+        self.errors += other.errors;
+        self.uptime_secs += other.uptime_secs;
+    }
+}
+
+/// Storage used by a feed replicated in mirror mode, accounted from the
+/// time at which the feed was first mirrored.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MirroredFeed {
+    /// Time at which the feed was first mirrored, in milliseconds since the
+    /// UNIX epoch.
+    pub added: u64,
+    /// Number of messages of the feed stored since.
+    pub messages: u64,
+    /// Size of the messages of the feed stored since, in bytes.
+    pub bytes: u64,
+}
+
+/// Evidence of the keypair of the local identity being in use on another
+/// device: a peer presented a message of the local feed with a sequence
+/// number higher than that of the latest stored message.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IdentityConflict {
+    /// Public key of the peer which presented the message.
+    pub peer: String,
+    /// Sequence number of the presented message.
+    pub sequence: u64,
+    /// Sequence number of the latest stored message of the local feed.
+    pub local_sequence: u64,
+    /// Time of detection, in milliseconds since the UNIX epoch.
+    pub detected_at: u64,
+}
+
+/// A message waiting in the outbox to be published on the local feed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OutboxEntry {
+    /// Content of the message.
+    pub content: Value,
+    /// Time at which the message is due to be published, in milliseconds
+    /// since the UNIX epoch. Drafts (`None`) are never published
+    /// automatically.
+    pub publish_at: Option<u64>,
+    /// Error with which the latest attempt to publish the message failed,
+    /// if any.
+    #[serde(default)]
+    pub error: Option<String>,
 }
 
 /// The public key (ID) of a peer and a message sequence number.
@@ -40,6 +218,65 @@ pub struct PubKeyAndSeqNum {
     seq_num: u64,
 }
 
+/// An event relevant to replication with a peer.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ReplicationEvent {
+    /// A replication session was started using the given protocol
+    /// (`ebt` or `classic`).
+    SessionStarted { protocol: String },
+    /// A replication session using the given protocol came to an end.
+    SessionEnded { protocol: String },
+    /// A vector clock was sent to the peer.
+    ClockSent { clock: VectorClock },
+    /// A vector clock was received from the peer.
+    ClockReceived { clock: VectorClock },
+    /// Replication with the peer failed.
+    Error { message: String },
+    /// The peer presented a message of the local feed with the given
+    /// sequence number, which is not stored locally (see
+    /// `IdentityConflict`).
+    IdentityConflict { sequence: u64 },
+    /// The peer requested the given feed, whose messages are not forwarded
+    /// to it for the given reason.
+    ForwardingRefused { feed: String, reason: Refusal },
+    /// The messages of the given feed received from the peer exceed the
+    /// ingest rate limit, and the excess messages are dropped or queued.
+    IngestLimited { feed: String, action: ExcessAction },
+}
+
+/// A replication event and the time at which it was recorded.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReplicationLogEntry {
+    /// Milliseconds since the UNIX epoch.
+    pub timestamp: u64,
+    pub event: ReplicationEvent,
+}
+
+/// Direction in which a traced message travelled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TraceDirection {
+    /// The message was received from the peer.
+    Received,
+    /// The message was sent to the peer.
+    Sent,
+}
+
+/// A message of a traced feed received from or sent to a peer.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MessageTraceEntry {
+    /// Milliseconds since the UNIX epoch.
+    pub timestamp: u64,
+    /// Sequence number of the message.
+    pub sequence: u64,
+    /// SSB ID of the peer.
+    pub peer: String,
+    pub direction: TraceDirection,
+    /// Replication protocol (`ebt` or `classic`).
+    pub protocol: String,
+}
+
 // TODO: Can we remove the `Option` from all of these fields?
 // Will make the rest of the code more compact (no need to match on an
 // `Option` every time).
@@ -48,74 +285,130 @@ pub struct PubKeyAndSeqNum {
 pub struct KvStorage {
     /// The core database which stores messages and blob references.
     db: Option<Db>,
+    /// The trees of the core database, one per kind of record.
+    trees: Option<Trees>,
     /// Indexes to allow for efficient database value look-ups.
     pub indexes: Option<Indexes>,
+    /// Non-replicated records of local apps.
+    pub records: Option<LocalRecords>,
     /// A message-passing sender.
     ch_broker: Option<ChBrokerSend>,
 }
 
 impl KvStorage {
     /// Open the key-value database using the given configuration, open the
-    /// database index trees and populate the instance of `KvStorage`
-    /// with the database, indexes and message-passing sender.
+    /// record, database index and local records trees and populate the
+    /// instance of `KvStorage` with the database, trees, indexes, local
+    /// records and message-passing sender.
     pub fn open(&mut self, config: DbConfig, ch_broker: ChBrokerSend) -> Result<()> {
         let db = config.open()?;
-        let indexes = Indexes::open(&db)?;
+        let trees = Trees::open(&db)?;
+        let mut indexes = Indexes::open(&db)?;
+        let records = LocalRecords::open(&db)?;
+
+        // Patterns which no longer compile (for example, after an upgrade
+        // of the regex engine) are ignored rather than preventing the
+        // database from being opened.
+        let patterns = Self::read_mute_patterns(&trees.mute_patterns)?;
+        match MuteList::new(patterns) {
+            Ok(mutes) => indexes.set_mutes(mutes),
+            Err(err) => warn!("Ignoring mute patterns: {}", err),
+        }
+
+        // Databases created before the replies and channel posts indexes
+        // were introduced have their stored posts indexed.
+        if (indexes.replies_is_empty() || indexes.channel_posts_is_empty())
+            && !trees.messages.is_empty()
+        {
+            Self::index_stored_posts(&trees.messages, &indexes)?;
+        }
 
         self.db = Some(db);
+        self.trees = Some(trees);
         self.indexes = Some(indexes);
+        self.records = Some(records);
         self.ch_broker = Some(ch_broker);
 
         Ok(())
     }
 
-    /// Generate a key for the latest sequence number of the feed authored by
-    /// the given public key.
-    fn key_latest_seq(user_id: &str) -> Vec<u8> {
-        let mut key = Vec::new();
-        key.push(PREFIX_LATEST_SEQ);
-        key.extend_from_slice(user_id.as_bytes());
-        key
+    /// Index the replies to threads and the posts of channels among the
+    /// messages stored in the given tree. Muted posts are left out, as when
+    /// they are received.
+    fn index_stored_posts(messages: &Tree, indexes: &Indexes) -> Result<()> {
+        debug!("Building replies and channel posts indexes");
+
+        for item in messages.iter() {
+            let (_key, raw) = item?;
+            let msg_kvt = MessageKvt::from_slice(&raw)?;
+            let received = msg_kvt.rts.unwrap_or(msg_kvt.timestamp) as u64;
+            let msg_val = msg_kvt.into_message()?;
+            let muted = msg_val
+                .value
+                .get("content")
+                .map_or(false, |content| indexes.is_muted(content));
+            if !muted {
+                let author_id = msg_val.author().to_string();
+                indexes.index_reply(&author_id, &msg_val, received)?;
+                indexes.index_channel_post(&author_id, &msg_val, received)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Flush the pending writes of the database to disk. Does nothing if the
+    /// database is not open.
+    pub async fn flush(&self) -> Result<()> {
+        if let Some(db) = &self.db {
+            db.flush_async().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Close the database. A temporary database is deleted once closed.
+    pub fn close(&mut self) {
+        self.db = None;
+        self.trees = None;
+        self.indexes = None;
+        self.records = None;
+    }
+
+    /// Query whether the database has been opened.
+    pub fn is_open(&self) -> bool {
+        self.db.is_some()
+    }
+
+    /// Return the size of the database (including indexes) on disk, in bytes.
+    pub fn size_on_disk(&self) -> Result<u64> {
+        let db = self.db.as_ref().ok_or(Error::OptionIsNone)?;
+
+        Ok(db.size_on_disk()?)
     }
 
     /// Generate a key for a message KVT authored by the given public key and
     /// with the given message sequence number.
     fn key_msg_kvt(user_id: &str, msg_seq: u64) -> Vec<u8> {
         let mut key = Vec::new();
-        key.push(PREFIX_MSG_KVT);
         key.extend_from_slice(&msg_seq.to_be_bytes()[..]);
         key.extend_from_slice(user_id.as_bytes());
         key
     }
 
-    /// Generate a key for a message value with the given ID (reference).
-    fn key_msg_val(msg_id: &str) -> Vec<u8> {
-        let mut key = Vec::new();
-        key.push(PREFIX_MSG_VAL);
-        key.extend_from_slice(msg_id.as_bytes());
-        key
-    }
-
-    /// Generate a key for a blob with the given ID (reference).
-    fn key_blob(blob_id: &str) -> Vec<u8> {
-        let mut key = Vec::new();
-        key.push(PREFIX_BLOB);
-        key.extend_from_slice(blob_id.as_bytes());
-        key
-    }
-
-    /// Generate a key for a peer with the given public key.
-    fn key_peer(user_id: &str) -> Vec<u8> {
-        let mut key = Vec::new();
-        key.push(PREFIX_PEER);
-        key.extend_from_slice(user_id.as_bytes());
+    /// Generate a key for the replication event of the peer with the given
+    /// public key and with the given ID, so that the events of a peer are
+    /// stored contiguously and in order.
+    fn key_replication_log(user_id: &str, id: u64) -> Vec<u8> {
+        let mut key = user_id.as_bytes().to_vec();
+        key.extend_from_slice(&id.to_be_bytes()[..]);
         key
     }
 
     /// Get the status of a blob with the given ID.
     pub fn get_blob(&self, blob_id: &str) -> Result<Option<BlobStatus>> {
-        let db = self.db.as_ref().ok_or(Error::OptionIsNone)?;
-        if let Some(raw) = db.get(Self::key_blob(blob_id))? {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+        if let Some(raw) = trees.blobs.get(blob_id)? {
             Ok(serde_cbor::from_slice(&raw)?)
         } else {
             Ok(None)
@@ -124,24 +417,48 @@ impl KvStorage {
 
     /// Set the status of a blob with the given ID.
     pub fn set_blob(&self, blob_id: &str, blob: &BlobStatus) -> Result<()> {
-        let db = self.db.as_ref().ok_or(Error::OptionIsNone)?;
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
         let raw = serde_cbor::to_vec(blob)?;
-        db.insert(Self::key_blob(blob_id), raw)?;
+        trees.blobs.insert(blob_id, raw)?;
 
         Ok(())
     }
 
+    /// Get the offset up to which the given blob has been downloaded, if the
+    /// download is incomplete.
+    pub fn get_blob_offset(&self, blob_id: &str) -> Result<u64> {
+        Ok(self
+            .get_blob(blob_id)?
+            .map(|blob| blob.offset)
+            .unwrap_or_default())
+    }
+
+    /// Set the offset up to which the given blob has been downloaded.
+    pub fn set_blob_offset(&self, blob_id: &str, offset: u64) -> Result<()> {
+        let mut blob = self.get_blob(blob_id)?.unwrap_or_default();
+        blob.offset = offset;
+        self.set_blob(blob_id, &blob)
+    }
+
+    /// Mark the given blob as retrieved, discarding the progress of its
+    /// download.
+    pub fn set_blob_retrieved(&self, blob_id: &str) -> Result<()> {
+        let mut blob = self.get_blob(blob_id)?.unwrap_or_default();
+        blob.retrieved = true;
+        blob.offset = 0;
+        self.set_blob(blob_id, &blob)
+    }
+
     /// Get a list of IDs for all blobs which have not yet been retrieved.
     pub fn get_pending_blobs(&self) -> Result<Vec<String>> {
         let mut list = Vec::new();
 
-        let db = self.db.as_ref().ok_or(Error::OptionIsNone)?;
-        let scan_key: &[u8] = &[PREFIX_BLOB];
-        for item in db.range(scan_key..) {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+        for item in trees.blobs.iter() {
             let (k, v) = item?;
             let blob: BlobStatus = serde_cbor::from_slice(&v)?;
             if !blob.retrieved {
-                list.push(String::from_utf8_lossy(&k[1..]).to_string());
+                list.push(String::from_utf8_lossy(&k).to_string());
             }
         }
 
@@ -151,9 +468,8 @@ impl KvStorage {
     /// Get the sequence number of the latest message in the feed authored by
     /// the peer with the given public key.
     pub fn get_latest_seq(&self, user_id: &str) -> Result<Option<u64>> {
-        let db = self.db.as_ref().ok_or(Error::OptionIsNone)?;
-        let key = Self::key_latest_seq(user_id);
-        let seq = if let Some(value) = db.get(key)? {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+        let seq = if let Some(value) = trees.latest_seq.get(user_id)? {
             let mut u64_buffer = [0u8; 8];
             u64_buffer.copy_from_slice(&value);
             Some(u64::from_be_bytes(u64_buffer))
@@ -167,19 +483,44 @@ impl KvStorage {
     /// Get the message KVT (Key Value Timestamp) for the given author and
     /// message sequence number.
     pub fn get_msg_kvt(&self, user_id: &str, msg_seq: u64) -> Result<Option<MessageKvt>> {
-        let db = self.db.as_ref().ok_or(Error::OptionIsNone)?;
-        if let Some(raw) = db.get(Self::key_msg_kvt(user_id, msg_seq))? {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+        if let Some(raw) = trees.messages.get(Self::key_msg_kvt(user_id, msg_seq))? {
             Ok(Some(MessageKvt::from_slice(&raw)?))
         } else {
             Ok(None)
         }
     }
 
-    /// Get the message value for the given message ID (key).
+    /// Get the value of the message KVT for the given public key and sequence
+    /// number, serialized as JSON. The value is sliced from the stored KVT
+    /// rather than deserialized and serialized again.
+    pub fn get_msg_json(&self, user_id: &str, msg_seq: u64) -> Result<Option<String>> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+        if let Some(raw) = trees.messages.get(Self::key_msg_kvt(user_id, msg_seq))? {
+            let msg_kvt: RawMessageKvt = serde_json::from_slice(&raw)?;
+            Ok(Some(msg_kvt.value.get().to_owned()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Check whether the message with the given author and sequence number
+    /// is stored, without reading or deserializing it.
+    pub fn contains_msg(&self, user_id: &str, msg_seq: u64) -> Result<bool> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+
+        Ok(trees
+            .messages
+            .contains_key(Self::key_msg_kvt(user_id, msg_seq))?)
+    }
+
+    /// Get the message value for the given message ID (key). The ID may be
+    /// given as a sigil link or an SSB URI.
     pub fn get_msg_val(&self, msg_id: &str) -> Result<Option<MessageValue>> {
-        let db = self.db.as_ref().ok_or(Error::OptionIsNone)?;
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+        let msg_id = ssb_uri::to_sigil(msg_id)?;
 
-        if let Some(raw) = db.get(Self::key_msg_val(msg_id))? {
+        if let Some(raw) = trees.msg_refs.get(msg_id)? {
             let msg_ref = serde_cbor::from_slice::<PubKeyAndSeqNum>(&raw)?;
             let msg = self
                 .get_msg_kvt(&msg_ref.pub_key, msg_ref.seq_num)?
@@ -191,6 +532,34 @@ impl KvStorage {
         }
     }
 
+    /// Cache the given message, fetched out of order (by ID) rather than
+    /// replicated, along with the time (in milliseconds) at which it was
+    /// received. The message is kept apart from the replicated feeds.
+    pub fn set_ooo_msg(&self, msg_val: &MessageValue, received: u64) -> Result<()> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+
+        let mut msg_kvt = MessageKvt::new(msg_val.clone());
+        msg_kvt.rts = Some(received as f64);
+        trees
+            .ooo_messages
+            .insert(msg_val.id().to_string(), msg_kvt.to_string().as_bytes())?;
+
+        Ok(())
+    }
+
+    /// Get the cached message KVT fetched out of order with the given ID
+    /// (key). The ID may be given as a sigil link or an SSB URI.
+    pub fn get_ooo_msg_kvt(&self, msg_id: &str) -> Result<Option<MessageKvt>> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+        let msg_id = ssb_uri::to_sigil(msg_id)?;
+
+        if let Some(raw) = trees.ooo_messages.get(msg_id)? {
+            Ok(Some(MessageKvt::from_slice(&raw)?))
+        } else {
+            Ok(None)
+        }
+    }
+
     /// Get the latest message value authored by the given public key.
     pub fn get_latest_msg_val(&self, user_id: &str) -> Result<Option<MessageValue>> {
         let latest_msg = if let Some(last_id) = self.get_latest_seq(user_id)? {
@@ -209,8 +578,8 @@ impl KvStorage {
     /// Add the public key and latest sequence number of a peer to the list of
     /// peers.
     pub async fn set_peer(&self, user_id: &str, latest_seq: u64) -> Result<()> {
-        let db = self.db.as_ref().ok_or(Error::OptionIsNone)?;
-        db.insert(Self::key_peer(user_id), &latest_seq.to_be_bytes()[..])?;
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+        trees.peers.insert(user_id, &latest_seq.to_be_bytes()[..])?;
 
         // TODO: Should we be flushing here?
         // Flush may have a performance impact. It may also be unnecessary
@@ -222,16 +591,12 @@ impl KvStorage {
     /// Return the public key and latest sequence number for all peers in the
     /// database.
     pub async fn get_peers(&self) -> Result<Vec<(String, u64)>> {
-        let db = self.db.as_ref().ok_or(Error::OptionIsNone)?;
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
         let mut peers = Vec::new();
 
-        // Use the generic peer prefix to return an iterator over all peers.
-        let scan_peer_key: &[u8] = &[PREFIX_PEER];
-        for peer in db.range(scan_peer_key..) {
+        for peer in trees.peers.iter() {
             let (peer_key, _) = peer?;
-            // Drop the prefix byte and convert the remaining bytes to
-            // a string.
-            let pub_key = String::from_utf8_lossy(&peer_key[1..]).to_string();
+            let pub_key = String::from_utf8_lossy(&peer_key).to_string();
             // Get the latest sequence number for the peer.
             // Fallback to a value of 0 if a `None` value is returned.
             let seq_num = self.get_latest_seq(&pub_key)?.unwrap_or(0);
@@ -241,180 +606,1547 @@ impl KvStorage {
         Ok(peers)
     }
 
-    /// Append a message value to a feed.
-    pub async fn append_feed(&self, msg_val: MessageValue) -> Result<u64> {
-        debug!("Appending message to feed in database");
-        let seq_num = self.get_latest_seq(msg_val.author())?.map_or(0, |num| num) + 1;
-
-        if msg_val.sequence() != seq_num {
-            return Err(Error::InvalidSequence);
-        }
-
-        let author = msg_val.author().to_owned();
+    /// Record a replication event in the log of the peer with the given
+    /// public key.
+    ///
+    /// Each event is stored under a key of its own (the public key followed
+    /// by an ID increasing with each event), so that recording an event does
+    /// not rewrite the log and the oldest events are removed by key range.
+    pub fn append_replication_log(&self, user_id: &str, event: ReplicationEvent) -> Result<()> {
         let db = self.db.as_ref().ok_or(Error::OptionIsNone)?;
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
 
-        let msg_ref = serde_cbor::to_vec(&PubKeyAndSeqNum {
-            pub_key: author.clone(),
-            seq_num,
-        })?;
-        db.insert(Self::key_msg_val(&msg_val.id().to_string()), msg_ref)?;
+        let timestamp = now_millis();
 
-        let mut msg_kvt = MessageKvt::new(msg_val.clone());
-        msg_kvt.rts = None;
-        db.insert(
-            Self::key_msg_kvt(&author, seq_num),
-            msg_kvt.to_string().as_bytes(),
+        let entry = ReplicationLogEntry { timestamp, event };
+        trees.replication_logs.insert(
+            Self::key_replication_log(user_id, db.generate_id()?),
+            serde_cbor::to_vec(&entry)?,
         )?;
-        db.insert(Self::key_latest_seq(&author), &seq_num.to_be_bytes()[..])?;
 
-        // Add the public key and latest sequence number for this peer to the
-        // list of peers.
-        self.set_peer(&author, seq_num).await?;
+        // Discard the oldest entries to keep the log bounded.
+        let len = trees.replication_logs.scan_prefix(user_id).count();
+        if len > REPLICATION_LOG_CAPACITY {
+            for key in trees
+                .replication_logs
+                .scan_prefix(user_id)
+                .keys()
+                .take(len - REPLICATION_LOG_CAPACITY)
+            {
+                trees.replication_logs.remove(key?)?;
+            }
+        }
 
-        debug!("Passing message to indexer");
-        // Pass the author and message value to the indexer.
-        if let Some(indexes) = &self.indexes {
-            indexes.index_msg(&author, msg_val)?
+        Ok(())
+    }
+
+    /// Get the replication log of the peer with the given public key, ordered
+    /// from oldest to newest entry.
+    pub fn get_replication_log(&self, user_id: &str) -> Result<Vec<ReplicationLogEntry>> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+
+        let mut log = Vec::new();
+        for item in trees.replication_logs.scan_prefix(user_id) {
+            let (_, value) = item?;
+            log.push(serde_cbor::from_slice(&value)?);
         }
 
-        db.flush_async().await?;
+        Ok(log)
+    }
 
-        // Publish a notification that the feed belonging to the given public
-        // key has been updated.
-        let broker_msg = BrokerEvent::new(
-            Destination::Broadcast,
-            BrokerMessage::StoreKv(StoreKvEvent((author, seq_num))),
-        );
+    /// Record a message of the feed with the given public key in the trace
+    /// of the feed.
+    pub fn append_message_trace(
+        &self,
+        feed_id: &str,
+        sequence: u64,
+        peer: &str,
+        direction: TraceDirection,
+        protocol: &str,
+    ) -> Result<()> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+
+        let timestamp = now_millis();
+
+        let mut trace = self.get_message_trace(feed_id)?;
+        trace.push(MessageTraceEntry {
+            timestamp,
+            sequence,
+            peer: peer.to_owned(),
+            direction,
+            protocol: protocol.to_owned(),
+        });
+
+        // Discard the oldest entries to keep the trace bounded.
+        if trace.len() > MESSAGE_TRACE_CAPACITY {
+            trace.drain(..trace.len() - MESSAGE_TRACE_CAPACITY);
+        }
 
-        // Matching on the error here (instead of unwrapping) allows us to
-        // write unit tests for `append_feed`; a case where we do not have
-        // a broker deployed to receive the event message.
-        if let Err(err) = self
-            .ch_broker
-            .as_ref()
-            .ok_or(Error::OptionIsNone)?
-            .send(broker_msg)
-            .await
-        {
-            warn!(
-                "Failed to notify broker of message appended to kv store: {}",
-                err
-            )
-        };
+        trees
+            .message_traces
+            .insert(feed_id, serde_cbor::to_vec(&trace)?)?;
 
-        Ok(seq_num)
+        Ok(())
     }
 
-    /// Get all messages comprising the feed authored by the given public key.
-    pub fn get_feed(&self, user_id: &str) -> Result<Vec<MessageKvt>> {
-        let mut feed = Vec::new();
+    /// Get the message trace of the feed with the given public key, ordered
+    /// from oldest to newest entry.
+    pub fn get_message_trace(&self, feed_id: &str) -> Result<Vec<MessageTraceEntry>> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
 
-        // Lookup the latest sequence number for the given peer.
-        if let Some(latest_seq) = self.get_latest_seq(user_id)? {
-            // Iterate through the messages in the feed.
-            for msg_seq in 1..=latest_seq {
-                // Get the message KVT for the given author and message
-                // sequence number and add it to the feed vector.
-                feed.push(
-                    self.get_msg_kvt(user_id, msg_seq)?
-                        .ok_or(Error::OptionIsNone)?,
-                )
-            }
+        if let Some(raw) = trees.message_traces.get(feed_id)? {
+            Ok(serde_cbor::from_slice(&raw)?)
+        } else {
+            Ok(Vec::new())
         }
-
-        Ok(feed)
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
+    /// Generate a key for a Bendy Butt or buttwoo message authored by the
+    /// given public key and with the given sequence number, so that the
+    /// messages of a feed are stored contiguously and in order.
+    fn key_binary_msg(author: &[u8], sequence: u64) -> Vec<u8> {
+        let mut key = author.to_vec();
+        key.extend_from_slice(&sequence.to_be_bytes()[..]);
+        key
+    }
 
-    use kuska_ssb::{api::dto::content::TypedMessage, keystore::OwnedIdentity};
-    use serde_json::json;
-    use sled::Config;
+    /// Replicate the Bendy Butt feed with the given ID (a sigil link or an
+    /// SSB URI). Returns `false` if the feed was already replicated.
+    pub fn want_bendybutt_feed(&self, feed_id: &str) -> Result<bool> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+        let feed_id = bendybutt::feed_id(&bendybutt::parse_feed_id(feed_id)?);
+
+        Ok(trees
+            .bendybutt_feeds
+            .compare_and_swap(
+                feed_id,
+                None as Option<&[u8]>,
+                Some(&0u64.to_be_bytes()[..]),
+            )?
+            .is_ok())
+    }
 
-    use crate::secret_config::SecretConfig;
+    /// Get the replicated Bendy Butt feeds, along with the latest sequence
+    /// number of each (zero if no message of the feed is stored yet).
+    pub fn get_bendybutt_feeds(&self) -> Result<Vec<(String, u64)>> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
 
-    fn open_temporary_kv() -> Result<KvStorage> {
-        let mut kv = KvStorage::default();
-        let (sender, _) = futures::channel::mpsc::unbounded();
-        let path = tempdir::TempDir::new("solardb").unwrap();
-        let config = Config::new().path(path.path());
-        kv.open(config, sender).unwrap();
+        let mut feeds = Vec::new();
+        for item in trees.bendybutt_feeds.iter() {
+            let (k, v) = item?;
+            let mut u64_buffer = [0u8; 8];
+            u64_buffer.copy_from_slice(&v);
+            feeds.push((
+                String::from_utf8_lossy(&k).to_string(),
+                u64::from_be_bytes(u64_buffer),
+            ));
+        }
 
-        Ok(kv)
+        Ok(feeds)
     }
 
-    fn initialise_keypair_and_kv() -> Result<(OwnedIdentity, KvStorage)> {
-        // Create a unique keypair to sign messages.
-        let keypair = SecretConfig::create().to_owned_identity()?;
-
-        // Open a temporary key-value store.
-        let kv = open_temporary_kv()?;
+    /// Get the latest sequence number of the Bendy Butt feed with the given
+    /// ID, or `None` if the feed is not replicated.
+    pub fn get_latest_bendybutt_seq(&self, feed_id: &str) -> Result<Option<u64>> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+        let feed_id = bendybutt::feed_id(&bendybutt::parse_feed_id(feed_id)?);
 
-        Ok((keypair, kv))
+        Ok(trees.bendybutt_feeds.get(feed_id)?.map(|v| {
+            let mut u64_buffer = [0u8; 8];
+            u64_buffer.copy_from_slice(&v);
+            u64::from_be_bytes(u64_buffer)
+        }))
     }
 
-    #[async_std::test]
-    async fn test_feed_length() -> Result<()> {
-        use kuska_ssb::feed::Message;
+    /// Get the bencoded message of the Bendy Butt feed with the given ID and
+    /// with the given sequence number.
+    pub fn get_bendybutt_msg(&self, feed_id: &str, sequence: u64) -> Result<Option<Vec<u8>>> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+        let author = bendybutt::parse_feed_id(feed_id)?;
 
-        let (keypair, kv) = initialise_keypair_and_kv()?;
+        Ok(trees
+            .bendybutt_messages
+            .get(Self::key_binary_msg(&author, sequence))?
+            .map(|v| v.to_vec()))
+    }
 
-        let mut last_msg: Option<Message> = None;
-        for i in 1..=4 {
-            // Create a post-type message.
-            let msg_content = TypedMessage::Post {
-                text: format!("Important announcement #{i}"),
-                mentions: None,
-            };
+    /// Validate the given bencoded Bendy Butt message and append it to its
+    /// feed, which must be replicated. Returns the decoded message, or `None`
+    /// if the message is already stored.
+    pub fn append_bendybutt_msg(&self, raw: &[u8]) -> Result<Option<BendyButtMessage>> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+        let msg = BendyButtMessage::from_slice(raw)?;
+        let author_id = msg.author_id();
 
-            let msg = MessageValue::sign(last_msg.as_ref(), &keypair, json!(msg_content))?;
+        let latest_seq = self.get_latest_bendybutt_seq(&author_id)?.ok_or_else(|| {
+            Error::InvalidMessage(format!("{} is not a replicated feed", author_id))
+        })?;
+        if msg.sequence <= latest_seq {
+            return Ok(None);
+        }
 
-            // Append the signed message to the feed. Returns the sequence number
-            // of the appended message.
-            let seq = kv.append_feed(msg).await?;
-            assert_eq!(seq, i);
+        let latest = self.get_bendybutt_msg(&author_id, latest_seq)?;
+        validation::validate_bendybutt(&msg, latest.as_deref())?;
 
-            last_msg = kv.get_latest_msg_val(&keypair.id)?;
+        trees
+            .bendybutt_messages
+            .insert(Self::key_binary_msg(&msg.author, msg.sequence), raw)?;
+        trees
+            .bendybutt_feeds
+            .insert(author_id, &msg.sequence.to_be_bytes()[..])?;
 
-            let feed = kv.get_feed(&keypair.id)?;
-            assert_eq!(feed.len(), i as usize);
+        // Replicate the buttwoo subfeed added by the message, if any.
+        if let Some(subfeed) = msg.content_json()["subfeed"].as_str() {
+            if buttwoo::is_feed_id(subfeed) {
+                self.want_buttwoo_feed(subfeed)?;
+            }
         }
 
-        Ok(())
+        Ok(Some(msg))
     }
 
-    #[async_std::test]
-    async fn test_single_message_content_matches() -> Result<()> {
-        let (keypair, kv) = initialise_keypair_and_kv()?;
+    /// Replicate the buttwoo feed with the given SSB URI. Returns `false` if
+    /// the feed was already replicated.
+    pub fn want_buttwoo_feed(&self, feed_id: &str) -> Result<bool> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+        let feed_id = buttwoo::feed_id(&buttwoo::parse_feed_id(feed_id)?);
+
+        Ok(trees
+            .buttwoo_feeds
+            .compare_and_swap(
+                feed_id,
+                None as Option<&[u8]>,
+                Some(&0u64.to_be_bytes()[..]),
+            )?
+            .is_ok())
+    }
 
-        // Create a post-type message.
-        let msg_content = TypedMessage::Post {
-            text: "A strange rambling expiration of my own conscious".to_string(),
-            mentions: None,
-        };
+    /// Get the replicated buttwoo feeds, along with the latest sequence
+    /// number of each (zero if no message of the feed is stored yet).
+    pub fn get_buttwoo_feeds(&self) -> Result<Vec<(String, u64)>> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
 
-        let last_msg = kv.get_latest_msg_val(&keypair.id)?;
-        let msg = MessageValue::sign(last_msg.as_ref(), &keypair, json!(msg_content))?;
+        let mut feeds = Vec::new();
+        for item in trees.buttwoo_feeds.iter() {
+            let (k, v) = item?;
+            let mut u64_buffer = [0u8; 8];
+            u64_buffer.copy_from_slice(&v);
+            feeds.push((
+                String::from_utf8_lossy(&k).to_string(),
+                u64::from_be_bytes(u64_buffer),
+            ));
+        }
 
-        // Append the signed message to the feed. Returns the sequence number
-        // of the appended message.
-        let seq = kv.append_feed(msg).await?;
-        assert_eq!(seq, 1);
+        Ok(feeds)
+    }
 
-        let latest_seq = kv.get_latest_seq(&keypair.id)?;
-        assert_eq!(latest_seq, Some(1));
+    /// Get the latest sequence number of the buttwoo feed with the given ID,
+    /// or `None` if the feed is not replicated.
+    pub fn get_latest_buttwoo_seq(&self, feed_id: &str) -> Result<Option<u64>> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+        let feed_id = buttwoo::feed_id(&buttwoo::parse_feed_id(feed_id)?);
 
-        // Lookup the value of the previous message. This will be `None`
-        let last_msg = kv.get_latest_msg_val(&keypair.id)?;
+        Ok(trees.buttwoo_feeds.get(feed_id)?.map(|v| {
+            let mut u64_buffer = [0u8; 8];
+            u64_buffer.copy_from_slice(&v);
+            u64::from_be_bytes(u64_buffer)
+        }))
+    }
+
+    /// Get the BIPF-encoded message of the buttwoo feed with the given ID and
+    /// with the given sequence number.
+    pub fn get_buttwoo_msg(&self, feed_id: &str, sequence: u64) -> Result<Option<Vec<u8>>> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+        let author = buttwoo::parse_feed_id(feed_id)?;
+
+        Ok(trees
+            .buttwoo_messages
+            .get(Self::key_binary_msg(&author, sequence))?
+            .map(|v| v.to_vec()))
+    }
+
+    /// Validate the given BIPF-encoded buttwoo message and append it to its
+    /// feed, which must be replicated. Returns the decoded message, or `None`
+    /// if the message is already stored.
+    pub fn append_buttwoo_msg(&self, raw: &[u8]) -> Result<Option<ButtwooMessage>> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+        let msg = ButtwooMessage::from_slice(raw)?;
+        let author_id = msg.author_id();
+
+        let latest_seq = self.get_latest_buttwoo_seq(&author_id)?.ok_or_else(|| {
+            Error::InvalidMessage(format!("{} is not a replicated feed", author_id))
+        })?;
+        if msg.sequence <= latest_seq {
+            return Ok(None);
+        }
+
+        let latest = self.get_buttwoo_msg(&author_id, latest_seq)?;
+        validation::validate_buttwoo(&msg, latest.as_deref())?;
+
+        trees
+            .buttwoo_messages
+            .insert(Self::key_binary_msg(&msg.author, msg.sequence), raw)?;
+        trees
+            .buttwoo_feeds
+            .insert(author_id, &msg.sequence.to_be_bytes()[..])?;
+
+        Ok(Some(msg))
+    }
+
+    /// Pin the feed authored by the given public key, exempting it from
+    /// pruning. Returns `false` if the feed was already pinned.
+    pub fn pin_feed(&self, user_id: &str) -> Result<bool> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+
+        Ok(trees.pinned_feeds.insert(user_id, &[] as &[u8])?.is_none())
+    }
+
+    /// Unpin the feed authored by the given public key. Returns `false` if
+    /// the feed was not pinned.
+    pub fn unpin_feed(&self, user_id: &str) -> Result<bool> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+
+        Ok(trees.pinned_feeds.remove(user_id)?.is_some())
+    }
+
+    /// Query whether the feed authored by the given public key is pinned.
+    pub fn is_feed_pinned(&self, user_id: &str) -> Result<bool> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+
+        Ok(trees.pinned_feeds.contains_key(user_id)?)
+    }
+
+    /// Return the public keys of all pinned feeds.
+    pub fn get_pinned_feeds(&self) -> Result<Vec<String>> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+
+        let mut pinned = Vec::new();
+
+        for item in trees.pinned_feeds.iter() {
+            let (k, _) = item?;
+            pinned.push(String::from_utf8_lossy(&k).to_string());
+        }
+
+        Ok(pinned)
+    }
+
+    /// Read the mute patterns stored in the given tree.
+    fn read_mute_patterns(tree: &Tree) -> Result<Vec<MutePattern>> {
+        let mut patterns = Vec::new();
+
+        for item in tree.iter() {
+            let (k, _) = item?;
+            patterns.push(serde_json::from_slice(&k)?);
+        }
+
+        Ok(patterns)
+    }
+
+    /// Return the mute patterns.
+    pub fn get_mute_patterns(&self) -> Result<Vec<MutePattern>> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+
+        Self::read_mute_patterns(&trees.mute_patterns)
+    }
+
+    /// Mute the given pattern, leaving the messages matching it out of the
+    /// timeline and channel indexes from now on. Returns `false` if the
+    /// pattern was already muted.
+    pub fn mute(&mut self, pattern: &MutePattern) -> Result<bool> {
+        let mut patterns = self.get_mute_patterns()?;
+        if patterns.contains(pattern) {
+            return Ok(false);
+        }
+        patterns.push(pattern.to_owned());
+        // Compile the patterns before storing the new one, so that invalid
+        // patterns are rejected.
+        let mutes = MuteList::new(patterns)?;
+
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+        trees
+            .mute_patterns
+            .insert(serde_json::to_vec(pattern)?, &[] as &[u8])?;
+        self.indexes
+            .as_mut()
+            .ok_or(Error::Indexes)?
+            .set_mutes(mutes);
+
+        Ok(true)
+    }
+
+    /// Unmute the given pattern. Messages indexed after the pattern was
+    /// muted are not added back to the indexes. Returns `false` if the
+    /// pattern was not muted.
+    pub fn unmute(&mut self, pattern: &MutePattern) -> Result<bool> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+        if trees
+            .mute_patterns
+            .remove(serde_json::to_vec(pattern)?)?
+            .is_none()
+        {
+            return Ok(false);
+        }
+
+        let mutes = MuteList::new(self.get_mute_patterns()?)?;
+        self.indexes
+            .as_mut()
+            .ok_or(Error::Indexes)?
+            .set_mutes(mutes);
+
+        Ok(true)
+    }
+
+    /// Add the blob with the given ID to the want-list, to be requested
+    /// immediately. Returns `false` if the blob was already wanted.
+    pub fn want_blob(&self, blob_id: &str) -> Result<bool> {
+        if self.get_blob_want(blob_id)?.is_some() {
+            return Ok(false);
+        }
+        self.set_blob_want(blob_id, &BlobWant::default())?;
+
+        Ok(true)
+    }
+
+    /// Get the want-list entry of the blob with the given ID.
+    pub fn get_blob_want(&self, blob_id: &str) -> Result<Option<BlobWant>> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+        if let Some(raw) = trees.blob_wants.get(blob_id)? {
+            Ok(Some(serde_cbor::from_slice(&raw)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Set the want-list entry of the blob with the given ID.
+    pub fn set_blob_want(&self, blob_id: &str, want: &BlobWant) -> Result<()> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+        trees
+            .blob_wants
+            .insert(blob_id, serde_cbor::to_vec(want)?)?;
+
+        Ok(())
+    }
+
+    /// Remove the blob with the given ID from the want-list. Returns `false`
+    /// if the blob was not wanted.
+    pub fn remove_blob_want(&self, blob_id: &str) -> Result<bool> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+
+        Ok(trees.blob_wants.remove(blob_id)?.is_some())
+    }
+
+    /// Return the want-list: the IDs of all wanted blobs along with the
+    /// state of their retries.
+    pub fn get_blob_wants(&self) -> Result<Vec<(String, BlobWant)>> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+        let mut wants = Vec::new();
+
+        for item in trees.blob_wants.iter() {
+            let (k, v) = item?;
+            let want: BlobWant = serde_cbor::from_slice(&v)?;
+            wants.push((String::from_utf8_lossy(&k).to_string(), want));
+        }
+
+        Ok(wants)
+    }
+
+    /// Generate a key for the outbox entry with the given ID.
+    fn key_outbox(entry_id: u64) -> Vec<u8> {
+        entry_id.to_be_bytes().to_vec()
+    }
+
+    /// Add an entry to the outbox. Returns the ID of the entry.
+    pub fn add_outbox_entry(&self, entry: &OutboxEntry) -> Result<u64> {
+        let db = self.db.as_ref().ok_or(Error::OptionIsNone)?;
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+        let entry_id = db.generate_id()?;
+        // Entries are stored as JSON rather than CBOR, since the content may
+        // contain arbitrary-precision numbers.
+        trees
+            .outbox
+            .insert(Self::key_outbox(entry_id), serde_json::to_vec(entry)?)?;
+
+        Ok(entry_id)
+    }
+
+    /// Get the outbox entry with the given ID.
+    pub fn get_outbox_entry(&self, entry_id: u64) -> Result<Option<OutboxEntry>> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+        if let Some(raw) = trees.outbox.get(Self::key_outbox(entry_id))? {
+            Ok(Some(serde_json::from_slice(&raw)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Set the outbox entry with the given ID.
+    pub fn set_outbox_entry(&self, entry_id: u64, entry: &OutboxEntry) -> Result<()> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+        trees
+            .outbox
+            .insert(Self::key_outbox(entry_id), serde_json::to_vec(entry)?)?;
+
+        Ok(())
+    }
+
+    /// Replace the outbox entry with the given ID. Returns `false` if there
+    /// is no such entry (for example, if it has already been published).
+    pub fn replace_outbox_entry(&self, entry_id: u64, entry: &OutboxEntry) -> Result<bool> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+        let raw = serde_json::to_vec(entry)?;
+
+        // Replace the entry atomically, so that an entry which is removed
+        // concurrently is not added back.
+        let updated = trees
+            .outbox
+            .update_and_fetch(Self::key_outbox(entry_id), |old| old.map(|_| raw.clone()))?;
+
+        Ok(updated.is_some())
+    }
+
+    /// Remove the outbox entry with the given ID and return it.
+    pub fn remove_outbox_entry(&self, entry_id: u64) -> Result<Option<OutboxEntry>> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+        if let Some(raw) = trees.outbox.remove(Self::key_outbox(entry_id))? {
+            Ok(Some(serde_json::from_slice(&raw)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Return the IDs and entries of the outbox, ordered by ID (the order in
+    /// which they were added).
+    pub fn get_outbox_entries(&self) -> Result<Vec<(u64, OutboxEntry)>> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+        let mut entries = Vec::new();
+
+        for item in trees.outbox.iter() {
+            let (k, v) = item?;
+            let mut u64_buffer = [0u8; 8];
+            u64_buffer.copy_from_slice(&k[..8]);
+            entries.push((u64::from_be_bytes(u64_buffer), serde_json::from_slice(&v)?));
+        }
+
+        Ok(entries)
+    }
+
+    /// Record the given identity conflict, unless a conflict has already
+    /// been recorded. Returns `false` if a conflict was already recorded.
+    pub fn set_identity_conflict(&self, conflict: &IdentityConflict) -> Result<bool> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+        let swapped = trees.identity_conflict.compare_and_swap(
+            IDENTITY_CONFLICT_KEY,
+            None as Option<&[u8]>,
+            Some(serde_cbor::to_vec(conflict)?),
+        )?;
+
+        Ok(swapped.is_ok())
+    }
+
+    /// Get the recorded identity conflict, if any.
+    pub fn get_identity_conflict(&self) -> Result<Option<IdentityConflict>> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+        if let Some(raw) = trees.identity_conflict.get(IDENTITY_CONFLICT_KEY)? {
+            Ok(Some(serde_cbor::from_slice(&raw)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Clear the recorded identity conflict. Returns `false` if no conflict
+    /// was recorded.
+    pub fn clear_identity_conflict(&self) -> Result<bool> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+
+        Ok(trees
+            .identity_conflict
+            .remove(IDENTITY_CONFLICT_KEY)?
+            .is_some())
+    }
+
+    /// Get the cached capabilities of the peer with the given public key.
+    pub fn get_peer_capabilities(&self, user_id: &str) -> Result<Option<PeerCapabilities>> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+        if let Some(raw) = trees.peer_capabilities.get(user_id)? {
+            Ok(Some(serde_cbor::from_slice(&raw)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Set the cached capabilities of the peer with the given public key.
+    pub fn set_peer_capabilities(
+        &self,
+        user_id: &str,
+        capabilities: &PeerCapabilities,
+    ) -> Result<()> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+        trees
+            .peer_capabilities
+            .insert(user_id, serde_cbor::to_vec(capabilities)?)?;
+
+        Ok(())
+    }
+
+    /// Get the reputation of the peer with the given public key.
+    pub fn get_peer_reputation(&self, user_id: &str) -> Result<Option<PeerReputation>> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+        if let Some(raw) = trees.peer_reputations.get(user_id)? {
+            Ok(Some(serde_cbor::from_slice(&raw)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Set the reputation of the peer with the given public key.
+    pub fn set_peer_reputation(&self, user_id: &str, reputation: &PeerReputation) -> Result<()> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+        trees
+            .peer_reputations
+            .insert(user_id, serde_cbor::to_vec(reputation)?)?;
+
+        Ok(())
+    }
+
+    /// Return the public keys of the peers with a reputation, along with
+    /// their reputation.
+    pub fn get_peer_reputations(&self) -> Result<Vec<(String, PeerReputation)>> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+        let mut reputations = Vec::new();
+
+        for item in trees.peer_reputations.iter() {
+            let (k, v) = item?;
+            reputations.push((
+                String::from_utf8_lossy(&k).into_owned(),
+                serde_cbor::from_slice(&v)?,
+            ));
+        }
+
+        Ok(reputations)
+    }
+
+    /// Queue the given peer to be followed back, along with the reference of
+    /// its follow message.
+    pub fn add_follow_request(&self, user_id: &str, msg_ref: &str) -> Result<()> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+        trees.follow_requests.insert(user_id, msg_ref.as_bytes())?;
+
+        Ok(())
+    }
+
+    /// Remove the given peer from the follow-back queue. Returns the
+    /// reference of its follow message, or `None` if the peer was not queued.
+    pub fn remove_follow_request(&self, user_id: &str) -> Result<Option<String>> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+
+        Ok(trees
+            .follow_requests
+            .remove(user_id)?
+            .map(|raw| String::from_utf8_lossy(&raw).into_owned()))
+    }
+
+    /// Record that the feed authored by the given public key is mirrored
+    /// from the given time (in milliseconds since the UNIX epoch). Returns
+    /// `false` if the feed was already mirrored.
+    pub fn add_mirrored_feed(&self, user_id: &str, added: u64) -> Result<bool> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+        let feed = MirroredFeed {
+            added,
+            ..MirroredFeed::default()
+        };
+
+        let swapped = trees.mirrored_feeds.compare_and_swap(
+            user_id,
+            None as Option<&[u8]>,
+            Some(serde_cbor::to_vec(&feed)?),
+        )?;
+
+        Ok(swapped.is_ok())
+    }
+
+    /// Return the public keys of the mirrored feeds, along with the storage
+    /// used by each.
+    pub fn get_mirrored_feeds(&self) -> Result<Vec<(String, MirroredFeed)>> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+        let mut feeds = Vec::new();
+
+        for item in trees.mirrored_feeds.iter() {
+            let (k, v) = item?;
+            feeds.push((
+                String::from_utf8_lossy(&k).into_owned(),
+                serde_cbor::from_slice(&v)?,
+            ));
+        }
+
+        Ok(feeds)
+    }
+
+    /// Generate a key for the value of the given feed in the vector clock
+    /// sent to the given peer.
+    fn key_sent_clock(peer_id: &str, feed_id: &str) -> String {
+        format!("{}:{}", peer_id, feed_id)
+    }
+
+    /// Get the values of the vector clock last sent to the given peer.
+    pub fn get_sent_clock(&self, peer_id: &str) -> Result<VectorClock> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+        let prefix = Self::key_sent_clock(peer_id, "");
+
+        let mut clock = VectorClock::new();
+        for item in trees.sent_clocks.scan_prefix(&prefix) {
+            let (k, v) = item?;
+            let mut i64_buffer = [0u8; 8];
+            i64_buffer.copy_from_slice(&v);
+            clock.insert(
+                String::from_utf8_lossy(&k[prefix.len()..]).into_owned(),
+                i64::from_be_bytes(i64_buffer),
+            );
+        }
+
+        Ok(clock)
+    }
+
+    /// Record the values of the given vector clock (or note) sent to the
+    /// given peer. The feeds sent as `-1` (not replicated) are forgotten.
+    pub fn update_sent_clock(&self, peer_id: &str, clock: &VectorClock) -> Result<()> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+
+        let mut batch = sled::Batch::default();
+        for (feed_id, value) in clock {
+            let key = Self::key_sent_clock(peer_id, feed_id);
+            if *value == -1 {
+                batch.remove(key.as_bytes());
+            } else {
+                batch.insert(key.as_bytes(), &value.to_be_bytes());
+            }
+        }
+        trees.sent_clocks.apply_batch(batch)?;
+
+        Ok(())
+    }
+
+    /// Get the time (in milliseconds since the UNIX epoch) the full vector
+    /// clock was last sent to the given peer, or `None` if it never was.
+    pub fn get_full_clock_sent_at(&self, peer_id: &str) -> Result<Option<u64>> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+
+        Ok(trees.sent_clocks.get(peer_id)?.map(|v| {
+            let mut u64_buffer = [0u8; 8];
+            u64_buffer.copy_from_slice(&v);
+            u64::from_be_bytes(u64_buffer)
+        }))
+    }
+
+    /// Record the time (in milliseconds since the UNIX epoch) the full
+    /// vector clock was sent to the given peer.
+    pub fn set_full_clock_sent_at(&self, peer_id: &str, sent_at: u64) -> Result<()> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+        trees.sent_clocks.insert(peer_id, &sent_at.to_be_bytes())?;
+
+        Ok(())
+    }
+
+    /// Return the public keys of the peers queued to be followed back, along
+    /// with the references of their follow messages.
+    pub fn get_follow_requests(&self) -> Result<Vec<(String, String)>> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+        let mut requests = Vec::new();
+
+        for item in trees.follow_requests.iter() {
+            let (k, v) = item?;
+            requests.push((
+                String::from_utf8_lossy(&k).into_owned(),
+                String::from_utf8_lossy(&v).into_owned(),
+            ));
+        }
+
+        Ok(requests)
+    }
+
+    /// Generate a key for the value with the given key in the scope of the
+    /// client with the given API token. The token is hashed, so that scopes
+    /// are of fixed length and tokens are not stored.
+    fn key_client_value(token: &str, key: &str) -> Vec<u8> {
+        let mut scoped_key = Vec::new();
+        scoped_key.extend_from_slice(&Sha256::digest(token.as_bytes()));
+        scoped_key.extend_from_slice(key.as_bytes());
+        scoped_key
+    }
+
+    /// Get the value with the given key in the scope of the client with the
+    /// given API token.
+    pub fn get_client_value(&self, token: &str, key: &str) -> Result<Option<Vec<u8>>> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+
+        Ok(trees
+            .client_values
+            .get(Self::key_client_value(token, key))?
+            .map(|value| value.to_vec()))
+    }
+
+    /// Set the value with the given key in the scope of the client with the
+    /// given API token. Returns `false` if the key already had a value.
+    pub fn set_client_value(&self, token: &str, key: &str, value: &[u8]) -> Result<bool> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+
+        Ok(trees
+            .client_values
+            .insert(Self::key_client_value(token, key), value)?
+            .is_none())
+    }
+
+    /// Return the keys and values in the scope of the client with the given
+    /// API token whose keys start with the given prefix.
+    pub fn get_client_values(&self, token: &str, prefix: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+        let mut values = Vec::new();
+
+        let scan_key = Self::key_client_value(token, prefix);
+        // Skip the hashed token.
+        let key_offset = scan_key.len() - prefix.len();
+        for item in trees.client_values.scan_prefix(scan_key) {
+            let (k, v) = item?;
+            let key = String::from_utf8_lossy(&k[key_offset..]).to_string();
+            values.push((key, v.to_vec()));
+        }
+
+        Ok(values)
+    }
+
+    /// Mark the messages of the feed of the message with the given ID, up to
+    /// and including the message, as read by the client with the given API
+    /// token. Returns `false` if the message was already marked as read, and
+    /// an error if the message is not stored.
+    pub fn mark_read(&self, token: &str, msg_id: &str) -> Result<bool> {
+        let msg = self
+            .get_msg_val(msg_id)?
+            .ok_or_else(|| Error::Other(format!("Message {} not found", msg_id)))?;
+        // The read mark of a feed is the sequence number of its latest
+        // message marked as read.
+        let key = format!("{}{}", CLIENT_READ_PREFIX, msg.author());
+
+        if let Some(read_seq) = self.get_client_value(token, &key)? {
+            if Self::decode_read_mark(&read_seq) >= msg.sequence() {
+                return Ok(false);
+            }
+        }
+
+        self.set_client_value(token, &key, &msg.sequence().to_be_bytes())?;
+
+        Ok(true)
+    }
+
+    /// Decode the sequence number of a read mark.
+    fn decode_read_mark(value: &[u8]) -> u64 {
+        let mut u64_buffer = [0u8; 8];
+        if value.len() == 8 {
+            u64_buffer.copy_from_slice(value);
+        }
+        u64::from_be_bytes(u64_buffer)
+    }
+
+    /// Return the number of unread messages of each stored feed, for the
+    /// client with the given API token: the stored messages above the read
+    /// mark of the feed. Feeds without unread messages are omitted.
+    pub async fn get_unread_counts(&self, token: &str) -> Result<BTreeMap<String, u64>> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+
+        let mut read_marks: HashMap<String, u64> = HashMap::new();
+        for (key, read_seq) in self.get_client_values(token, CLIENT_READ_PREFIX)? {
+            let author = key[CLIENT_READ_PREFIX.len()..].to_string();
+            read_marks.insert(author, Self::decode_read_mark(&read_seq));
+        }
+
+        let mut unread_counts = BTreeMap::new();
+        for (pub_key, seq_num) in self.get_peers().await? {
+            let read_seq = read_marks.get(&pub_key).copied().unwrap_or(0);
+            // Messages may be missing from the feed (for instance, when
+            // deleted), so the stored messages are counted one by one.
+            let mut unread = 0;
+            for msg_seq in read_seq + 1..=seq_num {
+                if trees
+                    .messages
+                    .contains_key(Self::key_msg_kvt(&pub_key, msg_seq))?
+                {
+                    unread += 1;
+                }
+            }
+            if unread > 0 {
+                unread_counts.insert(pub_key, unread);
+            }
+        }
+
+        Ok(unread_counts)
+    }
+
+    /// Delete the read marks of the feed authored by the given public key,
+    /// for every client. Returns the number of read marks deleted.
+    pub fn remove_read_marks(&self, user_id: &str) -> Result<usize> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+        let key = format!("{}{}", CLIENT_READ_PREFIX, user_id);
+        let mut removed = 0;
+
+        // Client scopes are keyed by hashed tokens, so the marks of every
+        // client are found by scanning the whole tree.
+        for item in trees.client_values.iter() {
+            let (k, _) = item?;
+            if k.get(CLIENT_SCOPE_LEN..) == Some(key.as_bytes()) {
+                trees.client_values.remove(k)?;
+                removed += 1;
+            }
+        }
+
+        Ok(removed)
+    }
+
+    /// Generate a key for the network statistics of the given day (in days
+    /// since the UNIX epoch).
+    fn key_network_stats(day: u64) -> Vec<u8> {
+        day.to_be_bytes().to_vec()
+    }
+
+    /// Get the network statistics of the given day (in days since the UNIX
+    /// epoch).
+    pub fn get_network_stats(&self, day: u64) -> Result<Option<NetworkStats>> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+        if let Some(raw) = trees.network_stats.get(Self::key_network_stats(day))? {
+            Ok(Some(serde_cbor::from_slice(&raw)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Set the network statistics of the given day (in days since the UNIX
+    /// epoch).
+    pub fn set_network_stats(&self, day: u64, stats: &NetworkStats) -> Result<()> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+        trees
+            .network_stats
+            .insert(Self::key_network_stats(day), serde_cbor::to_vec(stats)?)?;
+
+        Ok(())
+    }
+
+    /// Return the days (in days since the UNIX epoch) from the given day
+    /// onwards for which network statistics were recorded, along with the
+    /// statistics, ordered from oldest to newest.
+    pub fn get_network_stats_since(&self, first_day: u64) -> Result<Vec<(u64, NetworkStats)>> {
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+        let mut stats = Vec::new();
+
+        for item in trees
+            .network_stats
+            .range(Self::key_network_stats(first_day)..)
+        {
+            let (k, v) = item?;
+            let mut u64_buffer = [0u8; 8];
+            u64_buffer.copy_from_slice(&k[..8]);
+            stats.push((u64::from_be_bytes(u64_buffer), serde_cbor::from_slice(&v)?));
+        }
+
+        Ok(stats)
+    }
+
+    /// Append a message value to a feed.
+    pub async fn append_feed(&self, msg_val: MessageValue) -> Result<u64> {
+        debug!("Appending message to feed in database");
+        let author = msg_val.author().to_owned();
+
+        // Record the local receive time (in milliseconds), since the
+        // timestamp asserted by the author cannot be relied upon for
+        // ordering.
+        let received = now_millis();
+
+        let seq_num = self.insert_msg(msg_val, received).await?;
+
+        let db = self.db.as_ref().ok_or(Error::OptionIsNone)?;
+        db.flush_async().await?;
+
+        // Publish a notification that the feed belonging to the given public
+        // key has been updated.
+        let broker_msg = BrokerEvent::new(
+            Destination::Broadcast,
+            BrokerMessage::StoreKv(StoreKvEvent((author, seq_num))),
+        );
+
+        // Matching on the error here (instead of unwrapping) allows us to
+        // write unit tests for `append_feed`; a case where we do not have
+        // a broker deployed to receive the event message.
+        if let Err(err) = self
+            .ch_broker
+            .as_ref()
+            .ok_or(Error::OptionIsNone)?
+            .send(broker_msg)
+            .await
+        {
+            warn!(
+                "Failed to notify broker of message appended to kv store: {}",
+                err
+            )
+        };
+
+        Ok(seq_num)
+    }
+
+    /// Store and index a message value received at the given time (in
+    /// milliseconds), without flushing the database or notifying the broker.
+    async fn insert_msg(&self, msg_val: MessageValue, received: u64) -> Result<u64> {
+        let seq_num = self.get_latest_seq(msg_val.author())?.map_or(0, |num| num) + 1;
+
+        if msg_val.sequence() != seq_num {
+            return Err(Error::InvalidSequence);
+        }
+
+        let author = msg_val.author().to_owned();
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+
+        let msg_ref = serde_cbor::to_vec(&PubKeyAndSeqNum {
+            pub_key: author.clone(),
+            seq_num,
+        })?;
+        trees.msg_refs.insert(msg_val.id().to_string(), msg_ref)?;
+
+        let mut msg_kvt = MessageKvt::new(msg_val.clone());
+        msg_kvt.rts = Some(received as f64);
+        let msg_json = msg_kvt.to_string();
+        trees
+            .messages
+            .insert(Self::key_msg_kvt(&author, seq_num), msg_json.as_bytes())?;
+
+        // Account for the storage used by mirrored feeds.
+        if let Some(raw) = trees.mirrored_feeds.get(&author)? {
+            let mut feed: MirroredFeed = serde_cbor::from_slice(&raw)?;
+            feed.messages += 1;
+            feed.bytes += msg_json.len() as u64;
+            trees
+                .mirrored_feeds
+                .insert(author.as_str(), serde_cbor::to_vec(&feed)?)?;
+        }
+        trees
+            .latest_seq
+            .insert(author.as_str(), &seq_num.to_be_bytes()[..])?;
+
+        // Add the public key and latest sequence number for this peer to the
+        // list of peers.
+        self.set_peer(&author, seq_num).await?;
+
+        // Replicate the metafeed announced by the author, if any.
+        let content = msg_val.content();
+        if content["type"] == "metafeed/announce" {
+            if let Some(metafeed) = content["metafeed"].as_str() {
+                if let Err(err) = self.want_bendybutt_feed(metafeed) {
+                    warn!("Ignoring metafeed announced by {}: {}", author, err);
+                }
+            }
+        }
+
+        debug!("Passing message to indexer");
+        // Pass the author and message value to the indexer.
+        if let Some(indexes) = &self.indexes {
+            indexes.index_received_msg(&author, msg_val, received)?
+        }
+
+        Ok(seq_num)
+    }
+
+    /// Get all messages comprising the feed authored by the given public key.
+    /// The public key may be given as a sigil link or an SSB URI.
+    pub fn get_feed(&self, user_id: &str) -> Result<Vec<MessageKvt>> {
+        let mut feed = Vec::new();
+        let user_id = &ssb_uri::to_sigil(user_id)?;
+
+        // Lookup the latest sequence number for the given peer.
+        if let Some(latest_seq) = self.get_latest_seq(user_id)? {
+            // Iterate through the messages in the feed.
+            for msg_seq in 1..=latest_seq {
+                // Get the message KVT for the given author and message
+                // sequence number and add it to the feed vector.
+                feed.push(
+                    self.get_msg_kvt(user_id, msg_seq)?
+                        .ok_or(Error::OptionIsNone)?,
+                )
+            }
+        }
+
+        Ok(feed)
+    }
+
+    /// Delete the feed authored by the given public key, along with all
+    /// index entries derived from its messages.
+    ///
+    /// Returns the number of messages deleted.
+    pub async fn remove_feed(&self, user_id: &str) -> Result<u64> {
+        let removed = self.remove_feeds(&[user_id.to_owned()]).await?;
+
+        Ok(removed.into_iter().sum())
+    }
+
+    /// Delete the feeds authored by the given public keys, along with all
+    /// index entries derived from their messages. The indexes are scanned
+    /// once for all the feeds, rather than once per feed.
+    ///
+    /// Returns the number of messages deleted from each feed, in the given
+    /// order.
+    pub async fn remove_feeds(&self, user_ids: &[String]) -> Result<Vec<u64>> {
+        let db = self.db.as_ref().ok_or(Error::OptionIsNone)?;
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+        if user_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut removed_counts = Vec::with_capacity(user_ids.len());
+
+        for user_id in user_ids {
+            let mut removed = 0;
+            if let Some(latest_seq) = self.get_latest_seq(user_id)? {
+                for msg_seq in 1..=latest_seq {
+                    if let Some(msg_kvt) = self.get_msg_kvt(user_id, msg_seq)? {
+                        trees.msg_refs.remove(msg_kvt.key)?;
+                        trees.messages.remove(Self::key_msg_kvt(user_id, msg_seq))?;
+                        removed += 1;
+                    }
+                }
+            }
+
+            trees.latest_seq.remove(user_id.as_str())?;
+            trees.peers.remove(user_id.as_str())?;
+            trees.mirrored_feeds.remove(user_id.as_str())?;
+            self.remove_read_marks(user_id)?;
+            removed_counts.push(removed);
+        }
+
+        if let Some(indexes) = &self.indexes {
+            indexes.remove_authors(&user_ids.iter().cloned().collect())?
+        }
+
+        db.flush_async().await?;
+
+        Ok(removed_counts)
+    }
+
+    /// Copy every readable feed (classic, Bendy Butt and buttwoo) from the
+    /// given (possibly corrupted) database into this one, indexing the copied
+    /// messages along the way (leaving out the messages matching the
+    /// restored mute patterns). Readable outbox and replication log entries
+    /// are added again under new IDs, and the records of the other trees
+    /// (including the local records of apps) are copied as-is, except for
+    /// those which are rebuilt or safely dropped.
+    ///
+    /// Each feed is copied up to its first unreadable or invalid message,
+    /// since the messages which follow cannot be appended without it. The
+    /// source database is not modified, so its records may still be laid out
+    /// as by earlier versions (see `trees`).
+    pub async fn salvage(&mut self, source: &Db) -> Result<RepairReport> {
+        let mut report = RepairReport::default();
+
+        // Mute patterns are restored first, so that the messages they match
+        // are left out of the indexes rebuilt below. Patterns which no
+        // longer compile are skipped.
+        for entry in trees::MUTE_PATTERNS.scan_unmigrated(source)? {
+            let pattern = entry
+                .ok()
+                .and_then(|(key, _)| serde_json::from_slice::<MutePattern>(&key).ok());
+            match pattern {
+                Some(pattern) if self.mute(&pattern).is_ok() => (),
+                _ => report.unreadable_entries += 1,
+            }
+        }
+
+        let db = self.db.as_ref().ok_or(Error::OptionIsNone)?;
+        let trees = self.trees.as_ref().ok_or(Error::OptionIsNone)?;
+
+        // Determine the expected length of each feed from both the latest
+        // sequence numbers and the message keys, since either may have been
+        // lost.
+        let mut feeds: BTreeMap<String, u64> = BTreeMap::new();
+        for entry in trees::LATEST_SEQ.scan_unmigrated(source)? {
+            match entry {
+                Ok((key, value)) if value.len() == 8 => {
+                    let mut u64_buffer = [0u8; 8];
+                    u64_buffer.copy_from_slice(&value);
+                    let author = String::from_utf8_lossy(&key).to_string();
+                    let seq_num = feeds.entry(author).or_insert(0);
+                    *seq_num = (*seq_num).max(u64::from_be_bytes(u64_buffer));
+                }
+                _ => report.unreadable_entries += 1,
+            }
+        }
+        for entry in trees::MESSAGES.scan_unmigrated(source)? {
+            match entry {
+                Ok((key, _)) if key.len() > 8 => {
+                    let mut u64_buffer = [0u8; 8];
+                    u64_buffer.copy_from_slice(&key[..8]);
+                    let author = String::from_utf8_lossy(&key[8..]).to_string();
+                    let seq_num = feeds.entry(author).or_insert(0);
+                    *seq_num = (*seq_num).max(u64::from_be_bytes(u64_buffer));
+                }
+                _ => report.unreadable_entries += 1,
+            }
+        }
+
+        for (author, expected) in feeds {
+            let mut recovered = 0;
+
+            for msg_seq in 1..=expected {
+                let key = Self::key_msg_kvt(&author, msg_seq);
+                let msg_kvt = match trees::MESSAGES.get_unmigrated(source, &key) {
+                    Ok(Some(raw)) => MessageKvt::from_slice(&raw).ok(),
+                    _ => None,
+                };
+                let (msg_val, received) = match msg_kvt {
+                    Some(msg_kvt) => {
+                        // Fall back to the timestamp of the KVT for messages
+                        // stored before receive times were recorded.
+                        let received = msg_kvt.rts.unwrap_or(msg_kvt.timestamp) as u64;
+                        match msg_kvt.into_message() {
+                            Ok(msg_val) => (msg_val, received),
+                            Err(_) => break,
+                        }
+                    }
+                    None => break,
+                };
+
+                if msg_val.author().to_string() != author || msg_val.sequence() != msg_seq {
+                    break;
+                }
+
+                self.insert_msg(msg_val, received).await?;
+                recovered += 1;
+            }
+
+            if recovered > 0 {
+                report.feeds_recovered += 1;
+                report.messages_recovered += recovered;
+            }
+            if recovered < expected {
+                report.lost.push(LostFeed {
+                    author,
+                    format: "classic",
+                    recovered,
+                    expected,
+                });
+            }
+        }
+
+        // Bendy Butt feeds are salvaged first, since their messages may add
+        // buttwoo subfeeds.
+        self.salvage_binary_feeds(source, &BENDYBUTT_FORMAT, &mut report)?;
+        self.salvage_binary_feeds(source, &BUTTWOO_FORMAT, &mut report)?;
+
+        // Outbox entries are added under new IDs, in their original order,
+        // since the IDs generated by the fresh database start over.
+        for entry in trees::OUTBOX.scan_unmigrated(source)? {
+            let outbox_entry = entry
+                .ok()
+                .and_then(|(_, value)| serde_json::from_slice::<OutboxEntry>(&value).ok());
+            match outbox_entry {
+                Some(outbox_entry) => {
+                    self.add_outbox_entry(&outbox_entry)?;
+                }
+                None => report.unreadable_entries += 1,
+            }
+        }
+
+        // Replication log entries are keyed by new IDs as well, in their
+        // original order.
+        for entry in trees::REPLICATION_LOGS.scan_unmigrated(source)? {
+            match entry {
+                Ok((key, value)) if key.len() > 8 => {
+                    let user_id = String::from_utf8_lossy(&key[..key.len() - 8]);
+                    trees.replication_logs.insert(
+                        Self::key_replication_log(&user_id, db.generate_id()?),
+                        value,
+                    )?;
+                }
+                _ => report.unreadable_entries += 1,
+            }
+        }
+
+        // The remaining records are copied as-is, except for those of two
+        // trees: peers are rebuilt from the salvaged messages (as are the
+        // latest sequence numbers, message references and indexes), and the
+        // vector clocks last sent to each peer are dropped, since the
+        // salvaged feeds may be shorter than advertised; the full clock is
+        // then sent to each peer again.
+        let local_records = db.open_tree(trees::LOCAL_RECORDS.name)?;
+        for (spec, tree) in [
+            (trees::BLOBS, &trees.blobs),
+            (trees::PINNED_FEEDS, &trees.pinned_feeds),
+            (trees::BLOB_WANTS, &trees.blob_wants),
+            // Publishing must remain disabled until a detected identity
+            // conflict is cleared.
+            (trees::IDENTITY_CONFLICT, &trees.identity_conflict),
+            (trees::PEER_CAPABILITIES, &trees.peer_capabilities),
+            (trees::CLIENT_VALUES, &trees.client_values),
+            (trees::NETWORK_STATS, &trees.network_stats),
+            (trees::PEER_REPUTATIONS, &trees.peer_reputations),
+            (trees::FOLLOW_REQUESTS, &trees.follow_requests),
+            (trees::OOO_MESSAGES, &trees.ooo_messages),
+            (trees::MESSAGE_TRACES, &trees.message_traces),
+            // Mirrored feeds are copied after their messages, so that the
+            // storage they use is not accounted for twice.
+            (trees::MIRRORED_FEEDS, &trees.mirrored_feeds),
+            (trees::LOCAL_RECORDS, &local_records),
+        ] {
+            for entry in spec.scan_unmigrated(source)? {
+                match entry {
+                    Ok((key, value)) => {
+                        tree.insert(key, value)?;
+                    }
+                    Err(_) => report.unreadable_entries += 1,
+                }
+            }
+        }
+
+        db.flush_async().await?;
+
+        Ok(report)
+    }
+
+    /// Copy every readable feed of the given binary format from the given
+    /// database into this one, adding the feeds and messages recovered to
+    /// the given report. As with classic feeds, each feed is copied up to
+    /// its first unreadable or invalid message.
+    fn salvage_binary_feeds(
+        &self,
+        source: &Db,
+        format: &BinaryFormat,
+        report: &mut RepairReport,
+    ) -> Result<()> {
+        // Determine the expected length of each feed from both the latest
+        // sequence numbers and the message keys, since either may have been
+        // lost.
+        let mut feeds: BTreeMap<String, u64> = BTreeMap::new();
+        for entry in format.feeds.scan_unmigrated(source)? {
+            match entry {
+                Ok((key, value)) if value.len() == 8 => {
+                    let mut u64_buffer = [0u8; 8];
+                    u64_buffer.copy_from_slice(&value);
+                    let feed_id = String::from_utf8_lossy(&key).to_string();
+                    let seq_num = feeds.entry(feed_id).or_insert(0);
+                    *seq_num = (*seq_num).max(u64::from_be_bytes(u64_buffer));
+                }
+                _ => report.unreadable_entries += 1,
+            }
+        }
+        for entry in format.messages.scan_unmigrated(source)? {
+            match entry {
+                Ok((key, _)) if key.len() > 8 => {
+                    let (author, sequence) = key.split_at(key.len() - 8);
+                    let mut u64_buffer = [0u8; 8];
+                    u64_buffer.copy_from_slice(sequence);
+                    let seq_num = feeds.entry((format.feed_id)(author)).or_insert(0);
+                    *seq_num = (*seq_num).max(u64::from_be_bytes(u64_buffer));
+                }
+                _ => report.unreadable_entries += 1,
+            }
+        }
+
+        for (feed_id, expected) in feeds {
+            let author = match (format.parse_feed_id)(&feed_id) {
+                Ok(author) => author,
+                Err(_) => {
+                    report.unreadable_entries += 1;
+                    continue;
+                }
+            };
+            // Feeds are replicated even if none of their messages is stored.
+            (format.want)(self, &feed_id)?;
+
+            let mut recovered = 0;
+            for msg_seq in 1..=expected {
+                let key = Self::key_binary_msg(&author, msg_seq);
+                let raw = match format.messages.get_unmigrated(source, &key) {
+                    Ok(Some(raw)) => raw,
+                    _ => break,
+                };
+                match (format.append)(self, &raw) {
+                    Ok(Some(sequence)) if sequence == msg_seq => recovered += 1,
+                    _ => break,
+                }
+            }
+
+            if recovered > 0 {
+                report.feeds_recovered += 1;
+                report.messages_recovered += recovered;
+            }
+            if recovered < expected {
+                report.lost.push(LostFeed {
+                    author: feed_id,
+                    format: format.name,
+                    recovered,
+                    expected,
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The trees and operations of a binary feed format (Bendy Butt or
+/// buttwoo), with which its feeds are salvaged.
+struct BinaryFormat {
+    /// Name of the feed format.
+    name: &'static str,
+    /// Tree of the latest sequence number of each feed.
+    feeds: TreeSpec,
+    /// Tree of the messages, keyed by author public key and sequence number.
+    messages: TreeSpec,
+    /// Return the ID of the feed with the given author public key.
+    feed_id: fn(&[u8]) -> String,
+    /// Return the author public key of the feed with the given ID.
+    parse_feed_id: fn(&str) -> Result<Vec<u8>>,
+    /// Replicate the feed with the given ID.
+    want: fn(&KvStorage, &str) -> Result<bool>,
+    /// Validate and append the given message, returning its sequence number
+    /// (or `None` if it is already stored).
+    append: fn(&KvStorage, &[u8]) -> Result<Option<u64>>,
+}
+
+/// The Bendy Butt feed format (metafeeds).
+const BENDYBUTT_FORMAT: BinaryFormat = BinaryFormat {
+    name: bendybutt::FORMAT,
+    feeds: trees::BENDYBUTT_FEEDS,
+    messages: trees::BENDYBUTT_MESSAGES,
+    feed_id: bendybutt::feed_id,
+    parse_feed_id: |feed_id| Ok(bendybutt::parse_feed_id(feed_id)?.to_vec()),
+    want: KvStorage::want_bendybutt_feed,
+    append: |kv, raw| Ok(kv.append_bendybutt_msg(raw)?.map(|msg| msg.sequence)),
+};
+
+/// The buttwoo feed format.
+const BUTTWOO_FORMAT: BinaryFormat = BinaryFormat {
+    name: buttwoo::FORMAT,
+    feeds: trees::BUTTWOO_FEEDS,
+    messages: trees::BUTTWOO_MESSAGES,
+    feed_id: buttwoo::feed_id,
+    parse_feed_id: |feed_id| Ok(buttwoo::parse_feed_id(feed_id)?.to_vec()),
+    want: KvStorage::want_buttwoo_feed,
+    append: |kv, raw| Ok(kv.append_buttwoo_msg(raw)?.map(|msg| msg.sequence)),
+};
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use kuska_sodiumoxide::crypto::sign::ed25519;
+    use kuska_ssb::{api::dto::content::TypedMessage, keystore::OwnedIdentity};
+    use serde_json::json;
+    use sled::Config;
+    use solar_core::{bendybutt::Bencode, buttwoo::Bipf, index::TimelineOrder};
+
+    use crate::secret_config::SecretConfig;
+
+    fn open_temporary_kv() -> Result<KvStorage> {
+        let mut kv = KvStorage::default();
+        let (sender, _) = futures::channel::mpsc::unbounded();
+        let path = tempdir::TempDir::new("solardb").unwrap();
+        let config = Config::new().path(path.path());
+        kv.open(config, sender).unwrap();
+
+        Ok(kv)
+    }
+
+    fn initialise_keypair_and_kv() -> Result<(OwnedIdentity, KvStorage)> {
+        // Create a unique keypair to sign messages.
+        let keypair = SecretConfig::create().to_owned_identity()?;
+
+        // Open a temporary key-value store.
+        let kv = open_temporary_kv()?;
+
+        Ok((keypair, kv))
+    }
+
+    #[async_std::test]
+    async fn test_feed_length() -> Result<()> {
+        use kuska_ssb::feed::Message;
+
+        let (keypair, kv) = initialise_keypair_and_kv()?;
+
+        let mut last_msg: Option<Message> = None;
+        for i in 1..=4 {
+            // Create a post-type message.
+            let msg_content = TypedMessage::Post {
+                text: format!("Important announcement #{i}"),
+                mentions: None,
+            };
+
+            let msg = MessageValue::sign(last_msg.as_ref(), &keypair, json!(msg_content))?;
+
+            // Append the signed message to the feed. Returns the sequence number
+            // of the appended message.
+            let seq = kv.append_feed(msg).await?;
+            assert_eq!(seq, i);
+
+            last_msg = kv.get_latest_msg_val(&keypair.id)?;
+
+            let feed = kv.get_feed(&keypair.id)?;
+            assert_eq!(feed.len(), i as usize);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ooo_messages() -> Result<()> {
+        let (keypair, kv) = initialise_keypair_and_kv()?;
+
+        let msg_content = TypedMessage::Post {
+            text: "Out of order".to_string(),
+            mentions: None,
+        };
+        let msg = MessageValue::sign(None, &keypair, json!(msg_content))?;
+        let msg_id = msg.id().to_string();
+
+        assert!(kv.get_ooo_msg_kvt(&msg_id)?.is_none());
+
+        kv.set_ooo_msg(&msg, 1_000)?;
+        let msg_kvt = kv.get_ooo_msg_kvt(&msg_id)?.unwrap();
+        assert_eq!(msg_kvt.key, msg_id);
+        assert_eq!(msg_kvt.rts, Some(1_000.0));
+
+        // Cached messages are kept apart from the replicated feeds.
+        assert!(kv.get_msg_val(&msg_id)?.is_none());
+        assert!(kv.get_latest_seq(&keypair.id)?.is_none());
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_single_message_content_matches() -> Result<()> {
+        let (keypair, kv) = initialise_keypair_and_kv()?;
+
+        // Create a post-type message.
+        let msg_content = TypedMessage::Post {
+            text: "A strange rambling expiration of my own conscious".to_string(),
+            mentions: None,
+        };
+
+        let last_msg = kv.get_latest_msg_val(&keypair.id)?;
+        let msg = MessageValue::sign(last_msg.as_ref(), &keypair, json!(msg_content))?;
+
+        // Append the signed message to the feed. Returns the sequence number
+        // of the appended message.
+        let seq = kv.append_feed(msg).await?;
+        assert_eq!(seq, 1);
+
+        let latest_seq = kv.get_latest_seq(&keypair.id)?;
+        assert_eq!(latest_seq, Some(1));
+
+        // Lookup the value of the previous message. This will be `None`
+        let last_msg = kv.get_latest_msg_val(&keypair.id)?;
         assert!(last_msg.is_some());
         let expected = serde_json::value::to_value(msg_content)?;
         let last_msg = last_msg.unwrap().content().clone();
 
         assert_eq!(last_msg, expected);
 
+        // The serialized value matches the value of the stored KVT.
+        let msg_json = kv.get_msg_json(&keypair.id, 1)?.unwrap();
+        let msg_kvt = kv.get_msg_kvt(&keypair.id, 1)?.unwrap();
+        assert_eq!(serde_json::from_str::<Value>(&msg_json)?, msg_kvt.value);
+        assert!(kv.get_msg_json(&keypair.id, 2)?.is_none());
+
         Ok(())
     }
 
@@ -498,8 +2230,13 @@ mod test {
         let msg_kvt = kv.get_msg_kvt(&keypair.id, 2)?;
         assert!(msg_kvt.is_some());
 
+        let msg_kvt = msg_kvt.unwrap();
+
+        // Ensure the local receive time has been recorded.
+        assert!(msg_kvt.rts.is_some());
+
         // Retrieve the key from the KVT.
-        let msg_kvt_key = msg_kvt.unwrap().key;
+        let msg_kvt_key = msg_kvt.key;
 
         // Get the second message in the key-value store in the form of a value.
         let msg_val = kv.get_msg_val(&msg_kvt_key)?;
@@ -508,12 +2245,455 @@ mod test {
         // and signed message.
         assert_eq!(msg_val, Some(msg_2_clone));
 
+        // Ensure the message can also be retrieved by SSB URI.
+        let msg_uri = ssb_uri::to_uri(&msg_kvt_key)?;
+        assert_eq!(kv.get_msg_val(&msg_uri)?, msg_val);
+
+        // Ensure both messages are reported as stored, and no others.
+        assert!(kv.contains_msg(&keypair.id, 1)?);
+        assert!(kv.contains_msg(&keypair.id, 2)?);
+        assert!(!kv.contains_msg(&keypair.id, 3)?);
+
         // Get all messages comprising the feed.
         let feed = kv.get_feed(&keypair.id)?;
 
         // Ensure that two messages are returned.
         assert_eq!(feed.len(), 2);
 
+        // Ensure the same feed is returned when looked up by SSB URI.
+        let feed_uri = ssb_uri::to_uri(&keypair.id)?;
+        assert_eq!(kv.get_feed(&feed_uri)?.len(), 2);
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_replication_log() -> Result<()> {
+        let (keypair, kv) = initialise_keypair_and_kv()?;
+
+        assert!(kv.get_replication_log(&keypair.id)?.is_empty());
+
+        let mut clock = VectorClock::new();
+        clock.insert(keypair.id.to_owned(), 2);
+
+        kv.append_replication_log(
+            &keypair.id,
+            ReplicationEvent::SessionStarted {
+                protocol: "ebt".to_string(),
+            },
+        )?;
+        kv.append_replication_log(&keypair.id, ReplicationEvent::ClockSent { clock })?;
+
+        // Ensure the replication log does not interfere with the peers list.
+        kv.set_peer(&keypair.id, 1).await?;
+        assert_eq!(kv.get_peers().await?.len(), 1);
+
+        let log = kv.get_replication_log(&keypair.id)?;
+        assert_eq!(log.len(), 2);
+        assert_eq!(
+            log[0].event,
+            ReplicationEvent::SessionStarted {
+                protocol: "ebt".to_string()
+            }
+        );
+
+        // Ensure the log is bounded and retains the newest entries.
+        for i in 0..REPLICATION_LOG_CAPACITY {
+            kv.append_replication_log(
+                &keypair.id,
+                ReplicationEvent::Error {
+                    message: i.to_string(),
+                },
+            )?;
+        }
+
+        let log = kv.get_replication_log(&keypair.id)?;
+        assert_eq!(log.len(), REPLICATION_LOG_CAPACITY);
+        assert_eq!(
+            log.last().unwrap().event,
+            ReplicationEvent::Error {
+                message: (REPLICATION_LOG_CAPACITY - 1).to_string()
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_message_trace() -> Result<()> {
+        let kv = open_temporary_kv()?;
+
+        assert!(kv.get_message_trace("@feed")?.is_empty());
+
+        kv.append_message_trace("@feed", 1, "@a", TraceDirection::Received, "ebt")?;
+        kv.append_message_trace("@feed", 1, "@b", TraceDirection::Sent, "classic")?;
+        kv.append_message_trace("@other", 1, "@a", TraceDirection::Received, "ebt")?;
+
+        let trace = kv.get_message_trace("@feed")?;
+        assert_eq!(trace.len(), 2);
+        assert_eq!(trace[0].peer, "@a");
+        assert_eq!(trace[0].direction, TraceDirection::Received);
+        assert_eq!(trace[1].protocol, "classic");
+
+        // Ensure the trace is bounded and retains the newest entries.
+        for i in 0..MESSAGE_TRACE_CAPACITY as u64 {
+            kv.append_message_trace("@feed", i + 2, "@a", TraceDirection::Received, "ebt")?;
+        }
+
+        let trace = kv.get_message_trace("@feed")?;
+        assert_eq!(trace.len(), MESSAGE_TRACE_CAPACITY);
+        assert_eq!(
+            trace.last().unwrap().sequence,
+            MESSAGE_TRACE_CAPACITY as u64 + 1
+        );
+
+        Ok(())
+    }
+
+    /// Return a Bendy Butt message of the metafeed with the given keypair,
+    /// adding the subfeed with the given keypair, signed by both.
+    fn bendybutt_msg(
+        metafeed: &(ed25519::PublicKey, ed25519::SecretKey),
+        subfeed: &(ed25519::PublicKey, ed25519::SecretKey),
+        sequence: i64,
+        previous: Option<&[u8]>,
+    ) -> Vec<u8> {
+        let bfe = |prefix: &[u8], data: &[u8]| Bencode::Bytes([prefix, data].concat());
+
+        let mut content = std::collections::BTreeMap::new();
+        content.insert(b"type".to_vec(), bfe(&[6, 0], b"metafeed/add/derived"));
+        content.insert(b"subfeed".to_vec(), bfe(&[0, 0], subfeed.0.as_ref()));
+        let content = Bencode::Dict(content);
+        let signed_content = [bendybutt::CONTENT_SIGNATURE_PREFIX, &content.encode()].concat();
+        let content_signature = ed25519::sign_detached(&signed_content, &subfeed.1);
+
+        let payload = Bencode::List(vec![
+            bfe(&[0, 3], metafeed.0.as_ref()),
+            Bencode::Int(sequence),
+            match previous {
+                Some(previous) => bfe(&[1, 4], &Sha256::digest(previous)),
+                None => bfe(&[6, 2], &[]),
+            },
+            Bencode::Int(1_650_000_000_000),
+            Bencode::List(vec![content, bfe(&[4, 0], content_signature.as_ref())]),
+        ]);
+        let signature = ed25519::sign_detached(&payload.encode(), &metafeed.1);
+
+        Bencode::List(vec![payload, bfe(&[4, 0], signature.as_ref())]).encode()
+    }
+
+    #[test]
+    fn test_bendybutt_feeds() -> Result<()> {
+        let kv = open_temporary_kv()?;
+        let metafeed = ed25519::gen_keypair();
+        let subfeed = ed25519::gen_keypair();
+        let feed_id = bendybutt::feed_id(metafeed.0.as_ref());
+
+        let first = bendybutt_msg(&metafeed, &subfeed, 1, None);
+        let second = bendybutt_msg(&metafeed, &subfeed, 2, Some(&first));
+
+        // Only the messages of replicated feeds are stored.
+        assert!(kv.append_bendybutt_msg(&first).is_err());
+        assert!(kv.want_bendybutt_feed(&feed_id)?);
+        assert!(!kv.want_bendybutt_feed(&feed_id)?);
+        assert_eq!(kv.get_bendybutt_feeds()?, vec![(feed_id.clone(), 0)]);
+
+        // Messages must follow the latest stored message.
+        assert!(kv.append_bendybutt_msg(&second).is_err());
+        assert_eq!(kv.append_bendybutt_msg(&first)?.unwrap().sequence, 1);
+        assert!(kv.append_bendybutt_msg(&first)?.is_none());
+        assert_eq!(kv.append_bendybutt_msg(&second)?.unwrap().sequence, 2);
+        assert_eq!(kv.get_latest_bendybutt_seq(&feed_id)?, Some(2));
+        assert_eq!(kv.get_bendybutt_msg(&feed_id, 1)?, Some(first.clone()));
+
+        // Messages whose payload is not signed by the author or whose content
+        // is not signed by the subfeed are rejected.
+        let mut forged = bendybutt_msg(&metafeed, &subfeed, 3, Some(&second));
+        let last_signature_byte = forged.len() - 2;
+        forged[last_signature_byte] ^= 1;
+        assert!(kv.append_bendybutt_msg(&forged).is_err());
+        let other = ed25519::gen_keypair();
+        let unconsented = bendybutt_msg(&metafeed, &(subfeed.0, other.1), 3, Some(&second));
+        assert!(kv.append_bendybutt_msg(&unconsented).is_err());
+        assert_eq!(kv.get_latest_bendybutt_seq(&feed_id)?, Some(2));
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_salvage_bendybutt_feeds() -> Result<()> {
+        let source = open_temporary_kv()?;
+        let metafeed = ed25519::gen_keypair();
+        let subfeed = ed25519::gen_keypair();
+        let feed_id = bendybutt::feed_id(metafeed.0.as_ref());
+        let empty_feed_id = bendybutt::feed_id(ed25519::gen_keypair().0.as_ref());
+
+        let first = bendybutt_msg(&metafeed, &subfeed, 1, None);
+        let second = bendybutt_msg(&metafeed, &subfeed, 2, Some(&first));
+        let third = bendybutt_msg(&metafeed, &subfeed, 3, Some(&second));
+        source.want_bendybutt_feed(&feed_id)?;
+        source.want_bendybutt_feed(&empty_feed_id)?;
+        for msg in [&first, &second, &third] {
+            source.append_bendybutt_msg(msg)?;
+        }
+        // Lose the second message.
+        let trees = source.trees.as_ref().unwrap();
+        trees
+            .bendybutt_messages
+            .remove(KvStorage::key_binary_msg(metafeed.0.as_ref(), 2))?;
+
+        let mut kv = open_temporary_kv()?;
+        let report = kv.salvage(source.db.as_ref().unwrap()).await?;
+
+        assert_eq!(report.feeds_recovered, 1);
+        assert_eq!(report.messages_recovered, 1);
+        assert_eq!(
+            report.lost,
+            vec![LostFeed {
+                author: feed_id.clone(),
+                format: bendybutt::FORMAT,
+                recovered: 1,
+                expected: 3,
+            }]
+        );
+        assert_eq!(kv.get_latest_bendybutt_seq(&feed_id)?, Some(1));
+        assert_eq!(kv.get_bendybutt_msg(&feed_id, 1)?, Some(first));
+        // Feeds without messages remain replicated.
+        assert_eq!(kv.get_latest_bendybutt_seq(&empty_feed_id)?, Some(0));
+
+        Ok(())
+    }
+
+    /// Return a buttwoo message of the feed with the given keypair, with the
+    /// given tag and following the given previous message.
+    fn buttwoo_msg(
+        feed: &(ed25519::PublicKey, ed25519::SecretKey),
+        sequence: i32,
+        previous: Option<&[u8]>,
+        tag: u8,
+    ) -> Vec<u8> {
+        let bfe = |prefix: &[u8], data: &[u8]| Bipf::Buffer([prefix, data].concat());
+
+        let content = Bipf::Object(vec![(
+            Bipf::String("type".to_owned()),
+            Bipf::String("post".to_owned()),
+        )])
+        .encode();
+        let value = Bipf::Array(vec![
+            bfe(&[0, 4], feed.0.as_ref()),
+            bfe(&[6, 2], &[]),
+            Bipf::Int(sequence),
+            Bipf::Double(1_650_000_000_000.0),
+            match previous.map(ButtwooMessage::from_slice) {
+                Some(previous) => bfe(
+                    &[1, 5],
+                    blake3::hash(&previous.unwrap().hashed_bytes()).as_bytes(),
+                ),
+                None => bfe(&[6, 2], &[]),
+            },
+            Bipf::Buffer(vec![tag]),
+            Bipf::Int(content.len() as i32),
+            bfe(&[0], blake3::hash(&content).as_bytes()),
+        ])
+        .encode();
+        let signature = ed25519::sign_detached(&value, &feed.1);
+
+        Bipf::Array(vec![
+            Bipf::Buffer(value),
+            Bipf::Buffer(signature.as_ref().to_vec()),
+            Bipf::Buffer(content),
+        ])
+        .encode()
+    }
+
+    #[async_std::test]
+    async fn test_salvage_buttwoo_feeds_and_mute_patterns() -> Result<()> {
+        let (keypair, mut source) = initialise_keypair_and_kv()?;
+        let feed = ed25519::gen_keypair();
+        let feed_id = buttwoo::feed_id(feed.0.as_ref());
+
+        let spam = MutePattern::Word("spam".to_string());
+        source.mute(&spam)?;
+        let first = MessageValue::sign(None, &keypair, json!({ "type": "post", "text": "Spam!" }))?;
+        let second = MessageValue::sign(
+            Some(&first),
+            &keypair,
+            json!({ "type": "post", "text": "Hello" }),
+        )?;
+        source.append_feed(first).await?;
+        source.append_feed(second).await?;
+
+        let first = buttwoo_msg(&feed, 1, None, 0);
+        let second = buttwoo_msg(&feed, 2, Some(&first), 0);
+        source.want_buttwoo_feed(&feed_id)?;
+        source.append_buttwoo_msg(&first)?;
+        source.append_buttwoo_msg(&second)?;
+
+        let mut kv = open_temporary_kv()?;
+        let report = kv.salvage(source.db.as_ref().unwrap()).await?;
+
+        assert_eq!(report.feeds_recovered, 2);
+        assert_eq!(report.messages_recovered, 4);
+        assert!(report.lost.is_empty());
+        assert_eq!(kv.get_latest_buttwoo_seq(&feed_id)?, Some(2));
+        assert_eq!(kv.get_buttwoo_msg(&feed_id, 2)?, Some(second));
+
+        // Muted messages are left out of the rebuilt indexes.
+        assert_eq!(kv.get_mute_patterns()?, vec![spam]);
+        let indexes = kv.indexes.as_ref().unwrap();
+        let timeline = indexes.get_timeline(TimelineOrder::Claimed, None, 10)?;
+        assert_eq!(timeline.entries.len(), 1);
+        assert_eq!(timeline.entries[0].sequence, 2);
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_salvage_outbox() -> Result<()> {
+        let source = open_temporary_kv()?;
+        let draft = OutboxEntry {
+            content: json!({ "type": "post", "text": "Draft" }),
+            publish_at: None,
+            error: None,
+        };
+        let scheduled = OutboxEntry {
+            content: json!({ "type": "post", "text": "Scheduled" }),
+            publish_at: Some(1_000),
+            error: None,
+        };
+        source.add_outbox_entry(&draft)?;
+        let corrupted_id = source.add_outbox_entry(&draft)?;
+        source.add_outbox_entry(&scheduled)?;
+        let trees = source.trees.as_ref().unwrap();
+        trees
+            .outbox
+            .insert(KvStorage::key_outbox(corrupted_id), b"{".to_vec())?;
+
+        let mut kv = open_temporary_kv()?;
+        let report = kv.salvage(source.db.as_ref().unwrap()).await?;
+
+        assert_eq!(report.unreadable_entries, 1);
+        // Entries added after the repair do not replace salvaged ones.
+        kv.add_outbox_entry(&draft)?;
+        let entries: Vec<OutboxEntry> = kv
+            .get_outbox_entries()?
+            .into_iter()
+            .map(|(_, entry)| entry)
+            .collect();
+        assert_eq!(entries, vec![draft.clone(), scheduled, draft]);
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_salvage_logs_and_records() -> Result<()> {
+        let (keypair, source) = initialise_keypair_and_kv()?;
+        let started = |protocol: &str| ReplicationEvent::SessionStarted {
+            protocol: protocol.to_string(),
+        };
+        source.append_replication_log(&keypair.id, started("ebt"))?;
+        source.append_replication_log(&keypair.id, started("classic"))?;
+        let mut clock = VectorClock::new();
+        clock.insert(keypair.id.to_owned(), 2);
+        source.update_sent_clock(&keypair.id, &clock)?;
+        let records = source.records.as_ref().unwrap();
+        records.set("app", "theme", &json!("dark"))?;
+
+        let mut kv = open_temporary_kv()?;
+        kv.salvage(source.db.as_ref().unwrap()).await?;
+
+        // Entries recorded after the repair follow the salvaged ones.
+        kv.append_replication_log(&keypair.id, started("ebt"))?;
+        let events: Vec<ReplicationEvent> = kv
+            .get_replication_log(&keypair.id)?
+            .into_iter()
+            .map(|entry| entry.event)
+            .collect();
+        assert_eq!(
+            events,
+            vec![started("ebt"), started("classic"), started("ebt")]
+        );
+
+        let records = kv.records.as_ref().unwrap();
+        assert_eq!(records.get("app", "theme")?, Some(json!("dark")));
+        // The full clock is sent again after a repair.
+        assert!(kv.get_sent_clock(&keypair.id)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_buttwoo_feeds() -> Result<()> {
+        let kv = open_temporary_kv()?;
+        let feed = ed25519::gen_keypair();
+        let feed_id = buttwoo::feed_id(feed.0.as_ref());
+
+        let first = buttwoo_msg(&feed, 1, None, 0);
+        let second = buttwoo_msg(&feed, 2, Some(&first), 0);
+
+        // Only the messages of replicated feeds are stored.
+        assert!(kv.append_buttwoo_msg(&first).is_err());
+        assert!(kv.want_buttwoo_feed(&feed_id)?);
+        assert!(!kv.want_buttwoo_feed(&feed_id)?);
+        assert_eq!(kv.get_buttwoo_feeds()?, vec![(feed_id.clone(), 0)]);
+
+        // Messages must follow the latest stored message.
+        assert!(kv.append_buttwoo_msg(&second).is_err());
+        assert_eq!(kv.append_buttwoo_msg(&first)?.unwrap().sequence, 1);
+        assert!(kv.append_buttwoo_msg(&first)?.is_none());
+        assert_eq!(kv.append_buttwoo_msg(&second)?.unwrap().sequence, 2);
+        assert_eq!(kv.get_latest_buttwoo_seq(&feed_id)?, Some(2));
+        assert_eq!(kv.get_buttwoo_msg(&feed_id, 1)?, Some(first.clone()));
+
+        // Messages which are not signed by the author or whose content does
+        // not match its hash are rejected.
+        let mut forged = buttwoo_msg(&feed, 3, Some(&second), 0);
+        let last_content_byte = forged.len() - 1;
+        forged[last_content_byte] ^= 1;
+        assert!(kv.append_buttwoo_msg(&forged).is_err());
+        let other = ed25519::gen_keypair();
+        let unsigned = buttwoo_msg(&(feed.0, other.1), 3, Some(&second), 0);
+        assert!(kv.append_buttwoo_msg(&unsigned).is_err());
+
+        // No message may follow the end of a feed.
+        let last = buttwoo_msg(&feed, 3, Some(&second), 1);
+        assert_eq!(kv.append_buttwoo_msg(&last)?.unwrap().sequence, 3);
+        let after_end = buttwoo_msg(&feed, 4, Some(&last), 0);
+        assert!(kv.append_buttwoo_msg(&after_end).is_err());
+        assert_eq!(kv.get_latest_buttwoo_seq(&feed_id)?, Some(3));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sent_clocks() -> Result<()> {
+        let kv = open_temporary_kv()?;
+        let peer_id = "@o8lWpyLeSqV/BJV9pbxFhKpwm6Lw5k+sqexYK+zT9Tc=.ed25519";
+        let feed_id = "@HEqy940T6uB+T+d9Jaa58aNfRzLx9eRWqkZljBmnkmk=.ed25519";
+
+        assert!(kv.get_sent_clock(peer_id)?.is_empty());
+        assert_eq!(kv.get_full_clock_sent_at(peer_id)?, None);
+
+        let clock = VectorClock::from([(peer_id.to_string(), 2), (feed_id.to_string(), 6)]);
+        kv.update_sent_clock(peer_id, &clock)?;
+        kv.set_full_clock_sent_at(peer_id, 1000)?;
+        assert_eq!(kv.get_sent_clock(peer_id)?, clock);
+        assert_eq!(kv.get_full_clock_sent_at(peer_id)?, Some(1000));
+
+        // Notes update the values sent, and feeds no longer replicated are
+        // forgotten.
+        kv.update_sent_clock(
+            peer_id,
+            &VectorClock::from([(peer_id.to_string(), 4), (feed_id.to_string(), -1)]),
+        )?;
+        assert_eq!(
+            kv.get_sent_clock(peer_id)?,
+            VectorClock::from([(peer_id.to_string(), 4)])
+        );
+
+        // The clocks sent to other peers are kept apart.
+        assert!(kv.get_sent_clock(feed_id)?.is_empty());
+
         Ok(())
     }
 
@@ -528,6 +2708,7 @@ mod test {
             &BlobStatus {
                 retrieved: true,
                 users: ["u1".to_string()].to_vec(),
+                offset: 0,
             },
         )?;
 
@@ -536,6 +2717,7 @@ mod test {
             &BlobStatus {
                 retrieved: false,
                 users: ["u2".to_string()].to_vec(),
+                offset: 0,
             },
         )?;
 
@@ -550,6 +2732,7 @@ mod test {
             &BlobStatus {
                 retrieved: false,
                 users: ["u7".to_string()].to_vec(),
+                offset: 0,
             },
         )?;
 
@@ -564,4 +2747,406 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn test_blob_offset() -> Result<()> {
+        let kv = open_temporary_kv()?;
+
+        assert_eq!(kv.get_blob_offset("b1")?, 0);
+
+        kv.set_blob_offset("b1", 1024)?;
+        assert_eq!(kv.get_blob_offset("b1")?, 1024);
+        assert_eq!(kv.get_pending_blobs()?, ["b1".to_string()].to_vec());
+
+        // The progress is discarded once the blob is retrieved.
+        kv.set_blob_retrieved("b1")?;
+        assert_eq!(kv.get_blob_offset("b1")?, 0);
+        assert!(kv.get_pending_blobs()?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_blob_wants() -> Result<()> {
+        let kv = open_temporary_kv()?;
+
+        assert!(kv.get_blob_wants()?.is_empty());
+
+        assert!(kv.want_blob("b1")?);
+        assert!(kv.want_blob("b2")?);
+        assert_eq!(kv.get_blob_want("b1")?, Some(BlobWant::default()));
+
+        // Wanting a blob again keeps the state of its retries.
+        let want = BlobWant {
+            attempts: 2,
+            next_attempt: 1_000,
+        };
+        kv.set_blob_want("b1", &want)?;
+        assert!(!kv.want_blob("b1")?);
+        assert_eq!(
+            kv.get_blob_wants()?,
+            [
+                ("b1".to_string(), want),
+                ("b2".to_string(), BlobWant::default())
+            ]
+            .to_vec()
+        );
+
+        assert!(kv.remove_blob_want("b1")?);
+        assert!(!kv.remove_blob_want("b1")?);
+        assert_eq!(kv.get_blob_want("b1")?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_outbox() -> Result<()> {
+        let kv = open_temporary_kv()?;
+
+        assert!(kv.get_outbox_entries()?.is_empty());
+
+        let draft = OutboxEntry {
+            content: json!({ "type": "post", "text": "draft", "rank": 1.5 }),
+            publish_at: None,
+            error: None,
+        };
+        let scheduled = OutboxEntry {
+            content: json!({ "type": "post", "text": "scheduled" }),
+            publish_at: Some(1_000),
+            error: None,
+        };
+        let draft_id = kv.add_outbox_entry(&draft)?;
+        let scheduled_id = kv.add_outbox_entry(&scheduled)?;
+        assert_eq!(kv.get_outbox_entry(draft_id)?, Some(draft.clone()));
+        assert_eq!(
+            kv.get_outbox_entries()?,
+            vec![(draft_id, draft.clone()), (scheduled_id, scheduled.clone())]
+        );
+
+        // Only existing entries can be replaced.
+        let edited = OutboxEntry {
+            publish_at: Some(2_000),
+            ..draft.clone()
+        };
+        assert!(kv.replace_outbox_entry(draft_id, &edited)?);
+        assert_eq!(kv.get_outbox_entry(draft_id)?, Some(edited));
+
+        assert_eq!(kv.remove_outbox_entry(scheduled_id)?, Some(scheduled));
+        assert_eq!(kv.remove_outbox_entry(scheduled_id)?, None);
+        assert!(!kv.replace_outbox_entry(scheduled_id, &draft)?);
+        assert_eq!(kv.get_outbox_entry(scheduled_id)?, None);
+
+        // A removed entry can be set again under the same ID.
+        kv.set_outbox_entry(scheduled_id, &draft)?;
+        assert_eq!(kv.get_outbox_entry(scheduled_id)?, Some(draft));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_identity_conflict() -> Result<()> {
+        let kv = open_temporary_kv()?;
+
+        assert_eq!(kv.get_identity_conflict()?, None);
+        assert!(!kv.clear_identity_conflict()?);
+
+        let conflict = IdentityConflict {
+            peer: "@peer".to_string(),
+            sequence: 5,
+            local_sequence: 3,
+            detected_at: 1_000,
+        };
+        assert!(kv.set_identity_conflict(&conflict)?);
+
+        // The first recorded conflict is kept.
+        let later = IdentityConflict {
+            sequence: 6,
+            ..conflict.clone()
+        };
+        assert!(!kv.set_identity_conflict(&later)?);
+        assert_eq!(kv.get_identity_conflict()?, Some(conflict));
+
+        assert!(kv.clear_identity_conflict()?);
+        assert_eq!(kv.get_identity_conflict()?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_peer_capabilities() -> Result<()> {
+        let kv = open_temporary_kv()?;
+
+        assert_eq!(kv.get_peer_capabilities("@peer")?, None);
+
+        let capabilities = PeerCapabilities {
+            ebt: Some(CapabilityObservation {
+                supported: false,
+                observed_at: 1_000,
+            }),
+            feed_formats: vec!["classic".to_string()],
+            ..PeerCapabilities::default()
+        };
+        kv.set_peer_capabilities("@peer", &capabilities)?;
+        assert_eq!(kv.get_peer_capabilities("@peer")?, Some(capabilities));
+        assert_eq!(kv.get_peer_capabilities("@other")?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_peer_reputations() -> Result<()> {
+        let kv = open_temporary_kv()?;
+
+        assert_eq!(kv.get_peer_reputation("@peer")?, None);
+
+        let mut reputation = PeerReputation {
+            messages_received: 10,
+            sessions: 1,
+            ..PeerReputation::default()
+        };
+        reputation.merge(PeerReputation {
+            invalid_messages: 1,
+            sessions: 1,
+            errors: 1,
+            ..PeerReputation::default()
+        });
+        assert_eq!(reputation.sessions, 2);
+
+        kv.set_peer_reputation("@peer", &reputation)?;
+        assert_eq!(kv.get_peer_reputation("@peer")?, Some(reputation.clone()));
+        assert_eq!(
+            kv.get_peer_reputations()?,
+            vec![("@peer".to_string(), reputation)]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_follow_requests() -> Result<()> {
+        let kv = open_temporary_kv()?;
+
+        assert!(kv.get_follow_requests()?.is_empty());
+
+        kv.add_follow_request("@a", "%follow-a")?;
+        kv.add_follow_request("@b", "%follow-b")?;
+        assert_eq!(
+            kv.get_follow_requests()?,
+            vec![
+                ("@a".to_string(), "%follow-a".to_string()),
+                ("@b".to_string(), "%follow-b".to_string()),
+            ]
+        );
+
+        assert_eq!(
+            kv.remove_follow_request("@a")?,
+            Some("%follow-a".to_string())
+        );
+        assert_eq!(kv.remove_follow_request("@a")?, None);
+        assert_eq!(kv.get_follow_requests()?.len(), 1);
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_mirrored_feeds() -> Result<()> {
+        let (keypair, kv) = initialise_keypair_and_kv()?;
+
+        let msg_content = TypedMessage::Post {
+            text: "Before mirroring".to_string(),
+            mentions: None,
+        };
+        let msg = MessageValue::sign(None, &keypair, json!(msg_content))?;
+        kv.append_feed(msg.clone()).await?;
+
+        assert!(kv.add_mirrored_feed(&keypair.id, 1_000)?);
+        assert!(!kv.add_mirrored_feed(&keypair.id, 2_000)?);
+
+        // Only the messages stored once the feed is mirrored are accounted.
+        let msg_content = TypedMessage::Post {
+            text: "After mirroring".to_string(),
+            mentions: None,
+        };
+        let msg = MessageValue::sign(Some(&msg), &keypair, json!(msg_content))?;
+        kv.append_feed(msg).await?;
+
+        let feeds = kv.get_mirrored_feeds()?;
+        assert_eq!(feeds.len(), 1);
+        let (feed_id, feed) = &feeds[0];
+        assert_eq!(feed_id, &keypair.id);
+        assert_eq!(feed.added, 1_000);
+        assert_eq!(feed.messages, 1);
+        assert_eq!(
+            feed.bytes,
+            kv.get_msg_kvt(&keypair.id, 2)?.unwrap().to_string().len() as u64
+        );
+
+        kv.remove_feed(&keypair.id).await?;
+        assert!(kv.get_mirrored_feeds()?.is_empty());
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_remove_feed() -> Result<()> {
+        let (keypair, kv) = initialise_keypair_and_kv()?;
+
+        let mut last_msg = None;
+        for i in 1..=3 {
+            let msg_content = TypedMessage::Post {
+                text: format!("Ephemeral announcement #{i}"),
+                mentions: None,
+            };
+            let msg = MessageValue::sign(last_msg.as_ref(), &keypair, json!(msg_content))?;
+            kv.append_feed(msg.clone()).await?;
+            last_msg = Some(msg);
+        }
+
+        let msg_ref = last_msg.unwrap().id().to_string();
+
+        assert_eq!(kv.remove_feed(&keypair.id).await?, 3);
+
+        assert_eq!(kv.get_latest_seq(&keypair.id)?, None);
+        assert!(kv.get_feed(&keypair.id)?.is_empty());
+        assert_eq!(kv.get_msg_val(&msg_ref)?, None);
+        assert!(kv.get_peers().await?.is_empty());
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_remove_feeds() -> Result<()> {
+        let (keypair, kv) = initialise_keypair_and_kv()?;
+        let other = SecretConfig::create().to_owned_identity()?;
+        let kept = SecretConfig::create().to_owned_identity()?;
+
+        for author in [&keypair, &other, &kept] {
+            let msg_content = TypedMessage::Post {
+                text: "Ephemeral announcement".to_string(),
+                mentions: None,
+            };
+            kv.append_feed(MessageValue::sign(None, author, json!(msg_content))?)
+                .await?;
+        }
+
+        let removed = kv
+            .remove_feeds(&[keypair.id.to_owned(), other.id.to_owned()])
+            .await?;
+        assert_eq!(removed, vec![1, 1]);
+
+        assert_eq!(kv.get_peers().await?, vec![(kept.id.to_owned(), 1)]);
+        let indexes = kv.indexes.as_ref().unwrap();
+        let timeline = indexes.get_timeline(TimelineOrder::Claimed, None, 10)?;
+        assert_eq!(timeline.entries.len(), 1);
+        assert_eq!(timeline.entries[0].author, kept.id);
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_read_state() -> Result<()> {
+        let (keypair, kv) = initialise_keypair_and_kv()?;
+
+        let mut msg_refs = Vec::new();
+        let mut last_msg = None;
+        for i in 1..=3 {
+            let msg_content = TypedMessage::Post {
+                text: format!("Unread announcement #{i}"),
+                mentions: None,
+            };
+            let msg = MessageValue::sign(last_msg.as_ref(), &keypair, json!(msg_content))?;
+            kv.append_feed(msg.clone()).await?;
+            msg_refs.push(msg.id().to_string());
+            last_msg = Some(msg);
+        }
+
+        let unread = kv.get_unread_counts("token-a").await?;
+        assert_eq!(unread.get(&keypair.id), Some(&3));
+
+        // Marking a message as read marks the messages before it as read.
+        assert!(kv.mark_read("token-a", &msg_refs[1])?);
+        assert!(!kv.mark_read("token-a", &msg_refs[1])?);
+        assert!(!kv.mark_read("token-a", &msg_refs[0])?);
+        assert!(kv.mark_read("token-a", "%unknown=.sha256").is_err());
+
+        let unread = kv.get_unread_counts("token-a").await?;
+        assert_eq!(unread.get(&keypair.id), Some(&1));
+
+        // Read state is kept separately for each client.
+        let unread = kv.get_unread_counts("token-b").await?;
+        assert_eq!(unread.get(&keypair.id), Some(&3));
+        assert!(kv.mark_read("token-b", &msg_refs[0])?);
+
+        assert!(kv.mark_read("token-a", &msg_refs[2])?);
+        assert!(kv.get_unread_counts("token-a").await?.is_empty());
+
+        // The read marks of a feed are deleted for every client.
+        assert_eq!(kv.remove_read_marks(&keypair.id)?, 2);
+        let unread = kv.get_unread_counts("token-a").await?;
+        assert_eq!(unread.get(&keypair.id), Some(&3));
+
+        // Only stored messages are counted, and the read marks of a removed
+        // feed are deleted along with it.
+        assert!(kv.mark_read("token-a", &msg_refs[0])?);
+        assert_eq!(kv.remove_feed(&keypair.id).await?, 3);
+        assert!(kv.get_unread_counts("token-a").await?.is_empty());
+        assert_eq!(kv.remove_read_marks(&keypair.id)?, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_network_stats() -> Result<()> {
+        let kv = open_temporary_kv()?;
+
+        let mut stats = NetworkStats {
+            messages_replicated: 2,
+            bytes_received: 1_024,
+            ..NetworkStats::default()
+        };
+        stats.peers.insert("@peer".to_string());
+        stats.merge(NetworkStats {
+            new_feeds: 1,
+            messages_replicated: 3,
+            peers: ["@peer".to_string(), "@other".to_string()]
+                .iter()
+                .cloned()
+                .collect(),
+            ..NetworkStats::default()
+        });
+        assert_eq!(stats.messages_replicated, 5);
+        assert_eq!(stats.peers.len(), 2);
+        assert!(!stats.is_empty());
+
+        kv.set_network_stats(19_000, &NetworkStats::default())?;
+        kv.set_network_stats(19_002, &stats)?;
+        assert_eq!(kv.get_network_stats(19_001)?, None);
+        assert_eq!(kv.get_network_stats(19_002)?, Some(stats.clone()));
+
+        let days: Vec<u64> = kv
+            .get_network_stats_since(19_001)?
+            .into_iter()
+            .map(|(day, _)| day)
+            .collect();
+        assert_eq!(days, vec![19_002]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pins() -> Result<()> {
+        let (keypair, kv) = initialise_keypair_and_kv()?;
+
+        assert!(!kv.is_feed_pinned(&keypair.id)?);
+        assert!(kv.pin_feed(&keypair.id)?);
+        assert!(!kv.pin_feed(&keypair.id)?);
+        assert!(kv.is_feed_pinned(&keypair.id)?);
+        assert_eq!(kv.get_pinned_feeds()?, vec![keypair.id.to_owned()]);
+
+        assert!(kv.unpin_feed(&keypair.id)?);
+        assert!(!kv.unpin_feed(&keypair.id)?);
+        assert!(kv.get_pinned_feeds()?.is_empty());
+
+        Ok(())
+    }
 }
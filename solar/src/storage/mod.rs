@@ -1,3 +1,13 @@
+pub mod backup;
 pub mod blob;
+pub mod export;
 pub mod indexes;
 pub mod kv;
+pub mod mutes;
+pub mod publish;
+pub mod records;
+pub mod repair;
+pub mod schema;
+pub mod synthetic;
+pub mod trees;
+pub mod validation;
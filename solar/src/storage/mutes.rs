@@ -0,0 +1,127 @@
+//! Mute patterns.
+//!
+//! Operators of public-facing viewers backed by solar can mute words or
+//! regular expressions: messages whose text (or channel) matches a mute
+//! pattern are left out of the timeline and channel indexes, and out of the
+//! results of the corresponding JSON-RPC queries, while still being stored
+//! and replicated. Muting a pattern does not remove the messages indexed
+//! before, which are instead skipped when queried.
+//!
+//! Mute patterns are managed at runtime with the `mute`, `unmute` and
+//! `mutes` JSON-RPC methods, and persist across restarts.
+use regex::RegexSet;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{error::Error, Result};
+
+/// A muted word (or phrase) or regular expression.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MutePattern {
+    /// A word or phrase, matched as a whole and regardless of case.
+    Word(String),
+    /// A regular expression, matched anywhere in the text.
+    Regex(String),
+}
+
+impl MutePattern {
+    /// Return the regular expression matching the pattern.
+    fn to_regex(&self) -> String {
+        match self {
+            MutePattern::Word(word) => {
+                format!(r"(?i)(?:^|\W){}(?:$|\W)", regex::escape(word.trim()))
+            }
+            MutePattern::Regex(regex) => regex.to_owned(),
+        }
+    }
+}
+
+/// The compiled mute patterns.
+#[derive(Debug, Clone)]
+pub struct MuteList {
+    patterns: Vec<MutePattern>,
+    set: RegexSet,
+}
+
+impl Default for MuteList {
+    fn default() -> Self {
+        MuteList {
+            patterns: Vec::new(),
+            set: RegexSet::empty(),
+        }
+    }
+}
+
+impl MuteList {
+    /// Compile the given mute patterns. Returns an error if a pattern is
+    /// empty or is not a valid regular expression.
+    pub fn new(patterns: Vec<MutePattern>) -> Result<Self> {
+        if let Some(pattern) = patterns.iter().find(|pattern| match pattern {
+            MutePattern::Word(word) => word.trim().is_empty(),
+            MutePattern::Regex(regex) => regex.is_empty(),
+        }) {
+            return Err(Error::Other(format!("Empty mute pattern: {:?}", pattern)));
+        }
+
+        let set = RegexSet::new(patterns.iter().map(MutePattern::to_regex))
+            .map_err(|err| Error::Other(format!("Invalid mute pattern: {}", err)))?;
+
+        Ok(MuteList { patterns, set })
+    }
+
+    /// Return the mute patterns.
+    pub fn patterns(&self) -> &[MutePattern] {
+        &self.patterns
+    }
+
+    /// Query whether the given text matches any mute pattern.
+    pub fn matches(&self, text: &str) -> bool {
+        self.set.is_match(text)
+    }
+
+    /// Query whether the given message content is muted, since its text or
+    /// channel matches a mute pattern. The content of private messages is an
+    /// encrypted string, which is never muted.
+    pub fn mutes(&self, content: &Value) -> bool {
+        if self.patterns.is_empty() {
+            return false;
+        }
+
+        ["text", "channel"]
+            .iter()
+            .filter_map(|field| content.get(field).and_then(Value::as_str))
+            .any(|text| self.matches(text))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    fn test_mute_list() -> Result<()> {
+        let mutes = MuteList::new(vec![
+            MutePattern::Word("spam".to_string()),
+            MutePattern::Regex(r"buy \d+ coins".to_string()),
+        ])?;
+
+        assert!(mutes.matches("Such SPAM!"));
+        assert!(!mutes.matches("spammers are whole words apart"));
+        assert!(mutes.matches("buy 100 coins now"));
+        assert!(!mutes.matches("Buy 100 coins now"));
+
+        assert!(mutes.mutes(&json!({ "type": "post", "text": "spam" })));
+        assert!(mutes.mutes(&json!({ "type": "channel", "channel": "spam" })));
+        assert!(!mutes.mutes(&json!({ "type": "post", "text": "ham" })));
+        assert!(!mutes.mutes(&json!("spam.box")));
+
+        // Invalid and empty patterns are rejected.
+        assert!(MuteList::new(vec![MutePattern::Regex("(".to_string())]).is_err());
+        assert!(MuteList::new(vec![MutePattern::Word(" ".to_string())]).is_err());
+
+        Ok(())
+    }
+}
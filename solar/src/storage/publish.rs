@@ -0,0 +1,433 @@
+//! Publishing on the local feed.
+//!
+//! Signing a message requires the latest message of the feed: two concurrent
+//! publishes reading the same latest message would sign two messages with the
+//! same sequence number, forking the feed. Publishes are therefore serialized
+//! by the publish lock. Each publish waits for its turn, then reads the
+//! latest message of the feed, signs, validates and appends the new message
+//! while holding the lock, and returns the sequence number of the new message
+//! to its caller.
+//!
+//! A caller may also require the state of the feed to be as expected before
+//! the message is published (for example, when the content was derived from
+//! the latest message of the feed). The publish fails with a conflict if
+//! another message has been published in the meantime.
+//!
+//! Publishing is disabled while an identity conflict is recorded (see the
+//! identity guard), to avoid forking a feed which is also being published
+//! on another device.
+//!
+//! Embedders may further constrain what applications write to the feed with
+//! a publish policy: a maximum content size, allowed or denied content types
+//! and per-type rate limits. Content which breaks the policy is rejected
+//! before it is signed.
+//!
+//! The policy may also name remote signers: identities whose private key is
+//! held by a client rather than by the node. Clients sign the messages of
+//! such a feed themselves and hand them to the node, which validates and
+//! appends them (see `append_signed`), so that the private key never has to
+//! live on the server hosting the node. Pre-signed messages are subject to
+//! the content restrictions of the policy, but not to its rate limits.
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use async_std::sync::Mutex;
+use kuska_ssb::{feed::Message, keystore::OwnedIdentity};
+use log::info;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    actors::replication::identity_guard,
+    config::PUBLISH_POLICY,
+    error::Error,
+    node::KV_STORE,
+    storage::validation::{self, Source},
+    Result,
+};
+
+/// Content type under which encrypted (boxed) content, whose type is not
+/// visible, is subject to the publish policy.
+pub const PRIVATE_CONTENT_TYPE: &str = "private";
+
+/// Lock serializing publishes on the local feed.
+static PUBLISH_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+/// Times of the recent publishes of each content type, for the rate limits
+/// of the publish policy. Only accessed while holding the publish lock.
+static RECENT_PUBLISHES: Lazy<std::sync::Mutex<RecentPublishes>> =
+    Lazy::new(|| std::sync::Mutex::new(RecentPublishes::default()));
+
+/// Maximum number of messages of a content type which may be published
+/// within a period.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RateLimit {
+    pub messages: usize,
+    /// Length of the period, in seconds.
+    pub period_secs: u64,
+}
+
+impl RateLimit {
+    fn period(&self) -> Duration {
+        Duration::from_secs(self.period_secs)
+    }
+}
+
+/// Policy constraining the messages published on the local feed.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PublishPolicy {
+    /// Maximum size of the content of a message, in bytes of its JSON
+    /// serialization (default: none, although the maximum size of the whole
+    /// message still applies).
+    #[serde(default)]
+    pub max_content_size: Option<usize>,
+
+    /// Content types which may be published; all other types are rejected
+    /// (default: all types may be published).
+    #[serde(default)]
+    pub allowed_types: Option<Vec<String>>,
+
+    /// Content types which may not be published (default: none).
+    #[serde(default)]
+    pub denied_types: Vec<String>,
+
+    /// Rate limits for individual content types (default: none).
+    #[serde(default)]
+    pub rate_limits: HashMap<String, RateLimit>,
+
+    /// Public keys of the remote signers whose pre-signed messages may be
+    /// appended to their feed (default: none).
+    #[serde(default)]
+    pub remote_signers: Vec<String>,
+}
+
+impl PublishPolicy {
+    /// Query whether the policy does not restrict publishing at all.
+    pub fn is_unrestricted(&self) -> bool {
+        self.max_content_size.is_none()
+            && self.allowed_types.is_none()
+            && self.denied_types.is_empty()
+            && self.rate_limits.is_empty()
+    }
+
+    /// Query whether the given author is a remote signer.
+    pub fn is_remote_signer(&self, author: &str) -> bool {
+        let author = author.trim_start_matches('@');
+        self.remote_signers
+            .iter()
+            .any(|signer| signer.trim_start_matches('@') == author)
+    }
+
+    /// Check the given content, of the given type, against the size and type
+    /// restrictions of the policy.
+    fn check_content(&self, content: &Value, content_type: &str) -> Result<()> {
+        if let Some(max_content_size) = self.max_content_size {
+            let size = serde_json::to_vec(content)?.len();
+            if size > max_content_size {
+                return Err(Error::PublishPolicy(format!(
+                    "content of {} bytes exceeds the maximum of {} bytes",
+                    size, max_content_size
+                )));
+            }
+        }
+
+        let is_allowed = self
+            .allowed_types
+            .as_ref()
+            .map_or(true, |allowed| allowed.iter().any(|t| t == content_type));
+        let is_denied = self.denied_types.iter().any(|t| t == content_type);
+        if !is_allowed || is_denied {
+            return Err(Error::PublishPolicy(format!(
+                "content type {} may not be published",
+                content_type
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// Times of the recent publishes of each content type.
+#[derive(Debug, Default)]
+struct RecentPublishes(HashMap<String, VecDeque<Instant>>);
+
+impl RecentPublishes {
+    /// Check whether a message of the given content type may be published at
+    /// the given time without exceeding the rate limit of the type (if any),
+    /// forgetting the publishes which fall out of its period.
+    fn check(&mut self, policy: &PublishPolicy, content_type: &str, now: Instant) -> Result<()> {
+        let limit = match policy.rate_limits.get(content_type) {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+
+        let times = self.0.entry(content_type.to_owned()).or_default();
+        while let Some(time) = times.front() {
+            if now.duration_since(*time) >= limit.period() {
+                times.pop_front();
+            } else {
+                break;
+            }
+        }
+
+        if times.len() >= limit.messages {
+            return Err(Error::PublishPolicy(format!(
+                "rate limit of {} {} messages per {} seconds exceeded",
+                limit.messages, content_type, limit.period_secs
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Record a publish of the given content type at the given time, if the
+    /// type is rate limited.
+    fn record(&mut self, policy: &PublishPolicy, content_type: &str, now: Instant) {
+        if policy.rate_limits.contains_key(content_type) {
+            self.0
+                .entry(content_type.to_owned())
+                .or_default()
+                .push_back(now);
+        }
+    }
+}
+
+/// Return the content type under which the given content is subject to the
+/// publish policy.
+fn policy_content_type(content: &Value) -> &str {
+    match content {
+        Value::String(_) => PRIVATE_CONTENT_TYPE,
+        _ => content["type"].as_str().unwrap_or_default(),
+    }
+}
+
+/// Expected state of the local feed for a message to be published.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expected {
+    /// The latest message of the feed has the given ID (`None` if the feed
+    /// is empty).
+    Previous(Option<String>),
+    /// The published message is assigned the given sequence number.
+    Sequence(u64),
+}
+
+impl Expected {
+    /// Check the expectation against the latest message of the feed.
+    fn check(&self, latest: Option<&Message>) -> Result<()> {
+        let previous = latest.map(|msg| msg.id().to_string());
+        let sequence = latest.map_or(0, |msg| msg.sequence()) + 1;
+
+        match self {
+            Expected::Previous(expected) if *expected != previous => {
+                Err(Error::PublishConflict(format!(
+                    "expected previous message {:?}, found {:?}",
+                    expected, previous
+                )))
+            }
+            Expected::Sequence(expected) if *expected != sequence => {
+                Err(Error::PublishConflict(format!(
+                    "expected sequence {}, next sequence is {}",
+                    expected, sequence
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Return the times of the recent publishes of each content type.
+fn recent_publishes() -> std::sync::MutexGuard<'static, RecentPublishes> {
+    RECENT_PUBLISHES
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+}
+
+/// Sign the given content with the given identity and append the resulting
+/// message to its feed, provided the feed is in the expected state (if any).
+/// Returns the published message and its sequence number.
+pub async fn publish(
+    identity: &OwnedIdentity,
+    content: Value,
+    expected: Option<Expected>,
+) -> Result<(Message, u64)> {
+    let _guard = PUBLISH_LOCK.lock().await;
+
+    identity_guard::ensure_no_conflict().await?;
+
+    // Enforce the publish policy, if any, before signing.
+    let policy = PUBLISH_POLICY
+        .get()
+        .filter(|policy| !policy.is_unrestricted());
+    let content_type = policy_content_type(&content).to_owned();
+    if let Some(policy) = policy {
+        policy.check_content(&content, &content_type)?;
+        recent_publishes().check(policy, &content_type, Instant::now())?;
+    }
+
+    let last_msg = KV_STORE.read().await.get_latest_msg_val(&identity.id)?;
+    if let Some(expected) = expected {
+        expected.check(last_msg.as_ref())?;
+    }
+
+    // Instantiate and cryptographically-sign a new message.
+    let msg = Message::sign(last_msg.as_ref(), identity, content).map_err(Error::Validation)?;
+
+    // Validate the message before it is appended.
+    validation::validate(&msg, last_msg.as_ref(), Source::Local)?;
+
+    // Append the signed message to the feed. The sequence number is checked
+    // once more when appending, guarding against messages of the local feed
+    // appended by other means (for example, when resyncing the feed).
+    let seq = KV_STORE.write().await.append_feed(msg.clone()).await?;
+
+    if let Some(policy) = policy {
+        recent_publishes().record(policy, &content_type, Instant::now());
+    }
+
+    info!(
+        "published message {} with sequence number {}",
+        msg.id().to_string(),
+        seq
+    );
+
+    Ok((msg, seq))
+}
+
+/// Append the given message, signed by a client on behalf of a remote
+/// signer, to the feed of its author. Returns the sequence number of the
+/// message.
+///
+/// The message is rejected unless its author is a remote signer of the
+/// publish policy and it follows the latest message of the feed.
+pub async fn append_signed(msg: &Message) -> Result<u64> {
+    let _guard = PUBLISH_LOCK.lock().await;
+
+    let author = msg.author().to_string();
+    let policy = PUBLISH_POLICY.get().cloned().unwrap_or_default();
+    if !policy.is_remote_signer(&author) {
+        return Err(Error::PublishPolicy(format!(
+            "{} is not a remote signer",
+            author
+        )));
+    }
+
+    let content = msg.content();
+    policy.check_content(content, policy_content_type(content))?;
+
+    let last_msg = KV_STORE.read().await.get_latest_msg_val(&author)?;
+    validation::validate(msg, last_msg.as_ref(), Source::Local)?;
+
+    let seq = KV_STORE.write().await.append_feed(msg.clone()).await?;
+
+    info!(
+        "appended pre-signed message {} of {} with sequence number {}",
+        msg.id().to_string(),
+        author,
+        seq
+    );
+
+    Ok(seq)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    use crate::secret_config::SecretConfig;
+
+    #[test]
+    fn test_expected() -> Result<()> {
+        let keypair = SecretConfig::create().to_owned_identity()?;
+        let msg = Message::sign(None, &keypair, json!({ "type": "post", "text": "hi" }))?;
+
+        assert!(Expected::Previous(None).check(None).is_ok());
+        assert!(Expected::Sequence(1).check(None).is_ok());
+        assert!(Expected::Sequence(2).check(None).is_err());
+
+        let id = msg.id().to_string();
+        assert!(Expected::Previous(Some(id)).check(Some(&msg)).is_ok());
+        assert!(Expected::Previous(None).check(Some(&msg)).is_err());
+        assert!(Expected::Sequence(2).check(Some(&msg)).is_ok());
+        assert!(Expected::Sequence(1).check(Some(&msg)).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_publish_policy() {
+        let policy = PublishPolicy {
+            max_content_size: Some(64),
+            allowed_types: Some(vec!["post".to_string(), "vote".to_string()]),
+            denied_types: vec!["vote".to_string()],
+            ..PublishPolicy::default()
+        };
+        assert!(!policy.is_unrestricted());
+
+        let post = json!({ "type": "post", "text": "hi" });
+        assert!(policy.check_content(&post, "post").is_ok());
+
+        // The content is too large.
+        let post = json!({ "type": "post", "text": "a".repeat(64) });
+        assert!(policy.check_content(&post, "post").is_err());
+
+        // The type is not allowed, or is denied.
+        let about = json!({ "type": "about", "about": "@abc" });
+        assert!(policy.check_content(&about, "about").is_err());
+        let vote = json!({ "type": "vote", "vote": { "link": "%abc", "value": 1 } });
+        assert!(policy.check_content(&vote, "vote").is_err());
+
+        // Boxed content is subject to the policy as the private type.
+        assert_eq!(policy_content_type(&json!("c2VjcmV0.box")), "private");
+        assert_eq!(policy_content_type(&post), "post");
+    }
+
+    #[test]
+    fn test_remote_signers() {
+        let policy = PublishPolicy {
+            remote_signers: vec!["abc=.ed25519".to_string()],
+            ..PublishPolicy::default()
+        };
+
+        // Remote signers do not restrict publishing on the local feed.
+        assert!(policy.is_unrestricted());
+
+        assert!(policy.is_remote_signer("@abc=.ed25519"));
+        assert!(policy.is_remote_signer("abc=.ed25519"));
+        assert!(!policy.is_remote_signer("@def=.ed25519"));
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let mut policy = PublishPolicy::default();
+        policy.rate_limits.insert(
+            "post".to_string(),
+            RateLimit {
+                messages: 2,
+                period_secs: 60,
+            },
+        );
+
+        let mut recent = RecentPublishes::default();
+        let start = Instant::now();
+        for secs in [0, 10] {
+            let now = start + Duration::from_secs(secs);
+            assert!(recent.check(&policy, "post", now).is_ok());
+            recent.record(&policy, "post", now);
+        }
+
+        // A third post within the period is rejected; other types are not
+        // limited.
+        let now = start + Duration::from_secs(30);
+        assert!(recent.check(&policy, "post", now).is_err());
+        assert!(recent.check(&policy, "about", now).is_ok());
+
+        // Once the first post falls out of the period, a post is accepted.
+        let now = start + Duration::from_secs(60);
+        assert!(recent.check(&policy, "post", now).is_ok());
+    }
+}
@@ -0,0 +1,211 @@
+//! Local records store.
+//!
+//! A key-value store for app-private data (settings, drafts, caches and the
+//! like) which is kept on this node only: records are never published to the
+//! local feed nor replicated to peers. Records are grouped by app, so that
+//! clients sharing a node do not clash, and hold arbitrary JSON values.
+//!
+//! Records are stored in a tree of the main database, keyed by the app name
+//! and the record key (separated by a NUL byte).
+
+use std::collections::BTreeMap;
+
+use serde_json::Value;
+use sled::{Db, Tree};
+
+use crate::{error::Error, storage::trees, Result};
+
+/// Maximum length of an app name or a record key, in bytes.
+pub const MAX_KEY_LEN: usize = 256;
+
+/// Maximum size of a record value (JSON-encoded), in bytes.
+pub const MAX_VALUE_SIZE: usize = 64 * 1024;
+
+/// Separator between the app name and the record key.
+const SEPARATOR: u8 = 0u8;
+
+/// Non-replicated records, stored in a tree of the main database.
+pub struct LocalRecords {
+    records: Tree,
+}
+
+impl LocalRecords {
+    /// Open the tree of local records of the given database.
+    pub fn open(db: &Db) -> Result<Self> {
+        Ok(LocalRecords {
+            records: db.open_tree(trees::LOCAL_RECORDS.name)?,
+        })
+    }
+
+    /// Ensure that the given app name or record key is neither empty nor too
+    /// long and holds no NUL character.
+    fn check_name(kind: &str, name: &str) -> Result<()> {
+        if name.is_empty() {
+            return Err(Error::LocalRecord(format!("{kind} must not be empty")));
+        }
+        if name.len() > MAX_KEY_LEN {
+            return Err(Error::LocalRecord(format!(
+                "{kind} exceeds {MAX_KEY_LEN} bytes"
+            )));
+        }
+        if name.as_bytes().contains(&SEPARATOR) {
+            return Err(Error::LocalRecord(format!(
+                "{kind} must not contain NUL characters"
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Generate the key prefix of the records of the given app.
+    fn app_prefix(app: &str) -> Result<Vec<u8>> {
+        LocalRecords::check_name("app", app)?;
+
+        let mut prefix = app.as_bytes().to_vec();
+        prefix.push(SEPARATOR);
+
+        Ok(prefix)
+    }
+
+    /// Generate the database key of the given record of the given app.
+    fn record_key(app: &str, key: &str) -> Result<Vec<u8>> {
+        LocalRecords::check_name("key", key)?;
+
+        let mut record_key = LocalRecords::app_prefix(app)?;
+        record_key.extend_from_slice(key.as_bytes());
+
+        Ok(record_key)
+    }
+
+    /// Set the value of the given record of the given app. Returns `true` if
+    /// the record did not exist yet.
+    pub fn set(&self, app: &str, key: &str, value: &Value) -> Result<bool> {
+        let record_key = LocalRecords::record_key(app, key)?;
+        let value = serde_json::to_vec(value)?;
+        if value.len() > MAX_VALUE_SIZE {
+            return Err(Error::LocalRecord(format!(
+                "value exceeds {MAX_VALUE_SIZE} bytes"
+            )));
+        }
+
+        Ok(self.records.insert(record_key, value)?.is_none())
+    }
+
+    /// Get the value of the given record of the given app.
+    pub fn get(&self, app: &str, key: &str) -> Result<Option<Value>> {
+        let record_key = LocalRecords::record_key(app, key)?;
+
+        match self.records.get(record_key)? {
+            Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Remove the given record of the given app. Returns `true` if the record
+    /// existed.
+    pub fn remove(&self, app: &str, key: &str) -> Result<bool> {
+        let record_key = LocalRecords::record_key(app, key)?;
+
+        Ok(self.records.remove(record_key)?.is_some())
+    }
+
+    /// Return the records of the given app whose key starts with the given
+    /// prefix, ordered by key.
+    pub fn list(&self, app: &str, key_prefix: &str) -> Result<BTreeMap<String, Value>> {
+        let app_prefix = LocalRecords::app_prefix(app)?;
+        let mut prefix = app_prefix.clone();
+        prefix.extend_from_slice(key_prefix.as_bytes());
+
+        let mut records = BTreeMap::new();
+        for entry in self.records.scan_prefix(prefix) {
+            let (record_key, value) = entry?;
+            let key = String::from_utf8_lossy(&record_key[app_prefix.len()..]).into_owned();
+            records.insert(key, serde_json::from_slice(&value)?);
+        }
+
+        Ok(records)
+    }
+
+    /// Remove all the records of the given app, returning the number of
+    /// records removed.
+    pub fn clear(&self, app: &str) -> Result<usize> {
+        let prefix = LocalRecords::app_prefix(app)?;
+
+        let mut removed = 0;
+        for entry in self.records.scan_prefix(prefix) {
+            let (record_key, _) = entry?;
+            self.records.remove(record_key)?;
+            removed += 1;
+        }
+
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+    use sled::Config;
+
+    fn open_temporary_records() -> Result<LocalRecords> {
+        let db = Config::new().temporary(true).open()?;
+
+        LocalRecords::open(&db)
+    }
+
+    #[test]
+    fn test_local_records() -> Result<()> {
+        let records = open_temporary_records()?;
+
+        assert!(records.set("patchbay", "settings", &json!({ "theme": "dark" }))?);
+        assert!(records.set("patchbay", "draft/1", &json!("Hello"))?);
+        assert!(records.set("patchbay", "draft/2", &json!("World"))?);
+        // Records of other apps are kept apart, even if the name of one app
+        // is a prefix of the name of the other.
+        assert!(records.set("patch", "settings", &json!(null))?);
+        assert!(!records.set("patchbay", "draft/2", &json!("Solar"))?);
+
+        assert_eq!(
+            records.get("patchbay", "settings")?,
+            Some(json!({ "theme": "dark" }))
+        );
+        assert_eq!(records.get("patch", "settings")?, Some(json!(null)));
+        assert_eq!(records.get("patchbay", "missing")?, None);
+
+        let drafts = records.list("patchbay", "draft/")?;
+        assert_eq!(
+            drafts.into_iter().collect::<Vec<_>>(),
+            vec![
+                ("draft/1".to_string(), json!("Hello")),
+                ("draft/2".to_string(), json!("Solar"))
+            ]
+        );
+        assert_eq!(records.list("patchbay", "")?.len(), 3);
+
+        assert!(records.remove("patchbay", "draft/1")?);
+        assert!(!records.remove("patchbay", "draft/1")?);
+        assert_eq!(records.clear("patchbay")?, 2);
+        assert!(records.list("patchbay", "")?.is_empty());
+        assert_eq!(records.list("patch", "")?.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_records() -> Result<()> {
+        let records = open_temporary_records()?;
+
+        assert!(records.set("", "settings", &json!(1)).is_err());
+        assert!(records.set("app", "", &json!(1)).is_err());
+        assert!(records.get("app\0other", "settings").is_err());
+        assert!(records.get("app", &"k".repeat(MAX_KEY_LEN + 1)).is_err());
+
+        let large = json!("x".repeat(MAX_VALUE_SIZE));
+        assert!(records.set("app", "cache", &large).is_err());
+        assert_eq!(records.get("app", "cache")?, None);
+
+        Ok(())
+    }
+}
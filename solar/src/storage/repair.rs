@@ -0,0 +1,192 @@
+//! Key-value database repair.
+//!
+//! A power loss can leave the sled database partially corrupted, at which
+//! point the node may no longer be able to read its feeds. Repairing copies
+//! every readable feed into a fresh database (re-indexing it in the process),
+//! moves the corrupted database aside as a backup and puts the fresh one in
+//! its place. Feeds are copied up to their first unreadable message; the
+//! report lists every feed which could not be copied in full.
+
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::{info, warn};
+use sled::Config as DbConfig;
+
+use crate::{storage::kv::KvStorage, Result};
+
+/// A feed which could not be fully recovered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LostFeed {
+    /// Public key of the feed author (the feed ID, for the formats other
+    /// than classic).
+    pub author: String,
+    /// Format of the feed (`classic`, `bendybutt-v1` or `buttwoo-v1`).
+    pub format: &'static str,
+    /// Number of messages recovered.
+    pub recovered: u64,
+    /// Number of messages stored before the corruption.
+    pub expected: u64,
+}
+
+/// Summary of a database repair.
+#[derive(Debug, Default)]
+pub struct RepairReport {
+    /// Number of feeds from which at least one message was recovered.
+    pub feeds_recovered: usize,
+    /// Total number of messages recovered.
+    pub messages_recovered: u64,
+    /// Feeds which could not be fully recovered.
+    pub lost: Vec<LostFeed>,
+    /// Number of database entries which could not be read.
+    pub unreadable_entries: usize,
+    /// Location of the corrupted database.
+    pub backup_path: Option<PathBuf>,
+}
+
+impl fmt::Display for RepairReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Recovered {} messages from {} feeds",
+            self.messages_recovered, self.feeds_recovered
+        )?;
+        if self.unreadable_entries > 0 {
+            writeln!(
+                f,
+                "Skipped {} unreadable database entries",
+                self.unreadable_entries
+            )?;
+        }
+        for lost in &self.lost {
+            writeln!(
+                f,
+                "Lost messages {}..={} of {} ({})",
+                lost.recovered + 1,
+                lost.expected,
+                lost.author,
+                lost.format
+            )?;
+        }
+        if let Some(backup_path) = &self.backup_path {
+            write!(f, "Corrupted database moved to {:?}", backup_path)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Salvage the readable feeds of the database at the given path into a
+/// fresh database, which then replaces it.
+pub async fn repair_database(path: &Path) -> Result<RepairReport> {
+    info!("Repairing database at {:?}", path);
+
+    let source = DbConfig::new().path(path).open()?;
+
+    // Remove any leftovers of an interrupted repair.
+    let repaired_path = path.with_extension("repaired");
+    if repaired_path.exists() {
+        fs::remove_dir_all(&repaired_path)?;
+    }
+
+    let mut repaired = KvStorage::default();
+    // The repaired database is not yet in use by any actor, so there is no
+    // need to keep the receiving end of the broker channel.
+    let (sender, _) = futures::channel::mpsc::unbounded();
+    repaired.open(DbConfig::new().path(&repaired_path), sender)?;
+
+    let mut report = repaired.salvage(&source).await?;
+
+    // Close both databases before moving them.
+    drop(repaired);
+    drop(source);
+
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+    let backup_path = path.with_extension(format!("corrupted-{secs}"));
+    fs::rename(path, &backup_path)?;
+    fs::rename(&repaired_path, path)?;
+
+    for lost in &report.lost {
+        warn!(
+            "Recovered {} of {} messages authored by {}",
+            lost.recovered, lost.expected, lost.author
+        );
+    }
+    report.backup_path = Some(backup_path);
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use kuska_ssb::{feed::Message as MessageValue, keystore::OwnedIdentity};
+    use serde_json::json;
+
+    use crate::storage::kv::{IdentityConflict, OutboxEntry};
+
+    #[async_std::test]
+    async fn test_repair_database() -> Result<()> {
+        let dir = tempdir::TempDir::new("solardb").unwrap();
+        let path = dir.path().join("feeds");
+        let keypair = OwnedIdentity::create();
+        let conflict = IdentityConflict {
+            peer: "@peer".to_string(),
+            sequence: 5,
+            local_sequence: 3,
+            detected_at: 1_000,
+        };
+        let draft = OutboxEntry {
+            content: json!({ "type": "post", "text": "Draft" }),
+            publish_at: None,
+            error: None,
+        };
+
+        {
+            let mut kv = KvStorage::default();
+            let (sender, _) = futures::channel::mpsc::unbounded();
+            kv.open(DbConfig::new().path(&path), sender)?;
+
+            let mut last_msg: Option<MessageValue> = None;
+            for i in 1..=3 {
+                let msg = MessageValue::sign(
+                    last_msg.as_ref(),
+                    &keypair,
+                    json!({ "type": "post", "text": format!("Post #{i}") }),
+                )?;
+                kv.append_feed(msg.clone()).await?;
+                last_msg = Some(msg);
+            }
+            kv.pin_feed(&keypair.id)?;
+            kv.set_identity_conflict(&conflict)?;
+            kv.add_outbox_entry(&draft)?;
+        }
+
+        let report = repair_database(&path).await?;
+        assert_eq!(report.feeds_recovered, 1);
+        assert_eq!(report.messages_recovered, 3);
+        assert!(report.lost.is_empty());
+        assert!(report.backup_path.as_ref().unwrap().exists());
+
+        let mut kv = KvStorage::default();
+        let (sender, _) = futures::channel::mpsc::unbounded();
+        kv.open(DbConfig::new().path(&path), sender)?;
+
+        assert_eq!(kv.get_latest_seq(&keypair.id)?, Some(3));
+        assert_eq!(kv.get_feed(&keypair.id)?.len(), 3);
+        assert!(kv.is_feed_pinned(&keypair.id)?);
+        assert_eq!(kv.get_identity_conflict()?, Some(conflict));
+        let outbox = kv.get_outbox_entries()?;
+        assert_eq!(outbox.len(), 1);
+        assert_eq!(outbox[0].1, draft);
+
+        Ok(())
+    }
+}
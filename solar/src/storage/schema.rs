@@ -0,0 +1,232 @@
+//! Schema registry for well-known content types.
+//!
+//! Each schema lists the fields of a content type (`post`, `contact`,
+//! `about` and `vote`), whether they are required and the kind of value they
+//! hold. Nested fields are named by their dotted path (eg. `vote.link`).
+//! Optional fields may be omitted or `null`; fields which are not listed are
+//! allowed, since clients commonly extend the well-known types.
+//!
+//! The registry is used by the schema check of the validation pipeline,
+//! which rejects malformed messages published locally, and by the JSON-RPC
+//! API to flag malformed messages in query results.
+use serde_json::Value;
+
+/// Kind of value held by a field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    String,
+    Bool,
+    Number,
+    Object,
+    Array,
+    /// A feed ID (`@...`).
+    FeedId,
+    /// A message ID (`%...`).
+    MsgId,
+    /// A message ID or an array of message IDs.
+    MsgIds,
+    /// A blob ID (`&...`), or an object linking to a blob ID.
+    Blob,
+    /// A feed, message or blob ID.
+    Link,
+}
+
+impl Kind {
+    /// Query whether the given value is of this kind.
+    fn matches(&self, value: &Value) -> bool {
+        let has_sigil = |value: &Value, sigils: &str| {
+            value
+                .as_str()
+                .and_then(|id| id.chars().next())
+                .map_or(false, |sigil| sigils.contains(sigil))
+        };
+
+        match self {
+            Kind::String => value.is_string(),
+            Kind::Bool => value.is_boolean(),
+            Kind::Number => value.is_number(),
+            Kind::Object => value.is_object(),
+            Kind::Array => value.is_array(),
+            Kind::FeedId => has_sigil(value, "@"),
+            Kind::MsgId => has_sigil(value, "%"),
+            Kind::MsgIds => match value.as_array() {
+                Some(ids) => ids.iter().all(|id| has_sigil(id, "%")),
+                None => has_sigil(value, "%"),
+            },
+            Kind::Blob => has_sigil(value, "&") || has_sigil(&value["link"], "&"),
+            Kind::Link => has_sigil(value, "@%&"),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Kind::String => "a string",
+            Kind::Bool => "a boolean",
+            Kind::Number => "a number",
+            Kind::Object => "an object",
+            Kind::Array => "an array",
+            Kind::FeedId => "a feed ID",
+            Kind::MsgId => "a message ID",
+            Kind::MsgIds => "one or more message IDs",
+            Kind::Blob => "a blob link",
+            Kind::Link => "a feed, message or blob ID",
+        }
+    }
+}
+
+/// A field of a content type.
+#[derive(Debug)]
+pub struct Field {
+    /// Dotted path of the field within the content.
+    pub path: &'static str,
+    pub kind: Kind,
+    pub required: bool,
+}
+
+/// The schema of a content type.
+#[derive(Debug)]
+pub struct Schema {
+    pub content_type: &'static str,
+    pub fields: &'static [Field],
+}
+
+const fn required(path: &'static str, kind: Kind) -> Field {
+    Field {
+        path,
+        kind,
+        required: true,
+    }
+}
+
+const fn optional(path: &'static str, kind: Kind) -> Field {
+    Field {
+        path,
+        kind,
+        required: false,
+    }
+}
+
+/// Schemas of the well-known content types.
+pub const SCHEMAS: &[Schema] = &[
+    Schema {
+        content_type: "about",
+        fields: &[
+            required("about", Kind::Link),
+            optional("name", Kind::String),
+            optional("description", Kind::String),
+            optional("image", Kind::Blob),
+        ],
+    },
+    Schema {
+        content_type: "contact",
+        fields: &[
+            required("contact", Kind::FeedId),
+            optional("following", Kind::Bool),
+            optional("blocking", Kind::Bool),
+            optional("autofollow", Kind::Bool),
+        ],
+    },
+    Schema {
+        content_type: "post",
+        fields: &[
+            required("text", Kind::String),
+            optional("channel", Kind::String),
+            optional("root", Kind::MsgId),
+            optional("branch", Kind::MsgIds),
+            optional("fork", Kind::MsgId),
+            optional("mentions", Kind::Array),
+            optional("recps", Kind::Array),
+        ],
+    },
+    Schema {
+        content_type: "vote",
+        fields: &[
+            required("vote", Kind::Object),
+            required("vote.link", Kind::Link),
+            required("vote.value", Kind::Number),
+            optional("vote.expression", Kind::String),
+        ],
+    },
+];
+
+/// Return the schema of the given content type, if it is a well-known type.
+pub fn schema(content_type: &str) -> Option<&'static Schema> {
+    SCHEMAS
+        .iter()
+        .find(|schema| schema.content_type == content_type)
+}
+
+/// Return the value at the given dotted path of the given content.
+fn lookup<'a>(content: &'a Value, path: &str) -> &'a Value {
+    path.split('.').fold(content, |value, key| &value[key])
+}
+
+/// Return the violations of the schema of its type by the given content,
+/// which is expected to be an object. Content of types without a schema has
+/// no violations.
+pub fn violations(content: &Value) -> Vec<String> {
+    let schema = match content["type"].as_str().and_then(schema) {
+        Some(schema) => schema,
+        None => return Vec::new(),
+    };
+
+    schema
+        .fields
+        .iter()
+        .filter_map(|field| match lookup(content, field.path) {
+            Value::Null if field.required => Some(format!("missing {}", field.path)),
+            Value::Null => None,
+            value if !field.kind.matches(value) => {
+                Some(format!("{} is not {}", field.path, field.kind.name()))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    fn test_violations() {
+        let post = json!({
+            "type": "post",
+            "text": "a reply",
+            "root": "%root=.sha256",
+            "branch": ["%root=.sha256", "%reply=.sha256"],
+            "channel": null,
+            "custom": 1
+        });
+        assert!(violations(&post).is_empty());
+
+        let post = json!({ "type": "post", "root": "@feed=.ed25519" });
+        assert_eq!(
+            violations(&post),
+            vec!["missing text", "root is not a message ID"]
+        );
+
+        let about = json!({
+            "type": "about",
+            "about": "@feed=.ed25519",
+            "image": { "link": "&blob=.sha256", "size": 512 }
+        });
+        assert!(violations(&about).is_empty());
+
+        let vote = json!({ "type": "vote", "vote": { "link": "%msg=.sha256" } });
+        assert_eq!(violations(&vote), vec!["missing vote.value"]);
+        let vote = json!({ "type": "vote", "vote": 1 });
+        assert_eq!(
+            violations(&vote),
+            vec![
+                "vote is not an object",
+                "missing vote.link",
+                "missing vote.value"
+            ]
+        );
+
+        assert!(violations(&json!({ "type": "gathering" })).is_empty());
+    }
+}
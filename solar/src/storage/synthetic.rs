@@ -0,0 +1,74 @@
+//! Synthetic feed generation.
+//!
+//! Generate signed feeds containing a mix of commonly-indexed message types
+//! (posts, abouts, contacts and channel subscriptions). Used by the
+//! benchmark suite to populate the database with realistic data.
+
+use kuska_ssb::{feed::Message as MessageValue, keystore::OwnedIdentity};
+use serde_json::json;
+
+use crate::{error::Error, Result};
+
+/// Generate the given number of unique public-private keypairs.
+pub fn generate_keypairs(count: usize) -> Vec<OwnedIdentity> {
+    (0..count).map(|_| OwnedIdentity::create()).collect()
+}
+
+/// Generate a signed feed of the given length, authored by the given keypair.
+///
+/// Message types are rotated in a fixed order: post, about, contact and
+/// channel. Contact messages follow the given contacts in turn (if any).
+pub fn generate_feed(
+    author: &OwnedIdentity,
+    contacts: &[String],
+    length: u64,
+) -> Result<Vec<MessageValue>> {
+    let mut feed: Vec<MessageValue> = Vec::new();
+
+    for seq in 1..=length {
+        let content = match seq % 4 {
+            1 => json!({
+                "type": "post",
+                "text": format!("Synthetic post #{seq}"),
+            }),
+            2 => json!({
+                "type": "about",
+                "about": author.id,
+                "name": format!("synthetic_{seq}"),
+                "description": format!("Synthetic description #{seq}"),
+            }),
+            3 if !contacts.is_empty() => json!({
+                "type": "contact",
+                "contact": contacts[(seq as usize / 4) % contacts.len()],
+                "following": true,
+                "blocking": false,
+            }),
+            _ => json!({
+                "type": "channel",
+                "channel": format!("synthetic_{}", seq % 16),
+                "subscribed": true,
+            }),
+        };
+
+        let msg = MessageValue::sign(feed.last(), author, content).map_err(Error::Validation)?;
+        feed.push(msg);
+    }
+
+    Ok(feed)
+}
+
+/// Generate one signed feed of the given length for each of the given
+/// authors. Contact messages follow the other authors in turn.
+pub fn generate_feeds(authors: &[OwnedIdentity], length: u64) -> Result<Vec<Vec<MessageValue>>> {
+    let ids: Vec<String> = authors.iter().map(|author| author.id.to_owned()).collect();
+
+    authors
+        .iter()
+        .map(|author| {
+            let contacts: Vec<String> =
+                ids.iter().filter(|id| **id != author.id).cloned().collect();
+
+            generate_feed(author, &contacts, length)
+        })
+        .collect()
+}
@@ -0,0 +1,292 @@
+//! Trees of the key-value database.
+//!
+//! `KvStorage` keeps each kind of record (messages, message references,
+//! latest sequence numbers, blobs, peers and so on) in a tree of its own, so
+//! that range scans only visit records of the kind being looked up and so
+//! that each kind of record can be tuned and inspected separately.
+//!
+//! Earlier versions kept every record in the default tree, under a key
+//! prefixed with a byte identifying the kind of record. Such records are
+//! moved into their tree (without the prefix byte) when the database is
+//! opened.
+
+use log::info;
+use sled::{Db, IVec, Tree};
+
+use crate::Result;
+
+/// A tree of the key-value database.
+#[derive(Debug, Clone, Copy)]
+pub struct TreeSpec {
+    /// Name of the tree.
+    pub name: &'static str,
+    /// Prefix of the keys of the records of the tree in the default tree of
+    /// earlier versions (if such records were stored then).
+    pub legacy_prefix: Option<u8>,
+}
+
+/// Latest sequence number of each stored feed.
+pub const LATEST_SEQ: TreeSpec = TreeSpec::legacy("latest_seq", 0);
+/// Message KVTs (Key Value Timestamp), keyed by sequence number and author.
+pub const MESSAGES: TreeSpec = TreeSpec::legacy("messages", 1);
+/// Author and sequence number of each message, keyed by message ID.
+pub const MSG_REFS: TreeSpec = TreeSpec::legacy("msg_refs", 2);
+/// Status of each blob.
+pub const BLOBS: TreeSpec = TreeSpec::legacy("blobs", 3);
+/// Peers whose feeds are stored.
+pub const PEERS: TreeSpec = TreeSpec::legacy("peers", 4);
+/// Replication log of each peer.
+pub const REPLICATION_LOGS: TreeSpec = TreeSpec::legacy("replication_logs", 5);
+/// Pinned feeds.
+pub const PINNED_FEEDS: TreeSpec = TreeSpec::legacy("pinned_feeds", 6);
+/// Want-list of blobs.
+pub const BLOB_WANTS: TreeSpec = TreeSpec::legacy("blob_wants", 8);
+/// Outbox entries, keyed by ID.
+pub const OUTBOX: TreeSpec = TreeSpec::legacy("outbox", 9);
+/// The detected identity conflict, stored under the empty key.
+pub const IDENTITY_CONFLICT: TreeSpec = TreeSpec::legacy("identity_conflict", 10);
+/// Capabilities of each peer.
+pub const PEER_CAPABILITIES: TreeSpec = TreeSpec::legacy("peer_capabilities", 11);
+/// Values in the scope of each client.
+pub const CLIENT_VALUES: TreeSpec = TreeSpec::legacy("client_values", 12);
+/// Network statistics, keyed by day.
+pub const NETWORK_STATS: TreeSpec = TreeSpec::legacy("network_stats", 13);
+/// Reputation of each peer.
+pub const PEER_REPUTATIONS: TreeSpec = TreeSpec::new("peer_reputations");
+/// Reference of the follow message of each peer waiting to be followed back.
+pub const FOLLOW_REQUESTS: TreeSpec = TreeSpec::new("follow_requests");
+/// Message KVTs fetched out of order (by ID) rather than replicated, keyed
+/// by message ID.
+pub const OOO_MESSAGES: TreeSpec = TreeSpec::new("ooo_messages");
+/// Storage used by each feed replicated in mirror mode.
+pub const MIRRORED_FEEDS: TreeSpec = TreeSpec::new("mirrored_feeds");
+/// Trace of the messages of each traced feed.
+pub const MESSAGE_TRACES: TreeSpec = TreeSpec::new("message_traces");
+/// Latest sequence number of each replicated Bendy Butt feed (zero if no
+/// message of the feed is stored yet).
+pub const BENDYBUTT_FEEDS: TreeSpec = TreeSpec::new("bendybutt_feeds");
+/// Bencoded Bendy Butt messages, keyed by author public key and sequence
+/// number.
+pub const BENDYBUTT_MESSAGES: TreeSpec = TreeSpec::new("bendybutt_messages");
+/// Latest sequence number of each replicated buttwoo feed (zero if no
+/// message of the feed is stored yet).
+pub const BUTTWOO_FEEDS: TreeSpec = TreeSpec::new("buttwoo_feeds");
+/// BIPF-encoded buttwoo messages, keyed by author public key and sequence
+/// number.
+pub const BUTTWOO_MESSAGES: TreeSpec = TreeSpec::new("buttwoo_messages");
+/// Mute patterns, keyed by JSON-serialized pattern.
+pub const MUTE_PATTERNS: TreeSpec = TreeSpec::new("mute_patterns");
+/// Vector clock values last sent to each peer, keyed by peer and feed ID,
+/// along with the time the full clock was last sent to each peer, keyed by
+/// peer ID.
+pub const SENT_CLOCKS: TreeSpec = TreeSpec::new("sent_clocks");
+/// Non-replicated records of local apps, keyed by app name and record key
+/// (kept by `LocalRecords` rather than `KvStorage`).
+pub const LOCAL_RECORDS: TreeSpec = TreeSpec::new("local_records");
+
+impl TreeSpec {
+    const fn new(name: &'static str) -> Self {
+        TreeSpec {
+            name,
+            legacy_prefix: None,
+        }
+    }
+
+    const fn legacy(name: &'static str, legacy_prefix: u8) -> Self {
+        TreeSpec {
+            name,
+            legacy_prefix: Some(legacy_prefix),
+        }
+    }
+
+    /// Iterate over the records of the tree stored by earlier versions in
+    /// the default tree of the given database.
+    fn scan_legacy(&self, db: &Db) -> impl Iterator<Item = sled::Result<(IVec, IVec)>> {
+        self.legacy_prefix
+            .map(|prefix| db.scan_prefix([prefix]))
+            .into_iter()
+            .flatten()
+    }
+
+    /// Open the tree in the given database, moving any records stored by
+    /// earlier versions into it.
+    fn open(&self, db: &Db) -> Result<Tree> {
+        let tree = db.open_tree(self.name)?;
+
+        // Records are inserted before being removed from the default tree,
+        // so that an interrupted migration is resumed on the next open.
+        let mut moved = 0;
+        for entry in self.scan_legacy(db) {
+            let (key, value) = entry?;
+            tree.insert(&key[1..], value)?;
+            db.remove(key)?;
+            moved += 1;
+        }
+        if moved > 0 {
+            info!("Moved {} records into the {} tree", moved, self.name);
+        }
+
+        Ok(tree)
+    }
+
+    /// Open the tree in the given database only if it exists, to avoid
+    /// writing to a database which is being read from.
+    fn open_existing(&self, db: &Db) -> Result<Option<Tree>> {
+        let exists = db
+            .tree_names()
+            .iter()
+            .any(|name| &name[..] == self.name.as_bytes());
+
+        Ok(if exists {
+            Some(db.open_tree(self.name)?)
+        } else {
+            None
+        })
+    }
+
+    /// Iterate over the records of the tree in the given database, including
+    /// those stored by earlier versions (whose keys are returned without the
+    /// prefix byte), without modifying the database.
+    pub fn scan_unmigrated(
+        &self,
+        db: &Db,
+    ) -> Result<impl Iterator<Item = sled::Result<(IVec, IVec)>>> {
+        let legacy = self
+            .scan_legacy(db)
+            .map(|entry| entry.map(|(key, value)| (IVec::from(&key[1..]), value)));
+
+        Ok(self
+            .open_existing(db)?
+            .into_iter()
+            .flat_map(|tree| tree.iter())
+            .chain(legacy))
+    }
+
+    /// Get the record of the tree with the given key from the given
+    /// database, which may have been stored by an earlier version, without
+    /// modifying the database.
+    pub fn get_unmigrated(&self, db: &Db, key: &[u8]) -> Result<Option<IVec>> {
+        if let Some(tree) = self.open_existing(db)? {
+            if let Some(value) = tree.get(key)? {
+                return Ok(Some(value));
+            }
+        }
+
+        match self.legacy_prefix {
+            Some(prefix) => {
+                let mut legacy_key = vec![prefix];
+                legacy_key.extend_from_slice(key);
+
+                Ok(db.get(legacy_key)?)
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// The trees in which `KvStorage` keeps its records.
+pub struct Trees {
+    pub latest_seq: Tree,
+    pub messages: Tree,
+    pub msg_refs: Tree,
+    pub blobs: Tree,
+    pub peers: Tree,
+    pub replication_logs: Tree,
+    pub pinned_feeds: Tree,
+    pub blob_wants: Tree,
+    pub outbox: Tree,
+    pub identity_conflict: Tree,
+    pub peer_capabilities: Tree,
+    pub client_values: Tree,
+    pub network_stats: Tree,
+    pub peer_reputations: Tree,
+    pub follow_requests: Tree,
+    pub ooo_messages: Tree,
+    pub mirrored_feeds: Tree,
+    pub message_traces: Tree,
+    pub bendybutt_feeds: Tree,
+    pub bendybutt_messages: Tree,
+    pub buttwoo_feeds: Tree,
+    pub buttwoo_messages: Tree,
+    pub mute_patterns: Tree,
+    pub sent_clocks: Tree,
+}
+
+impl Trees {
+    /// Open the trees of the given database, moving any records stored by
+    /// earlier versions into them.
+    pub fn open(db: &Db) -> Result<Self> {
+        Ok(Trees {
+            latest_seq: LATEST_SEQ.open(db)?,
+            messages: MESSAGES.open(db)?,
+            msg_refs: MSG_REFS.open(db)?,
+            blobs: BLOBS.open(db)?,
+            peers: PEERS.open(db)?,
+            replication_logs: REPLICATION_LOGS.open(db)?,
+            pinned_feeds: PINNED_FEEDS.open(db)?,
+            blob_wants: BLOB_WANTS.open(db)?,
+            outbox: OUTBOX.open(db)?,
+            identity_conflict: IDENTITY_CONFLICT.open(db)?,
+            peer_capabilities: PEER_CAPABILITIES.open(db)?,
+            client_values: CLIENT_VALUES.open(db)?,
+            network_stats: NETWORK_STATS.open(db)?,
+            peer_reputations: PEER_REPUTATIONS.open(db)?,
+            follow_requests: FOLLOW_REQUESTS.open(db)?,
+            ooo_messages: OOO_MESSAGES.open(db)?,
+            mirrored_feeds: MIRRORED_FEEDS.open(db)?,
+            message_traces: MESSAGE_TRACES.open(db)?,
+            bendybutt_feeds: BENDYBUTT_FEEDS.open(db)?,
+            bendybutt_messages: BENDYBUTT_MESSAGES.open(db)?,
+            buttwoo_feeds: BUTTWOO_FEEDS.open(db)?,
+            buttwoo_messages: BUTTWOO_MESSAGES.open(db)?,
+            mute_patterns: MUTE_PATTERNS.open(db)?,
+            sent_clocks: SENT_CLOCKS.open(db)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use sled::Config;
+
+    #[test]
+    fn test_migrate_prefixed_records() -> Result<()> {
+        let db = Config::new().temporary(true).open()?;
+        db.insert([4u8, b'@', b'a'], &[] as &[u8])?;
+        db.insert([13u8, 0, 0, 0, 0, 0, 0, 0, 1], &[7u8] as &[u8])?;
+        db.insert([10u8], &[1u8] as &[u8])?;
+
+        // Records stored by earlier versions can be read without being
+        // moved.
+        let peers: Vec<_> = PEERS.scan_unmigrated(&db)?.collect::<sled::Result<_>>()?;
+        assert_eq!(
+            peers,
+            vec![(IVec::from(&b"@a"[..]), IVec::from(&[] as &[u8]))]
+        );
+        assert_eq!(
+            IDENTITY_CONFLICT.get_unmigrated(&db, &[])?,
+            Some(IVec::from(&[1u8][..]))
+        );
+        assert!(!db.tree_names().contains(&IVec::from(PEERS.name.as_bytes())));
+
+        let trees = Trees::open(&db)?;
+        assert!(db.is_empty());
+        assert!(trees.peers.contains_key(b"@a")?);
+        assert_eq!(
+            trees.network_stats.get(1u64.to_be_bytes())?,
+            Some(IVec::from(&[7u8][..]))
+        );
+        assert_eq!(
+            trees.identity_conflict.get(&[] as &[u8])?,
+            Some(IVec::from(&[1u8][..]))
+        );
+
+        // Records in the trees are found as well.
+        assert_eq!(PEERS.scan_unmigrated(&db)?.count(), 1);
+        assert!(MESSAGES.get_unmigrated(&db, b"missing")?.is_none());
+
+        Ok(())
+    }
+}
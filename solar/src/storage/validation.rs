@@ -0,0 +1,446 @@
+//! Message validation pipeline.
+//!
+//! Each message is passed through a series of checks before it is appended
+//! to a feed:
+//!
+//!  - Hash chain: the sequence number follows the latest stored message of
+//!    the feed and `previous` references that message
+//!  - Signature: the message is signed (the signature itself is verified
+//!    when the message is parsed or signed)
+//!  - Size: the message does not exceed the maximum size of 8192 characters
+//!  - Schema: the content is either a private box or an object whose fields
+//!    match the schema of its type, for the well-known types (see the schema
+//!    registry)
+//!
+//! Messages are validated strictly or leniently depending on their source
+//! (published locally or replicated), as set in the validation policy. In
+//! strict mode, any failed check rejects the message. In lenient mode, only
+//! failures of the hash chain and signature checks reject the message: size
+//! and schema failures are logged and tolerated, so that the known-bad
+//! messages found on old feeds do not prevent the rest of the feed from being
+//! replicated.
+//!
+//! Bendy Butt messages (of metafeeds) and buttwoo messages are only checked
+//! for their hash chain, content and signatures, whose failures always
+//! reject the message.
+use std::fmt;
+
+use kuska_sodiumoxide::crypto::sign::ed25519::{verify_detached, PublicKey, Signature};
+use kuska_ssb::feed::{Feed as MessageKvt, Message};
+use log::warn;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use solar_core::{
+    bendybutt::{BendyButtMessage, ContentSection},
+    buttwoo::ButtwooMessage,
+};
+
+use crate::{
+    actors::replication::config::Strictness, config::VALIDATION_POLICY, error::Error,
+    storage::schema, Result,
+};
+
+/// Maximum size of a message, in UTF-16 code units of its JSON
+/// serialization (indented by two spaces).
+pub const MAX_MESSAGE_SIZE: usize = 8192;
+
+/// Source of a message.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Source {
+    /// Published by the local identity.
+    Local,
+    /// Received from a peer.
+    Replicated,
+}
+
+/// Check performed by the validation pipeline.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Check {
+    HashChain,
+    Signature,
+    Size,
+    Schema,
+}
+
+impl Check {
+    /// Query whether a failure of the check rejects the message regardless
+    /// of the strictness.
+    fn is_essential(&self) -> bool {
+        matches!(self, Check::HashChain | Check::Signature)
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Check::HashChain => write!(f, "hash chain"),
+            Check::Signature => write!(f, "signature"),
+            Check::Size => write!(f, "size"),
+            Check::Schema => write!(f, "schema"),
+        }
+    }
+}
+
+/// Failed check of a message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Failure {
+    pub check: Check,
+    pub reason: String,
+}
+
+impl Failure {
+    fn new(check: Check, reason: impl Into<String>) -> Self {
+        Self {
+            check,
+            reason: reason.into(),
+        }
+    }
+}
+
+/// Check that the message follows the given latest stored message of the
+/// feed (if any).
+fn check_hash_chain(msg: &Message, value: &Value, latest: Option<&Message>) -> Option<Failure> {
+    let expected_seq = latest.map_or(0, |latest| latest.sequence()) + 1;
+    if msg.sequence() != expected_seq {
+        return Some(Failure::new(
+            Check::HashChain,
+            format!("expected sequence {}, got {}", expected_seq, msg.sequence()),
+        ));
+    }
+
+    let expected_previous = latest.map(|latest| latest.id().to_string());
+    let previous = value["previous"].as_str().map(str::to_owned);
+    if previous != expected_previous {
+        return Some(Failure::new(
+            Check::HashChain,
+            format!(
+                "expected previous {:?}, got {:?}",
+                expected_previous, previous
+            ),
+        ));
+    }
+
+    None
+}
+
+/// Check that the message carries an ed25519 signature.
+fn check_signature(value: &Value) -> Option<Failure> {
+    match value["signature"].as_str() {
+        Some(signature) if signature.ends_with(".sig.ed25519") => None,
+        _ => Some(Failure::new(Check::Signature, "missing ed25519 signature")),
+    }
+}
+
+/// Check that the message does not exceed the maximum size.
+fn check_size(value: &Value) -> Option<Failure> {
+    let size = serde_json::to_string_pretty(value)
+        .map(|json| json.encode_utf16().count())
+        .unwrap_or(usize::MAX);
+
+    if size > MAX_MESSAGE_SIZE {
+        Some(Failure::new(
+            Check::Size,
+            format!("{} characters exceeds {}", size, MAX_MESSAGE_SIZE),
+        ))
+    } else {
+        None
+    }
+}
+
+/// Check that the content of the message matches the schema of its type.
+pub fn check_schema(content: &Value) -> Option<Failure> {
+    let content = match content {
+        Value::String(boxed) if boxed.ends_with(".box") => return None,
+        Value::Object(_) => content,
+        _ => {
+            return Some(Failure::new(
+                Check::Schema,
+                "content is neither an object nor a private box",
+            ))
+        }
+    };
+
+    let content_type = match content["type"].as_str() {
+        Some(content_type) if (3..=52).contains(&content_type.chars().count()) => content_type,
+        _ => return Some(Failure::new(Check::Schema, "invalid content type")),
+    };
+
+    let violations = schema::violations(content);
+    if violations.is_empty() {
+        None
+    } else {
+        Some(Failure::new(
+            Check::Schema,
+            format!(
+                "invalid {} content: {}",
+                content_type,
+                violations.join(", ")
+            ),
+        ))
+    }
+}
+
+/// Run every check of the pipeline on the given message, which is expected
+/// to follow the given latest stored message of its feed, and return the
+/// failed checks.
+pub fn check(msg: &Message, latest: Option<&Message>) -> Vec<Failure> {
+    let value = MessageKvt::new(msg.clone()).value;
+
+    vec![
+        check_hash_chain(msg, &value, latest),
+        check_signature(&value),
+        check_size(&value),
+        check_schema(&value["content"]),
+    ]
+    .into_iter()
+    .flatten()
+    .collect()
+}
+
+/// Validate the given message with the given strictness. Tolerated failures
+/// are logged.
+pub fn validate_with(
+    msg: &Message,
+    latest: Option<&Message>,
+    strictness: Strictness,
+) -> Result<()> {
+    for failure in check(msg, latest) {
+        if failure.check.is_essential() || strictness == Strictness::Strict {
+            return Err(Error::InvalidMessage(format!(
+                "message {} of {} failed the {} check: {}",
+                msg.sequence(),
+                msg.author(),
+                failure.check,
+                failure.reason
+            )));
+        }
+
+        warn!(
+            "Tolerating message {} of {} which failed the {} check: {}",
+            msg.sequence(),
+            msg.author(),
+            failure.check,
+            failure.reason
+        );
+    }
+
+    Ok(())
+}
+
+/// Validate the given message from the given source, according to the
+/// validation policy.
+pub fn validate(msg: &Message, latest: Option<&Message>, source: Source) -> Result<()> {
+    let policy = VALIDATION_POLICY.get().cloned().unwrap_or_default();
+    let strictness = match source {
+        Source::Local => policy.local,
+        Source::Replicated => policy.replicated,
+    };
+
+    validate_with(msg, latest, strictness)
+}
+
+/// Query whether the given ed25519 signature of the given data by the given
+/// public key is valid.
+fn verify_ed25519(key: &[u8], data: &[u8], signature: &[u8]) -> bool {
+    match (PublicKey::from_slice(key), Signature::from_slice(signature)) {
+        (Some(key), Some(signature)) => verify_detached(&signature, data, &key),
+        _ => false,
+    }
+}
+
+/// Run the checks of Bendy Butt messages on the given message, which is
+/// expected to follow the given latest stored (bencoded) message of its
+/// feed, and return the failed checks.
+pub fn check_bendybutt(msg: &BendyButtMessage, latest: Option<&[u8]>) -> Vec<Failure> {
+    let mut failures = Vec::new();
+
+    let latest_seq = match latest.map(BendyButtMessage::from_slice).transpose() {
+        Ok(latest) => latest.map_or(0, |latest| latest.sequence),
+        Err(err) => {
+            failures.push(Failure::new(Check::HashChain, err.to_string()));
+            return failures;
+        }
+    };
+    let expected_previous = latest.map(|latest| {
+        let mut hash = [0; 32];
+        hash.copy_from_slice(&Sha256::digest(latest));
+        hash
+    });
+    if msg.sequence != latest_seq + 1 {
+        failures.push(Failure::new(
+            Check::HashChain,
+            format!("expected sequence {}, got {}", latest_seq + 1, msg.sequence),
+        ));
+    } else if msg.previous != expected_previous {
+        failures.push(Failure::new(
+            Check::HashChain,
+            "previous does not reference the latest message",
+        ));
+    }
+
+    if !verify_ed25519(&msg.author, &msg.payload, &msg.signature) {
+        failures.push(Failure::new(Check::Signature, "invalid payload signature"));
+    }
+
+    // The content is signed by the subfeed it is about, to prove that the
+    // subfeed consents to being part of the metafeed.
+    if let ContentSection::Signed { signature, .. } = &msg.content {
+        let verified = match (msg.subfeed(), msg.signed_content()) {
+            (Ok(Some(subfeed)), Some(content)) => verify_ed25519(&subfeed, &content, signature),
+            _ => false,
+        };
+        if !verified {
+            failures.push(Failure::new(Check::Signature, "invalid content signature"));
+        }
+    }
+
+    failures
+}
+
+/// Run the checks of buttwoo messages on the given message, which is
+/// expected to follow the given latest stored (BIPF-encoded) message of its
+/// feed, and return the failed checks.
+pub fn check_buttwoo(msg: &ButtwooMessage, latest: Option<&[u8]>) -> Vec<Failure> {
+    let mut failures = Vec::new();
+
+    let latest = match latest.map(ButtwooMessage::from_slice).transpose() {
+        Ok(latest) => latest,
+        Err(err) => {
+            failures.push(Failure::new(Check::HashChain, err.to_string()));
+            return failures;
+        }
+    };
+    let latest_seq = latest.as_ref().map_or(0, |latest| latest.sequence);
+    let expected_previous = latest
+        .as_ref()
+        .map(|latest| *blake3::hash(&latest.hashed_bytes()).as_bytes());
+    if msg.sequence != latest_seq + 1 {
+        failures.push(Failure::new(
+            Check::HashChain,
+            format!("expected sequence {}, got {}", latest_seq + 1, msg.sequence),
+        ));
+    } else if msg.previous != expected_previous {
+        failures.push(Failure::new(
+            Check::HashChain,
+            "previous does not reference the latest message",
+        ));
+    }
+    if let Some(latest) = &latest {
+        if latest.is_end_of_feed() {
+            failures.push(Failure::new(Check::HashChain, "the feed has ended"));
+        } else if msg.parent != latest.parent {
+            failures.push(Failure::new(
+                Check::HashChain,
+                "parent differs from the latest message",
+            ));
+        }
+    }
+
+    // The content is only signed through its hash, which is part of the
+    // signed value.
+    if msg.content_hash[1..] != *blake3::hash(&msg.content).as_bytes() {
+        failures.push(Failure::new(
+            Check::Signature,
+            "content does not match the signed hash",
+        ));
+    }
+
+    if !verify_ed25519(&msg.author, &msg.value, &msg.signature) {
+        failures.push(Failure::new(Check::Signature, "invalid signature"));
+    }
+
+    failures
+}
+
+/// Validate the given buttwoo message, which is expected to follow the given
+/// latest stored (BIPF-encoded) message of its feed. Any failed check
+/// rejects the message.
+pub fn validate_buttwoo(msg: &ButtwooMessage, latest: Option<&[u8]>) -> Result<()> {
+    match check_buttwoo(msg, latest).into_iter().next() {
+        Some(failure) => Err(Error::InvalidMessage(format!(
+            "message {} of {} failed the {} check: {}",
+            msg.sequence,
+            msg.author_id(),
+            failure.check,
+            failure.reason
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Validate the given Bendy Butt message, which is expected to follow the
+/// given latest stored (bencoded) message of its feed. Any failed check
+/// rejects the message.
+pub fn validate_bendybutt(msg: &BendyButtMessage, latest: Option<&[u8]>) -> Result<()> {
+    match check_bendybutt(msg, latest).into_iter().next() {
+        Some(failure) => Err(Error::InvalidMessage(format!(
+            "message {} of {} failed the {} check: {}",
+            msg.sequence,
+            msg.author_id(),
+            failure.check,
+            failure.reason
+        ))),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    use crate::secret_config::SecretConfig;
+
+    #[test]
+    fn test_validation_pipeline() -> Result<()> {
+        let keypair = SecretConfig::create().to_owned_identity()?;
+
+        let first = Message::sign(None, &keypair, json!({ "type": "post", "text": "hi" }))?;
+        assert!(check(&first, None).is_empty());
+
+        // The hash chain is enforced regardless of the strictness.
+        let failures = check(&first, Some(&first));
+        assert_eq!(failures[0].check, Check::HashChain);
+        assert!(validate_with(&first, Some(&first), Strictness::Lenient).is_err());
+
+        // Schema failures are only tolerated in lenient mode.
+        let vote = Message::sign(Some(&first), &keypair, json!({ "type": "vote", "vote": 1 }))?;
+        let failures = check(&vote, Some(&first));
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].check, Check::Schema);
+        assert!(validate_with(&vote, Some(&first), Strictness::Lenient).is_ok());
+        assert!(validate_with(&vote, Some(&first), Strictness::Strict).is_err());
+
+        // Likewise for size failures.
+        let text = "a".repeat(MAX_MESSAGE_SIZE);
+        let large = Message::sign(
+            Some(&first),
+            &keypair,
+            json!({ "type": "post", "text": text }),
+        )?;
+        let failures = check(&large, Some(&first));
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].check, Check::Size);
+        assert!(validate_with(&large, Some(&first), Strictness::Lenient).is_ok());
+        assert!(validate_with(&large, Some(&first), Strictness::Strict).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_schema() {
+        assert!(check_schema(&json!("c2VjcmV0.box")).is_none());
+        assert!(check_schema(&json!({ "type": "contact", "contact": "@abc" })).is_none());
+        assert!(check_schema(&json!({ "type": "unknown-type" })).is_none());
+        assert!(check_schema(&json!("plaintext")).is_some());
+        assert!(check_schema(&json!({ "type": "ab" })).is_some());
+        assert!(check_schema(&json!({ "type": "contact", "contact": 1 })).is_some());
+
+        let failure = check_schema(&json!({ "type": "post", "text": "hi", "root": 1 }));
+        assert_eq!(
+            failure.map(|failure| failure.reason),
+            Some("invalid post content: root is not a message ID".to_string())
+        );
+    }
+}
@@ -0,0 +1,188 @@
+//! In-process event subscriptions.
+//!
+//! Applications embedding solar can subscribe to the events passed between
+//! the actors of a running node (messages appended to the database, blobs
+//! stored, connection state changes, EBT replication events, storage
+//! pressure changes and changes of the follow graph), in order to react to them without polling the
+//! JSON-RPC API.
+//!
+//! A subscription registers an actor with the broker which forwards the
+//! events of the requested type to a stream. The subscription ends once the
+//! node stops; dropping the stream releases the actor on the next event.
+
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::{channel::mpsc, select_biased, FutureExt, SinkExt, Stream, StreamExt};
+
+use crate::{
+    actors::{
+        network::connection_state::ConnectionTransition,
+        replication::{blob_events::BlobEvent, ebt::EbtEvent},
+        retention::pressure::StoragePressure,
+        social::SocialEvent,
+    },
+    broker::*,
+    error::Error,
+    storage::{blob::StoreBlobEvent, kv::StoreKvEvent},
+    Result,
+};
+
+/// An event to which an embedding application can subscribe.
+pub trait NodeEvent: Sized + Send + 'static {
+    /// Extract an event of this type from the given broker message.
+    fn from_message(msg: BrokerMessage) -> Option<Self>;
+}
+
+impl NodeEvent for StoreKvEvent {
+    fn from_message(msg: BrokerMessage) -> Option<Self> {
+        match msg {
+            BrokerMessage::StoreKv(event) => Some(event),
+            _ => None,
+        }
+    }
+}
+
+impl NodeEvent for StoreBlobEvent {
+    fn from_message(msg: BrokerMessage) -> Option<Self> {
+        match msg {
+            BrokerMessage::StoreBlob(event) => Some(event),
+            _ => None,
+        }
+    }
+}
+
+impl NodeEvent for BlobEvent {
+    fn from_message(msg: BrokerMessage) -> Option<Self> {
+        match msg {
+            BrokerMessage::Blob(event) => Some(event),
+            _ => None,
+        }
+    }
+}
+
+impl NodeEvent for StoragePressure {
+    fn from_message(msg: BrokerMessage) -> Option<Self> {
+        match msg {
+            BrokerMessage::StoragePressure(pressure) => Some(pressure),
+            _ => None,
+        }
+    }
+}
+
+impl NodeEvent for SocialEvent {
+    fn from_message(msg: BrokerMessage) -> Option<Self> {
+        match msg {
+            BrokerMessage::Social(event) => Some(event),
+            _ => None,
+        }
+    }
+}
+
+impl NodeEvent for ConnectionTransition {
+    fn from_message(msg: BrokerMessage) -> Option<Self> {
+        match msg {
+            BrokerMessage::Transition(transition) => Some(transition),
+            _ => None,
+        }
+    }
+}
+
+impl NodeEvent for EbtEvent {
+    fn from_message(msg: BrokerMessage) -> Option<Self> {
+        match msg {
+            BrokerMessage::Ebt(event) => Some(event),
+            _ => None,
+        }
+    }
+}
+
+/// A stream of the events of a given type, returned by
+/// `NodeHandle::subscribe`.
+pub struct Subscription<T> {
+    events: mpsc::UnboundedReceiver<T>,
+}
+
+impl<T> Stream for Subscription<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        self.events.poll_next_unpin(cx)
+    }
+}
+
+/// Subscribe to the events of the given type.
+pub async fn subscribe<T: NodeEvent>() -> Result<Subscription<T>> {
+    // Register with the broker before returning the subscription, so that no
+    // event is missed.
+    let ActorEndpoint {
+        actor_id,
+        mut ch_broker,
+        ch_terminate,
+        ch_terminated,
+        ch_msg,
+    } = BROKER.lock().await.register("subscription", true).await?;
+    let mut ch_msg = ch_msg.ok_or(Error::OptionIsNone)?;
+    let mut ch_terminate = ch_terminate.fuse();
+
+    let (sender, events) = mpsc::unbounded();
+
+    Broker::spawn("subscription", async move {
+        loop {
+            select_biased! {
+                _ = ch_terminate => break,
+                msg = ch_msg.next().fuse() => {
+                    let event = match msg {
+                        Some(msg) => T::from_message(msg),
+                        None => break,
+                    };
+                    if let Some(event) = event {
+                        // The subscription has been dropped.
+                        if sender.unbounded_send(event).is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        let _ = ch_broker.send(BrokerEvent::Disconnect { actor_id }).await;
+        let _ = ch_terminated.send(Void {});
+
+        Ok(())
+    });
+
+    Ok(Subscription { events })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[async_std::test]
+    async fn test_subscribe() -> Result<()> {
+        let mut subscription = subscribe::<StoreKvEvent>().await?;
+
+        let mut ch_broker = BROKER.lock().await.create_sender();
+        // Events of other types are not forwarded.
+        ch_broker
+            .send(BrokerEvent::new(
+                Destination::Broadcast,
+                BrokerMessage::StoreBlob(StoreBlobEvent("&blob=.sha256".to_string())),
+            ))
+            .await?;
+        ch_broker
+            .send(BrokerEvent::new(
+                Destination::Broadcast,
+                BrokerMessage::StoreKv(StoreKvEvent(("@subscriber=.ed25519".to_string(), 7))),
+            ))
+            .await?;
+
+        let StoreKvEvent((author, seq)) = subscription.next().await.unwrap();
+        assert_eq!((author.as_str(), seq), ("@subscriber=.ed25519", 7));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,35 @@
+//! Helpers shared by the actors and the storage.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Return the current time in milliseconds since the UNIX epoch (zero if the
+/// system clock is set before it).
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Format the given SSB ID with an `@` prefix, as feeds and peers are
+/// identified throughout the node. Public keys are formatted without it by
+/// `to_ssb_id` and may be received without it from peers.
+pub(crate) fn prefixed(ssb_id: &str) -> String {
+    if ssb_id.starts_with('@') {
+        ssb_id.to_owned()
+    } else {
+        format!("@{}", ssb_id)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_prefixed() {
+        let key = "HEqy940T6uB+T+d9Jaa58aNfRzLx9eRWqkZljBmnkmk=.ed25519";
+        assert_eq!(prefixed(key), format!("@{}", key));
+        assert_eq!(prefixed(&format!("@{}", key)), format!("@{}", key));
+    }
+}
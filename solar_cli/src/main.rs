@@ -1,7 +1,9 @@
 use std::{
     convert::{TryFrom, TryInto},
     env,
+    io::{self, Write},
     path::PathBuf,
+    time::Duration,
 };
 
 use clap::{error::ErrorKind as ClapErrorKind, CommandFactory, Parser};
@@ -9,7 +11,14 @@ use kuska_sodiumoxide::crypto::auth::Key as NetworkKey;
 use kuska_ssb::{crypto::ToSodiumObject, discovery};
 use url::Url;
 
-use solar::{ApplicationConfig, JsonRpcConfig, NetworkConfig, Node, Result};
+use solar::{
+    ApplicationConfig, FollowBackPolicy, JsonRpcConfig, LogConfig, LogFormat, NetworkConfig, Node,
+    Result, RetentionConfig, TransportConfig,
+};
+
+/// Environment variable from which the passphrase of a backup archive is
+/// read (prompted for on stdin if unset).
+const BACKUP_PASSPHRASE_VAR: &str = "SOLAR_BACKUP_PASSPHRASE";
 
 /// Generate a command line parser.
 /// This defines the options that are exposed when running the solar binary.
@@ -24,6 +33,31 @@ struct Cli {
     #[arg(long)]
     pub database_cache_capacity: Option<u64>,
 
+    /// Salvage readable feeds from a corrupted key-value database into a
+    /// fresh one before starting, reporting any lost messages (default: false)
+    #[arg(long)]
+    pub repair: Option<bool>,
+
+    /// Keep all data in memory with a new keypair, leaving no files behind on
+    /// exit; the data directory is not used (default: false)
+    #[arg(long)]
+    pub ephemeral: Option<bool>,
+
+    /// Export the stored feeds, blobs and keypair into a flumelog-offset data
+    /// directory (as read by ssb-server) at the given path and exit
+    #[arg(long, value_name = "DIR")]
+    pub export_offset_log: Option<PathBuf>,
+
+    /// Write the keypair and the local feed into an archive at the given
+    /// path, encrypted with a passphrase, and exit
+    #[arg(long, value_name = "FILE")]
+    pub backup: Option<PathBuf>,
+
+    /// Restore the keypair and the local feed from the archive at the given
+    /// path, decrypted with a passphrase, and exit
+    #[arg(long, value_name = "FILE")]
+    pub restore_backup: Option<PathBuf>,
+
     /// Connect to a remote peer by specifying a URL
     /// (e.g. tcp://<host>:<port>?shs=<public key>).
     /// Pass a comma-separated list of URLs to connect to multiple peers
@@ -31,6 +65,13 @@ struct Cli {
     #[arg(short, long)]
     pub connect: Option<String>,
 
+    /// Join a room by consuming an HTTP invite
+    /// (e.g. https://<room>/join?token=<token>).
+    /// Pass a comma-separated list of invites to join multiple rooms
+    /// (no spaces)
+    #[arg(long)]
+    pub join_invite: Option<String>,
+
     /// IP to bind for TCP server (default: 0.0.0.0)
     #[arg(short, long)]
     pub ip: Option<String>,
@@ -48,6 +89,51 @@ struct Cli {
     #[arg(short, long)]
     pub lan: Option<bool>,
 
+    /// Address announced by LAN discovery in place of the addresses of the
+    /// local network interfaces, in the form <host>:<port> (eg. the address
+    /// of the host when running in a container)
+    #[arg(long)]
+    pub lan_announce_addr: Option<String>,
+
+    /// Interval in seconds between LAN discovery announcements (default: 15)
+    #[arg(long)]
+    pub lan_interval: Option<u64>,
+
+    /// Listen for LAN discovery announcements without announcing the local
+    /// peer (default: false)
+    #[arg(long)]
+    pub lan_listen_only: Option<bool>,
+
+    /// Maximum number of outbound dials in flight at once; further dials are
+    /// queued (default: 8)
+    #[arg(long)]
+    pub max_concurrent_dials: Option<usize>,
+
+    /// Capacity in bytes of the read and write buffers of each box stream
+    /// (default: 32768)
+    #[arg(long)]
+    pub box_stream_buffer_size: Option<usize>,
+
+    /// Disable Nagle's algorithm on TCP connections, sending small writes
+    /// without delay (default: false)
+    #[arg(long)]
+    pub tcp_nodelay: Option<bool>,
+
+    /// Size in bytes of the send buffer of TCP sockets (default: chosen by
+    /// the operating system)
+    #[arg(long)]
+    pub tcp_send_buffer_size: Option<usize>,
+
+    /// Size in bytes of the receive buffer of TCP sockets (default: chosen
+    /// by the operating system)
+    #[arg(long)]
+    pub tcp_recv_buffer_size: Option<usize>,
+
+    /// Port on which to serve local SSB clients over MUXRPC, bound to
+    /// 127.0.0.1 (default: disabled)
+    #[arg(long)]
+    pub local_rpc_port: Option<u16>,
+
     /// Run the JSON-RPC server (default: true)
     #[arg(short, long)]
     pub jsonrpc: Option<bool>,
@@ -60,12 +146,149 @@ struct Cli {
     #[arg(long)]
     pub jsonrpc_port: Option<u16>,
 
+    /// Serve the web dashboard, on the JSON-RPC IP (default: false)
+    #[arg(long)]
+    pub dashboard: Option<bool>,
+
+    /// Port to bind for the web dashboard (default: 3031)
+    #[arg(long)]
+    pub dashboard_port: Option<u16>,
+
+    /// Serve the public read-only web viewer of feeds and threads
+    /// (default: false)
+    #[arg(long)]
+    pub viewer: Option<bool>,
+
+    /// IP to bind for the public web viewer (default: 0.0.0.0)
+    #[arg(long)]
+    pub viewer_ip: Option<String>,
+
+    /// Port to bind for the public web viewer (default: 3032)
+    #[arg(long)]
+    pub viewer_port: Option<u16>,
+
+    /// Record the state-changing JSON-RPC calls in an audit log at the given
+    /// path (default: disabled)
+    #[arg(long)]
+    pub jsonrpc_audit_log: Option<PathBuf>,
+
+    /// Rotate the JSON-RPC audit log once it exceeds the given size in bytes
+    /// (default: 10485760)
+    #[arg(long)]
+    pub jsonrpc_audit_log_max_size: Option<u64>,
+
+    /// Number of rotated JSON-RPC audit log files to keep (default: 5)
+    #[arg(long)]
+    pub jsonrpc_audit_log_max_files: Option<usize>,
+
+    /// Accept only the API tokens listed (one per line) in the file at the
+    /// given path from the callers of the audited JSON-RPC calls; required by
+    /// the audit log (default: none)
+    #[arg(long)]
+    pub jsonrpc_api_tokens: Option<PathBuf>,
+
+    /// Filter the messages returned to JSON-RPC clients by the filter
+    /// profiles defined in the TOML file at the given path (default: disabled)
+    #[arg(long)]
+    pub jsonrpc_filters: Option<PathBuf>,
+
+    /// Accept messages signed by clients on behalf of the given public key,
+    /// whose private key is not held by the node (`append_signed` JSON-RPC
+    /// method). Pass a comma-separated list of public keys to accept
+    /// multiple remote signers (no spaces)
+    #[arg(long)]
+    pub remote_signers: Option<String>,
+
+    /// Follow back new followers within the given number of hops from the
+    /// local identity in the follow graph (default: disabled)
+    #[arg(long)]
+    pub follow_back_hops: Option<usize>,
+
+    /// Follow back new followers who redeemed an invite (default: false)
+    #[arg(long)]
+    pub follow_back_invites: Option<bool>,
+
+    /// Queue other new followers, to be followed back once approved with the
+    /// `approve_follow_request` JSON-RPC method (default: false)
+    #[arg(long)]
+    pub follow_back_queue: Option<bool>,
+
+    /// Log filter directives in the form `level` or `target=level`, separated
+    /// by commas (default: value of the RUST_LOG environment variable or
+    /// `error`)
+    #[arg(long)]
+    pub log_filter: Option<String>,
+
+    /// Format of log records: `text` or `json` (default: text)
+    #[arg(long)]
+    pub log_format: Option<String>,
+
+    /// Write logs to the given file instead of stderr
+    #[arg(long)]
+    pub log_file: Option<PathBuf>,
+
+    /// Rotate the log file once it exceeds the given size in bytes
+    #[arg(long)]
+    pub log_max_size: Option<u64>,
+
+    /// Rotate the log file once it is older than the given number of seconds
+    #[arg(long)]
+    pub log_rotation_interval: Option<u64>,
+
+    /// Number of rotated log files to keep (default: 5)
+    #[arg(long)]
+    pub log_max_files: Option<usize>,
+
+    /// Delete stored feeds of authors further than the given number of hops
+    /// from the local identity in the follow graph (default: disabled)
+    #[arg(long)]
+    pub prune_hops: Option<usize>,
+
+    /// Interval in seconds between feed pruning runs (default: 3600)
+    #[arg(long)]
+    pub prune_interval: Option<u64>,
+
+    /// Free disk space in bytes below which storage is reported to be running
+    /// low (default: 1073741824)
+    #[arg(long)]
+    pub low_free_space: Option<u64>,
+
+    /// Free disk space in bytes below which blob fetching and the replication
+    /// of feeds beyond `--critical-hops` are paused (default: 268435456)
+    #[arg(long)]
+    pub critical_free_space: Option<u64>,
+
+    /// Database size in bytes above which storage is reported to be running
+    /// low (default: none)
+    #[arg(long)]
+    pub max_database_size: Option<u64>,
+
+    /// Database size in bytes above which blob fetching and the replication
+    /// of feeds beyond `--critical-hops` are paused (default: none)
+    #[arg(long)]
+    pub critical_database_size: Option<u64>,
+
+    /// Number of hops from the local identity within which feeds are still
+    /// replicated while storage is critically low (default: 1)
+    #[arg(long)]
+    pub critical_hops: Option<usize>,
+
+    /// Read simulation driver commands from stdin (default: false)
+    #[cfg(feature = "netsim")]
+    #[arg(long)]
+    pub netsim: Option<bool>,
+
     /// Resync the local database by requesting the local feed from peers
     #[arg(long)]
     pub resync: Option<bool>,
 
+    /// Replicate every feed offered by connected peers, regardless of the
+    /// follow graph, as an archive or mirror (default: false)
+    #[arg(long)]
+    pub mirror: Option<bool>,
+
     /// Only replicate with peers whose public keys are stored in
-    /// `replication.toml` (default: true)
+    /// `replication.toml` (default: true, or false in mirror mode)
     #[arg(short, long)]
     pub selective: Option<bool>,
 }
@@ -118,6 +341,119 @@ impl Cli {
             }
         }
 
+        // Ensure room invites are valid.
+        if let Some(invites) = self.join_invite.to_owned() {
+            for invite in invites.split(',') {
+                let is_valid = Url::parse(invite)
+                    .map(|url| {
+                        matches!(url.scheme(), "http" | "https")
+                            && url.query_pairs().any(|(key, _)| key == "token")
+                    })
+                    .unwrap_or(false);
+                if !is_valid {
+                    // Print a help message about the invalid invite and exit.
+                    Cli::command()
+                        .error(
+                            ClapErrorKind::ValueValidation,
+                            "invites passed via '--join-invite' must be HTTP(S) URLs including a 'token' query parameter",
+                        )
+                        .exit()
+                }
+            }
+        }
+
+        // Ensure the public keys of remote signers are valid.
+        if let Some(signers) = self.remote_signers.to_owned() {
+            for signer in signers.split(',') {
+                if signer.trim_start_matches('@').to_ed25519_pk().is_err() {
+                    // Print a help message about the invalid public key and exit.
+                    Cli::command()
+                        .error(
+                            ClapErrorKind::ValueValidation,
+                            "public keys passed via '--remote-signers' must be of the form @<key>=.ed25519",
+                        )
+                        .exit()
+                }
+            }
+        }
+
+        // Ensure the LAN announcement address is valid.
+        if let Some(addr) = self.lan_announce_addr.to_owned() {
+            let is_valid = addr
+                .rsplit_once(':')
+                .map(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok())
+                .unwrap_or(false);
+            if !is_valid {
+                // Print a help message about the invalid address and exit.
+                Cli::command()
+                    .error(
+                        ClapErrorKind::ValueValidation,
+                        "address passed via '--lan-announce-addr' must take the form <host>:<port>",
+                    )
+                    .exit()
+            }
+        }
+
+        // Ensure the LAN announcement interval is valid.
+        if self.lan_interval == Some(0) {
+            // Print a help message about the invalid interval and exit.
+            Cli::command()
+                .error(
+                    ClapErrorKind::ValueValidation,
+                    "interval passed via '--lan-interval' must be at least 1 second",
+                )
+                .exit()
+        }
+
+        // Ensure the dial concurrency limit is valid.
+        if self.max_concurrent_dials == Some(0) {
+            // Print a help message about the invalid limit and exit.
+            Cli::command()
+                .error(
+                    ClapErrorKind::ValueValidation,
+                    "limit passed via '--max-concurrent-dials' must be at least 1",
+                )
+                .exit()
+        }
+
+        // Ensure the box stream buffers can hold a full box.
+        if matches!(self.box_stream_buffer_size, Some(size) if size < 8192) {
+            // Print a help message about the invalid size and exit.
+            Cli::command()
+                .error(
+                    ClapErrorKind::ValueValidation,
+                    "size passed via '--box-stream-buffer-size' must be at least 8192 bytes",
+                )
+                .exit()
+        }
+
+        // Ensure the TCP socket buffer sizes are valid.
+        if self.tcp_send_buffer_size == Some(0) || self.tcp_recv_buffer_size == Some(0) {
+            // Print a help message about the invalid size and exit.
+            Cli::command()
+                .error(
+                    ClapErrorKind::ValueValidation,
+                    "TCP socket buffer sizes must be at least 1 byte",
+                )
+                .exit()
+        }
+
+        // Ensure the critical storage thresholds are beyond the low ones.
+        let low_free_space = self.low_free_space.unwrap_or(1 << 30);
+        let critical_free_space = self.critical_free_space.unwrap_or(256 << 20);
+        let database_sizes = self.max_database_size.zip(self.critical_database_size);
+        if critical_free_space > low_free_space
+            || matches!(database_sizes, Some((max, critical)) if critical < max)
+        {
+            // Print a help message about the inconsistent thresholds and exit.
+            Cli::command()
+                .error(
+                    ClapErrorKind::ValueValidation,
+                    "critical storage thresholds must not be below the low storage thresholds",
+                )
+                .exit()
+        }
+
         // Ensure the network key is valid.
         if let Some(key) = self.network_key.to_owned() {
             match &hex::decode(key) {
@@ -144,6 +480,34 @@ impl Cli {
             }
         }
 
+        // Ensure options requiring a data directory are not combined with an
+        // ephemeral node.
+        if self.ephemeral.unwrap_or(false)
+            && (self.export_offset_log.is_some()
+                || self.backup.is_some()
+                || self.restore_backup.is_some()
+                || self.repair.unwrap_or(false))
+        {
+            // Print a help message about the conflicting options and exit.
+            Cli::command()
+                .error(
+                    ClapErrorKind::ArgumentConflict,
+                    "'--ephemeral' cannot be combined with '--export-offset-log', '--backup', '--restore-backup' or '--repair'",
+                )
+                .exit()
+        }
+
+        // Ensure a backup is not written and restored at once.
+        if self.backup.is_some() && self.restore_backup.is_some() {
+            // Print a help message about the conflicting options and exit.
+            Cli::command()
+                .error(
+                    ClapErrorKind::ArgumentConflict,
+                    "'--backup' cannot be combined with '--restore-backup'",
+                )
+                .exit()
+        }
+
         self
     }
 }
@@ -155,19 +519,50 @@ impl TryFrom<Cli> for ApplicationConfig {
     /// variables, fall back to defaults when necessary and return the
     /// application configuration.
     fn try_from(cli_args: Cli) -> Result<Self> {
-        let mut config = ApplicationConfig::new(cli_args.data_dir)?;
+        let mut config = if cli_args.ephemeral.unwrap_or(false) {
+            ApplicationConfig::new_ephemeral()?
+        } else {
+            ApplicationConfig::new(cli_args.data_dir)?
+        };
 
         // Retrieve application configuration parameters from the parsed CLI input.
         // Set defaults if options have not been provided.
         let database_cache_capacity = cli_args.database_cache_capacity.unwrap_or(1_000_000_000);
+        let database_repair = cli_args.repair.unwrap_or(false);
         let ip = cli_args.ip.unwrap_or("0.0.0.0".to_string());
         let port = cli_args.port.unwrap_or(8008);
         let lan_discovery = cli_args.lan.unwrap_or(false);
+        let lan_interval = cli_args.lan_interval.unwrap_or(15);
+        let lan_listen_only = cli_args.lan_listen_only.unwrap_or(false);
+        let max_concurrent_dials = cli_args.max_concurrent_dials.unwrap_or(8);
+        let box_stream_buffer_size = cli_args.box_stream_buffer_size.unwrap_or(0x8000);
+        let tcp_nodelay = cli_args.tcp_nodelay.unwrap_or(false);
         let jsonrpc = cli_args.jsonrpc.unwrap_or(true);
         let jsonrpc_ip = cli_args.jsonrpc_ip.unwrap_or("127.0.0.1".to_string());
         let jsonrpc_port = cli_args.jsonrpc_port.unwrap_or(3030);
+        let dashboard = cli_args.dashboard.unwrap_or(false);
+        let dashboard_port = cli_args.dashboard_port.unwrap_or(3031);
+        let viewer = cli_args.viewer.unwrap_or(false);
+        let viewer_ip = cli_args.viewer_ip.unwrap_or("0.0.0.0".to_string());
+        let viewer_port = cli_args.viewer_port.unwrap_or(3032);
+        let jsonrpc_audit_log_max_size = cli_args.jsonrpc_audit_log_max_size.unwrap_or(10 << 20);
+        let jsonrpc_audit_log_max_files = cli_args.jsonrpc_audit_log_max_files.unwrap_or(5);
         let resync = cli_args.resync.unwrap_or(false);
-        let selective = cli_args.selective.unwrap_or(true);
+        let mirror = cli_args.mirror.unwrap_or(false);
+        let selective = cli_args.selective.unwrap_or(!mirror);
+        let log_filter = cli_args
+            .log_filter
+            .or_else(|| env::var("RUST_LOG").ok())
+            .unwrap_or("error".to_string());
+        let log_format = match cli_args.log_format {
+            Some(format) => format.parse()?,
+            None => LogFormat::Text,
+        };
+        let log_max_files = cli_args.log_max_files.unwrap_or(5);
+        let prune_interval = cli_args.prune_interval.unwrap_or(3600);
+        let low_free_space = cli_args.low_free_space.unwrap_or(1 << 30);
+        let critical_free_space = cli_args.critical_free_space.unwrap_or(256 << 20);
+        let critical_hops = cli_args.critical_hops.unwrap_or(1);
 
         let network_key = match cli_args.network_key {
             // The key has already been validated so it's safe to unwrap here.
@@ -212,43 +607,171 @@ impl TryFrom<Cli> for ApplicationConfig {
 
         // Define the key-value database cache capacity.
         config.database_cache_capacity = database_cache_capacity;
+        config.database_repair = database_repair;
+
+        // Stop the node on Ctrl+C.
+        config.handle_ctrlc = true;
 
         // Define the JSON-RPC configuration parameters.
         config.jsonrpc = JsonRpcConfig {
             server: jsonrpc,
             ip: jsonrpc_ip.parse()?,
             port: jsonrpc_port,
+            dashboard,
+            dashboard_port,
+            viewer,
+            viewer_ip: viewer_ip.parse()?,
+            viewer_port,
+            audit_log: cli_args.jsonrpc_audit_log,
+            audit_log_max_size: Some(jsonrpc_audit_log_max_size),
+            audit_log_max_files: jsonrpc_audit_log_max_files,
+            api_tokens: cli_args.jsonrpc_api_tokens,
+            filters: cli_args.jsonrpc_filters,
+        };
+
+        // Define the logging configuration parameters.
+        config.log = LogConfig {
+            filter: log_filter,
+            format: log_format,
+            file: cli_args.log_file,
+            max_file_size: cli_args.log_max_size,
+            rotation_interval: cli_args.log_rotation_interval.map(Duration::from_secs),
+            max_files: log_max_files,
         };
 
+        // Parse room invites from the provided CLI options.
+        let join_invites = cli_args
+            .join_invite
+            .map(|invites| invites.split(',').map(String::from).collect())
+            .unwrap_or_default();
+
         // Define the network configuration parameters.
         config.network = NetworkConfig {
             connect: peer_connections,
+            join_invites,
             key: network_key,
             lan_discovery,
+            lan_announce_addr: cli_args.lan_announce_addr,
+            lan_interval: Duration::from_secs(lan_interval),
+            lan_listen_only,
+            max_concurrent_dials,
+            local_rpc_port: cli_args.local_rpc_port,
             ip: ip.parse()?,
             port,
+            transport: TransportConfig {
+                box_stream_buffer_size,
+                tcp_nodelay,
+                tcp_send_buffer_size: cli_args.tcp_send_buffer_size,
+                tcp_recv_buffer_size: cli_args.tcp_recv_buffer_size,
+            },
+        };
+
+        // Define the retention configuration parameters.
+        config.retention = RetentionConfig {
+            prune_hops: cli_args.prune_hops,
+            prune_interval: Duration::from_secs(prune_interval),
+            low_free_space,
+            critical_free_space,
+            max_database_size: cli_args.max_database_size,
+            critical_database_size: cli_args.critical_database_size,
+            critical_hops,
+        };
+
+        // Define the remote signers of the publish policy.
+        config.publish.remote_signers = cli_args
+            .remote_signers
+            .map(|signers| signers.split(',').map(String::from).collect())
+            .unwrap_or_default();
+
+        // Define the policy by which new followers are followed back.
+        config.follow_back = FollowBackPolicy {
+            hops: cli_args.follow_back_hops,
+            invites: cli_args.follow_back_invites.unwrap_or(false),
+            queue: cli_args.follow_back_queue.unwrap_or(false),
         };
 
         // Define the replication configuration parameters.
         config.replication.resync = resync;
         config.replication.selective = selective;
+        config.replication.mirror = mirror;
 
         Ok(config)
     }
 }
 
+/// Return the passphrase of a backup archive, read from the
+/// `SOLAR_BACKUP_PASSPHRASE` environment variable or else from stdin.
+fn backup_passphrase() -> Result<String> {
+    if let Ok(passphrase) = env::var(BACKUP_PASSPHRASE_VAR) {
+        return Ok(passphrase);
+    }
+
+    eprint!("Backup passphrase: ");
+    io::stderr().flush()?;
+    let mut passphrase = String::new();
+    io::stdin().read_line(&mut passphrase)?;
+
+    Ok(passphrase.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
+
 #[async_std::main]
 async fn main() {
-    // Initialise the logger.
-    env_logger::init();
-    log::set_max_level(log::LevelFilter::max());
-
     // Parse command line arguments and run custom validators.
     let cli = Cli::parse().validate();
 
+    #[cfg(feature = "netsim")]
+    let netsim = cli.netsim.unwrap_or(false);
+
+    let export_offset_log = cli.export_offset_log.clone();
+    let backup = cli.backup.clone();
+    let restore_backup = cli.restore_backup.clone();
+
     // Load configuration parameters and apply defaults.
-    let config = cli.try_into().expect("Could not load configuration");
+    let config: ApplicationConfig = cli.try_into().expect("Could not load configuration");
+
+    // Initialise the logger.
+    solar::logger::init(&config.log).expect("Could not initialise logger");
+
+    // Export to a flumelog-offset data directory without starting the node.
+    if let Some(target) = export_offset_log {
+        let base_path = config.base_path.expect("Data directory is not defined");
+        match solar::storage::export::export_offset_log(&base_path, &target).await {
+            Ok(report) => println!("{report}"),
+            Err(err) => eprintln!("Export failed: {err}"),
+        }
+
+        return;
+    }
 
-    // Start the solar node in async runtime.
-    let _node = Node::start(config).await;
+    // Back up or restore the local feed without starting the node.
+    if backup.is_some() || restore_backup.is_some() {
+        let base_path = config.base_path.expect("Data directory is not defined");
+        let passphrase = backup_passphrase().expect("Could not read the backup passphrase");
+        if let Some(target) = backup {
+            match solar::storage::backup::export_backup(&base_path, &target, &passphrase).await {
+                Ok(report) => println!("Backed up {report}"),
+                Err(err) => eprintln!("Backup failed: {err}"),
+            }
+        } else if let Some(source) = restore_backup {
+            match solar::storage::backup::restore_backup(&base_path, &source, &passphrase).await {
+                Ok(report) => println!("Restored {report}"),
+                Err(err) => eprintln!("Restore failed: {err}"),
+            }
+        }
+
+        return;
+    }
+
+    // Spawn the simulation driver. Commands are read once the database has
+    // been opened by the node.
+    #[cfg(feature = "netsim")]
+    if netsim {
+        async_std::task::spawn(solar::netsim::driver());
+    }
+
+    // Start the solar node in async runtime and wait for it to terminate.
+    match Node::start(config).await {
+        Ok(node) => node.done().await,
+        Err(err) => eprintln!("Failed to start node: {err}"),
+    }
 }
@@ -0,0 +1,32 @@
+use anyhow::Result;
+use kuska_ssb::keystore::OwnedIdentity;
+use serde_json::json;
+use solar_client::{Client, Signer};
+
+const SERVER_ADDR: &str = "http://127.0.0.1:3030";
+const TEXT: &str = "Testing client-side signing via the solar JSON-RPC client";
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let client = Client::new(SERVER_ADDR.to_owned())?;
+
+    // The private key is held by the client only. The node must be started
+    // with the public key as a remote signer:
+    //
+    // solar --remote-signers <public key>
+    let signer = Signer::new(OwnedIdentity::create());
+    println!("{}", signer.id());
+
+    let post = json!({
+        "type": "post",
+        "text": TEXT,
+    });
+
+    // The message is signed locally, following the latest message of the
+    // feed, and appended by the node.
+    let msg_ref_and_seq_num = signer.publish(&client, post).await?;
+    println!("{:?}", msg_ref_and_seq_num);
+    // ("%ZwYwLxMHgU8eC43HOziJvYURjZzAzwFk3v5RYS/NbQY=.sha256", 1)
+
+    Ok(())
+}
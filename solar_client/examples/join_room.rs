@@ -0,0 +1,15 @@
+use anyhow::Result;
+use solar_client::{Client, SolarClient};
+
+const SERVER_ADDR: &str = "http://127.0.0.1:3030";
+const INVITE: &str = "https://room.example.com/join?token=abc123";
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let client = Client::new(SERVER_ADDR.to_owned())?;
+
+    let room = client.join_room(INVITE).await?;
+    println!("{:#?}", room);
+
+    Ok(())
+}
@@ -0,0 +1,25 @@
+use anyhow::Result;
+use solar_client::{Client, SolarClient};
+
+const SERVER_ADDR: &str = "http://127.0.0.1:3030";
+const MSG_URI: &str = "ssb:message/classic/RCb--_ZhqV1lJNIcoNrk4yM3AfBobT7u8seObZgcEbA=";
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let client = Client::new(SERVER_ADDR.to_owned())?;
+
+    let link = client.link(MSG_URI).await?;
+    println!("{:#?}", link);
+    /*
+    Object {
+        "sigil": String("%RCb++/ZhqV1lJNIcoNrk4yM3AfBobT7u8seObZgcEbA=.sha256"),
+        "uri": String("ssb:message/classic/RCb--_ZhqV1lJNIcoNrk4yM3AfBobT7u8seObZgcEbA="),
+    }
+    */
+
+    // SSB URIs are accepted wherever a sigil link is.
+    let message = client.message(MSG_URI).await?;
+    println!("{:#?}", message);
+
+    Ok(())
+}
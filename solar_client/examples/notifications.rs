@@ -0,0 +1,17 @@
+use anyhow::Result;
+use solar_client::{Client, SolarClient};
+
+const SERVER_ADDR: &str = "http://127.0.0.1:3030";
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let client = Client::new(SERVER_ADDR.to_owned())?;
+
+    let notifications = client.notifications(Some(true)).await?;
+    println!("{:#?}", notifications);
+
+    let marked = client.mark_notifications_read(None).await?;
+    println!("Marked {} notifications as read", marked);
+
+    Ok(())
+}
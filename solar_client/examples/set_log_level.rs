@@ -0,0 +1,14 @@
+use anyhow::Result;
+use solar_client::{Client, SolarClient};
+
+const SERVER_ADDR: &str = "http://127.0.0.1:3030";
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let client = Client::new(SERVER_ADDR.to_owned())?;
+
+    let filter = client.set_log_level(Some("ebt-handler"), "trace").await?;
+    println!("{}", filter);
+
+    Ok(())
+}
@@ -0,0 +1,19 @@
+use anyhow::Result;
+use solar_client::{Client, SolarClient};
+
+const SERVER_ADDR: &str = "http://127.0.0.1:3030";
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let client = Client::new(SERVER_ADDR.to_owned())?;
+
+    // Retrieve the ten most recent messages, followed by the next ten.
+    let first_page = client.timeline(None, Some(10), None).await?;
+    println!("{:#?}", first_page);
+
+    let cursor = first_page["cursor"].as_str().map(str::to_owned);
+    let second_page = client.timeline(cursor, Some(10), None).await?;
+    println!("{:#?}", second_page);
+
+    Ok(())
+}
@@ -1,12 +1,24 @@
+mod signer;
+
 use anyhow::Result;
 use serde_json::Value;
 
+pub use signer::Signer;
+
 #[jsonrpc_client::api]
 pub trait SolarClient {
+    async fn about(&self, pub_key: &str) -> Value;
+
+    async fn append_signed(&self, msg: Value) -> (String, u64);
+
     async fn blocks(&self, pub_key: &str) -> Vec<String>;
 
     async fn blockers(&self, pub_key: &str) -> Vec<String>;
 
+    async fn clock(&self, pub_key: &str) -> Option<Value>;
+
+    async fn contacts(&self, pub_key: &str) -> Value;
+
     async fn descriptions(&self, pub_key: &str) -> Vec<(String, String)>;
 
     async fn self_descriptions(&self, pub_key: &str) -> Vec<String>;
@@ -15,8 +27,12 @@ pub trait SolarClient {
 
     async fn latest_self_description(&self, pub_key: &str) -> String;
 
+    async fn dial_history(&self, pub_key: &str) -> Vec<Value>;
+
     async fn feed(&self, pub_key: &str) -> Vec<Value>;
 
+    async fn fetch_message(&self, msg_ref: &str) -> Option<Value>;
+
     async fn follows(&self, pub_key: &str) -> Vec<String>;
 
     async fn followers(&self, pub_key: &str) -> Vec<String>;
@@ -33,8 +49,24 @@ pub trait SolarClient {
 
     async fn latest_self_image(&self, pub_key: &str) -> String;
 
+    async fn latest_message(&self, pub_key: &str) -> Option<Value>;
+
+    async fn join_room(&self, invite: &str) -> Value;
+
+    async fn likes(&self, msg_ref: &str) -> Vec<String>;
+
+    async fn link(&self, link: &str) -> Value;
+
+    async fn local_clock(&self) -> Value;
+
+    async fn mark_notifications_read(&self, msg_refs: Option<Vec<String>>) -> usize;
+
     async fn message(&self, msg_ref: &str) -> Value;
 
+    async fn message_trace(&self, pub_key: &str, sequence: Option<u64>) -> Value;
+
+    async fn mirror_status(&self) -> Value;
+
     async fn names(&self, pub_key: &str) -> Vec<(String, String)>;
 
     async fn self_names(&self, pub_key: &str) -> Vec<String>;
@@ -43,16 +75,46 @@ pub trait SolarClient {
 
     async fn latest_self_name(&self, pub_key: &str) -> String;
 
+    async fn notifications(&self, unread_only: Option<bool>) -> Vec<Value>;
+
     async fn peers(&self) -> Vec<(String, u64)>;
 
+    async fn pin_feed(&self, pub_key: &str) -> bool;
+
+    async fn pins(&self) -> Value;
+
     async fn ping(&self) -> String;
 
+    async fn preview_feed(
+        &self,
+        pub_key: &str,
+        peer: Option<&str>,
+        limit: Option<u64>,
+    ) -> Vec<Value>;
+
     async fn publish(&self, msg: Value) -> (String, u64);
 
+    async fn replicate_now(&self, pub_key: &str, strategy: &str) -> bool;
+
+    async fn replication_log(&self, pub_key: &str) -> Vec<Value>;
+
+    async fn set_log_level(&self, target: Option<&str>, level: &str) -> String;
+
     async fn subscribers(&self, channel: &str) -> Vec<String>;
 
     async fn subscriptions(&self, pub_key: &str) -> Vec<String>;
 
+    async fn timeline(
+        &self,
+        cursor: Option<String>,
+        limit: Option<usize>,
+        order: Option<String>,
+    ) -> Value;
+
+    async fn unpin_feed(&self, pub_key: &str) -> bool;
+
+    async fn votes(&self, msg_ref: &str) -> Vec<Value>;
+
     async fn whoami(&self) -> String;
 }
 
@@ -0,0 +1,57 @@
+//! Client-side signing.
+//!
+//! A client may hold the private key of a feed itself, so that the key never
+//! has to live on the server hosting the node. The client signs each message
+//! locally and hands the signed message to the node, which validates it and
+//! appends it to the feed (`append_signed`). The node only accepts messages
+//! of the public keys it is configured to accept as remote signers.
+use anyhow::{anyhow, Result};
+use kuska_ssb::{feed::Message, keystore::OwnedIdentity};
+use serde_json::Value;
+
+use crate::{Client, SolarClient};
+
+/// Signs messages with a private key held by the client.
+pub struct Signer {
+    identity: OwnedIdentity,
+}
+
+impl Signer {
+    pub fn new(identity: OwnedIdentity) -> Self {
+        Self { identity }
+    }
+
+    /// Return the public key (ID) of the feed of the signer.
+    pub fn id(&self) -> &str {
+        &self.identity.id
+    }
+
+    /// Sign the given content as the message following the given message
+    /// value (`None` for the first message of the feed), returning the
+    /// signed message value.
+    pub fn sign(&self, previous: Option<&Value>, content: Value) -> Result<Value> {
+        let previous = match previous {
+            Some(value) => Some(
+                Message::from_slice(&serde_json::to_vec(value)?)
+                    .map_err(|err| anyhow!("Invalid previous message: {}", err))?,
+            ),
+            None => None,
+        };
+        let msg = Message::sign(previous.as_ref(), &self.identity, content)
+            .map_err(|err| anyhow!("Failed to sign message: {}", err))?;
+
+        Ok(msg.value)
+    }
+
+    /// Sign the given content as the next message of the feed of the signer
+    /// and append it to the feed through the node.
+    ///
+    /// Returns the key (hash) and sequence number of the published message.
+    pub async fn publish(&self, client: &Client, content: Value) -> Result<(String, u64)> {
+        let latest = client.latest_message(self.id()).await?;
+        let previous = latest.as_ref().map(|msg_kvt| &msg_kvt["value"]);
+        let msg = self.sign(previous, content)?;
+
+        Ok(client.append_signed(msg).await?)
+    }
+}
@@ -0,0 +1,137 @@
+// Generate the feed and handshake fixtures.
+//
+// Usage: node fixtures/generate.js
+//
+// The fixtures are derived from fixed seeds, so that running this script
+// again reproduces them exactly. Messages are serialized, hashed and signed
+// following the rules of ssb-keys and ssb-validate, and the handshake hellos
+// follow the secret handshake (SHS1) specification, using only the crypto
+// module of Node.js.
+
+'use strict'
+
+const crypto = require('crypto')
+const fs = require('fs')
+const path = require('path')
+
+// DER prefixes of raw ed25519 and x25519 private keys (PKCS #8).
+const ED25519_PKCS8 = Buffer.from('302e020100300506032b657004220420', 'hex')
+const X25519_PKCS8 = Buffer.from('302e020100300506032b656e04220420', 'hex')
+
+// Network key of the main Scuttlebutt network.
+const NETWORK_KEY = Buffer.from(
+  'd4a1cb88a66f02f8db635ce26441cc5dac1b08420ceaac230839b755845a9ffb',
+  'hex'
+)
+
+function seed (name) {
+  return crypto.createHash('sha256').update(name).digest()
+}
+
+function privateKey (prefix, name) {
+  return crypto.createPrivateKey({
+    key: Buffer.concat([prefix, seed(name)]),
+    format: 'der',
+    type: 'pkcs8'
+  })
+}
+
+function rawPublicKey (key) {
+  // The raw key is the last 32 bytes of the DER encoding (SPKI).
+  const der = crypto.createPublicKey(key).export({ format: 'der', type: 'spki' })
+  return der.subarray(der.length - 32)
+}
+
+// Serialize a message as ssb-js does.
+function stringify (value) {
+  return JSON.stringify(value, null, 2)
+}
+
+// Return the key of a message. ssb-js hashes the serialized message as a
+// 'binary' (latin1) string, ie. with the low byte of each UTF-16 code unit.
+function messageKey (value) {
+  const hash = crypto.createHash('sha256')
+    .update(Buffer.from(stringify(value), 'binary'))
+    .digest('base64')
+
+  return '%' + hash + '.sha256'
+}
+
+function generateFeed () {
+  const key = privateKey(ED25519_PKCS8, 'solar-conformance classic feed')
+  const author = '@' + rawPublicKey(key).toString('base64') + '.ed25519'
+
+  const contents = [
+    [1612345678901, { type: 'about', about: author, name: 'solar-fixture' }],
+    // The fractional timestamp and the characters outside of latin1 (the
+    // low byte of each UTF-16 code unit is hashed) catch serialization and
+    // hashing differences.
+    [1612345680000.5, { type: 'post', text: 'Sunbathing scuttlecrabs ☀ in kuskaland 🦀' }],
+    [
+      1612345690123,
+      {
+        type: 'contact',
+        contact: '@HEqy940T6uB+T+d9Jaa58aNfRzLx9eRWqkZljBmnkmk=.ed25519',
+        following: true
+      }
+    ],
+    [1612345700000, { type: 'post', text: 'Quotes ", backslashes \\ and\ttabs\nare escaped é' }]
+  ]
+
+  let previous = null
+  const messages = contents.map(([timestamp, content], i) => {
+    const value = {
+      previous,
+      author,
+      sequence: i + 1,
+      timestamp,
+      hash: 'sha256',
+      content
+    }
+    // The signature covers the UTF-8 encoding of the serialized message.
+    const signature = crypto.sign(null, Buffer.from(stringify(value), 'utf8'), key)
+    value.signature = signature.toString('base64') + '.sig.ed25519'
+
+    previous = messageKey(value)
+
+    // Messages are sent on history streams without indentation.
+    return { key: previous, value, wire: JSON.stringify(value) }
+  })
+
+  return {
+    source: 'fixtures/generate.js (ssb-keys serialization)',
+    author,
+    messages
+  }
+}
+
+// Return the hello of a peer with the given ephemeral key: the HMAC of the
+// ephemeral public key (keyed with the network key) followed by the key.
+function hello (name) {
+  const ephemeral = rawPublicKey(privateKey(X25519_PKCS8, name))
+  const hmac = crypto.createHmac('sha512', NETWORK_KEY)
+    .update(ephemeral)
+    .digest()
+    .subarray(0, 32)
+
+  return Buffer.concat([hmac, ephemeral]).toString('hex')
+}
+
+function generateHandshake () {
+  return {
+    source: 'fixtures/generate.js (SHS1 specification)',
+    network_key: NETWORK_KEY.toString('hex'),
+    client_hello: hello('solar-conformance client ephemeral key'),
+    server_hello: hello('solar-conformance server ephemeral key'),
+    client_authenticate_len: 112,
+    server_accept_len: 80
+  }
+}
+
+function write (name, fixture) {
+  const file = path.join(__dirname, name)
+  fs.writeFileSync(file, JSON.stringify(fixture, null, 2) + '\n')
+}
+
+write('feeds/classic.json', generateFeed())
+write('handshake/shs1.json', generateHandshake())
@@ -0,0 +1,109 @@
+//! EBT note encoding and vector clock parsing.
+
+use solar::{
+    conformance::{self, EbtSession},
+    ebt_clock, EbtEvent, Result, VectorClock,
+};
+use solar_conformance::{load, ClockFixture, FeedFixture, NoteFixture};
+
+/// Request number of the replicate request sent to the peer.
+const REQ_NO: i32 = 1;
+
+#[test]
+fn notes_decode_as_expected() -> Result<()> {
+    let notes: Vec<NoteFixture> = load("ebt/notes.json")?;
+
+    for note in notes {
+        assert_eq!(
+            ebt_clock::decode(note.value)?,
+            (note.replicate, note.receive, note.sequence),
+            "failed to decode {} note {}",
+            note.source,
+            note.value
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn notes_encode_as_expected() -> Result<()> {
+    let notes: Vec<NoteFixture> = load("ebt/notes.json")?;
+
+    for note in notes {
+        assert_eq!(
+            ebt_clock::encode(note.replicate, note.receive, note.sequence)?,
+            note.value,
+            "failed to encode {} note {}",
+            note.source,
+            note.value
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn clocks_parse_as_expected() -> Result<()> {
+    let clocks: Vec<ClockFixture> = load("ebt/clocks.json")?;
+
+    for fixture in clocks {
+        // Clocks are deserialized from the raw response bytes, exactly as in
+        // the EBT MUXRPC handler.
+        let clock: VectorClock = serde_json::from_slice(fixture.wire.as_bytes())?;
+        assert_eq!(clock.len(), fixture.entries, "{} clock", fixture.source);
+
+        for value in clock.values() {
+            ebt_clock::decode(*value)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn clocks_and_messages_are_received_by_the_handler() -> Result<()> {
+    let clocks: Vec<ClockFixture> = load("ebt/clocks.json")?;
+    let feed: FeedFixture = load("feeds/classic.json")?;
+    conformance::open_store().await?;
+
+    let mut session = EbtSession::requested(&feed.author, REQ_NO).await;
+
+    // The peer sends its clocks and messages as responses to the replicate
+    // request.
+    for fixture in &clocks {
+        session
+            .receive(&conformance::json_packet(-REQ_NO, fixture.wire.as_bytes()))
+            .await?;
+
+        match session.events().as_slice() {
+            [EbtEvent::ReceivedClock(_, peer, clock)] => {
+                assert_eq!(peer, &feed.author);
+                assert_eq!(clock.len(), fixture.entries, "{} clock", fixture.source);
+            }
+            events => panic!(
+                "unexpected events for {} clock: {:?}",
+                fixture.source, events
+            ),
+        }
+    }
+
+    for fixture in &feed.messages {
+        session
+            .receive(&conformance::json_packet(-REQ_NO, fixture.wire.as_bytes()))
+            .await?;
+
+        match session.events().as_slice() {
+            [EbtEvent::ReceivedMessage(peer, msg)] => {
+                assert_eq!(peer, &feed.author);
+                assert_eq!(msg.id().to_string(), fixture.key, "{} message", feed.source);
+            }
+            events => panic!(
+                "unexpected events for {} message: {:?}",
+                feed.source, events
+            ),
+        }
+    }
+
+    Ok(())
+}
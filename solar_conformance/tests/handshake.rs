@@ -0,0 +1,212 @@
+//! Secret handshake (SHS1) framing and network key handling.
+
+use async_std::{
+    net::{TcpListener, TcpStream},
+    prelude::*,
+    task,
+};
+use kuska_sodiumoxide::crypto::auth;
+use kuska_ssb::{
+    discovery,
+    handshake::async_std::{handshake_client, handshake_server},
+    keystore::OwnedIdentity,
+};
+use solar::Result;
+use solar_conformance::{load, HandshakeFixture, RecordingStream};
+
+#[async_std::test]
+async fn handshake_message_lengths_match_shs1() -> Result<()> {
+    let fixture: HandshakeFixture = load("handshake/shs1.json")?;
+
+    // The fixture was generated with the key of the main Scuttlebutt network.
+    let network_key = discovery::ssb_net_id();
+    assert_eq!(hex::encode(network_key.0), fixture.network_key);
+
+    let server_identity = OwnedIdentity::create();
+    let client_identity = OwnedIdentity::create();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+
+    let server_key = network_key.clone();
+    let server = task::spawn(async move {
+        let (stream, _) = listener.accept().await?;
+        let mut stream = RecordingStream::new(stream);
+        let handshake = handshake_server(
+            &mut stream,
+            server_key,
+            server_identity.pk,
+            server_identity.sk,
+        )
+        .await?;
+
+        Ok::<_, solar::Error>((handshake.peer_pk, stream.written_len()))
+    });
+
+    let stream = TcpStream::connect(addr).await?;
+    let mut stream = RecordingStream::new(stream);
+    let handshake = handshake_client(
+        &mut stream,
+        network_key,
+        client_identity.pk,
+        client_identity.sk,
+        server_identity.pk,
+    )
+    .await?;
+
+    let (server_peer_pk, server_written) = server.await?;
+
+    // Both peers should have learned the identity of the other.
+    assert_eq!(handshake.peer_pk, server_identity.pk);
+    assert_eq!(server_peer_pk, client_identity.pk);
+
+    assert_eq!(
+        stream.written_len(),
+        fixture.client_hello.len() / 2 + fixture.client_authenticate_len
+    );
+    assert_eq!(
+        server_written,
+        fixture.server_hello.len() / 2 + fixture.server_accept_len
+    );
+
+    Ok(())
+}
+
+/// Return whether the given hello is valid for the given network key: the
+/// HMAC of the ephemeral public key followed by the key.
+fn is_valid_hello(hello: &[u8], network_key: &str) -> bool {
+    if hello.len() != 64 {
+        return false;
+    }
+
+    let key = auth::Key::from_slice(&hex::decode(network_key).unwrap()).unwrap();
+    let tag = auth::Tag::from_slice(&hello[..32]).unwrap();
+
+    auth::verify(&tag, &hello[32..], &key)
+}
+
+#[async_std::test]
+async fn server_accepts_fixture_client_hello() -> Result<()> {
+    let fixture: HandshakeFixture = load("handshake/shs1.json")?;
+    let client_hello = hex::decode(&fixture.client_hello).unwrap();
+    assert!(is_valid_hello(&client_hello, &fixture.network_key));
+
+    let server_identity = OwnedIdentity::create();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+
+    let server = task::spawn(async move {
+        let (mut stream, _) = listener.accept().await?;
+        let result = handshake_server(
+            &mut stream,
+            discovery::ssb_net_id(),
+            server_identity.pk,
+            server_identity.sk,
+        )
+        .await;
+
+        Ok::<_, solar::Error>(result.is_err())
+    });
+
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_all(&client_hello).await?;
+
+    // The server only answers with its own hello once the hello of the
+    // client has been verified.
+    let mut server_hello = [0; 64];
+    stream.read_exact(&mut server_hello).await?;
+    assert!(is_valid_hello(&server_hello, &fixture.network_key));
+
+    // The handshake cannot go on without the ephemeral secret key of the
+    // generated client.
+    drop(stream);
+    assert!(server.await?);
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn client_accepts_fixture_server_hello() -> Result<()> {
+    let fixture: HandshakeFixture = load("handshake/shs1.json")?;
+    let server_hello = hex::decode(&fixture.server_hello).unwrap();
+    assert!(is_valid_hello(&server_hello, &fixture.network_key));
+
+    let server_identity = OwnedIdentity::create();
+    let client_identity = OwnedIdentity::create();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+
+    let client = task::spawn(async move {
+        let mut stream = TcpStream::connect(addr).await?;
+        let result = handshake_client(
+            &mut stream,
+            discovery::ssb_net_id(),
+            client_identity.pk,
+            client_identity.sk,
+            server_identity.pk,
+        )
+        .await;
+
+        Ok::<_, solar::Error>(result.is_err())
+    });
+
+    let (mut stream, _) = listener.accept().await?;
+    let mut client_hello = [0; 64];
+    stream.read_exact(&mut client_hello).await?;
+    assert!(is_valid_hello(&client_hello, &fixture.network_key));
+
+    stream.write_all(&server_hello).await?;
+
+    // The client only sends its authenticate message once the hello of the
+    // server has been verified.
+    let mut client_authenticate = vec![0; fixture.client_authenticate_len];
+    stream.read_exact(&mut client_authenticate).await?;
+
+    drop(stream);
+    assert!(client.await?);
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn handshake_fails_with_mismatched_network_key() -> Result<()> {
+    let server_identity = OwnedIdentity::create();
+    let client_identity = OwnedIdentity::create();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+
+    let server = task::spawn(async move {
+        let (mut stream, _) = listener.accept().await?;
+        let result = handshake_server(
+            &mut stream,
+            discovery::ssb_net_id(),
+            server_identity.pk,
+            server_identity.sk,
+        )
+        .await;
+
+        Ok::<_, solar::Error>(result.is_err())
+    });
+
+    // Use a network key which differs from the main network key.
+    let mut other_key = discovery::ssb_net_id();
+    other_key.0[0] ^= 0xff;
+
+    let mut stream = TcpStream::connect(addr).await?;
+    let result = handshake_client(
+        &mut stream,
+        other_key,
+        client_identity.pk,
+        client_identity.sk,
+        server_identity.pk,
+    )
+    .await;
+
+    assert!(server.await?);
+    assert!(result.is_err());
+
+    Ok(())
+}
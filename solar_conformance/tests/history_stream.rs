@@ -0,0 +1,81 @@
+//! `createHistoryStream` request parsing and message serving.
+
+use kuska_ssb::{api::dto, feed::Message};
+use serde_json::json;
+use solar::{
+    conformance::{self, HistoryStreamSession},
+    Result,
+};
+use solar_conformance::{load, FeedFixture, HistoryStreamArgsFixture};
+
+#[test]
+fn history_stream_args_parse_as_expected() -> Result<()> {
+    let fixtures: Vec<HistoryStreamArgsFixture> = load("history_stream/args.json")?;
+
+    for fixture in fixtures {
+        // Arguments are deserialized from the request body, exactly as in the
+        // history stream MUXRPC handler.
+        let args: serde_json::Value = serde_json::from_str(&fixture.wire)?;
+        let mut args: Vec<dto::CreateHistoryStreamIn> = serde_json::from_value(args)?;
+        let args = args.pop().expect("empty createHistoryStream args");
+
+        assert_eq!(args.id, fixture.id, "{} args", fixture.source);
+        assert_eq!(args.seq, fixture.seq, "{} args", fixture.source);
+        assert_eq!(args.live, fixture.live, "{} args", fixture.source);
+        assert_eq!(args.keys, fixture.keys, "{} args", fixture.source);
+    }
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn feed_is_served_byte_for_byte() -> Result<()> {
+    let feed: FeedFixture = load("feeds/classic.json")?;
+    conformance::open_store().await?;
+
+    for fixture in &feed.messages {
+        // Parse the message from the bytes received on the wire. This
+        // validates the signature and hash of the message.
+        let msg = Message::from_slice(fixture.wire.as_bytes())?;
+        assert_eq!(
+            msg.id().to_string(),
+            fixture.key,
+            "{} message {}",
+            feed.source,
+            msg.sequence()
+        );
+
+        conformance::append(msg).await?;
+    }
+
+    // Request the feed as a peer would.
+    let request = json!({
+        "name": ["createHistoryStream"],
+        "type": "source",
+        "args": [{ "id": feed.author, "seq": 1, "keys": false }],
+    });
+    let mut session = HistoryStreamSession::new();
+    session
+        .receive(&conformance::json_packet(1, request.to_string().as_bytes()))
+        .await?;
+
+    let sent = session.sent();
+    assert_eq!(sent.len(), feed.messages.len() + 1);
+
+    // Messages must be served with the exact bytes in which they were
+    // received; any difference invalidates the signature for the peer.
+    for (packet, fixture) in sent.iter().zip(&feed.messages) {
+        assert_eq!(packet.req_no.abs(), 1);
+        assert!(!packet.end);
+        assert_eq!(
+            String::from_utf8_lossy(&packet.body),
+            fixture.wire,
+            "{} message {}",
+            feed.source,
+            fixture.key
+        );
+    }
+    assert!(sent[feed.messages.len()].end);
+
+    Ok(())
+}
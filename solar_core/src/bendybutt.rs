@@ -0,0 +1,538 @@
+//! Bendy Butt messages.
+//!
+//! Bendy Butt (`bendybutt-v1`) is the feed format of metafeeds, whose feeds
+//! are referred to as `@<key>.bbfeed-v1` (or `ssb:feed/bendybutt-v1/<key>`)
+//! and whose messages as `%<hash>.bbmsg-v1`. Messages are bencoded, each
+//! value being encoded in BFE (binary field encoding): a type byte and a
+//! format byte followed by the data.
+//!
+//! A message is a list of its payload and the signature of the payload by
+//! the author. The payload is a list of the author, the sequence number,
+//! the key of the previous message (`nil` for the first message), the
+//! timestamp and the content section. The content section is either a list
+//! of the content (a dictionary) and the signature of the content by the
+//! subfeed it is about, or an encrypted box.
+//!
+//! Signature verification and message keys (the SHA-256 hash of the encoded
+//! message) are left to the native node.
+
+use alloc::{
+    borrow::ToOwned,
+    collections::BTreeMap,
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::{convert::TryInto, str};
+
+use serde_json::{Map, Value};
+
+use crate::{buttwoo, Error, Result};
+
+/// Name of the feed format, as negotiated in EBT replicate requests.
+pub const FORMAT: &str = "bendybutt-v1";
+
+/// Suffix of the sigil links of Bendy Butt feeds.
+pub const FEED_SUFFIX: &str = ".bbfeed-v1";
+
+/// Suffix of the sigil links of Bendy Butt messages.
+pub const MSG_SUFFIX: &str = ".bbmsg-v1";
+
+/// Prefix of the SSB URIs of Bendy Butt feeds.
+const FEED_URI_PREFIX: &str = "ssb:feed/bendybutt-v1/";
+
+/// Prefix of the signed bytes of the content of a message.
+pub const CONTENT_SIGNATURE_PREFIX: &[u8] = b"bendybutt";
+
+/// Maximum size in bytes of an encoded message.
+pub const MAX_MESSAGE_SIZE: usize = 8192;
+
+/// Length in bytes of an ed25519 public key or a sha256 hash.
+const KEY_LENGTH: usize = 32;
+
+/// Length in bytes of an ed25519 signature.
+const SIGNATURE_LENGTH: usize = 64;
+
+/// BFE type and format bytes of the values found in messages.
+const BFE_CLASSIC_FEED: [u8; 2] = [0, 0];
+const BFE_BENDYBUTT_FEED: [u8; 2] = [0, 3];
+const BFE_BUTTWOO_FEED: [u8; 2] = [0, 4];
+const BFE_CLASSIC_MSG: [u8; 2] = [1, 0];
+const BFE_BENDYBUTT_MSG: [u8; 2] = [1, 4];
+const BFE_BUTTWOO_MSG: [u8; 2] = [1, 5];
+const BFE_BLOB: [u8; 2] = [2, 0];
+const BFE_SIGNATURE: [u8; 2] = [4, 0];
+const BFE_BOX: u8 = 5;
+const BFE_STRING: [u8; 2] = [6, 0];
+const BFE_BOOL: [u8; 2] = [6, 1];
+const BFE_NIL: [u8; 2] = [6, 2];
+
+/// A bencoded value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Bencode {
+    Int(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Bencode>),
+    /// Dictionaries are encoded with their keys in ascending order.
+    Dict(BTreeMap<Vec<u8>, Bencode>),
+}
+
+impl Bencode {
+    /// Encode the value.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode_into(&mut buf);
+
+        buf
+    }
+
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        match self {
+            Bencode::Int(int) => buf.extend_from_slice(format!("i{}e", int).as_bytes()),
+            Bencode::Bytes(bytes) => {
+                buf.extend_from_slice(format!("{}:", bytes.len()).as_bytes());
+                buf.extend_from_slice(bytes);
+            }
+            Bencode::List(list) => {
+                buf.push(b'l');
+                for value in list {
+                    value.encode_into(buf);
+                }
+                buf.push(b'e');
+            }
+            Bencode::Dict(dict) => {
+                buf.push(b'd');
+                for (key, value) in dict {
+                    Bencode::Bytes(key.to_owned()).encode_into(buf);
+                    value.encode_into(buf);
+                }
+                buf.push(b'e');
+            }
+        }
+    }
+
+    /// Decode the given bytes, which must hold a single value in canonical
+    /// encoding.
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        let (value, rest) = Self::decode_prefix(data)?;
+        if !rest.is_empty() {
+            return Err(invalid("trailing bytes"));
+        }
+
+        Ok(value)
+    }
+
+    /// Decode the value at the start of the given bytes, returning it along
+    /// with the remaining bytes.
+    fn decode_prefix(data: &[u8]) -> Result<(Self, &[u8])> {
+        match data.first() {
+            Some(b'i') => {
+                let end = position(data, b'e')?;
+                let digits = str::from_utf8(&data[1..end]).map_err(|_| invalid("integer"))?;
+                // Reject the non-canonical encodings of integers.
+                let canonical = digits == "0"
+                    || !(digits.starts_with('0')
+                        || digits.starts_with("-0")
+                        || digits.starts_with('+'));
+                let int = match digits.parse::<i64>() {
+                    Ok(int) if canonical => int,
+                    _ => return Err(invalid("integer")),
+                };
+
+                Ok((Bencode::Int(int), &data[end + 1..]))
+            }
+            Some(b'0'..=b'9') => {
+                let (bytes, rest) = decode_bytes(data)?;
+                Ok((Bencode::Bytes(bytes.to_vec()), rest))
+            }
+            Some(b'l') => {
+                let mut list = Vec::new();
+                let mut rest = &data[1..];
+                while rest.first() != Some(&b'e') {
+                    let (value, remaining) = Self::decode_prefix(rest)?;
+                    list.push(value);
+                    rest = remaining;
+                }
+
+                Ok((Bencode::List(list), &rest[1..]))
+            }
+            Some(b'd') => {
+                let mut dict = BTreeMap::new();
+                let mut rest = &data[1..];
+                while rest.first() != Some(&b'e') {
+                    let (key, remaining) = decode_bytes(rest)?;
+                    // Keys must be unique and in ascending order.
+                    if dict
+                        .keys()
+                        .next_back()
+                        .map_or(false, |last: &Vec<u8>| last.as_slice() >= key)
+                    {
+                        return Err(invalid("dictionary key order"));
+                    }
+                    let (value, remaining) = Self::decode_prefix(remaining)?;
+                    dict.insert(key.to_vec(), value);
+                    rest = remaining;
+                }
+
+                Ok((Bencode::Dict(dict), &rest[1..]))
+            }
+            _ => Err(invalid("value")),
+        }
+    }
+
+    /// Return the elements of the value if it is a list of the given length.
+    fn as_list(&self, len: usize) -> Result<&[Bencode]> {
+        match self {
+            Bencode::List(list) if list.len() == len => Ok(list),
+            _ => Err(invalid("list")),
+        }
+    }
+
+    /// Return the bytes of the value if it is a byte string.
+    fn as_bytes(&self) -> Result<&[u8]> {
+        match self {
+            Bencode::Bytes(bytes) => Ok(bytes),
+            _ => Err(invalid("byte string")),
+        }
+    }
+
+    /// Return the value if it is a non-negative integer.
+    fn as_u64(&self) -> Result<u64> {
+        match self {
+            Bencode::Int(int) => Ok((*int).try_into()?),
+            _ => Err(invalid("integer")),
+        }
+    }
+}
+
+/// Return an error describing an invalid part of a message.
+fn invalid(part: &str) -> Error {
+    Error::BendyButt(format!("invalid {}", part))
+}
+
+/// Return the index of the first occurrence of the given byte.
+fn position(data: &[u8], byte: u8) -> Result<usize> {
+    data.iter()
+        .position(|b| *b == byte)
+        .ok_or_else(|| invalid("encoding"))
+}
+
+/// Decode the byte string at the start of the given bytes, returning it
+/// along with the remaining bytes.
+fn decode_bytes(data: &[u8]) -> Result<(&[u8], &[u8])> {
+    let colon = position(data, b':')?;
+    let digits = str::from_utf8(&data[..colon]).map_err(|_| invalid("byte string"))?;
+    if digits.len() > 1 && digits.starts_with('0') {
+        return Err(invalid("byte string"));
+    }
+    let len: usize = digits.parse().map_err(|_| invalid("byte string"))?;
+    let start = colon + 1;
+    let end = start
+        .checked_add(len)
+        .filter(|end| *end <= data.len())
+        .ok_or_else(|| invalid("byte string"))?;
+
+    Ok((&data[start..end], &data[end..]))
+}
+
+/// Return the data of the given BFE value if it has the given type and
+/// format and the given length.
+fn bfe_data<'a>(value: &'a Bencode, prefix: [u8; 2], len: usize) -> Result<&'a [u8]> {
+    match value.as_bytes()? {
+        bytes if bytes.len() == len + 2 && bytes[..2] == prefix => Ok(&bytes[2..]),
+        _ => Err(invalid("BFE value")),
+    }
+}
+
+/// Return the sigil link of the Bendy Butt feed with the given public key.
+pub fn feed_id(key: &[u8]) -> String {
+    format!("@{}{}", base64::encode(key), FEED_SUFFIX)
+}
+
+/// Return the sigil link of the Bendy Butt message with the given hash.
+pub fn msg_id(hash: &[u8]) -> String {
+    format!("%{}{}", base64::encode(hash), MSG_SUFFIX)
+}
+
+/// Query whether the given link refers to a Bendy Butt feed.
+pub fn is_feed_id(link: &str) -> bool {
+    (link.starts_with('@') && link.ends_with(FEED_SUFFIX)) || link.starts_with(FEED_URI_PREFIX)
+}
+
+/// Return the public key of the Bendy Butt feed with the given sigil link or
+/// SSB URI.
+pub fn parse_feed_id(link: &str) -> Result<[u8; KEY_LENGTH]> {
+    let key = match link.strip_prefix(FEED_URI_PREFIX) {
+        Some(data) => base64::decode_config(data, base64::URL_SAFE),
+        None => {
+            let data = link
+                .strip_prefix('@')
+                .and_then(|link| link.strip_suffix(FEED_SUFFIX))
+                .ok_or_else(|| Error::SsbUri(link.to_owned()))?;
+            base64::decode(data)
+        }
+    };
+
+    key.ok()
+        .and_then(|key| key.try_into().ok())
+        .ok_or_else(|| Error::SsbUri(link.to_owned()))
+}
+
+/// The content section of a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ContentSection {
+    /// The content, along with its signature by the subfeed it is about.
+    Signed {
+        content: Bencode,
+        signature: Vec<u8>,
+    },
+    /// Encrypted content.
+    Encrypted(Vec<u8>),
+}
+
+/// A decoded Bendy Butt message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BendyButtMessage {
+    /// Public key of the author (the metafeed).
+    pub author: [u8; KEY_LENGTH],
+    pub sequence: u64,
+    /// Hash of the previous message, if any.
+    pub previous: Option<[u8; KEY_LENGTH]>,
+    /// Milliseconds since the UNIX epoch, as claimed by the author.
+    pub timestamp: u64,
+    pub content: ContentSection,
+    /// Encoded payload, as signed by the author.
+    pub payload: Vec<u8>,
+    /// Signature of the payload by the author.
+    pub signature: Vec<u8>,
+}
+
+impl BendyButtMessage {
+    /// Decode the given bencoded message.
+    pub fn from_slice(data: &[u8]) -> Result<Self> {
+        if data.len() > MAX_MESSAGE_SIZE {
+            return Err(invalid("message size"));
+        }
+
+        let message = Bencode::decode(data)?;
+        let message = message.as_list(2)?;
+        let payload = message[0].as_list(5)?;
+        let signature = bfe_data(&message[1], BFE_SIGNATURE, SIGNATURE_LENGTH)?;
+
+        let author = bfe_data(&payload[0], BFE_BENDYBUTT_FEED, KEY_LENGTH)?;
+        let sequence = payload[1].as_u64()?;
+        let previous = match payload[2].as_bytes()? {
+            bytes if bytes == BFE_NIL => None,
+            _ => Some(bfe_data(&payload[2], BFE_BENDYBUTT_MSG, KEY_LENGTH)?),
+        };
+        let timestamp = payload[3].as_u64()?;
+
+        // The first message has no previous message, and the others do.
+        if sequence == 0 || (sequence == 1) != previous.is_none() {
+            return Err(invalid("sequence"));
+        }
+
+        let content = match &payload[4] {
+            Bencode::List(_) => {
+                let section = payload[4].as_list(2)?;
+                if !matches!(section[0], Bencode::Dict(_)) {
+                    return Err(invalid("content"));
+                }
+                ContentSection::Signed {
+                    content: section[0].to_owned(),
+                    signature: bfe_data(&section[1], BFE_SIGNATURE, SIGNATURE_LENGTH)?.to_vec(),
+                }
+            }
+            Bencode::Bytes(bytes) if bytes.len() > 2 && bytes[0] == BFE_BOX => {
+                ContentSection::Encrypted(bytes.to_owned())
+            }
+            _ => return Err(invalid("content section")),
+        };
+
+        Ok(BendyButtMessage {
+            author: author.try_into().map_err(|_| invalid("author"))?,
+            sequence,
+            previous: previous
+                .map(|hash| hash.try_into().map_err(|_| invalid("previous")))
+                .transpose()?,
+            timestamp,
+            content,
+            payload: message[0].encode(),
+            signature: signature.to_vec(),
+        })
+    }
+
+    /// Return the sigil link of the author.
+    pub fn author_id(&self) -> String {
+        feed_id(&self.author)
+    }
+
+    /// Return the public key of the subfeed the content is about, which
+    /// signed the content, unless the content is encrypted.
+    pub fn subfeed(&self) -> Result<Option<[u8; KEY_LENGTH]>> {
+        let content = match &self.content {
+            ContentSection::Signed { content, .. } => content,
+            ContentSection::Encrypted(_) => return Ok(None),
+        };
+
+        let subfeed = match content {
+            Bencode::Dict(dict) => dict.get(&b"subfeed"[..]),
+            _ => None,
+        }
+        .ok_or_else(|| invalid("subfeed"))?;
+        let key = bfe_data(subfeed, BFE_CLASSIC_FEED, KEY_LENGTH)
+            .or_else(|_| bfe_data(subfeed, BFE_BENDYBUTT_FEED, KEY_LENGTH))
+            .or_else(|_| bfe_data(subfeed, BFE_BUTTWOO_FEED, KEY_LENGTH))?;
+
+        Ok(Some(key.try_into().map_err(|_| invalid("subfeed"))?))
+    }
+
+    /// Return the bytes signed by the subfeed to produce the content
+    /// signature, unless the content is encrypted.
+    pub fn signed_content(&self) -> Option<Vec<u8>> {
+        match &self.content {
+            ContentSection::Signed { content, .. } => {
+                let mut signed = CONTENT_SIGNATURE_PREFIX.to_vec();
+                signed.extend(content.encode());
+                Some(signed)
+            }
+            ContentSection::Encrypted(_) => None,
+        }
+    }
+
+    /// Return the content as JSON, decoding its BFE values, or the encrypted
+    /// content as a base64 string.
+    pub fn content_json(&self) -> Value {
+        match &self.content {
+            ContentSection::Signed { content, .. } => to_json(content),
+            ContentSection::Encrypted(bytes) => Value::String(base64::encode(&bytes[2..])),
+        }
+    }
+}
+
+/// Convert the given bencoded value to JSON, decoding its BFE values.
+fn to_json(value: &Bencode) -> Value {
+    match value {
+        Bencode::Int(int) => Value::from(*int),
+        Bencode::Bytes(bytes) => bfe_to_json(bytes),
+        Bencode::List(list) => Value::Array(list.iter().map(to_json).collect()),
+        Bencode::Dict(dict) => Value::Object(
+            dict.iter()
+                .map(|(key, value)| (String::from_utf8_lossy(key).to_string(), to_json(value)))
+                .collect::<Map<String, Value>>(),
+        ),
+    }
+}
+
+/// Convert the given BFE value to JSON.
+fn bfe_to_json(bytes: &[u8]) -> Value {
+    if bytes.len() < 2 {
+        return Value::String(base64::encode(bytes));
+    }
+
+    let (prefix, data) = (&bytes[..2], &bytes[2..]);
+    match [prefix[0], prefix[1]] {
+        BFE_STRING => Value::String(String::from_utf8_lossy(data).to_string()),
+        BFE_BOOL if data.len() == 1 => Value::Bool(data[0] == 1),
+        BFE_NIL => Value::Null,
+        BFE_CLASSIC_FEED => Value::String(format!("@{}.ed25519", base64::encode(data))),
+        BFE_BENDYBUTT_FEED => Value::String(feed_id(data)),
+        BFE_CLASSIC_MSG => Value::String(format!("%{}.sha256", base64::encode(data))),
+        BFE_BENDYBUTT_MSG => Value::String(msg_id(data)),
+        BFE_BUTTWOO_FEED => Value::String(buttwoo::feed_id(data)),
+        BFE_BUTTWOO_MSG => Value::String(buttwoo::msg_id(data)),
+        BFE_BLOB => Value::String(format!("&{}.sha256", base64::encode(data))),
+        BFE_SIGNATURE => Value::String(format!("{}.sig.ed25519", base64::encode(data))),
+        _ => Value::String(base64::encode(bytes)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use alloc::vec;
+
+    /// Return the given data as a BFE value with the given prefix.
+    fn bfe(prefix: [u8; 2], data: &[u8]) -> Bencode {
+        let mut bytes = prefix.to_vec();
+        bytes.extend_from_slice(data);
+        Bencode::Bytes(bytes)
+    }
+
+    /// Return an (unsigned) encoded message with the given sequence number
+    /// and previous message hash.
+    fn message(sequence: i64, previous: Option<[u8; 32]>) -> Vec<u8> {
+        let mut content = BTreeMap::new();
+        content.insert(b"type".to_vec(), bfe(BFE_STRING, b"metafeed/add/derived"));
+        content.insert(b"subfeed".to_vec(), bfe(BFE_CLASSIC_FEED, &[2; 32]));
+
+        let payload = Bencode::List(vec![
+            bfe(BFE_BENDYBUTT_FEED, &[1; 32]),
+            Bencode::Int(sequence),
+            match previous {
+                Some(hash) => bfe(BFE_BENDYBUTT_MSG, &hash),
+                None => Bencode::Bytes(BFE_NIL.to_vec()),
+            },
+            Bencode::Int(1_650_000_000_000),
+            Bencode::List(vec![Bencode::Dict(content), bfe(BFE_SIGNATURE, &[3; 64])]),
+        ]);
+
+        Bencode::List(vec![payload, bfe(BFE_SIGNATURE, &[4; 64])]).encode()
+    }
+
+    #[test]
+    fn test_bencode() -> Result<()> {
+        let value = Bencode::decode(b"d3:bar4:spam3:fooi42e4:listli-1e0:ee")?;
+        assert_eq!(
+            value.encode(),
+            b"d3:bar4:spam3:fooi42e4:listli-1e0:ee".to_vec()
+        );
+
+        // Non-canonical encodings are rejected.
+        assert!(Bencode::decode(b"i042e").is_err());
+        assert!(Bencode::decode(b"i-0e").is_err());
+        assert!(Bencode::decode(b"d3:fooi1e3:bari2ee").is_err());
+        assert!(Bencode::decode(b"4:spa").is_err());
+        assert!(Bencode::decode(b"i1ei2e").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_message() -> Result<()> {
+        let msg = BendyButtMessage::from_slice(&message(1, None))?;
+        assert_eq!(msg.author_id(), feed_id(&[1; 32]));
+        assert_eq!(msg.sequence, 1);
+        assert_eq!(msg.previous, None);
+        assert_eq!(msg.subfeed()?, Some([2; 32]));
+        assert!(msg.signed_content().unwrap().starts_with(b"bendybutt"));
+        assert_eq!(msg.content_json()["type"], "metafeed/add/derived");
+
+        let msg = BendyButtMessage::from_slice(&message(2, Some([5; 32])))?;
+        assert_eq!(msg.previous, Some([5; 32]));
+
+        // The previous message must be given for (and only for) later
+        // messages.
+        assert!(BendyButtMessage::from_slice(&message(2, None)).is_err());
+        assert!(BendyButtMessage::from_slice(&message(1, Some([5; 32]))).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_feed_id() -> Result<()> {
+        let id = feed_id(&[1; 32]);
+        assert!(is_feed_id(&id));
+        assert!(!is_feed_id(
+            "@AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=.ed25519"
+        ));
+        assert_eq!(parse_feed_id(&id)?, [1; 32]);
+        assert_eq!(
+            parse_feed_id("ssb:feed/bendybutt-v1/AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=")?,
+            [1; 32]
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,518 @@
+//! Buttwoo messages.
+//!
+//! Buttwoo (`buttwoo-v1`) is a binary feed format whose feeds are referred
+//! to as `ssb:feed/buttwoo-v1/<key>` and whose messages as
+//! `ssb:message/buttwoo-v1/<hash>`. Messages are encoded in BIPF (binary
+//! in-place format), their links being encoded in BFE (binary field
+//! encoding): a type byte and a format byte followed by the data.
+//!
+//! A message is a list of the encoded value, the signature of the encoded
+//! value by the author and the encoded content. The value is a list of the
+//! author, the parent message (`nil` unless the feed is a subfeed), the
+//! sequence number, the timestamp, the previous message (`nil` for the first
+//! message), the tag, the length of the content and the hash of the content.
+//! The tag marks the end of a feed, after which no message may follow.
+//!
+//! Signature verification and hashing (BLAKE3, for both the content hash
+//! and the message key) are left to the native node.
+
+use alloc::{
+    borrow::ToOwned,
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::{convert::TryInto, str};
+
+use serde_json::{Map, Number, Value};
+
+use crate::{Error, Result};
+
+/// Name of the feed format, as negotiated in EBT replicate requests.
+pub const FORMAT: &str = "buttwoo-v1";
+
+/// Prefix of the SSB URIs of buttwoo feeds.
+const FEED_URI_PREFIX: &str = "ssb:feed/buttwoo-v1/";
+
+/// Prefix of the SSB URIs of buttwoo messages.
+const MSG_URI_PREFIX: &str = "ssb:message/buttwoo-v1/";
+
+/// Maximum size in bytes of an encoded message.
+pub const MAX_MESSAGE_SIZE: usize = 16384;
+
+/// Tag of the messages ending their feed.
+pub const TAG_END_OF_FEED: u8 = 1;
+
+/// Length in bytes of an ed25519 public key or a BLAKE3 hash.
+const KEY_LENGTH: usize = 32;
+
+/// Length in bytes of an ed25519 signature.
+const SIGNATURE_LENGTH: usize = 64;
+
+/// BFE type and format bytes of the values found in messages.
+const BFE_BUTTWOO_FEED: [u8; 2] = [0, 4];
+const BFE_BUTTWOO_MSG: [u8; 2] = [1, 5];
+const BFE_NIL: [u8; 2] = [6, 2];
+
+/// Prefix of the content hash.
+const CONTENT_HASH_PREFIX: u8 = 0;
+
+/// BIPF type codes.
+const BIPF_STRING: u64 = 0;
+const BIPF_BUFFER: u64 = 1;
+const BIPF_INT: u64 = 2;
+const BIPF_DOUBLE: u64 = 3;
+const BIPF_ARRAY: u64 = 4;
+const BIPF_OBJECT: u64 = 5;
+const BIPF_BOOLNULL: u64 = 6;
+
+/// A BIPF value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Bipf {
+    String(String),
+    Buffer(Vec<u8>),
+    Int(i32),
+    Double(f64),
+    Array(Vec<Bipf>),
+    /// Objects keep their entries in encoding order.
+    Object(Vec<(Bipf, Bipf)>),
+    Bool(bool),
+    Null,
+}
+
+impl Bipf {
+    /// Encode the value.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode_into(&mut buf);
+
+        buf
+    }
+
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        let (type_code, body) = match self {
+            Bipf::String(string) => (BIPF_STRING, string.as_bytes().to_vec()),
+            Bipf::Buffer(bytes) => (BIPF_BUFFER, bytes.to_owned()),
+            Bipf::Int(int) => (BIPF_INT, int.to_le_bytes().to_vec()),
+            Bipf::Double(double) => (BIPF_DOUBLE, double.to_le_bytes().to_vec()),
+            Bipf::Array(array) => {
+                let mut body = Vec::new();
+                for value in array {
+                    value.encode_into(&mut body);
+                }
+                (BIPF_ARRAY, body)
+            }
+            Bipf::Object(entries) => {
+                let mut body = Vec::new();
+                for (key, value) in entries {
+                    key.encode_into(&mut body);
+                    value.encode_into(&mut body);
+                }
+                (BIPF_OBJECT, body)
+            }
+            Bipf::Bool(boolean) => (BIPF_BOOLNULL, [*boolean as u8].to_vec()),
+            Bipf::Null => (BIPF_BOOLNULL, Vec::new()),
+        };
+
+        encode_varint((body.len() as u64) << 3 | type_code, buf);
+        buf.extend(body);
+    }
+
+    /// Decode the given bytes, which must hold a single value.
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        let (value, rest) = Self::decode_prefix(data)?;
+        if !rest.is_empty() {
+            return Err(invalid("trailing bytes"));
+        }
+
+        Ok(value)
+    }
+
+    /// Decode the value at the start of the given bytes, returning it along
+    /// with the remaining bytes.
+    fn decode_prefix(data: &[u8]) -> Result<(Self, &[u8])> {
+        let (tag, rest) = decode_varint(data)?;
+        let len: usize = (tag >> 3).try_into()?;
+        if len > rest.len() {
+            return Err(invalid("length"));
+        }
+        let (body, rest) = rest.split_at(len);
+
+        let value = match tag & 7 {
+            BIPF_STRING => Bipf::String(
+                str::from_utf8(body)
+                    .map_err(|_| invalid("string"))?
+                    .to_owned(),
+            ),
+            BIPF_BUFFER => Bipf::Buffer(body.to_vec()),
+            BIPF_INT => Bipf::Int(i32::from_le_bytes(
+                body.try_into().map_err(|_| invalid("integer"))?,
+            )),
+            BIPF_DOUBLE => Bipf::Double(f64::from_le_bytes(
+                body.try_into().map_err(|_| invalid("double"))?,
+            )),
+            BIPF_ARRAY => {
+                let mut array = Vec::new();
+                let mut remaining = body;
+                while !remaining.is_empty() {
+                    let (value, rest) = Self::decode_prefix(remaining)?;
+                    array.push(value);
+                    remaining = rest;
+                }
+                Bipf::Array(array)
+            }
+            BIPF_OBJECT => {
+                let mut entries = Vec::new();
+                let mut remaining = body;
+                while !remaining.is_empty() {
+                    let (key, rest) = Self::decode_prefix(remaining)?;
+                    let (value, rest) = Self::decode_prefix(rest)?;
+                    entries.push((key, value));
+                    remaining = rest;
+                }
+                Bipf::Object(entries)
+            }
+            BIPF_BOOLNULL => match body {
+                [] => Bipf::Null,
+                [0] => Bipf::Bool(false),
+                [1] => Bipf::Bool(true),
+                _ => return Err(invalid("boolean")),
+            },
+            _ => return Err(invalid("type")),
+        };
+
+        Ok((value, rest))
+    }
+
+    /// Return the elements of the value if it is an array of the given
+    /// length.
+    fn as_array(&self, len: usize) -> Result<&[Bipf]> {
+        match self {
+            Bipf::Array(array) if array.len() == len => Ok(array),
+            _ => Err(invalid("array")),
+        }
+    }
+
+    /// Return the bytes of the value if it is a buffer.
+    fn as_buffer(&self) -> Result<&[u8]> {
+        match self {
+            Bipf::Buffer(bytes) => Ok(bytes),
+            _ => Err(invalid("buffer")),
+        }
+    }
+
+    /// Return the value if it is a non-negative integer, which may be
+    /// encoded as a double (for timestamps in milliseconds).
+    fn as_u64(&self) -> Result<u64> {
+        match self {
+            Bipf::Int(int) => Ok((*int).try_into()?),
+            Bipf::Double(double) if *double >= 0.0 && double.fract() == 0.0 => Ok(*double as u64),
+            _ => Err(invalid("integer")),
+        }
+    }
+
+    /// Convert the value to JSON.
+    pub fn to_json(&self) -> Value {
+        match self {
+            Bipf::String(string) => Value::String(string.to_owned()),
+            Bipf::Buffer(bytes) => Value::String(base64::encode(bytes)),
+            Bipf::Int(int) => Value::from(*int),
+            Bipf::Double(double) => Number::from_f64(*double).map_or(Value::Null, Value::Number),
+            Bipf::Array(array) => Value::Array(array.iter().map(Bipf::to_json).collect()),
+            Bipf::Object(entries) => Value::Object(
+                entries
+                    .iter()
+                    .map(|(key, value)| {
+                        let key = match key {
+                            Bipf::String(key) => key.to_owned(),
+                            key => key.to_json().to_string(),
+                        };
+                        (key, value.to_json())
+                    })
+                    .collect::<Map<String, Value>>(),
+            ),
+            Bipf::Bool(boolean) => Value::Bool(*boolean),
+            Bipf::Null => Value::Null,
+        }
+    }
+}
+
+/// Return an error describing an invalid part of a message.
+fn invalid(part: &str) -> Error {
+    Error::Buttwoo(format!("invalid {}", part))
+}
+
+/// Encode the given integer as an unsigned LEB128 varint.
+fn encode_varint(mut int: u64, buf: &mut Vec<u8>) {
+    while int >= 0x80 {
+        buf.push((int as u8 & 0x7f) | 0x80);
+        int >>= 7;
+    }
+    buf.push(int as u8);
+}
+
+/// Decode the unsigned LEB128 varint at the start of the given bytes,
+/// returning it along with the remaining bytes.
+fn decode_varint(data: &[u8]) -> Result<(u64, &[u8])> {
+    let mut int = 0u64;
+    for (i, byte) in data.iter().enumerate().take(10) {
+        int |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok((int, &data[i + 1..]));
+        }
+    }
+
+    Err(invalid("varint"))
+}
+
+/// Return the data of the given BFE value if it has the given type and
+/// format, or `None` if it is `nil`.
+fn bfe_link(value: &Bipf, prefix: [u8; 2]) -> Result<Option<[u8; KEY_LENGTH]>> {
+    match value.as_buffer()? {
+        bytes if bytes == BFE_NIL => Ok(None),
+        bytes if bytes.len() == KEY_LENGTH + 2 && bytes[..2] == prefix => Ok(Some(
+            bytes[2..].try_into().map_err(|_| invalid("BFE value"))?,
+        )),
+        _ => Err(invalid("BFE value")),
+    }
+}
+
+/// Return the SSB URI of the buttwoo feed with the given public key.
+pub fn feed_id(key: &[u8]) -> String {
+    format!(
+        "{}{}",
+        FEED_URI_PREFIX,
+        base64::encode_config(key, base64::URL_SAFE)
+    )
+}
+
+/// Return the SSB URI of the buttwoo message with the given hash.
+pub fn msg_id(hash: &[u8]) -> String {
+    format!(
+        "{}{}",
+        MSG_URI_PREFIX,
+        base64::encode_config(hash, base64::URL_SAFE)
+    )
+}
+
+/// Query whether the given link refers to a buttwoo feed.
+pub fn is_feed_id(link: &str) -> bool {
+    link.starts_with(FEED_URI_PREFIX)
+}
+
+/// Return the public key of the buttwoo feed with the given SSB URI.
+pub fn parse_feed_id(link: &str) -> Result<[u8; KEY_LENGTH]> {
+    link.strip_prefix(FEED_URI_PREFIX)
+        .and_then(|data| base64::decode_config(data, base64::URL_SAFE).ok())
+        .and_then(|key| key.try_into().ok())
+        .ok_or_else(|| Error::SsbUri(link.to_owned()))
+}
+
+/// A decoded buttwoo message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ButtwooMessage {
+    /// Public key of the author.
+    pub author: [u8; KEY_LENGTH],
+    /// Key of the message from which the feed descends, if it is a subfeed.
+    pub parent: Option<[u8; KEY_LENGTH]>,
+    pub sequence: u64,
+    /// Key of the previous message, if any.
+    pub previous: Option<[u8; KEY_LENGTH]>,
+    /// Milliseconds since the UNIX epoch, as claimed by the author.
+    pub timestamp: u64,
+    pub tag: u8,
+    /// Length of the content, in bytes.
+    pub content_length: u64,
+    /// Hash of the content, prefixed with its format byte.
+    pub content_hash: Vec<u8>,
+    /// Encoded value, as signed by the author.
+    pub value: Vec<u8>,
+    /// Signature of the value by the author.
+    pub signature: Vec<u8>,
+    /// Encoded content.
+    pub content: Vec<u8>,
+}
+
+impl ButtwooMessage {
+    /// Decode the given BIPF-encoded message. The content length is checked,
+    /// but the content hash is left to the caller.
+    pub fn from_slice(data: &[u8]) -> Result<Self> {
+        if data.len() > MAX_MESSAGE_SIZE {
+            return Err(invalid("message size"));
+        }
+
+        let message = Bipf::decode(data)?;
+        let message = message.as_array(3)?;
+        let value_bytes = message[0].as_buffer()?;
+        let signature = message[1].as_buffer()?;
+        let content = message[2].as_buffer()?;
+        if signature.len() != SIGNATURE_LENGTH {
+            return Err(invalid("signature"));
+        }
+
+        let value = Bipf::decode(value_bytes)?;
+        let value = value.as_array(8)?;
+        let author = bfe_link(&value[0], BFE_BUTTWOO_FEED)?.ok_or_else(|| invalid("author"))?;
+        let parent = bfe_link(&value[1], BFE_BUTTWOO_MSG)?;
+        let sequence = value[2].as_u64()?;
+        let timestamp = value[3].as_u64()?;
+        let previous = bfe_link(&value[4], BFE_BUTTWOO_MSG)?;
+        let tag = match value[5].as_buffer()? {
+            [tag] => *tag,
+            _ => return Err(invalid("tag")),
+        };
+        let content_length = value[6].as_u64()?;
+        let content_hash = value[7].as_buffer()?;
+
+        // The first message has no previous message, and the others do.
+        if sequence == 0 || (sequence == 1) != previous.is_none() {
+            return Err(invalid("sequence"));
+        }
+        if content_hash.len() != KEY_LENGTH + 1 || content_hash[0] != CONTENT_HASH_PREFIX {
+            return Err(invalid("content hash"));
+        }
+        if content.len() as u64 != content_length {
+            return Err(invalid("content length"));
+        }
+
+        Ok(ButtwooMessage {
+            author,
+            parent,
+            sequence,
+            previous,
+            timestamp,
+            tag,
+            content_length,
+            content_hash: content_hash.to_vec(),
+            value: value_bytes.to_vec(),
+            signature: signature.to_vec(),
+            content: content.to_vec(),
+        })
+    }
+
+    /// Return the SSB URI of the author.
+    pub fn author_id(&self) -> String {
+        feed_id(&self.author)
+    }
+
+    /// Return the bytes hashed to produce the key of the message: the
+    /// encoded value followed by the signature.
+    pub fn hashed_bytes(&self) -> Vec<u8> {
+        let mut hashed = self.value.to_owned();
+        hashed.extend_from_slice(&self.signature);
+        hashed
+    }
+
+    /// Query whether the message ends its feed.
+    pub fn is_end_of_feed(&self) -> bool {
+        self.tag == TAG_END_OF_FEED
+    }
+
+    /// Return the content as JSON, or the encrypted content as a base64
+    /// string.
+    pub fn content_json(&self) -> Value {
+        match Bipf::decode(&self.content) {
+            Ok(content) => content.to_json(),
+            Err(_) => Value::String(base64::encode(&self.content)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use alloc::vec;
+
+    /// Return the given data as a BFE value with the given prefix.
+    fn bfe(prefix: [u8; 2], data: &[u8]) -> Bipf {
+        let mut bytes = prefix.to_vec();
+        bytes.extend_from_slice(data);
+        Bipf::Buffer(bytes)
+    }
+
+    /// Return an (unsigned) encoded message with the given sequence number
+    /// and previous message key.
+    fn message(sequence: i32, previous: Option<[u8; 32]>) -> Vec<u8> {
+        let content = Bipf::Object(vec![(
+            Bipf::String("type".to_string()),
+            Bipf::String("post".to_string()),
+        )])
+        .encode();
+
+        let value = Bipf::Array(vec![
+            bfe(BFE_BUTTWOO_FEED, &[1; 32]),
+            Bipf::Buffer(BFE_NIL.to_vec()),
+            Bipf::Int(sequence),
+            Bipf::Double(1_650_000_000_000.0),
+            match previous {
+                Some(key) => bfe(BFE_BUTTWOO_MSG, &key),
+                None => Bipf::Buffer(BFE_NIL.to_vec()),
+            },
+            Bipf::Buffer(vec![0]),
+            Bipf::Int(content.len() as i32),
+            Bipf::Buffer([&[CONTENT_HASH_PREFIX][..], &[2; 32]].concat()),
+        ]);
+
+        Bipf::Array(vec![
+            Bipf::Buffer(value.encode()),
+            Bipf::Buffer(vec![3; 64]),
+            Bipf::Buffer(content),
+        ])
+        .encode()
+    }
+
+    #[test]
+    fn test_bipf() -> Result<()> {
+        let value = Bipf::Object(vec![
+            (Bipf::String("a".to_string()), Bipf::Int(-1)),
+            (
+                Bipf::String("b".to_string()),
+                Bipf::Array(vec![Bipf::Null, Bipf::Bool(true), Bipf::Double(1.5)]),
+            ),
+        ]);
+        let encoded = value.encode();
+        assert_eq!(Bipf::decode(&encoded)?, value);
+        assert_eq!(Bipf::decode(b"\x0a\x61")?, Bipf::String("a".to_string()));
+
+        // Truncated and trailing bytes are rejected.
+        assert!(Bipf::decode(&encoded[..encoded.len() - 1]).is_err());
+        assert!(Bipf::decode(&[&encoded[..], &[0]].concat()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_message() -> Result<()> {
+        let msg = ButtwooMessage::from_slice(&message(1, None))?;
+        assert_eq!(msg.author_id(), feed_id(&[1; 32]));
+        assert_eq!(msg.sequence, 1);
+        assert_eq!(msg.timestamp, 1_650_000_000_000);
+        assert_eq!(msg.previous, None);
+        assert_eq!(msg.parent, None);
+        assert!(!msg.is_end_of_feed());
+        assert_eq!(msg.content_json()["type"], "post");
+
+        let msg = ButtwooMessage::from_slice(&message(2, Some([5; 32])))?;
+        assert_eq!(msg.previous, Some([5; 32]));
+
+        // The previous message must be given for (and only for) later
+        // messages.
+        assert!(ButtwooMessage::from_slice(&message(2, None)).is_err());
+        assert!(ButtwooMessage::from_slice(&message(1, Some([5; 32]))).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_feed_id() -> Result<()> {
+        let id = feed_id(&[1; 32]);
+        assert!(is_feed_id(&id));
+        assert!(!is_feed_id(
+            "@AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=.ed25519"
+        ));
+        assert_eq!(parse_feed_id(&id)?, [1; 32]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,35 @@
+use alloc::string::String;
+use core::{fmt, num};
+
+/// Possible solar core errors.
+#[derive(Debug)]
+pub enum Error {
+    /// Invalid Bendy Butt message.
+    BendyButt(String),
+    /// Invalid buttwoo message.
+    Buttwoo(String),
+    /// Invalid SSB URI.
+    SsbUri(String),
+    /// TryFromInt error.
+    TryFromInt(num::TryFromIntError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::BendyButt(err) => write!(f, "Invalid Bendy Butt message: {err}"),
+            Error::Buttwoo(err) => write!(f, "Invalid buttwoo message: {err}"),
+            Error::SsbUri(err) => write!(f, "Invalid SSB URI: {err}"),
+            Error::TryFromInt(err) => write!(f, "Integer conversion error: {err}"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
+
+impl From<num::TryFromIntError> for Error {
+    fn from(err: num::TryFromIntError) -> Error {
+        Error::TryFromInt(err)
+    }
+}
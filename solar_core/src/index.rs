@@ -0,0 +1,235 @@
+//! Index data types and logic.
+//!
+//! Values stored in the database indexes, along with the parts of the
+//! indexing logic which do not depend on the storage backend.
+
+use alloc::{borrow::ToOwned, format, string::String, vec::Vec};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// The latest values of a single about field (name, image or description)
+/// for a public key.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct AboutField {
+    /// Most recent value assigned by any author, along with the public key
+    /// of the assigner.
+    pub latest: Option<(String, String)>,
+    /// Most recent self-assigned value.
+    pub latest_self: Option<String>,
+}
+
+impl AboutField {
+    /// Record a value assigned to `about_id` by `author_id`.
+    pub fn update(&mut self, about_id: &str, author_id: &str, value: String) {
+        if author_id == about_id {
+            self.latest_self = Some(value.to_owned());
+        }
+        self.latest = Some((author_id.to_owned(), value));
+    }
+
+    /// Return the value with the highest precedence: the most recent
+    /// self-assigned value if there is one, otherwise the most recent value
+    /// assigned by any author.
+    pub fn resolve(&self) -> Option<&str> {
+        self.latest_self
+            .as_deref()
+            .or_else(|| self.latest.as_ref().map(|(_author, value)| value.as_str()))
+    }
+}
+
+/// The latest about values (name, image and description) for a public key,
+/// along with its self-assigned public web hosting preference.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct About {
+    pub description: AboutField,
+    pub image: AboutField,
+    pub name: AboutField,
+    /// Whether the author agrees to their messages being shown on public
+    /// web pages (`publicWebHosting`), or `None` if they did not say.
+    #[serde(default)]
+    pub public_web_hosting: Option<bool>,
+}
+
+/// The latest contact state of an edge in the social graph (from one public
+/// key to another).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ContactState {
+    /// The author follows the contact.
+    pub following: bool,
+    /// The author blocks the contact.
+    pub blocking: bool,
+}
+
+/// A vote cast on a message.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Vote {
+    /// Public key of the voter.
+    pub voter: String,
+    /// Value of the vote (`1` for a like, `0` to retract a like).
+    pub value: i64,
+    /// Optional expression (eg. "Like", "Dig" or an emoji).
+    pub expression: Option<String>,
+    /// Timestamp asserted by the voter.
+    pub timestamp: f64,
+}
+
+/// The reason for which a notification was raised.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationKind {
+    /// The message mentions the local identity.
+    Mention,
+    /// The message replies to a message authored by the local identity.
+    Reply,
+}
+
+/// A message of interest to the local identity.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Notification {
+    /// Key (hash) of the message.
+    pub msg_ref: String,
+    /// Public key of the message author.
+    pub author: String,
+    pub kind: NotificationKind,
+    /// Timestamp asserted by the author.
+    pub timestamp: f64,
+    /// The notification has been marked as read.
+    pub read: bool,
+}
+
+/// The timestamp by which the timeline is ordered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimelineOrder {
+    /// The timestamp asserted by the author of the message.
+    Claimed,
+    /// The time at which the message was received (indexed) locally.
+    Received,
+}
+
+/// A message in the timeline.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct TimelineEntry {
+    /// Key (hash) of the message.
+    pub msg_ref: String,
+    /// Public key of the message author.
+    pub author: String,
+    /// Sequence number of the message.
+    pub sequence: u64,
+    /// Claimed or received timestamp in milliseconds, depending on the
+    /// timeline order.
+    pub timestamp: u64,
+}
+
+/// A page of timeline entries, ordered from newest to oldest.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct TimelinePage {
+    pub entries: Vec<TimelineEntry>,
+    /// Cursor from which to retrieve the next page, or `None` if this is
+    /// the last page.
+    pub cursor: Option<String>,
+}
+
+/// Return the contacts index key for the edge from the given author to the
+/// given contact. The key is prefixed by the author so that all contacts of
+/// an author can be retrieved with a prefix scan.
+pub fn contact_key(author_id: &str, contact_id: &str) -> String {
+    format!("{}:{}", author_id, contact_id)
+}
+
+/// Query whether the given message content mentions the given public key,
+/// either in the `mentions` array or in the text of the message.
+pub fn mentions(content: &Value, ssb_id: &str) -> bool {
+    let mentioned = match content.get("mentions").and_then(Value::as_array) {
+        Some(mentions) => mentions.iter().any(|mention| {
+            let link = mention
+                .get("link")
+                .and_then(Value::as_str)
+                .or_else(|| mention.as_str());
+            link == Some(ssb_id)
+        }),
+        None => false,
+    };
+
+    let in_text = match content.get("text").and_then(Value::as_str) {
+        Some(text) => text.contains(ssb_id),
+        None => false,
+    };
+
+    mentioned || in_text
+}
+
+/// Return the message references of the `root` and `branch` fields of the
+/// given message content (ie. the messages it replies to).
+pub fn reply_refs(content: &Value) -> Vec<&str> {
+    let mut refs: Vec<&str> = Vec::new();
+    if let Some(root) = content.get("root").and_then(Value::as_str) {
+        refs.push(root)
+    }
+    match content.get("branch") {
+        Some(Value::String(branch)) => refs.push(branch),
+        Some(Value::Array(branches)) => refs.extend(branches.iter().filter_map(Value::as_str)),
+        _ => (),
+    }
+
+    refs
+}
+
+/// Return the timeline key for a message with the given timestamp (in
+/// milliseconds). Keys are ordered by timestamp, then by message key.
+pub fn timeline_key(timestamp: u64, msg_ref: &str) -> Vec<u8> {
+    let mut key = timestamp.to_be_bytes().to_vec();
+    key.extend_from_slice(msg_ref.as_bytes());
+
+    key
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use alloc::string::ToString;
+    use serde_json::json;
+
+    const FEED: &str = "@FCX/tsDLpubCPKKfIrw4gc+SQkHcaD17s7GI6i/ziWY=.ed25519";
+
+    #[test]
+    fn test_about_field() {
+        let mut field = AboutField::default();
+        field.update(FEED, "@other", "Other".to_string());
+        assert_eq!(field.resolve(), Some("Other"));
+
+        field.update(FEED, FEED, "Self".to_string());
+        field.update(FEED, "@other", "Other again".to_string());
+        assert_eq!(field.resolve(), Some("Self"));
+        assert_eq!(
+            field.latest,
+            Some(("@other".to_string(), "Other again".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_mentions_and_replies() {
+        let content = json!({
+            "type": "post",
+            "text": "hello",
+            "root": "%root.sha256",
+            "branch": ["%a.sha256", "%b.sha256"],
+            "mentions": [{ "link": FEED }]
+        });
+        assert!(mentions(&content, FEED));
+        assert!(!mentions(&content, "@other"));
+        assert!(mentions(&json!({ "text": format!("hi {}", FEED) }), FEED));
+        assert_eq!(
+            reply_refs(&content),
+            ["%root.sha256", "%a.sha256", "%b.sha256"]
+        );
+    }
+
+    #[test]
+    fn test_timeline_key_order() {
+        assert!(timeline_key(1, "%b.sha256") < timeline_key(2, "%a.sha256"));
+        assert!(timeline_key(2, "%a.sha256") < timeline_key(2, "%b.sha256"));
+    }
+}
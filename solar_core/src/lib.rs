@@ -0,0 +1,29 @@
+//! Runtime-agnostic core of solar.
+//!
+//! Message link handling, Bendy Butt and buttwoo message encoding, EBT
+//! vector clock encoding and index data types and logic, without
+//! dependencies on the async runtime or the native database. The crate is
+//! `no_std` (requiring only `alloc`) so that it can be compiled to WASM for
+//! browser-based tools; the native node uses it with the `std` feature
+//! enabled.
+//!
+//! Message signing and signature verification are provided by kuska-ssb and
+//! are not part of the core.
+
+#![no_std]
+
+extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
+
+pub mod bendybutt;
+pub mod buttwoo;
+pub mod clock;
+mod error;
+pub mod index;
+pub mod link;
+
+pub use error::Error;
+
+/// Convenience Result that returns `solar_core::Error`.
+pub type Result<T> = core::result::Result<T, Error>;
@@ -0,0 +1,180 @@
+//! SSB URIs.
+//!
+//! Newer clients refer to feeds, messages and blobs with SSB URIs
+//! (`ssb:feed/classic/<key>`, `ssb:message/classic/<hash>` and
+//! `ssb:blob/classic/<hash>`, where the key or hash is URL-safe base64)
+//! rather than sigil links (`@<key>.ed25519`, `%<hash>.sha256` and
+//! `&<hash>.sha256`). Feeds, messages and blobs are stored and indexed by
+//! sigil link; URIs are canonicalized to sigil links wherever a link is
+//! accepted. The older `ed25519` and `sha256` URI formats are also accepted.
+
+use alloc::{borrow::ToOwned, format, string::String};
+
+use serde::{Deserialize, Deserializer};
+
+use crate::{Error, Result};
+
+/// Length in bytes of an ed25519 public key or a sha256 hash.
+const KEY_LENGTH: usize = 32;
+
+/// The kind of entity a link refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LinkType {
+    Feed,
+    Message,
+    Blob,
+}
+
+impl LinkType {
+    fn sigil(self) -> char {
+        match self {
+            LinkType::Feed => '@',
+            LinkType::Message => '%',
+            LinkType::Blob => '&',
+        }
+    }
+
+    fn suffix(self) -> &'static str {
+        match self {
+            LinkType::Feed => ".ed25519",
+            LinkType::Message | LinkType::Blob => ".sha256",
+        }
+    }
+
+    fn uri_prefix(self) -> &'static str {
+        match self {
+            LinkType::Feed => "ssb:feed/classic/",
+            LinkType::Message => "ssb:message/classic/",
+            LinkType::Blob => "ssb:blob/classic/",
+        }
+    }
+}
+
+/// Query whether the given link is an SSB URI.
+pub fn is_uri(link: &str) -> bool {
+    link.starts_with("ssb:")
+}
+
+/// Canonicalize the given feed, message or blob link to a sigil link.
+/// Sigil links are returned unchanged.
+pub fn to_sigil(link: &str) -> Result<String> {
+    if !is_uri(link) {
+        return Ok(link.to_owned());
+    }
+
+    let invalid = || Error::SsbUri(link.to_owned());
+
+    let mut parts = link.trim_start_matches("ssb:").splitn(3, '/');
+    let link_type = match parts.next() {
+        Some("feed") => LinkType::Feed,
+        Some("message") => LinkType::Message,
+        Some("blob") => LinkType::Blob,
+        _ => return Err(invalid()),
+    };
+    let format = parts.next().ok_or_else(invalid)?;
+    let data = parts.next().ok_or_else(invalid)?;
+
+    match (link_type, format) {
+        (_, "classic") | (LinkType::Feed, "ed25519") => (),
+        (LinkType::Message | LinkType::Blob, "sha256") => (),
+        _ => return Err(invalid()),
+    }
+
+    let bytes = base64::decode_config(data, base64::URL_SAFE).map_err(|_| invalid())?;
+    if bytes.len() != KEY_LENGTH {
+        return Err(invalid());
+    }
+
+    Ok(format!(
+        "{}{}{}",
+        link_type.sigil(),
+        base64::encode_config(bytes, base64::STANDARD),
+        link_type.suffix()
+    ))
+}
+
+/// Convert the given feed, message or blob link to an SSB URI. SSB URIs are
+/// returned in canonical form.
+pub fn to_uri(link: &str) -> Result<String> {
+    let sigil_link = to_sigil(link)?;
+    let invalid = || Error::SsbUri(link.to_owned());
+
+    let link_type = match sigil_link.chars().next() {
+        Some('@') => LinkType::Feed,
+        Some('%') => LinkType::Message,
+        Some('&') => LinkType::Blob,
+        _ => return Err(invalid()),
+    };
+    let data = sigil_link[1..]
+        .strip_suffix(link_type.suffix())
+        .ok_or_else(invalid)?;
+
+    let bytes = base64::decode_config(data, base64::STANDARD).map_err(|_| invalid())?;
+    if bytes.len() != KEY_LENGTH {
+        return Err(invalid());
+    }
+
+    Ok(format!(
+        "{}{}",
+        link_type.uri_prefix(),
+        base64::encode_config(bytes, base64::URL_SAFE)
+    ))
+}
+
+/// Deserialize a feed, message or blob link, canonicalizing SSB URIs to
+/// sigil links.
+pub fn deserialize_link<'de, D>(deserializer: D) -> core::result::Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let link = String::deserialize(deserializer)?;
+
+    to_sigil(&link).map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const FEED: &str = "@FCX/tsDLpubCPKKfIrw4gc+SQkHcaD17s7GI6i/ziWY=.ed25519";
+    const FEED_URI: &str = "ssb:feed/classic/FCX_tsDLpubCPKKfIrw4gc-SQkHcaD17s7GI6i_ziWY=";
+    const MSG: &str = "%g3hPVPDEO1Aj/uPl0+J2NlhFB2bbFLIHlty+YuqFZ3w=.sha256";
+    const MSG_URI: &str = "ssb:message/classic/g3hPVPDEO1Aj_uPl0-J2NlhFB2bbFLIHlty-YuqFZ3w=";
+
+    #[test]
+    fn test_to_sigil() -> Result<()> {
+        assert_eq!(to_sigil(FEED_URI)?, FEED);
+        assert_eq!(to_sigil(MSG_URI)?, MSG);
+        assert_eq!(
+            to_sigil("ssb:feed/ed25519/FCX_tsDLpubCPKKfIrw4gc-SQkHcaD17s7GI6i_ziWY=")?,
+            FEED
+        );
+        assert_eq!(
+            to_sigil("ssb:blob/sha256/g3hPVPDEO1Aj_uPl0-J2NlhFB2bbFLIHlty-YuqFZ3w=")?,
+            MSG.replacen('%', "&", 1)
+        );
+
+        // Sigil links are returned unchanged.
+        assert_eq!(to_sigil(FEED)?, FEED);
+
+        assert!(
+            to_sigil("ssb:feed/bendybutt-v1/FCX_tsDLpubCPKKfIrw4gc-SQkHcaD17s7GI6i_ziWY=").is_err()
+        );
+        assert!(to_sigil("ssb:message/classic/abc").is_err());
+        assert!(to_sigil("ssb:experimental?action=claim-http-invite").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_to_uri() -> Result<()> {
+        assert_eq!(to_uri(FEED)?, FEED_URI);
+        assert_eq!(to_uri(MSG)?, MSG_URI);
+        assert_eq!(to_uri(MSG_URI)?, MSG_URI);
+
+        assert!(to_uri("#solar").is_err());
+        assert!(to_uri("@abc.ed25519").is_err());
+
+        Ok(())
+    }
+}
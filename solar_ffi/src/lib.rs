@@ -0,0 +1,7 @@
+//! C libraries of solar.
+//!
+//! Builds the C interface of solar (see `solar/src/ffi.rs`) as static and
+//! dynamic libraries, so that solar itself is only built as a Rust library
+//! and the C libraries are only built when they are asked for.
+
+pub use solar::ffi::*;